@@ -0,0 +1,356 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! `pathrs` is a small command-line wrapper around libpathrs, so that shell
+//! scripts and CI jobs can operate on untrusted directory trees with the same
+//! guarantees as users of the library. Every path given to a subcommand is
+//! resolved inside the `ROOT` directory, exactly as with `Root::resolve`.
+
+use pathrs::{error::Error, InodeType, JsonLines, ResolverBackend, Root};
+
+use std::{
+    env,
+    error::Error as StdError,
+    ffi::OsString,
+    fmt,
+    fs::Permissions,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+const USAGE: &str = "usage: pathrs [--backend kernel|emulated] ROOT COMMAND [ARGS...]
+
+Every PATH is resolved inside ROOT (as though ROOT were the root directory).
+
+commands:
+  resolve PATH...                print the path of the resolved PATH inside ROOT
+  cat PATH...                    write the contents of each PATH to stdout
+  write [-a] [-m MODE] PATH      write stdin to PATH, creating it if necessary
+                                 (-a appends rather than truncating)
+  mkdir [-p] [-m MODE] PATH...   create each directory PATH (-p creates any
+                                 missing parents and allows PATH to exist)
+  rm [-r] PATH...                remove each PATH (directories must be empty,
+                                 unless -r is given)
+  ls [PATH]                      list the entries of directory PATH (or ROOT)
+  cp [-m MODE] SRC DST           copy the regular file SRC to DST
+  walk [PATH]                    print every inode under PATH (or ROOT) as
+                                 JSON lines";
+
+/// Errors reported by the command-line tool.
+enum CliError {
+    /// The command-line arguments were invalid.
+    Usage(String),
+    /// The operation was refused by the tool itself.
+    Refused(String),
+    /// The operation failed.
+    Pathrs(Error),
+    /// Reading from stdin (or writing to stdout) failed.
+    Io(io::Error),
+}
+
+impl From<Error> for CliError {
+    fn from(err: Error) -> Self {
+        CliError::Pathrs(err)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        CliError::Io(err)
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(msg) | CliError::Refused(msg) => write!(f, "{}", msg),
+            CliError::Pathrs(err) => {
+                // Print the full chain, since the top-level error is usually
+                // just a short description of the operation.
+                write!(f, "{}", err)?;
+                let mut source = err.source();
+                while let Some(err) = source {
+                    write!(f, ": {}", err)?;
+                    source = err.source();
+                }
+                Ok(())
+            }
+            CliError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Options shared by several subcommands.
+struct Options {
+    append: bool,
+    parents: bool,
+    recursive: bool,
+    mode: Option<u32>,
+    paths: Vec<PathBuf>,
+}
+
+impl Options {
+    /// Parse the (POSIX-style) options of a subcommand. Only the options in
+    /// `allowed` are accepted.
+    fn parse(args: Vec<OsString>, allowed: &str) -> Result<Self, CliError> {
+        let mut opts = Options {
+            append: false,
+            parents: false,
+            recursive: false,
+            mode: None,
+            paths: vec![],
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--") => {
+                    opts.paths.extend(args.map(PathBuf::from));
+                    break;
+                }
+                Some(flag @ "-a") | Some(flag @ "-p") | Some(flag @ "-r") | Some(flag @ "-m")
+                    if allowed.contains(&flag[1..]) =>
+                {
+                    match flag {
+                        "-a" => opts.append = true,
+                        "-p" => opts.parents = true,
+                        "-r" => opts.recursive = true,
+                        _ => {
+                            let mode = args
+                                .next()
+                                .and_then(|mode| mode.into_string().ok())
+                                .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                                .ok_or_else(|| {
+                                    CliError::Usage("-m requires an octal mode".into())
+                                })?;
+                            opts.mode = Some(mode);
+                        }
+                    }
+                }
+                Some(flag) if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(CliError::Usage(format!("unknown option {}", flag)))
+                }
+                _ => opts.paths.push(PathBuf::from(arg)),
+            }
+        }
+        Ok(opts)
+    }
+
+    fn perm(&self, default: u32) -> Permissions {
+        Permissions::from_mode(self.mode.unwrap_or(default))
+    }
+
+    /// Require exactly `count` paths.
+    fn exact(&self, count: usize) -> Result<(), CliError> {
+        if self.paths.len() == count {
+            Ok(())
+        } else {
+            Err(CliError::Usage(format!(
+                "expected {} path(s), got {}",
+                count,
+                self.paths.len()
+            )))
+        }
+    }
+
+    /// Require at least one path.
+    fn some(&self) -> Result<(), CliError> {
+        if self.paths.is_empty() {
+            Err(CliError::Usage("expected at least one path".into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn resolve(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.some()?;
+    let mut stdout = io::stdout();
+    for path in &opts.paths {
+        let handle = root.resolve(path)?;
+        let relpath = root.relative_path_of(&handle)?;
+        stdout.write_all(
+            Path::new("/")
+                .join(relpath.as_unverified_path())
+                .as_os_str()
+                .as_bytes(),
+        )?;
+        stdout.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn cat(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.some()?;
+    let mut stdout = io::stdout();
+    for path in &opts.paths {
+        let mut file = root.open_file(path, libc::O_RDONLY)?;
+        io::copy(&mut file, &mut stdout)?;
+    }
+    Ok(())
+}
+
+fn write(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.exact(1)?;
+    let handle = root.ensure_file(&opts.paths[0], &opts.perm(0o644))?;
+    let flags = if opts.append {
+        libc::O_WRONLY | libc::O_APPEND
+    } else {
+        libc::O_WRONLY | libc::O_TRUNC
+    };
+    let mut file = root.reopen(&handle, flags)?;
+    io::copy(&mut io::stdin(), &mut file)?;
+    Ok(())
+}
+
+fn mkdir(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.some()?;
+    let perm = opts.perm(0o755);
+    for path in &opts.paths {
+        if opts.parents {
+            root.mkdir_all(path, &perm)?;
+        } else {
+            root.create(path, &InodeType::Directory(&perm))?;
+        }
+    }
+    Ok(())
+}
+
+fn rm(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.some()?;
+    for path in &opts.paths {
+        if opts.recursive {
+            root.remove_all(path)?;
+        } else {
+            root.remove(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn ls(root: &Root, opts: Options) -> Result<(), CliError> {
+    let path = match opts.paths.len() {
+        0 => PathBuf::from("/"),
+        1 => opts.paths[0].clone(),
+        _ => return opts.exact(1),
+    };
+    let mut names = root
+        .resolve(path)?
+        .read_dir()?
+        .map(|entry| entry.map(|entry| entry.into_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    names.sort();
+    let mut stdout = io::stdout();
+    for name in names {
+        stdout.write_all(name.as_bytes())?;
+        stdout.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn cp(root: &Root, opts: Options) -> Result<(), CliError> {
+    opts.exact(2)?;
+    let mut src = root.open_file(&opts.paths[0], libc::O_RDONLY)?;
+    let meta = src.metadata()?;
+    if !meta.is_file() {
+        return Err(CliError::Refused(format!(
+            "{:?} is not a regular file",
+            opts.paths[0]
+        )));
+    }
+    let perm = opts.perm(meta.permissions().mode() & 0o7777);
+    let handle = root.ensure_file(&opts.paths[1], &perm)?;
+    let mut dst = root.reopen(&handle, libc::O_WRONLY | libc::O_TRUNC)?;
+    io::copy(&mut src, &mut dst)?;
+    Ok(())
+}
+
+fn walk(root: &Root, opts: Options) -> Result<(), CliError> {
+    let path = match opts.paths.len() {
+        0 => PathBuf::from("/"),
+        1 => opts.paths[0].clone(),
+        _ => return opts.exact(1),
+    };
+    let mut output = JsonLines::new(io::stdout());
+    for entry in root.walk(path)? {
+        output.write_walk_entry(&entry?)?;
+    }
+    Ok(())
+}
+
+fn run(mut args: Vec<OsString>) -> Result<(), CliError> {
+    let usage = || CliError::Usage(USAGE.into());
+
+    let mut backend = None;
+    if args.first().and_then(|arg| arg.to_str()) == Some("--backend") {
+        args.remove(0);
+        backend = match args.first().and_then(|arg| arg.to_str()) {
+            Some("kernel") => Some(ResolverBackend::Kernel),
+            Some("emulated") => Some(ResolverBackend::Emulated),
+            _ => {
+                return Err(CliError::Usage(
+                    "--backend must be kernel or emulated".into(),
+                ))
+            }
+        };
+        args.remove(0);
+    }
+    if args.len() < 2 {
+        return Err(usage());
+    }
+    let mut args = args.into_iter();
+    let root_path = args.next().ok_or_else(usage)?;
+    let command = args.next().ok_or_else(usage)?;
+    let args = args.collect::<Vec<_>>();
+
+    let mut root = Root::open(root_path)?;
+    if let Some(backend) = backend {
+        root.resolver.backend = backend;
+    }
+    match command.to_str() {
+        Some("resolve") => resolve(&root, Options::parse(args, "")?),
+        Some("cat") => cat(&root, Options::parse(args, "")?),
+        Some("write") => write(&root, Options::parse(args, "am")?),
+        Some("mkdir") => mkdir(&root, Options::parse(args, "pm")?),
+        Some("rm") => rm(&root, Options::parse(args, "r")?),
+        Some("ls") => ls(&root, Options::parse(args, "")?),
+        Some("cp") => cp(&root, Options::parse(args, "m")?),
+        Some("walk") => walk(&root, Options::parse(args, "")?),
+        _ => Err(usage()),
+    }
+}
+
+fn main() {
+    if let Err(err) = run(env::args_os().skip(1).collect()) {
+        match err {
+            // Silently exit if stdout was closed early (`pathrs ... | head`).
+            CliError::Io(ref err) if err.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+            CliError::Usage(_) => {
+                eprintln!("pathrs: {}", err);
+                process::exit(2);
+            }
+            _ => {
+                eprintln!("pathrs: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -31,9 +31,19 @@ import (
 type Error struct {
 	description string
 	errno       syscall.Errno
+	code        int64
 	backtrace   []backtraceLine
 }
 
+// Error codes of libpathrs errors which have no errno(3) equivalent (see
+// Error.Code).
+const (
+	ErrorCodeSafetyViolation = int64(C.PATHRS_ESAFETY)
+	ErrorCodeNotSupported    = int64(C.PATHRS_ENOTSUPPORTED)
+	ErrorCodeNotImplemented  = int64(C.PATHRS_ENOTIMPLEMENTED)
+	ErrorCodeUnknown         = int64(C.PATHRS_EUNKNOWN)
+)
+
 type backtraceLine struct {
 	ip       uintptr
 	sAddress uintptr
@@ -56,6 +66,12 @@ func (err *Error) Unwrap() error {
 	return nil
 }
 
+// Code returns the stable error code of the error -- either a negated errno(3)
+// value (such as -ENOENT) or one of the ErrorCode* constants.
+func (err *Error) Code() int64 {
+	return err.code
+}
+
 // Backtrace returns a textual backtrace of the the call-stack when the error
 // was triggered within libpathrs. Depending on the (build and runtime)
 // configuration of libpathrs, this may return differing levels of information.
@@ -80,6 +96,7 @@ func newError(e *C.pathrs_error_t) error {
 
 	err := &Error{
 		errno:       syscall.Errno(e.saved_errno),
+		code:        int64(e.error_code),
 		description: C.GoString(e.description),
 	}
 
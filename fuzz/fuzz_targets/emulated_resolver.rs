@@ -0,0 +1,48 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pathrs::testing::FuzzSandbox;
+
+use std::{env, process, sync::Mutex};
+
+/// Number of distinct directory layouts, selected by the first input byte.
+const LAYOUTS: usize = 16;
+
+// Creating a layout is far more expensive than resolving a path in it, so
+// each layout is only created once per fuzzing process.
+static SANDBOXES: Mutex<Vec<Option<FuzzSandbox>>> = Mutex::new(Vec::new());
+
+fuzz_target!(|data: &[u8]| {
+    let (layout, path) = match data.split_first() {
+        Some((layout, path)) => (*layout as usize % LAYOUTS, path),
+        None => return,
+    };
+
+    let mut sandboxes = SANDBOXES.lock().unwrap();
+    if sandboxes.is_empty() {
+        sandboxes.resize_with(LAYOUTS, || None);
+    }
+    let sandbox = sandboxes[layout].get_or_insert_with(|| {
+        let base = env::temp_dir().join(format!("pathrs-fuzz-{}-{}", process::id(), layout));
+        FuzzSandbox::new(base, layout as u64).expect("failed to create fuzz sandbox")
+    });
+    sandbox.check_resolve(path);
+});
@@ -38,6 +38,31 @@
 #include <stdlib.h>
 #include <sys/types.h>
 
+/**
+ * `pathrs_error_t.error_code` of errors caused by libpathrs detecting a
+ * safety violation during an operation (such as an attack). Codes below
+ * `-4096` have no errno(3) equivalent and are reserved for libpathrs.
+ */
+#define PATHRS_ESAFETY -4097
+
+/**
+ * `pathrs_error_t.error_code` of errors caused by a feature not being
+ * supported by the running kernel.
+ */
+#define PATHRS_ENOTSUPPORTED -4098
+
+/**
+ * `pathrs_error_t.error_code` of errors caused by a feature not being
+ * implemented by libpathrs.
+ */
+#define PATHRS_ENOTIMPLEMENTED -4099
+
+/**
+ * `pathrs_error_t.error_code` of errors which have no errno(3) equivalent
+ * and do not fit any other code.
+ */
+#define PATHRS_EUNKNOWN -4100
+
 /**
  * The type of object being passed to "object agnostic" libpathrs functions.
  */
@@ -164,6 +189,13 @@ typedef struct __CBINDGEN_ALIGNED(8) {
      * disabled at libpathrs build-time or through an environment variable).
      */
     pathrs_backtrace_t *backtrace;
+    /**
+     * Stable error code of the error: either a negative errno(3) value
+     * (such as `-ENOENT`) or one of the libpathrs-specific codes below
+     * `-4096` (such as `PATHRS_ESAFETY`). Unlike `saved_errno`, this is
+     * never 0.
+     */
+    int64_t error_code;
 } pathrs_error_t;
 
 /**
@@ -219,7 +251,8 @@ typedef struct __CBINDGEN_ALIGNED(8) {
     pathrs_resolver_t resolver;
     /**
      * Flags to pass to resolver. These must be valid `RESOLVE_*` flags. At
-     * time of writing, only `RESOLVE_NO_SYMLINKS` is supported.
+     * time of writing, only `RESOLVE_NO_SYMLINKS` is supported -- as well as the
+     * libpathrs-specific `(1 << 32)`, which enables case-insensitive lookups.
      */
     uint64_t flags;
 } pathrs_config_root_t;
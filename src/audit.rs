@@ -0,0 +1,283 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    stat, syscalls, Handle, InodeType, RenameFlags, Root, Stat,
+};
+
+use std::{
+    fs::File,
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// The identity of an inode which was affected by an operation, as returned
+/// by the `*_audited` variants of the mutating [`Root`] operations (such as
+/// [`Root::remove_audited`]).
+///
+/// This can be compared with the identity of earlier resolutions (see
+/// [`Handle::acted_on`]), to build higher-level time-of-check-to-time-of-use
+/// assertions or audit trails. Note that inode numbers are only unique within
+/// a filesystem, and can be reused once an inode has been freed.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::remove_audited`]: struct.Root.html#method.remove_audited
+/// [`Handle::acted_on`]: struct.Handle.html#method.acted_on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ActedOn {
+    /// Device containing the inode (`st_dev`).
+    pub dev: u64,
+    /// Inode number (`st_ino`).
+    pub ino: u64,
+    /// Id of the mount the inode was accessed through, if the kernel provides
+    /// it (`stx_mnt_id`).
+    pub mnt_id: Option<u64>,
+}
+
+impl From<Stat> for ActedOn {
+    fn from(stat: Stat) -> Self {
+        Self {
+            dev: stat.dev,
+            ino: stat.ino,
+            mnt_id: stat.mnt_id,
+        }
+    }
+}
+
+impl ActedOn {
+    /// Is this the same inode as `other`? Unlike `==`, the mount ids are only
+    /// compared if both are known.
+    pub fn same_inode(&self, other: &ActedOn) -> bool {
+        self.dev == other.dev
+            && self.ino == other.ino
+            && match (self.mnt_id, other.mnt_id) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+
+    /// Get the identity of the inode referenced by `fd`.
+    fn of_fd(fd: RawFd) -> Result<Self, Error> {
+        stat::stat_at(fd, Path::new(""))
+            .map(Self::from)
+            .wrap("get identity of inode")
+    }
+}
+
+impl Handle {
+    /// Get the [`ActedOn`] identity of the inode referenced by the [`Handle`],
+    /// for comparison with the identities returned by audited operations.
+    ///
+    /// [`ActedOn`]: struct.ActedOn.html
+    /// [`Handle`]: struct.Handle.html
+    pub fn acted_on(&self) -> Result<ActedOn, Error> {
+        ActedOn::of_fd(self.inner.as_raw_fd())
+    }
+}
+
+/// Ensure that `after` is the same inode as `before`.
+pub(crate) fn check_same(
+    before: &ActedOn,
+    after: &ActedOn,
+    description: &str,
+) -> Result<(), Error> {
+    ensure!(
+        before.same_inode(after),
+        error::SafetyViolation { description }
+    );
+    Ok(())
+}
+
+impl Root {
+    /// Get an `O_PATH` handle to the inode at `path` without following a
+    /// trailing symlink, along with its identity.
+    fn open_nofollow(&self, path: &Path) -> Result<(File, ActedOn), Error> {
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .grace_policy
+            .retry(|| self.resolve(parent))
+            .wrap("resolve target parent directory")?
+            .inner;
+        let file = syscalls::openat(dir.as_raw_fd(), name, libc::O_PATH, 0).context(
+            error::RawOsError {
+                operation: "open trailing component",
+            },
+        )?;
+        let id = ActedOn::of_fd(file.as_raw_fd())?;
+        Ok((file, id))
+    }
+
+    /// Within the [`Root`]'s tree, create a new inode at `path` as with
+    /// [`Root::create`], returning the identity of the inode which was
+    /// created.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::create`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::create`]: struct.Root.html#method.create
+    pub fn create_audited<P: AsRef<Path>>(
+        &self,
+        path: P,
+        inode_type: &InodeType,
+    ) -> Result<ActedOn, Error> {
+        let path = path.as_ref();
+        self.create(path, inode_type)?;
+        // The inode could have been swapped out in the meantime, but we
+        // cannot do better without support from the kernel.
+        self.open_nofollow(path)
+            .map(|(_, id)| id)
+            .wrap("get identity of created inode")
+    }
+
+    /// Within the [`Root`]'s tree, remove the inode at `path` as with
+    /// [`Root::remove`], returning the identity of the inode which was
+    /// removed.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::remove`]. If the inode that was
+    /// removed doesn't appear to be the one which was at `path` beforehand
+    /// (because of a racing rename), an [`Error::SafetyViolation`] is returned
+    /// after the removal.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::remove`]: struct.Root.html#method.remove
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn remove_audited<P: AsRef<Path>>(&self, path: P) -> Result<ActedOn, Error> {
+        let path = path.as_ref();
+        let (file, id) = self
+            .open_nofollow(path)
+            .wrap("get identity of inode to remove")?;
+        let nlink_before = file
+            .metadata()
+            .context(error::OsError {
+                operation: "get link count of inode to remove",
+            })?
+            .nlink();
+        self.remove(path)?;
+
+        // If we removed the inode we have a handle to, it must have lost a
+        // link.
+        let nlink_after = file
+            .metadata()
+            .context(error::OsError {
+                operation: "get link count of removed inode",
+            })?
+            .nlink();
+        ensure!(
+            nlink_after < nlink_before,
+            error::SafetyViolation {
+                description: "removed inode doesn't match the inode which was at the path",
+            }
+        );
+        Ok(id)
+    }
+
+    /// Within the [`Root`]'s tree, rename `source` to `destination` as with
+    /// [`Root::rename`], returning the identity of the inode which was moved.
+    /// With `RENAME_EXCHANGE`, use [`Root::swap_contents_audited`] instead.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::rename`]. If the inode at
+    /// `destination` after the rename is not the one which was at `source`
+    /// beforehand (because of a racing rename), an [`Error::SafetyViolation`]
+    /// is returned after the rename.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::rename`]: struct.Root.html#method.rename
+    /// [`Root::swap_contents_audited`]: struct.Root.html#method.swap_contents_audited
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn rename_audited<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        flags: RenameFlags,
+    ) -> Result<ActedOn, Error> {
+        let (source, destination) = (source.as_ref(), destination.as_ref());
+        let (_, before) = self
+            .open_nofollow(source)
+            .wrap("get identity of inode to rename")?;
+        self.rename(source, destination, flags)?;
+        let (_, after) = self
+            .open_nofollow(destination)
+            .wrap("get identity of renamed inode")?;
+        check_same(
+            &before,
+            &after,
+            "renamed inode doesn't match the inode which was at the source path",
+        )?;
+        Ok(after)
+    }
+
+    /// Within the [`Root`]'s tree, exchange the inodes at `first` and `second`
+    /// as with [`Root::swap_contents`], returning the identities of the
+    /// inodes which are now at `first` and `second` (respectively).
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::swap_contents`]. If the inodes
+    /// don't appear to have been exchanged (because of a racing rename), an
+    /// [`Error::SafetyViolation`] is returned after the exchange.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::swap_contents`]: struct.Root.html#method.swap_contents
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn swap_contents_audited<P: AsRef<Path>>(
+        &self,
+        first: P,
+        second: P,
+    ) -> Result<(ActedOn, ActedOn), Error> {
+        let (first, second) = (first.as_ref(), second.as_ref());
+        let (_, first_before) = self
+            .open_nofollow(first)
+            .wrap("get identity of first inode to exchange")?;
+        let (_, second_before) = self
+            .open_nofollow(second)
+            .wrap("get identity of second inode to exchange")?;
+        self.swap_contents(first, second)?;
+        let (_, first_after) = self
+            .open_nofollow(first)
+            .wrap("get identity of first exchanged inode")?;
+        let (_, second_after) = self
+            .open_nofollow(second)
+            .wrap("get identity of second exchanged inode")?;
+        check_same(
+            &second_before,
+            &first_after,
+            "inode at first path doesn't match the inode which was at the second path",
+        )?;
+        check_same(
+            &first_before,
+            &second_after,
+            "inode at second path doesn't match the inode which was at the first path",
+        )?;
+        Ok((first_after, second_after))
+    }
+}
@@ -0,0 +1,328 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! A file descriptor broker, where a privileged process holds a [`Root`] and
+//! opens files inside it on behalf of unprivileged workers.
+//!
+//! Requests and responses are sent over a unix stream socket:
+//!
+//! * Requests are `[u32 path length][i32 open flags][path]`, with integers in
+//!   native byte order.
+//! * Responses are a single `i32` status in native byte order. A status of `0`
+//!   indicates success, and the opened file descriptor is attached to the
+//!   response with `SCM_RIGHTS`. Otherwise, the status is the `errno` of the
+//!   failure (`EACCES` if the request was denied by the [`BrokerPolicy`]).
+//!
+//! [`Root`]: ../struct.Root.html
+//! [`BrokerPolicy`]: struct.BrokerPolicy.html
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, OpenFlags, Root,
+};
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Error as IOError, ErrorKind, Read, Write},
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::AsRawFd,
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use libc::c_int;
+use snafu::{OptionExt, ResultExt};
+
+/// Size of a request header (path length and flags).
+const REQUEST_HEADER_SIZE: usize = mem::size_of::<u32>() + mem::size_of::<c_int>();
+
+/// Maximum path length accepted in a request.
+const REQUEST_MAX_PATH: usize = libc::PATH_MAX as usize;
+
+/// Credentials of the process on the other end of a unix socket, as in
+/// `SO_PEERCRED`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PeerCredentials {
+    /// Process ID of the peer (when it connected).
+    pub pid: libc::pid_t,
+    /// Effective user ID of the peer (when it connected).
+    pub uid: libc::uid_t,
+    /// Effective group ID of the peer (when it connected).
+    pub gid: libc::gid_t,
+}
+
+impl PeerCredentials {
+    /// Get the credentials of the peer of `stream`. This can be used by the
+    /// broker to decide which [`BrokerPolicy`] to apply to a client.
+    ///
+    /// [`BrokerPolicy`]: struct.BrokerPolicy.html
+    pub fn of(stream: &UnixStream) -> Result<Self, Error> {
+        let cred =
+            syscalls::getsockopt_peercred(stream.as_raw_fd()).context(error::RawOsError {
+                operation: "get peer credentials",
+            })?;
+        Ok(Self {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+/// Restrictions on the requests a [`Broker`] will serve for a client.
+///
+/// [`Broker`]: struct.Broker.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BrokerPolicy {
+    /// Whether the client may open files for writing (or with `O_TRUNC`).
+    pub allow_write: bool,
+    /// The subtrees of the [`Root`] the client may open files in. If empty,
+    /// the client may open any file in the [`Root`]. These are checked against
+    /// the verified path of the opened file (see [`Root::relative_path_of`]),
+    /// so a client cannot use symlinks to escape its subtrees.
+    ///
+    /// [`Root`]: ../struct.Root.html
+    /// [`Root::relative_path_of`]: ../struct.Root.html#method.relative_path_of
+    pub allowed_subtrees: Vec<PathBuf>,
+}
+
+/// The server side of a file descriptor broker. See the [module
+/// documentation](index.html) for the protocol.
+///
+/// The broker is deliberately minimal -- it doesn't accept connections or
+/// spawn threads. You are responsible for accepting clients (and deciding
+/// their [`BrokerPolicy`], usually based on their [`PeerCredentials`]) and
+/// then calling [`Broker::serve_client`]. Files are opened with
+/// [`Root::reopen`], so the [`Root`]'s `reopen_policy` applies to every client.
+///
+/// [`Root`]: ../struct.Root.html
+/// [`Root::reopen`]: ../struct.Root.html#method.reopen
+/// [`BrokerPolicy`]: struct.BrokerPolicy.html
+/// [`PeerCredentials`]: struct.PeerCredentials.html
+/// [`Broker::serve_client`]: struct.Broker.html#method.serve_client
+#[derive(Debug)]
+pub struct Broker {
+    root: Root,
+}
+
+impl Broker {
+    /// Create a new [`Broker`] which opens files inside `root`.
+    ///
+    /// [`Broker`]: struct.Broker.html
+    pub fn new(root: Root) -> Self {
+        Self { root }
+    }
+
+    /// The [`Root`] which this broker opens files inside.
+    ///
+    /// [`Root`]: ../struct.Root.html
+    #[inline]
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+
+    /// Serve requests from the client on `stream` (with the restrictions in
+    /// `policy`) until the client disconnects.
+    ///
+    /// # Errors
+    ///
+    /// Failed requests are reported to the client and are not errors. An error
+    /// is only returned if communication with the client fails or the client
+    /// sends a malformed request, in which case you should disconnect the
+    /// client.
+    pub fn serve_client(&self, stream: &UnixStream, policy: &BrokerPolicy) -> Result<(), Error> {
+        let mut stream = stream;
+        loop {
+            let mut header = [0u8; REQUEST_HEADER_SIZE];
+            match stream.read(&mut header[..1]) {
+                Ok(0) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                ret => ret.context(error::OsError {
+                    operation: "read broker request",
+                })?,
+            };
+            stream
+                .read_exact(&mut header[1..])
+                .context(error::OsError {
+                    operation: "read broker request header",
+                })?;
+
+            let (len, flags) = header.split_at(mem::size_of::<u32>());
+            let len = u32::from_ne_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let flags = c_int::from_ne_bytes([flags[0], flags[1], flags[2], flags[3]]);
+            ensure!(
+                len <= REQUEST_MAX_PATH,
+                error::InvalidArgument {
+                    name: "request",
+                    description: format!("path length {} is too long", len),
+                }
+            );
+            let mut path = vec![0u8; len];
+            stream.read_exact(&mut path).context(error::OsError {
+                operation: "read broker request path",
+            })?;
+            let path = PathBuf::from(OsString::from_vec(path));
+
+            let (status, file) = match self.handle_request(&path, OpenFlags(flags), policy) {
+                Ok(file) => (0, Some(file)),
+                Err(err) => (err.raw_os_error().unwrap_or(libc::EIO), None),
+            };
+            send_response(stream, status, file.as_ref())?;
+        }
+    }
+
+    fn handle_request(
+        &self,
+        path: &Path,
+        flags: OpenFlags,
+        policy: &BrokerPolicy,
+    ) -> Result<File, Error> {
+        if path.as_os_str().as_bytes().contains(&b'\0')
+            || flags.0 & libc::O_CREAT != 0
+            || flags.0 & libc::O_TMPFILE == libc::O_TMPFILE
+        {
+            return request_error(libc::EINVAL, "validate broker request");
+        }
+        if !policy.allow_write && (flags.wants_write() || flags.0 & libc::O_TRUNC != 0) {
+            return request_error(libc::EACCES, "check broker policy");
+        }
+
+        let handle = self
+            .root
+            .resolve(path)
+            .wrap("resolve broker request path")?;
+        if !policy.allowed_subtrees.is_empty() {
+            let relpath = self
+                .root
+                .relative_path_of(&handle)
+                .wrap("verify broker request path")?;
+            let relpath = relpath.as_unverified_path();
+            if !policy
+                .allowed_subtrees
+                .iter()
+                .any(|subtree| relpath.starts_with(subtree))
+            {
+                return request_error(libc::EACCES, "check broker policy");
+            }
+        }
+        self.root.reopen(&handle, flags)
+    }
+}
+
+/// Fail a broker request with the given `errno`, which is sent to the client.
+fn request_error<T>(errno: c_int, operation: &str) -> Result<T, Error> {
+    Err(IOError::from_raw_os_error(errno)).context(error::OsError { operation })
+}
+
+/// Send a response (with an optional file descriptor) to a broker client.
+fn send_response(stream: &UnixStream, status: c_int, file: Option<&File>) -> Result<(), Error> {
+    let response = status.to_ne_bytes();
+    let sent = syscalls::sendmsg_fd(
+        stream.as_raw_fd(),
+        &response,
+        file.map(|file| file.as_raw_fd()),
+    )
+    .context(error::RawOsError {
+        operation: "send broker response",
+    })?;
+    // The file descriptor was sent with the first byte, so the remainder (if
+    // any) can be sent normally.
+    let mut stream = stream;
+    stream.write_all(&response[sent..]).context(error::OsError {
+        operation: "send broker response",
+    })
+}
+
+/// The client side of a file descriptor broker. See the [module
+/// documentation](index.html) for the protocol.
+#[derive(Debug)]
+pub struct BrokerClient {
+    stream: UnixStream,
+}
+
+impl BrokerClient {
+    /// Create a new [`BrokerClient`] which sends requests over `stream`.
+    ///
+    /// [`BrokerClient`]: struct.BrokerClient.html
+    pub fn new(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Ask the broker to open `path` (inside the broker's [`Root`]) with
+    /// `flags`. `O_CREAT` and `O_TMPFILE` are not supported.
+    ///
+    /// # Errors
+    ///
+    /// If the broker fails to open the file, an [`Error::OsError`] with the
+    /// broker's `errno` is returned.
+    ///
+    /// [`Root`]: ../struct.Root.html
+    /// [`Error::OsError`]: ../error/enum.Error.html#variant.OsError
+    pub fn open<P: AsRef<Path>, F: Into<OpenFlags>>(
+        &mut self,
+        path: P,
+        flags: F,
+    ) -> Result<File, Error> {
+        let path = path.as_ref().as_os_str().as_bytes();
+        ensure!(
+            path.len() <= REQUEST_MAX_PATH,
+            error::InvalidArgument {
+                name: "path",
+                description: "path is too long for a broker request",
+            }
+        );
+        let mut request = Vec::with_capacity(REQUEST_HEADER_SIZE + path.len());
+        request.extend_from_slice(&(path.len() as u32).to_ne_bytes());
+        request.extend_from_slice(&flags.into().0.to_ne_bytes());
+        request.extend_from_slice(path);
+        self.stream.write_all(&request).context(error::OsError {
+            operation: "send broker request",
+        })?;
+
+        let mut response = [0u8; mem::size_of::<c_int>()];
+        let (received, file) = syscalls::recvmsg_fd(self.stream.as_raw_fd(), &mut response)
+            .context(error::RawOsError {
+                operation: "receive broker response",
+            })?;
+        if received == 0 {
+            return Err(IOError::from(ErrorKind::UnexpectedEof)).context(error::OsError {
+                operation: "receive broker response",
+            });
+        }
+        self.stream
+            .read_exact(&mut response[received..])
+            .context(error::OsError {
+                operation: "receive broker response",
+            })?;
+
+        match c_int::from_ne_bytes(response) {
+            0 => file.context(error::SafetyViolation {
+                description: "broker response is missing a file descriptor",
+            }),
+            errno => Err(IOError::from_raw_os_error(errno)).context(error::OsError {
+                operation: "broker open",
+            }),
+        }
+    }
+}
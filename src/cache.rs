@@ -0,0 +1,383 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! An opt-in cache of resolutions, for workloads which resolve the same paths
+//! over and over again (see `Root::resolve_cache_capacity`).
+//!
+//! Each entry maps a requested path to the `O_PATH` handle it resolved to,
+//! along with the (st_dev, st_ino) of the handle and its canonical path inside
+//! the root. Cached handles are never trusted blindly -- on every hit the
+//! readlink(/proc/self/fd/$n) path of the handle must still be the canonical
+//! path inside the root (so that a handle which was moved outside the root is
+//! never returned), and the canonical path is then walked from the root one
+//! component at a time with O_NOFOLLOW and must still lead to the same inode
+//! (so that no component has been swapped for a symlink). If either check
+//! fails, the entry is dropped and the path is resolved from scratch.
+//!
+//! Renames and removals done through the Root drop the affected entries, but
+//! changes made by other processes are only noticed by the checks above. In
+//! particular, if a symlink along a requested path is changed by someone else
+//! the cached handle (still inside the root) will keep being returned until
+//! the entry is invalidated.
+//!
+//! There is also an opt-in cache of failed lookups (see
+//! `Root::negative_cache_capacity`), for workloads which keep probing for
+//! paths which usually don't exist. Entries are keyed by the (st_dev, st_ino)
+//! of the parent directory and the final component, so renaming the parent
+//! doesn't affect them. They expire after a configurable TTL, and are dropped
+//! when an inode with the same name is created through the Root.
+
+use crate::{
+    error::Error,
+    syscalls,
+    utils::{FileExt, RawFdExt},
+    Handle, ResolverFlags,
+};
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Key of a cache entry -- the requested path and the flags it was resolved
+/// with (since those change the result of the resolution).
+pub(crate) type CacheKey = (PathBuf, ResolverFlags);
+
+#[derive(Debug)]
+struct CacheEntry {
+    /// The cached O_PATH handle.
+    file: File,
+    /// (st_dev, st_ino) of `file`.
+    id: (u64, u64),
+    /// The canonical path of `file` inside the root (without a leading "/").
+    canonical: PathBuf,
+    /// Value of the cache clock when the entry was last used.
+    last_used: u64,
+}
+
+/// A cache of resolutions within a single root.
+#[derive(Debug, Default)]
+pub(crate) struct ResolveCache {
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    /// Number of entries, so that disabled caches can be skipped without
+    /// taking the lock.
+    len: AtomicUsize,
+    /// Logical clock used for least-recently-used eviction.
+    clock: AtomicU64,
+}
+
+/// Strip `path` down to the components which matter for resolution. Different
+/// spellings of the same path ("a//b", "./a/b" and "/a/b") are the same after
+/// this, but ".." components are kept since "a/symlink/.." is not necessarily
+/// "a".
+fn clean_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|part| matches!(part, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
+
+/// Get the cache key for resolving `path` with `flags`.
+pub(crate) fn cache_key(path: &Path, flags: ResolverFlags) -> CacheKey {
+    (clean_path(path), flags)
+}
+
+/// Get the canonical path of `file` inside the root at `root_path`.
+fn canonical_path(root_path: &Path, file: &File) -> Result<Option<PathBuf>, Error> {
+    // SAFETY: as_unsafe_path is safe here since the path is only compared
+    //         against the path recorded when the (verified) handle was cached.
+    let path = file.as_unsafe_path()?;
+    Ok(path.strip_prefix(root_path).ok().map(Path::to_path_buf))
+}
+
+/// Get the (st_dev, st_ino) of the inode at `canonical` inside `root`, without
+/// following symlinks in any component. Returns `None` if the path doesn't
+/// exist or passes through a symlink.
+fn canonical_id(root: &File, canonical: &Path) -> Result<Option<(u64, u64)>, Error> {
+    let mut current: Option<File> = None;
+    let mut components = canonical.components().peekable();
+    while let Some(component) = components.next() {
+        let name = match component {
+            Component::Normal(name) => name,
+            // Canonical paths only contain names.
+            _ => return Ok(None),
+        };
+        // syscalls::openat always adds O_NOFOLLOW.
+        let mut flags = libc::O_PATH;
+        if components.peek().is_some() {
+            flags |= libc::O_DIRECTORY;
+        }
+        let dirfd = current
+            .as_ref()
+            .map_or(root.as_raw_fd(), AsRawFd::as_raw_fd);
+        match syscalls::openat(dirfd, name, flags, 0) {
+            Ok(file) => current = Some(file),
+            Err(_) => return Ok(None),
+        }
+    }
+    current.as_ref().unwrap_or(root).inode_id().map(Some)
+}
+
+impl ResolveCache {
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<CacheKey, CacheEntry>> {
+        // The map is always left in a consistent state, so a panic in another
+        // thread doesn't matter.
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Is the cached handle still the inode at its canonical path inside
+    /// `root`?
+    fn revalidate(root: &File, entry: &CacheEntry) -> Result<bool, Error> {
+        let root_path = root.as_unsafe_path()?;
+        if canonical_path(&root_path, &entry.file)?.as_ref() != Some(&entry.canonical) {
+            return Ok(false);
+        }
+        Ok(canonical_id(root, &entry.canonical)? == Some(entry.id))
+    }
+
+    /// Look up `key` in the cache, returning a new handle to the cached inode
+    /// if it is still valid.
+    pub(crate) fn lookup(&self, root: &File, key: &CacheKey) -> Option<Handle> {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let mut entries = self.entries();
+        let valid = match entries.get(key) {
+            None => return None,
+            Some(entry) => Self::revalidate(root, entry).unwrap_or(false),
+        };
+        if !valid {
+            entries.remove(key);
+            self.len.store(entries.len(), Ordering::Relaxed);
+            return None;
+        }
+        let now = self.tick();
+        let entry = entries.get_mut(key)?;
+        entry.last_used = now;
+        entry
+            .file
+            .try_clone_hotfix()
+            .ok()
+            .map(Handle::from_file_unchecked)
+    }
+
+    /// Add the freshly resolved `handle` to the cache as `key`, evicting the
+    /// least-recently-used entries to stay within `capacity`. Caching is
+    /// best-effort, so errors are ignored.
+    pub(crate) fn insert(&self, root: &File, key: CacheKey, handle: &Handle, capacity: usize) {
+        let entry = (|| -> Result<Option<CacheEntry>, Error> {
+            let root_path = root.as_unsafe_path()?;
+            let canonical = match canonical_path(&root_path, &handle.inner)? {
+                Some(canonical) => canonical,
+                None => return Ok(None),
+            };
+            Ok(Some(CacheEntry {
+                file: handle.inner.try_clone_hotfix()?,
+                id: handle.inner.inode_id()?,
+                canonical,
+                last_used: self.tick(),
+            }))
+        })();
+        let entry = match entry {
+            Ok(Some(entry)) => entry,
+            _ => return,
+        };
+
+        let mut entries = self.entries();
+        while !entries.contains_key(&key) && entries.len() >= capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => return,
+            };
+        }
+        entries.insert(key, entry);
+        self.len.store(entries.len(), Ordering::Relaxed);
+    }
+
+    /// Drop every entry whose requested or canonical path is `path` (or is
+    /// underneath it).
+    pub(crate) fn invalidate(&self, path: &Path) {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let path = clean_path(path);
+        let mut entries = self.entries();
+        entries.retain(|(requested, _), entry| {
+            !requested.starts_with(&path) && !entry.canonical.starts_with(&path)
+        });
+        self.len.store(entries.len(), Ordering::Relaxed);
+    }
+
+    /// Drop every entry.
+    pub(crate) fn clear(&self) {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        entries.clear();
+        self.len.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Key of a negative cache entry -- the (st_dev, st_ino) of the parent
+/// directory, the final component and the flags of the failed resolution.
+pub(crate) type NegativeKey = ((u64, u64), OsString, ResolverFlags);
+
+/// Get the negative cache key for `name` inside the directory `parent`.
+pub(crate) fn negative_key(
+    parent: &Handle,
+    name: &Path,
+    flags: ResolverFlags,
+) -> Result<NegativeKey, Error> {
+    Ok((
+        parent.inner.inode_id()?,
+        name.as_os_str().to_os_string(),
+        flags,
+    ))
+}
+
+/// A cache of (recently) failed lookups within a single root.
+#[derive(Debug, Default)]
+pub(crate) struct NegativeCache {
+    /// When each entry was added.
+    entries: Mutex<HashMap<NegativeKey, Instant>>,
+    /// Number of entries, so that disabled caches can be skipped without
+    /// taking the lock.
+    len: AtomicUsize,
+}
+
+impl NegativeCache {
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<NegativeKey, Instant>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Is there any entry for `name` (in any directory)? This lets lookups
+    /// skip resolving the parent directory in the common case.
+    pub(crate) fn contains_name(&self, name: &OsStr) -> bool {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        self.entries().keys().any(|(_, entry, _)| entry == name)
+    }
+
+    /// Is `key` a failed lookup which happened less than `ttl` ago?
+    pub(crate) fn lookup(&self, key: &NegativeKey, ttl: Duration) -> bool {
+        let mut entries = self.entries();
+        let fresh = match entries.get(key) {
+            None => return false,
+            Some(added) => added.elapsed() < ttl,
+        };
+        if !fresh {
+            entries.remove(key);
+            self.len.store(entries.len(), Ordering::Relaxed);
+        }
+        fresh
+    }
+
+    /// Record the failed lookup `key`, evicting expired (and then the oldest)
+    /// entries to stay within `capacity`.
+    pub(crate) fn insert(&self, key: NegativeKey, ttl: Duration, capacity: usize) {
+        let mut entries = self.entries();
+        if !entries.contains_key(&key) && entries.len() >= capacity {
+            entries.retain(|_, added| added.elapsed() < ttl);
+        }
+        while !entries.contains_key(&key) && entries.len() >= capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, added)| **added)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => return,
+            };
+        }
+        entries.insert(key, Instant::now());
+        self.len.store(entries.len(), Ordering::Relaxed);
+    }
+
+    /// Drop every entry for `name` (in any directory).
+    pub(crate) fn invalidate(&self, name: &OsStr) {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        entries.retain(|(_, entry, _), _| entry != name);
+        self.len.store(entries.len(), Ordering::Relaxed);
+    }
+
+    /// Drop every entry.
+    pub(crate) fn clear(&self) {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        entries.clear();
+        self.len.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonical_id;
+    use crate::{tests::TempDir, utils::FileExt};
+
+    use std::{
+        fs::{self, File},
+        os::unix::fs::symlink,
+        path::Path,
+    };
+
+    #[test]
+    fn canonical_id_nofollow() {
+        let tmpdir = TempDir::new();
+        fs::create_dir_all(tmpdir.path().join("a/b")).unwrap();
+        symlink("a", tmpdir.path().join("link")).unwrap();
+        symlink("b", tmpdir.path().join("a/blink")).unwrap();
+        let root = File::open(tmpdir.path()).unwrap();
+        let b = File::open(tmpdir.path().join("a/b")).unwrap();
+
+        let id = Some(b.inode_id().unwrap());
+        assert_eq!(canonical_id(&root, Path::new("a/b")).unwrap(), id);
+        assert_eq!(
+            canonical_id(&root, Path::new("")).unwrap(),
+            Some(root.inode_id().unwrap())
+        );
+        // The same inode, but reached through a symlink.
+        assert_eq!(canonical_id(&root, Path::new("link/b")).unwrap(), None);
+        // A trailing symlink is the symlink itself, not its target.
+        assert_ne!(canonical_id(&root, Path::new("a/blink")).unwrap(), id);
+        assert_eq!(canonical_id(&root, Path::new("a/missing")).unwrap(), None);
+    }
+}
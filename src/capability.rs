@@ -0,0 +1,250 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error},
+    utils::FileExt,
+    Handle,
+};
+
+use std::{convert::TryInto, ffi::OsStr};
+
+use snafu::ResultExt;
+
+/// Name of the extended attribute storing file capabilities.
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// `VFS_CAP_REVISION_MASK` from `<linux/capability.h>`.
+const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+/// `VFS_CAP_FLAGS_EFFECTIVE` from `<linux/capability.h>`.
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+/// `VFS_CAP_REVISION_1`, with 32-bit capability sets.
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+/// `VFS_CAP_REVISION_2`, with 64-bit capability sets.
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+/// `VFS_CAP_REVISION_3`, which adds the root user of the user namespace the
+/// capabilities apply to.
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+
+/// Size of each revision of `struct vfs_ns_cap_data`.
+const XATTR_CAPS_SZ_1: usize = 12;
+const XATTR_CAPS_SZ_2: usize = 20;
+const XATTR_CAPS_SZ_3: usize = 24;
+
+/// The file capabilities of a file, as stored in the `security.capability`
+/// extended attribute.
+///
+/// Capabilities are given by their number (such as `10` for
+/// `CAP_NET_BIND_SERVICE`), and the sets are bitmasks of `1 << cap`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct FileCapabilities {
+    /// The permitted set of capabilities.
+    pub permitted: u64,
+    /// The inheritable set of capabilities.
+    pub inheritable: u64,
+    /// Whether the permitted capabilities are also raised in the effective set
+    /// on `execve(2)` (for programs which are not capability-aware).
+    pub effective: bool,
+    /// The (host) uid of the root user of the user namespace in which the
+    /// capabilities apply, or `None` for capabilities which apply in every
+    /// user namespace. This selects between the v3 format (used by the kernel
+    /// for capabilities set from within a user namespace) and the v2 format.
+    pub rootid: Option<u32>,
+}
+
+/// Convert the capability numbers `caps` into a bitmask.
+fn capability_mask(caps: &[u32]) -> Result<u64, Error> {
+    caps.iter().try_fold(0, |mask, &cap| {
+        ensure!(
+            cap < 64,
+            error::InvalidArgument {
+                name: "caps",
+                description: format!("capability {} is out of range", cap),
+            }
+        );
+        Ok(mask | 1 << cap)
+    })
+}
+
+/// Read the little-endian `u32` at `idx` (in units of `u32`) of `value`.
+fn le32(value: &[u8], idx: usize) -> u32 {
+    let offset = idx * 4;
+    u32::from_le_bytes(
+        value[offset..offset + 4]
+            .try_into()
+            .expect("slice of 4 bytes must fit in [u8; 4]"),
+    )
+}
+
+impl FileCapabilities {
+    /// Create a new [`FileCapabilities`] which grants `caps` (that is, with
+    /// `caps` in the permitted set and the effective flag set), so that they
+    /// are available to programs which are not capability-aware.
+    ///
+    /// # Errors
+    ///
+    /// If any of `caps` is not a valid capability number, an
+    /// [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`FileCapabilities`]: struct.FileCapabilities.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn new(caps: &[u32]) -> Result<Self, Error> {
+        Ok(Self {
+            permitted: capability_mask(caps)?,
+            effective: true,
+            ..Default::default()
+        })
+    }
+
+    /// Decode the value of a `security.capability` extended attribute (in
+    /// any of the v1, v2 or v3 formats).
+    ///
+    /// # Errors
+    ///
+    /// If `value` is not a valid capability extended attribute, an
+    /// [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn from_bytes(value: &[u8]) -> Result<Self, Error> {
+        let invalid = |description: &str| {
+            error::InvalidArgument {
+                name: "value",
+                description,
+            }
+            .fail()
+        };
+        if value.len() < 4 {
+            return invalid("capability xattr is too short");
+        }
+        let magic = le32(value, 0);
+        let (size, wide) = match magic & VFS_CAP_REVISION_MASK {
+            VFS_CAP_REVISION_1 => (XATTR_CAPS_SZ_1, false),
+            VFS_CAP_REVISION_2 => (XATTR_CAPS_SZ_2, true),
+            VFS_CAP_REVISION_3 => (XATTR_CAPS_SZ_3, true),
+            _ => return invalid("unknown capability xattr revision"),
+        };
+        if value.len() != size {
+            return invalid("capability xattr has the wrong size for its revision");
+        }
+        let (mut permitted, mut inheritable) = (le32(value, 1) as u64, le32(value, 2) as u64);
+        if wide {
+            permitted |= (le32(value, 3) as u64) << 32;
+            inheritable |= (le32(value, 4) as u64) << 32;
+        }
+        Ok(Self {
+            permitted,
+            inheritable,
+            effective: magic & VFS_CAP_FLAGS_EFFECTIVE != 0,
+            rootid: if size == XATTR_CAPS_SZ_3 {
+                Some(le32(value, 5))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Encode the capabilities as the value of a `security.capability`
+    /// extended attribute (in the v3 format if `rootid` is set, and the v2
+    /// format otherwise).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let revision = match self.rootid {
+            Some(_) => VFS_CAP_REVISION_3,
+            None => VFS_CAP_REVISION_2,
+        };
+        let flags = if self.effective {
+            VFS_CAP_FLAGS_EFFECTIVE
+        } else {
+            0
+        };
+        let mut words = vec![
+            revision | flags,
+            self.permitted as u32,
+            self.inheritable as u32,
+            (self.permitted >> 32) as u32,
+            (self.inheritable >> 32) as u32,
+        ];
+        words.extend(self.rootid);
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    /// Whether `cap` is in the permitted set.
+    pub fn is_permitted(&self, cap: u32) -> bool {
+        cap < 64 && self.permitted & 1 << cap != 0
+    }
+}
+
+impl Handle {
+    /// Get the file capabilities of the file, or `None` if it has none.
+    ///
+    /// # Errors
+    ///
+    /// If the `security.capability` extended attribute of the file is
+    /// malformed, an [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn capabilities(&self) -> Result<Option<FileCapabilities>, Error> {
+        match self.inner.get_xattr(OsStr::new(CAPABILITY_XATTR)) {
+            Ok(value) => FileCapabilities::from_bytes(&value).map(Some),
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the file capabilities of the file, which must be a regular file.
+    /// This requires `CAP_SETFCAP`.
+    ///
+    /// When called from within a user namespace, the kernel converts v2
+    /// capabilities into v3 capabilities for the root user of the caller's
+    /// user namespace (and only permits setting a `rootid` which maps to it).
+    ///
+    /// # Errors
+    ///
+    /// If the file is not a regular file, an [`Error::InvalidArgument`] is
+    /// returned.
+    ///
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_capabilities(&self, caps: &FileCapabilities) -> Result<(), Error> {
+        let is_file = self
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "check inode type for file capabilities",
+            })?
+            .is_file();
+        ensure!(
+            is_file,
+            error::InvalidArgument {
+                name: "handle",
+                description: "file capabilities can only be set on regular files",
+            }
+        );
+        self.inner
+            .set_xattr(OsStr::new(CAPABILITY_XATTR), &caps.to_bytes())
+    }
+
+    /// Remove the file capabilities of the file (if it has any).
+    pub fn remove_capabilities(&self) -> Result<(), Error> {
+        match self.inner.remove_xattr(OsStr::new(CAPABILITY_XATTR)) {
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+            ret => ret,
+        }
+    }
+}
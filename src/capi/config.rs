@@ -73,12 +73,12 @@ impl From<ResolverBackend> for CResolver {
     }
 }
 
-impl Into<ResolverBackend> for CResolver {
-    fn into(self) -> ResolverBackend {
-        match self {
+impl From<CResolver> for ResolverBackend {
+    fn from(other: CResolver) -> Self {
+        match other {
             CResolver::PATHRS_KERNEL_RESOLVER => ResolverBackend::Kernel,
             CResolver::PATHRS_EMULATED_RESOLVER => ResolverBackend::Emulated,
-            _ => panic!("invalid resolver: {:?}", self),
+            _ => panic!("invalid resolver: {:?}", other),
         }
     }
 }
@@ -91,7 +91,8 @@ pub struct CRootConfig {
     /// Resolver used for all resolution under this `pathrs_root_t`.
     pub resolver: CResolver,
     /// Flags to pass to resolver. These must be valid `RESOLVE_*` flags. At
-    /// time of writing, only `RESOLVE_NO_SYMLINKS` is supported.
+    /// time of writing, only `RESOLVE_NO_SYMLINKS` is supported -- as well as the
+    /// libpathrs-specific `(1 << 32)`, which enables case-insensitive lookups.
     pub flags: u64,
 }
 
@@ -323,14 +324,14 @@ pub extern "C" fn pathrs_configure(
                 //      to touch root.inner directly (because we return a CError
                 //      rather than setting the error inside the CRoot).
                 let mut root = obj.inner.write().unwrap();
-                let mut root = root.as_mut().context(error::InvalidArgument {
+                let root = root.as_mut().context(error::InvalidArgument {
                     name: "ptr",
                     description: "invalid pathrs object",
                 })?;
 
                 if !old_cfg_ptr.is_null() {
                     let mut old_cfg = CRootConfig::default();
-                    old_cfg.fetch(&root)?;
+                    old_cfg.fetch(root)?;
                     copy_struct_out(&old_cfg, old_cfg_ptr, cfg_size)
                         .wrap("copy libpathrs config to caller old_cfg_ptr")?;
                 }
@@ -338,7 +339,7 @@ pub extern "C" fn pathrs_configure(
                     let mut new_cfg = CRootConfig::default();
                     copy_struct_in(&mut new_cfg, new_cfg_ptr, cfg_size)
                         .wrap("copy caller new_cfg_ptr to libpathrs config")?;
-                    new_cfg.apply(&mut root)?;
+                    new_cfg.apply(root)?;
                 }
             }
             _ => {
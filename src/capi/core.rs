@@ -169,10 +169,7 @@ pub extern "C" fn pathrs_mknod(
             libc::S_IFBLK => InodeType::BlockDevice(&perms, dev),
             libc::S_IFCHR => InodeType::CharacterDevice(&perms, dev),
             libc::S_IFIFO => InodeType::Fifo(&perms),
-            libc::S_IFSOCK => error::NotImplemented {
-                feature: "mknod(S_IFSOCK)",
-            }
-            .fail()?,
+            libc::S_IFSOCK => InodeType::DetachedSocket(&perms),
             _ => error::InvalidArgument {
                 name: "mode",
                 description: "invalid S_IFMT mask",
@@ -86,10 +86,10 @@ pub(crate) trait Leakable {
 /// of the Rust compiler (you cannot have default trait methods that use Self
 /// directly, because the size of Self is not known by the trait).
 ///
-/// ```
-/// leakable!{ impl Leakable for CError; }
-/// leakable!{ impl<T> Leakable for CVec<T>; }
-/// ```
+/// The macro is crate-private, so it can't be used from a doctest. Usage looks
+/// like `leakable!{ impl Leakable for CError; }` (or `leakable!{ impl<T>
+/// Leakable for CVec<T>; }` for generic types), and both forms are exercised
+/// by the tests at the bottom of this file.
 macro_rules! leakable {
     // Inner implementation.
     (...) => {
@@ -214,7 +214,7 @@ impl<T> CPointer<T> {
                 name: "ptr",
                 description: "invalid pathrs object",
             })?;
-            func(&inner)
+            func(inner)
         })
     }
 
@@ -410,6 +410,80 @@ impl From<Backtrace> for CBacktrace {
     }
 }
 
+/// `pathrs_error_t.error_code` of errors caused by libpathrs detecting a
+/// safety violation during an operation (such as an attack). Codes below
+/// `-4096` have no errno(3) equivalent and are reserved for libpathrs.
+pub const PATHRS_ESAFETY: i64 = -4097;
+
+/// `pathrs_error_t.error_code` of errors caused by a feature not being
+/// supported by the running kernel.
+pub const PATHRS_ENOTSUPPORTED: i64 = -4098;
+
+/// `pathrs_error_t.error_code` of errors caused by a feature not being
+/// implemented by libpathrs.
+pub const PATHRS_ENOTIMPLEMENTED: i64 = -4099;
+
+/// `pathrs_error_t.error_code` of errors which have no errno(3) equivalent
+/// and do not fit any other code.
+pub const PATHRS_EUNKNOWN: i64 = -4100;
+
+/// Translate `err` into its stable `pathrs_error_t.error_code`.
+///
+/// The kind of error is the outermost error in the chain which is not just
+/// wrapping another error (with additional context or as the source of an OS
+/// error). The mapping is:
+///
+/// | Error              | Code                                             |
+/// | ------------------ | ------------------------------------------------ |
+/// | `SafetyViolation`  | `PATHRS_ESAFETY`                                 |
+/// | `NotSupported`     | `PATHRS_ENOTSUPPORTED`                           |
+/// | `NotImplemented`   | `PATHRS_ENOTIMPLEMENTED`                         |
+/// | `InvalidArgument`  | `-EINVAL`                                        |
+/// | `Conflict`         | `-EEXIST`                                        |
+/// | `WouldEscape`      | `-EXDEV`                                         |
+/// | `CrossDevice`      | `-EXDEV`                                         |
+/// | `NotADirectory`    | `-ENOTDIR`                                       |
+/// | `IsADirectory`     | `-EISDIR`                                        |
+/// | `StaleRoot`        | `-EBADF`                                         |
+/// | `TooManyOpenFiles` | `-EMFILE` or `-ENFILE`                           |
+/// | `Timeout`          | `-ETIMEDOUT`                                     |
+/// | `ResolutionBudgetExceeded` | `-ELOOP`                                 |
+/// | any other error    | `-errno` of the root cause, or `PATHRS_EUNKNOWN` |
+// NOTE: This mapping is part of the API, existing entries must not change.
+pub(crate) fn error_code(err: &Error) -> i64 {
+    let kind = err
+        .iter_chain_hotfix()
+        .filter_map(|err| err.downcast_ref::<Error>())
+        .find(|err| {
+            !matches!(
+                err,
+                Error::Wrapped { .. } | Error::OsError { .. } | Error::RawOsError { .. }
+            )
+        });
+    let errno = match kind {
+        Some(Error::SafetyViolation { .. }) => return PATHRS_ESAFETY,
+        Some(Error::NotSupported { .. }) => return PATHRS_ENOTSUPPORTED,
+        Some(Error::NotImplemented { .. }) => return PATHRS_ENOTIMPLEMENTED,
+        Some(Error::InvalidArgument { .. }) => libc::EINVAL,
+        Some(Error::Conflict { .. }) => libc::EEXIST,
+        Some(Error::WouldEscape { .. }) | Some(Error::CrossDevice { .. }) => libc::EXDEV,
+        Some(Error::NotADirectory { .. }) => libc::ENOTDIR,
+        Some(Error::IsADirectory { .. }) => libc::EISDIR,
+        Some(Error::StaleRoot { .. }) => libc::EBADF,
+        Some(Error::TooManyOpenFiles { .. }) => err.raw_os_error().unwrap_or(libc::EMFILE),
+        Some(Error::Timeout { .. }) => libc::ETIMEDOUT,
+        Some(Error::ResolutionBudgetExceeded { .. }) => libc::ELOOP,
+        Some(Error::Wrapped { .. })
+        | Some(Error::OsError { .. })
+        | Some(Error::RawOsError { .. })
+        | None => match err.raw_os_error() {
+            Some(errno) => errno,
+            None => return PATHRS_EUNKNOWN,
+        },
+    };
+    -i64::from(errno.abs())
+}
+
 /// Attempts to represent a Rust Error type in C. This structure must be freed
 /// using `pathrs_free(PATHRS_ERROR)`.
 // NOTE: This API is exposed to library users in a read-only manner with memory
@@ -429,6 +503,12 @@ pub struct CError {
     /// Backtrace captured at the error site (or NULL if backtraces have been
     /// disabled at libpathrs build-time or through an environment variable).
     pub backtrace: Option<&'static mut CBacktrace>,
+
+    /// Stable error code of the error: either a negative errno(3) value
+    /// (such as `-ENOENT`) or one of the libpathrs-specific codes below
+    /// `-4096` (such as `PATHRS_ESAFETY`). Unlike `saved_errno`, this is
+    /// never 0.
+    pub error_code: i64,
 }
 
 leakable! {
@@ -442,16 +522,17 @@ impl From<&Error> for CError {
     /// an IOError then errno is populated with that value.
     fn from(err: &Error) -> Self {
         let desc = err.iter_chain_hotfix().fold(String::new(), |mut s, next| {
-            if s != "" {
+            if !s.is_empty() {
                 s.push_str(": ");
             }
             s.push_str(&next.to_string());
             s
         });
 
-        // Create a C-compatible string for CError.description.
-        let desc =
-            CString::new(desc).expect("CString::new(description) failed in CError generation");
+        // Create a C-compatible string for CError.description. Descriptions
+        // can include arbitrary user-provided data, so escape any nul bytes.
+        let desc = CString::new(desc.replace('\0', "\\0"))
+            .expect("CString::new(description) failed in CError generation");
 
         let errno = match err.root_cause().downcast_ref::<IOError>() {
             Some(err) => err.raw_os_error().unwrap_or(0).abs(),
@@ -465,6 +546,7 @@ impl From<&Error> for CError {
                 .cloned()
                 .map(CBacktrace::from)
                 .map(Leakable::leak),
+            error_code: error_code(err),
         }
     }
 }
@@ -565,3 +647,168 @@ pub extern "C" fn pathrs_free(ptr_type: CPointerType, ptr: *const c_void) {
         _ => panic!("invalid ptr_type: {:?}", ptr_type),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Plain(u32);
+    leakable! { impl Leakable for Plain; }
+
+    #[derive(Debug, PartialEq)]
+    struct Generic<T>(T);
+    leakable! { impl<T> Leakable for Generic<T>; }
+
+    #[test]
+    fn leakable_roundtrip() {
+        let leaked = Plain(42).leak();
+        assert_eq!(leaked.unleak(), Plain(42));
+
+        let leaked = Generic("value").leak();
+        assert_eq!(leaked.unleak(), Generic("value"));
+        Generic(vec![1, 2, 3]).leak().free();
+    }
+
+    #[test]
+    fn error_code_constants() {
+        // These values are part of the C API, and must never change.
+        assert_eq!(PATHRS_ESAFETY, -4097);
+        assert_eq!(PATHRS_ENOTSUPPORTED, -4098);
+        assert_eq!(PATHRS_ENOTIMPLEMENTED, -4099);
+        assert_eq!(PATHRS_EUNKNOWN, -4100);
+    }
+
+    #[test]
+    fn error_code_mapping() {
+        use crate::{error::ErrorExt, syscalls};
+        use snafu::ResultExt;
+        use std::time::Duration;
+
+        fn fail<S: snafu::IntoError<Error, Source = snafu::NoneError>>(selector: S) -> Error {
+            Err::<(), _>(snafu::NoneError)
+                .context(selector)
+                .unwrap_err()
+        }
+        let inner = || {
+            fail(error::InvalidArgument {
+                name: "inner",
+                description: "inner",
+            })
+        };
+
+        let cases = vec![
+            (
+                fail(error::SafetyViolation { description: "x" }),
+                PATHRS_ESAFETY,
+            ),
+            (
+                fail(error::NotSupported { feature: "x" }),
+                PATHRS_ENOTSUPPORTED,
+            ),
+            (
+                fail(error::NotImplemented { feature: "x" }),
+                PATHRS_ENOTIMPLEMENTED,
+            ),
+            (
+                fail(error::InvalidArgument {
+                    name: "x",
+                    description: "x",
+                }),
+                -i64::from(libc::EINVAL),
+            ),
+            (
+                fail(error::Conflict {
+                    path: "x",
+                    description: "x",
+                }),
+                -i64::from(libc::EEXIST),
+            ),
+            (
+                fail(error::WouldEscape {
+                    path: "x",
+                    target: "y",
+                }),
+                -i64::from(libc::EXDEV),
+            ),
+            (
+                fail(error::CrossDevice {
+                    first: "x",
+                    second: "y",
+                }),
+                -i64::from(libc::EXDEV),
+            ),
+            (
+                Err::<(), _>(inner())
+                    .context(error::NotADirectory { component: "x" })
+                    .unwrap_err(),
+                -i64::from(libc::ENOTDIR),
+            ),
+            (
+                Err::<(), _>(inner())
+                    .context(error::IsADirectory { path: "x" })
+                    .unwrap_err(),
+                -i64::from(libc::EISDIR),
+            ),
+            (
+                fail(error::StaleRoot {
+                    fd: 3,
+                    description: "x",
+                }),
+                -i64::from(libc::EBADF),
+            ),
+            (
+                Err::<(), _>(IOError::from_raw_os_error(libc::ENFILE))
+                    .context(error::TooManyOpenFiles {
+                        operation: "x",
+                        limit: 1u64,
+                    })
+                    .unwrap_err(),
+                -i64::from(libc::ENFILE),
+            ),
+            (
+                fail(error::Timeout {
+                    operation: "x",
+                    timeout: Duration::from_secs(1),
+                }),
+                -i64::from(libc::ETIMEDOUT),
+            ),
+            (
+                fail(error::ResolutionBudgetExceeded {
+                    path: "x",
+                    steps: 1u64,
+                    elapsed: Duration::from_secs(1),
+                }),
+                -i64::from(libc::ELOOP),
+            ),
+            (
+                Err::<(), _>(IOError::from_raw_os_error(libc::ENOENT))
+                    .context(error::OsError { operation: "x" })
+                    .unwrap_err(),
+                -i64::from(libc::ENOENT),
+            ),
+            (
+                syscalls::openat(libc::AT_FDCWD, "/nonexistent/pathrs", libc::O_PATH, 0)
+                    .context(error::RawOsError { operation: "x" })
+                    .unwrap_err(),
+                -i64::from(libc::ENOENT),
+            ),
+            (
+                Err::<(), _>(IOError::other("x"))
+                    .context(error::OsError { operation: "x" })
+                    .unwrap_err(),
+                PATHRS_EUNKNOWN,
+            ),
+        ];
+
+        for (err, code) in cases {
+            assert_eq!(error_code(&err), code, "{}", err);
+            // Additional context doesn't change the code.
+            let err = Err::<(), _>(err).wrap("context").unwrap_err();
+            assert_eq!(error_code(&err), code, "{}", err);
+            let err = Err::<(), _>(err).wrap("more context").unwrap_err();
+            assert_eq!(error_code(&err), code, "{}", err);
+            assert_eq!(CError::from(&err).error_code, code, "{}", err);
+        }
+    }
+}
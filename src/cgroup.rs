@@ -0,0 +1,347 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Verified access to cgroup2 hierarchies.
+//!
+//! Container managers create cgroups and write controller files in paths
+//! which are (at least partially) controlled by the workloads inside them,
+//! since cgroup delegation hands part of the hierarchy to the container. A
+//! [`CgroupRoot`] is a [`Root`] which has been verified to be a directory in
+//! a cgroup2 hierarchy, and every file opened through it is checked to be on
+//! the same cgroup2 mount.
+//!
+//! [`CgroupRoot`]: struct.CgroupRoot.html
+//! [`Root`]: ../struct.Root.html
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    stat, syscalls, Handle, OpenFlags, Root,
+};
+
+use std::{
+    fmt,
+    fs::{File, Permissions},
+    io::{Read, Write},
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Component, Path},
+};
+
+use snafu::ResultExt;
+
+/// `f_type` of cgroup2.
+const CGROUP2_SUPER_MAGIC: libc::__fsword_t = 0x6367_7270;
+
+/// Permissions used for cgroups created by [`CgroupRoot::create_child`].
+///
+/// [`CgroupRoot::create_child`]: struct.CgroupRoot.html#method.create_child
+const CGROUP_DIRECTORY_MODE: u32 = 0o755;
+
+/// A [`Root`] which has been verified to be a directory within a cgroup2
+/// hierarchy (not necessarily the root of the hierarchy, so that delegated
+/// subtrees can be used).
+///
+/// Cgroups are given as paths relative to the [`CgroupRoot`] (with the
+/// [`CgroupRoot`] itself being `""` or `"/"`), and are resolved with the
+/// scoping rules of a [`Root`].
+///
+/// [`Root`]: ../struct.Root.html
+/// [`CgroupRoot`]: struct.CgroupRoot.html
+#[derive(Debug)]
+pub struct CgroupRoot {
+    root: Root,
+    /// Id of the cgroup2 mount, if the kernel provides it (`stx_mnt_id`).
+    mnt_id: Option<u64>,
+}
+
+/// Check that `file` is on cgroup2.
+fn check_cgroup2(file: &File) -> Result<(), Error> {
+    let fs_type = syscalls::fstatfs(file.as_raw_fd())
+        .context(error::RawOsError {
+            operation: "check filesystem of cgroup file",
+        })?
+        .f_type;
+    ensure!(
+        fs_type == CGROUP2_SUPER_MAGIC,
+        error::SafetyViolation {
+            description: format!("cgroup file is on a filesystem with f_type 0x{:X}", fs_type),
+        }
+    );
+    Ok(())
+}
+
+/// Reject interface file names which are not a single path component.
+fn check_file_name(file: &str) -> Result<(), Error> {
+    let mut components = Path::new(file).components();
+    ensure!(
+        matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ),
+        error::InvalidArgument {
+            name: "file",
+            description: "must be the name of a single cgroup interface file",
+        }
+    );
+    Ok(())
+}
+
+impl CgroupRoot {
+    /// Open the directory at `path` in a cgroup2 hierarchy (such as
+    /// `/sys/fs/cgroup`, or a cgroup delegated to a container) as a
+    /// [`CgroupRoot`].
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not on a cgroup2 filesystem, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`CgroupRoot`]: struct.CgroupRoot.html
+    /// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_root(Root::open(path)?)
+    }
+
+    /// Verify that `root` is a directory within a cgroup2 hierarchy and wrap
+    /// it in a [`CgroupRoot`].
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`CgroupRoot::open`].
+    ///
+    /// [`CgroupRoot`]: struct.CgroupRoot.html
+    /// [`CgroupRoot::open`]: struct.CgroupRoot.html#method.open
+    pub fn from_root(root: Root) -> Result<Self, Error> {
+        check_cgroup2(&root.inner)?;
+        let mnt_id = stat::mount_id(root.inner.as_raw_fd())?;
+        Ok(Self { root, mnt_id })
+    }
+
+    /// The underlying [`Root`].
+    ///
+    /// [`Root`]: ../struct.Root.html
+    #[inline]
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+
+    /// Unwrap the [`CgroupRoot`] to get the underlying [`Root`].
+    ///
+    /// [`CgroupRoot`]: struct.CgroupRoot.html
+    /// [`Root`]: ../struct.Root.html
+    #[inline]
+    pub fn into_root(self) -> Root {
+        self.root
+    }
+
+    /// Check that `file` is on the same cgroup2 mount as the root.
+    fn verify(&self, file: &File) -> Result<(), Error> {
+        check_cgroup2(file)?;
+        if let (Some(expected), Some(actual)) = (self.mnt_id, stat::mount_id(file.as_raw_fd())?) {
+            ensure!(
+                expected == actual,
+                error::SafetyViolation {
+                    description: format!(
+                        "cgroup file is on mount {} rather than the cgroup2 mount {}",
+                        actual, expected
+                    ),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Open the interface file `file` (such as `memory.max`) of `cgroup`.
+    fn open_file(&self, cgroup: &Path, file: &str, flags: libc::c_int) -> Result<File, Error> {
+        check_file_name(file)?;
+        let file = self.root.open_file(cgroup.join(file), OpenFlags(flags))?;
+        self.verify(&file)?;
+        Ok(file)
+    }
+
+    /// Resolve `cgroup`, returning an `O_PATH` [`Handle`] to its directory.
+    ///
+    /// # Errors
+    ///
+    /// If the resolved directory is not on the same cgroup2 mount as the
+    /// [`CgroupRoot`], an [`Error::SafetyViolation`] is returned. Otherwise,
+    /// the errors are identical to [`Root::resolve`].
+    ///
+    /// [`Handle`]: ../struct.Handle.html
+    /// [`CgroupRoot`]: struct.CgroupRoot.html
+    /// [`Root::resolve`]: ../struct.Root.html#method.resolve
+    /// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+    pub fn resolve<P: AsRef<Path>>(&self, cgroup: P) -> Result<Handle, Error> {
+        let handle = self.root.resolve(cgroup)?;
+        self.verify(&handle.inner)?;
+        Ok(handle)
+    }
+
+    /// Create the cgroup `cgroup` (if it doesn't already exist), returning an
+    /// `O_PATH` [`Handle`] to its directory. The parent cgroup must already
+    /// exist.
+    ///
+    /// # Errors
+    ///
+    /// If something other than a directory exists at `cgroup`, an
+    /// [`Error::Conflict`] is returned. Otherwise, the errors are identical to
+    /// [`CgroupRoot::resolve`].
+    ///
+    /// [`Handle`]: ../struct.Handle.html
+    /// [`CgroupRoot::resolve`]: struct.CgroupRoot.html#method.resolve
+    /// [`Error::Conflict`]: ../error/enum.Error.html#variant.Conflict
+    pub fn create_child<P: AsRef<Path>>(&self, cgroup: P) -> Result<Handle, Error> {
+        let handle = self
+            .root
+            .ensure_dir(cgroup, &Permissions::from_mode(CGROUP_DIRECTORY_MODE))
+            .wrap("create cgroup")?;
+        self.verify(&handle.inner)?;
+        Ok(handle)
+    }
+
+    /// Remove the (empty) cgroup `cgroup`. The cgroup must not contain any
+    /// processes or child cgroups.
+    pub fn remove_child<P: AsRef<Path>>(&self, cgroup: P) -> Result<(), Error> {
+        let cgroup = cgroup.as_ref();
+        self.resolve(cgroup)?;
+        self.root.remove(cgroup).wrap("remove cgroup")
+    }
+
+    /// Read the interface file `file` (such as `memory.current`) of `cgroup`,
+    /// without the trailing newline.
+    ///
+    /// # Errors
+    ///
+    /// If `file` is not a single path component, an
+    /// [`Error::InvalidArgument`] is returned. Otherwise, the errors are
+    /// identical to [`CgroupRoot::resolve`].
+    ///
+    /// [`CgroupRoot::resolve`]: struct.CgroupRoot.html#method.resolve
+    /// [`Error::InvalidArgument`]: ../error/enum.Error.html#variant.InvalidArgument
+    pub fn read<P: AsRef<Path>>(&self, cgroup: P, file: &str) -> Result<String, Error> {
+        let mut value = String::new();
+        self.open_file(cgroup.as_ref(), file, libc::O_RDONLY)?
+            .read_to_string(&mut value)
+            .context(error::OsError {
+                operation: "read cgroup file",
+            })?;
+        value.truncate(value.trim_end_matches('\n').len());
+        Ok(value)
+    }
+
+    /// Write the formatted `value` to the interface file `file` (such as
+    /// `memory.max`) of `cgroup`, with a single `write(2)`.
+    ///
+    /// Controller files (those not prefixed with `cgroup.`) are only written
+    /// if their controller is available in `cgroup` (as listed in its
+    /// `cgroup.controllers`).
+    ///
+    /// # Errors
+    ///
+    /// If the controller of `file` is not available in `cgroup`, an
+    /// [`Error::NotSupported`] is returned. Otherwise, the errors are
+    /// identical to [`CgroupRoot::read`].
+    ///
+    /// [`CgroupRoot::read`]: struct.CgroupRoot.html#method.read
+    /// [`Error::NotSupported`]: ../error/enum.Error.html#variant.NotSupported
+    pub fn write<P: AsRef<Path>, V: fmt::Display>(
+        &self,
+        cgroup: P,
+        file: &str,
+        value: V,
+    ) -> Result<(), Error> {
+        let cgroup = cgroup.as_ref();
+        check_file_name(file)?;
+        if let Some((controller, _)) = file.split_once('.') {
+            if controller != "cgroup" && !self.controllers(cgroup)?.iter().any(|c| c == controller)
+            {
+                return error::NotSupported {
+                    feature: format!("cgroup controller {:?} in {:?}", controller, cgroup),
+                }
+                .fail();
+            }
+        }
+        self.open_file(cgroup, file, libc::O_WRONLY)?
+            .write_all(value.to_string().as_bytes())
+            .context(error::OsError {
+                operation: "write cgroup file",
+            })
+    }
+
+    /// The controllers available in `cgroup` (`cgroup.controllers`).
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`CgroupRoot::read`].
+    ///
+    /// [`CgroupRoot::read`]: struct.CgroupRoot.html#method.read
+    pub fn controllers<P: AsRef<Path>>(&self, cgroup: P) -> Result<Vec<String>, Error> {
+        Ok(self
+            .read(cgroup, "cgroup.controllers")?
+            .split_whitespace()
+            .map(String::from)
+            .collect())
+    }
+
+    /// Enable `controllers` for the children of `cgroup`
+    /// (`cgroup.subtree_control`).
+    ///
+    /// # Errors
+    ///
+    /// If one of `controllers` is not available in `cgroup`, an
+    /// [`Error::NotSupported`] is returned. Otherwise, the errors are
+    /// identical to [`CgroupRoot::read`].
+    ///
+    /// [`CgroupRoot::read`]: struct.CgroupRoot.html#method.read
+    /// [`Error::NotSupported`]: ../error/enum.Error.html#variant.NotSupported
+    pub fn enable_controllers<P: AsRef<Path>>(
+        &self,
+        cgroup: P,
+        controllers: &[&str],
+    ) -> Result<(), Error> {
+        let cgroup = cgroup.as_ref();
+        let available = self.controllers(cgroup)?;
+        if let Some(missing) = controllers
+            .iter()
+            .find(|controller| !available.iter().any(|c| c == *controller))
+        {
+            return error::NotSupported {
+                feature: format!("cgroup controller {:?} in {:?}", missing, cgroup),
+            }
+            .fail();
+        }
+        let value = controllers
+            .iter()
+            .map(|controller| format!("+{}", controller))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.write(cgroup, "cgroup.subtree_control", value)
+    }
+
+    /// Move the process `pid` into `cgroup` (`cgroup.procs`).
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`CgroupRoot::read`].
+    ///
+    /// [`CgroupRoot::read`]: struct.CgroupRoot.html#method.read
+    pub fn add_process<P: AsRef<Path>>(&self, cgroup: P, pid: libc::pid_t) -> Result<(), Error> {
+        self.write(cgroup, "cgroup.procs", pid)
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    syscalls, Handle, InodeType, OpenFlags, Root,
+};
+
+use std::{
+    fs::{File, Permissions},
+    io::Write,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use libc::dev_t;
+use snafu::ResultExt;
+
+/// The kind of inode described by a [`Change`].
+///
+/// This is an owned equivalent of [`InodeType`], with the addition of the
+/// contents of ordinary files.
+///
+/// [`Change`]: struct.Change.html
+/// [`InodeType`]: enum.InodeType.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Ordinary file with the given permission bits and contents.
+    File { mode: u32, contents: Vec<u8> },
+    /// Directory with the given permission bits.
+    Directory { mode: u32 },
+    /// Symlink with the given target (see [`InodeType::Symlink`]).
+    ///
+    /// [`InodeType::Symlink`]: enum.InodeType.html#variant.Symlink
+    Symlink { target: PathBuf },
+    /// Hard-link to the given path inside the [`Root`] (see
+    /// [`InodeType::Hardlink`]).
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`InodeType::Hardlink`]: enum.InodeType.html#variant.Hardlink
+    Hardlink { source: PathBuf },
+    /// Named pipe (aka FIFO) with the given permission bits.
+    Fifo { mode: u32 },
+    /// Character device with the given permission bits and device number.
+    CharacterDevice { mode: u32, rdev: dev_t },
+    /// Block device with the given permission bits and device number.
+    BlockDevice { mode: u32, rdev: dev_t },
+}
+
+/// A single desired entry to be created by [`Root::apply_changeset`].
+///
+/// [`Root::apply_changeset`]: struct.Root.html#method.apply_changeset
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Path of the entry inside the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    pub path: PathBuf,
+    /// What the entry should be.
+    pub kind: ChangeKind,
+    /// Access time to set on the entry (or `None` to leave it as the time of
+    /// creation).
+    pub atime: Option<SystemTime>,
+    /// Modification time to set on the entry (or `None` to leave it as the
+    /// time of creation).
+    pub mtime: Option<SystemTime>,
+}
+
+impl Change {
+    /// Create a new [`Change`] for `path`, without any timestamps.
+    ///
+    /// [`Change`]: struct.Change.html
+    pub fn new<P: Into<PathBuf>>(path: P, kind: ChangeKind) -> Self {
+        Self {
+            path: path.into(),
+            kind,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    /// Set the access and modification times of the entry (`None` leaves the
+    /// corresponding time unchanged).
+    pub fn with_times(mut self, atime: Option<SystemTime>, mtime: Option<SystemTime>) -> Self {
+        self.atime = atime;
+        self.mtime = mtime;
+        self
+    }
+
+    /// Whether any timestamps need to be set after the entry is created.
+    fn has_times(&self) -> bool {
+        self.atime.is_some() || self.mtime.is_some()
+    }
+
+    /// The key used to order changes. Parents always have fewer components
+    /// than their children, and hard-links are applied last so that their
+    /// sources (wherever they are in the tree) have already been created.
+    fn order_key(&self) -> (bool, usize) {
+        let depth = self
+            .path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .count();
+        (matches!(self.kind, ChangeKind::Hardlink { .. }), depth)
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, create every entry described by `changes`.
+    ///
+    /// The changes are applied in dependency order rather than the order they
+    /// are given in -- entries are created from the top of the tree downwards
+    /// (so a directory is created before its contents), and hard-links are
+    /// created after every other entry. Changes at the same level are applied
+    /// in the order given. Every entry is created with the same safe
+    /// resolution as [`Root::create`], and ordinary files are created with
+    /// [`Root::create_file`] before their contents are written.
+    ///
+    /// Entries which already exist result in an error, except for directories
+    /// which already exist as directories (their permissions are left
+    /// unchanged).
+    ///
+    /// Timestamps are set once every entry has been created, starting from the
+    /// bottom of the tree. Creating an entry updates the modification time of
+    /// its parent directory, so this ordering is necessary for directory
+    /// timestamps to survive the creation of their contents. Timestamps of
+    /// symlinks are set on the symlink itself.
+    ///
+    /// # Errors
+    ///
+    /// A failed change does not stop the remaining changes from being applied
+    /// (though changes underneath a failed directory will usually fail as
+    /// well). The result of each change is returned, in the same order as
+    /// `changes`.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::create`]: struct.Root.html#method.create
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    pub fn apply_changeset(&self, changes: &[Change]) -> Vec<Result<(), Error>> {
+        let mut order = (0..changes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&idx| changes[idx].order_key());
+
+        let mut results = (0..changes.len()).map(|_| None).collect::<Vec<_>>();
+        for &idx in &order {
+            let change = &changes[idx];
+            let ret = self
+                .apply_change(change)
+                .wrap_with(|| format!("apply change to {:?}", change.path));
+            results[idx] = Some(ret);
+        }
+        for idx in order.into_iter().rev() {
+            let change = &changes[idx];
+            if !change.has_times() || !matches!(results[idx], Some(Ok(_))) {
+                continue;
+            }
+            results[idx] = Some(
+                self.apply_change_times(change)
+                    .wrap_with(|| format!("set times of change to {:?}", change.path)),
+            );
+        }
+        results
+            .into_iter()
+            .map(|ret| ret.expect("every change should've been applied"))
+            .collect()
+    }
+
+    fn apply_change(&self, change: &Change) -> Result<(), Error> {
+        let path = change.path.as_path();
+        match &change.kind {
+            ChangeKind::File { mode, contents } => {
+                let handle = self.create_file(path, &Permissions::from_mode(*mode))?;
+                let ret = handle
+                    .reopen(OpenFlags(libc::O_WRONLY))
+                    .wrap("reopen new file for writing")
+                    .and_then(|mut file| {
+                        file.write_all(contents).context(error::OsError {
+                            operation: "write new file contents",
+                        })
+                    });
+                if ret.is_err() {
+                    // Don't leave a partially-written file around.
+                    let _ = self.remove(path);
+                }
+                ret
+            }
+            ChangeKind::Directory { mode } => {
+                let perm = Permissions::from_mode(*mode);
+                match self.create(path, &InodeType::Directory(&perm)) {
+                    Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                        let stat = self.lstat_nofollow(path)?;
+                        if stat.mode & libc::S_IFMT == libc::S_IFDIR {
+                            Ok(())
+                        } else {
+                            Err(err)
+                        }
+                    }
+                    ret => ret,
+                }
+            }
+            ChangeKind::Symlink { target } => self.create(path, &InodeType::Symlink(target)),
+            ChangeKind::Hardlink { source } => self.create(path, &InodeType::Hardlink(source)),
+            ChangeKind::Fifo { mode } => {
+                self.create(path, &InodeType::Fifo(&Permissions::from_mode(*mode)))
+            }
+            ChangeKind::CharacterDevice { mode, rdev } => self.create(
+                path,
+                &InodeType::CharacterDevice(&Permissions::from_mode(*mode), *rdev),
+            ),
+            ChangeKind::BlockDevice { mode, rdev } => self.create(
+                path,
+                &InodeType::BlockDevice(&Permissions::from_mode(*mode), *rdev),
+            ),
+        }
+    }
+
+    fn apply_change_times(&self, change: &Change) -> Result<(), Error> {
+        let file = self.open_entry_nofollow(&change.path)?;
+        Handle::from_file_unchecked(file).set_times(change.atime, change.mtime)
+    }
+
+    /// Get an `O_PATH` descriptor for `path` without following the final
+    /// component (if it is a symlink).
+    pub(crate) fn open_entry_nofollow(&self, path: &Path) -> Result<File, Error> {
+        let (parent, name) = path_split(path).wrap("split entry path into (parent, name)")?;
+        let dir = self
+            .resolve(parent)
+            .wrap("resolve parent directory of entry")?;
+        syscalls::openat(
+            dir.inner.as_raw_fd(),
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW,
+            0,
+        )
+        .context(error::RawOsError {
+            operation: "open entry without following symlinks",
+        })
+    }
+}
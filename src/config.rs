@@ -0,0 +1,406 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+// NOTE: This is a hand-written parser (rather than serde) so that libpathrs
+//       doesn't need any extra dependencies.
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    DevicePolicy, DeviceRule, DeviceType, FallbackPolicy, FilenamePolicy, FilenameRules,
+    GraceErrors, ModeBits, ReopenPolicy, Resolver, ResolverBackend, ResolverFlags, Root,
+    RootConfig,
+};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use snafu::ResultExt;
+
+/// A declarative description of a [`Root`] -- the path of the root directory
+/// and the [`RootConfig`] to apply to it -- which can be loaded from a
+/// configuration file.
+///
+/// The file format is a list of `key = value` lines. Blank lines and lines
+/// starting with `#` are ignored, and lists are separated by commas. Only
+/// `path` is required; every other key defaults to the corresponding
+/// [`RootConfig`] default.
+///
+/// ```text
+/// # Path of the root directory (required).
+/// path = /srv/tenants/alice
+///
+/// # ResolverBackend ("kernel" or "emulated") and ResolverFlags.
+/// resolver.backend = emulated
+/// resolver.flags = no_symlinks, case_insensitive
+///
+/// # FilenamePolicy (the filename validator is only set if one of these is
+/// # present).
+/// filename.rules = no_control_chars, no_dot_entries, no_windows_reserved
+/// filename.max_length = 255
+///
+/// # DevicePolicy ("allow_all", "deny_all", "whiteout_only" or a list of
+/// # "<c|b> <major|*>:<minor|*>" rules).
+/// device_policy = c 1:3, c 1:5, b 8:*
+///
+/// # ModePolicy.
+/// mode_policy.strip = setuid, setgid
+/// mode_policy.reject = world_writable
+///
+/// # ReopenPolicy (an empty list forbids all reopens).
+/// reopen_policy = regular, directory
+///
+/// # Throttle (the batch sleep is in milliseconds).
+/// throttle.max_entries_per_sec = 5000
+/// throttle.max_bytes_per_sec = 52428800
+/// throttle.batch_size = 1000
+/// throttle.batch_sleep_ms = 100
+///
+/// # GracePolicy (the delay is in milliseconds).
+/// grace.errors = enoent, estale
+/// grace.max_retries = 5
+/// grace.delay_ms = 20
+///
+/// # FallbackPolicy ("degrade" or "loud").
+/// fallback_policy = loud
+///
+/// # ResolutionBudget (the maximum duration is in milliseconds).
+/// resolution_budget.max_steps = 4096
+/// resolution_budget.max_duration_ms = 500
+///
+/// # Resolution cache (0 disables the cache).
+/// resolve_cache.capacity = 512
+///
+/// # Negative cache (0 disables the cache, the TTL is in milliseconds).
+/// negative_cache.capacity = 128
+/// negative_cache.ttl_ms = 1000
+/// ```
+///
+/// To hot-reload a configuration, load the file again and [`apply`] the new
+/// [`RootConfig`] to the existing [`Root`] (or use it as the key for a
+/// [`RootPool`]).
+///
+/// [`Root`]: struct.Root.html
+/// [`RootConfig`]: struct.RootConfig.html
+/// [`RootPool`]: struct.RootPool.html
+/// [`apply`]: struct.RootConfig.html#method.apply
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootSpec {
+    /// Path of the root directory.
+    pub path: PathBuf,
+    /// Configuration to apply to the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    pub config: RootConfig,
+}
+
+impl RootSpec {
+    /// Parse a [`RootSpec`] from the contents of a configuration file.
+    ///
+    /// # Errors
+    ///
+    /// Unknown keys, duplicate keys and invalid values are all errors, so
+    /// that typos in the configuration cannot silently weaken the policies.
+    ///
+    /// [`RootSpec`]: struct.RootSpec.html
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut path = None;
+        let mut config = RootConfig::default();
+        let mut filename_policy = None;
+        let mut seen = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |description: String| {
+                error::InvalidArgument {
+                    name: "config",
+                    description: format!("line {}: {}", idx + 1, description),
+                }
+                .fail()
+            };
+
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => return invalid(format!("expected 'key = value', got {:?}", line)),
+            };
+            if seen.contains(&key) {
+                return invalid(format!("duplicate key {:?}", key));
+            }
+            seen.push(key);
+
+            let parsed = match key {
+                "path" => {
+                    path = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "resolver.backend" => parse_backend(value).map(|backend| {
+                    config.resolver = Resolver {
+                        backend,
+                        ..config.resolver
+                    }
+                }),
+                "resolver.flags" => parse_list(value, RESOLVER_FLAGS)
+                    .map(|flags| config.resolver.flags = fold(flags, ResolverFlags::empty())),
+                "filename.rules" => parse_list(value, FILENAME_RULES).map(|rules| {
+                    filename_policy
+                        .get_or_insert_with(FilenamePolicy::default)
+                        .rules = fold(rules, FilenameRules::empty())
+                }),
+                "filename.max_length" => value
+                    .parse()
+                    .map_err(|_| format!("invalid length {:?}", value))
+                    .map(|max_length| {
+                        filename_policy
+                            .get_or_insert_with(FilenamePolicy::default)
+                            .max_length = Some(max_length)
+                    }),
+                "device_policy" => {
+                    parse_device_policy(value).map(|policy| config.device_policy = policy)
+                }
+                "mode_policy.strip" => parse_list(value, MODE_BITS)
+                    .map(|bits| config.mode_policy.strip = fold(bits, ModeBits::empty())),
+                "mode_policy.reject" => parse_list(value, MODE_BITS)
+                    .map(|bits| config.mode_policy.reject = fold(bits, ModeBits::empty())),
+                "reopen_policy" => parse_list(value, REOPEN_POLICY)
+                    .map(|types| config.reopen_policy = fold(types, ReopenPolicy::empty())),
+                "throttle.max_entries_per_sec" => {
+                    parse_number(value).map(|rate| config.throttle.max_entries_per_sec = Some(rate))
+                }
+                "throttle.max_bytes_per_sec" => {
+                    parse_number(value).map(|rate| config.throttle.max_bytes_per_sec = Some(rate))
+                }
+                "throttle.batch_size" => {
+                    parse_number(value).map(|size| config.throttle.batch_size = size)
+                }
+                "throttle.batch_sleep_ms" => parse_number(value)
+                    .map(|ms| config.throttle.batch_sleep = Duration::from_millis(ms)),
+                "grace.errors" => parse_list(value, GRACE_ERRORS)
+                    .map(|errors| config.grace_policy.errors = fold(errors, GraceErrors::empty())),
+                "grace.max_retries" => value
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", value))
+                    .map(|retries| config.grace_policy.max_retries = retries),
+                "grace.delay_ms" => parse_number(value)
+                    .map(|ms| config.grace_policy.delay = Duration::from_millis(ms)),
+                "resolve_cache.capacity" => value
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", value))
+                    .map(|capacity| config.resolve_cache_capacity = capacity),
+                "negative_cache.capacity" => value
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", value))
+                    .map(|capacity| config.negative_cache_capacity = capacity),
+                "negative_cache.ttl_ms" => parse_number(value)
+                    .map(|ms| config.negative_cache_ttl = Duration::from_millis(ms)),
+                "resolution_budget.max_steps" => parse_number(value)
+                    .map(|steps| config.resolution_budget.max_steps = Some(steps)),
+                "resolution_budget.max_duration_ms" => parse_number(value).map(|ms| {
+                    config.resolution_budget.max_duration = Some(Duration::from_millis(ms))
+                }),
+                "fallback_policy" => {
+                    parse_fallback_policy(value).map(|policy| config.fallback_policy = policy)
+                }
+                _ => Err(format!("unknown key {:?}", key)),
+            };
+            if let Err(description) = parsed {
+                return invalid(description);
+            }
+        }
+
+        config.filename_validator = filename_policy.map(|policy| Arc::new(policy) as _);
+        match path {
+            Some(path) => Ok(Self { path, config }),
+            None => error::InvalidArgument {
+                name: "config",
+                description: "missing required key \"path\"",
+            }
+            .fail(),
+        }
+    }
+
+    /// Read and parse a [`RootSpec`] from the configuration file at `file`.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`RootSpec::parse`], with the additional
+    /// possibility of failing to read `file`.
+    ///
+    /// [`RootSpec`]: struct.RootSpec.html
+    /// [`RootSpec::parse`]: struct.RootSpec.html#method.parse
+    pub fn load<P: AsRef<Path>>(file: P) -> Result<Self, Error> {
+        let file = file.as_ref();
+        let text = fs::read_to_string(file).context(error::OsError {
+            operation: "read root config file",
+        })?;
+        Self::parse(&text).wrap_with(|| format!("parse root config file {:?}", file))
+    }
+}
+
+impl FromStr for RootSpec {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Self::parse(text)
+    }
+}
+
+impl Root {
+    /// Open the [`Root`] described by `spec` (with [`Root::open`]) and apply
+    /// its configuration.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::open`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open`]: struct.Root.html#method.open
+    pub fn from_config(spec: &RootSpec) -> Result<Self, Error> {
+        let mut root = Root::open(&spec.path).wrap("open root from config")?;
+        spec.config.apply(&mut root);
+        Ok(root)
+    }
+}
+
+const RESOLVER_FLAGS: &[(&str, ResolverFlags)] = &[
+    ("no_symlinks", ResolverFlags::NO_SYMLINKS),
+    ("case_insensitive", ResolverFlags::CASE_INSENSITIVE),
+    ("allow_magiclinks", ResolverFlags::ALLOW_MAGICLINKS),
+];
+
+const FILENAME_RULES: &[(&str, FilenameRules)] = &[
+    ("no_control_chars", FilenameRules::NO_CONTROL_CHARS),
+    ("no_dot_entries", FilenameRules::NO_DOT_ENTRIES),
+    ("no_windows_reserved", FilenameRules::NO_WINDOWS_RESERVED),
+];
+
+const MODE_BITS: &[(&str, ModeBits)] = &[
+    ("setuid", ModeBits::SETUID),
+    ("setgid", ModeBits::SETGID),
+    ("sticky", ModeBits::STICKY),
+    ("world_writable", ModeBits::WORLD_WRITABLE),
+];
+
+const REOPEN_POLICY: &[(&str, ReopenPolicy)] = &[
+    ("regular", ReopenPolicy::REGULAR),
+    ("directory", ReopenPolicy::DIRECTORY),
+    ("fifo", ReopenPolicy::FIFO),
+    ("character_device", ReopenPolicy::CHARACTER_DEVICE),
+    ("block_device", ReopenPolicy::BLOCK_DEVICE),
+    ("terminal", ReopenPolicy::TERMINAL),
+];
+
+const GRACE_ERRORS: &[(&str, GraceErrors)] = &[
+    ("enoent", GraceErrors::ENOENT),
+    ("estale", GraceErrors::ESTALE),
+];
+
+/// Split a comma-separated list, ignoring empty entries.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Parse a comma-separated list of names from `table`.
+fn parse_list<T: Copy>(value: &str, table: &[(&str, T)]) -> Result<Vec<T>, String> {
+    split_list(value)
+        .map(|item| {
+            table
+                .iter()
+                .find(|(name, _)| *name == item)
+                .map(|&(_, flag)| flag)
+                .ok_or_else(|| format!("unknown value {:?}", item))
+        })
+        .collect()
+}
+
+fn fold<T: std::ops::BitOr<Output = T>>(flags: Vec<T>, empty: T) -> T {
+    flags.into_iter().fold(empty, |acc, flag| acc | flag)
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number {:?}", value))
+}
+
+fn parse_backend(value: &str) -> Result<ResolverBackend, String> {
+    match value {
+        "kernel" => Ok(ResolverBackend::Kernel),
+        "emulated" => Ok(ResolverBackend::Emulated),
+        _ => Err(format!("unknown resolver backend {:?}", value)),
+    }
+}
+
+fn parse_fallback_policy(value: &str) -> Result<FallbackPolicy, String> {
+    match value {
+        "degrade" => Ok(FallbackPolicy::Degrade),
+        "loud" => Ok(FallbackPolicy::Loud),
+        _ => Err(format!("unknown fallback policy {:?}", value)),
+    }
+}
+
+fn parse_device_policy(value: &str) -> Result<DevicePolicy, String> {
+    match value {
+        "allow_all" => Ok(DevicePolicy::AllowAll),
+        "deny_all" => Ok(DevicePolicy::DenyAll),
+        "whiteout_only" => Ok(DevicePolicy::WhiteoutOnly),
+        _ => split_list(value)
+            .map(parse_device_rule)
+            .collect::<Result<_, _>>()
+            .map(DevicePolicy::AllowList),
+    }
+}
+
+/// Parse a `<c|b> <major|*>:<minor|*>` device rule.
+fn parse_device_rule(rule: &str) -> Result<DeviceRule, String> {
+    let invalid = || format!("invalid device rule {:?}", rule);
+    let number = |num: &str| match num {
+        "*" => Ok(None),
+        _ => num.parse().map(Some).map_err(|_| invalid()),
+    };
+
+    let mut parts = rule.split_whitespace();
+    let device_type = match parts.next() {
+        Some("c") => DeviceType::Character,
+        Some("b") => DeviceType::Block,
+        _ => return Err(invalid()),
+    };
+    let (major, minor) = match (parts.next(), parts.next()) {
+        (Some(numbers), None) => {
+            let pos = numbers.find(':').ok_or_else(invalid)?;
+            (number(&numbers[..pos])?, number(&numbers[pos + 1..])?)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(DeviceRule {
+        device_type,
+        major,
+        minor,
+    })
+}
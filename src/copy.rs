@@ -0,0 +1,477 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    dax,
+    error::{self, Error, ErrorExt},
+    opstats,
+    root::path_split,
+    syscalls,
+    utils::RawFdExt,
+    FallbackPolicy, OpenFlags, Root,
+};
+
+use std::{
+    ffi::OsString,
+    fs::{File, Permissions},
+    io::{self, Read, Write},
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use snafu::ResultExt;
+
+/// Counter used to generate unique temporary names.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of temporary names to try before giving up.
+const TEMP_NAME_RETRIES: usize = 16;
+
+/// Maximum number of bytes copied by a single `copy_file_range(2)` call.
+const COPY_CHUNK_SIZE: usize = 1 << 30;
+
+/// Alignment of the buffer (and of the I/O sizes) used for `O_DIRECT` copies.
+/// This is the largest logical block size in common use, so it should satisfy
+/// the alignment requirements of just about every device.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Size of the buffer used for `O_DIRECT` copies.
+const DIRECT_IO_BUFFER_SIZE: usize = 1 << 20;
+
+/// The strategy used to copy the contents of a file with [`Root::copy_file`].
+///
+/// [`Root::copy_file`]: struct.Root.html#method.copy_file
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum CopyStrategy {
+    /// The contents were copied in-kernel with `copy_file_range(2)` (which
+    /// may share the underlying extents on filesystems that support it).
+    CopyFileRange,
+
+    /// The contents were copied through a userspace buffer with `read(2)` and
+    /// `write(2)`.
+    ReadWrite,
+
+    /// The contents were copied through an aligned userspace buffer, with
+    /// both files opened with `O_DIRECT` (see [`CopyOptions::direct_io`]).
+    ///
+    /// [`CopyOptions::direct_io`]: struct.CopyOptions.html#structfield.direct_io
+    DirectIo,
+}
+
+/// Options for [`Root::copy_file_with`].
+///
+/// [`Root::copy_file_with`]: struct.Root.html#method.copy_file_with
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CopyOptions {
+    /// Bypass the page cache by copying with `O_DIRECT`, through an aligned
+    /// buffer. This is intended for writing large images, which would
+    /// otherwise evict everything else from the page cache. If the tail of
+    /// the file is not a multiple of the block size, it is written padded and
+    /// then truncated to the right size.
+    ///
+    /// If either filesystem doesn't support `O_DIRECT`, the `fallback_policy`
+    /// of the [`Root`] decides whether a buffered copy is done instead.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub direct_io: bool,
+
+    /// Set `FS_XFLAG_DAX` on the destination if (and only if) it is set on
+    /// the source (see [`Handle::set_dax`]). Without this, the destination
+    /// inherits the flag of the directory it is created in.
+    ///
+    /// Because the destination is usually an `O_TMPFILE` file, the flag only
+    /// changes the DAX state of the copy once its inode is next loaded (after
+    /// the copy has been closed). If the destination filesystem doesn't
+    /// support the flag and the source had it set, the `fallback_policy` of
+    /// the [`Root`] decides whether this is an error.
+    ///
+    /// [`Handle::set_dax`]: struct.Handle.html#method.set_dax
+    /// [`Root`]: struct.Root.html
+    pub preserve_dax: bool,
+}
+
+/// The strategy used to create the destination of [`Root::copy_file`].
+///
+/// [`Root::copy_file`]: struct.Root.html#method.copy_file
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum CreateStrategy {
+    /// The destination was written as an anonymous `O_TMPFILE` and linked
+    /// into place once complete, so it never existed partially written.
+    Tmpfile,
+
+    /// The destination was written under a temporary name in the same
+    /// directory and linked into place once complete. A crash during the copy
+    /// can leave the temporary file behind.
+    TempName,
+}
+
+/// The result of a successful [`Root::copy_file`], reporting which strategies
+/// were chosen (see [`FallbackPolicy`]).
+///
+/// [`Root::copy_file`]: struct.Root.html#method.copy_file
+/// [`FallbackPolicy`]: enum.FallbackPolicy.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CopyOutcome {
+    /// Number of bytes copied.
+    pub bytes: u64,
+    /// Strategy used to copy the contents.
+    pub data: CopyStrategy,
+    /// Strategy used to create the destination.
+    pub creation: CreateStrategy,
+}
+
+/// Does `err` indicate that the requested strategy is not supported (as
+/// opposed to a genuine failure)?
+fn is_unsupported(err: &syscalls::Error) -> bool {
+    matches!(
+        err.root_cause().raw_os_error(),
+        Some(libc::EOPNOTSUPP)
+            | Some(libc::EINVAL)
+            | Some(libc::EXDEV)
+            | Some(libc::EISDIR)
+            | Some(libc::ENOSYS)
+    )
+}
+
+/// Does `err` indicate that the filesystem doesn't support `FS_XFLAG_DAX`?
+fn is_unsupported_dax(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+    )
+}
+
+/// Fail if `policy` does not permit falling back from `feature`.
+fn check_fallback(policy: FallbackPolicy, feature: &str) -> Result<(), Error> {
+    match policy {
+        FallbackPolicy::Degrade => Ok(()),
+        FallbackPolicy::Loud => error::NotSupported { feature }.fail(),
+    }
+}
+
+/// Try to set `O_DIRECT` on `file`, returning whether it is supported.
+fn set_direct(file: &File) -> Result<bool, Error> {
+    let fd = file.as_raw_fd();
+    let flags = syscalls::fcntl_getfl(fd).context(error::RawOsError {
+        operation: "get file status flags",
+    })?;
+    match syscalls::fcntl_setfl(fd, flags | libc::O_DIRECT) {
+        Ok(_) => Ok(true),
+        Err(err) if err.root_cause().raw_os_error() == Some(libc::EINVAL) => Ok(false),
+        Err(err) => Err(err).context(error::RawOsError {
+            operation: "set O_DIRECT",
+        }),
+    }
+}
+
+/// Copy the contents of `src` to the (empty) `dst`, which both have
+/// `O_DIRECT` set.
+fn copy_direct(src: &mut File, dst: &mut File) -> Result<u64, Error> {
+    // Vec doesn't let us pick the alignment of its allocation, so we allocate
+    // a bit more and use an aligned subslice.
+    let mut storage = vec![0u8; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGN];
+    let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let buf = &mut storage[offset..offset + DIRECT_IO_BUFFER_SIZE];
+
+    let mut copied = 0;
+    loop {
+        let n = src.read(buf).context(error::OsError {
+            operation: "read file contents with O_DIRECT",
+        })?;
+        if n == 0 {
+            break;
+        }
+        copied += n as u64;
+        if n % DIRECT_IO_ALIGN == 0 {
+            dst.write_all(&buf[..n]).context(error::OsError {
+                operation: "write file contents with O_DIRECT",
+            })?;
+            continue;
+        }
+        // A short read which isn't block-aligned means we've hit the end of
+        // the file. O_DIRECT writes must be block-aligned, so write a padded
+        // block and then cut off the padding.
+        let padded = n + DIRECT_IO_ALIGN - n % DIRECT_IO_ALIGN;
+        buf[n..padded].iter_mut().for_each(|byte| *byte = 0);
+        dst.write_all(&buf[..padded]).context(error::OsError {
+            operation: "write padded file tail with O_DIRECT",
+        })?;
+        dst.set_len(copied).context(error::OsError {
+            operation: "truncate padding of file tail",
+        })?;
+        break;
+    }
+    Ok(copied)
+}
+
+/// Copy the remaining contents of `src` to `dst`.
+fn copy_data(
+    src: &mut File,
+    dst: &mut File,
+    policy: FallbackPolicy,
+) -> Result<(u64, CopyStrategy), Error> {
+    let mut copied = 0;
+    loop {
+        match syscalls::copy_file_range(src.as_raw_fd(), dst.as_raw_fd(), COPY_CHUNK_SIZE) {
+            // Some pseudo-filesystems (such as procfs) report a size of zero
+            // for files with contents, which copy_file_range(2) takes at face
+            // value. For empty files read(2) is just as cheap, so use it.
+            Ok(0) if copied == 0 => break,
+            Ok(0) => return Ok((copied, CopyStrategy::CopyFileRange)),
+            Ok(n) => copied += n as u64,
+            Err(err) if copied == 0 && is_unsupported(&err) => {
+                check_fallback(policy, "copy_file_range")?;
+                break;
+            }
+            Err(err) => {
+                return Err(err).context(error::RawOsError {
+                    operation: "copy file contents",
+                })
+            }
+        }
+    }
+    let copied = io::copy(src, dst).context(error::OsError {
+        operation: "copy file contents",
+    })?;
+    Ok((copied, CopyStrategy::ReadWrite))
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, copy the contents of the regular file at
+    /// `source` to a new file at `destination` (with the permissions `perm`).
+    ///
+    /// The destination is only linked into place once the copy has
+    /// completed, so other processes never see a partially written file at
+    /// `destination`. If the preferred strategies (`copy_file_range(2)` and
+    /// `O_TMPFILE`) are not supported, the [`Root`]'s `fallback_policy`
+    /// decides whether slower fallbacks are used. The chosen strategies are
+    /// reported in the returned [`CopyOutcome`].
+    ///
+    /// This is equivalent to [`Root::copy_file_with`] with the default
+    /// [`CopyOptions`].
+    ///
+    /// # Errors
+    ///
+    /// If `destination` already exists, an error is returned (as with
+    /// [`Root::create_file`]). If a preferred strategy is not supported and
+    /// the `fallback_policy` is [`FallbackPolicy::Loud`], an
+    /// [`Error::NotSupported`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`CopyOutcome`]: struct.CopyOutcome.html
+    /// [`CopyOptions`]: struct.CopyOptions.html
+    /// [`Root::copy_file_with`]: struct.Root.html#method.copy_file_with
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    /// [`FallbackPolicy::Loud`]: enum.FallbackPolicy.html#variant.Loud
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    pub fn copy_file<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        perm: &Permissions,
+    ) -> Result<CopyOutcome, Error> {
+        self.copy_file_with(source, destination, perm, &Default::default())
+    }
+
+    /// Within the [`Root`]'s tree, copy the contents of the regular file at
+    /// `source` to a new file at `destination` (with the permissions `perm`)
+    /// as with [`Root::copy_file`], using the given [`CopyOptions`].
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::copy_file`]. If
+    /// [`CopyOptions::direct_io`] is set but `O_DIRECT` is not supported and
+    /// the `fallback_policy` is [`FallbackPolicy::Loud`], an
+    /// [`Error::NotSupported`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    /// [`CopyOptions`]: struct.CopyOptions.html
+    /// [`CopyOptions::direct_io`]: struct.CopyOptions.html#structfield.direct_io
+    /// [`FallbackPolicy::Loud`]: enum.FallbackPolicy.html#variant.Loud
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    pub fn copy_file_with<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        perm: &Permissions,
+        options: &CopyOptions,
+    ) -> Result<CopyOutcome, Error> {
+        let mut src = self
+            .open_file(source, OpenFlags(libc::O_RDONLY))
+            .wrap("open copy source")?;
+        let metadata = src.metadata().context(error::OsError {
+            operation: "check copy source",
+        })?;
+        ensure!(
+            metadata.is_file(),
+            error::InvalidArgument {
+                name: "source",
+                description: "must be a regular file",
+            }
+        );
+
+        let (parent, name) =
+            path_split(destination.as_ref()).wrap("split target path into (parent, name)")?;
+        self.validate_name(name)?;
+        let mode = self.sanitize_mode(perm.mode())?;
+        let dir = self
+            .grace_policy
+            .retry(|| self.resolve(parent))
+            .wrap("resolve target parent directory for copy")?
+            .inner;
+        let dirfd = dir.as_raw_fd();
+
+        let (mut dst, temp) = self.create_copy_destination(&dir, name, mode)?;
+        let creation = match temp {
+            None => CreateStrategy::Tmpfile,
+            Some(_) => CreateStrategy::TempName,
+        };
+        let result = if options.preserve_dax {
+            self.copy_dax_flag(&src, &dst)
+        } else {
+            Ok(())
+        }
+        .and_then(|_| self.copy_contents(&mut src, &mut dst, options))
+        .and_then(|copied| {
+            match temp {
+                None => dst.link_into(dirfd, name),
+                Some(ref temp) => syscalls::linkat(dirfd, temp.as_path(), dirfd, name, 0).context(
+                    error::RawOsError {
+                        operation: "link copy into place",
+                    },
+                ),
+            }
+            .map(|_| copied)
+        });
+        if let Some(temp) = temp {
+            // Best-effort cleanup, the copy (if any) has its own name now.
+            let _ = syscalls::unlinkat(dirfd, temp, 0);
+        }
+        let (bytes, data) = result.wrap("pathrs copy_file")?;
+        opstats::record_bytes(bytes);
+        self.invalidate_caches(destination.as_ref());
+
+        Ok(CopyOutcome {
+            bytes,
+            data,
+            creation,
+        })
+    }
+
+    /// Make the `FS_XFLAG_DAX` flag of `dst` match `src` (for
+    /// [`CopyOptions::preserve_dax`]).
+    ///
+    /// [`CopyOptions::preserve_dax`]: struct.CopyOptions.html#structfield.preserve_dax
+    fn copy_dax_flag(&self, src: &File, dst: &File) -> Result<(), Error> {
+        let dax = dax::dax_flag(src).wrap("get DAX flag of copy source")?;
+        if dax == dax::dax_flag(dst).wrap("get DAX flag of copy destination")? {
+            return Ok(());
+        }
+        match dax::set_dax_flag(dst, dax) {
+            Err(err) if is_unsupported_dax(&err) => {
+                check_fallback(self.fallback_policy, "FS_XFLAG_DAX")
+            }
+            ret => ret.wrap("set DAX flag of copy destination"),
+        }
+    }
+
+    /// Copy the contents of `src` to `dst` using the strategy requested by
+    /// `options` (falling back according to the `fallback_policy`).
+    fn copy_contents(
+        &self,
+        src: &mut File,
+        dst: &mut File,
+        options: &CopyOptions,
+    ) -> Result<(u64, CopyStrategy), Error> {
+        if options.direct_io {
+            if set_direct(src)? && set_direct(dst)? {
+                return copy_direct(src, dst).map(|copied| (copied, CopyStrategy::DirectIo));
+            }
+            check_fallback(self.fallback_policy, "O_DIRECT")?;
+            // We might have set O_DIRECT on src already.
+            let fd = src.as_raw_fd();
+            syscalls::fcntl_getfl(fd)
+                .and_then(|flags| syscalls::fcntl_setfl(fd, flags & !libc::O_DIRECT))
+                .context(error::RawOsError {
+                    operation: "clear O_DIRECT",
+                })?;
+        }
+        copy_data(src, dst, self.fallback_policy)
+    }
+
+    /// Create an unnamed file in `dir` for [`Root::copy_file`], falling back
+    /// to a file with a temporary name (which is returned) if `O_TMPFILE` is
+    /// not supported.
+    ///
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    pub(crate) fn create_copy_destination(
+        &self,
+        dir: &File,
+        name: &Path,
+        mode: libc::mode_t,
+    ) -> Result<(File, Option<PathBuf>), Error> {
+        let dirfd = dir.as_raw_fd();
+        match syscalls::openat(dirfd, ".", libc::O_TMPFILE | libc::O_WRONLY, mode) {
+            Ok(file) => return Ok((file, None)),
+            Err(err) if is_unsupported(&err) => check_fallback(self.fallback_policy, "O_TMPFILE")?,
+            Err(err) => {
+                return Err(err).context(error::RawOsError {
+                    operation: "create anonymous copy destination",
+                })
+            }
+        }
+
+        let mut last_error = None;
+        for _ in 0..TEMP_NAME_RETRIES {
+            let mut temp = OsString::from(".");
+            temp.push(name);
+            temp.push(format!(
+                ".pathrs-copy-{}-{}",
+                process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let temp = PathBuf::from(temp);
+            match syscalls::openat(
+                dirfd,
+                &temp,
+                libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY,
+                mode,
+            ) {
+                Ok(file) => return Ok((file, Some(temp))),
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::EEXIST) => {
+                    last_error = Some(err)
+                }
+                Err(err) => {
+                    return Err(err).context(error::RawOsError {
+                        operation: "create temporary copy destination",
+                    })
+                }
+            }
+        }
+        // If we ever are here, then last_error must be Some.
+        Err(last_error.expect("create_copy_destination loop failed so last_error must exist"))
+            .context(error::RawOsError {
+                operation: "create temporary copy destination",
+            })
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, Root,
+};
+
+use std::{
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use snafu::ResultExt;
+
+lazy_static! {
+    /// Serialises all [`Root::with_cwd`] calls in the process, since the
+    /// current working directory is shared by every thread.
+    ///
+    /// [`Root::with_cwd`]: struct.Root.html#method.with_cwd
+    static ref CWD_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Restores the previous working directory when dropped, so that the
+/// working directory is restored even if the closure passed to
+/// [`Root::with_cwd`] panics.
+///
+/// [`Root::with_cwd`]: struct.Root.html#method.with_cwd
+struct CwdGuard {
+    old: Option<File>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl CwdGuard {
+    fn restore(mut self) -> Result<(), Error> {
+        match self.old.take() {
+            Some(old) => syscalls::fchdir(old.as_raw_fd()).context(error::RawOsError {
+                operation: "restore previous working directory",
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CwdGuard {
+    fn drop(&mut self) {
+        if let Some(old) = self.old.take() {
+            // We are unwinding, so there is nobody to report the error to.
+            let _ = syscalls::fchdir(old.as_raw_fd());
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve the directory at `path`, change the
+    /// current working directory of the process into it, run `func` and then
+    /// change back to the previous working directory.
+    ///
+    /// This is intended for interoperating with legacy code (such as C
+    /// libraries) which only accepts relative paths. The directory is entered
+    /// with `fchdir(2)` on the resolved [`Handle`], so the working directory
+    /// is guaranteed to be inside the [`Root`] when `func` starts. However,
+    /// any paths used by `func` are resolved by the kernel without any of the
+    /// protections of libpathrs -- so `func` must not use paths containing
+    /// `..` or symlinks which it doesn't trust.
+    ///
+    /// # Thread Safety
+    ///
+    /// The current working directory is process-global, so this changes the
+    /// working directory of *every* thread in the process while `func` runs.
+    /// All calls to `with_cwd` are serialised by a process-wide lock (so
+    /// calling `with_cwd` from within `func` will deadlock), but nothing stops
+    /// other code in the process from using (or changing) the working
+    /// directory at the same time.
+    ///
+    /// # Errors
+    ///
+    /// If `path` cannot be resolved or is not a directory, `func` is not
+    /// called. If the previous working directory cannot be restored after
+    /// `func` returns, an error is returned (and the result of `func` is
+    /// lost). If `func` panics, the previous working directory is restored on
+    /// a best-effort basis before the panic continues.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    pub fn with_cwd<P, F, T>(&self, path: P, func: F) -> Result<T, Error>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> T,
+    {
+        let dir = self.resolve(path).wrap("resolve new working directory")?;
+
+        // A panic inside func (which we re-raise) doesn't leave the working
+        // directory in a bad state because of CwdGuard, so we can ignore lock
+        // poisoning.
+        let lock = CWD_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let old = syscalls::openat(libc::AT_FDCWD, ".", libc::O_PATH | libc::O_DIRECTORY, 0)
+            .context(error::RawOsError {
+                operation: "open previous working directory",
+            })?;
+        syscalls::fchdir(dir.inner.as_raw_fd()).context(error::RawOsError {
+            operation: "change into new working directory",
+        })?;
+
+        let guard = CwdGuard {
+            old: Some(old),
+            _lock: lock,
+        };
+        let ret = func();
+        guard.restore()?;
+        Ok(ret)
+    }
+}
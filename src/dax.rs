@@ -0,0 +1,160 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, Handle, OpenFlags, Stat, StatAttributes,
+};
+
+use std::{fs::File, os::unix::io::AsRawFd};
+
+use snafu::ResultExt;
+
+/// The DAX (direct access) state of a file, as returned by
+/// [`Handle::dax_state`].
+///
+/// On persistent-memory-backed filesystems (ext4 and xfs mounted with
+/// `-o dax=inode`), the per-file `FS_XFLAG_DAX` flag selects whether the
+/// page cache is bypassed and file mappings go directly to the device. A
+/// change of the flag only takes effect once the inode is next loaded into
+/// memory (usually once every file descriptor and mapping of it has been
+/// closed), so the requested and active states can differ.
+///
+/// [`Handle::dax_state`]: struct.Handle.html#method.dax_state
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct DaxState {
+    /// Whether `FS_XFLAG_DAX` is set on the inode.
+    pub requested: bool,
+    /// Whether the inode is currently in the DAX state (`STATX_ATTR_DAX`), or
+    /// `None` if the kernel doesn't report it.
+    pub active: Option<bool>,
+}
+
+/// Open `handle` for inode flag ioctls. Only regular files and directories are
+/// permitted, since ioctls on device inodes are handled by the driver.
+fn open_for_xflags(handle: &Handle) -> Result<File, Error> {
+    let stat = syscalls::fstatat(handle.inner.as_raw_fd(), "").context(error::RawOsError {
+        operation: "check inode type for inode flags",
+    })?;
+    ensure!(
+        matches!(stat.st_mode & libc::S_IFMT, libc::S_IFREG | libc::S_IFDIR),
+        error::InvalidArgument {
+            name: "handle",
+            description: "inode flags can only be used on regular files and directories",
+        }
+    );
+    handle
+        .reopen(OpenFlags(libc::O_RDONLY))
+        .wrap("reopen handle for inode flags")
+}
+
+/// Get whether `FS_XFLAG_DAX` is set on `file`. Filesystems which don't
+/// support `FS_IOC_FSGETXATTR` don't support DAX either.
+pub(crate) fn dax_flag(file: &File) -> Result<bool, Error> {
+    match syscalls::ioctl_fsgetxattr(file.as_raw_fd()) {
+        Ok(fsx) => Ok(fsx.fsx_xflags & syscalls::FS_XFLAG_DAX != 0),
+        Err(err)
+            if matches!(
+                err.root_cause().raw_os_error(),
+                Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP)
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err).context(error::RawOsError {
+            operation: "get inode flags",
+        }),
+    }
+}
+
+/// Set or clear `FS_XFLAG_DAX` on `file`.
+pub(crate) fn set_dax_flag(file: &File, enabled: bool) -> Result<(), Error> {
+    let fd = file.as_raw_fd();
+    let mut fsx = syscalls::ioctl_fsgetxattr(fd).context(error::RawOsError {
+        operation: "get inode flags",
+    })?;
+    if enabled {
+        fsx.fsx_xflags |= syscalls::FS_XFLAG_DAX;
+    } else {
+        fsx.fsx_xflags &= !syscalls::FS_XFLAG_DAX;
+    }
+    syscalls::ioctl_fssetxattr(fd, &fsx).context(error::RawOsError {
+        operation: "set inode flags",
+    })
+}
+
+impl Handle {
+    /// Get the [`DaxState`] of the file or directory referenced by the
+    /// [`Handle`].
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] doesn't reference a regular file or directory, an
+    /// [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`DaxState`]: struct.DaxState.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn dax_state(&self) -> Result<DaxState, Error> {
+        let file = open_for_xflags(self)?;
+        let stx = syscalls::statx(file.as_raw_fd(), "", libc::STATX_BASIC_STATS).context(
+            error::RawOsError {
+                operation: "get inode attributes",
+            },
+        )?;
+        let stat = Stat::from(stx);
+        Ok(DaxState {
+            requested: dax_flag(&file)?,
+            active: match stat.attributes {
+                Some(attrs) if stat.attributes_supported.contains(StatAttributes::DAX) => {
+                    Some(attrs.contains(StatAttributes::DAX))
+                }
+                _ => None,
+            },
+        })
+    }
+
+    /// Set (or clear) `FS_XFLAG_DAX` on the file or directory referenced by
+    /// the [`Handle`] (see [`DaxState`]).
+    ///
+    /// The flag is inherited by new files and directories created inside a
+    /// directory, including `O_TMPFILE` files (such as those used by
+    /// [`Root::copy_file`]). Setting it on a directory is therefore the only
+    /// way to get an `O_TMPFILE` file which is in the DAX state from the
+    /// start -- setting it on the file itself doesn't take effect until the
+    /// inode is next loaded, which for an unlinked file is never. See
+    /// [`CopyOptions::preserve_dax`] for propagating the flag when copying.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] doesn't reference a regular file or directory, an
+    /// [`Error::InvalidArgument`] is returned. If the filesystem doesn't
+    /// support the flag, an error is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`DaxState`]: struct.DaxState.html
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    /// [`CopyOptions::preserve_dax`]: struct.CopyOptions.html#structfield.preserve_dax
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_dax(&self, enabled: bool) -> Result<(), Error> {
+        let file = open_for_xflags(self)?;
+        set_dax_flag(&file, enabled)
+    }
+}
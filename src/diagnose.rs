@@ -0,0 +1,394 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Self-tests of the escape defenses of a [`Root`].
+//!
+//! [`root_escape_check`] attempts a set of well-known root escapes against a
+//! [`Root`] (with its current [`Resolver`] configuration) and reports which
+//! of them were contained, which were blocked by one of the defenses of
+//! libpathrs (such as the kernel returning `-EXDEV` or the emulated resolver
+//! detecting a [`SafetyViolation`]) and which actually escaped. This is
+//! intended to be used by downstream runtimes (as part of their QA) to check
+//! that libpathrs works as expected on their kernels and filesystems.
+//!
+//! [`Root`]: ../struct.Root.html
+//! [`Resolver`]: ../struct.Resolver.html
+//! [`root_escape_check`]: fn.root_escape_check.html
+//! [`SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls,
+    utils::FileExt,
+    Handle, InodeType, Root,
+};
+
+use std::{
+    fs::Permissions,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use snafu::ResultExt;
+
+/// Number of resolutions attempted by each of the race checks.
+const RACE_ATTEMPTS: u64 = 10_000;
+
+/// A kind of root escape attempted by [`root_escape_check`].
+///
+/// [`root_escape_check`]: fn.root_escape_check.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EscapeAttempt {
+    /// Resolving more `..` components than there are directories above the
+    /// lookup (`/a/../../..`), which should be clamped to the root.
+    DotDot,
+    /// Resolving a symlink to `/`, which should be resolved relative to the
+    /// root.
+    AbsoluteSymlink,
+    /// Resolving a symlink with more `..` components than there are
+    /// directories above it.
+    RelativeSymlink,
+    /// Resolving `a/b/../../..` while another thread repeatedly moves `a/b`
+    /// outside of the root (and back).
+    DotDotRace,
+    /// Resolving through the `/proc/self/root` magic-link (with a [`Root`] of
+    /// `/proc`).
+    ///
+    /// [`Root`]: ../struct.Root.html
+    MagicLink,
+    /// The same as [`DotDotRace`], except that the root is a bind-mount (in a
+    /// detached mount namespace created with `open_tree(2)`), so that escaping
+    /// the bind-mount would give access to the rest of the filesystem.
+    ///
+    /// [`DotDotRace`]: #variant.DotDotRace
+    BindMountRace,
+}
+
+/// The outcome of one [`EscapeAttempt`].
+///
+/// [`EscapeAttempt`]: enum.EscapeAttempt.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EscapeOutcome {
+    /// Every resolution stayed inside the root.
+    Contained,
+    /// At least one resolution was refused with an error (and none escaped).
+    /// `errno` and `description` are taken from the first refusal.
+    Blocked {
+        /// The `errno` of the refusal (if it was caused by an OS error).
+        errno: Option<i32>,
+        /// A description of the refusal (including the full error chain).
+        description: String,
+    },
+    /// At least one resolution escaped the root.
+    Escaped,
+    /// The attempt could not be run in this environment.
+    Skipped {
+        /// Why the attempt was skipped.
+        reason: String,
+    },
+}
+
+/// The result of one [`EscapeAttempt`], as returned by
+/// [`root_escape_check`].
+///
+/// [`EscapeAttempt`]: enum.EscapeAttempt.html
+/// [`root_escape_check`]: fn.root_escape_check.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EscapeCheck {
+    /// The escape which was attempted.
+    pub attempt: EscapeAttempt,
+    /// The overall outcome of the attempt.
+    pub outcome: EscapeOutcome,
+    /// Number of resolutions done (more than one for the race checks).
+    pub attempts: u64,
+    /// Number of resolutions which were refused with an error. For the race
+    /// checks this does not include `ENOENT` (which just means the racing
+    /// rename happened before the lookup).
+    pub blocked: u64,
+    /// Number of resolutions which escaped the root.
+    pub escaped: u64,
+}
+
+/// The results of [`root_escape_check`].
+///
+/// [`root_escape_check`]: fn.root_escape_check.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EscapeReport {
+    /// The results of each attempted escape, in the order they were run.
+    pub checks: Vec<EscapeCheck>,
+}
+
+impl EscapeReport {
+    /// Whether any of the attempted escapes succeeded.
+    pub fn escaped(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.outcome == EscapeOutcome::Escaped)
+    }
+}
+
+/// Accumulates the results of the resolutions of one [`EscapeAttempt`].
+///
+/// [`EscapeAttempt`]: enum.EscapeAttempt.html
+struct Tally {
+    check: EscapeCheck,
+}
+
+impl Tally {
+    fn new(attempt: EscapeAttempt) -> Self {
+        Self {
+            check: EscapeCheck {
+                attempt,
+                outcome: EscapeOutcome::Contained,
+                attempts: 0,
+                blocked: 0,
+                escaped: 0,
+            },
+        }
+    }
+
+    fn skipped(attempt: EscapeAttempt, reason: String) -> EscapeCheck {
+        let mut tally = Self::new(attempt);
+        tally.check.outcome = EscapeOutcome::Skipped { reason };
+        tally.check
+    }
+
+    /// Record a resolution which should have resulted in `expected`.
+    fn record(&mut self, result: Result<Handle, Error>, expected: (u64, u64), racy: bool) {
+        let check = &mut self.check;
+        check.attempts += 1;
+        match result {
+            Ok(handle) => {
+                // If we can't tell where the handle is, err on the side of
+                // reporting an escape.
+                if handle.inner.inode_id().ok() != Some(expected) {
+                    check.escaped += 1;
+                    check.outcome = EscapeOutcome::Escaped;
+                }
+            }
+            Err(ref err) if racy && err.raw_os_error() == Some(libc::ENOENT) => (),
+            Err(err) => {
+                check.blocked += 1;
+                if check.outcome == EscapeOutcome::Contained {
+                    check.outcome = EscapeOutcome::Blocked {
+                        errno: err.raw_os_error(),
+                        description: err
+                            .iter_chain_hotfix()
+                            .map(|err| err.to_string())
+                            .collect::<Vec<_>>()
+                            .join(": "),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// The sacrificial directory tree used by [`root_escape_check`].
+///
+/// ```text
+/// <sandbox>/outside/
+/// <sandbox>/inner/a/b/
+/// <sandbox>/inner/abs -> /
+/// <sandbox>/inner/rel -> ../../../../..
+/// ```
+///
+/// `inner` is used as the root for all checks, and `outside` is where the
+/// race checks move `a/b` to.
+///
+/// [`root_escape_check`]: fn.root_escape_check.html
+struct Sandbox<'a> {
+    root: &'a Root,
+    path: PathBuf,
+    dir: Handle,
+    inner: Root,
+    inner_id: (u64, u64),
+}
+
+impl<'a> Sandbox<'a> {
+    const ENTRIES: &'static [&'static str] = &[
+        "outside",
+        "inner",
+        "inner/a",
+        "inner/a/b",
+        "inner/abs",
+        "inner/rel",
+    ];
+
+    fn new(root: &'a Root) -> Result<Self, Error> {
+        let path = PathBuf::from(format!("/.pathrs-escape-check.{}", process::id()));
+        let perm = Permissions::from_mode(0o700);
+        root.create(&path, &InodeType::Directory(&perm))
+            .wrap("create escape check sandbox")?;
+        for name in Self::ENTRIES {
+            let inode = match *name {
+                "inner/abs" => InodeType::Symlink(Path::new("/")),
+                "inner/rel" => InodeType::Symlink(Path::new("../../../../..")),
+                _ => InodeType::Directory(&perm),
+            };
+            root.create(path.join(name), &inode)
+                .wrap("create escape check sandbox")?;
+        }
+
+        let dir = root.resolve(&path).wrap("resolve escape check sandbox")?;
+        let mut inner = Root::from_file_unchecked(
+            root.resolve(path.join("inner"))
+                .wrap("resolve escape check root")?
+                .inner,
+        );
+        inner.resolver = root.resolver;
+        let inner_id = inner.inner.inode_id()?;
+        Ok(Self {
+            root,
+            path,
+            dir,
+            inner,
+            inner_id,
+        })
+    }
+
+    /// Resolve `path` inside `root` [`RACE_ATTEMPTS`] times, while another
+    /// thread moves `a/b` in and out of the sandbox root.
+    ///
+    /// [`RACE_ATTEMPTS`]: constant.RACE_ATTEMPTS.html
+    fn race(&self, attempt: EscapeAttempt, root: &Root) -> EscapeCheck {
+        let mut tally = Tally::new(attempt);
+        let done = AtomicBool::new(false);
+        let dirfd = self.dir.inner.as_raw_fd();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    let _ = syscalls::renameat(dirfd, "inner/a/b", dirfd, "outside/b");
+                    let _ = syscalls::renameat(dirfd, "outside/b", dirfd, "inner/a/b");
+                }
+            });
+            for _ in 0..RACE_ATTEMPTS {
+                tally.record(root.resolve("a/b/../../.."), self.inner_id, true);
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        tally.check
+    }
+
+    fn check_lexical(&self, attempt: EscapeAttempt, path: &str) -> EscapeCheck {
+        let mut tally = Tally::new(attempt);
+        tally.record(self.inner.resolve(path), self.inner_id, false);
+        tally.check
+    }
+
+    fn check_magic_link(&self) -> EscapeCheck {
+        let attempt = EscapeAttempt::MagicLink;
+        let mut proc_root = match Root::open("/proc") {
+            Ok(root) => root,
+            Err(err) => return Tally::skipped(attempt, format!("cannot open /proc: {}", err)),
+        };
+        proc_root.resolver = self.inner.resolver;
+        let host_root = match syscalls::openat(libc::AT_FDCWD, "/", libc::O_PATH, 0)
+            .context(error::RawOsError {
+                operation: "open host root",
+            })
+            .and_then(|file| file.inode_id())
+        {
+            Ok(id) => id,
+            Err(err) => return Tally::skipped(attempt, err.to_string()),
+        };
+
+        let mut tally = Tally::new(attempt);
+        match proc_root.resolve("self/root/.") {
+            // Landing anywhere other than the host root is fine (the emulated
+            // resolver treats magic-links as regular symlinks).
+            Ok(handle) if handle.inner.inode_id().ok() != Some(host_root) => {
+                tally.check.attempts += 1
+            }
+            result => tally.record(result, (0, 0), false),
+        }
+        tally.check
+    }
+
+    fn check_bind_mount_race(&self) -> EscapeCheck {
+        let attempt = EscapeAttempt::BindMountRace;
+        // The detached bind-mount is only reachable through this file
+        // descriptor, and is unmounted once it is closed.
+        let mount = match syscalls::open_tree(
+            self.inner.inner.as_raw_fd(),
+            "",
+            syscalls::OPEN_TREE_CLONE | libc::AT_EMPTY_PATH as u32,
+        ) {
+            Ok(file) => file,
+            Err(err) => return Tally::skipped(attempt, err.to_string()),
+        };
+        let mut mount_root = Root::from_file_unchecked(mount);
+        mount_root.resolver = self.inner.resolver;
+        self.race(attempt, &mount_root)
+    }
+
+    fn cleanup(self) -> Result<(), Error> {
+        // Make sure a/b is back in place if the racing thread left it moved.
+        let dirfd = self.dir.inner.as_raw_fd();
+        let _ = syscalls::renameat(dirfd, "outside/b", dirfd, "inner/a/b");
+        drop(self.inner);
+        for name in Self::ENTRIES.iter().rev() {
+            self.root
+                .remove(self.path.join(name))
+                .wrap("remove escape check sandbox")?;
+        }
+        self.root
+            .remove(&self.path)
+            .wrap("remove escape check sandbox")
+    }
+}
+
+/// Attempt a set of known root escapes against `root` (using its current
+/// [`Resolver`]), and report which defenses engaged.
+///
+/// The escapes are attempted in a sacrificial directory tree which is created
+/// (and removed afterwards) at the top of `root`, so `root` must be writable.
+/// The race checks are probabilistic (a race check which is
+/// [`EscapeOutcome::Contained`] may just not have hit the race window), and
+/// take up to a few seconds, and the [`BindMountRace`] check
+/// requires `open_tree(2)` (and `CAP_SYS_ADMIN`) -- it is skipped if the
+/// bind-mount cannot be created.
+///
+/// # Errors
+///
+/// An escape which succeeds is not an error (it is reported in the returned
+/// [`EscapeReport`]). If the sandbox could not be created or removed, an
+/// error is returned.
+///
+/// [`Resolver`]: ../struct.Resolver.html
+/// [`EscapeOutcome::Contained`]: enum.EscapeOutcome.html#variant.Contained
+/// [`BindMountRace`]: enum.EscapeAttempt.html#variant.BindMountRace
+/// [`EscapeReport`]: struct.EscapeReport.html
+pub fn root_escape_check(root: &Root) -> Result<EscapeReport, Error> {
+    let sandbox = Sandbox::new(root)?;
+    let checks = vec![
+        sandbox.check_lexical(EscapeAttempt::DotDot, "/a/../../.."),
+        sandbox.check_lexical(EscapeAttempt::AbsoluteSymlink, "abs"),
+        sandbox.check_lexical(EscapeAttempt::RelativeSymlink, "rel"),
+        sandbox.race(EscapeAttempt::DotDotRace, &sandbox.inner),
+        sandbox.check_magic_link(),
+        sandbox.check_bind_mount_race(),
+    ];
+    sandbox.cleanup()?;
+    Ok(EscapeReport { checks })
+}
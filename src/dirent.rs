@@ -0,0 +1,525 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error},
+    features, syscalls,
+};
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{File, FileType},
+    io::{Seek, SeekFrom},
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::FileTypeExt,
+        io::{AsRawFd, RawFd},
+    },
+    sync::Mutex,
+};
+
+use snafu::ResultExt;
+
+lazy_static! {
+    /// Whether `getdents64(2)` fills in `d_type`, keyed by the filesystem
+    /// magic (`f_type`). Filled in by the `d_type` probe of
+    /// [`Handle::filesystem_features`], and by [`Dirents`] whenever it has to fall back
+    /// to `fstatat(2)`. [`Dirents`] use this to decide whether to resolve the
+    /// type of entries up front.
+    ///
+    /// [`Handle::filesystem_features`]: struct.Handle.html#method.filesystem_features
+    /// [`Dirents`]: struct.Dirents.html
+    static ref D_TYPE_CACHE: Mutex<HashMap<i64, bool>> = Mutex::new(HashMap::new());
+}
+
+/// Record whether the filesystem with the magic `fs_type` fills in `d_type`.
+pub(crate) fn record_d_type_support(fs_type: i64, supported: bool) {
+    D_TYPE_CACHE.lock().unwrap().insert(fs_type, supported);
+}
+
+/// The type of a directory entry, as returned by [`DirEntry::entry_type`] and
+/// [`Dirents::entry_type`].
+///
+/// [`DirEntry::entry_type`]: struct.DirEntry.html#method.entry_type
+/// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EntryType {
+    /// Regular file.
+    File,
+    /// Directory.
+    Directory,
+    /// Symlink.
+    Symlink,
+    /// Named pipe (`S_IFIFO`).
+    Fifo,
+    /// Unix domain socket.
+    Socket,
+    /// Character device.
+    CharacterDevice,
+    /// Block device.
+    BlockDevice,
+    /// The filesystem didn't say (`DT_UNKNOWN`), so the entry needs to be
+    /// stat-ed to find out -- see [`Dirents::entry_type`].
+    ///
+    /// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+    Unknown,
+}
+
+impl EntryType {
+    /// Get the [`EntryType`] of a [`FileType`].
+    ///
+    /// [`EntryType`]: enum.EntryType.html
+    /// [`FileType`]: https://doc.rust-lang.org/std/fs/struct.FileType.html
+    pub fn of(file_type: FileType) -> Self {
+        if file_type.is_file() {
+            Self::File
+        } else if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else if file_type.is_fifo() {
+            Self::Fifo
+        } else if file_type.is_socket() {
+            Self::Socket
+        } else if file_type.is_char_device() {
+            Self::CharacterDevice
+        } else if file_type.is_block_device() {
+            Self::BlockDevice
+        } else {
+            Self::Unknown
+        }
+    }
+
+    fn from_d_type(d_type: u8) -> Self {
+        match d_type {
+            libc::DT_REG => Self::File,
+            libc::DT_DIR => Self::Directory,
+            libc::DT_LNK => Self::Symlink,
+            libc::DT_FIFO => Self::Fifo,
+            libc::DT_SOCK => Self::Socket,
+            libc::DT_CHR => Self::CharacterDevice,
+            libc::DT_BLK => Self::BlockDevice,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn from_mode(mode: libc::mode_t) -> Self {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Self::File,
+            libc::S_IFDIR => Self::Directory,
+            libc::S_IFLNK => Self::Symlink,
+            libc::S_IFIFO => Self::Fifo,
+            libc::S_IFSOCK => Self::Socket,
+            libc::S_IFCHR => Self::CharacterDevice,
+            libc::S_IFBLK => Self::BlockDevice,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn d_type(self) -> u8 {
+        match self {
+            Self::File => libc::DT_REG,
+            Self::Directory => libc::DT_DIR,
+            Self::Symlink => libc::DT_LNK,
+            Self::Fifo => libc::DT_FIFO,
+            Self::Socket => libc::DT_SOCK,
+            Self::CharacterDevice => libc::DT_CHR,
+            Self::BlockDevice => libc::DT_BLK,
+            Self::Unknown => libc::DT_UNKNOWN,
+        }
+    }
+}
+
+/// A single directory entry, as returned by [`Dirents`].
+///
+/// [`Dirents`]: struct.Dirents.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub(crate) ino: u64,
+    pub(crate) d_type: u8,
+    pub(crate) name: OsString,
+}
+
+impl DirEntry {
+    /// The inode number of the entry (`d_ino`).
+    ///
+    /// Note that for mountpoints this is the inode number of the underlying
+    /// directory, not the root of the mounted filesystem.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// The raw inode type of the entry (`d_type`), one of the `DT_*` constants.
+    ///
+    /// Not all filesystems fill this field, so it may be `DT_UNKNOWN` -- in
+    /// which case you need to open the entry to get its type.
+    #[inline]
+    pub fn d_type(&self) -> u8 {
+        self.d_type
+    }
+
+    /// The type of the entry according to `d_type`, without doing any extra
+    /// syscalls. This is [`EntryType::Unknown`] if the filesystem didn't fill
+    /// `d_type` -- use [`Dirents::entry_type`] if you need to know the type in
+    /// that case.
+    ///
+    /// [`EntryType::Unknown`]: enum.EntryType.html#variant.Unknown
+    /// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+    #[inline]
+    pub fn entry_type(&self) -> EntryType {
+        EntryType::from_d_type(self.d_type)
+    }
+
+    /// The name of the entry. This is always a single path component, and is
+    /// never `.` or `..`.
+    #[inline]
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Unwrap the [`DirEntry`] to get the name of the entry.
+    ///
+    /// [`DirEntry`]: struct.DirEntry.html
+    #[inline]
+    pub fn into_name(self) -> OsString {
+        self.name
+    }
+}
+
+/// Iterator over the entries of a directory, implemented directly with
+/// `getdents64(2)` and created with [`Handle::read_dir`].
+///
+/// Unlike [`std::fs::ReadDir`], this doesn't go through `readdir(3)` (and its
+/// hidden per-`DIR` state) and exposes the raw `d_ino` and `d_type` of each
+/// entry. The `.` and `..` entries are skipped.
+///
+/// Some filesystems don't fill `d_type` (it is `DT_UNKNOWN`), in which case
+/// [`Dirents::entry_type`] falls back to `fstatat(2)`. Once libpathrs knows
+/// that a filesystem doesn't fill `d_type` (see [`Dirents::fills_d_type`]),
+/// this is done for every entry before it is returned, so callers don't see
+/// [`EntryType::Unknown`]. [`Dirents::resolve_entry_types`] overrides this.
+///
+/// [`Handle::read_dir`]: struct.Handle.html#method.read_dir
+/// [`std::fs::ReadDir`]: https://doc.rust-lang.org/std/fs/struct.ReadDir.html
+/// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+/// [`Dirents::fills_d_type`]: struct.Dirents.html#method.fills_d_type
+/// [`Dirents::resolve_entry_types`]: struct.Dirents.html#method.resolve_entry_types
+/// [`EntryType::Unknown`]: enum.EntryType.html#variant.Unknown
+#[derive(Debug)]
+pub struct Dirents {
+    dir: File,
+    buf: Vec<u8>,
+    len: usize,
+    offset: usize,
+    /// Whether to resolve `DT_UNKNOWN` entries, or `None` to decide based on
+    /// `D_TYPE_CACHE`.
+    resolve_types: Option<bool>,
+    fs_type: Option<i64>,
+}
+
+impl Dirents {
+    /// Size of the buffer passed to `getdents64(2)`.
+    const BUFFER_SIZE: usize = 32 * 1024;
+
+    // Offsets of fields in struct linux_dirent64.
+    const D_INO_OFFSET: usize = 0;
+    const D_RECLEN_OFFSET: usize = 16;
+    const D_TYPE_OFFSET: usize = 18;
+    const D_NAME_OFFSET: usize = 19;
+
+    /// Wrap a directory. The [`File`] must be a directory opened for reading
+    /// -- `O_PATH` descriptors will return `-EBADF`.
+    ///
+    /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
+    pub(crate) fn new(dir: File) -> Self {
+        Self {
+            dir,
+            buf: vec![0; Self::BUFFER_SIZE],
+            len: 0,
+            offset: 0,
+            resolve_types: None,
+            fs_type: None,
+        }
+    }
+
+    /// Whether to fill in the type of entries with `DT_UNKNOWN` (using
+    /// [`Dirents::entry_type`]) before they are returned, which costs an
+    /// `fstatat(2)` for each such entry. By default, this is only done if the
+    /// filesystem is known not to fill `d_type` (see [`Dirents::fills_d_type`]).
+    ///
+    /// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+    /// [`Dirents::fills_d_type`]: struct.Dirents.html#method.fills_d_type
+    pub fn resolve_entry_types(mut self, resolve: bool) -> Self {
+        self.resolve_types = Some(resolve);
+        self
+    }
+
+    /// Whether `DT_UNKNOWN` entries should be resolved before they are
+    /// returned. The filesystem type is only looked up the first time this is
+    /// needed, so filesystems which fill `d_type` don't pay for it.
+    fn should_resolve_types(&mut self) -> Result<bool, Error> {
+        if let Some(resolve) = self.resolve_types {
+            return Ok(resolve);
+        }
+        let fs_type = match self.fs_type {
+            Some(fs_type) => fs_type,
+            None => {
+                let fs_type = features::fs_magic(&self.dir)?;
+                self.fs_type = Some(fs_type);
+                fs_type
+            }
+        };
+        Ok(D_TYPE_CACHE.lock().unwrap().get(&fs_type) == Some(&false))
+    }
+
+    /// Get the type of `entry` (which must have been returned by this
+    /// [`Dirents`]). This only costs an `fstatat(2)` (without following
+    /// symlinks) if the filesystem didn't fill `d_type`, and never returns
+    /// [`EntryType::Unknown`].
+    ///
+    /// [`Dirents`]: struct.Dirents.html
+    /// [`EntryType::Unknown`]: enum.EntryType.html#variant.Unknown
+    pub fn entry_type(&mut self, entry: &DirEntry) -> Result<EntryType, Error> {
+        match entry.entry_type() {
+            EntryType::Unknown => (),
+            entry_type => return Ok(entry_type),
+        }
+        let stat =
+            syscalls::fstatat(self.dir.as_raw_fd(), &entry.name).context(error::RawOsError {
+                operation: "stat directory entry with unknown type",
+            })?;
+        if self.fs_type.is_none() {
+            let fs_type = features::fs_magic(&self.dir)?;
+            record_d_type_support(fs_type, false);
+            self.fs_type = Some(fs_type);
+        }
+        Ok(EntryType::from_mode(stat.st_mode))
+    }
+
+    /// Whether the filesystem of the directory is known to fill `d_type`, so
+    /// that callers can decide up front whether they will have to pay for
+    /// [`Dirents::entry_type`] falling back to `fstatat(2)`. This is `None` if
+    /// nothing is known about the filesystem yet (libpathrs only learns about
+    /// a filesystem when probing [`Handle::filesystem_features`] or when falling back to
+    /// `fstatat(2)` on it). If this is `Some(false)`, entries with `DT_UNKNOWN`
+    /// are resolved before they are returned (unless disabled with
+    /// [`Dirents::resolve_entry_types`]).
+    ///
+    /// [`Dirents::entry_type`]: struct.Dirents.html#method.entry_type
+    /// [`Handle::filesystem_features`]: struct.Handle.html#method.filesystem_features
+    /// [`Dirents::resolve_entry_types`]: struct.Dirents.html#method.resolve_entry_types
+    pub fn fills_d_type(&self) -> Result<Option<bool>, Error> {
+        let fs_type = match self.fs_type {
+            Some(fs_type) => fs_type,
+            None => features::fs_magic(&self.dir)?,
+        };
+        Ok(D_TYPE_CACHE.lock().unwrap().get(&fs_type).copied())
+    }
+
+    /// Take enough state to resume reading the directory later with
+    /// [`SuspendedDirents::resume`], after which the [`Dirents`] should be
+    /// dropped (closing the directory). This is used to limit the number of
+    /// file descriptors held open by recursive operations. If this fails, the
+    /// [`Dirents`] is left unchanged.
+    ///
+    /// [`Dirents`]: struct.Dirents.html
+    /// [`SuspendedDirents::resume`]: struct.SuspendedDirents.html#method.resume
+    pub(crate) fn suspend(&mut self) -> Result<SuspendedDirents, Error> {
+        let position = self.dir.stream_position().context(error::OsError {
+            operation: "get directory position",
+        })?;
+        Ok(SuspendedDirents {
+            buf: mem::take(&mut self.buf),
+            len: self.len,
+            offset: self.offset,
+            resolve_types: self.resolve_types,
+            fs_type: self.fs_type,
+            position,
+        })
+    }
+}
+
+/// A [`Dirents`] whose directory has been closed with [`Dirents::suspend`].
+///
+/// Any entries which were already read from the kernel are kept, and the
+/// directory position is restored when resuming.
+///
+/// [`Dirents`]: struct.Dirents.html
+/// [`Dirents::suspend`]: struct.Dirents.html#method.suspend
+#[derive(Debug)]
+pub(crate) struct SuspendedDirents {
+    buf: Vec<u8>,
+    len: usize,
+    offset: usize,
+    resolve_types: Option<bool>,
+    fs_type: Option<i64>,
+    position: u64,
+}
+
+impl SuspendedDirents {
+    /// Resume reading with `dir`, which must be the same directory the
+    /// [`Dirents`] was originally reading (opened for reading). If this fails,
+    /// the [`SuspendedDirents`] is left unchanged.
+    ///
+    /// [`Dirents`]: struct.Dirents.html
+    /// [`SuspendedDirents`]: struct.SuspendedDirents.html
+    pub(crate) fn resume(&mut self, mut dir: File) -> Result<Dirents, Error> {
+        dir.seek(SeekFrom::Start(self.position))
+            .context(error::OsError {
+                operation: "restore directory position",
+            })?;
+        Ok(Dirents {
+            dir,
+            buf: mem::take(&mut self.buf),
+            len: self.len,
+            offset: self.offset,
+            resolve_types: self.resolve_types,
+            fs_type: self.fs_type,
+        })
+    }
+}
+
+impl AsRawFd for Dirents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dir.as_raw_fd()
+    }
+}
+
+impl Iterator for Dirents {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.offset >= self.len {
+                self.len = match syscalls::getdents64(self.dir.as_raw_fd(), &mut self.buf) {
+                    Ok(0) => return None,
+                    Ok(len) => len,
+                    Err(err) => {
+                        return Some(Err(err).context(error::RawOsError {
+                            operation: "read directory entries",
+                        }))
+                    }
+                };
+                self.offset = 0;
+            }
+
+            let entry = &self.buf[self.offset..self.len];
+            let mut ino = [0u8; 8];
+            ino.copy_from_slice(&entry[Self::D_INO_OFFSET..Self::D_INO_OFFSET + 8]);
+            let mut reclen = [0u8; 2];
+            reclen.copy_from_slice(&entry[Self::D_RECLEN_OFFSET..Self::D_RECLEN_OFFSET + 2]);
+            let reclen = u16::from_ne_bytes(reclen) as usize;
+            let d_type = entry[Self::D_TYPE_OFFSET];
+            // d_name is NUL-terminated (and padded with NULs to reclen).
+            let name = &entry[Self::D_NAME_OFFSET..reclen];
+            let name = &name[..name.iter().position(|&c| c == b'\0').unwrap_or(name.len())];
+            self.offset += reclen;
+
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let mut entry = DirEntry {
+                ino: u64::from_ne_bytes(ino),
+                d_type,
+                name: OsStr::from_bytes(name).to_os_string(),
+            };
+            if d_type == libc::DT_UNKNOWN {
+                match self.should_resolve_types() {
+                    Ok(true) => (),
+                    Ok(false) => return Some(Ok(entry)),
+                    Err(err) => return Some(Err(err)),
+                }
+                match self.entry_type(&entry) {
+                    Ok(entry_type) => entry.d_type = entry_type.d_type(),
+                    // The entry was removed after it was read, so leave its
+                    // type unknown.
+                    Err(err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_d_type_support, Dirents, EntryType};
+    use crate::tests::TempDir;
+
+    use std::fs::{self, File};
+
+    // Made-up filesystem magics, so the cache entries used here can't be
+    // changed by other tests probing real filesystems.
+    const NO_D_TYPE_MAGIC: i64 = 0x7061_7468_0001;
+    const D_TYPE_MAGIC: i64 = 0x7061_7468_0002;
+    const UNKNOWN_MAGIC: i64 = 0x7061_7468_0003;
+
+    fn dirents_with_fs_type(tmpdir: &TempDir, fs_type: i64) -> Dirents {
+        let mut dirents = Dirents::new(File::open(tmpdir.path()).unwrap());
+        dirents.fs_type = Some(fs_type);
+        dirents
+    }
+
+    #[test]
+    fn resolve_types_from_cache() {
+        let tmpdir = TempDir::new();
+        record_d_type_support(NO_D_TYPE_MAGIC, false);
+        record_d_type_support(D_TYPE_MAGIC, true);
+
+        let mut dirents = dirents_with_fs_type(&tmpdir, NO_D_TYPE_MAGIC);
+        assert_eq!(dirents.fills_d_type().unwrap(), Some(false));
+        assert!(dirents.should_resolve_types().unwrap());
+        let mut dirents = dirents_with_fs_type(&tmpdir, D_TYPE_MAGIC);
+        assert_eq!(dirents.fills_d_type().unwrap(), Some(true));
+        assert!(!dirents.should_resolve_types().unwrap());
+        let mut dirents = dirents_with_fs_type(&tmpdir, UNKNOWN_MAGIC);
+        assert_eq!(dirents.fills_d_type().unwrap(), None);
+        assert!(!dirents.should_resolve_types().unwrap());
+
+        // An explicit choice overrides the cache.
+        let mut dirents = dirents_with_fs_type(&tmpdir, NO_D_TYPE_MAGIC).resolve_entry_types(false);
+        assert!(!dirents.should_resolve_types().unwrap());
+        let mut dirents = dirents_with_fs_type(&tmpdir, UNKNOWN_MAGIC).resolve_entry_types(true);
+        assert!(dirents.should_resolve_types().unwrap());
+    }
+
+    #[test]
+    fn resolve_types_survives_suspend() {
+        let tmpdir = TempDir::new();
+        fs::write(tmpdir.path().join("file"), "").unwrap();
+        record_d_type_support(NO_D_TYPE_MAGIC, false);
+
+        let mut dirents = dirents_with_fs_type(&tmpdir, NO_D_TYPE_MAGIC);
+        let mut suspended = dirents.suspend().unwrap();
+        drop(dirents);
+        let mut dirents = suspended
+            .resume(File::open(tmpdir.path()).unwrap())
+            .unwrap();
+        assert!(dirents.should_resolve_types().unwrap());
+
+        let entries = dirents.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].entry_type(), EntryType::File);
+    }
+}
@@ -0,0 +1,423 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    stat, syscalls,
+    utils::{self, FileExt},
+    DeviceType, Dirents, InodeType, OpenFlags, RenameFlags, Root,
+};
+
+use std::{
+    fs::{File, Permissions},
+    io::Error as IOError,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use libc::c_int;
+use snafu::ResultExt;
+
+/// Whether (and how much) a directory can be written to, as reported by
+/// [`Root::probe_writability`].
+///
+/// [`Root::probe_writability`]: struct.Root.html#method.probe_writability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Writability {
+    /// The inode is on a read-only mount (or filesystem), as reported by
+    /// `faccessat(2)` failing with `EROFS`.
+    pub read_only: bool,
+    /// The caller has write (and, for directories, search) permission on the
+    /// inode, as checked by `faccessat(2)` with the effective ids.
+    pub permitted: bool,
+    /// Number of bytes available to unprivileged users on the filesystem.
+    pub free_bytes: u64,
+    /// Number of free inodes on the filesystem, or `None` if the filesystem
+    /// doesn't have a fixed number of inodes (such as btrfs).
+    pub free_inodes: Option<u64>,
+}
+
+impl Writability {
+    /// Can the inode be modified (ignoring free space)?
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        !self.read_only && self.permitted
+    }
+
+    /// Probe the writability of `file`.
+    fn of(file: &File) -> Result<Self, Error> {
+        let meta = file.metadata().context(error::OsError {
+            operation: "fstat inode to probe writability",
+        })?;
+        let mode = if meta.is_dir() {
+            libc::W_OK | libc::X_OK
+        } else {
+            libc::W_OK
+        };
+        // faccessat(2) only takes AT_EMPTY_PATH with faccessat2, so check
+        // through the magic-link instead.
+        let path = utils::procfd_path(file.as_raw_fd())?;
+        let (read_only, permitted) = match syscalls::faccessat(libc::AT_FDCWD, path, mode) {
+            Ok(()) => (false, true),
+            Err(err) => match err.root_cause().raw_os_error() {
+                Some(libc::EROFS) => (true, true),
+                Some(libc::EACCES) | Some(libc::EPERM) => (false, false),
+                _ => {
+                    return Err(err).context(error::RawOsError {
+                        operation: "check write access",
+                    })
+                }
+            },
+        };
+        let statfs = syscalls::fstatfs(file.as_raw_fd()).context(error::RawOsError {
+            operation: "get free space",
+        })?;
+        Ok(Self {
+            read_only,
+            permitted,
+            free_bytes: statfs.f_bavail * statfs.f_bsize as u64,
+            free_inodes: if statfs.f_files == 0 {
+                None
+            } else {
+                Some(statfs.f_ffree)
+            },
+        })
+    }
+
+    /// Fail the way creating an entry with `bytes` of contents in the
+    /// directory would (`inode` is whether a new inode would be allocated).
+    fn check(&self, inode: bool, bytes: u64, operation: &str) -> Result<(), Error> {
+        if self.read_only {
+            would_fail(libc::EROFS, operation)
+        } else if !self.permitted {
+            would_fail(libc::EACCES, operation)
+        } else if (inode && self.free_inodes == Some(0)) || bytes > self.free_bytes {
+            would_fail(libc::ENOSPC, operation)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// What a mutating operation would have done, as reported by the
+/// `Root::dry_run_*` methods.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DryRunOutcome {
+    /// An inode would be created at `path`.
+    Create { path: PathBuf },
+    /// The inode at `path` would be removed.
+    Remove { path: PathBuf, directory: bool },
+    /// `source` would be renamed to `destination`, replacing (or, with
+    /// `RENAME_EXCHANGE`, swapping with) an existing inode if `replaces`.
+    Rename {
+        source: PathBuf,
+        destination: PathBuf,
+        replaces: bool,
+    },
+    /// `bytes` bytes would be copied from `source` to a new file at
+    /// `destination`.
+    Copy {
+        source: PathBuf,
+        destination: PathBuf,
+        bytes: u64,
+    },
+}
+
+/// Fail a dry run with the `errno` that the real operation would fail with.
+fn would_fail<T>(errno: c_int, operation: &str) -> Result<T, Error> {
+    Err(IOError::from_raw_os_error(errno)).context(error::OsError { operation })
+}
+
+/// Get the metadata of `name` in `dirfd` (without following symlinks), or
+/// `None` if it doesn't exist.
+fn lookup(dirfd: &File, name: &Path) -> Result<Option<libc::stat>, Error> {
+    match syscalls::fstatat(dirfd.as_raw_fd(), name) {
+        Ok(stat) => Ok(Some(stat)),
+        Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(err) => Err(err).context(error::RawOsError {
+            operation: "look up dry-run target",
+        }),
+    }
+}
+
+fn is_dir(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+/// Is the directory `name` in `dir` empty?
+fn is_empty_dir(dir: &File, name: &Path) -> Result<bool, Error> {
+    let file = syscalls::openat(
+        dir.as_raw_fd(),
+        name,
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+        0,
+    )
+    .context(error::RawOsError {
+        operation: "open directory to check emptiness",
+    })?;
+    Dirents::new(file)
+        .next()
+        .transpose()
+        .map(|entry| entry.is_none())
+}
+
+/// Are both directories on the same mount (so that links and renames between
+/// them don't fail with `EXDEV`)?
+fn same_mount(a: &File, b: &File) -> Result<bool, Error> {
+    match (
+        stat::mount_id(a.as_raw_fd())?,
+        stat::mount_id(b.as_raw_fd())?,
+    ) {
+        (Some(a), Some(b)) => Ok(a == b),
+        _ => Ok(a.inode_id()?.0 == b.inode_id()?.0),
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve `path` and probe whether it can be
+    /// modified -- whether it is on a read-only mount, whether the caller has
+    /// write permission, and how much free space the filesystem has. Nothing
+    /// is modified.
+    ///
+    /// To check whether an entry can be created in a directory, probe the
+    /// directory.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::resolve`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    pub fn probe_writability<P: AsRef<Path>>(&self, path: P) -> Result<Writability, Error> {
+        let handle = self
+            .resolve(path)
+            .wrap("resolve path to probe writability")?;
+        Writability::of(&handle.inner)
+    }
+
+    /// Resolve the parent directory of `path` (as the mutating operations
+    /// do), returning it with the final component of `path`.
+    fn resolve_dry_run_parent<'p>(&self, path: &'p Path) -> Result<(File, &'p Path), Error> {
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .grace_policy
+            .retry(|| self.resolve(parent))
+            .wrap("resolve target parent directory for dry run")?
+            .inner;
+        Ok((dir, name))
+    }
+
+    /// Do all of the resolution, policy and permission checks of
+    /// [`Root::create`] (including free space and inodes), but stop before
+    /// creating anything.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::create`] -- where the real
+    /// operation would have failed in a syscall, an error with the same
+    /// `errno` (such as `EEXIST`, `EROFS`, `EACCES` or `ENOSPC`) is returned.
+    /// The checks are done without any locking, so the real operation can
+    /// still fail if the tree changes in the meantime.
+    ///
+    /// [`Root::create`]: struct.Root.html#method.create
+    pub fn dry_run_create<P: AsRef<Path>>(
+        &self,
+        path: P,
+        inode_type: &InodeType,
+    ) -> Result<DryRunOutcome, Error> {
+        let path = path.as_ref();
+        let (dir, name) = self.resolve_dry_run_parent(path)?;
+        self.validate_name(name)?;
+        match inode_type {
+            InodeType::CharacterDevice(_, dev) => self.check_device(DeviceType::Character, *dev)?,
+            InodeType::BlockDevice(_, dev) => self.check_device(DeviceType::Block, *dev)?,
+            InodeType::Symlink(target) => utils::check_no_nul("target", target)?,
+            _ => (),
+        }
+        if let Some(perm) = inode_type.permissions() {
+            self.sanitize_mode(perm.mode())?;
+        }
+        if lookup(&dir, name)?.is_some() {
+            return would_fail(libc::EEXIST, "pathrs dry-run create");
+        }
+
+        let mut new_inode = true;
+        if let InodeType::Hardlink(target) = inode_type {
+            let (oldparent, oldname) =
+                path_split(target).wrap("split hardlink source path into (parent, name)")?;
+            let olddir = self
+                .resolve(oldparent)
+                .wrap("resolve hardlink source parent for dry run")?
+                .inner;
+            match lookup(&olddir, oldname)? {
+                None => return would_fail(libc::ENOENT, "pathrs dry-run create"),
+                Some(stat) if is_dir(&stat) => {
+                    return would_fail(libc::EPERM, "pathrs dry-run create")
+                }
+                Some(_) => (),
+            }
+            if !same_mount(&olddir, &dir)? {
+                return would_fail(libc::EXDEV, "pathrs dry-run create");
+            }
+            new_inode = false;
+        }
+        Writability::of(&dir)?.check(new_inode, 0, "pathrs dry-run create")?;
+
+        Ok(DryRunOutcome::Create {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Do all of the resolution and permission checks of [`Root::remove`],
+    /// but stop before removing anything.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::remove`] (with the caveats of
+    /// [`Root::dry_run_create`]).
+    ///
+    /// [`Root::remove`]: struct.Root.html#method.remove
+    /// [`Root::dry_run_create`]: struct.Root.html#method.dry_run_create
+    pub fn dry_run_remove<P: AsRef<Path>>(&self, path: P) -> Result<DryRunOutcome, Error> {
+        let path = path.as_ref();
+        let (dir, name) = self.resolve_dry_run_parent(path)?;
+        let stat = match lookup(&dir, name)? {
+            Some(stat) => stat,
+            None => return would_fail(libc::ENOENT, "pathrs dry-run remove"),
+        };
+        let directory = is_dir(&stat);
+        if directory && !is_empty_dir(&dir, name)? {
+            return would_fail(libc::ENOTEMPTY, "pathrs dry-run remove");
+        }
+        Writability::of(&dir)?.check(false, 0, "pathrs dry-run remove")?;
+
+        Ok(DryRunOutcome::Remove {
+            path: path.to_path_buf(),
+            directory,
+        })
+    }
+
+    /// Do all of the resolution and permission checks of [`Root::rename`]
+    /// (including the semantics of `RENAME_NOREPLACE` and `RENAME_EXCHANGE`),
+    /// but stop before renaming anything.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::rename`] (with the caveats of
+    /// [`Root::dry_run_create`]).
+    ///
+    /// [`Root::rename`]: struct.Root.html#method.rename
+    /// [`Root::dry_run_create`]: struct.Root.html#method.dry_run_create
+    pub fn dry_run_rename<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        flags: RenameFlags,
+    ) -> Result<DryRunOutcome, Error> {
+        let (source, destination) = (source.as_ref(), destination.as_ref());
+        let (src_dir, src_name) = self.resolve_dry_run_parent(source)?;
+        let (dst_dir, dst_name) = self.resolve_dry_run_parent(destination)?;
+        self.validate_name(dst_name)?;
+
+        let operation = "pathrs dry-run rename";
+        let src = match lookup(&src_dir, src_name)? {
+            Some(stat) => stat,
+            None => return would_fail(libc::ENOENT, operation),
+        };
+        let dst = lookup(&dst_dir, dst_name)?;
+        let exchange = flags.0 & libc::RENAME_EXCHANGE != 0;
+        match dst {
+            None if exchange => return would_fail(libc::ENOENT, operation),
+            Some(_) if flags.0 & libc::RENAME_NOREPLACE != 0 => {
+                return would_fail(libc::EEXIST, operation)
+            }
+            Some(dst) if !exchange => {
+                if is_dir(&src) && !is_dir(&dst) {
+                    return would_fail(libc::ENOTDIR, operation);
+                } else if !is_dir(&src) && is_dir(&dst) {
+                    return would_fail(libc::EISDIR, operation);
+                } else if is_dir(&dst) && !is_empty_dir(&dst_dir, dst_name)? {
+                    return would_fail(libc::ENOTEMPTY, operation);
+                }
+            }
+            _ => (),
+        }
+        if !same_mount(&src_dir, &dst_dir)? {
+            return would_fail(libc::EXDEV, operation);
+        }
+        Writability::of(&src_dir)?.check(false, 0, operation)?;
+        Writability::of(&dst_dir)?.check(false, 0, operation)?;
+
+        Ok(DryRunOutcome::Rename {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            replaces: dst.is_some(),
+        })
+    }
+
+    /// Do all of the resolution, policy and permission checks of
+    /// [`Root::copy_file`] (including whether the destination filesystem has
+    /// enough free space for the contents), but stop before creating the
+    /// destination.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::copy_file`] (with the caveats of
+    /// [`Root::dry_run_create`]).
+    ///
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    /// [`Root::dry_run_create`]: struct.Root.html#method.dry_run_create
+    pub fn dry_run_copy_file<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        perm: &Permissions,
+    ) -> Result<DryRunOutcome, Error> {
+        let (source, destination) = (source.as_ref(), destination.as_ref());
+        let src = self
+            .open_file(source, OpenFlags(libc::O_RDONLY))
+            .wrap("open copy source")?;
+        let metadata = src.metadata().context(error::OsError {
+            operation: "check copy source",
+        })?;
+        ensure!(
+            metadata.is_file(),
+            error::InvalidArgument {
+                name: "source",
+                description: "must be a regular file",
+            }
+        );
+
+        let (dir, name) = self.resolve_dry_run_parent(destination)?;
+        self.validate_name(name)?;
+        self.sanitize_mode(perm.mode())?;
+        if lookup(&dir, name)?.is_some() {
+            return would_fail(libc::EEXIST, "pathrs dry-run copy_file");
+        }
+        Writability::of(&dir)?.check(true, metadata.len(), "pathrs dry-run copy_file")?;
+
+        Ok(DryRunOutcome::Copy {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            bytes: metadata.len(),
+        })
+    }
+}
@@ -0,0 +1,309 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    path::normalize_lexical,
+    syscalls, Handle, InodeType, Root,
+};
+
+use std::{
+    fs::{File, FileType, Metadata, Permissions},
+    os::unix::{fs::FileTypeExt, io::AsRawFd},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// What [`Root::create_on_conflict`] should do if there is already an inode
+/// at the path being created.
+///
+/// [`Root::create_on_conflict`]: struct.Root.html#method.create_on_conflict
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum OnConflict {
+    /// Fail with an [`Error::Conflict`].
+    ///
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    #[default]
+    Error,
+    /// Leave the existing inode alone.
+    Skip,
+    /// Keep existing directories (so that the contents of the new and existing
+    /// directories are merged) and replace other existing inodes of the same
+    /// type. If the existing inode has a different type (such as a directory
+    /// being replaced by a file), fail with an [`Error::Conflict`].
+    ///
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    Merge,
+    /// Identical to [`OnConflict::Merge`], except that existing inodes of a
+    /// different type are replaced as well. Existing directories can only be
+    /// replaced if they are empty.
+    ///
+    /// [`OnConflict::Merge`]: enum.OnConflict.html#variant.Merge
+    Overwrite,
+}
+
+/// What [`Root::create_on_conflict`] did.
+///
+/// [`Root::create_on_conflict`]: struct.Root.html#method.create_on_conflict
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CreateOutcome {
+    /// There was no existing inode, and the new inode was created.
+    Created,
+    /// The existing inode was left alone ([`OnConflict::Skip`]).
+    ///
+    /// [`OnConflict::Skip`]: enum.OnConflict.html#variant.Skip
+    Skipped,
+    /// The existing directory was kept, to be merged with the new directory.
+    Merged,
+    /// The existing inode was removed and the new inode was created.
+    Replaced,
+}
+
+/// Does an existing inode of type `file_type` have the same type as the inode
+/// that would be created by `inode_type`?
+fn same_type(inode_type: &InodeType, file_type: FileType) -> bool {
+    match inode_type {
+        InodeType::File(_) => file_type.is_file(),
+        InodeType::Directory(_) => file_type.is_dir(),
+        InodeType::Symlink(_) => file_type.is_symlink(),
+        // We don't know the type of the hardlink target until it is linked,
+        // but it can never be a directory.
+        InodeType::Hardlink(_) => !file_type.is_dir(),
+        InodeType::Fifo(_) => file_type.is_fifo(),
+        InodeType::CharacterDevice(..) => file_type.is_char_device(),
+        InodeType::BlockDevice(..) => file_type.is_block_device(),
+        InodeType::DetachedSocket(_) => file_type.is_socket(),
+    }
+}
+
+impl Root {
+    /// Get the metadata of the entry at `path` (without following the final
+    /// component), returning it with its `O_PATH` handle.
+    fn existing_entry(&self, path: &Path) -> Result<(File, Metadata), Error> {
+        let file = self.open_entry_nofollow(path).wrap("open existing entry")?;
+        let meta = file.metadata().context(error::OsError {
+            operation: "fstat existing entry",
+        })?;
+        Ok((file, meta))
+    }
+
+    /// Within the [`Root`]'s tree, make sure that there is a directory at
+    /// `path`, creating it with the permissions `perm` if there is nothing at
+    /// `path`. Returns an `O_PATH` [`Handle`] to the directory.
+    ///
+    /// Unlike checking whether `path` exists before calling [`Root::create`],
+    /// this cannot be raced -- the directory is created first, and the
+    /// existing inode is only checked if the creation failed with `EEXIST`.
+    /// The final component of `path` is never followed, so a symlink to a
+    /// directory is a conflict. The permissions of an existing directory are
+    /// not changed.
+    ///
+    /// # Errors
+    ///
+    /// If the inode at `path` is not a directory, an [`Error::Conflict`] is
+    /// returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::create`]: struct.Root.html#method.create
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    pub fn ensure_dir<P: AsRef<Path>>(&self, path: P, perm: &Permissions) -> Result<Handle, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        match self.create(&path, &InodeType::Directory(perm)) {
+            Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(err).wrap("ensure directory")
+            }
+            _ => (),
+        }
+        let (file, meta) = self.existing_entry(&path)?;
+        ensure!(
+            meta.is_dir(),
+            error::Conflict {
+                path,
+                description: "existing inode is not a directory",
+            }
+        );
+        Ok(Handle::from_file_unchecked(file))
+    }
+
+    /// Within the [`Root`]'s tree, make sure that there is a regular file at
+    /// `path`, creating it (empty, with the permissions `perm`) if there is
+    /// nothing at `path`. Returns an `O_PATH` [`Handle`] to the file.
+    ///
+    /// As with [`Root::ensure_dir`], the final component of `path` is never
+    /// followed and the contents and permissions of an existing file are not
+    /// changed.
+    ///
+    /// # Errors
+    ///
+    /// If the inode at `path` is not a regular file, an [`Error::Conflict`]
+    /// is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::ensure_dir`]: struct.Root.html#method.ensure_dir
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    pub fn ensure_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        perm: &Permissions,
+    ) -> Result<Handle, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        match self.create(&path, &InodeType::File(perm)) {
+            Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(err).wrap("ensure file")
+            }
+            _ => (),
+        }
+        let (file, meta) = self.existing_entry(&path)?;
+        ensure!(
+            meta.is_file(),
+            error::Conflict {
+                path,
+                description: "existing inode is not a regular file",
+            }
+        );
+        Ok(Handle::from_file_unchecked(file))
+    }
+
+    /// Within the [`Root`]'s tree, make sure that there is a symlink at `path`
+    /// which points to `target`, creating it if there is nothing at `path`.
+    /// Returns an `O_PATH` [`Handle`] to the symlink itself.
+    ///
+    /// As with [`Root::ensure_dir`], the existing inode is only checked if the
+    /// creation failed with `EEXIST`. The symlink target is compared exactly
+    /// (it is not resolved), so `target` and `./target` are different targets.
+    ///
+    /// # Errors
+    ///
+    /// If the inode at `path` is not a symlink, or is a symlink to a different
+    /// target, an [`Error::Conflict`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::ensure_dir`]: struct.Root.html#method.ensure_dir
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    pub fn ensure_symlink<P: AsRef<Path>, T: AsRef<Path>>(
+        &self,
+        path: P,
+        target: T,
+    ) -> Result<Handle, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        let target = target.as_ref();
+        match self.create(&path, &InodeType::Symlink(target)) {
+            Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(err).wrap("ensure symlink")
+            }
+            _ => (),
+        }
+        let (file, meta) = self.existing_entry(&path)?;
+        ensure!(
+            meta.file_type().is_symlink(),
+            error::Conflict {
+                path,
+                description: "existing inode is not a symlink",
+            }
+        );
+        let existing = syscalls::readlinkat(file.as_raw_fd(), "").context(error::RawOsError {
+            operation: "readlink existing symlink",
+        })?;
+        ensure!(
+            existing == target,
+            error::Conflict {
+                path,
+                description: format!(
+                    "existing symlink points to {:?} rather than {:?}",
+                    existing, target
+                ),
+            }
+        );
+        Ok(Handle::from_file_unchecked(file))
+    }
+
+    /// Within the [`Root`]'s tree, create a new inode of type `inode_type` at
+    /// `path` (as with [`Root::create`]), handling an existing inode at `path`
+    /// according to `on_conflict`. This is intended to be the single place
+    /// where tools which copy or extract trees into a [`Root`] decide what to
+    /// do with existing entries.
+    ///
+    /// The final component of `path` is never followed, so an existing symlink
+    /// is treated like any other inode (and is replaced rather than its target
+    /// being overwritten). As with [`Root::ensure_dir`], the existing inode is
+    /// only inspected if creating the new inode failed with `EEXIST`.
+    ///
+    /// # Errors
+    ///
+    /// If `on_conflict` doesn't allow the existing inode to be handled (or an
+    /// existing directory which should be replaced is not empty), an
+    /// [`Error::Conflict`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::create`]: struct.Root.html#method.create
+    /// [`Root::ensure_dir`]: struct.Root.html#method.ensure_dir
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    pub fn create_on_conflict<P: AsRef<Path>>(
+        &self,
+        path: P,
+        inode_type: &InodeType,
+        on_conflict: OnConflict,
+    ) -> Result<CreateOutcome, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        match self.create(&path, inode_type) {
+            Ok(()) => return Ok(CreateOutcome::Created),
+            Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(err).wrap("create inode")
+            }
+            Err(_) => (),
+        }
+
+        let (_, meta) = self.existing_entry(&path)?;
+        let file_type = meta.file_type();
+        let same_type = same_type(inode_type, file_type);
+        match on_conflict {
+            OnConflict::Error => error::Conflict {
+                path: &path,
+                description: "an inode already exists",
+            }
+            .fail()?,
+            OnConflict::Skip => return Ok(CreateOutcome::Skipped),
+            OnConflict::Merge if !same_type => error::Conflict {
+                path: &path,
+                description: "existing inode has a different type",
+            }
+            .fail()?,
+            _ if same_type && file_type.is_dir() => return Ok(CreateOutcome::Merged),
+            _ => (),
+        }
+
+        match self.remove(&path) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTEMPTY) => error::Conflict {
+                path,
+                description: "existing directory is not empty",
+            }
+            .fail(),
+            Err(err) => Err(err).wrap("remove existing inode"),
+            Ok(()) => self
+                .create(&path, inode_type)
+                .wrap("create inode to replace existing inode")
+                .map(|_| CreateOutcome::Replaced),
+        }
+    }
+}
@@ -29,12 +29,14 @@
 //  * I figure out a nice way to implement GlobalBacktrace...
 
 #[doc(inline)]
-pub use crate::syscalls::{Error as SyscallError, FrozenFd};
+pub use crate::syscalls::{Error as SyscallError, FrozenFd, SyscallInfo};
 
 use std::{
     error::Error as StdError,
     io::Error as IOError,
+    path::PathBuf,
     sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use snafu::{GenerateBacktrace, ResultExt};
@@ -132,6 +134,151 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
+    /// An inode already exists at the requested path, but it doesn't match
+    /// the inode requested by one of the idempotent creation helpers (such as
+    /// [`Root::ensure_dir`]).
+    ///
+    /// [`Root::ensure_dir`]: ../struct.Root.html#method.ensure_dir
+    #[snafu(display("conflicting inode at {:?}: {}", path, description))]
+    Conflict {
+        /// Path of the conflicting inode.
+        path: PathBuf,
+        /// Description of how the existing inode differs from the request.
+        description: String,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// Following a symlink would escape the root it is in (see
+    /// [`Root::read_link_target_handle`]).
+    ///
+    /// [`Root::read_link_target_handle`]: ../struct.Root.html#method.read_link_target_handle
+    #[snafu(display("symlink {:?} -> {:?} would escape the root", path, target))]
+    WouldEscape {
+        /// Path of the symlink.
+        path: PathBuf,
+        /// Target of the symlink.
+        target: PathBuf,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// A component of a path is not a directory, even though the path
+    /// continues after it (`ENOTDIR` in the middle of a resolution).
+    #[snafu(display("path component {:?} is not a directory", component))]
+    NotADirectory {
+        /// Path (inside the root) of the component which is not a directory.
+        component: PathBuf,
+        /// Underlying error (`-ENOTDIR`).
+        #[snafu(backtrace)]
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+    },
+
+    /// An operation which needs a non-directory (such as opening a file for
+    /// writing) was given a directory (`EISDIR`).
+    #[snafu(display("{:?} is a directory", path))]
+    IsADirectory {
+        /// Path (inside the root) of the directory.
+        path: PathBuf,
+        /// Underlying error (`-EISDIR`).
+        #[snafu(backtrace)]
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+    },
+
+    /// Two paths which must be on the same mount (such as the paths given to
+    /// [`Root::swap_contents`]) are on different mounts or filesystems.
+    ///
+    /// [`Root::swap_contents`]: ../struct.Root.html#method.swap_contents
+    #[snafu(display("{:?} and {:?} are on different filesystems", first, second))]
+    CrossDevice {
+        /// The first path.
+        first: PathBuf,
+        /// The second path.
+        second: PathBuf,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// The file descriptor of a [`Root`] no longer refers to the directory the
+    /// [`Root`] was created with -- usually because it was closed (or replaced
+    /// with `dup2(2)`) behind libpathrs's back. See [`Root::check`].
+    ///
+    /// [`Root`]: ../struct.Root.html
+    /// [`Root::check`]: ../struct.Root.html#method.check
+    #[snafu(display("root file descriptor {} is stale: {}", fd, description))]
+    StaleRoot {
+        /// The file descriptor of the root.
+        fd: i32,
+        /// Description of what changed about the file descriptor.
+        description: String,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// The requested libpathrs operation ran out of file descriptors, either
+    /// because the process hit its `RLIMIT_NOFILE` or because the system-wide
+    /// limit was reached. Recursive operations try to stay within a budget of
+    /// file descriptors, so this usually means the process already had most of
+    /// its file descriptors open.
+    #[snafu(display(
+        "{} failed: too many open files (RLIMIT_NOFILE is {}) -- raise the limit, close unused file descriptors or lower the fd budget of the operation",
+        operation,
+        limit
+    ))]
+    TooManyOpenFiles {
+        /// Operation which was being attempted.
+        operation: String,
+        /// The soft `RLIMIT_NOFILE` of the process at the time of the error.
+        limit: u64,
+        /// Underlying error (`-EMFILE` or `-ENFILE`).
+        source: IOError,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// The requested libpathrs operation did not complete within its timeout
+    /// (see [`Root::with_timeout`]). The operation may still be running in the
+    /// background, and may still complete (or fail) at some point. This is
+    /// also returned without starting the operation if too many earlier
+    /// operations with a timeout are still stuck.
+    ///
+    /// [`Root::with_timeout`]: ../struct.Root.html#method.with_timeout
+    #[snafu(display("{} timed out after {:?}", operation, timeout))]
+    Timeout {
+        /// Operation which was being attempted.
+        operation: String,
+        /// The timeout which was exceeded.
+        timeout: Duration,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
+    /// An emulated path resolution was aborted because it exceeded its
+    /// [`ResolutionBudget`] (see [`Root::resolution_budget`]). This is usually
+    /// a sign that an attacker is racing the resolution by swapping symlinks
+    /// in the path, or that the filesystem is unreasonably slow.
+    ///
+    /// [`ResolutionBudget`]: ../struct.ResolutionBudget.html
+    /// [`Root::resolution_budget`]: ../struct.Root.html#structfield.resolution_budget
+    #[snafu(display(
+        "resolution of {:?} exceeded its budget ({} steps in {:?})",
+        path,
+        steps,
+        elapsed
+    ))]
+    ResolutionBudgetExceeded {
+        /// Path which was being resolved.
+        path: PathBuf,
+        /// Number of components walked before the resolution was aborted.
+        steps: u64,
+        /// Time spent on the resolution before it was aborted.
+        elapsed: Duration,
+        /// Backtrace captured at time of error.
+        backtrace: Backtrace,
+    },
+
     /// The requested libpathrs operation resulted in an [`IOError`]. This
     /// should be contrasted with [`RawOsError`] -- which indicates an error
     /// triggered by one of libpathrs's syscall wrappers.
@@ -161,9 +308,11 @@ pub enum Error {
     RawOsError {
         /// Operation which was being attempted.
         operation: String,
-        /// Underlying syscall wrapper error.
+        /// Underlying syscall wrapper error (boxed, since it holds copies of
+        /// the syscall arguments and would otherwise make every `Error` huge).
         #[snafu(backtrace)]
-        source: SyscallError,
+        #[snafu(source(from(SyscallError, Box::new)))]
+        source: Box<SyscallError>,
     },
 
     /// Wrapped represents an Error which has some simple string-wrapping
@@ -186,13 +335,22 @@ pub enum Error {
 pub(crate) trait ErrorExt {
     /// Wrap a `Result<..., Error>` with an additional context string.
     fn wrap<S: Into<String>>(self, context: S) -> Self;
+
+    /// Wrap a `Result<..., Error>` with an additional context string, which
+    /// is only built if there is an error.
+    fn wrap_with<S: Into<String>, F: FnOnce() -> S>(self, context: F) -> Self;
 }
 
 impl<T> ErrorExt for Result<T, Error> {
     fn wrap<S: Into<String>>(self, context: S) -> Self {
-        self.context(Wrapped {
-            context: context.into(),
-        })
+        // The context selector only converts context into a String if there
+        // is an error, so successful calls with a &'static str don't allocate.
+        // Contexts which have to be formatted should use wrap_with instead.
+        self.context(Wrapped { context })
+    }
+
+    fn wrap_with<S: Into<String>, F: FnOnce() -> S>(self, context: F) -> Self {
+        self.with_context(|| Wrapped { context: context() })
     }
 }
 
@@ -209,8 +367,15 @@ impl<'a> Iterator for Chain<'a> {
     type Item = &'a (dyn StdError + 'static);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let current = self.current;
-        self.current = self.current.and_then(StdError::source);
+        // Boxed sources (such as Error::Wrapped's) would otherwise be yielded
+        // as Box<Error>, which can't be downcast to Error.
+        let current = self
+            .current
+            .map(|err| match err.downcast_ref::<Box<Error>>() {
+                Some(boxed) => boxed.as_ref() as &(dyn StdError + 'static),
+                None => err,
+            });
+        self.current = current.and_then(StdError::source);
         current
     }
 }
@@ -221,7 +386,7 @@ impl Error {
     ///
     /// [`Error::chain`]: https://doc.rust-lang.org/nightly/std/error/trait.Error.html#method.chain
     // XXX: https://github.com/rust-lang/rust/issues/58520
-    pub(crate) fn iter_chain_hotfix(&self) -> Chain {
+    pub(crate) fn iter_chain_hotfix(&self) -> Chain<'_> {
         Chain {
             current: Some(self),
         }
@@ -233,4 +398,53 @@ impl Error {
             .last()
             .expect("Error::iter_chain_hotfix() should have at least one result")
     }
+
+    /// Get the `errno` of the root cause of this error, if the root cause was
+    /// an OS error.
+    pub(crate) fn raw_os_error(&self) -> Option<i32> {
+        self.root_cause()
+            .downcast_ref::<IOError>()
+            .and_then(IOError::raw_os_error)
+    }
+
+    /// Get the description of the [`SafetyViolation`] which caused this
+    /// error (if any).
+    ///
+    /// [`SafetyViolation`]: enum.Error.html#variant.SafetyViolation
+    pub(crate) fn safety_violation(&self) -> Option<&str> {
+        self.iter_chain_hotfix()
+            .filter_map(|err| err.downcast_ref::<Error>())
+            .find_map(|err| match err {
+                Error::SafetyViolation { description, .. } => Some(description.as_str()),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorExt};
+    use crate::{error, tests::count_allocations};
+
+    #[test]
+    fn wrap_success_allocations() {
+        let (ret, count) = count_allocations(|| Ok::<_, Error>(()).wrap("static context"));
+        ret.unwrap();
+        assert_eq!(count, 0);
+        let (ret, count) = count_allocations(|| {
+            Ok::<_, Error>(()).wrap_with(|| format!("formatted context {}", 42))
+        });
+        ret.unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn wrap_with_error() {
+        let err = error::NotSupported { feature: "test" }
+            .fail::<()>()
+            .wrap_with(|| format!("formatted context {}", 42))
+            .unwrap_err();
+        assert!(matches!(err, Error::Wrapped { .. }));
+        assert_eq!(err.to_string(), "formatted context 42");
+    }
 }
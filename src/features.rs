@@ -0,0 +1,394 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    dirent,
+    error::{self, Error, ErrorExt},
+    syscalls,
+    utils::FileExt,
+    Dirents, Handle, RenameFlags, Root,
+};
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use snafu::ResultExt;
+
+lazy_static! {
+    /// Results of probing `renameat2(2)` flags, keyed by the filesystem magic
+    /// (`f_type`) and the flag.
+    static ref RENAME_FLAG_CACHE: Mutex<HashMap<(i64, u32), bool>> = Mutex::new(HashMap::new());
+}
+
+/// The `renameat2(2)` flags which [`RenameFlags::supported_on`] can probe.
+///
+/// [`RenameFlags::supported_on`]: struct.RenameFlags.html#method.supported_on
+const PROBED_RENAME_FLAGS: u32 =
+    libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT;
+
+/// Which `renameat2(2)` flags are supported on a particular filesystem, as
+/// returned by [`Root::rename_support`].
+///
+/// [`Root::rename_support`]: struct.Root.html#method.rename_support
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenameSupport {
+    /// Whether `RENAME_NOREPLACE` is supported.
+    pub noreplace: bool,
+    /// Whether `RENAME_EXCHANGE` is supported.
+    pub exchange: bool,
+    /// Whether `RENAME_WHITEOUT` is supported (and permitted -- it requires
+    /// `CAP_MKNOD`).
+    pub whiteout: bool,
+}
+
+/// Which `fallocate(2)` modes are supported on a particular filesystem (see
+/// [`FilesystemFeatures`]).
+///
+/// [`FilesystemFeatures`]: struct.FilesystemFeatures.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FallocateSupport {
+    /// Whether plain allocation (a `mode` of `0`) is supported.
+    pub allocate: bool,
+    /// Whether `FALLOC_FL_KEEP_SIZE` is supported.
+    pub keep_size: bool,
+    /// Whether `FALLOC_FL_PUNCH_HOLE` is supported.
+    pub punch_hole: bool,
+    /// Whether `FALLOC_FL_ZERO_RANGE` is supported.
+    pub zero_range: bool,
+}
+
+/// The features supported by a filesystem, as returned by
+/// [`Handle::filesystem_features`].
+///
+/// [`Handle::filesystem_features`]: struct.Handle.html#method.filesystem_features
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilesystemFeatures {
+    /// The filesystem magic (`f_type` from `statfs(2)`).
+    pub fs_type: i64,
+    /// Whether `O_TMPFILE` is supported.
+    pub tmpfile: bool,
+    /// Which `renameat2(2)` flags are supported.
+    pub rename: RenameSupport,
+    /// Whether `user.*` extended attributes are supported.
+    pub user_xattrs: bool,
+    /// Whether reflinks (the `FICLONE` ioctl) are supported.
+    pub reflink: bool,
+    /// Which `fallocate(2)` modes are supported.
+    pub fallocate: FallocateSupport,
+    /// Whether `getdents64(2)` fills in `d_type` (rather than always returning
+    /// `DT_UNKNOWN`).
+    pub d_type: bool,
+}
+
+/// Does `err` indicate that the probed feature is not supported (rather than
+/// some other failure)?
+fn is_unsupported(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP)
+            | Some(libc::EINVAL)
+            | Some(libc::ENOTTY)
+            | Some(libc::EXDEV)
+            | Some(libc::EISDIR)
+            | Some(libc::ENOSYS)
+            | Some(libc::EPERM)
+    )
+}
+
+/// Convert the result of a feature probe into whether the feature is
+/// supported.
+fn probe_result(result: Result<(), Error>) -> Result<bool, Error> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(err) if is_unsupported(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Uniquely-named scratch files used to probe a filesystem, which are removed
+/// when dropped.
+pub(crate) struct ProbeFiles<'a> {
+    dir: &'a File,
+    prefix: String,
+}
+
+impl<'a> ProbeFiles<'a> {
+    pub(crate) fn new(dir: &'a File) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        Self {
+            dir,
+            prefix: format!(
+                ".pathrs-probe.{}.{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    /// Name of the scratch file `name` (which may not exist yet).
+    pub(crate) fn name(&self, name: &str) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.prefix, name))
+    }
+
+    /// Create the empty scratch file `name`.
+    pub(crate) fn create(&self, name: &str) -> Result<File, Error> {
+        syscalls::openat(
+            self.dir.as_raw_fd(),
+            self.name(name),
+            libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+            0o600,
+        )
+        .context(error::RawOsError {
+            operation: "create filesystem probe file",
+        })
+    }
+}
+
+impl Drop for ProbeFiles<'_> {
+    fn drop(&mut self) {
+        for name in &["a", "b", "c"] {
+            let _ = syscalls::unlinkat(self.dir.as_raw_fd(), self.name(name), 0);
+        }
+    }
+}
+
+/// Get the filesystem magic (`f_type`) of `file`.
+// The type of f_type differs between architectures.
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn fs_magic(file: &File) -> Result<i64, Error> {
+    let statfs = syscalls::fstatfs(file.as_raw_fd()).context(error::RawOsError {
+        operation: "get filesystem type for feature probe",
+    })?;
+    Ok(statfs.f_type as i64)
+}
+
+/// Probe whether the single `renameat2(2)` flag `flag` is supported in the
+/// directory `dir`, by actually doing a rename of some scratch files. Results
+/// are cached per filesystem type.
+pub(crate) fn probe_rename_flag(dir: &File, flag: u32) -> Result<bool, Error> {
+    if !*syscalls::RENAME_FLAGS_SUPPORTED || flag & !PROBED_RENAME_FLAGS != 0 {
+        return Ok(false);
+    }
+    let key = (fs_magic(dir)?, flag);
+    if let Some(supported) = RENAME_FLAG_CACHE.lock().unwrap().get(&key) {
+        return Ok(*supported);
+    }
+
+    let files = ProbeFiles::new(dir);
+    files.create("a")?;
+    files.create("b")?;
+    let (old, new) = if flag == libc::RENAME_WHITEOUT {
+        (files.name("a"), files.name("c"))
+    } else {
+        (files.name("a"), files.name("b"))
+    };
+    let dirfd = dir.as_raw_fd();
+    let errno = match syscalls::renameat2(dirfd, &old, dirfd, &new, flag) {
+        Ok(()) => None,
+        Err(err) => err.root_cause().raw_os_error(),
+    };
+    let supported = match (flag, errno) {
+        // RENAME_NOREPLACE should refuse to replace "b".
+        (libc::RENAME_NOREPLACE, Some(libc::EEXIST)) => true,
+        (libc::RENAME_NOREPLACE, None) => false,
+        (_, None) => true,
+        (_, Some(libc::EINVAL)) => false,
+        // RENAME_WHITEOUT requires CAP_MKNOD, which has nothing to do with
+        // the filesystem, so don't cache this result.
+        (libc::RENAME_WHITEOUT, Some(libc::EPERM)) => return Ok(false),
+        (_, Some(errno)) => {
+            return Err(std::io::Error::from_raw_os_error(errno)).context(error::OsError {
+                operation: "probe renameat2 flag support",
+            })
+        }
+    };
+    RENAME_FLAG_CACHE.lock().unwrap().insert(key, supported);
+    Ok(supported)
+}
+
+impl Handle {
+    /// Probe which features are supported by the filesystem of this directory
+    /// [`Handle`], so that callers can pick a strategy up front (rather than
+    /// failing half-way through an operation).
+    ///
+    /// Each feature is probed by actually using it on some scratch files
+    /// created in the directory (which are removed afterwards), so the
+    /// directory must be writable. Apart from the [`RenameSupport`] (which is
+    /// cached per filesystem type, see [`RenameFlags::supported_on`]), every
+    /// call does a fresh probe.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] is not a directory, an error is returned. Features
+    /// which are not supported are not errors, but unexpected errors during a
+    /// probe (such as `ENOSPC`) are.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`RenameSupport`]: struct.RenameSupport.html
+    /// [`RenameFlags::supported_on`]: struct.RenameFlags.html#method.supported_on
+    pub fn filesystem_features(&self) -> Result<FilesystemFeatures, Error> {
+        let dir = &self.inner;
+        let dirfd = dir.as_raw_fd();
+        let is_dir = dir
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat handle to probe filesystem features",
+            })?
+            .is_dir();
+        ensure!(
+            is_dir,
+            error::InvalidArgument {
+                name: "handle",
+                description: "filesystem features can only be probed in a directory",
+            }
+        );
+        let tmpfile = probe_result(
+            syscalls::openat(dirfd, ".", libc::O_TMPFILE | libc::O_RDWR, 0o600)
+                .map(|_| ())
+                .context(error::RawOsError {
+                    operation: "probe O_TMPFILE support",
+                }),
+        )?;
+        let rename = RenameSupport {
+            noreplace: probe_rename_flag(dir, libc::RENAME_NOREPLACE)?,
+            exchange: probe_rename_flag(dir, libc::RENAME_EXCHANGE)?,
+            whiteout: probe_rename_flag(dir, libc::RENAME_WHITEOUT)?,
+        };
+
+        let files = ProbeFiles::new(dir);
+        let file = files.create("a")?;
+        let clone = files.create("b")?;
+        let user_xattrs = probe_result(
+            file.set_xattr(OsStr::new("user.pathrs.probe"), b"1")
+                .wrap("probe user xattr support"),
+        )?;
+        let fallocate = |mode, offset| {
+            probe_result(
+                syscalls::fallocate(file.as_raw_fd(), mode, offset, 4096).context(
+                    error::RawOsError {
+                        operation: "probe fallocate mode support",
+                    },
+                ),
+            )
+        };
+        let fallocate = FallocateSupport {
+            allocate: fallocate(0, 0)?,
+            keep_size: fallocate(libc::FALLOC_FL_KEEP_SIZE, 4096)?,
+            punch_hole: fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, 0)?,
+            zero_range: fallocate(libc::FALLOC_FL_ZERO_RANGE, 0)?,
+        };
+        let reflink = probe_result(
+            syscalls::ioctl_ficlone(clone.as_raw_fd(), file.as_raw_fd()).context(
+                error::RawOsError {
+                    operation: "probe reflink support",
+                },
+            ),
+        )?;
+
+        let name = files.name("a");
+        let reader = syscalls::openat(dirfd, ".", libc::O_RDONLY | libc::O_DIRECTORY, 0).context(
+            error::RawOsError {
+                operation: "open directory to probe d_type support",
+            },
+        )?;
+        let mut d_type = false;
+        // Don't let earlier results for this filesystem fill in d_type.
+        for entry in Dirents::new(reader).resolve_entry_types(false) {
+            let entry = entry?;
+            if entry.name().as_bytes() == name.as_os_str().as_bytes() {
+                d_type = entry.d_type() != libc::DT_UNKNOWN;
+                break;
+            }
+        }
+
+        let fs_type = fs_magic(dir)?;
+        dirent::record_d_type_support(fs_type, d_type);
+
+        Ok(FilesystemFeatures {
+            fs_type,
+            tmpfile,
+            rename,
+            user_xattrs,
+            reflink,
+            fallocate,
+            d_type,
+        })
+    }
+}
+
+impl RenameFlags {
+    /// Is this set of RenameFlags supported on the filesystem of the directory
+    /// `path` inside `root`?
+    ///
+    /// Unlike [`RenameFlags::supported`] (which only checks whether the kernel
+    /// supports `renameat2(2)`), this checks each flag separately by doing a
+    /// rename of some scratch files in the directory -- so the directory must
+    /// be writable. Filesystems differ in which flags they support (for
+    /// instance, many filesystems support `RENAME_EXCHANGE` but not
+    /// `RENAME_WHITEOUT`). The results are cached per filesystem type.
+    ///
+    /// Only `RENAME_NOREPLACE`, `RENAME_EXCHANGE` and `RENAME_WHITEOUT` can be
+    /// probed, and any other flags are reported as unsupported.
+    ///
+    /// [`RenameFlags::supported`]: struct.RenameFlags.html#method.supported
+    pub fn supported_on<P: AsRef<Path>>(self, root: &Root, path: P) -> Result<bool, Error> {
+        let dir = root
+            .resolve(path)
+            .wrap("resolve directory to probe rename flags")?;
+        let mut remaining = self.0;
+        while remaining != 0 {
+            let flag = 1 << remaining.trailing_zeros();
+            if !probe_rename_flag(&dir.inner, flag)? {
+                return Ok(false);
+            }
+            remaining &= !flag;
+        }
+        Ok(true)
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, probe which `renameat2(2)` flags are
+    /// supported on the filesystem of the directory at `path`. See
+    /// [`RenameFlags::supported_on`] for how the probing is done.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`RenameFlags::supported_on`]: struct.RenameFlags.html#method.supported_on
+    pub fn rename_support<P: AsRef<Path>>(&self, path: P) -> Result<RenameSupport, Error> {
+        let dir = self
+            .resolve(path)
+            .wrap("resolve directory to probe rename flags")?;
+        Ok(RenameSupport {
+            noreplace: probe_rename_flag(&dir.inner, libc::RENAME_NOREPLACE)?,
+            exchange: probe_rename_flag(&dir.inner, libc::RENAME_EXCHANGE)?,
+            whiteout: probe_rename_flag(&dir.inner, libc::RENAME_WHITEOUT)?,
+        })
+    }
+}
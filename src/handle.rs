@@ -18,11 +18,23 @@
 
 #![forbid(unsafe_code)]
 
-use crate::{error::Error, utils::RawFdExt};
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls,
+    utils::{self, FileExt, RawFdExt},
+    Dirents,
+};
 
-use std::fs::File;
+use std::{
+    fs::{File, Permissions},
+    ops::Deref,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use libc::c_int;
+use snafu::ResultExt;
 
 /// A handle to an existing inode within a [`Root`].
 ///
@@ -91,6 +103,26 @@ impl OpenFlags {
         let acc = self.access_mode();
         acc == libc::O_WRONLY || acc == libc::O_RDWR
     }
+
+    /// Add `O_DIRECT` to the flags, so that I/O bypasses the page cache.
+    ///
+    /// With `O_DIRECT` the buffers, lengths and file offsets of every read and
+    /// write must be suitably aligned (usually to the logical block size of
+    /// the underlying device) or the I/O fails with `EINVAL`. Some filesystems
+    /// (such as tmpfs) don't support `O_DIRECT` at all. See
+    /// [`CopyOptions::direct_io`] for a copy helper which handles this for you.
+    ///
+    /// [`CopyOptions::direct_io`]: struct.CopyOptions.html#structfield.direct_io
+    #[inline]
+    pub fn with_direct(self) -> Self {
+        Self(self.0 | libc::O_DIRECT)
+    }
+
+    /// Do the flags contain `O_DIRECT`?
+    #[inline]
+    pub fn wants_direct(self) -> bool {
+        self.0 & libc::O_DIRECT == libc::O_DIRECT
+    }
 }
 
 impl Handle {
@@ -108,6 +140,11 @@ impl Handle {
     ///    terminal (if you don't have one already, and the fd references a
     ///    TTY).
     ///
+    /// FIFOs and device inodes are opened with `O_NONBLOCK` (which is then
+    /// cleared, unless it is present in `flags`) so that this cannot block
+    /// forever waiting for a peer. As a result, opening a FIFO for writing
+    /// with no readers fails with `ENXIO`.
+    ///
     /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
     /// [`Root::create`]: struct.Root.html#method.create
     pub fn reopen<F: Into<OpenFlags>>(&self, flags: F) -> Result<File, Error> {
@@ -126,6 +163,87 @@ impl Handle {
         })
     }
 
+    /// Create a copy of an existing [`Handle`] with the given file descriptor
+    /// flags (as in `fcntl(F_SETFD)`), rather than the default `FD_CLOEXEC`.
+    ///
+    /// # Errors
+    ///
+    /// Only `FD_CLOEXEC` is currently defined by Linux, so any other flags will
+    /// result in an [`Error::InvalidArgument`].
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn dup_with_flags(&self, fd_flags: c_int) -> Result<Self, Error> {
+        ensure!(
+            fd_flags & !libc::FD_CLOEXEC == 0,
+            error::InvalidArgument {
+                name: "fd_flags",
+                description: "only FD_CLOEXEC is supported",
+            }
+        );
+        let inner = self.inner.try_clone_hotfix()?;
+        if fd_flags != libc::FD_CLOEXEC {
+            syscalls::fcntl_setfd(inner.as_raw_fd(), fd_flags).context(error::RawOsError {
+                operation: "set fd flags of handle",
+            })?;
+        }
+        Ok(Self { inner })
+    }
+
+    /// "Downgrade" an open [`File`] to an `O_PATH` [`Handle`] to the same
+    /// inode.
+    ///
+    /// The file is re-opened through procfs with `O_PATH`, and the new
+    /// descriptor is verified to reference the same inode as `file`. The
+    /// original file is not modified.
+    ///
+    /// # Safety
+    ///
+    /// As with [`Handle::from_file_unchecked`], the caller guarantees that
+    /// `file` was obtained safely (usually with [`Handle::reopen`]).
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
+    /// [`Handle::from_file_unchecked`]: struct.Handle.html#method.from_file_unchecked
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    pub fn downgrade(file: &File) -> Result<Self, Error> {
+        let inner = file
+            .reopen(OpenFlags(libc::O_PATH))
+            .wrap("reopen file as O_PATH handle")?;
+        ensure!(
+            inner.inode_id()? == file.inode_id()?,
+            error::SafetyViolation {
+                description: "downgraded handle references a different inode",
+            }
+        );
+        Ok(Self { inner })
+    }
+
+    /// "Upgrade" the handle to a usable [`File`] handle, as with
+    /// [`Handle::reopen`], but additionally verify that the new [`File`]
+    /// references the same inode as the [`Handle`].
+    ///
+    /// # Errors
+    ///
+    /// If the re-opened file references a different inode (which should only
+    /// be possible if procfs has been tampered with), an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn upgrade<F: Into<OpenFlags>>(&self, flags: F) -> Result<File, Error> {
+        let file = self.reopen(flags)?;
+        ensure!(
+            file.inode_id()? == self.inner.inode_id()?,
+            error::SafetyViolation {
+                description: "upgraded file references a different inode",
+            }
+        );
+        Ok(file)
+    }
+
     /// Unwrap a [`Handle`] to reveal the underlying [`File`].
     ///
     /// [`Handle`]: struct.Handle.html
@@ -157,9 +275,175 @@ impl Handle {
         Self { inner }
     }
 
+    /// Read the entries of the directory referenced by the [`Handle`].
+    ///
+    /// The directory is re-opened (as with [`Handle::reopen`]) with `O_RDONLY
+    /// | O_DIRECTORY` and read directly with `getdents64(2)`. See [`Dirents`]
+    /// for more details.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] is not a directory, an error is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    /// [`Dirents`]: struct.Dirents.html
+    pub fn read_dir(&self) -> Result<Dirents, Error> {
+        let dir = self
+            .reopen(libc::O_RDONLY | libc::O_DIRECTORY)
+            .wrap("reopen directory for reading")?;
+        Ok(Dirents::new(dir))
+    }
+
+    /// Change the permissions of the inode referenced by the [`Handle`], as
+    /// with `fchmod(2)`.
+    ///
+    /// Symlinks do not have permissions, so this will fail if the [`Handle`]
+    /// references a symlink.
+    ///
+    /// A [`Handle`] doesn't know which [`Root`] it was resolved in, so no
+    /// [`ModePolicy`] is applied to `perm`. Use [`Root::set_permissions`] if
+    /// the [`Root`]'s `mode_policy` should apply.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root`]: struct.Root.html
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    /// [`Root::set_permissions`]: struct.Root.html#method.set_permissions
+    pub fn set_permissions(&self, perm: &Permissions) -> Result<(), Error> {
+        self.inner.set_mode(perm.mode() & !libc::S_IFMT)
+    }
+
+    /// Change the owner of the inode referenced by the [`Handle`], as with
+    /// `fchown(2)`. If `uid` or `gid` are `None`, they are left unchanged.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    pub fn set_owner(
+        &self,
+        uid: Option<libc::uid_t>,
+        gid: Option<libc::gid_t>,
+    ) -> Result<(), Error> {
+        // -1 means "leave unchanged" for chown(2).
+        self.inner.set_owner(
+            uid.unwrap_or(libc::uid_t::MAX),
+            gid.unwrap_or(libc::gid_t::MAX),
+        )
+    }
+
+    /// Change the access and modification times of the inode referenced by
+    /// the [`Handle`], as with `futimens(2)`. If `atime` or `mtime` are
+    /// `None`, they are left unchanged.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    pub fn set_times(
+        &self,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner
+            .set_timestamps(to_timespec(atime), to_timespec(mtime))
+    }
+
+    /// Get the `/proc/self/fd/$n` path of the [`Handle`], for use with kernel
+    /// interfaces which only accept a path (such as `mount(2)` or some
+    /// `ioctl(2)`s on devices).
+    ///
+    /// When the kernel resolves this path, it jumps directly to the inode
+    /// referenced by the [`Handle`] (this is a "magic-link"), so the path
+    /// cannot be swapped for something else after the [`Handle`] was
+    /// resolved. This makes it the safest path to give to such interfaces --
+    /// but there are still caveats which you need to be aware of:
+    ///
+    /// * The path is only valid while the returned [`ProcFdPath`] (and thus
+    ///   the [`Handle`]) is alive. If the file descriptor is closed, the path
+    ///   will either stop existing or refer to an unrelated file which reuses
+    ///   the same file descriptor number.
+    /// * The path is only meaningful within the current process. It must not
+    ///   be passed to other processes, and it may refer to something else
+    ///   after a `setns(2)` or `chroot(2)` which changes what `/proc` is.
+    /// * The path goes through the global `/proc` (there is no way of passing
+    ///   a file descriptor in a path), so if an attacker can mount over
+    ///   `/proc` in your mount namespace they can redirect it. Prefer
+    ///   interfaces which take a file descriptor wherever possible.
+    /// * Any path-based interface will follow the magic-link, and the
+    ///   semantics of doing so for an `O_PATH` descriptor vary between
+    ///   syscalls (some will operate on the inode, others will fail).
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`ProcFdPath`]: struct.ProcFdPath.html
+    pub fn proc_fd_path(&self) -> Result<ProcFdPath<'_>, Error> {
+        let path = utils::procfd_path(self.inner.as_raw_fd())?;
+        Ok(ProcFdPath { handle: self, path })
+    }
+
     // TODO: All the different stat* interfaces?
 
     // TODO: bind(). This might be safe to do (set the socket path to
     //       /proc/self/fd/...) but I'm a bit sad it'd be separate from
     //       Handle::reopen().
 }
+
+/// The `/proc/self/fd/$n` path of a [`Handle`], returned by
+/// [`Handle::proc_fd_path`]. This borrows the [`Handle`] so that it cannot be
+/// closed while the path is in use -- see [`Handle::proc_fd_path`] for the
+/// caveats of using the path.
+///
+/// [`Handle`]: struct.Handle.html
+/// [`Handle::proc_fd_path`]: struct.Handle.html#method.proc_fd_path
+#[derive(Debug)]
+pub struct ProcFdPath<'a> {
+    handle: &'a Handle,
+    path: PathBuf,
+}
+
+impl ProcFdPath<'_> {
+    /// The [`Handle`] which the path refers to.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        self.handle
+    }
+
+    /// The `/proc/self/fd/$n` path.
+    #[inline]
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for ProcFdPath<'_> {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for ProcFdPath<'_> {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Convert an optional `SystemTime` to a `(tv_sec, tv_nsec)` pair for
+/// `utimensat(2)`, with `None` mapping to `UTIME_OMIT`.
+fn to_timespec(time: Option<SystemTime>) -> (i64, i64) {
+    let time = match time {
+        None => return (0, libc::UTIME_OMIT),
+        Some(time) => time,
+    };
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos() as i64),
+        Err(err) => {
+            // Times before the epoch have a negative tv_sec, but tv_nsec must
+            // still be positive.
+            let before = err.duration();
+            let (sec, nsec) = (before.as_secs() as i64, before.subsec_nanos() as i64);
+            if nsec == 0 {
+                (-sec, 0)
+            } else {
+                (-sec - 1, 1_000_000_000 - nsec)
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::error::{self, Error};
+
+use std::{env, sync::RwLock};
+
+/// Process-wide overrides which disable (or strengthen) parts of libpathrs,
+/// intended as a kill-switch for operators when a kernel bug is suspected.
+///
+/// The overrides take precedence over the configuration of every [`Root`]. They
+/// are global to the process, and can be changed with
+/// [`set_hardening_overrides`] or loaded from the environment with
+/// [`apply_env_overrides`] -- libpathrs never reads the environment by itself.
+///
+/// [`Root`]: struct.Root.html
+/// [`set_hardening_overrides`]: fn.set_hardening_overrides.html
+/// [`apply_env_overrides`]: fn.apply_env_overrides.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HardeningOverrides {
+    /// Always use [`ResolverBackend::Emulated`], regardless of the
+    /// [`Resolver`] of the [`Root`].
+    ///
+    /// [`ResolverBackend::Emulated`]: enum.ResolverBackend.html#variant.Emulated
+    /// [`Resolver`]: struct.Resolver.html
+    /// [`Root`]: struct.Root.html
+    pub force_emulated: bool,
+    /// Never pass `RESOLVE_CACHED` to `openat2(2)`.
+    pub disable_resolve_cached: bool,
+    /// After every [`Root::resolve`], verify that the [`Handle`] really is
+    /// inside the [`Root`] with [`Root::relative_path_of`]. This roughly
+    /// doubles the cost of resolutions, and resolutions of paths which are
+    /// concurrently renamed may fail spuriously with an
+    /// [`Error::SafetyViolation`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Root::relative_path_of`]: struct.Root.html#method.relative_path_of
+    /// [`Handle`]: struct.Handle.html
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub paranoid: bool,
+}
+
+lazy_static! {
+    static ref HARDENING_OVERRIDES: RwLock<HardeningOverrides> =
+        RwLock::new(HardeningOverrides::default());
+}
+
+/// Get the current [`HardeningOverrides`] of libpathrs.
+///
+/// [`HardeningOverrides`]: struct.HardeningOverrides.html
+pub fn hardening_overrides() -> HardeningOverrides {
+    *HARDENING_OVERRIDES.read().unwrap()
+}
+
+/// Change the [`HardeningOverrides`] of libpathrs. This affects every
+/// operation (including those running in other threads) started after the
+/// change.
+///
+/// [`HardeningOverrides`]: struct.HardeningOverrides.html
+pub fn set_hardening_overrides(overrides: HardeningOverrides) {
+    *HARDENING_OVERRIDES.write().unwrap() = overrides;
+}
+
+/// Update the [`HardeningOverrides`] of libpathrs from the environment of the
+/// process, and return the resulting overrides.
+///
+/// The following variables are read, and each can be set to `1` (or `true`,
+/// `yes`, `on`) to enable the override or `0` (or `false`, `no`, `off`) to
+/// disable it:
+///
+/// * `PATHRS_FORCE_EMULATED` -- `force_emulated`.
+/// * `PATHRS_DISABLE_RESOLVE_CACHED` -- `disable_resolve_cached`.
+/// * `PATHRS_PARANOID` -- `paranoid`.
+///
+/// Overrides whose variable is unset are left unchanged, so this can be
+/// combined with [`set_hardening_overrides`].
+///
+/// # Errors
+///
+/// If any of the variables has an unrecognised value, an error is returned
+/// and none of the overrides are changed.
+///
+/// [`HardeningOverrides`]: struct.HardeningOverrides.html
+/// [`set_hardening_overrides`]: fn.set_hardening_overrides.html
+pub fn apply_env_overrides() -> Result<HardeningOverrides, Error> {
+    let mut overrides = HARDENING_OVERRIDES.write().unwrap();
+    let mut new = *overrides;
+    let fields = [
+        ("PATHRS_FORCE_EMULATED", &mut new.force_emulated),
+        (
+            "PATHRS_DISABLE_RESOLVE_CACHED",
+            &mut new.disable_resolve_cached,
+        ),
+        ("PATHRS_PARANOID", &mut new.paranoid),
+    ];
+    for (name, field) in fields {
+        let value = match env::var_os(name) {
+            Some(value) => value,
+            None => continue,
+        };
+        *field = match value.to_str() {
+            Some("1") | Some("true") | Some("yes") | Some("on") => true,
+            Some("0") | Some("false") | Some("no") | Some("off") => false,
+            _ => {
+                return error::InvalidArgument {
+                    name,
+                    description: format!("unrecognised value {:?}", value),
+                }
+                .fail()
+            }
+        };
+    }
+    *overrides = new;
+    Ok(new)
+}
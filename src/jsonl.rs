@@ -0,0 +1,314 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error},
+    syscalls, Divergence, Manifest, ManifestEntry, ManifestFileType, WalkEntry,
+};
+
+use std::{
+    ffi::OsStr,
+    fmt::Write as FmtWrite,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// The name used for a [`ManifestFileType`] in JSON records.
+///
+/// [`ManifestFileType`]: enum.ManifestFileType.html
+fn file_type_name(file_type: ManifestFileType) -> &'static str {
+    match file_type {
+        ManifestFileType::File => "file",
+        ManifestFileType::Directory => "directory",
+        ManifestFileType::Symlink => "symlink",
+        ManifestFileType::Fifo => "fifo",
+        ManifestFileType::CharacterDevice => "character_device",
+        ManifestFileType::BlockDevice => "block_device",
+        ManifestFileType::Socket => "socket",
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// A single JSON object, built up one field at a time.
+struct Record(String);
+
+impl Record {
+    fn new(kind: &str) -> Self {
+        let mut record = Self(String::from("{"));
+        record.string("type", kind);
+        record
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        self.escaped(key);
+        self.0.push(':');
+    }
+
+    fn escaped(&mut self, value: &str) {
+        self.0.push('"');
+        for ch in value.chars() {
+            match ch {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                ch if (ch as u32) < 0x20 => {
+                    let _ = write!(self.0, "\\u{:04x}", ch as u32);
+                }
+                ch => self.0.push(ch),
+            }
+        }
+        self.0.push('"');
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.escaped(value);
+    }
+
+    fn number<N: Into<u64>>(&mut self, key: &str, value: N) {
+        self.key(key);
+        let _ = write!(self.0, "{}", value.into());
+    }
+
+    fn optional_number<N: Into<u64>>(&mut self, key: &str, value: Option<N>) {
+        match value {
+            Some(value) => self.number(key, value),
+            None => self.null(key),
+        }
+    }
+
+    fn null(&mut self, key: &str) {
+        self.key(key);
+        self.0.push_str("null");
+    }
+
+    /// Add a path-like field. JSON strings must be valid Unicode, so if the
+    /// value is not valid UTF-8 a lossy version is stored under `key` and the
+    /// raw bytes are stored (as an array of numbers) under `<key>_bytes`.
+    fn os_str(&mut self, key: &str, value: &OsStr) {
+        self.string(key, &value.to_string_lossy());
+        if value.to_str().is_none() {
+            self.key(&format!("{}_bytes", key));
+            self.0.push('[');
+            for (idx, byte) in value.as_bytes().iter().enumerate() {
+                if idx > 0 {
+                    self.0.push(',');
+                }
+                let _ = write!(self.0, "{}", byte);
+            }
+            self.0.push(']');
+        }
+    }
+
+    fn path(&mut self, key: &str, value: &Path) {
+        self.os_str(key, value.as_os_str())
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push_str("}\n");
+        self.0
+    }
+}
+
+/// Writes walk, manifest and [`Divergence`] results as [JSON lines], so that
+/// other tools (such as language bindings or shell scripts) can consume the
+/// results of libpathrs's traversals without re-walking the tree themselves.
+///
+/// Each call writes one JSON object per line. Every object has a `"type"`
+/// field (`"entry"`, `"manifest"` or `"divergence"`) describing the kind of
+/// record, and paths are stored as strings (with an additional `<key>_bytes`
+/// array of the raw bytes if the path is not valid UTF-8). Each line is
+/// written with a single `write_all`, so `writer` doesn't need to be buffered.
+///
+/// [`Divergence`]: enum.Divergence.html
+/// [JSON lines]: https://jsonlines.org/
+#[derive(Debug)]
+pub struct JsonLines<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    /// Create a new [`JsonLines`] which writes its records to `writer`.
+    ///
+    /// [`JsonLines`]: struct.JsonLines.html
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Unwrap the [`JsonLines`] to get the underlying writer.
+    ///
+    /// [`JsonLines`]: struct.JsonLines.html
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn emit(&mut self, record: Record) -> Result<(), Error> {
+        self.writer
+            .write_all(record.finish().as_bytes())
+            .context(error::OsError {
+                operation: "write json line",
+            })
+    }
+
+    /// Write a `"entry"` record for a [`WalkEntry`], with the fields `path`,
+    /// `depth`, `file_type`, `mode` (`st_mode & 07777`), `size`, `uid`, `gid`,
+    /// `dev`, `ino` and `nlink`. Symlinks also have a `target` field with the
+    /// contents of the symlink, and device inodes have `major` and `minor`
+    /// fields with their device number.
+    ///
+    /// [`WalkEntry`]: struct.WalkEntry.html
+    pub fn write_walk_entry(&mut self, entry: &WalkEntry) -> Result<(), Error> {
+        let meta = entry.metadata();
+        let file_type = ManifestFileType::from(meta.file_type());
+        let mut record = Record::new("entry");
+        record.path("path", entry.path());
+        record.number("depth", entry.depth() as u64);
+        record.string("file_type", file_type_name(file_type));
+        record.number("mode", meta.mode() & 0o7777);
+        record.number("size", meta.size());
+        record.number("uid", meta.uid());
+        record.number("gid", meta.gid());
+        record.number("dev", meta.dev());
+        record.number("ino", meta.ino());
+        record.number("nlink", meta.nlink());
+        if file_type == ManifestFileType::Symlink {
+            let target = syscalls::readlinkat(entry.handle().inner.as_raw_fd(), "").context(
+                error::RawOsError {
+                    operation: "readlink walked symlink",
+                },
+            )?;
+            record.path("target", &target);
+        }
+        if let Some((_, major, minor)) = entry.device() {
+            record.number("major", major);
+            record.number("minor", minor);
+        }
+        self.emit(record)
+    }
+
+    /// Write a `"manifest"` record for a single [`ManifestEntry`], with the
+    /// fields `path`, `file_type`, `mode`, `size` and `sha256` (a hex string).
+    /// Fields which are not checked by the [`ManifestEntry`] are `null`.
+    ///
+    /// [`ManifestEntry`]: struct.ManifestEntry.html
+    pub fn write_manifest_entry<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        entry: &ManifestEntry,
+    ) -> Result<(), Error> {
+        let mut record = Record::new("manifest");
+        record.path("path", path.as_ref());
+        record.string("file_type", file_type_name(entry.file_type));
+        record.optional_number("mode", entry.mode);
+        record.optional_number("size", entry.size);
+        match entry.sha256 {
+            Some(ref digest) => record.string("sha256", &hex(digest)),
+            None => record.null("sha256"),
+        }
+        self.emit(record)
+    }
+
+    /// Write a `"manifest"` record for every entry in a [`Manifest`] (in path
+    /// order).
+    ///
+    /// [`Manifest`]: struct.Manifest.html
+    pub fn write_manifest(&mut self, manifest: &Manifest) -> Result<(), Error> {
+        manifest
+            .entries
+            .iter()
+            .try_for_each(|(path, entry)| self.write_manifest_entry(path, entry))
+    }
+
+    /// Write a `"divergence"` record for a [`Divergence`], with the fields
+    /// `kind` (`"missing"`, `"unexpected"`, `"file_type"`, `"mode"`, `"size"`
+    /// or `"digest"`) and `path`. All kinds other than `"missing"` also have
+    /// `expected` and `actual` fields (`"unexpected"` only has `actual`).
+    ///
+    /// [`Divergence`]: enum.Divergence.html
+    pub fn write_divergence(&mut self, divergence: &Divergence) -> Result<(), Error> {
+        let mut record = Record::new("divergence");
+        match divergence {
+            Divergence::Missing { path } => {
+                record.string("kind", "missing");
+                record.path("path", path);
+            }
+            Divergence::Unexpected { path, file_type } => {
+                record.string("kind", "unexpected");
+                record.path("path", path);
+                record.string("actual", file_type_name(*file_type));
+            }
+            Divergence::FileType {
+                path,
+                expected,
+                actual,
+            } => {
+                record.string("kind", "file_type");
+                record.path("path", path);
+                record.string("expected", file_type_name(*expected));
+                record.string("actual", file_type_name(*actual));
+            }
+            Divergence::Mode {
+                path,
+                expected,
+                actual,
+            } => {
+                record.string("kind", "mode");
+                record.path("path", path);
+                record.number("expected", *expected);
+                record.number("actual", *actual);
+            }
+            Divergence::Size {
+                path,
+                expected,
+                actual,
+            } => {
+                record.string("kind", "size");
+                record.path("path", path);
+                record.number("expected", *expected);
+                record.number("actual", *actual);
+            }
+            Divergence::Digest {
+                path,
+                expected,
+                actual,
+            } => {
+                record.string("kind", "digest");
+                record.path("path", path);
+                record.string("expected", &hex(expected));
+                record.string("actual", &hex(actual));
+            }
+        }
+        self.emit(record)
+    }
+}
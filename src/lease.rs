@@ -0,0 +1,298 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, utils, Handle, OpenFlags,
+};
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, RawFd},
+    },
+};
+
+use libc::c_int;
+use snafu::ResultExt;
+
+/// Changes to the leased inode which (may) mean it was unlinked. Unlinking a
+/// file changes its link count (`IN_ATTRIB`). `IN_DELETE_SELF` can only happen
+/// once the inode is freed, which the lease's own descriptor prevents, but it
+/// costs nothing to ask for it.
+const UNLINK_WATCH_MASK: u32 = libc::IN_ATTRIB | libc::IN_DELETE_SELF;
+
+/// The type of a file lease, as in `fcntl(F_SETLEASE)`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LeaseType {
+    /// A read lease (`F_RDLCK`). The holder is notified when another process
+    /// opens the file for writing or truncates it.
+    Read,
+
+    /// A write lease (`F_WRLCK`). The holder is notified when another process
+    /// opens the file at all (or truncates it). Can only be taken if no other
+    /// process has the file open.
+    Write,
+}
+
+impl LeaseType {
+    fn as_raw(self) -> c_int {
+        match self {
+            Self::Read => libc::F_RDLCK,
+            Self::Write => libc::F_WRLCK,
+        }
+    }
+
+    fn from_raw(lease: c_int) -> Option<Self> {
+        match lease {
+            libc::F_RDLCK => Some(Self::Read),
+            libc::F_WRLCK => Some(Self::Write),
+            _ => None,
+        }
+    }
+}
+
+/// A file lease, created with [`Handle::lease`].
+///
+/// When the lease is broken (another process opens the file in a conflicting
+/// way, or truncates it) the kernel sends the lease holder a signal (`SIGIO` by
+/// default, see [`Lease::set_signal`]) and blocks the other process until the
+/// lease is released, downgraded, or `/proc/sys/fs/lease-break-time` seconds
+/// have elapsed.
+///
+/// Unlinking (or renaming) the file does **not** break the lease. Instead, the
+/// file is also watched with inotify: [`Lease::unlink_watch_fd`] becomes
+/// readable when the link count of the file changes, after which
+/// [`Lease::is_unlinked`] tells you whether the last link is gone.
+///
+/// The lease is released when the [`Lease`] is dropped.
+///
+/// [`Handle::lease`]: struct.Handle.html#method.lease
+/// [`Lease`]: struct.Lease.html
+/// [`Lease::set_signal`]: struct.Lease.html#method.set_signal
+/// [`Lease::unlink_watch_fd`]: struct.Lease.html#method.unlink_watch_fd
+/// [`Lease::is_unlinked`]: struct.Lease.html#method.is_unlinked
+#[derive(Debug)]
+pub struct Lease {
+    file: File,
+    lease_type: LeaseType,
+    watch: File,
+}
+
+impl Lease {
+    /// The type of lease originally requested.
+    #[inline]
+    pub fn lease_type(&self) -> LeaseType {
+        self.lease_type
+    }
+
+    /// Get the type of lease currently held. If the lease has been broken and
+    /// released by the kernel (or in response to a lease break), `None` is
+    /// returned.
+    pub fn current(&self) -> Result<Option<LeaseType>, Error> {
+        let lease = syscalls::fcntl_getlease(self.file.as_raw_fd()).context(error::RawOsError {
+            operation: "get current lease",
+        })?;
+        Ok(LeaseType::from_raw(lease))
+    }
+
+    /// The (non-blocking) inotify descriptor watching the leased file, which
+    /// becomes readable when the link count of the file changes. Use it with
+    /// `poll(2)` (or an event loop) to be notified of the file being unlinked,
+    /// and then call [`Lease::is_unlinked`] (which also drains the pending
+    /// events).
+    ///
+    /// [`Lease::is_unlinked`]: struct.Lease.html#method.is_unlinked
+    #[inline]
+    pub fn unlink_watch_fd(&self) -> RawFd {
+        self.watch.as_raw_fd()
+    }
+
+    /// Check whether the leased file has been unlinked, meaning the lease's
+    /// own descriptor is the last reference to it. Any pending events on
+    /// [`Lease::unlink_watch_fd`] are discarded.
+    ///
+    /// [`Lease::unlink_watch_fd`]: struct.Lease.html#method.unlink_watch_fd
+    pub fn is_unlinked(&self) -> Result<bool, Error> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match (&self.watch).read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    return Err(err).context(error::OsError {
+                        operation: "read lease unlink watch events",
+                    })
+                }
+            }
+        }
+        let meta = self.file.metadata().context(error::OsError {
+            operation: "fstat leased file",
+        })?;
+        Ok(meta.nlink() == 0)
+    }
+
+    /// Change the signal sent when the lease is broken (as in
+    /// `fcntl(F_SETSIG)`). A `signal` of `0` restores the default of `SIGIO`.
+    ///
+    /// If a non-zero signal is used and the handler was installed with
+    /// `SA_SIGINFO`, the `si_fd` field of the `siginfo_t` will contain the
+    /// [`Lease`]'s file descriptor (see [`AsRawFd`]), allowing you to
+    /// distinguish between multiple leases.
+    ///
+    /// [`Lease`]: struct.Lease.html
+    /// [`AsRawFd`]: https://doc.rust-lang.org/std/os/unix/io/trait.AsRawFd.html
+    pub fn set_signal(&self, signal: c_int) -> Result<(), Error> {
+        syscalls::fcntl_setsig(self.file.as_raw_fd(), signal).context(error::RawOsError {
+            operation: "set lease break signal",
+        })
+    }
+
+    /// Downgrade a write lease to a read lease (usually in response to a lease
+    /// break).
+    pub fn downgrade(&mut self) -> Result<(), Error> {
+        syscalls::fcntl_setlease(self.file.as_raw_fd(), libc::F_RDLCK).context(
+            error::RawOsError {
+                operation: "downgrade lease",
+            },
+        )?;
+        self.lease_type = LeaseType::Read;
+        Ok(())
+    }
+
+    /// Explicitly release the lease. This is equivalent to dropping the
+    /// [`Lease`], except that any errors are returned.
+    ///
+    /// [`Lease`]: struct.Lease.html
+    pub fn release(self) -> Result<(), Error> {
+        syscalls::fcntl_setlease(self.file.as_raw_fd(), libc::F_UNLCK).context(error::RawOsError {
+            operation: "release lease",
+        })
+    }
+}
+
+impl AsRawFd for Lease {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Closing the file will also release the lease, but be explicit about
+        // it since we don't know if the fd has been duplicated.
+        let _ = syscalls::fcntl_setlease(self.file.as_raw_fd(), libc::F_UNLCK);
+    }
+}
+
+impl Handle {
+    /// Take a file lease on the file referenced by the [`Handle`]. See
+    /// [`Lease`] for more details.
+    ///
+    /// The file is re-opened with `O_RDONLY` (the new descriptor is owned by
+    /// the [`Lease`]) and the lease is taken on that descriptor. The file is
+    /// also watched for being unlinked (see [`Lease::is_unlinked`]).
+    ///
+    /// # Errors
+    ///
+    /// Leases can only be taken on regular files, and only by the owner of the
+    /// file (or a process with `CAP_LEASE`). A [`LeaseType::Write`] lease
+    /// cannot be taken if any other process has the file open, and a
+    /// [`LeaseType::Read`] lease cannot be taken if it is open for writing.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Lease`]: struct.Lease.html
+    /// [`LeaseType::Write`]: enum.LeaseType.html#variant.Write
+    /// [`LeaseType::Read`]: enum.LeaseType.html#variant.Read
+    /// [`Lease::is_unlinked`]: struct.Lease.html#method.is_unlinked
+    pub fn lease(&self, lease_type: LeaseType) -> Result<Lease, Error> {
+        let meta = self.inner.metadata().context(error::OsError {
+            operation: "fstat handle to lease",
+        })?;
+        ensure!(
+            meta.is_file(),
+            error::InvalidArgument {
+                name: "handle",
+                description: "leases can only be taken on regular files",
+            }
+        );
+
+        let file = self
+            .reopen(OpenFlags(libc::O_RDONLY))
+            .wrap("reopen handle to take lease")?;
+        // Set up the watch before taking the lease, so that the lease is never
+        // held without it.
+        let watch = syscalls::inotify_init1().context(error::RawOsError {
+            operation: "create lease unlink watch",
+        })?;
+        syscalls::inotify_add_watch(
+            watch.as_raw_fd(),
+            utils::procfd_path(file.as_raw_fd())?,
+            UNLINK_WATCH_MASK,
+        )
+        .context(error::RawOsError {
+            operation: "watch leased file",
+        })?;
+        syscalls::fcntl_setlease(file.as_raw_fd(), lease_type.as_raw()).context(
+            error::RawOsError {
+                operation: "take lease",
+            },
+        )?;
+        Ok(Lease {
+            file,
+            lease_type,
+            watch,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LeaseType;
+
+    use crate::{tests::TempDir, Root};
+
+    use std::fs;
+
+    #[test]
+    fn lease_unlink_watch() {
+        let dir = TempDir::new();
+        let root = Root::open(dir.path()).unwrap();
+        fs::write(dir.path().join("file"), b"data").unwrap();
+        let lease = root
+            .resolve("file")
+            .unwrap()
+            .lease(LeaseType::Read)
+            .expect("take read lease");
+        assert!(!lease.is_unlinked().unwrap());
+
+        // Removing one of several links doesn't unlink the file.
+        fs::hard_link(dir.path().join("file"), dir.path().join("link")).unwrap();
+        fs::remove_file(dir.path().join("file")).unwrap();
+        assert!(!lease.is_unlinked().unwrap());
+
+        fs::remove_file(dir.path().join("link")).unwrap();
+        assert!(lease.is_unlinked().unwrap());
+        assert_eq!(lease.current().unwrap(), Some(LeaseType::Read));
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls,
+    utils::{FileExt, RawFdExt},
+    Root,
+};
+
+use std::{
+    os::unix::io::AsRawFd,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use snafu::ResultExt;
+
+/// How long a [`VerifiedPath`] stays valid after it was (re-)verified.
+///
+/// [`VerifiedPath`]: struct.VerifiedPath.html
+pub const VERIFIED_PATH_VALIDITY: Duration = Duration::from_secs(1);
+
+/// A textual host path to an inode inside a [`Root`], for interfaces which
+/// only take paths (such as `quotactl(2)` or `mount(2)` on older kernels),
+/// created with [`Root::verified_path`].
+///
+/// Unlike an [`UnverifiedPath`] (which is relative to the [`Root`]), this is
+/// the full path of the inode on the host, and walking the path from `/` one
+/// component at a time without following any symlinks was verified to reach
+/// the same inode that [`Root::resolve`] returned. The path is still racy --
+/// any component can be renamed or replaced right after the check -- so the
+/// path is only handed out for [`VERIFIED_PATH_VALIDITY`] after the last
+/// verification, to keep that window explicit and small. Use
+/// [`VerifiedPath::reverify`] right before each use if the path is used more
+/// than once.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::verified_path`]: struct.Root.html#method.verified_path
+/// [`Root::resolve`]: struct.Root.html#method.resolve
+/// [`UnverifiedPath`]: struct.UnverifiedPath.html
+/// [`VERIFIED_PATH_VALIDITY`]: constant.VERIFIED_PATH_VALIDITY.html
+/// [`VerifiedPath::reverify`]: struct.VerifiedPath.html#method.reverify
+#[derive(Clone, Debug)]
+pub struct VerifiedPath {
+    path: PathBuf,
+    inode_id: (u64, u64),
+    verified_at: Instant,
+}
+
+/// Walk `path` from `/` one component at a time without following symlinks,
+/// and get the (st_dev, st_ino) of the inode it ends at. This is what a
+/// path-based syscall would see, except that it would follow symlinks.
+fn walk_lexically(path: &Path) -> Result<(u64, u64), Error> {
+    let mut current = syscalls::openat(libc::AT_FDCWD, "/", libc::O_PATH | libc::O_DIRECTORY, 0)
+        .context(error::RawOsError {
+            operation: "open / for lexical path verification",
+        })?;
+    let mut parts = path.components().peekable();
+    while let Some(part) = parts.next() {
+        let part = match part {
+            Component::RootDir => continue,
+            Component::Normal(part) => part,
+            _ => {
+                return error::SafetyViolation {
+                    description: "verified path contains non-normal components",
+                }
+                .fail()
+            }
+        };
+        let next = syscalls::openat(current.as_raw_fd(), part, libc::O_PATH, 0).context(
+            error::RawOsError {
+                operation: "open component for lexical path verification",
+            },
+        )?;
+        // The final component may be a symlink (if that is what the path
+        // resolved to), but nothing before it may be.
+        if parts.peek().is_some() {
+            let meta = next.metadata().context(error::OsError {
+                operation: "fstat component for lexical path verification",
+            })?;
+            ensure!(
+                !meta.file_type().is_symlink(),
+                error::SafetyViolation {
+                    description: format!("verified path component {:?} is a symlink", part),
+                }
+            );
+        }
+        current = next;
+    }
+    current.inode_id()
+}
+
+impl VerifiedPath {
+    /// Get the path, or `None` if more than [`VERIFIED_PATH_VALIDITY`] has
+    /// passed since it was last verified.
+    ///
+    /// [`VERIFIED_PATH_VALIDITY`]: constant.VERIFIED_PATH_VALIDITY.html
+    pub fn path(&self) -> Option<&Path> {
+        if self.is_expired() {
+            None
+        } else {
+            Some(&self.path)
+        }
+    }
+
+    /// Has more than [`VERIFIED_PATH_VALIDITY`] passed since the path was
+    /// last verified?
+    ///
+    /// [`VERIFIED_PATH_VALIDITY`]: constant.VERIFIED_PATH_VALIDITY.html
+    pub fn is_expired(&self) -> bool {
+        self.verified_at.elapsed() >= VERIFIED_PATH_VALIDITY
+    }
+
+    /// Walk the path again and check that it still reaches the same inode,
+    /// restarting the validity period.
+    ///
+    /// # Errors
+    ///
+    /// If the path no longer reaches the same inode without crossing a
+    /// symlink, an [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn reverify(&mut self) -> Result<(), Error> {
+        let inode_id = walk_lexically(&self.path).wrap("walk verified path")?;
+        ensure!(
+            inode_id == self.inode_id,
+            error::SafetyViolation {
+                description: "verified path no longer reaches the resolved inode",
+            }
+        );
+        self.verified_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve `path` and get a [`VerifiedPath`]
+    /// -- a textual host path which was verified to lexically reach the same
+    /// inode, for interop with interfaces which only take paths.
+    ///
+    /// Prefer passing file descriptors (from [`Root::resolve`] or
+    /// [`Root::open_file`]) wherever the interface allows it, since any path
+    /// can be raced.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::resolve`] and
+    /// [`Root::relative_path_of`]. If walking the host path doesn't reach the
+    /// resolved inode (or crosses a symlink, including one above the
+    /// [`Root`]), an [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`VerifiedPath`]: struct.VerifiedPath.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Root::open_file`]: struct.Root.html#method.open_file
+    /// [`Root::relative_path_of`]: struct.Root.html#method.relative_path_of
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn verified_path<P: AsRef<Path>>(&self, path: P) -> Result<VerifiedPath, Error> {
+        let handle = self.resolve(path).wrap("resolve path to verify")?;
+        let relpath = self.relative_path_of(&handle)?.into_unverified_path_buf();
+        // SAFETY: as_unsafe_path is safe here since the resulting path is
+        //         verified by walking it below.
+        let root_path = self
+            .inner
+            .as_unsafe_path()
+            .wrap("get root path for verified path")?;
+        let relpath = relpath.strip_prefix("/").unwrap_or(&relpath);
+        let path = if relpath.as_os_str().is_empty() {
+            root_path
+        } else {
+            root_path.join(relpath)
+        };
+
+        let mut verified = VerifiedPath {
+            path,
+            inode_id: handle.inner.inode_id()?,
+            verified_at: Instant::now(),
+        };
+        verified.reverify()?;
+        Ok(verified)
+    }
+}
@@ -42,6 +42,14 @@
 //! * Native Backend:
 //!   - `openat2` support.
 //!
+//! # Paths
+//!
+//! Linux paths are arbitrary byte strings (other than `/` and nul bytes), and
+//! libpathrs treats them as such -- paths and names do not need to be valid
+//! UTF-8 (use [`OsStrExt::from_bytes`] to pass raw bytes). Paths containing a
+//! nul byte cannot be passed to the kernel, and are rejected with an
+//! [`Error::InvalidArgument`] (or `EINVAL`) rather than being truncated.
+//!
 //! # Examples
 //!
 //! The recommended usage of libpathrs looks something like this:
@@ -110,6 +118,8 @@
 //! [`Handle`]: trait.Handle.html
 //! [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
 //! [`chroot(2)`]: http://man7.org/linux/man-pages/man2/chroot.2.html
+//! [`OsStrExt::from_bytes`]: https://doc.rust-lang.org/std/os/unix/ffi/trait.OsStrExt.html#tymethod.from_bytes
+//! [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
 
 // libpathrs only supports Linux at the moment.
 #![cfg(target_os = "linux")]
@@ -128,22 +138,251 @@ mod handle;
 #[doc(inline)]
 pub use handle::*;
 
+// File leases.
+mod lease;
+#[doc(inline)]
+pub use lease::*;
+
+// Memory-mapping of files.
+mod mmap;
+#[doc(inline)]
+pub use mmap::*;
+
+// Zero-copy transfers of file contents to and from streams.
+mod sendfile;
+
+// Directory entry iteration.
+mod dirent;
+#[doc(inline)]
+pub use dirent::*;
+
 // `Root` implementation.
 mod root;
 #[doc(inline)]
 pub use root::*;
 
+// Symlink inspection helpers.
+mod link;
+#[doc(inline)]
+pub use link::*;
+
+// Idempotent creation helpers.
+mod ensure;
+#[doc(inline)]
+pub use ensure::*;
+
+// Scoped working directory changes for legacy code.
+mod cwd;
+
+// Verified textual paths for legacy path-based interfaces.
+mod legacy;
+#[doc(inline)]
+pub use legacy::*;
+
+// Metadata-only lookups.
+mod stat;
+#[doc(inline)]
+pub use stat::*;
+
+// Probing of filesystem features.
+mod features;
+#[doc(inline)]
+pub use features::*;
+
+// Policies which can be applied to a `Root`.
+mod policy;
+#[doc(inline)]
+pub use policy::*;
+
+// Caching and sharing of open `Root`s.
+mod pool;
+#[doc(inline)]
+pub use pool::*;
+
+// Loading root configurations from files.
+mod config;
+#[doc(inline)]
+pub use config::*;
+
+// Timeouts for operations on potentially-hanging filesystems.
+mod timeout;
+
+// Throttling of recursive operations.
+mod throttle;
+#[doc(inline)]
+pub use throttle::*;
+
+// Directory tree walking, and the operations built on top of it.
+mod walk;
+#[doc(inline)]
+pub use walk::*;
+mod manifest;
+#[doc(inline)]
+pub use manifest::*;
+mod snapshot;
+#[doc(inline)]
+pub use snapshot::*;
+mod relabel;
+#[doc(inline)]
+pub use relabel::*;
+mod readahead;
+#[doc(inline)]
+pub use readahead::*;
+
+// Asynchronous streams of walk and directory entries.
+#[cfg(feature = "stream")]
+mod stream;
+#[cfg(feature = "stream")]
+#[doc(inline)]
+pub use stream::*;
+
+// Machine-readable output of walk results.
+mod jsonl;
+#[doc(inline)]
+pub use jsonl::*;
+
+// Reading file contents with digest verification.
+mod verified;
+#[doc(inline)]
+pub use verified::*;
+
+// Size-limited writers for untrusted producers.
+mod limited;
+#[doc(inline)]
+pub use limited::*;
+
+// Identities of the inodes affected by operations (for audit trails).
+mod audit;
+#[doc(inline)]
+pub use audit::*;
+
+// Persistent memory (DAX) helpers.
+mod dax;
+#[doc(inline)]
+pub use dax::*;
+
+// Enumeration of all extended attributes of a file.
+mod xattr;
+#[doc(inline)]
+pub use xattr::*;
+
+// File capabilities (security.capability).
+mod capability;
+#[doc(inline)]
+pub use capability::*;
+
+// Copying files, with fallbacks for unsupported strategies.
+mod copy;
+#[doc(inline)]
+pub use copy::*;
+
+// Dry runs of mutating operations.
+mod dryrun;
+#[doc(inline)]
+pub use dryrun::*;
+
+// Multi-operation transactions.
+mod transaction;
+#[doc(inline)]
+pub use transaction::*;
+
+// Declarative batches of filesystem changes.
+mod changeset;
+#[doc(inline)]
+pub use changeset::*;
+
+// Helpers for OCI container runtimes.
+mod oci;
+#[doc(inline)]
+pub use oci::*;
+
+// Overlayfs metadata helpers.
+mod overlay;
+#[doc(inline)]
+pub use overlay::*;
+
+// Comparing resolutions against chroot(2) (oracle mode).
+mod oracle;
+#[doc(inline)]
+pub use oracle::*;
+
+// Pre-flight validation of mount(2) targets.
+mod mount;
+#[doc(inline)]
+pub use mount::*;
+
+// Pinning of handles across external operations.
+mod pin;
+#[doc(inline)]
+pub use pin::*;
+
+// File descriptor broker for multi-process architectures.
+pub mod broker;
+
+// Self-tests of the escape defenses.
+pub mod diagnose;
+
 // `Error` definitions.
 pub mod error;
 
+// Lexical path helpers.
+pub mod path;
+
+// Safe access to /proc/self and /proc/thread-self.
+pub mod procfs;
+
+// Verified access to sysfs.
+pub mod sysfs;
+
+// Verified access to cgroup2 hierarchies.
+pub mod cgroup;
+
+// Per-operation statistics (syscalls, bytes, retries and timings).
+mod opstats;
+#[doc(inline)]
+pub use opstats::*;
+
+// Retry behaviour for transient syscall errors.
+mod retry;
+#[doc(inline)]
+pub use retry::*;
+
+// Operator kill-switches for hardening features.
+mod hardening;
+#[doc(inline)]
+pub use hardening::*;
+
+// Pluggable metrics for resolutions and failures.
+mod metrics;
+#[doc(inline)]
+pub use metrics::*;
+
+// strace-style tracing of syscalls.
+#[cfg(feature = "syscall-trace")]
+pub mod trace;
+
+// Randomised equivalence testing of the resolvers.
+#[cfg(feature = "testing")]
+pub mod testing;
+
 // Backend resolver implementations.
 mod resolvers;
 #[doc(inline)]
-pub use resolvers::{Resolver, ResolverBackend, ResolverFlags};
+pub use resolvers::{
+    Openat2Support, ResolutionBudget, ResolveStats, Resolver, ResolverBackend, ResolverFlags,
+};
 
 // C API.
 mod capi;
 
 // Internally used helpers.
+mod cache;
+mod digest;
+mod remove;
 mod syscalls;
 mod utils;
+mod workers;
+
+// Helpers for unit tests.
+#[cfg(test)]
+mod tests;
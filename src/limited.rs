@@ -0,0 +1,323 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, Handle, OpenFlags, Root,
+};
+
+use std::{
+    fs::{File, Permissions},
+    io::{Error as IOError, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use snafu::ResultExt;
+
+/// Granularity (in bytes) at which all-zero data is turned into holes.
+const HOLE_BLOCK_SIZE: usize = 4096;
+
+/// Limits enforced by a [`LimitedWriter`].
+///
+/// [`LimitedWriter`]: struct.LimitedWriter.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteLimits {
+    /// Maximum number of bytes which may be written (including any bytes
+    /// which were turned into holes).
+    pub max_size: u64,
+    /// Maximum average number of bytes written per second, or `None` for no
+    /// rate limit. Writes which would exceed the rate block until they are
+    /// permitted.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Whether blocks of zeroes should be turned into holes rather than
+    /// written, so that sparse uploads don't use more disk space than
+    /// necessary.
+    pub punch_holes: bool,
+}
+
+impl WriteLimits {
+    /// Create a new [`WriteLimits`] with a maximum size of `max_size` bytes,
+    /// no rate limit and hole punching enabled.
+    ///
+    /// [`WriteLimits`]: struct.WriteLimits.html
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            max_bytes_per_sec: None,
+            punch_holes: true,
+        }
+    }
+}
+
+/// A writer which enforces [`WriteLimits`] on the data written to a file,
+/// intended for services which write data from untrusted clients into a
+/// [`Root`].
+///
+/// Once `max_size` bytes have been written, writes fail with `EFBIG` (a write
+/// which straddles the limit is shortened). [`Seek`] is deliberately not
+/// implemented, so the limit also bounds the size of the file (unless it was
+/// already larger).
+///
+/// Holes at the end of the file only have a size once the writer has been
+/// flushed (or finished), so you should always call [`LimitedWriter::finish`]
+/// when you are done writing. Dropping the writer will try to do this as well,
+/// but any errors are ignored.
+///
+/// [`WriteLimits`]: struct.WriteLimits.html
+/// [`Root`]: struct.Root.html
+/// [`Seek`]: https://doc.rust-lang.org/std/io/trait.Seek.html
+/// [`LimitedWriter::finish`]: struct.LimitedWriter.html#method.finish
+#[derive(Debug)]
+pub struct LimitedWriter {
+    file: Option<File>,
+    limits: WriteLimits,
+    /// Number of bytes accepted so far (including holes).
+    written: u64,
+    /// Current offset of the file.
+    offset: u64,
+    /// Size of the file, not including any trailing hole we have skipped over.
+    file_len: u64,
+    started: Instant,
+}
+
+impl LimitedWriter {
+    /// Create a new [`LimitedWriter`] which writes to `file` (starting from
+    /// the current offset of `file`).
+    ///
+    /// [`LimitedWriter`]: struct.LimitedWriter.html
+    pub fn new(file: File, limits: WriteLimits) -> Result<Self, Error> {
+        let mut file = file;
+        let offset = file.stream_position().context(error::OsError {
+            operation: "get offset of limited writer file",
+        })?;
+        let file_len = file
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat limited writer file",
+            })?
+            .len();
+        Ok(Self {
+            file: Some(file),
+            limits,
+            written: 0,
+            offset,
+            file_len,
+            started: Instant::now(),
+        })
+    }
+
+    /// Number of bytes written so far (including bytes which were turned into
+    /// holes).
+    #[inline]
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Flush the writer (extending the file over any trailing hole) and return
+    /// the underlying [`File`].
+    ///
+    /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
+    pub fn finish(mut self) -> Result<File, Error> {
+        self.extend_over_hole()?;
+        Ok(self.file.take().expect("file must exist until finished"))
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("file must exist until finished")
+    }
+
+    /// If we have skipped over a hole at the end of the file, make the file
+    /// large enough to include it.
+    fn extend_over_hole(&mut self) -> Result<(), Error> {
+        if self.offset > self.file_len {
+            let offset = self.offset;
+            self.file().set_len(offset).context(error::OsError {
+                operation: "extend file over trailing hole",
+            })?;
+            self.file_len = offset;
+        }
+        Ok(())
+    }
+
+    /// Block until writing `len` more bytes would not exceed the rate limit.
+    fn throttle(&self, len: usize) {
+        if let Some(rate) = self.limits.max_bytes_per_sec {
+            let target = (self.written + len as u64) as f64 / rate.max(1) as f64;
+            let target = Duration::from_secs_f64(target);
+            let elapsed = self.started.elapsed();
+            if target > elapsed {
+                thread::sleep(target - elapsed);
+            }
+        }
+    }
+
+    /// Skip over `len` bytes of zeroes, punching a hole if they overlap with
+    /// the existing contents of the file. Returns whether the hole could be
+    /// created (if not, the zeroes need to be written normally).
+    fn skip_zeroes(&mut self, len: usize) -> Result<bool, Error> {
+        let (offset, file_len) = (self.offset, self.file_len);
+        let fd = self.file().as_raw_fd();
+        if offset < file_len {
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            match syscalls::fallocate(fd, mode, offset, len as u64) {
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    return Ok(false)
+                }
+                ret => ret.context(error::RawOsError {
+                    operation: "punch hole in file",
+                })?,
+            }
+        }
+        let end = offset + len as u64;
+        self.file()
+            .seek(SeekFrom::Start(end))
+            .context(error::OsError {
+                operation: "seek over hole",
+            })?;
+        self.offset = end;
+        Ok(true)
+    }
+}
+
+/// Convert a libpathrs [`Error`] into an `IOError` for the `Write` trait.
+///
+/// [`Error`]: error/enum.Error.html
+fn to_io_error(err: Error) -> IOError {
+    IOError::from_raw_os_error(err.raw_os_error().unwrap_or(libc::EIO))
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = self.limits.max_size - self.written.min(self.limits.max_size);
+        if remaining == 0 {
+            return Err(IOError::from_raw_os_error(libc::EFBIG));
+        }
+        let mut buf = &buf[..buf.len().min(remaining as usize)];
+        if let Some(rate) = self.limits.max_bytes_per_sec {
+            // Don't accept more than a second's worth of data at once, so that
+            // the throttling is reasonably smooth.
+            buf = &buf[..buf.len().min(rate.max(1) as usize)];
+        }
+        self.throttle(buf.len());
+
+        let mut len = buf.len();
+        if self.limits.punch_holes {
+            // Zero blocks only become holes if they are block-aligned.
+            let head =
+                (HOLE_BLOCK_SIZE - (self.offset as usize % HOLE_BLOCK_SIZE)) % HOLE_BLOCK_SIZE;
+            let is_zero_block =
+                |chunk: &[u8]| chunk.len() == HOLE_BLOCK_SIZE && chunk.iter().all(|&b| b == 0);
+            let zeroes = if head == 0 {
+                buf.chunks(HOLE_BLOCK_SIZE)
+                    .take_while(|chunk| is_zero_block(chunk))
+                    .count()
+                    * HOLE_BLOCK_SIZE
+            } else {
+                0
+            };
+            if zeroes > 0 {
+                if self.skip_zeroes(zeroes).map_err(to_io_error)? {
+                    self.written += zeroes as u64;
+                    return Ok(zeroes);
+                }
+                len = zeroes;
+            } else {
+                let head = head.min(buf.len());
+                len = head
+                    + buf[head..]
+                        .chunks(HOLE_BLOCK_SIZE)
+                        .take_while(|chunk| !is_zero_block(chunk))
+                        .map(<[u8]>::len)
+                        .sum::<usize>();
+            }
+        }
+
+        let n = self.file().write(&buf[..len])?;
+        self.written += n as u64;
+        self.offset += n as u64;
+        self.file_len = self.file_len.max(self.offset);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        self.extend_over_hole().map_err(to_io_error)?;
+        self.file().flush()
+    }
+}
+
+impl Drop for LimitedWriter {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = self.extend_over_hole();
+        }
+    }
+}
+
+impl Handle {
+    /// Reopen the handle for writing (truncating the file) and return a
+    /// [`LimitedWriter`] which enforces `limits`. See [`Handle::reopen`] for
+    /// more details about how the handle is reopened.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Handle::reopen`].
+    ///
+    /// [`LimitedWriter`]: struct.LimitedWriter.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    pub fn reopen_limited(&self, limits: WriteLimits) -> Result<LimitedWriter, Error> {
+        let file = self
+            .reopen(OpenFlags(libc::O_WRONLY | libc::O_TRUNC))
+            .wrap("reopen handle for limited writing")?;
+        LimitedWriter::new(file, limits)
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, create a new file at `path` (as with
+    /// [`Root::create_file`]) and return a [`LimitedWriter`] for it which
+    /// enforces `limits`.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::create_file`] and [`Root::reopen`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    /// [`Root::reopen`]: struct.Root.html#method.reopen
+    /// [`LimitedWriter`]: struct.LimitedWriter.html
+    pub fn create_limited<P: AsRef<Path>>(
+        &self,
+        path: P,
+        perm: &Permissions,
+        limits: WriteLimits,
+    ) -> Result<LimitedWriter, Error> {
+        let handle = self.create_file(path, perm)?;
+        let file = self
+            .reopen(&handle, OpenFlags(libc::O_WRONLY))
+            .wrap("reopen new file for limited writing")?;
+        LimitedWriter::new(file, limits)
+    }
+}
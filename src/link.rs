@@ -0,0 +1,164 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    path::normalize_lexical,
+    root::path_split,
+    syscalls, Handle, Root,
+};
+
+use std::{
+    os::unix::io::AsRawFd,
+    path::{Component, Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// A symlink target read with [`Root::read_link_target_handle`].
+///
+/// [`Root::read_link_target_handle`]: struct.Root.html#method.read_link_target_handle
+#[derive(Debug)]
+pub struct LinkTarget {
+    target: PathBuf,
+    handle: Handle,
+}
+
+impl LinkTarget {
+    /// The literal contents of the symlink.
+    #[inline]
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// The [`Handle`] to the target of the symlink, resolved inside the
+    /// [`Root`].
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root`]: struct.Root.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Unwrap the [`LinkTarget`] to get the literal symlink contents and the
+    /// [`Handle`] to the target.
+    ///
+    /// [`LinkTarget`]: struct.LinkTarget.html
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn into_parts(self) -> (PathBuf, Handle) {
+        (self.target, self.handle)
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, read the symlink at `path` and resolve its
+    /// target, returning both the literal contents of the symlink and a
+    /// [`Handle`] to what it points to.
+    ///
+    /// The symlink is read through an `O_PATH | O_NOFOLLOW` handle, and
+    /// relative targets are resolved relative to the directory containing the
+    /// symlink (its path inside the [`Root`] is computed with
+    /// [`Root::relative_path_of`]). Absolute targets are resolved relative to
+    /// the [`Root`], as with any other symlink inside the [`Root`], and any
+    /// further symlinks in the target are resolved by the [`Resolver`] as
+    /// usual.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not a symlink, an [`Error::InvalidArgument`] is returned.
+    /// If the target has more `..` components than there are directories
+    /// between the [`Root`] and the symlink (so that following the symlink
+    /// outside of the [`Root`] would escape it), an [`Error::WouldEscape`] is
+    /// returned rather than silently clamping the target to the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Resolver`]: struct.Resolver.html
+    /// [`Root::relative_path_of`]: struct.Root.html#method.relative_path_of
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    /// [`Error::WouldEscape`]: error/enum.Error.html#variant.WouldEscape
+    pub fn read_link_target_handle<P: AsRef<Path>>(&self, path: P) -> Result<LinkTarget, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        let (parent, name) = path_split(&path).wrap("split symlink path into (parent, name)")?;
+        let dir = self
+            .resolve(parent)
+            .wrap("resolve parent directory of symlink")?;
+        let link = syscalls::openat(
+            dir.inner.as_raw_fd(),
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW,
+            0,
+        )
+        .context(error::RawOsError {
+            operation: "open symlink",
+        })?;
+        let is_symlink = link
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat symlink",
+            })?
+            .file_type()
+            .is_symlink();
+        ensure!(
+            is_symlink,
+            error::InvalidArgument {
+                name: "path",
+                description: format!("{:?} is not a symlink", path),
+            }
+        );
+        let target = syscalls::readlinkat(link.as_raw_fd(), "").context(error::RawOsError {
+            operation: "readlink symlink",
+        })?;
+
+        let dir_path = Path::new("/").join(
+            self.relative_path_of(&dir)
+                .wrap("get path of symlink parent directory")?
+                .into_unverified_path_buf(),
+        );
+
+        // Absolute targets start at the root, relative targets start at the
+        // directory containing the symlink.
+        let mut depth = if target.is_absolute() {
+            0
+        } else {
+            dir_path
+                .components()
+                .filter(|part| matches!(part, Component::Normal(_)))
+                .count()
+        };
+        for part in target.components() {
+            match part {
+                Component::Normal(_) => depth += 1,
+                Component::ParentDir if depth == 0 => {
+                    return error::WouldEscape { path, target }.fail()
+                }
+                Component::ParentDir => depth -= 1,
+                _ => (),
+            }
+        }
+
+        let handle = self
+            .resolve(dir_path.join(&target))
+            .wrap("resolve symlink target")?;
+        Ok(LinkTarget { target, handle })
+    }
+}
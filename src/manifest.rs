@@ -0,0 +1,334 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{digest, error::Error, throttle::ThrottledReader, workers, OpenFlags, Root};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{FileType, Metadata},
+    iter,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The type of an inode, as recorded in a [`Manifest`].
+///
+/// [`Manifest`]: struct.Manifest.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ManifestFileType {
+    /// Ordinary file.
+    File,
+    /// Directory.
+    Directory,
+    /// Symlink.
+    Symlink,
+    /// Named pipe (aka FIFO).
+    Fifo,
+    /// Character device.
+    CharacterDevice,
+    /// Block device.
+    BlockDevice,
+    /// Unix socket.
+    Socket,
+}
+
+impl From<FileType> for ManifestFileType {
+    fn from(file_type: FileType) -> Self {
+        if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else if file_type.is_fifo() {
+            Self::Fifo
+        } else if file_type.is_char_device() {
+            Self::CharacterDevice
+        } else if file_type.is_block_device() {
+            Self::BlockDevice
+        } else if file_type.is_socket() {
+            Self::Socket
+        } else {
+            Self::File
+        }
+    }
+}
+
+/// The expected state of a single inode in a [`Manifest`].
+///
+/// Any of the optional fields which are `None` are not verified.
+///
+/// [`Manifest`]: struct.Manifest.html
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ManifestEntry {
+    /// The expected type of the inode.
+    pub file_type: ManifestFileType,
+    /// The expected permission bits of the inode (`st_mode & 07777`).
+    pub mode: Option<u32>,
+    /// The expected size of the inode. Only checked for ordinary files.
+    pub size: Option<u64>,
+    /// The expected SHA-256 digest of the contents of the inode. Only checked
+    /// for ordinary files.
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl ManifestEntry {
+    /// Create a [`ManifestEntry`] which only checks the type of the inode.
+    ///
+    /// [`ManifestEntry`]: struct.ManifestEntry.html
+    pub fn new(file_type: ManifestFileType) -> Self {
+        Self {
+            file_type,
+            mode: None,
+            size: None,
+            sha256: None,
+        }
+    }
+}
+
+/// The expected contents of a directory tree, used with [`Root::verify_tree`].
+///
+/// [`Root::verify_tree`]: struct.Root.html#method.verify_tree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The expected entries, keyed by their path relative to the directory
+    /// being verified. The directory itself has an empty path, and is only
+    /// verified if it is present in the manifest.
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+/// A difference between a directory tree and a [`Manifest`], as reported by
+/// [`Root::verify_tree`].
+///
+/// [`Manifest`]: struct.Manifest.html
+/// [`Root::verify_tree`]: struct.Root.html#method.verify_tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// An entry in the manifest does not exist in the tree.
+    Missing { path: PathBuf },
+    /// An inode in the tree is not present in the manifest.
+    Unexpected {
+        path: PathBuf,
+        file_type: ManifestFileType,
+    },
+    /// The inode has a different type to the manifest. No further checks are
+    /// done on the inode.
+    FileType {
+        path: PathBuf,
+        expected: ManifestFileType,
+        actual: ManifestFileType,
+    },
+    /// The inode has different permission bits to the manifest.
+    Mode {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    /// The file has a different size to the manifest.
+    Size {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The file has different contents to the manifest.
+    Digest {
+        path: PathBuf,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+}
+
+/// Compare a single walked entry against its manifest entry.
+fn verify_entry(
+    path: &Path,
+    meta: &Metadata,
+    expected: &ManifestEntry,
+    contents: impl FnOnce() -> Result<[u8; 32], Error>,
+    divergences: &mut Vec<Divergence>,
+) -> Result<(), Error> {
+    let file_type = ManifestFileType::from(meta.file_type());
+    if file_type != expected.file_type {
+        divergences.push(Divergence::FileType {
+            path: path.to_path_buf(),
+            expected: expected.file_type,
+            actual: file_type,
+        });
+        return Ok(());
+    }
+
+    if let Some(mode) = expected.mode {
+        let actual = meta.mode() & 0o7777;
+        if actual != mode {
+            divergences.push(Divergence::Mode {
+                path: path.to_path_buf(),
+                expected: mode,
+                actual,
+            });
+        }
+    }
+
+    if file_type != ManifestFileType::File {
+        return Ok(());
+    }
+    if let Some(size) = expected.size {
+        if meta.size() != size {
+            divergences.push(Divergence::Size {
+                path: path.to_path_buf(),
+                expected: size,
+                actual: meta.size(),
+            });
+            // The digest cannot match, so don't bother reading the file.
+            return Ok(());
+        }
+    }
+    if let Some(sha256) = expected.sha256 {
+        let actual = contents()?;
+        if actual != sha256 {
+            divergences.push(Divergence::Digest {
+                path: path.to_path_buf(),
+                expected: sha256,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, compare the directory tree at `path` against
+    /// `manifest` and return every [`Divergence`] found.
+    ///
+    /// The tree is traversed with [`Root::walk`] and file contents are read
+    /// through the walked handles, so symlinks are never followed and a
+    /// directory moved out of the tree by a concurrent attacker makes the
+    /// verification fail (see [`Walk`] for the limits of this check) rather
+    /// than verify files outside of the tree. Directories which are not
+    /// present in the manifest are reported as [`Divergence::Unexpected`] and
+    /// are not descended into (so their contents are not reported).
+    ///
+    /// # Errors
+    ///
+    /// Divergences are not errors. An error is only returned if `path` cannot
+    /// be walked or an inode in the tree cannot be read.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    /// [`Walk`]: struct.Walk.html
+    /// [`Divergence`]: enum.Divergence.html
+    /// [`Divergence::Unexpected`]: enum.Divergence.html#variant.Unexpected
+    pub fn verify_tree<P: AsRef<Path>>(
+        &self,
+        path: P,
+        manifest: &Manifest,
+    ) -> Result<Vec<Divergence>, Error> {
+        self.verify_tree_parallel(path, manifest, 1)
+    }
+
+    /// Identical to [`Root::verify_tree`], except that the inodes in the tree
+    /// are verified (and file contents are hashed) by up to `threads` worker
+    /// threads. The tree is still walked by the calling thread.
+    ///
+    /// The returned divergences are in the same order as they would be with
+    /// [`Root::verify_tree`].
+    ///
+    /// [`Root::verify_tree`]: struct.Root.html#method.verify_tree
+    pub fn verify_tree_parallel<P: AsRef<Path>>(
+        &self,
+        path: P,
+        manifest: &Manifest,
+        threads: usize,
+    ) -> Result<Vec<Divergence>, Error> {
+        // Divergences are tagged with the index of the entry they were found
+        // in, so that they can be sorted back into walk order.
+        let divergences = Mutex::new(Vec::new());
+        let mut seen = BTreeSet::new();
+        let mut index = 0;
+
+        let mut walk = self.walk(path)?;
+        let throttler = walk.throttler();
+        let jobs = iter::from_fn(|| loop {
+            let entry = match walk.next()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            index += 1;
+            let path = entry.path();
+            match manifest.entries.get(path) {
+                Some(expected) => {
+                    seen.insert(path.to_path_buf());
+                    return Some(Ok((index, entry, expected)));
+                }
+                // The root of the walk is optional.
+                None if entry.depth() == 0 => (),
+                None => {
+                    divergences.lock().unwrap().push((
+                        index,
+                        Divergence::Unexpected {
+                            path: path.to_path_buf(),
+                            file_type: entry.metadata().file_type().into(),
+                        },
+                    ));
+                    walk.skip_current_dir();
+                }
+            }
+        });
+        workers::for_each(
+            "pathrs-verify",
+            threads,
+            jobs,
+            |(index, entry, expected)| {
+                let handle = entry.handle();
+                let mut found = Vec::new();
+                verify_entry(
+                    entry.path(),
+                    entry.metadata(),
+                    expected,
+                    || {
+                        let file = handle.reopen(OpenFlags(libc::O_RDONLY))?;
+                        digest::sha256_reader(ThrottledReader::new(file, throttler.as_deref()))
+                    },
+                    &mut found,
+                )?;
+                divergences
+                    .lock()
+                    .unwrap()
+                    .extend(found.into_iter().map(|divergence| (index, divergence)));
+                Ok(())
+            },
+        )?;
+
+        let mut divergences = divergences.into_inner().unwrap();
+        // The sort is stable, so divergences of the same entry stay in order.
+        divergences.sort_by_key(|(index, _)| *index);
+        let mut divergences: Vec<_> = divergences
+            .into_iter()
+            .map(|(_, divergence)| divergence)
+            .collect();
+        divergences.extend(
+            manifest
+                .entries
+                .keys()
+                .filter(|path| !seen.contains(*path))
+                .map(|path| Divergence::Missing {
+                    path: path.to_path_buf(),
+                }),
+        );
+        Ok(divergences)
+    }
+}
@@ -0,0 +1,334 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{error::Error, ResolverBackend};
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// An event where libpathrs had to fall back to a slower (or less precise)
+/// implementation of an operation, reported to [`MetricsRecorder::fallback`].
+///
+/// [`MetricsRecorder::fallback`]: trait.MetricsRecorder.html#method.fallback
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FallbackEvent {
+    /// An `openat2(2)` lookup ran out of `EAGAIN` retries (see
+    /// [`RetryPolicy`]) and fell back to the emulated resolver.
+    ///
+    /// [`RetryPolicy`]: struct.RetryPolicy.html
+    KernelEagain,
+    /// `openat2(2)` was unexpectedly unavailable, and the lookup fell back to
+    /// the emulated resolver.
+    KernelUnsupported,
+    /// A case-insensitive `openat2(2)` lookup failed, and was retried with the
+    /// emulated resolver.
+    CaseInsensitive,
+    /// The path was too long for `openat2(2)` (longer than `PATH_MAX`), and
+    /// the lookup fell back to the emulated resolver.
+    LongPath,
+    /// The path crossed a magic-link (with
+    /// [`ResolverFlags::ALLOW_MAGICLINKS`]), and the lookup fell back to the
+    /// emulated resolver.
+    ///
+    /// [`ResolverFlags::ALLOW_MAGICLINKS`]: struct.ResolverFlags.html#associatedconstant.ALLOW_MAGICLINKS
+    MagicLink,
+    /// An `openat2(2)` lookup failed with `ENOTDIR`, and was retried with the
+    /// emulated resolver to find out which component is not a directory.
+    NotADirectory,
+    /// `statx(2)` is unavailable, and `fstatat(2)` was used instead.
+    StatxUnsupported,
+}
+
+/// A receiver of metrics about libpathrs operations, so that they can be
+/// exported to a monitoring system. See [`set_metrics_recorder`].
+///
+/// Every method has an empty default implementation, so you only need to
+/// implement the metrics you are interested in. The methods are called inline
+/// from the operations being measured (possibly from many threads at once), so
+/// they should be cheap. [`MetricsCollector`] is a simple implementation which
+/// keeps counters and a latency histogram in memory.
+///
+/// [`set_metrics_recorder`]: fn.set_metrics_recorder.html
+/// [`MetricsCollector`]: struct.MetricsCollector.html
+pub trait MetricsRecorder: fmt::Debug + Send + Sync {
+    /// A path resolution with `backend` finished after `latency`. `success`
+    /// is whether the resolution succeeded. Fallbacks to the emulated
+    /// resolver are counted as resolutions by the original backend.
+    fn resolution(&self, backend: ResolverBackend, latency: Duration, success: bool) {
+        let _ = (backend, latency, success);
+    }
+
+    /// An `openat2(2)` lookup is being retried after failing with `EAGAIN`.
+    fn eagain_retry(&self) {}
+
+    /// libpathrs had to use a fallback implementation.
+    fn fallback(&self, event: FallbackEvent) {
+        let _ = event;
+    }
+
+    /// A safety violation (a possible attack, see
+    /// [`Error::SafetyViolation`]) was detected while resolving or re-opening
+    /// a path.
+    ///
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    fn safety_violation(&self, description: &str) {
+        let _ = description;
+    }
+
+    /// An emulated path resolution was aborted for exceeding its
+    /// [`ResolutionBudget`].
+    ///
+    /// [`ResolutionBudget`]: struct.ResolutionBudget.html
+    fn resolution_budget_exceeded(&self) {}
+}
+
+lazy_static! {
+    static ref METRICS_RECORDER: RwLock<Option<Arc<dyn MetricsRecorder>>> = RwLock::new(None);
+}
+
+/// Get the current [`MetricsRecorder`] of libpathrs (if any).
+///
+/// [`MetricsRecorder`]: trait.MetricsRecorder.html
+pub fn metrics_recorder() -> Option<Arc<dyn MetricsRecorder>> {
+    METRICS_RECORDER.read().unwrap().clone()
+}
+
+/// Change the [`MetricsRecorder`] of libpathrs (or disable metrics with
+/// `None`). The recorder is global to the process.
+///
+/// [`MetricsRecorder`]: trait.MetricsRecorder.html
+pub fn set_metrics_recorder(recorder: Option<Arc<dyn MetricsRecorder>>) {
+    *METRICS_RECORDER.write().unwrap() = recorder;
+}
+
+/// Upper bounds of the latency histogram buckets of [`MetricsCollector`]. The
+/// last bucket has no upper bound.
+///
+/// [`MetricsCollector`]: struct.MetricsCollector.html
+pub const LATENCY_BUCKETS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Number of buckets in a [`MetricsSnapshot`] latency histogram.
+///
+/// [`MetricsSnapshot`]: struct.MetricsSnapshot.html
+const NUM_BUCKETS: usize = LATENCY_BUCKETS.len() + 1;
+
+/// Per-backend counters of a [`MetricsCollector`].
+///
+/// [`MetricsCollector`]: struct.MetricsCollector.html
+#[derive(Debug, Default)]
+struct BackendCounters {
+    resolutions: AtomicU64,
+    failures: AtomicU64,
+    latency: [AtomicU64; NUM_BUCKETS],
+}
+
+impl BackendCounters {
+    fn snapshot(&self) -> BackendMetrics {
+        let mut latency = [0; NUM_BUCKETS];
+        for (count, bucket) in latency.iter_mut().zip(self.latency.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        BackendMetrics {
+            resolutions: self.resolutions.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            latency,
+        }
+    }
+}
+
+/// Metrics for a single [`ResolverBackend`], in a [`MetricsSnapshot`].
+///
+/// [`ResolverBackend`]: enum.ResolverBackend.html
+/// [`MetricsSnapshot`]: struct.MetricsSnapshot.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendMetrics {
+    /// Number of resolutions (successful or not).
+    pub resolutions: u64,
+    /// Number of failed resolutions.
+    pub failures: u64,
+    /// Histogram of resolution latencies. `latency[i]` is the number of
+    /// resolutions which took at most [`LATENCY_BUCKETS`]`[i]` (and more than
+    /// the previous bound), with the last entry counting resolutions slower
+    /// than every bound.
+    ///
+    /// [`LATENCY_BUCKETS`]: constant.LATENCY_BUCKETS.html
+    pub latency: [u64; NUM_BUCKETS],
+}
+
+/// A copy of the metrics of a [`MetricsCollector`] at a point in time.
+///
+/// [`MetricsCollector`]: struct.MetricsCollector.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Resolutions using [`ResolverBackend::Kernel`].
+    ///
+    /// [`ResolverBackend::Kernel`]: enum.ResolverBackend.html#variant.Kernel
+    pub kernel: BackendMetrics,
+    /// Resolutions using [`ResolverBackend::Emulated`].
+    ///
+    /// [`ResolverBackend::Emulated`]: enum.ResolverBackend.html#variant.Emulated
+    pub emulated: BackendMetrics,
+    /// Number of `openat2(2)` `EAGAIN` retries.
+    pub eagain_retries: u64,
+    /// Number of [`FallbackEvent`]s.
+    ///
+    /// [`FallbackEvent`]: enum.FallbackEvent.html
+    pub fallbacks: u64,
+    /// Number of safety violations.
+    pub safety_violations: u64,
+    /// Number of resolutions aborted for exceeding their resolution budget.
+    pub budgets_exceeded: u64,
+}
+
+/// A [`MetricsRecorder`] which keeps counters and latency histograms in
+/// memory, for exporting with [`MetricsCollector::snapshot`].
+///
+/// [`MetricsRecorder`]: trait.MetricsRecorder.html
+/// [`MetricsCollector::snapshot`]: struct.MetricsCollector.html#method.snapshot
+#[derive(Debug, Default)]
+pub struct MetricsCollector {
+    kernel: BackendCounters,
+    emulated: BackendCounters,
+    eagain_retries: AtomicU64,
+    fallbacks: AtomicU64,
+    safety_violations: AtomicU64,
+    budgets_exceeded: AtomicU64,
+}
+
+impl MetricsCollector {
+    /// Create a new [`MetricsCollector`] with every metric set to zero.
+    ///
+    /// [`MetricsCollector`]: struct.MetricsCollector.html
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current values of the metrics.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            kernel: self.kernel.snapshot(),
+            emulated: self.emulated.snapshot(),
+            eagain_retries: self.eagain_retries.load(Ordering::Relaxed),
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+            safety_violations: self.safety_violations.load(Ordering::Relaxed),
+            budgets_exceeded: self.budgets_exceeded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsRecorder for MetricsCollector {
+    fn resolution(&self, backend: ResolverBackend, latency: Duration, success: bool) {
+        let counters = match backend {
+            ResolverBackend::Kernel => &self.kernel,
+            ResolverBackend::Emulated => &self.emulated,
+        };
+        counters.resolutions.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(NUM_BUCKETS - 1);
+        counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn eagain_retry(&self) {
+        self.eagain_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn fallback(&self, _event: FallbackEvent) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn safety_violation(&self, _description: &str) {
+        self.safety_violations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn resolution_budget_exceeded(&self) {
+        self.budgets_exceeded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Start timing an operation, if there is a [`MetricsRecorder`].
+///
+/// [`MetricsRecorder`]: trait.MetricsRecorder.html
+pub(crate) fn start() -> Option<Instant> {
+    if METRICS_RECORDER.read().unwrap().is_some() {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// Record a resolution (started with [`start`]), along with any safety
+/// violation it resulted in.
+///
+/// [`start`]: fn.start.html
+pub(crate) fn record_resolution<T>(
+    start: Option<Instant>,
+    backend: ResolverBackend,
+    result: &Result<T, Error>,
+) {
+    if let (Some(start), Some(recorder)) = (start, metrics_recorder()) {
+        recorder.resolution(backend, start.elapsed(), result.is_ok());
+        if let Err(err) = result {
+            record_error(err);
+        }
+    }
+}
+
+/// Record a safety violation if `err` was caused by one.
+pub(crate) fn record_error(err: &Error) {
+    if let (Some(description), Some(recorder)) = (err.safety_violation(), metrics_recorder()) {
+        recorder.safety_violation(description);
+    }
+}
+
+pub(crate) fn record_eagain_retry() {
+    if let Some(recorder) = metrics_recorder() {
+        recorder.eagain_retry();
+    }
+}
+
+pub(crate) fn record_budget_exceeded() {
+    if let Some(recorder) = metrics_recorder() {
+        recorder.resolution_budget_exceeded();
+    }
+}
+
+pub(crate) fn record_fallback(event: FallbackEvent) {
+    if let Some(recorder) = metrics_recorder() {
+        recorder.fallback(event);
+    }
+}
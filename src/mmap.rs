@@ -0,0 +1,268 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// We need unsafe code to turn the mapping into a slice, so unlike most other
+// modules we cannot #![forbid(unsafe_code)].
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, Handle, Lease, LeaseType, OpenFlags,
+};
+
+use std::{
+    ops::Deref,
+    os::unix::io::{AsRawFd, RawFd},
+    slice,
+};
+
+use libc::c_void;
+use snafu::ResultExt;
+
+/// Filesystems whose files we refuse to map, because their reported sizes
+/// and contents are synthesised (or controlled by another process) and so
+/// mapping them is a recipe for `SIGBUS`.
+const UNMAPPABLE_FILESYSTEMS: &[i64] = &[
+    libc::PROC_SUPER_MAGIC,
+    libc::SYSFS_MAGIC,
+    libc::TRACEFS_MAGIC,
+    libc::DEBUGFS_MAGIC,
+    libc::SECURITYFS_MAGIC,
+    libc::FUSE_SUPER_MAGIC,
+];
+
+/// A read-only memory mapping of a file, created with
+/// [`Handle::map_readonly`] or [`Handle::map_readonly_leased`].
+///
+/// The mapping is unmapped when the [`Mapping`] is dropped.
+///
+/// [`Handle::map_readonly`]: struct.Handle.html#method.map_readonly
+/// [`Handle::map_readonly_leased`]: struct.Handle.html#method.map_readonly_leased
+/// [`Mapping`]: struct.Mapping.html
+#[derive(Debug)]
+pub struct Mapping {
+    addr: *mut c_void,
+    len: usize,
+    // The mapping doesn't need the file to stay open, but the lease (if any)
+    // is only held as long as its file is open.
+    lease: Option<Lease>,
+}
+
+// SAFETY: The mapping is read-only and not tied to any thread.
+unsafe impl Send for Mapping {}
+// SAFETY: The mapping is read-only, so sharing references is safe.
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Get the contents of the mapping.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: The mapping is valid for len bytes until we are dropped. The
+        //         caller of map_readonly promised that the contents won't be
+        //         modified underneath us.
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+
+    /// Length of the mapping in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the mapping is empty. This is always `false`, since empty
+    /// mappings cannot be created.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The [`Lease`] protecting the mapping, if the mapping was created with
+    /// [`Handle::map_readonly_leased`].
+    ///
+    /// [`Lease`]: struct.Lease.html
+    /// [`Handle::map_readonly_leased`]: struct.Handle.html#method.map_readonly_leased
+    #[inline]
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was created by us, and the borrow checker ensures
+        //         no slices of it outlive us.
+        unsafe { syscalls::munmap(self.addr, self.len) };
+    }
+}
+
+impl Handle {
+    /// Create a read-only memory mapping of `len` bytes of the file referenced
+    /// by the [`Handle`], starting at `offset`.
+    ///
+    /// The file must be a regular file, and must not be on a pseudo-filesystem
+    /// (procfs, sysfs and friends) or FUSE -- where the size of the file is
+    /// either meaningless or under the control of another process.
+    ///
+    /// # Safety
+    ///
+    /// If the file is truncated while it is mapped, accessing the truncated
+    /// part of the mapping will result in the process being killed with
+    /// `SIGBUS`. And if the file is modified while it is mapped, the contents
+    /// of the [`Mapping`] will change (which is undefined behaviour for a
+    /// `&[u8]`). The caller guarantees that neither will happen, usually by
+    /// ensuring that no other process can write to the file. If you cannot
+    /// guarantee that, consider using [`Handle::map_readonly_leased`] or just
+    /// reading the file instead.
+    ///
+    /// # Errors
+    ///
+    /// `offset` must be a multiple of the page size. If the requested range is
+    /// empty or extends beyond the end of the file (in which case accessing the
+    /// end of the mapping would result in `SIGBUS`), an
+    /// [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Mapping`]: struct.Mapping.html
+    /// [`Handle::map_readonly_leased`]: struct.Handle.html#method.map_readonly_leased
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub unsafe fn map_readonly(&self, len: usize, offset: u64) -> Result<Mapping, Error> {
+        self.check_mappable()?;
+        let file = self
+            .reopen(OpenFlags(libc::O_RDONLY))
+            .wrap("reopen handle for mapping")?;
+        let addr = map_fd(file.as_raw_fd(), len, offset)?;
+        Ok(Mapping {
+            addr,
+            len,
+            lease: None,
+        })
+    }
+
+    /// Identical to [`Handle::map_readonly`], except that a read [`Lease`] is
+    /// taken on the file before it is mapped (see [`Handle::lease`]).
+    ///
+    /// The kernel will notify you (by default with `SIGIO`) when another
+    /// process attempts to open the file for writing or truncate it, and will
+    /// block that process until the [`Mapping`] is dropped (or the lease break
+    /// timeout expires). This doesn't prevent modifications by processes which
+    /// already had the file open for writing when the lease was taken (the
+    /// lease cannot be taken in that case, which is checked by the kernel).
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Handle::map_readonly`], except that the caller only needs
+    /// to guarantee that they will stop using the [`Mapping`] (and drop it)
+    /// before the lease break timeout (`/proc/sys/fs/lease-break-time`)
+    /// expires after a lease break.
+    ///
+    /// [`Handle::map_readonly`]: struct.Handle.html#method.map_readonly
+    /// [`Handle::lease`]: struct.Handle.html#method.lease
+    /// [`Lease`]: struct.Lease.html
+    /// [`Mapping`]: struct.Mapping.html
+    pub unsafe fn map_readonly_leased(&self, len: usize, offset: u64) -> Result<Mapping, Error> {
+        self.check_mappable()?;
+        let lease = self
+            .lease(LeaseType::Read)
+            .wrap("take lease on file for mapping")?;
+        let addr = map_fd(lease.as_raw_fd(), len, offset)?;
+        Ok(Mapping {
+            addr,
+            len,
+            lease: Some(lease),
+        })
+    }
+
+    /// Check that the handle references a file we are willing to map -- a
+    /// regular file on a "normal" filesystem.
+    fn check_mappable(&self) -> Result<(), Error> {
+        let meta = self.inner.metadata().context(error::OsError {
+            operation: "fstat handle for mapping",
+        })?;
+        ensure!(
+            meta.is_file(),
+            error::InvalidArgument {
+                name: "handle",
+                description: "only regular files can be mapped",
+            }
+        );
+        let fs_type = syscalls::fstatfs(self.inner.as_raw_fd())
+            .context(error::RawOsError {
+                operation: "fstatfs handle for mapping",
+            })?
+            .f_type;
+        ensure!(
+            !UNMAPPABLE_FILESYSTEMS.contains(&fs_type),
+            error::SafetyViolation {
+                description: format!("refusing to map file on filesystem 0x{:X}", fs_type),
+            }
+        );
+        Ok(())
+    }
+}
+
+/// Validate the requested range against the (already opened) file and map it.
+fn map_fd(fd: RawFd, len: usize, offset: u64) -> Result<*mut c_void, Error> {
+    ensure!(
+        len > 0,
+        error::InvalidArgument {
+            name: "len",
+            description: "cannot create an empty mapping",
+        }
+    );
+    ensure!(
+        // The page size is always a power of two.
+        offset & (syscalls::page_size() as u64 - 1) == 0,
+        error::InvalidArgument {
+            name: "offset",
+            description: "must be a multiple of the page size",
+        }
+    );
+    // Check the size using the descriptor we are about to map, to avoid racing
+    // against a swap of the path.
+    let size = syscalls::fstatat(fd, "")
+        .context(error::RawOsError {
+            operation: "fstat file for mapping",
+        })?
+        .st_size as u64;
+    ensure!(
+        matches!(offset.checked_add(len as u64), Some(end) if end <= size),
+        error::InvalidArgument {
+            name: "len",
+            description: format!(
+                "mapping {} bytes at offset {} extends beyond the end of the file ({} bytes) and would result in SIGBUS",
+                len, offset, size
+            ),
+        }
+    );
+    syscalls::mmap_readonly(fd, len, offset).context(error::RawOsError {
+        operation: "map file",
+    })
+}
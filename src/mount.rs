@@ -0,0 +1,177 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    stat, syscalls,
+    utils::{self, FileExt},
+    Handle, Root,
+};
+
+use std::{
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// Filesystems whose contents are controlled by an unprivileged process, and
+/// so are never safe to mount on top of (the contents can change under the
+/// mount at any time).
+const USER_CONTROLLED_FILESYSTEMS: &[i64] = &[libc::FUSE_SUPER_MAGIC];
+
+/// A mount target which was verified by [`Root::is_path_safe_for_mount`].
+///
+/// The guard holds the resolved [`Handle`] open, so that the caller can pass
+/// [`MountTarget::procfd_path`] to `mount(2)` and be sure the kernel uses the
+/// verified inode. Keep the guard alive until `mount(2)` has returned, and
+/// use [`MountTarget::reverify`] right before the call to check that the path
+/// was not swapped in the meantime.
+///
+/// [`Root::is_path_safe_for_mount`]: struct.Root.html#method.is_path_safe_for_mount
+/// [`Handle`]: struct.Handle.html
+/// [`MountTarget::procfd_path`]: struct.MountTarget.html#method.procfd_path
+/// [`MountTarget::reverify`]: struct.MountTarget.html#method.reverify
+#[derive(Debug)]
+pub struct MountTarget<'a> {
+    root: &'a Root,
+    path: PathBuf,
+    handle: Handle,
+    inode_id: (u64, u64),
+    mnt_id: Option<u64>,
+}
+
+impl MountTarget<'_> {
+    /// The verified [`Handle`].
+    ///
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The `/proc/self/fd/...` path of the verified [`Handle`], to be passed
+    /// to `mount(2)` as the target. The path is only valid while the
+    /// [`MountTarget`] is alive.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`MountTarget`]: struct.MountTarget.html
+    pub fn procfd_path(&self) -> Result<PathBuf, Error> {
+        utils::procfd_path(self.handle.inner.as_raw_fd())
+    }
+
+    /// Resolve the path again and check that it still reaches the same inode
+    /// on the same mount.
+    ///
+    /// # Errors
+    ///
+    /// If the path now resolves to a different inode (or the inode has been
+    /// mounted over), an [`Error::SafetyViolation`] is returned. Otherwise,
+    /// the errors are identical to [`Root::is_path_safe_for_mount`].
+    ///
+    /// [`Root::is_path_safe_for_mount`]: struct.Root.html#method.is_path_safe_for_mount
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn reverify(&self) -> Result<(), Error> {
+        let current = self
+            .root
+            .open_entry_nofollow(&self.path)
+            .wrap("re-resolve mount target")?;
+        ensure!(
+            current.inode_id()? == self.inode_id
+                && stat::mount_id(current.as_raw_fd())? == self.mnt_id,
+            error::SafetyViolation {
+                description: format!("mount target {:?} was swapped after resolution", self.path),
+            }
+        );
+        Ok(())
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve `path` and check that it is safe to
+    /// use as the target of `mount(2)`, returning a [`MountTarget`] guard
+    /// which holds the resolved [`Handle`] open.
+    ///
+    /// The final component of `path` is not followed. The target must not be
+    /// a symlink, must not be on a filesystem which
+    /// can contain magic-links (such as procfs) or whose contents are
+    /// controlled by an unprivileged process (such as FUSE), and must still
+    /// be reached by `path` after these checks.
+    ///
+    /// # Errors
+    ///
+    /// If any of the checks fail, an [`Error::SafetyViolation`] is returned.
+    /// If `path` has no final component (such as `"/"`), an
+    /// [`Error::InvalidArgument`] is returned. Otherwise, the errors are
+    /// identical to [`Root::resolve`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`MountTarget`]: struct.MountTarget.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn is_path_safe_for_mount<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<MountTarget<'_>, Error> {
+        let path = path.as_ref();
+        let handle = Handle::from_file_unchecked(
+            self.open_entry_nofollow(path)
+                .wrap("resolve mount target")?,
+        );
+        let file = &handle.inner;
+
+        let meta = file.metadata().context(error::OsError {
+            operation: "fstat mount target",
+        })?;
+        ensure!(
+            !meta.file_type().is_symlink(),
+            error::SafetyViolation {
+                description: format!("mount target {:?} is a symlink", path),
+            }
+        );
+
+        let fs_type = syscalls::fstatfs(file.as_raw_fd())
+            .context(error::RawOsError {
+                operation: "check filesystem of mount target",
+            })?
+            .f_type;
+        ensure!(
+            !file.is_dangerous()? && !USER_CONTROLLED_FILESYSTEMS.contains(&fs_type),
+            error::SafetyViolation {
+                description: format!(
+                    "mount target {:?} is on an unsafe filesystem (f_type 0x{:X})",
+                    path, fs_type
+                ),
+            }
+        );
+
+        let target = MountTarget {
+            root: self,
+            path: path.to_path_buf(),
+            inode_id: file.inode_id()?,
+            mnt_id: stat::mount_id(file.as_raw_fd())?,
+            handle,
+        };
+        target.reverify()?;
+        Ok(target)
+    }
+}
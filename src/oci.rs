@@ -0,0 +1,776 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    path::normalize_lexical,
+    syscalls,
+    utils::FileExt,
+    DeviceType, Handle, InodeType, OpenFlags, Root,
+};
+
+use std::{
+    fs::{File, Permissions},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        io::{AsRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+use libc::dev_t;
+use snafu::ResultExt;
+
+/// Permissions used for directories created by the OCI helpers (matching
+/// the behaviour of runc).
+const OCI_DIRECTORY_MODE: u32 = 0o755;
+
+/// Permissions used for files created by the OCI helpers (matching the
+/// behaviour of runc).
+const OCI_FILE_MODE: u32 = 0o644;
+
+/// Device major number of `/dev/ptmx`.
+const TTYAUX_MAJOR: u32 = 5;
+/// Device minor number of `/dev/ptmx`.
+const PTMX_MINOR: u32 = 2;
+/// First device major number used for Unix98 pseudo-terminal slaves.
+const UNIX98_PTY_SLAVE_MAJOR: u32 = 136;
+/// Number of device majors used for Unix98 pseudo-terminal slaves.
+const UNIX98_PTY_MAJOR_COUNT: u32 = 8;
+
+/// Permissions of the device inodes created by
+/// [`Root::populate_devtmpfs_skeleton`].
+///
+/// [`Root::populate_devtmpfs_skeleton`]: struct.Root.html#method.populate_devtmpfs_skeleton
+const DEV_SKELETON_DEVICE_MODE: u32 = 0o666;
+
+/// The character devices created by [`Root::populate_devtmpfs_skeleton`], as
+/// `(name, major, minor)`.
+///
+/// [`Root::populate_devtmpfs_skeleton`]: struct.Root.html#method.populate_devtmpfs_skeleton
+const DEV_SKELETON_DEVICES: &[(&str, u32, u32)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// The symlinks created by [`Root::populate_devtmpfs_skeleton`], as `(name,
+/// target)`.
+///
+/// [`Root::populate_devtmpfs_skeleton`]: struct.Root.html#method.populate_devtmpfs_skeleton
+const DEV_SKELETON_SYMLINKS: &[(&str, &str)] = &[
+    ("ptmx", "pts/ptmx"),
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// The directories created by [`Root::populate_devtmpfs_skeleton`] (as
+/// mountpoints for `devpts` and `/dev/shm`).
+///
+/// [`Root::populate_devtmpfs_skeleton`]: struct.Root.html#method.populate_devtmpfs_skeleton
+const DEV_SKELETON_DIRECTORIES: &[&str] = &["pts", "shm"];
+
+/// The kind of character device expected by [`Root::open_console`].
+///
+/// [`Root::open_console`]: struct.Root.html#method.open_console
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConsoleDevice {
+    /// Any pseudo-terminal slave on a `devpts` filesystem (such as
+    /// `/dev/pts/0`, or a bind-mount of one on `/dev/console`).
+    PtySlave,
+    /// The pseudo-terminal multiplexer on a `devpts` filesystem (such as
+    /// `/dev/pts/ptmx`).
+    Ptmx,
+    /// A character device with exactly this device number, on any filesystem.
+    Device(dev_t),
+}
+
+impl ConsoleDevice {
+    /// Is this a device which only lives on `devpts`?
+    fn needs_devpts(self) -> bool {
+        !matches!(self, Self::Device(_))
+    }
+
+    fn matches(self, rdev: dev_t) -> bool {
+        let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+        match self {
+            Self::PtySlave => (UNIX98_PTY_SLAVE_MAJOR
+                ..UNIX98_PTY_SLAVE_MAJOR + UNIX98_PTY_MAJOR_COUNT)
+                .contains(&major),
+            Self::Ptmx => major == TTYAUX_MAJOR && minor == PTMX_MINOR,
+            Self::Device(dev) => rdev == dev,
+        }
+    }
+}
+
+/// A verified OCI `maskedPaths` target, returned by
+/// [`Root::resolve_masked_path`].
+///
+/// [`Root::resolve_masked_path`]: struct.Root.html#method.resolve_masked_path
+#[derive(Debug)]
+pub enum MaskedPath {
+    /// The target is a directory, and should be masked by mounting an empty
+    /// read-only `tmpfs` on it.
+    Directory(MountDestination),
+    /// The target is not a directory, and should be masked by bind-mounting
+    /// `/dev/null` on it.
+    File(MountDestination),
+}
+
+impl MaskedPath {
+    /// The verified mountpoint of the target.
+    #[inline]
+    pub fn destination(&self) -> &MountDestination {
+        match self {
+            Self::Directory(dest) | Self::File(dest) => dest,
+        }
+    }
+
+    /// Unwrap the [`MaskedPath`] to get the verified mountpoint of the
+    /// target.
+    ///
+    /// [`MaskedPath`]: enum.MaskedPath.html
+    #[inline]
+    pub fn into_destination(self) -> MountDestination {
+        match self {
+            Self::Directory(dest) | Self::File(dest) => dest,
+        }
+    }
+}
+
+/// The type of mountpoint needed for a mount, used with
+/// [`Root::prepare_mount_destination`].
+///
+/// [`Root::prepare_mount_destination`]: struct.Root.html#method.prepare_mount_destination
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MountDestinationType {
+    /// The mount source is a directory (or a filesystem), so the destination
+    /// must be a directory.
+    Directory,
+    /// The mount source is not a directory (such as a bind-mount of a regular
+    /// file or device), so the destination must not be a directory.
+    File,
+}
+
+/// A verified mountpoint inside a [`Root`], returned by
+/// [`Root::prepare_mount_destination`].
+///
+/// The mountpoint is referenced by an `O_PATH` [`Handle`], which is kept open
+/// for as long as the [`MountDestination`] is alive. You should mount onto the
+/// mountpoint using [`MountDestination::proc_path`] (with `mount(2)`) or the
+/// file descriptor itself (with `move_mount(2)` and `MOVE_MOUNT_T_EMPTY_PATH`)
+/// rather than the original path, since the original path could be swapped
+/// for a symlink after it was verified.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::prepare_mount_destination`]: struct.Root.html#method.prepare_mount_destination
+/// [`Handle`]: struct.Handle.html
+/// [`MountDestination`]: struct.MountDestination.html
+/// [`MountDestination::proc_path`]: struct.MountDestination.html#method.proc_path
+#[derive(Debug)]
+pub struct MountDestination {
+    handle: Handle,
+    proc_path: PathBuf,
+}
+
+impl MountDestination {
+    /// The `O_PATH` [`Handle`] to the mountpoint.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The `/proc/self/fd/$n` path of the mountpoint, suitable for use as the
+    /// target of `mount(2)`.
+    ///
+    /// The path is only valid while the [`MountDestination`] is alive, and
+    /// only within the current process. See [`Handle::proc_fd_path`] for the
+    /// other caveats of using the path.
+    ///
+    /// [`MountDestination`]: struct.MountDestination.html
+    /// [`Handle::proc_fd_path`]: struct.Handle.html#method.proc_fd_path
+    #[inline]
+    pub fn proc_path(&self) -> &Path {
+        &self.proc_path
+    }
+
+    /// Unwrap the [`MountDestination`] to get the [`Handle`] to the
+    /// mountpoint. Note that this invalidates the path returned by
+    /// [`MountDestination::proc_path`] once the [`Handle`] is dropped.
+    ///
+    /// [`MountDestination`]: struct.MountDestination.html
+    /// [`MountDestination::proc_path`]: struct.MountDestination.html#method.proc_path
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn into_handle(self) -> Handle {
+        self.handle
+    }
+}
+
+impl AsRawFd for MountDestination {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.inner.as_raw_fd()
+    }
+}
+
+/// A device inode found by [`Root::list_devices`], with the fields of an entry
+/// in the `linux.devices` list of an OCI runtime spec.
+///
+/// [`Root::list_devices`]: struct.Root.html#method.list_devices
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OciDevice {
+    /// The absolute path of the device inode within the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    pub path: PathBuf,
+    /// The type of the device.
+    pub device_type: DeviceType,
+    /// The major number of the device.
+    pub major: u32,
+    /// The minor number of the device.
+    pub minor: u32,
+    /// The permission bits of the device inode (`st_mode & 07777`).
+    pub file_mode: u32,
+    /// The owning user of the device inode.
+    pub uid: u32,
+    /// The owning group of the device inode.
+    pub gid: u32,
+}
+
+impl OciDevice {
+    /// The OCI `type` of the device (`"c"` or `"b"`).
+    pub fn type_code(&self) -> &'static str {
+        match self.device_type {
+            DeviceType::Character => "c",
+            DeviceType::Block => "b",
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, create any missing parent directories of the
+    /// OCI mount `destination`, create the mountpoint itself (if it doesn't
+    /// exist), and return a verified handle to it.
+    ///
+    /// This is the sequence of operations container runtimes need to do for
+    /// each entry in the OCI `mounts` configuration, and which is notoriously
+    /// easy to get wrong (an attacker-controlled container image can contain
+    /// symlinks which redirect the mount outside of the container's root
+    /// filesystem). All symlinks are resolved inside the [`Root`].
+    ///
+    /// Missing directories are created with mode `0o755` and missing file
+    /// mountpoints are created as empty regular files with mode `0o644`
+    /// (subject to the umask and the [`Root`]'s [`ModePolicy`]).
+    ///
+    /// # Errors
+    ///
+    /// If the existing mountpoint is a directory when `dest_type` is
+    /// [`MountDestinationType::File`] (or vice versa), an
+    /// [`Error::InvalidArgument`] is returned. Dangling symlinks in
+    /// `destination` are not followed to create their targets, and so result
+    /// in an error.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    /// [`MountDestinationType::File`]: enum.MountDestinationType.html#variant.File
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn prepare_mount_destination<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        dest_type: MountDestinationType,
+    ) -> Result<MountDestination, Error> {
+        // OCI mount destinations are always relative to the root filesystem,
+        // even if they are not absolute.
+        let destination = normalize_lexical(Path::new("/").join(destination));
+
+        if let Some(parent) = destination.parent() {
+            self.mkdir_all(parent, &Permissions::from_mode(OCI_DIRECTORY_MODE))
+                .wrap("create mountpoint parent directories")?;
+            let ret = match dest_type {
+                MountDestinationType::Directory => {
+                    let perm = Permissions::from_mode(OCI_DIRECTORY_MODE);
+                    self.create(&destination, &InodeType::Directory(&perm))
+                }
+                MountDestinationType::File => {
+                    let perm = Permissions::from_mode(OCI_FILE_MODE);
+                    self.create(&destination, &InodeType::File(&perm))
+                }
+            };
+            match ret {
+                Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                    return Err(err).wrap("create mountpoint");
+                }
+                _ => (),
+            }
+        }
+
+        let handle = self.resolve(&destination).wrap("resolve mountpoint")?;
+        let is_dir = handle
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat mountpoint",
+            })?
+            .is_dir();
+        match (dest_type, is_dir) {
+            (MountDestinationType::Directory, false) => error::InvalidArgument {
+                name: "destination",
+                description: format!("{:?} is not a directory", destination),
+            }
+            .fail()?,
+            (MountDestinationType::File, true) => error::InvalidArgument {
+                name: "destination",
+                description: format!("{:?} is a directory", destination),
+            }
+            .fail()?,
+            _ => (),
+        }
+
+        let proc_path = handle.proc_fd_path()?.to_path_buf();
+        Ok(MountDestination { handle, proc_path })
+    }
+
+    /// Within the [`Root`]'s tree, create an empty regular file at `path` (and
+    /// any missing parent directories) to be used as the target of an OCI
+    /// file bind-mount, and return a verified handle to it.
+    ///
+    /// The file is created with `O_CREAT | O_EXCL` with the permissions `perm`
+    /// (subject to the umask and the [`Root`]'s [`ModePolicy`]), and the
+    /// returned [`MountDestination`] is verified to be the same inode that was
+    /// created. If a regular file already exists at `path`, it is used as-is
+    /// (its mode is not changed) as long as it is empty. Non-empty files are
+    /// only truncated if `truncate` is set, and never if the file has more
+    /// than one hard link (since the other links may be outside of the
+    /// container's root filesystem).
+    ///
+    /// # Errors
+    ///
+    /// If `path` exists and is not a regular file, or is a non-empty file and
+    /// `truncate` is not set, an [`Error::InvalidArgument`] is returned. If
+    /// the file is swapped for a different inode while it is being created, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    /// [`MountDestination`]: struct.MountDestination.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn bind_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        perm: &Permissions,
+        truncate: bool,
+    ) -> Result<MountDestination, Error> {
+        let destination = normalize_lexical(Path::new("/").join(path));
+        if let Some(parent) = destination.parent() {
+            self.mkdir_all(parent, &Permissions::from_mode(OCI_DIRECTORY_MODE))
+                .wrap("create bind-mount target parent directories")?;
+        }
+
+        let created = match self.create_file(&destination, perm) {
+            Ok(file) => Some(file.inner.inode_id()?),
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => None,
+            Err(err) => return Err(err).wrap("create bind-mount target"),
+        };
+
+        let handle = self
+            .resolve(&destination)
+            .wrap("resolve bind-mount target")?;
+        let meta = handle.inner.metadata().context(error::OsError {
+            operation: "fstat bind-mount target",
+        })?;
+        if let Some(created) = created {
+            ensure!(
+                (meta.dev(), meta.ino()) == created,
+                error::SafetyViolation {
+                    description: format!(
+                        "bind-mount target {:?} was swapped after it was created",
+                        destination
+                    ),
+                }
+            );
+        }
+        ensure!(
+            meta.is_file(),
+            error::InvalidArgument {
+                name: "path",
+                description: format!("{:?} is not a regular file", destination),
+            }
+        );
+        if meta.len() > 0 {
+            ensure!(
+                truncate,
+                error::InvalidArgument {
+                    name: "path",
+                    description: format!("{:?} is not empty", destination),
+                }
+            );
+            ensure!(
+                meta.nlink() == 1,
+                error::InvalidArgument {
+                    name: "path",
+                    description: format!(
+                        "refusing to truncate {:?} with {} hard links",
+                        destination,
+                        meta.nlink()
+                    ),
+                }
+            );
+            // Truncate through the verified handle, so that the file cannot be
+            // swapped in the meantime.
+            handle
+                .reopen(OpenFlags(libc::O_WRONLY | libc::O_TRUNC))
+                .wrap("truncate bind-mount target")?;
+        }
+
+        let proc_path = handle.proc_fd_path()?.to_path_buf();
+        Ok(MountDestination { handle, proc_path })
+    }
+
+    /// Within the [`Root`]'s tree, resolve the OCI `maskedPaths` entry `path`
+    /// and return a verified mountpoint for masking it.
+    ///
+    /// Unlike [`Root::prepare_mount_destination`], nothing is created -- as
+    /// with other OCI runtimes, targets which don't exist are skipped (and
+    /// `None` is returned). Which kind of mount should be used to mask the
+    /// target depends on the type of the target, see [`MaskedPath`].
+    ///
+    /// # Errors
+    ///
+    /// If the target cannot be resolved for any reason other than not
+    /// existing, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::prepare_mount_destination`]: struct.Root.html#method.prepare_mount_destination
+    /// [`MaskedPath`]: enum.MaskedPath.html
+    pub fn resolve_masked_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<MaskedPath>, Error> {
+        let path = path.as_ref();
+        let (dest, is_dir) = match self
+            .resolve_existing_mountpoint(path)
+            .wrap_with(|| format!("resolve masked path {:?}", path))?
+        {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        Ok(Some(match is_dir {
+            true => MaskedPath::Directory(dest),
+            false => MaskedPath::File(dest),
+        }))
+    }
+
+    /// Within the [`Root`]'s tree, resolve the OCI `readonlyPaths` entry
+    /// `path` and return a verified mountpoint for it.
+    ///
+    /// The target should be bind-mounted onto itself (using the returned
+    /// [`MountDestination`] as both source and target) and then remounted with
+    /// `MS_RDONLY`. Targets which don't exist are skipped (and `None` is
+    /// returned).
+    ///
+    /// # Errors
+    ///
+    /// If `expected` is provided and the target is a directory when
+    /// `expected` is [`MountDestinationType::File`] (or vice versa), an
+    /// [`Error::InvalidArgument`] is returned. If the target cannot be
+    /// resolved for any reason other than not existing, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`MountDestination`]: struct.MountDestination.html
+    /// [`MountDestinationType::File`]: enum.MountDestinationType.html#variant.File
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn resolve_readonly_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+        expected: Option<MountDestinationType>,
+    ) -> Result<Option<MountDestination>, Error> {
+        let path = path.as_ref();
+        let (dest, is_dir) = match self
+            .resolve_existing_mountpoint(path)
+            .wrap_with(|| format!("resolve readonly path {:?}", path))?
+        {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        match (expected, is_dir) {
+            (Some(MountDestinationType::Directory), false) => error::InvalidArgument {
+                name: "path",
+                description: format!("readonly path {:?} is not a directory", path),
+            }
+            .fail(),
+            (Some(MountDestinationType::File), true) => error::InvalidArgument {
+                name: "path",
+                description: format!("readonly path {:?} is a directory", path),
+            }
+            .fail(),
+            _ => Ok(Some(dest)),
+        }
+    }
+
+    /// Within the [`Root`]'s tree, resolve `path` and create a detached bind
+    /// mount of it with `open_tree(2)` (`OPEN_TREE_CLONE`). If `recursive` is
+    /// set, any mounts underneath `path` are included (as with `rbind`).
+    ///
+    /// The returned mount file descriptor can then be attached anywhere (such
+    /// as in a new mount namespace, or on a [`MountDestination`]) with
+    /// `move_mount(2)` and `MOVE_MOUNT_F_EMPTY_PATH`. Since the bind source is
+    /// a file descriptor, no paths inside the [`Root`] are ever re-resolved by
+    /// the kernel.
+    ///
+    /// # Errors
+    ///
+    /// `open_tree(2)` requires Linux 5.2 and `CAP_SYS_ADMIN` in the user
+    /// namespace owning the current mount namespace. If the kernel doesn't
+    /// support it, the error will have an `errno` of `ENOSYS`.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`MountDestination`]: struct.MountDestination.html
+    pub fn open_tree_export<P: AsRef<Path>>(
+        &self,
+        path: P,
+        recursive: bool,
+    ) -> Result<File, Error> {
+        let handle = self.resolve(path).wrap("resolve open_tree source")?;
+        let mut flags = syscalls::OPEN_TREE_CLONE | libc::AT_EMPTY_PATH as u32;
+        if recursive {
+            flags |= libc::AT_RECURSIVE as u32;
+        }
+        syscalls::open_tree(handle.inner.as_raw_fd(), "", flags).context(error::RawOsError {
+            operation: "clone mount tree of resolved path",
+        })
+    }
+
+    /// Resolve an existing mountpoint, returning `None` if it doesn't exist
+    /// and whether it is a directory otherwise.
+    fn resolve_existing_mountpoint(
+        &self,
+        path: &Path,
+    ) -> Result<Option<(MountDestination, bool)>, Error> {
+        let path = normalize_lexical(Path::new("/").join(path));
+        let handle = match self.resolve(&path) {
+            Ok(handle) => handle,
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let is_dir = handle
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat mountpoint",
+            })?
+            .is_dir();
+        let proc_path = handle.proc_fd_path()?.to_path_buf();
+        Ok(Some((MountDestination { handle, proc_path }, is_dir)))
+    }
+
+    /// Within the [`Root`]'s tree, prepare the mountpoint for bind-mounting a
+    /// pseudo-terminal onto the console at `path` (usually `/dev/console`).
+    ///
+    /// This is identical to [`Root::prepare_mount_destination`] with
+    /// [`MountDestinationType::File`]. Once the pseudo-terminal has been
+    /// mounted, use [`Root::open_console`] to verify and open it.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::prepare_mount_destination`]: struct.Root.html#method.prepare_mount_destination
+    /// [`Root::open_console`]: struct.Root.html#method.open_console
+    /// [`MountDestinationType::File`]: enum.MountDestinationType.html#variant.File
+    pub fn prepare_console_mountpoint<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<MountDestination, Error> {
+        self.prepare_mount_destination(path, MountDestinationType::File)
+    }
+
+    /// Within the [`Root`]'s tree, open the console or pseudo-terminal at
+    /// `path` with `flags`, after verifying that it is the `expected` kind of
+    /// character device.
+    ///
+    /// Console paths inside a container's root filesystem are a historical
+    /// attack vector -- a malicious image can replace `/dev/console` (or
+    /// `/dev/pts/*`) with a symlink, a regular file, or a device node for a
+    /// host device, in order to trick the runtime into opening something else.
+    /// The inode is checked through the resolved [`Handle`] before it is
+    /// re-opened, so the check cannot be raced. [`ConsoleDevice::PtySlave`]
+    /// and [`ConsoleDevice::Ptmx`] also require the inode to be on a `devpts`
+    /// filesystem, since a device node with the right number could be created
+    /// anywhere by the image.
+    ///
+    /// As with all re-opens, `O_NOCTTY` is always set. If you want the console
+    /// to become the controlling terminal, use `ioctl(TIOCSCTTY)`.
+    ///
+    /// # Errors
+    ///
+    /// If the inode is not the expected kind of character device, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`ConsoleDevice::PtySlave`]: enum.ConsoleDevice.html#variant.PtySlave
+    /// [`ConsoleDevice::Ptmx`]: enum.ConsoleDevice.html#variant.Ptmx
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn open_console<P: AsRef<Path>, F: Into<OpenFlags>>(
+        &self,
+        path: P,
+        expected: ConsoleDevice,
+        flags: F,
+    ) -> Result<File, Error> {
+        let path = path.as_ref();
+        let handle = self.resolve(path).wrap("resolve console path")?;
+        let meta = handle.inner.metadata().context(error::OsError {
+            operation: "fstat console",
+        })?;
+        ensure!(
+            meta.file_type().is_char_device() && expected.matches(meta.rdev()),
+            error::SafetyViolation {
+                description: format!(
+                    "{:?} is not the expected console device {:?} (mode 0o{:o}, rdev {}:{})",
+                    path,
+                    expected,
+                    meta.mode(),
+                    libc::major(meta.rdev()),
+                    libc::minor(meta.rdev())
+                ),
+            }
+        );
+        if expected.needs_devpts() {
+            let fs_type = syscalls::fstatfs(handle.inner.as_raw_fd())
+                .context(error::RawOsError {
+                    operation: "fstatfs console",
+                })?
+                .f_type;
+            ensure!(
+                fs_type == libc::DEVPTS_SUPER_MAGIC,
+                error::SafetyViolation {
+                    description: format!(
+                        "{:?} is not on devpts (filesystem 0x{:X})",
+                        path, fs_type
+                    ),
+                }
+            );
+        }
+        handle.reopen(flags).wrap("reopen console")
+    }
+    /// List the device inodes within the directory `path` (usually `/dev`) in
+    /// the [`Root`]'s tree, sorted by path, as the entries of the
+    /// `linux.devices` list of an OCI runtime spec. This is needed when
+    /// converting an extracted image into a runtime spec.
+    ///
+    /// The tree is traversed with [`Root::walk`], so symlinks are never
+    /// followed (and device inodes are never opened).
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    pub fn list_devices<P: AsRef<Path>>(&self, path: P) -> Result<Vec<OciDevice>, Error> {
+        let base = normalize_lexical(Path::new("/").join(path.as_ref()));
+        let mut devices = Vec::new();
+        for entry in self.walk(path)? {
+            let entry = entry?;
+            if let Some((device_type, major, minor)) = entry.device() {
+                let meta = entry.metadata();
+                devices.push(OciDevice {
+                    path: base.join(entry.path()),
+                    device_type,
+                    major,
+                    minor,
+                    file_mode: meta.mode() & 0o7777,
+                    uid: meta.uid(),
+                    gid: meta.gid(),
+                });
+            }
+        }
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(devices)
+    }
+    /// Within the [`Root`]'s tree, create the standard minimal `/dev` skeleton
+    /// in the directory `path` (usually `/dev`, which is created if it doesn't
+    /// exist), matching the default devices of OCI runtimes:
+    ///
+    /// * The character devices `null`, `zero`, `full`, `random`, `urandom`
+    ///   and `tty` (with mode `0666`).
+    /// * The symlinks `ptmx` (to `pts/ptmx`), `fd` (to `/proc/self/fd`) and
+    ///   `stdin`, `stdout` and `stderr` (to `/proc/self/fd/{0,1,2}`).
+    /// * The directories `pts` and `shm` (as mountpoints).
+    ///
+    /// Existing entries are left alone if they match what would have been
+    /// created, so this can be used on a partially populated `/dev`. The
+    /// devices are created with [`Root::create`], so the [`Root`]'s
+    /// `device_policy` must permit them.
+    ///
+    /// # Errors
+    ///
+    /// If an existing entry doesn't match what would have been created (such
+    /// as a regular file called `null`, or a device with the wrong number), an
+    /// [`Error::Conflict`] is returned. If the `device_policy` rejects one of
+    /// the devices, an [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::create`]: struct.Root.html#method.create
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn populate_devtmpfs_skeleton<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let base = normalize_lexical(Path::new("/").join(path.as_ref()));
+        let dir_perm = Permissions::from_mode(OCI_DIRECTORY_MODE);
+        self.ensure_dir(&base, &dir_perm)
+            .wrap("create /dev skeleton directory")?;
+
+        let perm = Permissions::from_mode(DEV_SKELETON_DEVICE_MODE);
+        for &(name, major, minor) in DEV_SKELETON_DEVICES {
+            let path = base.join(name);
+            let dev = libc::makedev(major, minor);
+            match self.create(&path, &InodeType::CharacterDevice(&perm, dev)) {
+                Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                    return Err(err).wrap("create /dev skeleton device")
+                }
+                _ => (),
+            }
+            let file = self.open_entry_nofollow(&path)?;
+            let meta = file.metadata().context(error::OsError {
+                operation: "fstat /dev skeleton device",
+            })?;
+            ensure!(
+                meta.file_type().is_char_device() && meta.rdev() == dev,
+                error::Conflict {
+                    path,
+                    description: format!("existing inode is not the device {}:{}", major, minor),
+                }
+            );
+            // mknod(2) applies the umask, so make sure the mode is right.
+            file.set_mode(self.sanitize_mode(DEV_SKELETON_DEVICE_MODE)?)
+                .wrap("set mode of /dev skeleton device")?;
+        }
+        for &(name, target) in DEV_SKELETON_SYMLINKS {
+            self.ensure_symlink(base.join(name), target)
+                .wrap("create /dev skeleton symlink")?;
+        }
+        for name in DEV_SKELETON_DIRECTORIES {
+            self.ensure_dir(base.join(name), &dir_perm)
+                .wrap("create /dev skeleton directory")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::Root;
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+/// Statistics about the work done by the operations run inside
+/// [`Root::with_op_stats`].
+///
+/// [`Root::with_op_stats`]: struct.Root.html#method.with_op_stats
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// Number of syscalls issued (including each `EINTR` retry).
+    pub syscalls: u64,
+    /// Number of those syscalls which failed.
+    pub failed_syscalls: u64,
+    /// Number of bytes of file contents transferred by copying and streaming
+    /// operations (such as [`Root::copy_file`] and [`Handle::sendfile_to`]).
+    ///
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    /// [`Handle::sendfile_to`]: struct.Handle.html#method.sendfile_to
+    pub bytes: u64,
+    /// Number of transient errors which were retried (see [`RetryStats`]).
+    ///
+    /// [`RetryStats`]: struct.RetryStats.html
+    pub retries: u64,
+    /// Wall-clock time taken by the operations.
+    pub duration: Duration,
+}
+
+impl OpStats {
+    /// Add the counters of `other` (but not its duration, since nested
+    /// operations are already included in the outer duration).
+    fn add_counters(&mut self, other: &OpStats) {
+        self.syscalls += other.syscalls;
+        self.failed_syscalls += other.failed_syscalls;
+        self.bytes += other.bytes;
+        self.retries += other.retries;
+    }
+}
+
+thread_local! {
+    /// The statistics being collected on the current thread, if any.
+    static CURRENT: Cell<Option<OpStats>> = const { Cell::new(None) };
+}
+
+/// Update the statistics being collected on the current thread (if any).
+fn update<F: FnOnce(&mut OpStats)>(f: F) {
+    CURRENT.with(|current| {
+        if let Some(mut stats) = current.get() {
+            f(&mut stats);
+            current.set(Some(stats));
+        }
+    })
+}
+
+pub(crate) fn record_syscall(failed: bool) {
+    update(|stats| {
+        stats.syscalls += 1;
+        if failed {
+            stats.failed_syscalls += 1;
+        }
+    })
+}
+
+pub(crate) fn record_bytes(bytes: u64) {
+    update(|stats| stats.bytes += bytes)
+}
+
+pub(crate) fn record_retry() {
+    update(|stats| stats.retries += 1)
+}
+
+/// Restores the statistics of the enclosing [`Root::with_op_stats`] (with the
+/// nested counters added) when dropped.
+///
+/// [`Root::with_op_stats`]: struct.Root.html#method.with_op_stats
+struct StatsGuard(Option<OpStats>);
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        let nested = CURRENT.with(Cell::get);
+        let mut previous = self.0.take();
+        if let (Some(previous), Some(nested)) = (previous.as_mut(), nested) {
+            previous.add_counters(&nested);
+        }
+        CURRENT.with(|current| current.set(previous));
+    }
+}
+
+impl Root {
+    /// Run `f`, returning its result along with [`OpStats`] about the work
+    /// done by libpathrs on the current thread while it ran. This is cheap
+    /// enough to use in production (unlike [`Root::with_syscall_trace`] with the
+    /// `syscall-trace` feature), for
+    /// capacity planning and finding out why an operation is slow.
+    ///
+    /// Statistics are collected per-thread, so operations on other [`Root`]s
+    /// within `f` are also counted, while operations which run on other
+    /// threads (such as the worker thread of [`Root::with_timeout`]) are not.
+    /// Calls can be nested, in which case the counters of the inner call are
+    /// also added to the outer call.
+    ///
+    /// [`OpStats`]: struct.OpStats.html
+    /// [`Root`]: struct.Root.html
+    /// [`Root::with_syscall_trace`]: struct.Root.html#method.with_syscall_trace
+    /// [`Root::with_timeout`]: struct.Root.html#method.with_timeout
+    pub fn with_op_stats<T, F>(&self, f: F) -> (T, OpStats)
+    where
+        F: FnOnce(&Root) -> T,
+    {
+        let previous = CURRENT.with(|current| current.replace(Some(OpStats::default())));
+        let _guard = StatsGuard(previous);
+        let start = Instant::now();
+        let ret = f(self);
+        let mut stats = CURRENT.with(Cell::get).unwrap_or_default();
+        stats.duration = start.elapsed();
+        (ret, stats)
+    }
+}
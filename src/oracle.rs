@@ -0,0 +1,133 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error},
+    syscalls,
+    utils::FileExt,
+    ResolverFlags, Root,
+};
+
+use std::{
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// The outcome of resolving a path, as compared by
+/// [`Root::compare_with_chroot`].
+///
+/// [`Root::compare_with_chroot`]: struct.Root.html#method.compare_with_chroot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResolveOutcome {
+    /// The path resolved to the inode with the given `(st_dev, st_ino)`.
+    Resolved { dev: u64, ino: u64 },
+    /// Resolution failed with the given `errno`.
+    Failed { errno: i32 },
+}
+
+/// The result of comparing a libpathrs resolution against a `chroot(2)`-based
+/// resolution, created with [`Root::compare_with_chroot`].
+///
+/// [`Root::compare_with_chroot`]: struct.Root.html#method.compare_with_chroot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OracleComparison {
+    /// The path which was resolved.
+    pub path: PathBuf,
+    /// The outcome of [`Root::resolve`].
+    ///
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    pub resolved: ResolveOutcome,
+    /// The outcome of `stat(2)` inside a `chroot(2)` of the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    pub chroot: ResolveOutcome,
+}
+
+impl OracleComparison {
+    /// Whether the two resolutions disagreed -- either only one of them
+    /// succeeded, they resolved to different inodes, or they failed with
+    /// different `errno`s.
+    ///
+    /// Note that libpathrs deliberately refuses some resolutions the kernel
+    /// would allow inside a `chroot(2)` (such as crossing magic-links, which
+    /// fails with `ELOOP` or `EXDEV`), so not every divergence is a bug.
+    pub fn diverged(&self) -> bool {
+        self.resolved != self.chroot
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve `path` both with [`Root::resolve`]
+    /// and with `stat(2)` inside a throwaway `chroot(2)` of the [`Root`] (an
+    /// "oracle" of how the kernel would scope the resolution), and compare the
+    /// outcomes.
+    ///
+    /// This is a diagnostic mode, intended for qualifying libpathrs on unusual
+    /// filesystems and for testing libpathrs itself. The `chroot(2)` is done in
+    /// a forked child process, which first unshares a user namespace (so this
+    /// works unprivileged on systems which allow unprivileged user namespaces).
+    ///
+    /// # Errors
+    ///
+    /// Failing to resolve `path` is not an error (it is part of the
+    /// comparison). If the [`Root`] has any [`ResolverFlags`] set (which cannot
+    /// be emulated with `chroot(2)`), an [`Error::NotSupported`] is returned.
+    /// If the `chroot(2)` could not be set up, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`ResolverFlags`]: struct.ResolverFlags.html
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    pub fn compare_with_chroot<P: AsRef<Path>>(&self, path: P) -> Result<OracleComparison, Error> {
+        let path = path.as_ref();
+        ensure!(
+            self.resolver.flags == ResolverFlags::empty(),
+            error::NotSupported {
+                feature: "chroot oracle with resolver flags",
+            }
+        );
+
+        let resolved = match self.resolve(path) {
+            Ok(handle) => {
+                let (dev, ino) = handle.inner.inode_id()?;
+                ResolveOutcome::Resolved { dev, ino }
+            }
+            Err(err) => ResolveOutcome::Failed {
+                errno: err.raw_os_error().unwrap_or(0),
+            },
+        };
+        let chroot = match syscalls::chroot_stat(self.inner.as_raw_fd(), path).context(
+            error::RawOsError {
+                operation: "resolve path inside chroot",
+            },
+        )? {
+            Ok((dev, ino)) => ResolveOutcome::Resolved { dev, ino },
+            Err(errno) => ResolveOutcome::Failed { errno },
+        };
+
+        Ok(OracleComparison {
+            path: path.to_path_buf(),
+            resolved,
+            chroot,
+        })
+    }
+}
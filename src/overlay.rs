@@ -0,0 +1,291 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    utils::FileExt,
+    Root,
+};
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Magic number of an overlayfs file handle (`OVL_FH_MAGIC`).
+const OVL_FH_MAGIC: u8 = 0xfb;
+
+/// Size of the header of an overlayfs file handle (`struct ovl_fb`, without
+/// the trailing file id).
+const OVL_FH_HEADER_SIZE: usize = 21;
+
+/// Size of the header of an overlayfs metacopy value (`struct ovl_metacopy`,
+/// without the trailing digest).
+const OVL_METACOPY_HEADER_SIZE: usize = 4;
+
+/// The xattr namespace of overlayfs metadata.
+///
+/// By default overlayfs uses `trusted.overlay.*` xattrs (which require
+/// `CAP_SYS_ADMIN`), but it can be configured with the `userxattr` mount
+/// option to use `user.overlay.*` xattrs instead (which is needed for
+/// overlayfs mounts inside user namespaces).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OverlayNamespace {
+    /// `trusted.overlay.*` xattrs.
+    #[default]
+    Trusted,
+    /// `user.overlay.*` xattrs (the `userxattr` mount option).
+    User,
+}
+
+impl OverlayNamespace {
+    /// The full xattr name of the overlayfs attribute `name`.
+    fn xattr(self, name: &str) -> OsString {
+        let prefix = match self {
+            Self::Trusted => "trusted.overlay.",
+            Self::User => "user.overlay.",
+        };
+        OsString::from(format!("{}{}", prefix, name))
+    }
+}
+
+/// The overlayfs metadata of an entry in a layer, as returned by
+/// [`Root::overlay_attrs`].
+///
+/// [`Root::overlay_attrs`]: struct.Root.html#method.overlay_attrs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OverlayAttrs {
+    /// Whether the directory is opaque (`overlay.opaque`), hiding the contents
+    /// of the directories with the same path in lower layers.
+    pub opaque: bool,
+    /// The path of the directory in the lower layers which this directory was
+    /// renamed from (`overlay.redirect`). Absolute paths are relative to the
+    /// root of the lower layers, while relative paths are a name in the same
+    /// parent directory.
+    pub redirect: Option<PathBuf>,
+    /// The encoded file handle of the lower inode this entry was copied up
+    /// from (`overlay.origin`).
+    pub origin: Option<Vec<u8>>,
+    /// The metacopy value of the file (`overlay.metacopy`), meaning that only
+    /// the metadata of the file was copied up and the data is still in a lower
+    /// layer. An empty value is a plain metacopy flag, while newer kernels
+    /// store a versioned header (and optionally an fs-verity digest).
+    pub metacopy: Option<Vec<u8>>,
+}
+
+/// Get an xattr, returning `None` if it doesn't exist.
+fn get_optional_xattr(file: &File, name: &OsStr) -> Result<Option<Vec<u8>>, Error> {
+    match file.get_xattr(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Set an xattr to `value`, or remove it (if it exists) if `value` is `None`.
+fn set_optional_xattr(file: &File, name: &OsStr, value: Option<&[u8]>) -> Result<(), Error> {
+    match value {
+        Some(value) => file.set_xattr(name, value),
+        None => match file.remove_xattr(name) {
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+            ret => ret,
+        },
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, get the overlayfs metadata of the entry at
+    /// `path` in the given xattr `namespace`. The [`Root`] should be the upper
+    /// (or a lower) directory of an overlayfs mount, rather than the mount
+    /// itself. If the final component of `path` is a symlink, it is not
+    /// followed.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn overlay_attrs<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: OverlayNamespace,
+    ) -> Result<OverlayAttrs, Error> {
+        let file = self
+            .open_entry_nofollow(path.as_ref())
+            .wrap("open overlay entry")?;
+        let opaque = get_optional_xattr(&file, &namespace.xattr("opaque"))?;
+        let redirect = get_optional_xattr(&file, &namespace.xattr("redirect"))?;
+        Ok(OverlayAttrs {
+            opaque: opaque.as_deref() == Some(b"y"),
+            redirect: redirect.map(|value| PathBuf::from(OsStr::from_bytes(&value))),
+            origin: get_optional_xattr(&file, &namespace.xattr("origin"))?,
+            metacopy: get_optional_xattr(&file, &namespace.xattr("metacopy"))?,
+        })
+    }
+
+    /// Within the [`Root`]'s tree, mark the directory at `path` as opaque (or
+    /// not) for overlayfs.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not a directory, an [`Error::InvalidArgument`] is
+    /// returned (overlayfs ignores opaque markers on other inodes).
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_overlay_opaque<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: OverlayNamespace,
+        opaque: bool,
+    ) -> Result<(), Error> {
+        let file = self.open_overlay_dir(path.as_ref())?;
+        let value = if opaque { Some(&b"y"[..]) } else { None };
+        set_optional_xattr(&file, &namespace.xattr("opaque"), value)
+    }
+
+    /// Within the [`Root`]'s tree, set (or clear, if `redirect` is `None`) the
+    /// overlayfs redirect of the directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not a directory, or `redirect` is neither an absolute
+    /// path nor a single name, an [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_overlay_redirect<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: OverlayNamespace,
+        redirect: Option<&Path>,
+    ) -> Result<(), Error> {
+        if let Some(redirect) = redirect {
+            let bytes = redirect.as_os_str().as_bytes();
+            ensure!(
+                !bytes.is_empty()
+                    && !bytes.contains(&b'\0')
+                    && (redirect.is_absolute() || !bytes.contains(&b'/')),
+                error::InvalidArgument {
+                    name: "redirect",
+                    description: "redirect must be an absolute path or a single name",
+                }
+            );
+        }
+        let file = self.open_overlay_dir(path.as_ref())?;
+        set_optional_xattr(
+            &file,
+            &namespace.xattr("redirect"),
+            redirect.map(|redirect| redirect.as_os_str().as_bytes()),
+        )
+    }
+
+    /// Within the [`Root`]'s tree, set (or clear, if `origin` is `None`) the
+    /// overlayfs origin file handle of the entry at `path`. If the final
+    /// component of `path` is a symlink, it is not followed.
+    ///
+    /// # Errors
+    ///
+    /// If `origin` is not a well-formed overlayfs file handle (a `struct
+    /// ovl_fb`), an [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_overlay_origin<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: OverlayNamespace,
+        origin: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        if let Some(origin) = origin {
+            ensure!(
+                origin.len() >= OVL_FH_HEADER_SIZE
+                    && origin[1] == OVL_FH_MAGIC
+                    && origin[2] as usize == origin.len(),
+                error::InvalidArgument {
+                    name: "origin",
+                    description: "origin is not an overlayfs file handle",
+                }
+            );
+        }
+        let file = self
+            .open_entry_nofollow(path.as_ref())
+            .wrap("open overlay entry")?;
+        set_optional_xattr(&file, &namespace.xattr("origin"), origin)
+    }
+
+    /// Within the [`Root`]'s tree, set (or clear, if `metacopy` is `None`) the
+    /// overlayfs metacopy value of the regular file at `path`. Use an empty
+    /// value for a plain metacopy flag.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not a regular file, or a non-empty `metacopy` is not a
+    /// well-formed `struct ovl_metacopy`, an [`Error::InvalidArgument`] is
+    /// returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_overlay_metacopy<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: OverlayNamespace,
+        metacopy: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        if let Some(metacopy) = metacopy {
+            ensure!(
+                metacopy.is_empty()
+                    || (metacopy.len() >= OVL_METACOPY_HEADER_SIZE
+                        && metacopy[1] as usize == metacopy.len()),
+                error::InvalidArgument {
+                    name: "metacopy",
+                    description: "metacopy is not an overlayfs metacopy value",
+                }
+            );
+        }
+        let file = self
+            .open_entry_nofollow(path.as_ref())
+            .wrap("open overlay entry")?;
+        let is_file = file.metadata().map(|meta| meta.is_file()).unwrap_or(false);
+        ensure!(
+            is_file,
+            error::InvalidArgument {
+                name: "path",
+                description: "metacopy can only be set on regular files",
+            }
+        );
+        set_optional_xattr(&file, &namespace.xattr("metacopy"), metacopy)
+    }
+
+    /// Open the directory at `path` (without following a trailing symlink) to
+    /// change its overlayfs metadata.
+    fn open_overlay_dir(&self, path: &Path) -> Result<File, Error> {
+        let file = self
+            .open_entry_nofollow(path)
+            .wrap("open overlay directory")?;
+        let is_dir = file.metadata().map(|meta| meta.is_dir()).unwrap_or(false);
+        ensure!(
+            is_dir,
+            error::InvalidArgument {
+                name: "path",
+                description: "overlay directory metadata can only be set on directories",
+            }
+        );
+        Ok(file)
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Purely lexical path helpers.
+//!
+//! These helpers never touch the filesystem, and are provided for callers who
+//! need to manipulate path strings (such as those migrating from
+//! [`filepath-securejoin`]).
+//!
+//! # Safety
+//!
+//! Lexical path handling is **much weaker** than [`Root::resolve`]. Because
+//! symlinks are not taken into account, the paths produced by these helpers
+//! can still escape the intended root if any component is (or is later
+//! swapped with) a symlink. If you are going to operate on the resulting path,
+//! you should use [`Root`] methods instead.
+//!
+//! [`filepath-securejoin`]: https://github.com/cyphar/filepath-securejoin
+//! [`Root`]: ../struct.Root.html
+//! [`Root::resolve`]: ../struct.Root.html#method.resolve
+
+use std::path::{Component, Path, PathBuf};
+
+/// Lexically normalise `path`.
+///
+/// This is equivalent to Go's [`filepath.Clean`]: `.` components and
+/// duplicate separators are removed, and `..` components are applied to the
+/// preceding component. `..` components at the start of an absolute path are
+/// dropped (since `/..` is `/`), while leading `..` components of a relative
+/// path are kept. An empty result is returned as `.`.
+///
+/// Note that this is **not** equivalent to the path the kernel would resolve,
+/// since `a/symlink/..` is not necessarily `a` (see the [module
+/// documentation](index.html)).
+///
+/// [`filepath.Clean`]: https://golang.org/pkg/path/filepath/#Clean
+pub fn normalize_lexical<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+
+    let mut normalized = PathBuf::new();
+    // Number of components in `normalized` which can be removed by "..".
+    let mut depth = 0;
+    for part in path.components() {
+        match part {
+            Component::Prefix(_) => unreachable!("path prefixes don't exist on Linux"),
+            Component::RootDir => normalized.push(part.as_os_str()),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if depth > 0 {
+                    normalized.pop();
+                    depth -= 1;
+                } else if !path.is_absolute() {
+                    normalized.push(part.as_os_str());
+                }
+            }
+            Component::Normal(part) => {
+                normalized.push(part);
+                depth += 1;
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        normalized.push(Component::CurDir.as_os_str());
+    }
+    normalized
+}
+
+/// Lexically join `unsafe_path` to `root`, such that the result is always
+/// lexically inside `root`.
+///
+/// This is done by treating `unsafe_path` as though it were an absolute path
+/// relative to `root`, and normalising it with [`normalize_lexical`] before
+/// joining it to `root`. This means that all `..` components which would
+/// lexically escape `root` are clamped to `root`.
+///
+/// Unlike [`filepath-securejoin`]'s `SecureJoin`, symlinks are **not**
+/// evaluated. This makes `securejoin` completely unsafe to use with paths
+/// containing attacker-controlled symlinks (see the [module
+/// documentation](index.html)).
+///
+/// [`normalize_lexical`]: fn.normalize_lexical.html
+/// [`filepath-securejoin`]: https://github.com/cyphar/filepath-securejoin
+pub fn securejoin<R: AsRef<Path>, P: AsRef<Path>>(root: R, unsafe_path: P) -> PathBuf {
+    let unsafe_path = normalize_lexical(Path::new("/").join(unsafe_path));
+    let subpath = unsafe_path
+        .strip_prefix("/")
+        .expect("normalised absolute path should start with /");
+    match subpath.as_os_str().is_empty() {
+        // Avoid adding a trailing "/" to root.
+        true => root.as_ref().to_path_buf(),
+        false => root.as_ref().join(subpath),
+    }
+}
@@ -0,0 +1,318 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    stat, syscalls,
+    utils::{self, FileExt},
+    Handle, Lease, LeaseType, Root,
+};
+
+use std::{
+    cell::Cell,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// Changes to the parent directory which (may) mean the pinned entry was
+/// replaced.
+const PARENT_WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR;
+
+/// Events which concern the parent directory itself (or the watch as a whole)
+/// rather than a named entry in it.
+const PARENT_SELF_EVENTS: u32 =
+    libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_IGNORED | libc::IN_Q_OVERFLOW;
+
+/// Size of the fixed part of `struct inotify_event`.
+const INOTIFY_EVENT_SIZE: usize = mem::size_of::<libc::inotify_event>();
+
+/// Options for [`Root::pin_with`].
+///
+/// [`Root::pin_with`]: struct.Root.html#method.pin_with
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PinOptions {
+    /// Take a [`Lease`] of this type on the pinned file for the lifetime of
+    /// the [`PinnedHandle`] (see [`Handle::lease`]). Only regular files can be
+    /// leased.
+    ///
+    /// [`Lease`]: struct.Lease.html
+    /// [`PinnedHandle`]: struct.PinnedHandle.html
+    /// [`Handle::lease`]: struct.Handle.html#method.lease
+    pub lease: Option<LeaseType>,
+
+    /// Watch the parent directory with inotify, so that the entry being
+    /// replaced (and then possibly swapped back) during the pin window is
+    /// detected by [`PinnedHandle::was_replaced`].
+    ///
+    /// [`PinnedHandle::was_replaced`]: struct.PinnedHandle.html#method.was_replaced
+    pub watch_parent: bool,
+}
+
+/// An RAII guard which keeps a resolved [`Handle`] (and optionally a
+/// [`Lease`] on it, or an inotify watch on its parent directory) alive while
+/// the caller performs an external operation on it, created with
+/// [`Root::pin`] or [`Root::pin_with`].
+///
+/// After the operation, [`PinnedHandle::was_replaced`] reports whether the
+/// path stopped referring to the pinned inode during the pin window. The fd,
+/// lease and watch are released when the guard is dropped.
+///
+/// [`Handle`]: struct.Handle.html
+/// [`Lease`]: struct.Lease.html
+/// [`Root::pin`]: struct.Root.html#method.pin
+/// [`Root::pin_with`]: struct.Root.html#method.pin_with
+/// [`PinnedHandle::was_replaced`]: struct.PinnedHandle.html#method.was_replaced
+#[derive(Debug)]
+pub struct PinnedHandle<'a> {
+    root: &'a Root,
+    path: PathBuf,
+    name: OsString,
+    handle: Handle,
+    inode_id: (u64, u64),
+    mnt_id: Option<u64>,
+    lease: Option<Lease>,
+    watch: Option<File>,
+    // Set once the watch has seen the entry change, since reading the events
+    // consumes them.
+    changed: Cell<bool>,
+}
+
+/// Does the buffer of inotify events contain one that affects `name`?
+fn events_affect(mut events: &[u8], name: &OsStr) -> bool {
+    while events.len() >= INOTIFY_EVENT_SIZE {
+        let field = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&events[offset..offset + 4]);
+            u32::from_ne_bytes(bytes)
+        };
+        let (mask, len) = (field(4), field(12) as usize);
+        let end = (INOTIFY_EVENT_SIZE + len).min(events.len());
+        // The name is padded with nul bytes to an aligned length.
+        let event_name = events[INOTIFY_EVENT_SIZE..end]
+            .split(|&b| b == 0)
+            .next()
+            .unwrap_or_default();
+        if mask & PARENT_SELF_EVENTS != 0 || event_name == name.as_bytes() {
+            return true;
+        }
+        events = &events[end..];
+    }
+    false
+}
+
+impl PinnedHandle<'_> {
+    /// The pinned [`Handle`].
+    ///
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The [`Lease`] requested with [`PinOptions::lease`], if any. If the
+    /// lease has been broken, [`Lease::current`] no longer returns the
+    /// requested type.
+    ///
+    /// [`Lease`]: struct.Lease.html
+    /// [`PinOptions::lease`]: struct.PinOptions.html#structfield.lease
+    /// [`Lease::current`]: struct.Lease.html#method.current
+    #[inline]
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// The `/proc/self/fd/...` path of the pinned [`Handle`], for external
+    /// operations which only take paths. The path is only valid while the
+    /// [`PinnedHandle`] is alive.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`PinnedHandle`]: struct.PinnedHandle.html
+    pub fn procfd_path(&self) -> Result<PathBuf, Error> {
+        utils::procfd_path(self.handle.inner.as_raw_fd())
+    }
+
+    /// Drain the parent watch (if any), recording whether the entry changed.
+    fn drain_watch(&self) -> Result<(), Error> {
+        let mut watch = match &self.watch {
+            Some(watch) => watch,
+            None => return Ok(()),
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match watch.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    if events_affect(&buf[..n], &self.name) {
+                        self.changed.set(true);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => {
+                    return Err(err).context(error::OsError {
+                        operation: "read parent watch events",
+                    })
+                }
+            }
+        }
+    }
+
+    /// Check whether the path stopped referring to the pinned inode (on the
+    /// same mount) since it was pinned.
+    ///
+    /// Without [`PinOptions::watch_parent`], this only compares what the path
+    /// resolves to now, so an entry which was replaced and then swapped back
+    /// is not detected. With it, any change to the entry (or to the parent
+    /// directory itself) during the pin window is reported -- even if it was
+    /// harmless.
+    ///
+    /// # Errors
+    ///
+    /// If the path no longer exists, `true` is returned rather than an error.
+    /// Otherwise, the errors are identical to [`Root::pin_with`].
+    ///
+    /// [`PinOptions::watch_parent`]: struct.PinOptions.html#structfield.watch_parent
+    /// [`Root::pin_with`]: struct.Root.html#method.pin_with
+    pub fn was_replaced(&self) -> Result<bool, Error> {
+        self.drain_watch()?;
+        if self.changed.get() {
+            return Ok(true);
+        }
+        let current = match self.root.open_entry_nofollow(&self.path) {
+            Ok(current) => current,
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => return Ok(true),
+            Err(err) => return Err(err).wrap("re-resolve pinned path"),
+        };
+        Ok(current.inode_id()? != self.inode_id
+            || stat::mount_id(current.as_raw_fd())? != self.mnt_id)
+    }
+
+    /// Release the lease and watch (if any) and get the pinned [`Handle`].
+    ///
+    /// [`Handle`]: struct.Handle.html
+    pub fn into_handle(self) -> Handle {
+        self.handle
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, resolve `path` (without following the
+    /// final component) and pin the resulting [`Handle`] with the default
+    /// [`PinOptions`]. See [`PinnedHandle`] for more details.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::pin_with`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`PinOptions`]: struct.PinOptions.html
+    /// [`PinnedHandle`]: struct.PinnedHandle.html
+    /// [`Root::pin_with`]: struct.Root.html#method.pin_with
+    #[inline]
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<PinnedHandle<'_>, Error> {
+        self.pin_with(path, &PinOptions::default())
+    }
+
+    /// Identical to [`Root::pin`], except that the given [`PinOptions`] are
+    /// used. The parent watch is set up before the entry is opened, so that
+    /// no change between resolution and pinning is missed.
+    ///
+    /// # Errors
+    ///
+    /// If `path` has no final component (such as `"/"`), an
+    /// [`Error::InvalidArgument`] is returned. If a lease was requested, the
+    /// errors of [`Handle::lease`] also apply. Otherwise, the errors are
+    /// identical to [`Root::resolve`].
+    ///
+    /// [`Root::pin`]: struct.Root.html#method.pin
+    /// [`PinOptions`]: struct.PinOptions.html
+    /// [`Handle::lease`]: struct.Handle.html#method.lease
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn pin_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: &PinOptions,
+    ) -> Result<PinnedHandle<'_>, Error> {
+        let path = path.as_ref();
+        let (parent, name) = path_split(path).wrap("split pinned path into (parent, name)")?;
+        let dir = self
+            .resolve(parent)
+            .wrap("resolve parent directory of pinned path")?;
+
+        let watch = if options.watch_parent {
+            let watch = syscalls::inotify_init1().context(error::RawOsError {
+                operation: "create parent watch",
+            })?;
+            syscalls::inotify_add_watch(
+                watch.as_raw_fd(),
+                utils::procfd_path(dir.inner.as_raw_fd())?,
+                PARENT_WATCH_MASK,
+            )
+            .context(error::RawOsError {
+                operation: "watch parent directory of pinned path",
+            })?;
+            Some(watch)
+        } else {
+            None
+        };
+
+        let file = syscalls::openat(
+            dir.inner.as_raw_fd(),
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW,
+            0,
+        )
+        .context(error::RawOsError {
+            operation: "open pinned entry",
+        })?;
+        let handle = Handle::from_file_unchecked(file);
+        let lease = options
+            .lease
+            .map(|lease_type| handle.lease(lease_type))
+            .transpose()
+            .wrap("lease pinned file")?;
+
+        Ok(PinnedHandle {
+            root: self,
+            path: path.to_path_buf(),
+            name: name.as_os_str().to_os_string(),
+            inode_id: handle.inner.inode_id()?,
+            mnt_id: stat::mount_id(handle.inner.as_raw_fd())?,
+            handle,
+            lease,
+            watch,
+            changed: Cell::new(false),
+        })
+    }
+}
@@ -0,0 +1,387 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::FileType,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
+};
+
+/// A validator for the names of inodes created within a [`Root`].
+///
+/// Validators are applied to the trailing component of the path of any new
+/// inode created through a [`Root`] -- namely with [`Root::create`],
+/// [`Root::create_file`] and (for the destination path) [`Root::rename`].
+/// Existing path components are not validated.
+///
+/// [`FilenamePolicy`] provides a set of common rules, but you can implement
+/// this trait yourself if you need something more specific.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::create`]: struct.Root.html#method.create
+/// [`Root::create_file`]: struct.Root.html#method.create_file
+/// [`Root::rename`]: struct.Root.html#method.rename
+/// [`FilenamePolicy`]: struct.FilenamePolicy.html
+pub trait FilenameValidator: fmt::Debug + Send + Sync {
+    /// Check whether `name` is an acceptable name for a new inode. If it is
+    /// not, a description of why the name was rejected is returned.
+    fn validate(&self, name: &OsStr) -> Result<(), String>;
+}
+
+bitflags! {
+    /// Rules used by a [`FilenamePolicy`] to reject filenames.
+    ///
+    /// [`FilenamePolicy`]: struct.FilenamePolicy.html
+    #[derive(Default)]
+    pub struct FilenameRules: u32 {
+        /// Reject names containing ASCII control characters (`0x01`-`0x1F`
+        /// and `0x7F`), such as newlines and terminal escape sequences.
+        const NO_CONTROL_CHARS = 0x01;
+
+        /// Reject names which are exactly `.` or `..`. Such names should never
+        /// reach a validator, but this allows you to be explicit about it.
+        const NO_DOT_ENTRIES = 0x02;
+
+        /// Reject names which are problematic on Windows -- reserved device
+        /// names (`CON`, `PRN`, `AUX`, `NUL`, `COM1`-`COM9` and `LPT1`-`LPT9`,
+        /// with or without an extension), reserved characters (`<>:"\|?*`)
+        /// and names ending with `.` or a space.
+        const NO_WINDOWS_RESERVED = 0x04;
+    }
+}
+
+/// A [`FilenameValidator`] which implements the most commonly needed rules.
+///
+/// The default policy accepts all names.
+///
+/// [`FilenameValidator`]: trait.FilenameValidator.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FilenamePolicy {
+    /// Set of rules to apply.
+    pub rules: FilenameRules,
+    /// Maximum length (in bytes) of names. Note that most Linux filesystems
+    /// already limit names to `NAME_MAX` (255) bytes.
+    pub max_length: Option<usize>,
+}
+
+/// Reserved device names on Windows. This check is case-insensitive and
+/// applies to the name before the first `.`.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reserved characters on Windows (in addition to '/' and control characters).
+const WINDOWS_RESERVED_CHARS: &[u8] = b"<>:\"\\|?*";
+
+impl FilenameValidator for FilenamePolicy {
+    fn validate(&self, name: &OsStr) -> Result<(), String> {
+        let bytes = name.as_bytes();
+
+        if let Some(max_length) = self.max_length {
+            if bytes.len() > max_length {
+                return Err(format!("name is longer than {} bytes", max_length));
+            }
+        }
+
+        if self.rules.contains(FilenameRules::NO_CONTROL_CHARS)
+            && bytes.iter().any(|&c| c < 0x20 || c == 0x7f)
+        {
+            return Err("name contains control characters".into());
+        }
+
+        if self.rules.contains(FilenameRules::NO_DOT_ENTRIES) && (bytes == b"." || bytes == b"..") {
+            return Err("name is a dot entry".into());
+        }
+
+        if self.rules.contains(FilenameRules::NO_WINDOWS_RESERVED) {
+            if bytes.iter().any(|c| WINDOWS_RESERVED_CHARS.contains(c)) {
+                return Err("name contains characters reserved on Windows".into());
+            }
+            if bytes.ends_with(b".") || bytes.ends_with(b" ") {
+                return Err("name ends with '.' or ' '".into());
+            }
+            let stem = bytes.split(|&c| c == b'.').next().unwrap_or(bytes);
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| stem.eq_ignore_ascii_case(reserved.as_bytes()))
+            {
+                return Err("name is a reserved device name on Windows".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The type of a device inode, as used by [`DeviceRule`].
+///
+/// [`DeviceRule`]: struct.DeviceRule.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum DeviceType {
+    /// Character device (`S_IFCHR`).
+    Character,
+    /// Block device (`S_IFBLK`).
+    Block,
+}
+
+impl DeviceType {
+    /// The type of device inode `file_type` is, or `None` if it is not a
+    /// device inode.
+    pub fn of(file_type: FileType) -> Option<Self> {
+        if file_type.is_char_device() {
+            Some(Self::Character)
+        } else if file_type.is_block_device() {
+            Some(Self::Block)
+        } else {
+            None
+        }
+    }
+}
+
+/// A device which is permitted by [`DevicePolicy::AllowList`].
+///
+/// [`DevicePolicy::AllowList`]: enum.DevicePolicy.html#variant.AllowList
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct DeviceRule {
+    /// The type of device.
+    pub device_type: DeviceType,
+    /// The major number of the device. `None` matches any major number.
+    pub major: Option<u32>,
+    /// The minor number of the device. `None` matches any minor number.
+    pub minor: Option<u32>,
+}
+
+impl DeviceRule {
+    /// The overlayfs whiteout device (a character device with the device
+    /// number `0:0`).
+    pub const WHITEOUT: DeviceRule = DeviceRule {
+        device_type: DeviceType::Character,
+        major: Some(0),
+        minor: Some(0),
+    };
+
+    fn matches(&self, device_type: DeviceType, major: u32, minor: u32) -> bool {
+        self.device_type == device_type
+            && self.major.unwrap_or(major) == major
+            && self.minor.unwrap_or(minor) == minor
+    }
+}
+
+/// Policy controlling which device inodes can be created within a [`Root`]
+/// with [`Root::create`].
+///
+/// Creating device inodes is a common way of escalating privileges when
+/// extracting untrusted archives (an attacker only needs to convince you to
+/// create a device inode for `/dev/sda` in a directory they can access). The
+/// default policy allows all devices, to match the behaviour of `mknod(2)`.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::create`]: struct.Root.html#method.create
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum DevicePolicy {
+    /// Allow all device inodes to be created.
+    #[default]
+    AllowAll,
+
+    /// Do not allow any device inodes to be created.
+    DenyAll,
+
+    /// Only allow overlayfs whiteouts ([`DeviceRule::WHITEOUT`]) to be
+    /// created.
+    ///
+    /// [`DeviceRule::WHITEOUT`]: struct.DeviceRule.html#associatedconstant.WHITEOUT
+    WhiteoutOnly,
+
+    /// Only allow device inodes which match one of the given rules.
+    AllowList(Vec<DeviceRule>),
+}
+
+impl DevicePolicy {
+    /// Check whether a device inode of the given type and number is permitted.
+    pub fn allows(&self, device_type: DeviceType, major: u32, minor: u32) -> bool {
+        match self {
+            Self::AllowAll => true,
+            Self::DenyAll => false,
+            Self::WhiteoutOnly => DeviceRule::WHITEOUT.matches(device_type, major, minor),
+            Self::AllowList(rules) => rules
+                .iter()
+                .any(|rule| rule.matches(device_type, major, minor)),
+        }
+    }
+}
+
+bitflags! {
+    /// Policy controlling which inode types underneath a [`Root`] may be
+    /// upgraded from a [`Handle`] to a usable file descriptor with
+    /// [`Root::reopen`] (and [`Root::open_file`]).
+    ///
+    /// This is intended for services which open paths on behalf of untrusted
+    /// clients, so they cannot be tricked into opening device inodes (such as
+    /// `/dev/kmsg`) or FIFOs. The type of the inode is checked before it is
+    /// re-opened, and the re-opened file is verified (with `fstat(2)`) to be
+    /// the same inode afterwards.
+    ///
+    /// Regardless of the policy, FIFOs and device inodes are always opened with
+    /// `O_NONBLOCK` and every inode is opened with `O_NOCTTY` (see
+    /// [`Handle::reopen`]), so re-opening a terminal can never make it the
+    /// controlling terminal of the process. The default policy allows every
+    /// inode type.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::reopen`]: struct.Root.html#method.reopen
+    /// [`Root::open_file`]: struct.Root.html#method.open_file
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    pub struct ReopenPolicy: u32 {
+        /// Allow regular files to be re-opened.
+        const REGULAR = 0x01;
+
+        /// Allow directories to be re-opened.
+        const DIRECTORY = 0x02;
+
+        /// Allow named pipes (aka FIFOs) to be re-opened.
+        const FIFO = 0x04;
+
+        /// Allow character devices to be re-opened.
+        const CHARACTER_DEVICE = 0x08;
+
+        /// Allow block devices to be re-opened.
+        const BLOCK_DEVICE = 0x10;
+
+        /// Allow terminals to be re-opened. Without this, character devices
+        /// which turn out to be terminals (according to `isatty(3)`) are
+        /// rejected after they have been opened, and the file is closed
+        /// again. Only has an effect together with `CHARACTER_DEVICE`.
+        const TERMINAL = 0x20;
+    }
+}
+
+impl Default for ReopenPolicy {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl ReopenPolicy {
+    /// Check whether an inode with the given `st_mode` can be re-opened.
+    /// Symlinks and sockets can never be re-opened.
+    pub fn allows(self, mode: u32) -> bool {
+        let required = match mode & libc::S_IFMT {
+            libc::S_IFREG => Self::REGULAR,
+            libc::S_IFDIR => Self::DIRECTORY,
+            libc::S_IFIFO => Self::FIFO,
+            libc::S_IFCHR => Self::CHARACTER_DEVICE,
+            libc::S_IFBLK => Self::BLOCK_DEVICE,
+            _ => return false,
+        };
+        self.contains(required)
+    }
+}
+
+bitflags! {
+    /// Potentially dangerous mode bits, as used by [`ModePolicy`].
+    ///
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    #[derive(Default)]
+    pub struct ModeBits: u32 {
+        /// The set-user-ID bit (`S_ISUID`).
+        const SETUID = 0o4000;
+
+        /// The set-group-ID bit (`S_ISGID`).
+        const SETGID = 0o2000;
+
+        /// The sticky bit (`S_ISVTX`).
+        const STICKY = 0o1000;
+
+        /// The world-writable bit (`S_IWOTH`).
+        const WORLD_WRITABLE = 0o0002;
+    }
+}
+
+/// Policy controlling which mode bits can be set on inodes within a [`Root`].
+///
+/// The policy is applied to the mode of all new inodes created with
+/// [`Root::create`] and [`Root::create_file`] (symlinks and hardlinks have no
+/// mode of their own), as well as any mode changes made by a [`Root`] (such as
+/// with [`Root::set_permissions`] or [`Root::restore_metadata`]). Bits in
+/// `reject` cause the operation to fail, while bits in `strip` are silently
+/// cleared. If a bit is in both, it is rejected.
+///
+/// Mode changes made directly through a [`Handle`] (with
+/// [`Handle::set_permissions`]) are not subject to the policy.
+///
+/// The default policy allows all mode bits.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::create`]: struct.Root.html#method.create
+/// [`Root::create_file`]: struct.Root.html#method.create_file
+/// [`Root::set_permissions`]: struct.Root.html#method.set_permissions
+/// [`Root::restore_metadata`]: struct.Root.html#method.restore_metadata
+/// [`Handle`]: struct.Handle.html
+/// [`Handle::set_permissions`]: struct.Handle.html#method.set_permissions
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ModePolicy {
+    /// Mode bits which are cleared.
+    pub strip: ModeBits,
+    /// Mode bits which cause the operation to be rejected.
+    pub reject: ModeBits,
+}
+
+impl ModePolicy {
+    /// Apply the policy to the permission bits in `mode`, returning the
+    /// sanitised mode or a description of why the mode was rejected.
+    pub fn apply(&self, mode: u32) -> Result<u32, String> {
+        let bits = ModeBits::from_bits_truncate(mode);
+        let rejected = bits & self.reject;
+        if !rejected.is_empty() {
+            return Err(format!("mode contains forbidden bits {:?}", rejected));
+        }
+        Ok(mode & !self.strip.bits())
+    }
+}
+
+/// Policy controlling whether the high-level helpers of a [`Root`] (such as
+/// [`Root::copy_file`]) silently fall back to a slower or less atomic strategy
+/// when the preferred one is not supported, or fail instead.
+///
+/// Regardless of the policy, the strategies which were used are reported in
+/// the result of the operation (see [`CopyOutcome`]). The default policy
+/// degrades, which is usually what platform tools want -- security-sensitive
+/// users who need to know that (for instance) a file never appeared under a
+/// temporary name should use [`FallbackPolicy::Loud`].
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::copy_file`]: struct.Root.html#method.copy_file
+/// [`CopyOutcome`]: struct.CopyOutcome.html
+/// [`FallbackPolicy::Loud`]: enum.FallbackPolicy.html#variant.Loud
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FallbackPolicy {
+    /// Fall back to the next strategy if the preferred one is not supported.
+    #[default]
+    Degrade,
+
+    /// Fail with [`Error::NotSupported`] if the preferred strategy is not
+    /// supported.
+    ///
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    Loud,
+}
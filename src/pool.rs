@@ -0,0 +1,240 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{Error, ErrorExt},
+    utils::FileExt,
+    DevicePolicy, FallbackPolicy, FilenameValidator, GracePolicy, ModePolicy, ReopenPolicy,
+    ResolutionBudget, Resolver, Root, Throttle,
+};
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The configuration of a [`Root`], which can be shared between many
+/// [`Root`]s (see [`RootPool`]).
+///
+/// The fields are identical to the corresponding [`Root`] fields.
+///
+/// [`Root`]: struct.Root.html
+/// [`RootPool`]: struct.RootPool.html
+#[derive(Clone, Debug, Default)]
+pub struct RootConfig {
+    /// See [`Root::resolver`](struct.Root.html#structfield.resolver).
+    pub resolver: Resolver,
+    /// See [`Root::filename_validator`](struct.Root.html#structfield.filename_validator).
+    pub filename_validator: Option<Arc<dyn FilenameValidator>>,
+    /// See [`Root::device_policy`](struct.Root.html#structfield.device_policy).
+    pub device_policy: DevicePolicy,
+    /// See [`Root::mode_policy`](struct.Root.html#structfield.mode_policy).
+    pub mode_policy: ModePolicy,
+    /// See [`Root::reopen_policy`](struct.Root.html#structfield.reopen_policy).
+    pub reopen_policy: ReopenPolicy,
+    /// See [`Root::throttle`](struct.Root.html#structfield.throttle).
+    pub throttle: Throttle,
+    /// See [`Root::grace_policy`](struct.Root.html#structfield.grace_policy).
+    pub grace_policy: GracePolicy,
+    /// See [`Root::fallback_policy`](struct.Root.html#structfield.fallback_policy).
+    pub fallback_policy: FallbackPolicy,
+    /// See [`Root::resolution_budget`](struct.Root.html#structfield.resolution_budget).
+    pub resolution_budget: ResolutionBudget,
+    /// See [`Root::resolve_cache_capacity`](struct.Root.html#structfield.resolve_cache_capacity).
+    pub resolve_cache_capacity: usize,
+    /// See [`Root::negative_cache_capacity`](struct.Root.html#structfield.negative_cache_capacity).
+    pub negative_cache_capacity: usize,
+    /// See [`Root::negative_cache_ttl`](struct.Root.html#structfield.negative_cache_ttl).
+    pub negative_cache_ttl: Duration,
+}
+
+impl RootConfig {
+    /// Get a copy of the configuration of `root`.
+    pub fn of(root: &Root) -> Self {
+        Self {
+            resolver: root.resolver,
+            filename_validator: root.filename_validator.clone(),
+            device_policy: root.device_policy.clone(),
+            mode_policy: root.mode_policy,
+            reopen_policy: root.reopen_policy,
+            throttle: root.throttle,
+            grace_policy: root.grace_policy,
+            fallback_policy: root.fallback_policy,
+            resolution_budget: root.resolution_budget,
+            resolve_cache_capacity: root.resolve_cache_capacity,
+            negative_cache_capacity: root.negative_cache_capacity,
+            negative_cache_ttl: root.negative_cache_ttl,
+        }
+    }
+
+    /// Apply this configuration to `root`.
+    pub fn apply(&self, root: &mut Root) {
+        root.resolver = self.resolver;
+        root.filename_validator = self.filename_validator.clone();
+        root.device_policy = self.device_policy.clone();
+        root.mode_policy = self.mode_policy;
+        root.reopen_policy = self.reopen_policy;
+        root.throttle = self.throttle;
+        root.grace_policy = self.grace_policy;
+        root.fallback_policy = self.fallback_policy;
+        root.resolution_budget = self.resolution_budget;
+        root.resolve_cache_capacity = self.resolve_cache_capacity;
+        root.negative_cache_capacity = self.negative_cache_capacity;
+        root.negative_cache_ttl = self.negative_cache_ttl;
+    }
+}
+
+impl PartialEq for RootConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // Validators are opaque, so they are only equal if they are the same
+        // validator.
+        let validators_eq = match (&self.filename_validator, &other.filename_validator) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        validators_eq
+            && self.resolver == other.resolver
+            && self.device_policy == other.device_policy
+            && self.mode_policy == other.mode_policy
+            && self.reopen_policy == other.reopen_policy
+            && self.throttle == other.throttle
+            && self.grace_policy == other.grace_policy
+            && self.fallback_policy == other.fallback_policy
+            && self.resolution_budget == other.resolution_budget
+            && self.resolve_cache_capacity == other.resolve_cache_capacity
+            && self.negative_cache_capacity == other.negative_cache_capacity
+            && self.negative_cache_ttl == other.negative_cache_ttl
+    }
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+    config: Arc<RootConfig>,
+    root: Arc<Root>,
+    /// The `(st_dev, st_ino)` of the root directory when it was opened.
+    inode_id: (u64, u64),
+}
+
+/// A cache of open [`Root`]s, keyed by their path and [`RootConfig`].
+///
+/// This is intended for services which operate on a large number of
+/// directory trees (such as per-tenant directories) and would otherwise open
+/// (and configure) a new [`Root`] for every request. The [`Root`]s are shared
+/// between callers with [`Arc`].
+///
+/// Before a cached [`Root`] is reused, the path is checked to make sure it
+/// still refers to the same directory -- if it doesn't (or it no longer
+/// exists), the stale [`Root`] is evicted and the path is re-opened. If an
+/// operation on a pooled [`Root`] fails in a way which suggests that the
+/// [`Root`] is no longer usable, you should call [`RootPool::evict`].
+///
+/// [`Root`]: struct.Root.html
+/// [`RootConfig`]: struct.RootConfig.html
+/// [`RootPool::evict`]: struct.RootPool.html#method.evict
+/// [`Arc`]: https://doc.rust-lang.org/std/sync/struct.Arc.html
+#[derive(Debug, Default)]
+pub struct RootPool {
+    entries: Mutex<HashMap<PathBuf, Vec<PoolEntry>>>,
+}
+
+impl RootPool {
+    /// Create an empty [`RootPool`].
+    ///
+    /// [`RootPool`]: struct.RootPool.html
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a [`Root`] for `path` with the configuration `config`, opening it
+    /// (with [`Root::open`]) if there is no usable cached [`Root`].
+    ///
+    /// # Errors
+    ///
+    /// If `path` needs to be opened, the errors are identical to
+    /// [`Root::open`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open`]: struct.Root.html#method.open
+    pub fn get<P: AsRef<Path>>(
+        &self,
+        path: P,
+        config: &Arc<RootConfig>,
+    ) -> Result<Arc<Root>, Error> {
+        let path = path.as_ref();
+        // If the path no longer exists, every cached entry is stale.
+        let current_id = fs::metadata(path).ok().map(|meta| (meta.dev(), meta.ino()));
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(bucket) = entries.get_mut(path) {
+            bucket.retain(|entry| Some(entry.inode_id) == current_id);
+            if let Some(entry) = bucket.iter().find(|entry| *entry.config == **config) {
+                return Ok(Arc::clone(&entry.root));
+            }
+        }
+
+        let mut root = Root::open(path).wrap("open root for pool")?;
+        config.apply(&mut root);
+        let inode_id = root.inner.inode_id()?;
+        let root = Arc::new(root);
+        entries
+            .entry(path.to_path_buf())
+            .or_default()
+            .push(PoolEntry {
+                config: Arc::clone(config),
+                root: Arc::clone(&root),
+                inode_id,
+            });
+        Ok(root)
+    }
+
+    /// Evict every cached [`Root`] for `path` (regardless of configuration).
+    /// Existing users of the [`Root`]s are unaffected.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn evict<P: AsRef<Path>>(&self, path: P) {
+        self.entries.lock().unwrap().remove(path.as_ref());
+    }
+
+    /// Evict every cached [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Number of cached [`Root`]s.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Whether there are no cached [`Root`]s.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
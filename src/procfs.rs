@@ -0,0 +1,601 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Safe access to the per-process and per-thread directories of procfs.
+//!
+//! Many procfs files describe the calling *thread* rather than the process
+//! (such as `attr/current` for LSM labels, `ns/*` for namespaces, and `fd/*`
+//! after `unshare(CLONE_FILES)`), and must be accessed through
+//! `/proc/thread-self`. That symlink only exists since Linux 3.17, and
+//! callers which fall back to `/proc/self` on older kernels silently get the
+//! attributes of the thread-group leader instead. The helpers in this module
+//! fall back to `/proc/self/task/$tid` (which is equivalent) when
+//! `/proc/thread-self` doesn't exist.
+//!
+//! All lookups go through libpathrs's verified handle to the root of procfs,
+//! and the opened files are checked to be on procfs.
+//!
+//! [`open_namespace`] and [`open_pidfd_namespace`] provide typed access to the
+//! `ns/*` magic-links of other processes, for use with `setns(2)`.
+//!
+//! The writers for `uid_map`, `gid_map`, `setgroups` and `/proc/sys` (such as
+//! [`write_uid_map`] and [`write_sysctl`]) format their values the way the
+//! kernel expects, and write them with a single `write(2)`.
+//!
+//! [`open_namespace`]: fn.open_namespace.html
+//! [`open_pidfd_namespace`]: fn.open_pidfd_namespace.html
+//! [`write_uid_map`]: fn.write_uid_map.html
+//! [`write_sysctl`]: fn.write_sysctl.html
+
+use crate::{
+    error::{self, Error},
+    syscalls,
+    utils::PROCFS_HANDLE,
+    OpenFlags,
+};
+
+use std::{
+    fmt::{self, Write as FmtWrite},
+    fs::File,
+    io::{Read, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Component, Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// The procfs directory that a [`procfs`](index.html) path is relative to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcfsBase {
+    /// `/proc/self`, which describes the calling process (the thread-group
+    /// leader).
+    ProcSelf,
+    /// `/proc/thread-self`, which describes the calling thread. On kernels
+    /// without `/proc/thread-self`, `/proc/self/task/$tid` is used instead.
+    ProcThreadSelf,
+}
+
+/// Reject subpaths which could leave the base directory lexically.
+fn check_subpath(subpath: &Path) -> Result<(), Error> {
+    ensure!(
+        subpath
+            .components()
+            .all(|part| matches!(part, Component::Normal(_) | Component::CurDir)),
+        error::InvalidArgument {
+            name: "subpath",
+            description: "must be a relative path without '..' components",
+        }
+    );
+    Ok(())
+}
+
+/// Check that `file` is on procfs.
+fn check_procfs(file: &File) -> Result<(), Error> {
+    let fs_type = syscalls::fstatfs(file.as_raw_fd())
+        .context(error::RawOsError {
+            operation: "check filesystem of procfs file",
+        })?
+        .f_type;
+    ensure!(
+        fs_type == libc::PROC_SUPER_MAGIC,
+        error::SafetyViolation {
+            description: format!("procfs file is on a filesystem with f_type 0x{:X}", fs_type),
+        }
+    );
+    Ok(())
+}
+
+/// Open the base directory `base` as an `O_PATH` handle.
+fn open_base(base: ProcfsBase) -> Result<File, Error> {
+    let procfd = PROCFS_HANDLE.as_raw_fd();
+    let flags = libc::O_PATH | libc::O_DIRECTORY;
+    let dir = match base {
+        ProcfsBase::ProcSelf => syscalls::openat_follow(procfd, "self", flags, 0),
+        ProcfsBase::ProcThreadSelf => {
+            match syscalls::openat_follow(procfd, "thread-self", flags, 0) {
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => {
+                    let task = format!("self/task/{}", syscalls::gettid());
+                    syscalls::openat_follow(procfd, task, flags, 0)
+                }
+                ret => ret,
+            }
+        }
+    }
+    .context(error::RawOsError {
+        operation: "open procfs base directory",
+    })?;
+    check_procfs(&dir)?;
+    Ok(dir)
+}
+
+/// Open `subpath` relative to `base` with `flags`, without following the
+/// final component if it is a symlink (so magic-links such as `fd/$n` are
+/// opened as `O_PATH` handles to the link itself, which is only useful with
+/// `O_PATH`). Use [`open_follow`] to follow magic-links.
+///
+/// # Errors
+///
+/// `subpath` must be relative and must not contain `..` components, otherwise
+/// an [`Error::InvalidArgument`] is returned. If the opened file is not on
+/// procfs (for instance because something was mounted on top of it), an
+/// [`Error::SafetyViolation`] is returned.
+///
+/// [`open_follow`]: fn.open_follow.html
+/// [`Error::InvalidArgument`]: ../error/enum.Error.html#variant.InvalidArgument
+/// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+pub fn open<P: AsRef<Path>, F: Into<OpenFlags>>(
+    base: ProcfsBase,
+    subpath: P,
+    flags: F,
+) -> Result<File, Error> {
+    let subpath = subpath.as_ref();
+    check_subpath(subpath)?;
+    let dir = open_base(base)?;
+    let file = syscalls::openat(dir.as_raw_fd(), subpath, flags.into().0, 0).context(
+        error::RawOsError {
+            operation: "open procfs file",
+        },
+    )?;
+    check_procfs(&file)?;
+    Ok(file)
+}
+
+/// Identical to [`open`], except that the final component of `subpath` is
+/// followed if it is a (magic-)link, such as `fd/$n` or `ns/net`.
+///
+/// # Errors
+///
+/// The errors are identical to [`open`], except that the target of the link
+/// does not need to be on procfs.
+///
+/// [`open`]: fn.open.html
+pub fn open_follow<P: AsRef<Path>, F: Into<OpenFlags>>(
+    base: ProcfsBase,
+    subpath: P,
+    flags: F,
+) -> Result<File, Error> {
+    let subpath = subpath.as_ref();
+    check_subpath(subpath)?;
+    let dir = open_base(base)?;
+    syscalls::openat_follow(dir.as_raw_fd(), subpath, flags.into().0, 0).context(
+        error::RawOsError {
+            operation: "open procfs file (following links)",
+        },
+    )
+}
+
+/// Read the target of the (magic-)link `subpath` relative to `base`, such as
+/// `fd/$n` or `exe`.
+///
+/// # Errors
+///
+/// The errors are identical to [`open`].
+///
+/// [`open`]: fn.open.html
+pub fn readlink<P: AsRef<Path>>(base: ProcfsBase, subpath: P) -> Result<PathBuf, Error> {
+    let subpath = subpath.as_ref();
+    check_subpath(subpath)?;
+    let dir = open_base(base)?;
+    syscalls::readlinkat(dir.as_raw_fd(), subpath).context(error::RawOsError {
+        operation: "readlink procfs link",
+    })
+}
+
+/// Open the procfs directory of the process `pid` (`/proc/$pid`) as an
+/// `O_PATH` handle.
+fn open_pid_dir(pid: libc::pid_t) -> Result<File, Error> {
+    ensure!(
+        pid > 0,
+        error::InvalidArgument {
+            name: "pid",
+            description: "must be a positive process id",
+        }
+    );
+    let dir = syscalls::openat(
+        PROCFS_HANDLE.as_raw_fd(),
+        pid.to_string(),
+        libc::O_PATH | libc::O_DIRECTORY,
+        0,
+    )
+    .context(error::RawOsError {
+        operation: "open procfs pid directory",
+    })?;
+    check_procfs(&dir)?;
+    Ok(dir)
+}
+
+/// `f_type` of nsfs, the filesystem backing namespace files.
+const NSFS_MAGIC: libc::__fsword_t = 0x6e73_6673;
+
+/// A namespace type, corresponding to one of the `ns/*` files in procfs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    /// Mount namespace (`ns/mnt`, `CLONE_NEWNS`).
+    Mount,
+    /// User namespace (`ns/user`, `CLONE_NEWUSER`).
+    User,
+    /// PID namespace (`ns/pid`, `CLONE_NEWPID`).
+    Pid,
+    /// Network namespace (`ns/net`, `CLONE_NEWNET`).
+    Net,
+    /// IPC namespace (`ns/ipc`, `CLONE_NEWIPC`).
+    Ipc,
+    /// UTS namespace (`ns/uts`, `CLONE_NEWUTS`).
+    Uts,
+    /// Cgroup namespace (`ns/cgroup`, `CLONE_NEWCGROUP`).
+    Cgroup,
+}
+
+impl Namespace {
+    /// The name of the namespace file within `ns/`, which is also the prefix
+    /// of its magic-link target (such as `net:[4026531840]`).
+    pub fn name(self) -> &'static str {
+        match self {
+            Namespace::Mount => "mnt",
+            Namespace::User => "user",
+            Namespace::Pid => "pid",
+            Namespace::Net => "net",
+            Namespace::Ipc => "ipc",
+            Namespace::Uts => "uts",
+            Namespace::Cgroup => "cgroup",
+        }
+    }
+
+    /// The `CLONE_NEW*` flag for this namespace, as passed to `setns(2)` to
+    /// check that a namespace file is of the expected type.
+    pub fn clone_flag(self) -> libc::c_int {
+        match self {
+            Namespace::Mount => libc::CLONE_NEWNS,
+            Namespace::User => libc::CLONE_NEWUSER,
+            Namespace::Pid => libc::CLONE_NEWPID,
+            Namespace::Net => libc::CLONE_NEWNET,
+            Namespace::Ipc => libc::CLONE_NEWIPC,
+            Namespace::Uts => libc::CLONE_NEWUTS,
+            Namespace::Cgroup => libc::CLONE_NEWCGROUP,
+        }
+    }
+}
+
+/// Open the `ns` namespace file in the procfs directory `dir`.
+///
+/// Something mounted on top of the magic-link (such as a bind-mount of another
+/// namespace file) is detected by opening the link itself, which yields the
+/// root of the mount instead of a procfs symlink. The inode number in the
+/// link's target is then checked against the namespace file we end up with.
+fn open_ns_file(dir: &File, ns: Namespace) -> Result<File, Error> {
+    let subpath = format!("ns/{}", ns.name());
+    let link = syscalls::openat(dir.as_raw_fd(), &subpath, libc::O_PATH, 0).context(
+        error::RawOsError {
+            operation: "open namespace magic-link",
+        },
+    )?;
+    check_procfs(&link)?;
+    let is_symlink = link
+        .metadata()
+        .context(error::OsError {
+            operation: "check namespace magic-link",
+        })?
+        .file_type()
+        .is_symlink();
+    ensure!(
+        is_symlink,
+        error::SafetyViolation {
+            description: format!("{} is not a magic-link (it may be overmounted)", subpath),
+        }
+    );
+
+    let target = syscalls::readlinkat(link.as_raw_fd(), "").context(error::RawOsError {
+        operation: "readlink namespace magic-link",
+    })?;
+    let ino = target
+        .to_str()
+        .and_then(|target| target.strip_prefix(ns.name()))
+        .and_then(|target| target.strip_prefix(":["))
+        .and_then(|target| target.strip_suffix(']'))
+        .and_then(|ino| ino.parse::<u64>().ok());
+    let ino = match ino {
+        Some(ino) => ino,
+        None => error::SafetyViolation {
+            description: format!("{} has unexpected target {:?}", subpath, target),
+        }
+        .fail()?,
+    };
+
+    let file = syscalls::openat_follow(dir.as_raw_fd(), &subpath, libc::O_RDONLY, 0).context(
+        error::RawOsError {
+            operation: "open namespace file",
+        },
+    )?;
+    let fs_type = syscalls::fstatfs(file.as_raw_fd())
+        .context(error::RawOsError {
+            operation: "check filesystem of namespace file",
+        })?
+        .f_type;
+    let meta = file.metadata().context(error::OsError {
+        operation: "check namespace file",
+    })?;
+    ensure!(
+        fs_type == NSFS_MAGIC && meta.ino() == ino,
+        error::SafetyViolation {
+            description: format!(
+                "{} does not match its magic-link target {:?}",
+                subpath, target
+            ),
+        }
+    );
+    Ok(file)
+}
+
+/// Open the `ns` namespace file of the process `pid` (`/proc/$pid/ns/...`),
+/// suitable for use with `setns(2)`.
+///
+/// Note that `pid` is only resolved once, so if the process may exit (and its
+/// PID be recycled) concurrently, use [`open_pidfd_namespace`] instead.
+///
+/// # Errors
+///
+/// If the procfs directory of `pid` or its namespace magic-link are not on
+/// procfs, or something has been mounted on top of the magic-link, an
+/// [`Error::SafetyViolation`] is returned.
+///
+/// [`open_pidfd_namespace`]: fn.open_pidfd_namespace.html
+/// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+pub fn open_namespace(pid: libc::pid_t, ns: Namespace) -> Result<File, Error> {
+    open_ns_file(&open_pid_dir(pid)?, ns)
+}
+
+/// Identical to [`open_namespace`], except that the process is referenced by
+/// the pidfd `pidfd` (from `pidfd_open(2)` or `CLONE_PIDFD`).
+///
+/// After the namespace file has been opened, the process is checked to still
+/// be alive -- so the result cannot belong to a different process which was
+/// given a recycled PID.
+///
+/// # Errors
+///
+/// If the process has exited (or is not visible in libpathrs's procfs), an
+/// [`Error::OsError`] with `ESRCH` is returned. Otherwise the errors are
+/// identical to [`open_namespace`].
+///
+/// [`open_namespace`]: fn.open_namespace.html
+/// [`Error::OsError`]: ../error/enum.Error.html#variant.OsError
+pub fn open_pidfd_namespace<F: AsRawFd>(pidfd: &F, ns: Namespace) -> Result<File, Error> {
+    let pidfd = pidfd.as_raw_fd();
+    let mut fdinfo = String::new();
+    open(
+        ProcfsBase::ProcSelf,
+        format!("fdinfo/{}", pidfd),
+        libc::O_RDONLY,
+    )?
+    .read_to_string(&mut fdinfo)
+    .context(error::OsError {
+        operation: "read pidfd fdinfo",
+    })?;
+    let pid = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))
+        .and_then(|pid| pid.trim().parse::<libc::pid_t>().ok());
+    let pid = match pid {
+        Some(pid) if pid > 0 => pid,
+        // A pid of -1 means the process has exited, and 0 means it is not in
+        // our pid namespace.
+        Some(_) => {
+            return Err(std::io::Error::from_raw_os_error(libc::ESRCH)).context(error::OsError {
+                operation: "get pid of pidfd",
+            })
+        }
+        None => error::InvalidArgument {
+            name: "pidfd",
+            description: "is not a pidfd",
+        }
+        .fail()?,
+    };
+
+    let file = open_namespace(pid, ns)?;
+    syscalls::pidfd_send_signal(pidfd, 0).context(error::RawOsError {
+        operation: "check pidfd process is still alive",
+    })?;
+    Ok(file)
+}
+
+/// A single line of a `uid_map` or `gid_map` file (see
+/// `user_namespaces(7)`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IdMapping {
+    /// The first id of the range inside the user namespace.
+    pub inside: u32,
+    /// The first id of the range outside the user namespace (in the user
+    /// namespace of the process opening the map).
+    pub outside: u32,
+    /// The number of ids in the range.
+    pub count: u32,
+}
+
+/// Write `contents` to the procfs file `subpath` of the procfs directory
+/// `dir`. Most procfs files only accept a single `write(2)` (and `uid_map`
+/// rejects any writes after the first one), so `contents` must be complete.
+fn write_file(dir: &File, subpath: &Path, contents: &[u8]) -> Result<(), Error> {
+    check_subpath(subpath)?;
+    let mut file = syscalls::openat(dir.as_raw_fd(), subpath, libc::O_WRONLY, 0).context(
+        error::RawOsError {
+            operation: "open procfs file for writing",
+        },
+    )?;
+    check_procfs(&file)?;
+    file.write_all(contents).context(error::OsError {
+        operation: "write procfs file",
+    })
+}
+
+/// Write the id mappings `mappings` to the map file `name` of `pid`.
+fn write_id_map(pid: libc::pid_t, name: &str, mappings: &[IdMapping]) -> Result<(), Error> {
+    ensure!(
+        !mappings.is_empty(),
+        error::InvalidArgument {
+            name: "mappings",
+            description: "must contain at least one mapping",
+        }
+    );
+    let contents = mappings.iter().fold(String::new(), |mut contents, map| {
+        let _ = writeln!(contents, "{} {} {}", map.inside, map.outside, map.count);
+        contents
+    });
+    write_file(&open_pid_dir(pid)?, Path::new(name), contents.as_bytes())
+}
+
+/// Write the uid mappings of the user namespace of the process `pid`
+/// (`/proc/$pid/uid_map`). The kernel only allows the map to be written once.
+///
+/// # Errors
+///
+/// If `mappings` is empty, an [`Error::InvalidArgument`] is returned. If the
+/// opened file is not on procfs, an [`Error::SafetyViolation`] is returned.
+///
+/// [`Error::InvalidArgument`]: ../error/enum.Error.html#variant.InvalidArgument
+/// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+pub fn write_uid_map(pid: libc::pid_t, mappings: &[IdMapping]) -> Result<(), Error> {
+    write_id_map(pid, "uid_map", mappings)
+}
+
+/// Identical to [`write_uid_map`], except that the gid mappings
+/// (`/proc/$pid/gid_map`) are written. Unprivileged processes must disable
+/// `setgroups(2)` first (see [`write_setgroups`]).
+///
+/// [`write_uid_map`]: fn.write_uid_map.html
+/// [`write_setgroups`]: fn.write_setgroups.html
+pub fn write_gid_map(pid: libc::pid_t, mappings: &[IdMapping]) -> Result<(), Error> {
+    write_id_map(pid, "gid_map", mappings)
+}
+
+/// Set whether `setgroups(2)` is permitted in the user namespace of the
+/// process `pid` (`/proc/$pid/setgroups`). This must be done before the gid
+/// map is written.
+///
+/// # Errors
+///
+/// The errors are identical to [`write_uid_map`].
+///
+/// [`write_uid_map`]: fn.write_uid_map.html
+pub fn write_setgroups(pid: libc::pid_t, allow: bool) -> Result<(), Error> {
+    let contents: &[u8] = if allow { b"allow\n" } else { b"deny\n" };
+    write_file(&open_pid_dir(pid)?, Path::new("setgroups"), contents)
+}
+
+/// Convert the sysctl `name` (such as `kernel.core_pattern` or
+/// `net/ipv4/ip_forward`) to its path within `/proc/sys`.
+fn sysctl_path(name: &str) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(name.replace('.', "/"));
+    ensure!(
+        !name.is_empty() && !path.is_absolute(),
+        error::InvalidArgument {
+            name: "name",
+            description: "must be a relative sysctl name",
+        }
+    );
+    check_subpath(&path)?;
+    Ok(path)
+}
+
+/// Open `/proc/sys` as an `O_PATH` handle.
+fn open_sys_dir() -> Result<File, Error> {
+    let dir = syscalls::openat(
+        PROCFS_HANDLE.as_raw_fd(),
+        "sys",
+        libc::O_PATH | libc::O_DIRECTORY,
+        0,
+    )
+    .context(error::RawOsError {
+        operation: "open procfs sys directory",
+    })?;
+    check_procfs(&dir)?;
+    Ok(dir)
+}
+
+/// Write the sysctl `name` (such as `net.ipv4.ip_forward`, which is
+/// `/proc/sys/net/ipv4/ip_forward`) with the formatted `value`.
+///
+/// Note that most sysctls are namespaced, so for a container this must be
+/// called from within its namespaces (the procfs handle used by libpathrs is
+/// opened once per process).
+///
+/// # Errors
+///
+/// If `name` contains `..` components, an [`Error::InvalidArgument`] is
+/// returned. Otherwise, the errors are identical to [`write_uid_map`].
+///
+/// [`write_uid_map`]: fn.write_uid_map.html
+/// [`Error::InvalidArgument`]: ../error/enum.Error.html#variant.InvalidArgument
+pub fn write_sysctl<V: fmt::Display>(name: &str, value: V) -> Result<(), Error> {
+    let path = sysctl_path(name)?;
+    let contents = format!("{}\n", value);
+    write_file(&open_sys_dir()?, &path, contents.as_bytes())
+}
+
+/// Read the value of the sysctl `name` (as with [`write_sysctl`]), without
+/// the trailing newline.
+///
+/// # Errors
+///
+/// The errors are identical to [`write_sysctl`].
+///
+/// [`write_sysctl`]: fn.write_sysctl.html
+pub fn read_sysctl(name: &str) -> Result<String, Error> {
+    let path = sysctl_path(name)?;
+    let mut file = syscalls::openat(open_sys_dir()?.as_raw_fd(), &path, libc::O_RDONLY, 0)
+        .context(error::RawOsError {
+            operation: "open sysctl for reading",
+        })?;
+    check_procfs(&file)?;
+    let mut value = String::new();
+    file.read_to_string(&mut value).context(error::OsError {
+        operation: "read sysctl",
+    })?;
+    value.truncate(value.trim_end_matches('\n').len());
+    Ok(value)
+}
+
+/// The system-wide `kernel.core_pattern`, returned by [`core_pattern`].
+///
+/// [`core_pattern`]: fn.core_pattern.html
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CorePattern {
+    /// Core dumps are written to files matching this template.
+    File(String),
+    /// Core dumps are piped to this helper command (without the leading
+    /// `|`), which is run by the kernel as root in the initial namespaces.
+    /// Container runtimes need to be careful not to let containers trigger
+    /// such a helper with a path which resolves inside the container.
+    Pipe(String),
+}
+
+/// Get the system-wide `kernel.core_pattern` (`/proc/sys/kernel/core_pattern`).
+///
+/// # Errors
+///
+/// The errors are identical to [`read_sysctl`].
+///
+/// [`read_sysctl`]: fn.read_sysctl.html
+pub fn core_pattern() -> Result<CorePattern, Error> {
+    let pattern = read_sysctl("kernel.core_pattern")?;
+    Ok(match pattern.strip_prefix('|') {
+        Some(helper) => CorePattern::Pipe(helper.to_string()),
+        None => CorePattern::File(pattern),
+    })
+}
@@ -0,0 +1,89 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls, Handle, OpenFlags, Walk,
+};
+
+use std::{convert::TryFrom, fs::Metadata, os::unix::io::AsRawFd, path::Path};
+
+use snafu::ResultExt;
+
+/// Statistics of a [`Handle::readahead_tree`].
+///
+/// [`Handle::readahead_tree`]: struct.Handle.html#method.readahead_tree
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadaheadStats {
+    /// Number of files which were prefetched.
+    pub files: u64,
+    /// Total size of the files which were prefetched.
+    pub bytes: u64,
+}
+
+impl Handle {
+    /// Walk the directory tree starting at this directory handle (see
+    /// [`Walk`]) and prefetch the contents of every regular file for which
+    /// `filter` returns `true` into the page cache with `readahead(2)`, in
+    /// order to warm the caches before the tree is used (such as before a
+    /// container is started).
+    ///
+    /// `filter` is given the path of each file (relative to this directory)
+    /// and its metadata. Files are read through the walked handles (re-opened
+    /// with [`Handle::reopen`]), so the prefetching cannot be redirected
+    /// outside of the tree.
+    ///
+    /// # Errors
+    ///
+    /// If this handle is not a directory, or any matching file cannot be
+    /// re-opened for reading, an error is returned. Note that `readahead(2)`
+    /// is only a hint, so a successful prefetch doesn't guarantee that the
+    /// files will still be cached when they are later read.
+    ///
+    /// [`Walk`]: struct.Walk.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    pub fn readahead_tree<F>(&self, mut filter: F) -> Result<ReadaheadStats, Error>
+    where
+        F: FnMut(&Path, &Metadata) -> bool,
+    {
+        let mut stats = ReadaheadStats::default();
+        let walk = Walk::new(self.try_clone()?).wrap("start readahead walk")?;
+        for entry in walk {
+            let entry = entry.wrap("walk readahead tree")?;
+            let metadata = entry.metadata();
+            if !metadata.is_file() || !filter(entry.path(), metadata) {
+                continue;
+            }
+            let file = entry
+                .handle()
+                .reopen(OpenFlags(libc::O_RDONLY))
+                .wrap_with(|| format!("reopen {:?} for readahead", entry.path()))?;
+            // Files larger than the address space are prefetched as far as
+            // possible.
+            let count = usize::try_from(metadata.len()).unwrap_or(usize::MAX);
+            syscalls::readahead(file.as_raw_fd(), 0, count).context(error::RawOsError {
+                operation: "readahead file contents",
+            })?;
+            stats.files += 1;
+            stats.bytes += metadata.len();
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{Error, ErrorExt},
+    path::normalize_lexical,
+    utils::FileExt,
+    workers, Root,
+};
+
+use std::{
+    cmp,
+    ffi::OsString,
+    fs::{File, Metadata},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Options for [`Root::relabel`].
+///
+/// [`Root::relabel`]: struct.Root.html#method.relabel
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RelabelOptions {
+    /// Number of worker threads applying xattrs (a value of `0` is treated as
+    /// `1`). With a single thread, xattrs are applied by the calling thread.
+    pub threads: usize,
+    /// The progress callback is called after every `progress_interval`
+    /// entries (as well as once at the end of the walk).
+    pub progress_interval: u64,
+}
+
+impl Default for RelabelOptions {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            progress_interval: 1000,
+        }
+    }
+}
+
+/// Progress of a [`Root::relabel`].
+///
+/// [`Root::relabel`]: struct.Root.html#method.relabel
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelabelProgress {
+    /// Number of entries visited by the walk.
+    pub visited: u64,
+    /// Number of entries which had at least one xattr changed.
+    pub relabeled: u64,
+}
+
+/// Apply the xattrs returned by `labels` to `file`, skipping xattrs which
+/// already have the right value. Returns whether any xattr was changed.
+fn apply_labels<F>(labels: &F, path: &Path, file: &File, metadata: &Metadata) -> Result<bool, Error>
+where
+    F: Fn(&Path, &Metadata) -> Vec<(OsString, Vec<u8>)>,
+{
+    let mut changed = false;
+    for (name, value) in labels(path, metadata) {
+        match file.get_xattr(&name) {
+            Ok(ref current) if *current == value => continue,
+            Err(err) if err.raw_os_error() != Some(libc::ENODATA) => {
+                return Err(err).wrap(format!("get {:?} of {:?}", name, path))
+            }
+            _ => (),
+        }
+        file.set_xattr(&name, &value)
+            .wrap_with(|| format!("set {:?} of {:?}", name, path))?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, walk the directory tree starting at `path`
+    /// (with [`Root::walk`]) and apply the xattrs returned by `labels` to
+    /// every entry, in the style of `restorecon(8)`.
+    ///
+    /// `labels` is given the path of each entry (relative to the [`Root`],
+    /// such as `/usr/bin/ls`) and its metadata, and returns the xattrs (such
+    /// as `security.selinux`) the entry should have. Xattrs which already have
+    /// the right value are not rewritten, and symlinks are labelled themselves
+    /// rather than being followed. `progress` is called from the calling
+    /// thread as the walk progresses.
+    ///
+    /// # Errors
+    ///
+    /// The relabeling stops at the first error (which is returned), so some
+    /// entries may already have been relabeled. Entries which are removed
+    /// during the walk are skipped, while directories which are moved out of
+    /// the tree result in an [`Error::SafetyViolation`] (see [`Walk`] for the
+    /// limits of this check).
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    /// [`Walk`]: struct.Walk.html
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn relabel<P, F, G>(
+        &self,
+        path: P,
+        options: &RelabelOptions,
+        labels: F,
+        mut progress: G,
+    ) -> Result<RelabelProgress, Error>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path, &Metadata) -> Vec<(OsString, Vec<u8>)> + Sync,
+        G: FnMut(&RelabelProgress),
+    {
+        let base = normalize_lexical(Path::new("/").join(path.as_ref()));
+        let walk = self.walk(&base).wrap("start relabel walk")?;
+        let interval = cmp::max(options.progress_interval, 1);
+        let relabeled = AtomicU64::new(0);
+        let mut visited = 0;
+
+        let jobs = walk.map(|entry| {
+            let entry = entry.wrap("walk relabel tree")?;
+            visited += 1;
+            if visited % interval == 0 {
+                progress(&RelabelProgress {
+                    visited,
+                    relabeled: relabeled.load(Ordering::Relaxed),
+                });
+            }
+            Ok((base.join(entry.path()), entry))
+        });
+        workers::for_each("pathrs-relabel", options.threads, jobs, |(path, entry)| {
+            if apply_labels(&labels, &path, &entry.handle().inner, entry.metadata())? {
+                relabeled.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+        .wrap("relabel tree")?;
+
+        let done = RelabelProgress {
+            visited,
+            relabeled: relabeled.into_inner(),
+        };
+        progress(&done);
+        Ok(done)
+    }
+}
@@ -0,0 +1,630 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    syscalls::{self, UnlinkRing},
+    utils::{self, FileExt},
+    walk::DirState,
+    Dirents, EntryType, Root,
+};
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    iter,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+};
+
+use libc::c_int;
+use snafu::ResultExt;
+
+/// Number of unlinkat operations submitted to io_uring at once. A directory is
+/// verified before each batch, so this also bounds how many entries can be
+/// removed from a directory after it was moved out of the tree.
+const BATCH_SIZE: u32 = 32;
+
+/// Number of times removing an entry is retried after its type was changed
+/// underneath us (or entries were added to a directory we emptied).
+const MAX_RETRIES: usize = 16;
+
+/// A directory which is being emptied by a [`TreeRemover`].
+///
+/// [`TreeRemover`]: struct.TreeRemover.html
+struct RemoveDir {
+    state: DirState,
+    /// Name of the directory inside its parent.
+    name: OsString,
+    /// The `(st_dev, st_ino)` of the directory, used to verify the directory
+    /// when it is re-opened and to check that its subdirectories have not been
+    /// moved out of the tree.
+    inode_id: (u64, u64),
+}
+
+impl RemoveDir {
+    #[inline]
+    fn is_open(&self) -> bool {
+        matches!(self.state, DirState::Open(_))
+    }
+}
+
+/// What [`TreeRemover::open_subdir`] found.
+///
+/// [`TreeRemover::open_subdir`]: struct.TreeRemover.html#method.open_subdir
+enum Subdir {
+    /// The entry is a directory, opened for reading.
+    Dir(File),
+    /// The entry is not a directory (or is a symlink, which we never follow).
+    NotDir,
+    /// The entry doesn't exist (any more).
+    Missing,
+}
+
+/// State of a [`Root::remove_all`].
+///
+/// [`Root::remove_all`]: struct.Root.html#method.remove_all
+struct TreeRemover {
+    /// The io_uring used for batches of unlinks, or `None` if io_uring (or
+    /// `IORING_OP_UNLINKAT`) isn't usable.
+    ring: Option<UnlinkRing>,
+    /// Handle to the parent of the removal target, which suspended directories
+    /// are re-opened relative to if none of their ancestors are open.
+    anchor: File,
+    /// The `(st_dev, st_ino)` of `anchor`.
+    anchor_id: (u64, u64),
+    /// The directories currently being emptied, starting with the removal
+    /// target.
+    stack: Vec<RemoveDir>,
+    /// Maximum number of directories in `stack` which may be open at once.
+    fd_budget: usize,
+    /// Number of directories in `stack` which are currently open.
+    open_dirs: usize,
+}
+
+/// Should this unlinkat(2) failure of `name` be retried with
+/// [`TreeRemover::remove_entry`], because the inode type changed or there are
+/// new entries in the directory?
+///
+/// [`TreeRemover::remove_entry`]: struct.TreeRemover.html#method.remove_entry
+fn is_type_race(err: &syscalls::Error) -> bool {
+    matches!(
+        err.root_cause().raw_os_error(),
+        Some(libc::EISDIR) | Some(libc::ENOTDIR) | Some(libc::ENOTEMPTY) | Some(libc::EEXIST)
+    )
+}
+
+impl TreeRemover {
+    /// Prepare to remove entries from the directory `anchor`.
+    fn new(anchor: File) -> Result<Self, Error> {
+        Ok(Self {
+            // ENOSYS (no io_uring), EPERM (io_uring is disabled) or ENOMEM
+            // (the memlock limit is too low on older kernels) all mean we
+            // have to use plain unlinkat(2).
+            ring: UnlinkRing::new(BATCH_SIZE).ok(),
+            anchor_id: anchor.inode_id()?,
+            anchor,
+            stack: Vec::new(),
+            fd_budget: utils::default_fd_budget(),
+            open_dirs: 0,
+        })
+    }
+
+    /// Check that the directory `dirfd` (the top of the stack) is still
+    /// reachable from the parent of the removal target through the
+    /// directories we descended into, by walking back up with `..`. Since `..`
+    /// is resolved by the kernel based on the dentry tree, this detects
+    /// directories which were moved elsewhere.
+    fn verify(&self, dirfd: RawFd) -> Result<(), Error> {
+        let depth = match self.stack.len() {
+            // Entries of the parent of the removal target are only removed
+            // by name.
+            0 => return Ok(()),
+            len => len - 1,
+        };
+        let ancestors =
+            iter::once(&self.anchor_id).chain(self.stack[..depth].iter().map(|dir| &dir.inode_id));
+        let mut current: Option<File> = None;
+        for expected in ancestors.rev() {
+            let fd = current.as_ref().map_or(dirfd, AsRawFd::as_raw_fd);
+            let parent = syscalls::openat(fd, "..", libc::O_PATH | libc::O_DIRECTORY, 0)
+                .context(error::RawOsError {
+                    operation: "open parent directory during remove_all",
+                })
+                .map_err(|err| utils::check_fd_exhaustion(err, "open parent directory"))?;
+            ensure!(
+                parent.inode_id()? == *expected,
+                error::SafetyViolation {
+                    description: "directory was moved during remove_all",
+                }
+            );
+            current = Some(parent);
+        }
+        Ok(())
+    }
+
+    /// Close the shallowest open directory in the stack (except for the one at
+    /// the top). Returns whether a directory was closed.
+    fn suspend_one(&mut self) -> Result<bool, Error> {
+        let end = self.stack.len().saturating_sub(1);
+        let dir = match self.stack[..end].iter_mut().find(|dir| dir.is_open()) {
+            Some(dir) => dir,
+            None => return Ok(false),
+        };
+        if let DirState::Open(dirents) = &mut dir.state {
+            dir.state = DirState::Suspended(dirents.suspend()?);
+        }
+        self.open_dirs -= 1;
+        Ok(true)
+    }
+
+    /// Close directories until the stack is within the fd budget.
+    fn make_room(&mut self) -> Result<(), Error> {
+        while self.open_dirs > self.fd_budget && self.suspend_one()? {}
+        Ok(())
+    }
+
+    /// Re-open the directory at the top of the stack if it was suspended.
+    fn resume_top(&mut self) -> Result<(), Error> {
+        let idx = match self.stack.len() {
+            0 => return Ok(()),
+            len => len - 1,
+        };
+        if self.stack[idx].is_open() {
+            return Ok(());
+        }
+
+        // Walk down from the closest open ancestor (or the parent of the
+        // removal target) using the names we saw in getdents64(2). The
+        // directory must still be the same inode, otherwise the rest of the
+        // removal would be done in some other part of the tree.
+        let (base_fd, first) = match self.stack[..idx].iter().rposition(RemoveDir::is_open) {
+            Some(base) => match &self.stack[base].state {
+                DirState::Open(dirents) => (dirents.as_raw_fd(), base + 1),
+                DirState::Suspended(_) => unreachable!("rposition found an open directory"),
+            },
+            None => (self.anchor.as_raw_fd(), 0),
+        };
+        let mut current: Option<File> = None;
+        for (offset, dir) in self.stack[first..=idx].iter().enumerate() {
+            let dirfd = current.as_ref().map_or(base_fd, AsRawFd::as_raw_fd);
+            let flags = if first + offset == idx {
+                libc::O_RDONLY
+            } else {
+                libc::O_PATH
+            };
+            let file = syscalls::openat(
+                dirfd,
+                &dir.name,
+                flags | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+                0,
+            )
+            .context(error::RawOsError {
+                operation: "re-open suspended directory during remove_all",
+            })
+            .map_err(|err| utils::check_fd_exhaustion(err, "re-open suspended directory"))?;
+            current = Some(file);
+        }
+        let dir = current.expect("top of remove_all stack must have a name to re-open");
+        ensure!(
+            dir.inode_id()? == self.stack[idx].inode_id,
+            error::SafetyViolation {
+                description: "directory was moved during remove_all",
+            }
+        );
+
+        if let DirState::Suspended(suspended) = &mut self.stack[idx].state {
+            self.stack[idx].state = DirState::Open(suspended.resume(dir)?);
+        }
+        self.open_dirs += 1;
+        self.make_room()
+    }
+
+    /// The directory entries are currently being removed from: the top of the
+    /// stack (re-opened if it was suspended), or the parent of the removal
+    /// target. The file descriptor is only valid until the stack is next
+    /// modified.
+    fn current_dir(&mut self) -> Result<RawFd, Error> {
+        self.resume_top()?;
+        Ok(match self.stack.last() {
+            Some(dir) => match &dir.state {
+                DirState::Open(dirents) => dirents.as_raw_fd(),
+                DirState::Suspended(_) => unreachable!("top of remove_all stack must be open"),
+            },
+            None => self.anchor.as_raw_fd(),
+        })
+    }
+
+    /// Open `name` inside the current directory for reading, if it is a
+    /// directory. If we have run out of file descriptors, other directories
+    /// in the stack are closed (to be re-opened later) and the open is
+    /// retried.
+    fn open_subdir(&mut self, name: &OsStr) -> Result<Subdir, Error> {
+        loop {
+            let dirfd = self.current_dir()?;
+            let err = match syscalls::openat(
+                dirfd,
+                name,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+                0,
+            ) {
+                Ok(subdir) => return Ok(Subdir::Dir(subdir)),
+                Err(err) => err,
+            };
+            let errno = err.root_cause().raw_os_error();
+            match errno {
+                Some(libc::ENOENT) => return Ok(Subdir::Missing),
+                Some(libc::ENOTDIR) | Some(libc::ELOOP) => return Ok(Subdir::NotDir),
+                Some(libc::EMFILE) | Some(libc::ENFILE) if self.suspend_one()? => continue,
+                _ => {
+                    return Err(err)
+                        .context(error::RawOsError {
+                            operation: "open directory during remove_all",
+                        })
+                        .map_err(|err| {
+                            utils::check_fd_exhaustion(err, "open directory during remove_all")
+                        })
+                }
+            }
+        }
+    }
+
+    /// Remove `name` inside the current directory, emptying it first if it is
+    /// a directory.
+    fn remove_entry(&mut self, name: &OsStr) -> Result<(), Error> {
+        let mut last_error = None;
+        for _ in 0..MAX_RETRIES {
+            let flags = match self.open_subdir(name)? {
+                Subdir::Dir(subdir) => {
+                    self.stack.push(RemoveDir {
+                        inode_id: subdir.inode_id()?,
+                        state: DirState::Open(Dirents::new(subdir).resolve_entry_types(true)),
+                        name: name.to_os_string(),
+                    });
+                    self.open_dirs += 1;
+                    let ret = self.make_room().and_then(|_| self.remove_contents());
+                    if let Some(dir) = self.stack.pop() {
+                        if dir.is_open() {
+                            self.open_dirs -= 1;
+                        }
+                    }
+                    ret?;
+                    libc::AT_REMOVEDIR
+                }
+                Subdir::NotDir => 0,
+                Subdir::Missing => return Ok(()),
+            };
+
+            let dirfd = self.current_dir()?;
+            self.verify(dirfd)?;
+            match syscalls::unlinkat(dirfd, name, flags) {
+                Ok(_) => return Ok(()),
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => return Ok(()),
+                Err(err) if is_type_race(&err) => last_error = Some(err),
+                Err(err) => {
+                    return Err(err).context(error::RawOsError {
+                        operation: "remove inode during remove_all",
+                    })
+                }
+            }
+        }
+
+        // If we ever are here, then last_error must be Some.
+        Err(last_error.expect("remove_all loop failed so last_error must exist")).context(
+            error::RawOsError {
+                operation: "remove inode during remove_all",
+            },
+        )
+    }
+
+    /// Remove every entry of the directory at the top of the stack.
+    /// Directories are removed recursively, and everything else is removed in
+    /// batches.
+    fn remove_contents(&mut self) -> Result<(), Error> {
+        let mut batch = Vec::new();
+        loop {
+            self.resume_top()?;
+            let dirents = match self.stack.last_mut().map(|dir| &mut dir.state) {
+                Some(DirState::Open(dirents)) => dirents,
+                _ => unreachable!("top of remove_all stack must be open"),
+            };
+            let entry = match dirents.next() {
+                Some(entry) => entry?,
+                None => break,
+            };
+            match dirents.entry_type(&entry) {
+                Ok(EntryType::Directory) => self.remove_entry(entry.name())?,
+                Ok(_) => {
+                    batch.push(entry.into_name());
+                    if batch.len() >= BATCH_SIZE as usize {
+                        self.unlink_batch(&mut batch)?;
+                    }
+                }
+                // The entry was removed after we read the directory.
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        self.unlink_batch(&mut batch)
+    }
+
+    /// Unlink every non-directory in `batch` (which is emptied) from the
+    /// current directory, through io_uring if possible.
+    ///
+    /// The directory is verified before the batch is submitted, and again if
+    /// any of the unlinks failed (since failures can mean the directory is
+    /// being modified) before acting on the failures.
+    fn unlink_batch(&mut self, batch: &mut Vec<OsString>) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let dirfd = self.current_dir()?;
+        self.verify(dirfd)?;
+
+        let ops: Vec<(&OsStr, c_int)> = batch.iter().map(|name| (name.as_os_str(), 0)).collect();
+        let results = match self.ring.as_mut().map(|ring| ring.unlinkat_batch(dirfd, &ops)) {
+            // IORING_OP_UNLINKAT was added in Linux 5.11, and older kernels
+            // reject unknown operations with EINVAL (which unlinkat(2) never
+            // returns for these flags).
+            Some(Ok(results))
+                if !results.iter().any(|ret| {
+                    matches!(ret, Err(err) if err.root_cause().raw_os_error() == Some(libc::EINVAL))
+                }) =>
+            {
+                results
+            }
+            // The ring can't be used (any more), so do it the slow way. Some
+            // operations may have completed before the ring failed.
+            ring_ret => {
+                if ring_ret.is_some() {
+                    self.verify(dirfd)?;
+                }
+                self.ring = None;
+                ops.iter()
+                    .map(|(name, flags)| syscalls::unlinkat(dirfd, name, *flags))
+                    .collect()
+            }
+        };
+
+        let failed = results.iter().any(
+            |ret| matches!(ret, Err(err) if err.root_cause().raw_os_error() != Some(libc::ENOENT)),
+        );
+        if failed {
+            self.verify(dirfd)?;
+        }
+        for (name, ret) in batch.iter().zip(results) {
+            match ret {
+                Ok(_) => (),
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => (),
+                // The entry was swapped for a directory.
+                Err(err) if is_type_race(&err) => self.remove_entry(name)?,
+                Err(err) => {
+                    return Err(err).context(error::RawOsError {
+                        operation: "remove inode during remove_all",
+                    })
+                }
+            }
+        }
+        batch.clear();
+        Ok(())
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, remove the inode at `path` and (if it is a
+    /// directory) everything inside it, similar to `rm -rf`. Symlinks are
+    /// never followed, so if `path` (or anything inside it) is a symlink then
+    /// the symlink itself is removed.
+    ///
+    /// The tree is read with `getdents64(2)` and each directory is opened with
+    /// `O_NOFOLLOW` relative to its parent, so the removal cannot escape
+    /// `path`. Before each batch of entries is removed from a directory, it is
+    /// checked to still be inside `path` (by walking back up the tree with
+    /// `..`). This cannot be done atomically, so a directory which is moved out
+    /// of `path` during the removal may still lose the entries of the batch
+    /// which was in flight when it was moved (at most 32), but the removal
+    /// stops before removing anything else from it.
+    ///
+    /// Like [`Root::walk`], at most a fixed number of directories are held
+    /// open at once (see [`Walk::set_fd_budget`] for the default). Deeper
+    /// directories are closed and re-opened by name once the removal returns
+    /// to them -- if a re-opened directory is not the same directory, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// On kernels which support it (Linux 5.11 and later), everything other
+    /// than directories is removed in batches of `IORING_OP_UNLINKAT`
+    /// operations submitted through io_uring, which is much faster for large
+    /// trees. If io_uring is not available (or has been disabled), each inode
+    /// is removed with a separate `unlinkat(2)` instead.
+    ///
+    /// # Errors
+    ///
+    /// If `path` doesn't exist, an error is returned. If a directory was moved
+    /// out of `path` during the removal, an [`Error::SafetyViolation`] is
+    /// returned. The removal stops at the first error, so some of the tree may
+    /// have already been removed.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    /// [`Walk::set_fd_budget`]: struct.Walk.html#method.set_fd_budget
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn remove_all<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .resolve(parent)
+            .wrap("resolve target parent directory for remove_all")?
+            .inner;
+
+        // Make sure the target exists, rather than silently doing nothing.
+        syscalls::fstatat(dir.as_raw_fd(), name).context(error::RawOsError {
+            operation: "pathrs remove_all",
+        })?;
+
+        let ret = TreeRemover::new(dir).and_then(|mut remover| remover.remove_entry(name.as_ref()));
+        self.invalidate_caches(path);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RemoveDir, Subdir, TreeRemover};
+    use crate::{error::Error, tests::TempDir, utils::FileExt, walk::DirState, Dirents, Root};
+
+    use std::{fs, os::unix::fs::symlink};
+
+    /// Create a tree containing more entries than fit into a single batch, a
+    /// symlink to a directory outside the tree, and nested directories.
+    fn make_tree(tmpdir: &TempDir) {
+        let base = tmpdir.path();
+        fs::create_dir_all(base.join("tree/a/b/c")).unwrap();
+        fs::create_dir(base.join("outside")).unwrap();
+        fs::write(base.join("outside/keep"), "keep").unwrap();
+        for i in 0..300 {
+            fs::write(base.join(format!("tree/file{}", i)), "").unwrap();
+            fs::write(base.join(format!("tree/a/b/c/file{}", i)), "").unwrap();
+        }
+        symlink(base.join("outside"), base.join("tree/abs")).unwrap();
+        symlink("../../../outside", base.join("tree/a/b/rel")).unwrap();
+        fs::write(base.join("tree/a/b/c/.hidden"), "").unwrap();
+    }
+
+    fn check_removed(tmpdir: &TempDir) {
+        let base = tmpdir.path();
+        assert!(fs::symlink_metadata(base.join("tree")).is_err());
+        assert_eq!(fs::read(base.join("outside/keep")).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn remove_all_tree() {
+        let tmpdir = TempDir::new();
+        make_tree(&tmpdir);
+        let root = Root::open(tmpdir.path()).unwrap();
+        root.remove_all("tree").unwrap();
+        check_removed(&tmpdir);
+    }
+
+    #[test]
+    fn remove_all_without_io_uring() {
+        let tmpdir = TempDir::new();
+        make_tree(&tmpdir);
+        let root = Root::open(tmpdir.path()).unwrap();
+        let dir = root.resolve("/").unwrap().inner;
+        let mut remover = TreeRemover::new(dir).unwrap();
+        remover.ring = None;
+        remover.remove_entry("tree".as_ref()).unwrap();
+        check_removed(&tmpdir);
+    }
+
+    #[test]
+    fn remove_all_fd_budget() {
+        let tmpdir = TempDir::new();
+        make_tree(&tmpdir);
+        // A tree much deeper than the fd budget, with entries at every level
+        // (so each directory is still being read when we return to it).
+        let mut deep = tmpdir.path().join("tree");
+        for i in 0..64 {
+            for j in 0..4 {
+                fs::write(deep.join(format!("file{}", j)), "").unwrap();
+            }
+            deep.push(format!("dir{}", i));
+            fs::create_dir(&deep).unwrap();
+            fs::create_dir(deep.with_file_name(format!("sibling{}", i))).unwrap();
+        }
+        let root = Root::open(tmpdir.path()).unwrap();
+        let dir = root.resolve("/").unwrap().inner;
+        let mut remover = TreeRemover::new(dir).unwrap();
+        remover.fd_budget = 2;
+        remover.remove_entry("tree".as_ref()).unwrap();
+        assert_eq!(remover.open_dirs, 0);
+        check_removed(&tmpdir);
+    }
+
+    #[test]
+    fn remove_all_moved_suspended_directory() {
+        let tmpdir = TempDir::new();
+        fs::create_dir_all(tmpdir.path().join("tree/a/b")).unwrap();
+        fs::create_dir(tmpdir.path().join("outside")).unwrap();
+        let root = Root::open(tmpdir.path()).unwrap();
+        let dir = root.resolve("/").unwrap().inner;
+        let mut remover = TreeRemover::new(dir).unwrap();
+        remover.fd_budget = 1;
+
+        // Descend into tree/a/b (suspending tree and tree/a), then move tree/a
+        // out of the tree before returning to it.
+        for name in &["tree", "a", "b"] {
+            match remover.open_subdir(name.as_ref()).unwrap() {
+                Subdir::Dir(subdir) => {
+                    remover.stack.push(RemoveDir {
+                        inode_id: subdir.inode_id().unwrap(),
+                        state: DirState::Open(Dirents::new(subdir)),
+                        name: name.into(),
+                    });
+                    remover.open_dirs += 1;
+                    remover.make_room().unwrap();
+                }
+                _ => panic!("{} should be a directory", name),
+            }
+        }
+        assert_eq!(remover.open_dirs, 1);
+        remover.stack.pop();
+        remover.open_dirs -= 1;
+        fs::rename(
+            tmpdir.path().join("tree/a"),
+            tmpdir.path().join("outside/a"),
+        )
+        .unwrap();
+        fs::create_dir(tmpdir.path().join("tree/a")).unwrap();
+
+        let err = remover.current_dir().unwrap_err();
+        assert!(
+            err.iter_chain_hotfix()
+                .filter_map(|err| err.downcast_ref::<Error>())
+                .any(|err| matches!(err, Error::SafetyViolation { .. })),
+            "unexpected error: {}",
+            err
+        );
+        assert!(tmpdir.path().join("outside/a/b").is_dir());
+    }
+
+    #[test]
+    fn remove_all_non_directory() {
+        let tmpdir = TempDir::new();
+        fs::write(tmpdir.path().join("file"), "").unwrap();
+        fs::create_dir(tmpdir.path().join("dir")).unwrap();
+        symlink("dir", tmpdir.path().join("link")).unwrap();
+        let root = Root::open(tmpdir.path()).unwrap();
+        root.remove_all("file").unwrap();
+        root.remove_all("link").unwrap();
+        assert!(fs::symlink_metadata(tmpdir.path().join("file")).is_err());
+        assert!(fs::symlink_metadata(tmpdir.path().join("link")).is_err());
+        assert!(tmpdir.path().join("dir").is_dir());
+    }
+
+    #[test]
+    fn remove_all_missing() {
+        let tmpdir = TempDir::new();
+        let root = Root::open(tmpdir.path()).unwrap();
+        let err = root.remove_all("missing").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+}
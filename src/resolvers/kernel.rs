@@ -18,18 +18,72 @@
 
 use crate::{
     error::{self, Error, ErrorExt},
-    resolvers::{self, ResolverFlags},
-    syscalls::unstable,
+    hardening,
+    metrics::{self, FallbackEvent},
+    resolvers::{self, Openat2Support, ResolutionBudget, ResolveStats, ResolverFlags},
+    retry,
+    syscalls::{self, unstable},
+    utils::RawFdExt,
     Handle,
 };
 
-use std::{fs::File, os::unix::io::AsRawFd, path::Path};
+use std::{
+    fs::File,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Component, Path},
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use snafu::ResultExt;
 
-lazy_static! {
-    pub(crate) static ref IS_SUPPORTED: bool =
-        unstable::openat2(libc::AT_FDCWD, ".", &Default::default()).is_ok();
+/// Cached result of the last openat2(2) probe, or UNPROBED.
+static SUPPORT: AtomicU8 = AtomicU8::new(UNPROBED);
+
+const UNPROBED: u8 = 0;
+const SUPPORTED: u8 = 1;
+const NOT_IMPLEMENTED: u8 = 2;
+const BLOCKED: u8 = 3;
+
+/// Probe whether `openat2(2)` is usable (by opening `path` relative to
+/// `dirfd`), and update the cached result used by [`is_supported`].
+///
+/// [`is_supported`]: fn.is_supported.html
+pub(crate) fn probe<P: AsRef<Path>>(dirfd: RawFd, path: P) -> Openat2Support {
+    let support = match unstable::openat2(dirfd, path.as_ref(), &Default::default()) {
+        Ok(_) => Openat2Support::Supported,
+        Err(err) => match err.root_cause().raw_os_error() {
+            Some(libc::ENOSYS) => Openat2Support::NotImplemented,
+            // Seccomp profiles which don't know about openat2(2) usually
+            // return -EPERM (or -ENOSYS). The kernel itself never returns
+            // -EPERM for O_PATH lookups without any flags.
+            Some(libc::EPERM) => Openat2Support::Blocked,
+            // Any other error means the syscall reached the kernel, so the
+            // probe path was the problem and not openat2(2).
+            _ => Openat2Support::Supported,
+        },
+    };
+    let value = match support {
+        Openat2Support::Supported => SUPPORTED,
+        Openat2Support::NotImplemented => NOT_IMPLEMENTED,
+        Openat2Support::Blocked => BLOCKED,
+    };
+    SUPPORT.store(value, Ordering::Relaxed);
+    support
+}
+
+/// The cached result of the last `openat2(2)` probe (probing if there hasn't
+/// been one yet).
+pub(crate) fn support() -> Openat2Support {
+    match SUPPORT.load(Ordering::Relaxed) {
+        SUPPORTED => Openat2Support::Supported,
+        NOT_IMPLEMENTED => Openat2Support::NotImplemented,
+        BLOCKED => Openat2Support::Blocked,
+        _ => probe(libc::AT_FDCWD, "/"),
+    }
+}
+
+pub(crate) fn is_supported() -> bool {
+    support() == Openat2Support::Supported
 }
 
 /// Resolve `path` within `root` through `openat2(2)`.
@@ -37,31 +91,85 @@ pub(crate) fn resolve<P: AsRef<Path>>(
     root: &File,
     path: P,
     flags: ResolverFlags,
+    budget: &ResolutionBudget,
+    stats: &mut ResolveStats,
 ) -> Result<Handle, Error> {
-    ensure!(*IS_SUPPORTED, error::NotSupported { feature: "openat2" });
+    ensure!(is_supported(), error::NotSupported { feature: "openat2" });
 
-    let mut how = unstable::OpenHow::default();
-    how.flags = libc::O_PATH as u64;
-    // RESOLVE_IN_ROOT does exactly what we want, but we also want to avoid
-    // resolving magic-links. RESOLVE_IN_ROOT already blocks magic-link
-    // crossings, but that may change in the future (if the magic-links are
-    // considered "safe") but we should still explicitly avoid them entirely.
-    how.resolve = unstable::RESOLVE_IN_ROOT | unstable::RESOLVE_NO_MAGICLINKS | flags.bits;
+    let mut how = unstable::OpenHow {
+        flags: libc::O_PATH as u64,
+        // RESOLVE_IN_ROOT does exactly what we want, but we also want to avoid
+        // resolving magic-links. RESOLVE_IN_ROOT already blocks magic-link
+        // crossings, but that may change in the future (if the magic-links are
+        // considered "safe") but we should still explicitly avoid them
+        // entirely.
+        resolve: unstable::RESOLVE_IN_ROOT | unstable::RESOLVE_NO_MAGICLINKS | flags.resolve_bits(),
+        ..Default::default()
+    };
+    if hardening::hardening_overrides().disable_resolve_cached {
+        how.resolve &= !unstable::RESOLVE_CACHED;
+    }
 
     // openat2(2) can fail with -EAGAIN if there was a racing rename or mount
     // *anywhere on the system*. This can happen pretty frequently, so what we
-    // do is attempt the openat2(2) a couple of times, and then fall-back to
-    // userspace emulation.
+    // do is attempt the openat2(2) a couple of times (as configured by the
+    // RetryPolicy), and then fall-back to userspace emulation.
+    let policy = retry::retry_policy();
     let mut handle: Option<File> = None;
-    for _ in 0..16 {
+    let mut attempt = 0;
+    loop {
         match unstable::openat2(root.as_raw_fd(), path.as_ref(), &how) {
             Ok(file) => {
                 handle = Some(file);
                 break;
             }
             Err(err) => match err.root_cause().raw_os_error() {
-                Some(libc::ENOSYS) => break, // shouldn't happen
-                Some(libc::EAGAIN) => continue,
+                // This shouldn't happen, but seccomp profiles can be applied
+                // after we probed (or may filter openat2(2) based on its
+                // arguments), so fall back rather than failing the lookup.
+                Some(libc::ENOSYS) | Some(libc::EPERM) => {
+                    metrics::record_fallback(FallbackEvent::KernelUnsupported);
+                    break;
+                }
+                Some(libc::EAGAIN) if attempt < policy.max_eagain_retries => {
+                    attempt += 1;
+                    stats.retries += 1;
+                    retry::record_eagain_retry();
+                    metrics::record_eagain_retry();
+                    policy.backoff(attempt);
+                }
+                Some(libc::EAGAIN) => {
+                    retry::record_eagain_fallback();
+                    metrics::record_fallback(FallbackEvent::KernelEagain);
+                    break;
+                }
+                // The kernel only does case-insensitive lookups in casefolded
+                // directories, so let the emulated backend have a go.
+                Some(libc::ENOENT) if flags.contains(ResolverFlags::CASE_INSENSITIVE) => {
+                    metrics::record_fallback(FallbackEvent::CaseInsensitive);
+                    break;
+                }
+                // The kernel cannot take paths longer than PATH_MAX, but the
+                // emulated backend resolves one component at a time.
+                Some(libc::ENAMETOOLONG)
+                    if path.as_ref().as_os_str().len() >= libc::PATH_MAX as usize =>
+                {
+                    metrics::record_fallback(FallbackEvent::LongPath);
+                    break;
+                }
+                // RESOLVE_NO_MAGICLINKS blocks magic-links with -ELOOP (and
+                // RESOLVE_IN_ROOT would block them regardless), so let the
+                // emulated backend follow and verify them.
+                Some(libc::ELOOP) if flags.contains(ResolverFlags::ALLOW_MAGICLINKS) => {
+                    metrics::record_fallback(FallbackEvent::MagicLink);
+                    break;
+                }
+                // openat2(2) doesn't tell us which component wasn't a
+                // directory, but the emulated backend does.
+                Some(libc::ENOTDIR) => {
+                    metrics::record_fallback(FallbackEvent::NotADirectory);
+                    break;
+                }
                 // TODO: Add wrapper for known-bad openat2 return codes.
                 //Some(libc::EXDEV) | Some(libc::ELOOP) => { ... }
                 _ => {
@@ -73,11 +181,63 @@ pub(crate) fn resolve<P: AsRef<Path>>(
         }
     }
 
-    handle.map_or_else(
-        || {
-            resolvers::user::resolve(root, path, flags)
-                .wrap("fallback user-space resolution for RESOLVE_IN_ROOT")
-        },
-        |file| Ok(Handle::from_file_unchecked(file)),
-    )
+    match handle {
+        Some(file) => {
+            // The kernel silently does case-insensitive lookups inside
+            // casefolded directories, so tell the caller if that happened.
+            if flags.contains(ResolverFlags::CASE_INSENSITIVE) {
+                stats.casefolded_dirs += casefolded_dirs(root, &file);
+            }
+            Ok(Handle::from_file_unchecked(file))
+        }
+        None => resolvers::user::resolve(root, path, flags, budget, stats)
+            .wrap("fallback user-space resolution for RESOLVE_IN_ROOT"),
+    }
+}
+
+/// Count the casefolded (`chattr +F`) directories between `root` and `file`
+/// (including `root`, excluding `file`), by walking down the path `file` is
+/// currently at. This is best-effort: directories which cannot be opened or
+/// don't support `FS_IOC_GETFLAGS` are counted as not casefolded.
+fn casefolded_dirs(root: &File, file: &File) -> u64 {
+    let is_casefolded = |dir: &File| {
+        syscalls::ioctl_getflags(dir.as_raw_fd())
+            .map(|flags| flags & syscalls::FS_CASEFOLD_FL != 0)
+            .unwrap_or(false)
+    };
+    let (root_path, path) = match (root.as_unsafe_path(), file.as_unsafe_path()) {
+        (Ok(root_path), Ok(path)) => (root_path, path),
+        _ => return 0,
+    };
+    let subpath = match path.strip_prefix(&root_path) {
+        Ok(subpath) => subpath,
+        Err(_) => return 0,
+    };
+    let parts: Vec<_> = subpath
+        .components()
+        .filter_map(|part| match part {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+    let mut current = match syscalls::openat(root.as_raw_fd(), ".", flags, 0) {
+        Ok(dir) => dir,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    for (idx, part) in parts.iter().enumerate() {
+        if is_casefolded(&current) {
+            count += 1;
+        }
+        if idx + 1 == parts.len() {
+            break;
+        }
+        current = match syscalls::openat(current.as_raw_fd(), part, flags, 0) {
+            Ok(dir) => dir,
+            Err(_) => break,
+        };
+    }
+    count
 }
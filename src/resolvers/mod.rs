@@ -18,9 +18,14 @@
 
 #![forbid(unsafe_code)]
 
-use crate::{error::Error, syscalls::unstable, Handle};
+use crate::{error::Error, hardening, metrics, syscalls::unstable, utils, Handle};
 
-use std::{fs::File, path::Path};
+use std::{
+    fs::File,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 /// `openat2(2)`-based in-kernel resolver.
 pub mod kernel;
@@ -34,6 +39,47 @@ bitflags! {
     #[derive(Default)]
     pub struct ResolverFlags: u64 {
         const NO_SYMLINKS = unstable::RESOLVE_NO_SYMLINKS;
+
+        /// Match path components case-insensitively, in a manner similar to
+        /// directories with the casefold attribute (`chattr +F`) on ext4 and
+        /// f2fs. Components are first looked up exactly, and only if no such
+        /// entry exists is the directory scanned for a case-insensitive match
+        /// (ambiguous matches result in an error).
+        ///
+        /// This is not a `RESOLVE_*` flag. The kernel cannot do
+        /// case-insensitive lookups outside of casefolded directories, so the
+        /// native backend will fall back to the emulated backend if a path
+        /// could not be resolved as-is. Since the kernel does its own
+        /// case-insensitive lookups inside casefolded directories, the number
+        /// of such directories traversed by the native backend is reported in
+        /// [`ResolveStats::casefolded_dirs`].
+        ///
+        /// [`ResolveStats::casefolded_dirs`]: struct.ResolveStats.html#structfield.casefolded_dirs
+        const CASE_INSENSITIVE = 1 << 32;
+
+        /// Allow paths to be resolved through procfs-style "magic-links"
+        /// (such as `/proc/self/fd/$n`) of a procfs mounted inside the
+        /// [`Root`]. By default such paths are refused.
+        ///
+        /// Magic-links can point anywhere, so after following one the path of
+        /// the inode it landed on is checked to be inside the [`Root`], and
+        /// that path is then re-resolved as usual and must lead back to the
+        /// same inode. If either check fails an error is returned.
+        ///
+        /// This is not a `RESOLVE_*` flag. `openat2(2)` refuses to follow
+        /// magic-links with `RESOLVE_IN_ROOT`, so the native backend will fall
+        /// back to the emulated backend when it encounters one.
+        ///
+        /// [`Root`]: struct.Root.html
+        const ALLOW_MAGICLINKS = 1 << 33;
+    }
+}
+
+impl ResolverFlags {
+    /// The subset of flags which are `RESOLVE_*` flags understood by
+    /// `openat2(2)`.
+    pub(crate) fn resolve_bits(self) -> u64 {
+        (self & Self::NO_SYMLINKS).bits
     }
 }
 
@@ -56,17 +102,13 @@ pub enum ResolverBackend {
     //       hyper-concerned users.
 }
 
-lazy_static! {
-    static ref DEFAULT_RESOLVER_TYPE: ResolverBackend = if *kernel::IS_SUPPORTED {
-        ResolverBackend::Kernel
-    } else {
-        ResolverBackend::Emulated
-    };
-}
-
 impl Default for ResolverBackend {
     fn default() -> Self {
-        *DEFAULT_RESOLVER_TYPE
+        if kernel::is_supported() {
+            ResolverBackend::Kernel
+        } else {
+            ResolverBackend::Emulated
+        }
     }
 }
 
@@ -74,12 +116,29 @@ impl ResolverBackend {
     /// Checks if the resolver is supported on the current platform.
     pub fn supported(self) -> bool {
         match self {
-            ResolverBackend::Kernel => *kernel::IS_SUPPORTED,
+            ResolverBackend::Kernel => kernel::is_supported(),
             ResolverBackend::Emulated => true,
         }
     }
 }
 
+/// Whether `openat2(2)` (and thus [`ResolverBackend::Kernel`]) is usable, as
+/// determined by probing it. See [`Root::reprobe_resolver`].
+///
+/// [`ResolverBackend::Kernel`]: enum.ResolverBackend.html#variant.Kernel
+/// [`Root::reprobe_resolver`]: struct.Root.html#method.reprobe_resolver
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Openat2Support {
+    /// `openat2(2)` is usable.
+    Supported,
+    /// `openat2(2)` failed with `ENOSYS`, either because the kernel is too old
+    /// (it was added in Linux 5.6) or because a seccomp profile blocks it.
+    NotImplemented,
+    /// `openat2(2)` failed with `EPERM`, which only happens if a seccomp
+    /// profile blocks it.
+    Blocked,
+}
+
 /// Resolover backend and its associated flags.
 ///
 /// This is the primary structure used to configure how a given [`Root`] will
@@ -97,12 +156,175 @@ pub struct Resolver {
 }
 
 impl Resolver {
-    /// Internal dispatcher to the relevant backend.
+    /// Internal dispatcher to the relevant backend. The work done by the
+    /// resolution is added to `stats`.
     #[inline]
-    pub(crate) fn resolve<P: AsRef<Path>>(&self, root: &File, path: P) -> Result<Handle, Error> {
-        match self.backend {
-            ResolverBackend::Kernel => kernel::resolve(root, path, self.flags),
-            ResolverBackend::Emulated => user::resolve(root, path, self.flags),
+    pub(crate) fn resolve<P: AsRef<Path>>(
+        &self,
+        root: &File,
+        path: P,
+        budget: &ResolutionBudget,
+        stats: &mut ResolveStats,
+    ) -> Result<Handle, Error> {
+        let path = path.as_ref();
+        utils::check_no_nul("path", path)?;
+        stats.resolutions += 1;
+        let backend = if hardening::hardening_overrides().force_emulated {
+            ResolverBackend::Emulated
+        } else {
+            self.backend
+        };
+        let start = metrics::start();
+        let ret = match backend {
+            ResolverBackend::Kernel => kernel::resolve(root, path, self.flags, budget, stats),
+            ResolverBackend::Emulated => user::resolve(root, path, self.flags, budget, stats),
+        };
+        metrics::record_resolution(start, backend, &ret);
+        ret
+    }
+}
+
+/// Limits on the work done by a single resolution with the emulated backend
+/// (including fallbacks from the native backend), see
+/// [`Root::resolution_budget`].
+///
+/// The emulated backend can be strung along by an attacker who keeps changing
+/// symlinks in the path (each one only counts once towards the limit of
+/// symlinks per resolution, but can expand to many components), or by a slow
+/// filesystem. With a budget, such a resolution fails with
+/// [`Error::ResolutionBudgetExceeded`] (which is also counted in
+/// [`ResolveStats`] and reported to the [`MetricsRecorder`]) rather than
+/// silently stalling. By default there are no limits.
+///
+/// [`Root::resolution_budget`]: struct.Root.html#structfield.resolution_budget
+/// [`Error::ResolutionBudgetExceeded`]: error/enum.Error.html#variant.ResolutionBudgetExceeded
+/// [`ResolveStats`]: struct.ResolveStats.html
+/// [`MetricsRecorder`]: trait.MetricsRecorder.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResolutionBudget {
+    /// Maximum number of path components walked (including components from
+    /// symlink contents), or `None` for no limit.
+    pub max_steps: Option<u64>,
+    /// Maximum time spent walking the path, or `None` for no limit.
+    pub max_duration: Option<Duration>,
+}
+
+impl ResolutionBudget {
+    /// Has a resolution which walked `steps` components in `elapsed` exceeded
+    /// the budget?
+    pub(crate) fn is_exceeded(&self, steps: u64, elapsed: Duration) -> bool {
+        self.max_steps.is_some_and(|max| steps > max)
+            || self.max_duration.is_some_and(|max| elapsed > max)
+    }
+}
+
+/// Statistics about the work done by path resolutions, for deciding whether
+/// the emulated backend is fast enough for a workload. See
+/// [`Root::resolve_with_stats`] and [`Root::resolve_stats`].
+///
+/// Only the emulated backend walks components itself, so resolutions done
+/// entirely by `openat2(2)` only count towards `resolutions` and `retries`.
+/// Resolutions which fall back to the emulated backend count towards both.
+///
+/// [`Root::resolve_with_stats`]: struct.Root.html#method.resolve_with_stats
+/// [`Root::resolve_stats`]: struct.Root.html#method.resolve_stats
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResolveStats {
+    /// Number of resolutions (successful or not).
+    pub resolutions: u64,
+    /// Number of path components opened by the emulated backend (including
+    /// components from symlink contents).
+    pub components: u64,
+    /// Number of symlinks expanded by the emulated backend.
+    pub symlinks: u64,
+    /// Number of `openat2(2)` lookups retried after failing with `EAGAIN`.
+    pub retries: u64,
+    /// Number of `/proc/self/fd` verifications done.
+    pub proc_checks: u64,
+    /// Number of resolutions answered from the resolution cache (see
+    /// [`Root::resolve_cache_capacity`]).
+    ///
+    /// [`Root::resolve_cache_capacity`]: struct.Root.html#structfield.resolve_cache_capacity
+    pub cache_hits: u64,
+    /// Number of resolutions which failed because of a cached failed lookup
+    /// (see [`Root::negative_cache_capacity`]).
+    ///
+    /// [`Root::negative_cache_capacity`]: struct.Root.html#structfield.negative_cache_capacity
+    pub negative_hits: u64,
+    /// Number of resolutions which were aborted for exceeding the
+    /// [`ResolutionBudget`].
+    ///
+    /// [`ResolutionBudget`]: struct.ResolutionBudget.html
+    pub budget_exceeded: u64,
+    /// Number of casefolded (`chattr +F`) directories the resolved paths went
+    /// through, where lookups are case-insensitive regardless of the resolver.
+    /// This is only counted for resolutions done by `openat2(2)` with
+    /// [`ResolverFlags::CASE_INSENSITIVE`] set.
+    ///
+    /// [`ResolverFlags::CASE_INSENSITIVE`]: struct.ResolverFlags.html#associatedconstant.CASE_INSENSITIVE
+    pub casefolded_dirs: u64,
+}
+
+/// Aggregated [`ResolveStats`] of a [`Root`].
+///
+/// [`ResolveStats`]: struct.ResolveStats.html
+/// [`Root`]: struct.Root.html
+#[derive(Debug, Default)]
+pub(crate) struct ResolveStatsCounters {
+    resolutions: AtomicU64,
+    components: AtomicU64,
+    symlinks: AtomicU64,
+    retries: AtomicU64,
+    proc_checks: AtomicU64,
+    cache_hits: AtomicU64,
+    negative_hits: AtomicU64,
+    budget_exceeded: AtomicU64,
+    casefolded_dirs: AtomicU64,
+}
+
+impl ResolveStatsCounters {
+    pub(crate) fn add(&self, stats: &ResolveStats) {
+        self.resolutions
+            .fetch_add(stats.resolutions, Ordering::Relaxed);
+        self.components
+            .fetch_add(stats.components, Ordering::Relaxed);
+        self.symlinks.fetch_add(stats.symlinks, Ordering::Relaxed);
+        self.retries.fetch_add(stats.retries, Ordering::Relaxed);
+        self.proc_checks
+            .fetch_add(stats.proc_checks, Ordering::Relaxed);
+        self.cache_hits
+            .fetch_add(stats.cache_hits, Ordering::Relaxed);
+        self.negative_hits
+            .fetch_add(stats.negative_hits, Ordering::Relaxed);
+        self.budget_exceeded
+            .fetch_add(stats.budget_exceeded, Ordering::Relaxed);
+        self.casefolded_dirs
+            .fetch_add(stats.casefolded_dirs, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ResolveStats {
+        ResolveStats {
+            resolutions: self.resolutions.load(Ordering::Relaxed),
+            components: self.components.load(Ordering::Relaxed),
+            symlinks: self.symlinks.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            proc_checks: self.proc_checks.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            budget_exceeded: self.budget_exceeded.load(Ordering::Relaxed),
+            casefolded_dirs: self.casefolded_dirs.load(Ordering::Relaxed),
         }
     }
+
+    pub(crate) fn reset(&self) {
+        self.resolutions.store(0, Ordering::Relaxed);
+        self.components.store(0, Ordering::Relaxed);
+        self.symlinks.store(0, Ordering::Relaxed);
+        self.retries.store(0, Ordering::Relaxed);
+        self.proc_checks.store(0, Ordering::Relaxed);
+        self.cache_hits.store(0, Ordering::Relaxed);
+        self.negative_hits.store(0, Ordering::Relaxed);
+        self.budget_exceeded.store(0, Ordering::Relaxed);
+        self.casefolded_dirs.store(0, Ordering::Relaxed);
+    }
 }
@@ -34,53 +34,129 @@
 //! will fail in fewer cases because it has access to in-kernel locks and other
 //! measures, but the final check throgh procfs should block all attack
 //! attempts.
+//!
+//! Checking every component through procfs is expensive (each check is three
+//! readlink(2)s), so intermediate components are verified with the following
+//! invariants instead:
+//!
+//! * Walking down (opening a non-".." component) can never escape the
+//!   directory we are in, so it needs no check at all. If the directory we are
+//!   in was moved outside the root, every later lookup happens outside the
+//!   root too -- which the final procfs check detects.
+//! * We record the (st_dev, st_ino) of every directory we walk through (we
+//!   need to fstat(2) each component anyway, to detect symlinks). Looking up
+//!   ".." must land on the same inode as the directory we walked through to
+//!   get here. If it doesn't, a racing rename moved us and we bail. This is
+//!   the same guarantee the kernel gives for RESOLVE_IN_ROOT, which checks
+//!   whether ".." crossed a racing rename.
+//! * Absolute symlinks restart the walk (and the recorded chain) at the root.
+//!
+//! This means each component costs an openat(2) and an fstat(2), and there is
+//! only a single procfs check at the end of the resolution.
+//!
+//! Since every lookup is done one component at a time, paths longer than
+//! PATH_MAX can be resolved. The only place where the full path matters is the
+//! final procfs check, because readlink(/proc/self/fd/$n) fails with
+//! ENAMETOOLONG for such paths. In that case we instead walk back up to the
+//! root with ".." and compare each directory against the recorded chain.
+//!
+//! Symlinks on procfs-like filesystems are refused, unless the caller opted in
+//! with ResolverFlags::ALLOW_MAGICLINKS. In that case we let the kernel follow
+//! the magic-link, check through readlink(/proc/self/fd/$n) that it landed
+//! inside the root, and then restart the walk from the root with that path
+//! (treating it like an absolute symlink). Once the path has been walked, we
+//! must have ended up on the same inode the magic-link pointed to.
 
 use crate::{
     error::{self, Error, ErrorExt},
-    resolvers::ResolverFlags,
+    metrics,
+    resolvers::{ResolutionBudget, ResolveStats, ResolverFlags},
     syscalls,
     utils::{FileExt, RawFdExt},
-    Handle,
+    Dirents, Handle, OpenFlags,
 };
 
 use std::{
+    borrow::Cow,
     collections::VecDeque,
+    ffi::{OsStr, OsString},
     fs::File,
     io::Error as IOError,
-    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
     path::{Component, Path, PathBuf},
+    time::Instant,
 };
 
-use snafu::ResultExt;
+use snafu::{OptionExt, ResultExt};
 
 /// Maximum number of symlink traversals we will accept.
 const MAX_SYMLINK_TRAVERSALS: usize = 128;
 
+/// A component of the path being resolved, stored as a range of one of the
+/// [`PartBuffers`] of the resolution, so that the components don't each need
+/// their own allocation.
+///
+/// [`PartBuffers`]: struct.PartBuffers.html
+#[derive(Copy, Clone, Debug)]
+struct Part {
+    buffer: usize,
+    start: usize,
+    end: usize,
+}
+
+/// The paths whose components make up the [`Part`]s of a resolution (the path
+/// being resolved, and the contents of every symlink walked through).
+///
+/// [`Part`]: struct.Part.html
+#[derive(Debug, Default)]
+struct PartBuffers(Vec<OsString>);
+
+impl PartBuffers {
+    /// Add `path` as a new buffer, and put its components at the front of
+    /// `parts` (so that they are walked next). The "/" and "." components are
+    /// dropped, because they have no effect on the walk.
+    fn push_front(&mut self, path: OsString, parts: &mut VecDeque<Part>) {
+        let buffer = self.0.len();
+        let mut index = 0;
+        let mut start = 0;
+        for component in path.as_bytes().split(|&c| c == b'/') {
+            let end = start + component.len();
+            if !component.is_empty() && component != b"." {
+                parts.insert(index, Part { buffer, start, end });
+                index += 1;
+            }
+            start = end + 1;
+        }
+        self.0.push(path);
+    }
+
+    /// Get the name of `part`.
+    fn get(&self, part: Part) -> &OsStr {
+        OsStr::from_bytes(&self.0[part.buffer].as_bytes()[part.start..part.end])
+    }
+}
+
 /// Ensure that the expected path within the root matches the current fd.
 fn check_current<P: AsRef<Path>>(current: &File, root: &File, expected: P) -> Result<(), Error> {
     // SAFETY: as_unsafe_path is safe here since we're using it to build a path
     //         for a string-based check as part of a larger safety setup. This
     //         path will be re-checked after the unsafe "current_path" is
     //         generated.
-    let root_path = root
+    let mut full_path = root
         .as_unsafe_path()
         .wrap("get root path to construct expected path")?;
+    let root_len = full_path.as_os_str().len();
 
     // Combine the root path and our expected_path to get the full path to
     // compare current against.
-    let full_path: PathBuf = root_path.join(
-        expected
-            .as_ref()
-            .components()
-            // At this point, expected_path should only have Normal components.
-            // If there are any other components we can just ignore them because
-            // this expected_path check will probably fail.
-            .filter(|c| match c {
-                Component::Normal(_) => true,
-                _ => false,
-            })
-            .collect::<PathBuf>(),
-    );
+    expected
+        .as_ref()
+        .components()
+        // At this point, expected_path should only have Normal components.
+        // If there are any other components we can just ignore them because
+        // this expected_path check will probably fail.
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .for_each(|c| full_path.push(c));
 
     // Does /proc/self/fd agree with us? There are several circumstances where
     // this check might give a false positive (namely, if the kernel decides
@@ -109,8 +185,9 @@ fn check_current<P: AsRef<Path>>(current: &File, root: &File, expected: P) -> Re
     let new_root_path = root
         .as_unsafe_path()
         .wrap("get root path to double-check it hasn't moved")?;
+    let root_path = &full_path.as_os_str().as_bytes()[..root_len];
     ensure!(
-        root_path == new_root_path,
+        root_path == new_root_path.as_os_str().as_bytes(),
         error::SafetyViolation {
             description: "root moved during lookup"
         }
@@ -119,18 +196,155 @@ fn check_current<P: AsRef<Path>>(current: &File, root: &File, expected: P) -> Re
     Ok(())
 }
 
+/// Ensure that `current` is still inside the root by walking back up to the
+/// root with ".." and comparing every directory against the (st_dev, st_ino)
+/// `chain` recorded during the resolution (which starts with the root and ends
+/// with `current`). `parent` must be the directory `current` was opened from,
+/// and is used if `current` is not a directory (since ".." only works on
+/// directories).
+///
+/// This is only used when `check_current` is not possible because the path is
+/// too long for readlink(/proc/self/fd/$n), and unlike `check_current` it
+/// doesn't detect the root itself being moved.
+fn check_current_chain(
+    current: &File,
+    parent: Option<&File>,
+    chain: &[(u64, u64)],
+) -> Result<(), Error> {
+    ensure!(
+        chain.last() == Some(&current.inode_id()?),
+        error::SafetyViolation {
+            description: "final handle doesn't match the resolved directory chain",
+        }
+    );
+    let is_dir = current
+        .metadata()
+        .context(error::OsError {
+            operation: "fstat final handle",
+        })?
+        .is_dir();
+    let (mut dir, mut chain) = if is_dir {
+        (current.try_clone_hotfix()?, chain)
+    } else {
+        let parent = parent.context(error::SafetyViolation {
+            description: "final non-directory handle has no parent directory",
+        })?;
+        (parent.try_clone_hotfix()?, &chain[..chain.len() - 1])
+    };
+    while let Some((expected, rest)) = chain.split_last() {
+        ensure!(
+            dir.inode_id()? == *expected,
+            error::SafetyViolation {
+                description: "ancestor of final handle doesn't match the resolved directory chain",
+            }
+        );
+        if rest.is_empty() {
+            break;
+        }
+        dir = syscalls::openat(dir.as_raw_fd(), "..", libc::O_PATH | libc::O_DIRECTORY, 0)
+            .context(error::RawOsError {
+                operation: "open parent directory to verify final handle",
+            })?;
+        chain = rest;
+    }
+    Ok(())
+}
+
+/// Follow the magic-link `name` in `current` (with
+/// `ResolverFlags::ALLOW_MAGICLINKS`), returning the path (relative to `root`)
+/// of the inode it landed on and the (st_dev, st_ino) of that inode. The
+/// caller must re-resolve the path and check that it leads to the same inode,
+/// since the path given by readlink(/proc/self/fd/$n) could refer to a
+/// different inode within the root (if the magic-link pointed into another
+/// mount namespace, for instance).
+fn follow_magic_link(
+    current: &File,
+    name: &OsStr,
+    root: &File,
+    link_path: &Path,
+) -> Result<(PathBuf, (u64, u64)), Error> {
+    let landed = syscalls::openat_follow(current.as_raw_fd(), name, libc::O_PATH, 0).context(
+        error::RawOsError {
+            operation: "follow magic-link component",
+        },
+    )?;
+    let landed_id = landed.inode_id().wrap("get inode of magic-link target")?;
+
+    // SAFETY: as_unsafe_path is safe here because the path is only used to
+    //         figure out which path to re-resolve inside the root, and the
+    //         caller checks that the re-resolved inode matches landed_id.
+    let landed_path = landed
+        .as_unsafe_path()
+        .wrap("get path of magic-link target")?;
+    let root_path = root
+        .as_unsafe_path()
+        .wrap("get root path to check magic-link target")?;
+    let relpath = landed_path
+        .strip_prefix(&root_path)
+        .ok()
+        .context(error::WouldEscape {
+            path: link_path,
+            target: &landed_path,
+        })?;
+    Ok((relpath.to_path_buf(), landed_id))
+}
+
+/// Compare two path components case-insensitively.
+///
+/// This uses Unicode lowercasing for valid UTF-8 names, which is close to (but
+/// not quite identical to) the casefolding done by the kernel for casefolded
+/// directories. Other names are compared ASCII case-insensitively.
+fn casefold_eq(a: &OsStr, b: &OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => a.as_bytes().eq_ignore_ascii_case(b.as_bytes()),
+    }
+}
+
+/// Scan `dir` for an entry which matches `name` case-insensitively. If more
+/// than one entry matches, we bail rather than picking one arbitrarily.
+fn casefold_lookup(dir: &File, name: &OsStr) -> Result<Option<OsString>, Error> {
+    let dir = dir
+        .reopen(OpenFlags(libc::O_RDONLY | libc::O_DIRECTORY))
+        .wrap("reopen directory for reading")?;
+
+    let mut found = None;
+    for entry in Dirents::new(dir) {
+        let entry = entry?;
+        if casefold_eq(&entry.name, name) {
+            ensure!(
+                found.is_none(),
+                error::SafetyViolation {
+                    description: "multiple entries match component case-insensitively",
+                }
+            );
+            found = Some(entry.name);
+        }
+    }
+    Ok(found)
+}
+
 /// Resolve `path` within `root` through user-space emulation.
 pub(crate) fn resolve<P: AsRef<Path>>(
     root: &File,
     path: P,
     flags: ResolverFlags,
+    budget: &ResolutionBudget,
+    stats: &mut ResolveStats,
 ) -> Result<Handle, Error> {
     let path = path.as_ref();
 
+    // Keep track of the work done, to enforce the resolution budget.
+    let start = Instant::now();
+    let mut steps = 0;
+
     // What is the final path we expect to get after we do the final open? This
     // allows us to track any attacker moving path components around and we can
     // sanity-check at the very end. This does not include rootpath.
-    let mut expected_path = PathBuf::from(Component::RootDir.as_os_str());
+    // The buffers are sized so that they only need to grow if we walk
+    // through symlinks.
+    let mut expected_path = PathBuf::with_capacity(path.as_os_str().len() + 1);
+    expected_path.push(Component::RootDir);
 
     // We only need to keep track of our current dirfd, since we are applying
     // the components one-by-one, and can always switch back to the root
@@ -140,17 +354,46 @@ pub(crate) fn resolve<P: AsRef<Path>>(
     // Get initial set of components from the passed path. We remove components
     // as we do the path walk, and update them with the contents of any symlinks
     // we encounter. Path walking terminates when there are no components left.
-    let mut components = path
-        .components()
-        .map(|p| PathBuf::from(p.as_os_str()))
-        .collect::<VecDeque<_>>();
+    let mut buffers = PartBuffers::default();
+    let mut components = VecDeque::new();
+    buffers.push_front(path.as_os_str().to_os_string(), &mut components);
+
+    // The (st_dev, st_ino) of the root and of every directory in
+    // expected_path, used to verify ".." lookups.
+    let root_id = root.inode_id().wrap("get root inode to start chain")?;
+    let mut chain = Vec::with_capacity(components.len() + 1);
+    chain.push(root_id);
+    // The directory current was opened from, which is needed to verify
+    // non-directory handles with check_current_chain.
+    let mut parent: Option<File> = None;
+
+    // If we followed a magic-link, the number of components which were left
+    // after the re-resolved path of its target (and the inode the magic-link
+    // landed on), so we can check the re-resolution once it is done.
+    let mut magic_link_target: Option<(usize, (u64, u64))> = None;
 
     let mut symlink_traversals = 0;
-    while let Some(part) = components.pop_front() {
-        // XXX: Thanks to borrowck, we can't seem to just store Component in our
-        //      VecDeque. So we need to do a dirty conversion back to Component.
-        //      But we are definitely sure there is at only one component.
-        let part = part
+    loop {
+        if let Some((remaining, landed_id)) = magic_link_target {
+            if components.len() == remaining {
+                ensure!(
+                    current.inode_id()? == landed_id,
+                    error::SafetyViolation {
+                        description: "path of magic-link target leads to a different inode",
+                    }
+                );
+                magic_link_target = None;
+            }
+        }
+        let part = match components.pop_front() {
+            Some(part) => buffers.get(part),
+            None => break,
+        };
+
+        // XXX: We only store the names of the components in our VecDeque, so
+        //      we need to do a dirty conversion back to Component. But we are
+        //      definitely sure there is at only one component.
+        let part = Path::new(part)
             .components()
             .next()
             .expect("components should have one entry");
@@ -185,41 +428,118 @@ pub(crate) fn resolve<P: AsRef<Path>>(
             _ => continue,
         };
 
-        // Get our next element.
-        let next = syscalls::openat(current.as_raw_fd(), part, libc::O_PATH, 0).context(
-            error::RawOsError {
-                operation: "open next component of resolution",
-            },
-        )?;
-
-        // Make sure that the path is what we expect. If not, there was a racing
-        // rename and we should bail out here -- otherwise we might be tricked
-        // into revealing information outside the rootfs through error or
-        // timing-related attacks.
+        stats.components += 1;
+        steps += 1;
+        if budget.is_exceeded(steps, start.elapsed()) {
+            stats.budget_exceeded += 1;
+            metrics::record_budget_exceeded();
+            return error::ResolutionBudgetExceeded {
+                path,
+                steps,
+                elapsed: start.elapsed(),
+            }
+            .fail();
+        }
+        let is_dotdot = part == Component::ParentDir;
+        let mut name = Cow::Borrowed(part.as_os_str());
+
+        // Get our next element. If we were asked to do case-insensitive
+        // lookups and there is no exact match, look for any entry which
+        // matches case-insensitively.
+        let next = match syscalls::openat(current.as_raw_fd(), &name, libc::O_PATH, 0) {
+            Ok(next) => next,
+            Err(err)
+                if !is_dotdot
+                    && flags.contains(ResolverFlags::CASE_INSENSITIVE)
+                    && err.root_cause().raw_os_error() == Some(libc::ENOENT) =>
+            {
+                name = match casefold_lookup(&current, &name)
+                    .wrap("scan directory for case-insensitive match")?
+                {
+                    Some(name) => Cow::Owned(name),
+                    None => {
+                        return Err(err).context(error::RawOsError {
+                            operation: "open next component of resolution",
+                        })
+                    }
+                };
+                expected_path.set_file_name(&name);
+                syscalls::openat(current.as_raw_fd(), &name, libc::O_PATH, 0).context(
+                    error::RawOsError {
+                        operation: "open case-insensitive match of next component",
+                    },
+                )?
+            }
+            Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOTDIR) => {
+                let ret = Err(err).context(error::RawOsError {
+                    operation: "open next component of resolution",
+                });
+                return ret.context(error::NotADirectory {
+                    component: expected_path.parent().unwrap_or(&expected_path),
+                });
+            }
+            Err(err) => {
+                return Err(err).context(error::RawOsError {
+                    operation: "open next component of resolution",
+                })
+            }
+        };
+
+        // In casefolded directories the kernel does case-insensitive lookups
+        // for us, and so the name of the entry we got might not match the name
+        // we asked for. In that case, we need to use the real name for our
+        // expected_path checks.
+        if !is_dotdot && flags.contains(ResolverFlags::CASE_INSENSITIVE) {
+            // SAFETY: as_unsafe_path is safe here since we only use the
+            //         trailing component, and only if it is a case-insensitive
+            //         match of what we asked for. The full path is checked
+            //         later.
+            stats.proc_checks += 1;
+            let real = next
+                .as_unsafe_path()
+                .wrap("get real name of next component")?;
+            if let Some(real) = real.file_name() {
+                if real != name && casefold_eq(real, &name) {
+                    name = Cow::Owned(real.to_os_string());
+                    expected_path.set_file_name(&name);
+                }
+            }
+        }
+
+        // Is the next dirfd a symlink or an ordinary path?
+        // NOTE: File::metadata definitely does an fstat(2) here.
+        let next_meta = next.metadata().context(error::OsError {
+            operation: "fstat of next component",
+        })?;
+        let next_id = (next_meta.dev(), next_meta.ino());
+
+        // Make sure that ".." took us back to the directory we came from. If
+        // not, there was a racing rename and we should bail out here --
+        // otherwise we might be tricked into revealing information outside the
+        // rootfs through error or timing-related attacks.
         //
         // The safety argument for only needing to check ".." is identical to
         // the kernel implementation (namely, walking down is safe
         // by-definition). However, unlike the in-kernel version we don't have
         // the luxury of only doing this check when there was a racing rename --
         // we have to do it every time.
-        if part == Component::ParentDir {
-            check_current(&next, root, &expected_path)
-                .wrap("check next '..' component didn't escape")?;
+        if is_dotdot {
+            chain.pop();
+            ensure!(
+                chain.last() == Some(&next_id),
+                error::SafetyViolation {
+                    description: "'..' component doesn't match the directory it was reached from",
+                }
+            );
         }
 
-        // Is the next dirfd a symlink or an ordinary path?
-        // NOTE: File::metadata definitely does an fstat(2) here.
-        let next_type = next
-            .metadata()
-            .context(error::OsError {
-                operation: "fstat of next component",
-            })?
-            .file_type();
-
         // If we're an ordinary dirent, we just update current and move on
         // to the next component. Nothing special here.
-        if !next_type.is_symlink() {
-            current = next;
+        if !next_meta.file_type().is_symlink() {
+            if !is_dotdot {
+                chain.push(next_id);
+            }
+            parent = Some(std::mem::replace(&mut current, next));
             continue;
         }
 
@@ -231,6 +551,16 @@ pub(crate) fn resolve<P: AsRef<Path>>(
             .fail();
         }
 
+        // We need a limit on the number of symlinks we traverse to avoid
+        // hitting filesystem loops and DoSing.
+        symlink_traversals += 1;
+        stats.symlinks += 1;
+        if symlink_traversals >= MAX_SYMLINK_TRAVERSALS {
+            return Err(IOError::from_raw_os_error(libc::ELOOP)).context(error::OsError {
+                operation: "emulated symlink resolution",
+            })?;
+        }
+
         // Check if it's safe for us to touch. In principle this should never be
         // an actual security issue (since we readlink(2) the symlink) but it's
         // much better to be safe than sorry here.
@@ -238,19 +568,31 @@ pub(crate) fn resolve<P: AsRef<Path>>(
             .is_dangerous()
             .wrap("check if next is on a dangerous filesystem")?
         {
-            return error::SafetyViolation {
-                description: "next is a symlink on a dangerous filesystem",
-            }
-            .fail();
-        }
+            ensure!(
+                flags.contains(ResolverFlags::ALLOW_MAGICLINKS),
+                error::SafetyViolation {
+                    description: "next is a symlink on a dangerous filesystem",
+                }
+            );
+            ensure!(
+                magic_link_target.is_none(),
+                error::SafetyViolation {
+                    description: "path of magic-link target contains another magic-link",
+                }
+            );
 
-        // We need a limit on the number of symlinks we traverse to avoid
-        // hitting filesystem loops and DoSing.
-        symlink_traversals += 1;
-        if symlink_traversals >= MAX_SYMLINK_TRAVERSALS {
-            return Err(IOError::from_raw_os_error(libc::ELOOP)).context(error::OsError {
-                operation: "emulated symlink resolution",
-            })?;
+            // Let the kernel follow the magic-link, and then restart the walk
+            // from the root with the path of wherever it landed.
+            stats.proc_checks += 1;
+            let (relpath, landed_id) = follow_magic_link(&current, &name, root, &expected_path)
+                .wrap("follow magic-link inside root")?;
+            magic_link_target = Some((components.len(), landed_id));
+            buffers.push_front(relpath.into_os_string(), &mut components);
+            current = root.try_clone_hotfix().wrap("dup root as next current")?;
+            expected_path = PathBuf::from(Component::RootDir.as_os_str());
+            chain.truncate(1);
+            parent = None;
+            continue;
         }
 
         // XXX: There is currently no way for us to use next to get the
@@ -258,33 +600,91 @@ pub(crate) fn resolve<P: AsRef<Path>>(
         //      path to the symlink. However, since readlink(2) doesn't follow
         //      symlink components we can just do it manually safely.
         let contents =
-            syscalls::readlinkat(current.as_raw_fd(), part).context(error::RawOsError {
+            syscalls::readlinkat(current.as_raw_fd(), &name).context(error::RawOsError {
                 operation: "readlink next symlink component",
             })?;
 
         // Add contents of the symlink to the set of components we are looping
-        // over. The
-        contents
-            .components()
-            .map(|p| PathBuf::from(p.as_os_str()))
-            // VecDeque doesn't have an amortized way of prepending a Vec, so we
-            // need to do this manually. We need to rev() the iterator since
-            // we're pushing to the front each time.
-            .rev()
-            .for_each(|p| components.push_front(p));
+        // over.
+        let is_absolute = contents.is_absolute();
+        buffers.push_front(contents.into_os_string(), &mut components);
 
         // Remove our tentative expected_path contents. They will be filled on
         // later iterations. If the path is absolute we need to reset our
-        // current back to the root.
+        // current (and expected_path) back to the root.
         expected_path.pop();
-        if contents.is_absolute() {
+        if is_absolute {
             current = root.try_clone_hotfix().wrap("dup root as next current")?;
+            expected_path = PathBuf::from(Component::RootDir.as_os_str());
+            chain.truncate(1);
+            parent = None;
         }
     }
 
     // Make sure that the path is what we expect...
-    check_current(&current, root, &expected_path).wrap("check final handle didn't escape")?;
+    stats.proc_checks += 1;
+    match check_current(&current, root, &expected_path) {
+        // readlink(/proc/self/fd/$n) fails for paths longer than PATH_MAX, so
+        // we have to verify the handle with the directory chain instead.
+        Err(err) if err.raw_os_error() == Some(libc::ENAMETOOLONG) => {
+            check_current_chain(&current, parent.as_ref(), &chain)
+        }
+        ret => ret,
+    }
+    .wrap("check final handle didn't escape")?;
 
     // Everything is Kosher here -- convert to a handle.
     Ok(Handle::from_file_unchecked(current))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::resolve;
+    use crate::{
+        tests::{count_allocations, TempDir},
+        ResolverFlags,
+    };
+
+    use std::{
+        fs::{self, File},
+        path::PathBuf,
+    };
+
+    #[test]
+    fn resolve_allocations_per_component() {
+        let tmpdir = TempDir::new();
+        let mut deep = PathBuf::new();
+        for i in 0..32 {
+            deep.push(format!("dir{}", i));
+        }
+        fs::create_dir_all(tmpdir.path().join(&deep)).unwrap();
+        let root = File::open(tmpdir.path()).unwrap();
+
+        let count = |path: &PathBuf| {
+            let (ret, count) = count_allocations(|| {
+                resolve(
+                    &root,
+                    path,
+                    ResolverFlags::empty(),
+                    &Default::default(),
+                    &mut Default::default(),
+                )
+            });
+            ret.unwrap();
+            count
+        };
+        // Warm up any lazily-initialised state (such as the procfs handle).
+        count(&deep);
+        let shallow = count(&PathBuf::from("dir0"));
+        let deep = count(&deep);
+        // The components are not copied into their own buffers, so walking 31
+        // more of them only costs a few allocations (for growing the
+        // resolver's bookkeeping).
+        assert!(
+            deep < shallow + 8,
+            "resolving 32 components made {} allocations, 1 component made {}",
+            deep,
+            shallow
+        );
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{error::Error, opstats};
+
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How libpathrs retries syscalls which fail with transient errors.
+///
+/// Syscalls which fail with `EINTR` (which can happen on NFS and FUSE
+/// filesystems if a signal arrives mid-operation) are always retried, and this
+/// cannot be disabled. `openat2(2)` lookups which fail with `EAGAIN` (which
+/// happens if there was a racing rename or mount *anywhere on the system*) are
+/// retried up to `max_eagain_retries` times, after which the lookup falls back
+/// to the userspace resolver. The first such retry is immediate, and the delay
+/// before each subsequent retry starts at `initial_backoff` and doubles up to
+/// `max_backoff`.
+///
+/// The policy is global to the process, and can be changed with
+/// [`set_retry_policy`].
+///
+/// [`set_retry_policy`]: fn.set_retry_policy.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of times an `openat2(2)` lookup is retried after failing
+    /// with `EAGAIN`.
+    pub max_eagain_retries: u32,
+    /// Delay before the second `EAGAIN` retry.
+    pub initial_backoff: Duration,
+    /// Maximum delay between `EAGAIN` retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_eagain_retries: 16,
+            initial_backoff: Duration::from_micros(10),
+            max_backoff: Duration::from_millis(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the `attempt`-th (starting at `1`) `EAGAIN` retry.
+    pub(crate) fn backoff(&self, attempt: u32) {
+        if attempt < 2 {
+            return;
+        }
+        let shift = cmp::min(attempt - 2, 31);
+        let delay = self
+            .initial_backoff
+            .checked_mul(1 << shift)
+            .unwrap_or(self.max_backoff);
+        thread::sleep(cmp::min(delay, self.max_backoff));
+    }
+}
+
+lazy_static! {
+    static ref RETRY_POLICY: RwLock<RetryPolicy> = RwLock::new(RetryPolicy::default());
+}
+
+/// Get the current [`RetryPolicy`] of libpathrs.
+///
+/// [`RetryPolicy`]: struct.RetryPolicy.html
+pub fn retry_policy() -> RetryPolicy {
+    *RETRY_POLICY.read().unwrap()
+}
+
+/// Change the [`RetryPolicy`] of libpathrs. This affects every operation
+/// (including those running in other threads) started after the change.
+///
+/// [`RetryPolicy`]: struct.RetryPolicy.html
+pub fn set_retry_policy(policy: RetryPolicy) {
+    *RETRY_POLICY.write().unwrap() = policy;
+}
+
+bitflags! {
+    /// The errors retried by a [`GracePolicy`].
+    ///
+    /// [`GracePolicy`]: struct.GracePolicy.html
+    pub struct GraceErrors: u32 {
+        /// `ENOENT`, which can happen on network filesystems if a freshly
+        /// created directory is not yet visible to lookups.
+        const ENOENT = 1 << 0;
+        /// `ESTALE`, which can happen on NFS if a directory was replaced on
+        /// the server.
+        const ESTALE = 1 << 1;
+    }
+}
+
+impl Default for GraceErrors {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// A bounded retry policy for lookups in multi-step operations of a [`Root`]
+/// (such as looking up the parent directory in [`Root::create`], which may
+/// have just been created by the caller).
+///
+/// On network filesystems, a freshly created directory is occasionally not
+/// visible to the next lookup for a moment. Rather than surfacing these
+/// spurious errors to callers, lookups which fail with one of `errors` are
+/// retried up to `max_retries` times, waiting `delay` before each retry.
+/// Since these errors are usually not spurious, nothing is retried by default.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::create`]: struct.Root.html#method.create
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GracePolicy {
+    /// The errors which are retried.
+    pub errors: GraceErrors,
+    /// Maximum number of retries of a single lookup.
+    pub max_retries: u32,
+    /// Delay before each retry.
+    pub delay: Duration,
+}
+
+impl Default for GracePolicy {
+    fn default() -> Self {
+        Self {
+            errors: GraceErrors::empty(),
+            max_retries: 0,
+            delay: Duration::from_millis(10),
+        }
+    }
+}
+
+impl GracePolicy {
+    fn should_retry(&self, err: &Error) -> bool {
+        match err.raw_os_error() {
+            Some(libc::ENOENT) => self.errors.contains(GraceErrors::ENOENT),
+            Some(libc::ESTALE) => self.errors.contains(GraceErrors::ESTALE),
+            _ => false,
+        }
+    }
+
+    /// Run `lookup`, retrying it according to the policy.
+    pub(crate) fn retry<T, F: FnMut() -> Result<T, Error>>(
+        &self,
+        mut lookup: F,
+    ) -> Result<T, Error> {
+        let mut retries = 0;
+        loop {
+            match lookup() {
+                Err(err) if retries < self.max_retries && self.should_retry(&err) => {
+                    retries += 1;
+                    record_grace_retry();
+                    thread::sleep(self.delay);
+                }
+                ret => return ret,
+            }
+        }
+    }
+}
+
+static EINTR_RETRIES: AtomicU64 = AtomicU64::new(0);
+static EAGAIN_RETRIES: AtomicU64 = AtomicU64::new(0);
+static EAGAIN_FALLBACKS: AtomicU64 = AtomicU64::new(0);
+static GRACE_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the transient errors libpathrs has retried (see
+/// [`RetryPolicy`]), for observing contention.
+///
+/// [`RetryPolicy`]: struct.RetryPolicy.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Number of syscalls retried after failing with `EINTR`.
+    pub eintr_retries: u64,
+    /// Number of `openat2(2)` lookups retried after failing with `EAGAIN`.
+    pub eagain_retries: u64,
+    /// Number of `openat2(2)` lookups which fell back to the userspace resolver
+    /// after running out of `EAGAIN` retries.
+    pub eagain_fallbacks: u64,
+    /// Number of lookups retried by a [`GracePolicy`].
+    ///
+    /// [`GracePolicy`]: struct.GracePolicy.html
+    pub grace_retries: u64,
+}
+
+/// Get the process-wide [`RetryStats`] since the process started (or since
+/// the last [`reset_retry_stats`]).
+///
+/// [`RetryStats`]: struct.RetryStats.html
+/// [`reset_retry_stats`]: fn.reset_retry_stats.html
+pub fn retry_stats() -> RetryStats {
+    RetryStats {
+        eintr_retries: EINTR_RETRIES.load(Ordering::Relaxed),
+        eagain_retries: EAGAIN_RETRIES.load(Ordering::Relaxed),
+        eagain_fallbacks: EAGAIN_FALLBACKS.load(Ordering::Relaxed),
+        grace_retries: GRACE_RETRIES.load(Ordering::Relaxed),
+    }
+}
+
+/// Reset the process-wide [`RetryStats`] to zero.
+///
+/// [`RetryStats`]: struct.RetryStats.html
+pub fn reset_retry_stats() {
+    EINTR_RETRIES.store(0, Ordering::Relaxed);
+    EAGAIN_RETRIES.store(0, Ordering::Relaxed);
+    EAGAIN_FALLBACKS.store(0, Ordering::Relaxed);
+    GRACE_RETRIES.store(0, Ordering::Relaxed);
+}
+
+pub(crate) fn record_eintr_retry() {
+    EINTR_RETRIES.fetch_add(1, Ordering::Relaxed);
+    opstats::record_retry();
+}
+
+pub(crate) fn record_eagain_retry() {
+    EAGAIN_RETRIES.fetch_add(1, Ordering::Relaxed);
+    opstats::record_retry();
+}
+
+pub(crate) fn record_eagain_fallback() {
+    EAGAIN_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_grace_retry() {
+    GRACE_RETRIES.fetch_add(1, Ordering::Relaxed);
+    opstats::record_retry();
+}
@@ -19,17 +19,28 @@
 #![forbid(unsafe_code)]
 
 use crate::{
+    cache::{self, NegativeCache, NegativeKey, ResolveCache},
     error::{self, Error, ErrorExt},
-    resolvers::Resolver,
-    syscalls,
-    utils::RawFdExt,
-    Handle,
+    hardening, metrics,
+    resolvers::{kernel, ResolveStatsCounters, Resolver},
+    stat, syscalls,
+    utils::{self, FileExt, RawFdExt},
+    DevicePolicy, DeviceType, Dirents, FallbackPolicy, FilenameValidator, GracePolicy, Handle,
+    ModePolicy, OpenFlags, Openat2Support, ReopenPolicy, ResolutionBudget, ResolveStats,
+    ResolverBackend, Throttle,
 };
 
 use std::{
     fs::{File, Permissions},
-    os::unix::{ffi::OsStrExt, fs::PermissionsExt, io::AsRawFd},
-    path::Path,
+    io::Error as IOError,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use libc::dev_t;
@@ -89,18 +100,44 @@ pub enum InodeType<'a> {
     ///
     /// [`mknod(2)`]: http://man7.org/linux/man-pages/man2/mknod.2.html
     BlockDevice(&'a Permissions, dev_t),
-    // XXX: Does this really make sense?
-    //// "Detached" unix socket, as in [`mknod(2)`] with `S_IFSOCK`.
-    ////
-    //// [`mknod(2)`]: http://man7.org/linux/man-pages/man2/mknod.2.html
-    //DetachedSocket(),
+
+    /// "Detached" unix socket, as in [`mknod(2)`] with `S_IFSOCK`.
+    ///
+    /// The socket inode is not bound to any socket (so connecting to it will
+    /// fail with `-ECONNREFUSED`). This is only really useful for reproducing
+    /// filesystem images which contain sockets, such as when restoring a
+    /// backup.
+    ///
+    /// [`mknod(2)`]: http://man7.org/linux/man-pages/man2/mknod.2.html
+    DetachedSocket(&'a Permissions),
+}
+
+impl InodeType<'_> {
+    /// The permissions of the new inode, if the inode type has any.
+    pub(crate) fn permissions(&self) -> Option<&Permissions> {
+        match self {
+            InodeType::File(perm)
+            | InodeType::Directory(perm)
+            | InodeType::Fifo(perm)
+            | InodeType::CharacterDevice(perm, _)
+            | InodeType::BlockDevice(perm, _)
+            | InodeType::DetachedSocket(perm) => Some(perm),
+            InodeType::Symlink(_) | InodeType::Hardlink(_) => None,
+        }
+    }
 }
 
 /// Helper to split a Path into its parent directory and trailing path. The
 /// trailing component is guaranteed to not contain a directory separator.
-fn path_split(path: &'_ Path) -> Result<(&'_ Path, &'_ Path), Error> {
-    // Get the parent path.
-    let parent = path.parent().unwrap_or_else(|| "/".as_ref());
+pub(crate) fn path_split(path: &'_ Path) -> Result<(&'_ Path, &'_ Path), Error> {
+    // Get the parent path. Single-component relative paths have an empty
+    // parent, which is the root (like "/").
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| "/".as_ref());
+
+    utils::check_no_nul("path", path)?;
 
     // Now construct the trailing portion of the target.
     let name = path.file_name().context(error::InvalidArgument {
@@ -127,11 +164,13 @@ fn path_split(path: &'_ Path) -> Result<(&'_ Path, &'_ Path), Error> {
 /// [`renameat2(2)`] might not not be supported on your kernel -- in which
 /// case [`Root::rename`] will fail if you specify any RenameFlags. You can
 /// verify whether [`renameat2(2)`] flags are supported by calling
-/// [`RenameFlags::supported`].
+/// [`RenameFlags::supported`]. Support for the individual flags also depends
+/// on the filesystem, which can be checked with [`RenameFlags::supported_on`].
 ///
 /// [`renameat2(2)`]: http://man7.org/linux/man-pages/man2/rename.2.html
 /// [`Root::rename`]: struct.Root.html#method.rename
 /// [`RenameFlags::supported`]: struct.RenameFlags.html#method.supported
+/// [`RenameFlags::supported_on`]: struct.RenameFlags.html#method.supported_on
 // TODO: Switch to bitflags!.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct RenameFlags(pub u32);
@@ -143,6 +182,38 @@ impl RenameFlags {
     }
 }
 
+/// A path inside a [`Root`] which was correct at some point during the
+/// execution of the method which returned it.
+///
+/// Paths are inherently racy -- as soon as the path has been computed, an
+/// attacker (or any other process) could rename one of its components and the
+/// path would then refer to a different inode (or none at all). This wrapper
+/// exists to make that fact explicit in the API, and you should only ever use
+/// the contained path for informational purposes (or as an argument for
+/// another operation through the same [`Root`], such as [`Root::resolve`]).
+///
+/// The contained path is always absolute, and is relative to the [`Root`] (so
+/// `/` refers to the [`Root`] itself).
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::resolve`]: struct.Root.html#method.resolve
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnverifiedPath(PathBuf);
+
+impl UnverifiedPath {
+    /// Get the (unverified) path.
+    pub fn as_unverified_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Unwrap the [`UnverifiedPath`] to get the (unverified) path.
+    ///
+    /// [`UnverifiedPath`]: struct.UnverifiedPath.html
+    pub fn into_unverified_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
 /// A handle to the root of a directory tree.
 ///
 /// # Safety
@@ -173,6 +244,12 @@ pub struct Root {
     /// The underlying `O_PATH` `File` for this root handle.
     pub(crate) inner: File,
 
+    /// The (st_dev, st_ino) of `inner` when the root was created (or `None` if
+    /// `inner` could not be stat-ed), used by [`Root::check`].
+    ///
+    /// [`Root::check`]: #method.check
+    identity: Option<(u64, u64)>,
+
     /// The underlying [`Resolver`] to use for all operations underneath this
     /// root. This affects not just [`Root::resolve`] but also all other methods
     /// which have to implicitly resolve a path underneath `Root`.
@@ -181,6 +258,114 @@ pub struct Root {
     /// [`Root::resolve`]: #method.resolve
     // TODO: Drop this and switch to builder-pattern...
     pub resolver: Resolver,
+
+    /// The [`FilenameValidator`] applied to the names of all new inodes
+    /// created underneath this root. By default no validation is done (aside
+    /// from the restrictions imposed by the kernel).
+    ///
+    /// [`FilenameValidator`]: trait.FilenameValidator.html
+    pub filename_validator: Option<Arc<dyn FilenameValidator>>,
+
+    /// The [`DevicePolicy`] restricting which device inodes can be created
+    /// underneath this root with [`Root::create`]. By default all devices are
+    /// allowed.
+    ///
+    /// [`DevicePolicy`]: enum.DevicePolicy.html
+    /// [`Root::create`]: #method.create
+    pub device_policy: DevicePolicy,
+
+    /// The [`ModePolicy`] applied to the modes of all inodes created (or
+    /// chmod-ed) underneath this root. By default all mode bits are allowed.
+    ///
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    pub mode_policy: ModePolicy,
+
+    /// The [`ReopenPolicy`] restricting which inode types underneath this
+    /// root can be upgraded to a usable file with [`Root::reopen`]. By default
+    /// all inode types can be re-opened.
+    ///
+    /// [`ReopenPolicy`]: struct.ReopenPolicy.html
+    /// [`Root::reopen`]: #method.reopen
+    pub reopen_policy: ReopenPolicy,
+
+    /// The [`Throttle`] applied to all recursive operations underneath this
+    /// root (such as [`Root::walk`]). By default nothing is throttled.
+    ///
+    /// [`Throttle`]: struct.Throttle.html
+    /// [`Root::walk`]: #method.walk
+    pub throttle: Throttle,
+
+    /// The [`GracePolicy`] applied to the lookups of parent directories in
+    /// multi-step operations underneath this root (such as [`Root::create`]).
+    /// By default nothing is retried.
+    ///
+    /// [`GracePolicy`]: struct.GracePolicy.html
+    /// [`Root::create`]: #method.create
+    pub grace_policy: GracePolicy,
+
+    /// The [`FallbackPolicy`] applied to high-level helpers underneath this
+    /// root (such as [`Root::copy_file`]). By default unsupported strategies
+    /// are silently replaced with a fallback.
+    ///
+    /// [`FallbackPolicy`]: enum.FallbackPolicy.html
+    /// [`Root::copy_file`]: #method.copy_file
+    pub fallback_policy: FallbackPolicy,
+
+    /// The [`ResolutionBudget`] of every resolution underneath this root which
+    /// uses the emulated backend (including fallbacks from the native
+    /// backend). By default resolutions are not limited.
+    ///
+    /// [`ResolutionBudget`]: struct.ResolutionBudget.html
+    pub resolution_budget: ResolutionBudget,
+
+    /// The maximum number of resolutions kept in the resolution cache of this
+    /// root, or `0` to disable the cache (the default).
+    ///
+    /// With the cache enabled, [`Root::resolve`] (and every method which
+    /// resolves paths) remembers the handles that paths resolved to. A cached
+    /// handle is only returned after checking with `/proc/self/fd` (and by
+    /// walking its canonical path without following symlinks) that it is
+    /// still the same inode at the same path inside the root, which is usually
+    /// much cheaper than resolving the path again with the emulated backend. Entries are invalidated by renames and
+    /// removals done through this root (and by
+    /// [`Root::invalidate_resolve_cache`]), but not by changes made to
+    /// symlinks in the path by other processes.
+    ///
+    /// [`Root::resolve`]: #method.resolve
+    /// [`Root::invalidate_resolve_cache`]: #method.invalidate_resolve_cache
+    pub resolve_cache_capacity: usize,
+
+    /// The maximum number of failed lookups kept in the negative cache of
+    /// this root, or `0` to disable the cache (the default).
+    ///
+    /// With the cache enabled, resolutions which fail because the final
+    /// component of the path doesn't exist are remembered (keyed by the
+    /// identity of the parent directory and the name of the final component).
+    /// Resolving the same path again then fails with `ENOENT` after only
+    /// resolving the parent directory, which is itself cheap with
+    /// [`Root::resolve_cache_capacity`]. Entries expire after
+    /// [`Root::negative_cache_ttl`] and are dropped when an inode with the
+    /// same name is created through this root, but inodes created by other
+    /// processes are only noticed once the entry has expired.
+    ///
+    /// [`Root::resolve_cache_capacity`]: #structfield.resolve_cache_capacity
+    /// [`Root::negative_cache_ttl`]: #structfield.negative_cache_ttl
+    pub negative_cache_capacity: usize,
+
+    /// How long entries of the negative cache (see
+    /// [`Root::negative_cache_capacity`]) are trusted. Defaults to one second.
+    ///
+    /// [`Root::negative_cache_capacity`]: #structfield.negative_cache_capacity
+    pub negative_cache_ttl: Duration,
+
+    /// Cache of resolutions when `resolve_cache_capacity` is non-zero.
+    cache: ResolveCache,
+
+    /// Cache of failed lookups when `negative_cache_capacity` is non-zero.
+    negative_cache: NegativeCache,
+
+    /// Aggregated statistics of resolutions through this root.
+    stats: ResolveStatsCounters,
 }
 
 impl Root {
@@ -200,6 +385,8 @@ impl Root {
     /// [`Root`]: struct.Root.html
     /// [`Resolver`]: struct.Resolver.html
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        utils::check_no_nul("path", path)?;
         let file = syscalls::openat(libc::AT_FDCWD, path, libc::O_PATH | libc::O_DIRECTORY, 0)
             .context(error::RawOsError {
                 operation: "open root handle",
@@ -207,6 +394,135 @@ impl Root {
         Ok(Root::from_file_unchecked(file))
     }
 
+    /// Open a [`Root`] handle backed by an `O_RDONLY | O_DIRECTORY` file
+    /// descriptor, rather than an `O_PATH` one.
+    ///
+    /// This is identical to [`Root::open`], except that the underlying file
+    /// descriptor (returned by [`Root::into_file`]) can be used directly with
+    /// `fsync(2)` or `getdents64(2)`. This requires read access to the
+    /// directory, and (unlike `O_PATH`) opening the directory can trigger
+    /// automounts.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::open`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open`]: struct.Root.html#method.open
+    /// [`Root::into_file`]: struct.Root.html#method.into_file
+    pub fn open_directory<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        utils::check_no_nul("path", path)?;
+        let file = syscalls::openat(libc::AT_FDCWD, path, libc::O_RDONLY | libc::O_DIRECTORY, 0)
+            .context(error::RawOsError {
+                operation: "open root handle",
+            })?;
+        Ok(Root::from_file_unchecked(file))
+    }
+
+    /// Verify that the file descriptor of this [`Root`] still refers to the
+    /// directory it was created with.
+    ///
+    /// Consumers which share the file descriptor (in particular through the C
+    /// API) can close it or `dup2(2)` another file over it, after which
+    /// operations would silently act on an unrelated file. Every resolution
+    /// does this check first (at the cost of one `fstat(2)`), so it only needs
+    /// to be called explicitly before operations which don't resolve a path.
+    ///
+    /// # Errors
+    ///
+    /// If the file descriptor is closed or refers to a different inode, an
+    /// [`Error::StaleRoot`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::StaleRoot`]: error/enum.Error.html#variant.StaleRoot
+    pub fn check(&self) -> Result<(), Error> {
+        let fd = self.inner.as_raw_fd();
+        let current = match self.inner.inode_id() {
+            Ok(current) => current,
+            Err(err) => {
+                return error::StaleRoot {
+                    fd,
+                    description: format!("cannot be stat-ed: {}", err.root_cause()),
+                }
+                .fail()
+            }
+        };
+        match self.identity {
+            Some(identity) if identity == current => Ok(()),
+            Some((dev, ino)) => error::StaleRoot {
+                fd,
+                description: format!(
+                    "now refers to inode {} on device {} instead of inode {} on device {}",
+                    current.1, current.0, ino, dev
+                ),
+            }
+            .fail(),
+            None => error::StaleRoot {
+                fd,
+                description: "could not be stat-ed when the root was created",
+            }
+            .fail(),
+        }
+    }
+
+    /// Flush any changes to the root directory itself (such as newly created
+    /// or removed entries) to disk, as with `fsync(2)`.
+    ///
+    /// `O_PATH` file descriptors cannot be synced, so for [`Root`]s not opened
+    /// with [`Root::open_directory`] a temporary `O_RDONLY` file descriptor for
+    /// the root directory is opened.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open_directory`]: struct.Root.html#method.open_directory
+    pub fn sync(&self) -> Result<(), Error> {
+        let flags = syscalls::fcntl_getfl(self.inner.as_raw_fd()).context(error::RawOsError {
+            operation: "get root handle flags",
+        })?;
+        let reopened;
+        let dir = if flags & libc::O_PATH == 0 {
+            &self.inner
+        } else {
+            // Opening "." relative to the root cannot leave the root.
+            reopened = syscalls::openat(
+                self.inner.as_raw_fd(),
+                ".",
+                libc::O_RDONLY | libc::O_DIRECTORY,
+                0,
+            )
+            .context(error::RawOsError {
+                operation: "reopen root directory for syncing",
+            })?;
+            &reopened
+        };
+        dir.sync_all().context(error::OsError {
+            operation: "sync root directory",
+        })
+    }
+
+    /// Re-probe whether `openat2(2)` is usable (with a lookup relative to this
+    /// [`Root`]), and switch `Root.resolver` to the best supported backend.
+    ///
+    /// Support for `openat2(2)` is probed once per process and cached, which
+    /// can be wrong if a seccomp profile is applied (or changed) after the
+    /// probe -- and then every [`Root`] using the default [`Resolver`] uses the
+    /// wrong backend. This updates the cached result (and thus the default
+    /// [`Resolver`] of new [`Root`]s) as well. Regardless of the cached result,
+    /// resolutions which hit an `ENOSYS` or `EPERM` from `openat2(2)` fall back
+    /// to the emulated backend.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Resolver`]: struct.Resolver.html
+    pub fn reprobe_resolver(&mut self) -> Openat2Support {
+        let support = kernel::probe(self.inner.as_raw_fd(), ".");
+        self.resolver.backend = if support == Openat2Support::Supported {
+            ResolverBackend::Kernel
+        } else {
+            ResolverBackend::Emulated
+        };
+        support
+    }
+
     /// Create a copy of an existing [`Root`].
     ///
     /// The new handle is completely independent from the original, but
@@ -216,7 +532,22 @@ impl Root {
     pub fn try_clone(&self) -> Result<Self, Error> {
         Ok(Self {
             inner: self.inner.try_clone_hotfix()?,
+            identity: self.identity,
             resolver: self.resolver,
+            filename_validator: self.filename_validator.clone(),
+            device_policy: self.device_policy.clone(),
+            mode_policy: self.mode_policy,
+            reopen_policy: self.reopen_policy,
+            throttle: self.throttle,
+            grace_policy: self.grace_policy,
+            fallback_policy: self.fallback_policy,
+            resolution_budget: self.resolution_budget,
+            resolve_cache_capacity: self.resolve_cache_capacity,
+            negative_cache_capacity: self.negative_cache_capacity,
+            negative_cache_ttl: self.negative_cache_ttl,
+            cache: Default::default(),
+            negative_cache: Default::default(),
+            stats: Default::default(),
         })
     }
 
@@ -235,9 +566,10 @@ impl Root {
     ///
     /// # Safety
     ///
-    /// The caller guarantees that the provided file is an `O_PATH` file
-    /// descriptor with exactly the same semantics as one created through
-    /// [`Root::open`]. This means that this function should usually be used to
+    /// The caller guarantees that the provided file is an `O_PATH` (or
+    /// `O_RDONLY | O_DIRECTORY`) file descriptor with exactly the same
+    /// semantics as one created through [`Root::open`] (or
+    /// [`Root::open_directory`]). This means that this function should usually be used to
     /// convert a [`File`] returned from [`Root::into_file`] (possibly from
     /// another process) into a [`Root`].
     ///
@@ -249,17 +581,79 @@ impl Root {
     /// [`Root`]: struct.Root.html
     /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
     /// [`Root::open`]: struct.Root.html#method.open
+    /// [`Root::open_directory`]: struct.Root.html#method.open_directory
     /// [`Root::into_file`]: struct.Root.html#method.into_file
     // TODO: We should probably have a `Root::from_file` which attempts to
     //       re-open the path with `O_PATH | O_DIRECTORY`, to allow for an
     //       alternative to `Root::open`.
     pub fn from_file_unchecked(inner: File) -> Self {
         Self {
+            identity: inner.inode_id().ok(),
             inner,
             resolver: Default::default(),
+            filename_validator: None,
+            device_policy: Default::default(),
+            mode_policy: Default::default(),
+            reopen_policy: Default::default(),
+            throttle: Default::default(),
+            grace_policy: Default::default(),
+            fallback_policy: Default::default(),
+            resolution_budget: Default::default(),
+            resolve_cache_capacity: 0,
+            negative_cache_capacity: 0,
+            negative_cache_ttl: Duration::from_secs(1),
+            cache: Default::default(),
+            negative_cache: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Check that `name` is acceptable according to the configured
+    /// `filename_validator` (if any).
+    pub(crate) fn validate_name(&self, name: &Path) -> Result<(), Error> {
+        if let Some(ref validator) = self.filename_validator {
+            if let Err(reason) = validator.validate(name.as_os_str()) {
+                return error::InvalidArgument {
+                    name: "path",
+                    description: format!("name {:?} rejected by filename policy: {}", name, reason),
+                }
+                .fail();
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the configured `mode_policy` to the permission bits of `mode`
+    /// (any `S_IFMT` bits are cleared).
+    pub(crate) fn sanitize_mode(&self, mode: libc::mode_t) -> Result<libc::mode_t, Error> {
+        let mode = mode & !libc::S_IFMT;
+        match self.mode_policy.apply(mode) {
+            Ok(mode) => Ok(mode),
+            Err(reason) => error::InvalidArgument {
+                name: "mode",
+                description: format!("mode 0o{:o} rejected by mode policy: {}", mode, reason),
+            }
+            .fail(),
         }
     }
 
+    /// Check that creating the given device is permitted by the configured
+    /// `device_policy`.
+    pub(crate) fn check_device(&self, device_type: DeviceType, dev: dev_t) -> Result<(), Error> {
+        let (major, minor) = (libc::major(dev), libc::minor(dev));
+        ensure!(
+            self.device_policy.allows(device_type, major, minor),
+            error::InvalidArgument {
+                name: "inode_type",
+                description: format!(
+                    "{:?} device {}:{} rejected by device policy",
+                    device_type, major, minor
+                ),
+            }
+        );
+        Ok(())
+    }
+
     /// Within the given [`Root`]'s tree, resolve `path` and return a
     /// [`Handle`]. All symlink path components are scoped to [`Root`].
     ///
@@ -269,12 +663,294 @@ impl Root {
     /// corresponding Error will be returned. If no error is returned, then the
     /// path is guaranteed to have been reachable from the root of the directory
     /// tree and thus have been inside the root at one point in the resolution.
+    /// If a component in the middle of `path` is not a directory, an
+    /// [`Error::NotADirectory`] naming that component is returned.
     ///
     /// [`Root`]: struct.Root.html
     /// [`Handle`]: trait.Handle.html
+    /// [`Error::NotADirectory`]: error/enum.Error.html#variant.NotADirectory
     #[inline]
     pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Result<Handle, Error> {
-        self.resolver.resolve(&self.inner, path)
+        self.resolve_with_stats(path).map(|(handle, _)| handle)
+    }
+
+    /// Identical to [`Root::resolve`], except that statistics about the work
+    /// done by the resolution are also returned. The statistics are also
+    /// added to [`Root::resolve_stats`] (even if the resolution fails).
+    ///
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Root::resolve_stats`]: struct.Root.html#method.resolve_stats
+    pub fn resolve_with_stats<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<(Handle, ResolveStats), Error> {
+        self.check()?;
+        let path = path.as_ref();
+        let mut stats = ResolveStats::default();
+        let ret = self
+            .resolve_with_negative_cache(path, &mut stats)
+            .and_then(|handle| {
+                if hardening::hardening_overrides().paranoid {
+                    stats.proc_checks += 1;
+                    self.relative_path_of(&handle)
+                        .wrap("paranoid verification of resolved handle")?;
+                }
+                Ok(handle)
+            });
+        self.stats.add(&stats);
+        ret.map(|handle| (handle, stats))
+    }
+
+    /// Resolve `path`, using the resolution cache if it is enabled.
+    fn resolve_with_cache(&self, path: &Path, stats: &mut ResolveStats) -> Result<Handle, Error> {
+        let key = if self.resolve_cache_capacity > 0 {
+            Some(cache::cache_key(path, self.resolver.flags))
+        } else {
+            self.cache.clear();
+            None
+        };
+        let cached = key.as_ref().and_then(|key| {
+            let handle = self.cache.lookup(&self.inner, key)?;
+            stats.resolutions += 1;
+            stats.cache_hits += 1;
+            stats.proc_checks += 1;
+            Some(handle)
+        });
+        match cached {
+            Some(handle) => Ok(handle),
+            None => self
+                .resolver
+                .resolve(&self.inner, path, &self.resolution_budget, stats)
+                .inspect(|handle| {
+                    if let Some(key) = key {
+                        self.cache
+                            .insert(&self.inner, key, handle, self.resolve_cache_capacity);
+                    }
+                }),
+        }
+    }
+
+    /// Resolve `path`, failing early if the lookup is in the negative cache
+    /// and remembering the lookup if the final component doesn't exist.
+    fn resolve_with_negative_cache(
+        &self,
+        path: &Path,
+        stats: &mut ResolveStats,
+    ) -> Result<Handle, Error> {
+        if self.negative_cache_capacity == 0 {
+            self.negative_cache.clear();
+            return self.resolve_with_cache(path, stats);
+        }
+        let cached = path
+            .file_name()
+            .filter(|name| self.negative_cache.contains_name(name))
+            .and_then(|_| self.negative_key(path))
+            .is_some_and(|(_, key)| self.negative_cache.lookup(&key, self.negative_cache_ttl));
+        if cached {
+            stats.resolutions += 1;
+            stats.negative_hits += 1;
+            return Err(IOError::from_raw_os_error(libc::ENOENT)).context(error::OsError {
+                operation: "pathrs resolve (cached failed lookup)",
+            });
+        }
+        let ret = self.resolve_with_cache(path, stats);
+        if let Err(ref err) = ret {
+            if err.raw_os_error() == Some(libc::ENOENT) {
+                self.remember_failed_lookup(path);
+            }
+        }
+        ret
+    }
+
+    /// Resolve the parent directory of `path` and get the negative cache key
+    /// of `path`.
+    fn negative_key(&self, path: &Path) -> Option<(Handle, NegativeKey)> {
+        let (parent, name) = path_split(path).ok()?;
+        let parent = self.resolve(parent).ok()?;
+        let key = cache::negative_key(&parent, name, self.resolver.flags).ok()?;
+        Some((parent, key))
+    }
+
+    /// Add the failed lookup of `path` to the negative cache. Only lookups of
+    /// names which don't exist at all are remembered (not dangling symlinks
+    /// or paths with missing intermediate components), since creating the
+    /// name through this root is what invalidates the entry.
+    fn remember_failed_lookup(&self, path: &Path) {
+        let (parent, key) = match self.negative_key(path) {
+            Some(found) => found,
+            None => return,
+        };
+        match syscalls::fstatat(parent.inner.as_raw_fd(), &key.1) {
+            Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => self
+                .negative_cache
+                .insert(key, self.negative_cache_ttl, self.negative_cache_capacity),
+            _ => (),
+        }
+    }
+
+    /// Invalidate the entries of both caches which might be affected by a
+    /// change to `path`.
+    pub(crate) fn invalidate_caches(&self, path: &Path) {
+        self.cache.invalidate(path);
+        if let Some(name) = path.file_name() {
+            self.negative_cache.invalidate(name);
+        }
+    }
+
+    /// Get the aggregated [`ResolveStats`] of every resolution done through
+    /// this [`Root`] (including implicit resolutions by other methods) since
+    /// it was opened (or since the last [`Root::reset_resolve_stats`]).
+    ///
+    /// [`ResolveStats`]: struct.ResolveStats.html
+    /// [`Root`]: struct.Root.html
+    /// [`Root::reset_resolve_stats`]: struct.Root.html#method.reset_resolve_stats
+    pub fn resolve_stats(&self) -> ResolveStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset the aggregated [`ResolveStats`] of this [`Root`] to zero.
+    ///
+    /// [`ResolveStats`]: struct.ResolveStats.html
+    /// [`Root`]: struct.Root.html
+    pub fn reset_resolve_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Drop the entries of the resolution cache (see
+    /// [`Root::resolve_cache_capacity`]) for `path` and everything underneath
+    /// it, as well as the entries of the negative cache (see
+    /// [`Root::negative_cache_capacity`]) for its final component. This is
+    /// only needed if the path was changed without going through this
+    /// [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::resolve_cache_capacity`]: struct.Root.html#structfield.resolve_cache_capacity
+    /// [`Root::negative_cache_capacity`]: struct.Root.html#structfield.negative_cache_capacity
+    pub fn invalidate_resolve_cache<P: AsRef<Path>>(&self, path: P) {
+        self.invalidate_caches(path.as_ref());
+    }
+
+    /// Drop every entry of the resolution cache and the negative cache (see
+    /// [`Root::resolve_cache_capacity`] and [`Root::negative_cache_capacity`]).
+    ///
+    /// [`Root::resolve_cache_capacity`]: struct.Root.html#structfield.resolve_cache_capacity
+    /// [`Root::negative_cache_capacity`]: struct.Root.html#structfield.negative_cache_capacity
+    pub fn clear_resolve_cache(&self) {
+        self.cache.clear();
+        self.negative_cache.clear();
+    }
+
+    /// Within the [`Root`]'s tree, resolve `path` and open it with `flags`.
+    /// This is equivalent to [`Root::resolve`] followed by [`Root::reopen`].
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::resolve`] and [`Root::reopen`],
+    /// except that trying to open a directory with flags which are only valid
+    /// for non-directories (such as `O_WRONLY`) results in an
+    /// [`Error::IsADirectory`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Root::reopen`]: struct.Root.html#method.reopen
+    /// [`Error::IsADirectory`]: error/enum.Error.html#variant.IsADirectory
+    pub fn open_file<P: AsRef<Path>, F: Into<OpenFlags>>(
+        &self,
+        path: P,
+        flags: F,
+    ) -> Result<File, Error> {
+        let path = path.as_ref();
+        let handle = self.resolve(path).wrap("resolve path to open")?;
+        match self.reopen(&handle, flags) {
+            Err(err) if err.raw_os_error() == Some(libc::EISDIR) => {
+                Err(err).context(error::IsADirectory { path })
+            }
+            ret => ret,
+        }
+    }
+
+    /// Upgrade `handle` (which must have been resolved within the [`Root`]'s
+    /// tree) to a usable [`File`] with `flags`, as with [`Handle::reopen`],
+    /// applying the [`Root`]'s `reopen_policy`.
+    ///
+    /// # Errors
+    ///
+    /// If the inode type of `handle` is not permitted by the [`ReopenPolicy`],
+    /// an [`Error::InvalidArgument`] is returned (and the inode is not
+    /// opened). Terminals rejected by the [`ReopenPolicy`] are also an
+    /// [`Error::InvalidArgument`], but are only detected after being opened. If the re-opened file is not the same inode as `handle`, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`File`]: https://doc.rust-lang.org/std/fs/struct.File.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    /// [`ReopenPolicy`]: struct.ReopenPolicy.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn reopen<F: Into<OpenFlags>>(&self, handle: &Handle, flags: F) -> Result<File, Error> {
+        let before = handle.inner.metadata().context(error::OsError {
+            operation: "check inode type before reopen",
+        })?;
+        ensure!(
+            self.reopen_policy.allows(before.mode()),
+            error::InvalidArgument {
+                name: "handle",
+                description: format!(
+                    "inode type 0o{:o} rejected by reopen policy",
+                    before.mode() & libc::S_IFMT
+                ),
+            }
+        );
+
+        let file = handle.reopen(flags)?;
+        let after = file.metadata().context(error::OsError {
+            operation: "verify reopened file",
+        })?;
+        if (after.dev(), after.ino()) != (before.dev(), before.ino())
+            || !self.reopen_policy.allows(after.mode())
+        {
+            let ret = error::SafetyViolation {
+                description: "reopened file is not the same inode as the handle",
+            }
+            .fail();
+            if let Err(ref err) = ret {
+                metrics::record_error(err);
+            }
+            return ret;
+        }
+        // We can only tell whether a character device is a terminal once it
+        // is open. Thanks to O_NOCTTY, opening it didn't make it our
+        // controlling terminal.
+        if after.file_type().is_char_device()
+            && !self.reopen_policy.contains(ReopenPolicy::TERMINAL)
+            && syscalls::isatty(file.as_raw_fd())
+        {
+            return error::InvalidArgument {
+                name: "handle",
+                description: "terminal rejected by reopen policy",
+            }
+            .fail();
+        }
+        Ok(file)
+    }
+
+    /// Change the permissions of `handle` (which must have been resolved
+    /// within the [`Root`]'s tree), as with [`Handle::set_permissions`], after
+    /// applying the [`Root`]'s `mode_policy` to `perm`.
+    ///
+    /// # Errors
+    ///
+    /// If `perm` contains bits rejected by the [`ModePolicy`], an
+    /// [`Error::InvalidArgument`] is returned (and the permissions are not
+    /// changed).
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle::set_permissions`]: struct.Handle.html#method.set_permissions
+    /// [`ModePolicy`]: struct.ModePolicy.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn set_permissions(&self, handle: &Handle, perm: &Permissions) -> Result<(), Error> {
+        let mode = self.sanitize_mode(perm.mode())?;
+        handle.inner.set_mode(mode)
     }
 
     /// Within the [`Root`]'s tree, create an inode at `path` as specified by
@@ -298,19 +974,28 @@ impl Root {
         // the parent.
         let (parent, name) =
             path_split(path.as_ref()).wrap("split target path into (parent, name)")?;
+        self.validate_name(name)?;
+        match inode_type {
+            InodeType::CharacterDevice(_, dev) => self.check_device(DeviceType::Character, *dev)?,
+            InodeType::BlockDevice(_, dev) => self.check_device(DeviceType::Block, *dev)?,
+            _ => (),
+        }
+        let mode = match inode_type.permissions() {
+            Some(perm) => self.sanitize_mode(perm.mode())?,
+            None => 0,
+        };
         let dir = self
-            .resolve(parent)
+            .grace_policy
+            .retry(|| self.resolve(parent))
             .wrap("resolve target parent directory for inode creation")?
             .inner;
         let dirfd = dir.as_raw_fd();
 
         match inode_type {
             InodeType::File(_) => unreachable!(), /* We dealt with this above. */
-            InodeType::Directory(perm) => {
-                let mode = perm.mode() & !libc::S_IFMT;
-                syscalls::mkdirat(dirfd, name, mode)
-            }
+            InodeType::Directory(_) => syscalls::mkdirat(dirfd, name, mode),
             InodeType::Symlink(target) => {
+                utils::check_no_nul("target", target)?;
                 // I have no idea why &name is required here. it might be a
                 // compiler bug (the last argument seems to always be &&Path
                 // even if you switch around the argument order).
@@ -326,22 +1011,22 @@ impl Root {
                 let olddirfd = olddir.as_raw_fd();
                 syscalls::linkat(olddirfd, oldname, dirfd, name, 0)
             }
-            InodeType::Fifo(perm) => {
-                let mode = perm.mode() & !libc::S_IFMT;
-                syscalls::mknodat(dirfd, name, libc::S_IFIFO | mode, 0)
-            }
-            InodeType::CharacterDevice(perm, dev) => {
-                let mode = perm.mode() & !libc::S_IFMT;
+            InodeType::Fifo(_) => syscalls::mknodat(dirfd, name, libc::S_IFIFO | mode, 0),
+            InodeType::CharacterDevice(_, dev) => {
                 syscalls::mknodat(dirfd, name, libc::S_IFCHR | mode, *dev)
             }
-            InodeType::BlockDevice(perm, dev) => {
-                let mode = perm.mode() & !libc::S_IFMT;
+            InodeType::BlockDevice(_, dev) => {
                 syscalls::mknodat(dirfd, name, libc::S_IFBLK | mode, *dev)
             }
+            InodeType::DetachedSocket(_) => {
+                syscalls::mknodat(dirfd, name, libc::S_IFSOCK | mode, 0)
+            }
         }
         .context(error::RawOsError {
             operation: "pathrs create",
-        })
+        })?;
+        self.invalidate_caches(path.as_ref());
+        Ok(())
     }
 
     /// Create an [`InodeType::File`] within the [`Root`]'s tree at `path` with
@@ -381,8 +1066,11 @@ impl Root {
         // the parent.
         let (parent, name) =
             path_split(path.as_ref()).wrap("split target path into (parent, name)")?;
+        self.validate_name(name)?;
+        let mode = self.sanitize_mode(perm.mode())?;
         let dir = self
-            .resolve(parent)
+            .grace_policy
+            .retry(|| self.resolve(parent))
             .wrap("resolve target parent directory for inode creation")?
             .inner;
         let dirfd = dir.as_raw_fd();
@@ -391,10 +1079,12 @@ impl Root {
         //      O_NOFOLLOW. We might want to expose that here, though because it
         //      can't be done with the emulated backend that might be a bad
         //      idea.
-        let file = syscalls::openat(dirfd, name, libc::O_CREAT | libc::O_EXCL, perm.mode())
-            .context(error::RawOsError {
+        let file = syscalls::openat(dirfd, name, libc::O_CREAT | libc::O_EXCL, mode).context(
+            error::RawOsError {
                 operation: "pathrs create_file",
-            })?;
+            },
+        )?;
+        self.invalidate_caches(path.as_ref());
         // TODO: We should probably turn this to an `O_PATH`...
         Ok(Handle::from_file_unchecked(file))
     }
@@ -450,7 +1140,10 @@ impl Root {
             }
 
             match syscalls::unlinkat(dirfd, name, flags) {
-                Ok(_) => return Ok(()),
+                Ok(_) => {
+                    self.invalidate_caches(path.as_ref());
+                    return Ok(());
+                }
                 Err(err) => {
                     last_error = Some(err);
                     continue;
@@ -486,6 +1179,7 @@ impl Root {
             path_split(source.as_ref()).wrap("split source path into (parent, name)")?;
         let (dst_parent, dst_name) =
             path_split(destination.as_ref()).wrap("split target path into (parent, name)")?;
+        self.validate_name(dst_name)?;
 
         let src_dir = self
             .resolve(src_parent)
@@ -502,13 +1196,866 @@ impl Root {
             error::RawOsError {
                 operation: "pathrs rename",
             },
+        )?;
+        self.invalidate_caches(source.as_ref());
+        self.invalidate_caches(destination.as_ref());
+        Ok(())
+    }
+
+    /// Within the [`Root`]'s tree, atomically exchange the inodes at `first`
+    /// and `second` (with `RENAME_EXCHANGE`), so that each path refers to
+    /// what the other one referred to before. Both paths must exist.
+    ///
+    /// This is intended for flipping between two versions of a file (or
+    /// directory), such as an A/B configuration -- other processes always see
+    /// either the old or the new contents at both paths.
+    ///
+    /// # Errors
+    ///
+    /// If `renameat2(2)` is not supported, an [`Error::NotSupported`] is
+    /// returned. If the paths are on different mounts, an
+    /// [`Error::CrossDevice`] is returned. Otherwise, the error rules are
+    /// identical to [`renameat2(2)`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    /// [`Error::CrossDevice`]: error/enum.Error.html#variant.CrossDevice
+    /// [`renameat2(2)`]: http://man7.org/linux/man-pages/man2/renameat2.2.html
+    pub fn swap_contents<P: AsRef<Path>>(&self, first: P, second: P) -> Result<(), Error> {
+        let exchange = RenameFlags(libc::RENAME_EXCHANGE);
+        ensure!(
+            exchange.supported(),
+            error::NotSupported {
+                feature: "renameat2",
+            }
+        );
+
+        // Both names already exist, so (unlike Root::rename) there is no new
+        // name to validate.
+        let (first, second) = (first.as_ref(), second.as_ref());
+        let (first_parent, first_name) =
+            path_split(first).wrap("split first path into (parent, name)")?;
+        let (second_parent, second_name) =
+            path_split(second).wrap("split second path into (parent, name)")?;
+
+        let first_dir = self
+            .grace_policy
+            .retry(|| self.resolve(first_parent))
+            .wrap("resolve first parent directory for exchange")?
+            .inner;
+        let second_dir = self
+            .grace_policy
+            .retry(|| self.resolve(second_parent))
+            .wrap("resolve second parent directory for exchange")?
+            .inner;
+
+        match syscalls::renameat2(
+            first_dir.as_raw_fd(),
+            first_name,
+            second_dir.as_raw_fd(),
+            second_name,
+            exchange.0,
+        ) {
+            Ok(_) => {
+                self.invalidate_caches(first);
+                self.invalidate_caches(second);
+                Ok(())
+            }
+            Err(err) if err.root_cause().raw_os_error() == Some(libc::EXDEV) => {
+                error::CrossDevice { first, second }.fail()
+            }
+            Err(err) => Err(err).context(error::RawOsError {
+                operation: "pathrs swap_contents",
+            }),
+        }
+    }
+
+    /// Atomically exchange the directory at `path` within the [`Root`]'s tree
+    /// with the externally-prepared directory `staged` (with
+    /// `RENAME_EXCHANGE`), so that `path` refers to `staged` and the previous
+    /// directory takes the place of `staged`. A [`Handle`] to the previous
+    /// directory is returned, so that it can be cleaned up (or swapped back).
+    ///
+    /// This is intended for blue/green deployments of content into sandboxed
+    /// trees -- the new tree is staged outside the [`Root`] (on the same
+    /// filesystem) and then adopted in one step, so other processes always
+    /// see either the old or the new tree at `path`. The current name of
+    /// `staged` is found through procfs, and both names are verified to
+    /// still refer to the expected inodes before and after the exchange (if
+    /// they don't afterwards, the exchange is undone).
+    ///
+    /// # Errors
+    ///
+    /// If `renameat2(2)` is not supported, an [`Error::NotSupported`] is
+    /// returned. If either `path` or `staged` is not a directory, an
+    /// [`Error::InvalidArgument`] is returned. If `staged` is not on the same
+    /// mount as `path`, an [`Error::CrossDevice`] is returned. If either name
+    /// was swapped during the exchange, an [`Error::SafetyViolation`] is
+    /// returned. Otherwise, the error rules are identical to
+    /// [`Root::swap_contents`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::swap_contents`]: struct.Root.html#method.swap_contents
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    /// [`Error::CrossDevice`]: error/enum.Error.html#variant.CrossDevice
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn exchange_with_external<P: AsRef<Path>>(
+        &self,
+        path: P,
+        staged: &Handle,
+    ) -> Result<Handle, Error> {
+        let exchange = RenameFlags(libc::RENAME_EXCHANGE);
+        ensure!(
+            exchange.supported(),
+            error::NotSupported {
+                feature: "renameat2",
+            }
+        );
+
+        let path = path.as_ref();
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .grace_policy
+            .retry(|| self.resolve(parent))
+            .wrap("resolve target parent directory for exchange")?
+            .inner;
+        let current = syscalls::openat(dir.as_raw_fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0)
+            .context(error::RawOsError {
+            operation: "open exchange target",
+        })?;
+
+        for (file, name) in &[(&current, "path"), (&staged.inner, "staged")] {
+            let meta = file.metadata().context(error::OsError {
+                operation: "check exchanged inode type",
+            })?;
+            ensure!(
+                meta.is_dir(),
+                error::InvalidArgument {
+                    name: *name,
+                    description: "must be a directory",
+                }
+            );
+        }
+
+        // Only the final name of the staged directory comes from procfs --
+        // its parent is reached through "..", and the name is verified to
+        // refer to the staged directory.
+        // SAFETY: as_unsafe_path is safe here since the name is verified
+        //         before it is used.
+        let staged_path = staged
+            .inner
+            .as_unsafe_path()
+            .wrap("get path of staged directory")?;
+        let staged_name = Path::new(staged_path.file_name().context(error::InvalidArgument {
+            name: "staged",
+            description: "must not be the root of a mount",
+        })?);
+        let staged_dir = syscalls::openat(
+            staged.inner.as_raw_fd(),
+            "..",
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
         )
+        .context(error::RawOsError {
+            operation: "open parent of staged directory",
+        })?;
+        let open_staged = || {
+            syscalls::openat(
+                staged_dir.as_raw_fd(),
+                staged_name,
+                libc::O_PATH | libc::O_NOFOLLOW,
+                0,
+            )
+            .context(error::RawOsError {
+                operation: "open staged directory by name",
+            })
+        };
+        let staged_id = staged.inner.inode_id()?;
+        ensure!(
+            open_staged()?.inode_id()? == staged_id,
+            error::SafetyViolation {
+                description: "staged directory was moved before the exchange",
+            }
+        );
+
+        let current_id = current.inode_id()?;
+        if staged_id.0 != current_id.0
+            || stat::mount_id(staged.inner.as_raw_fd())? != stat::mount_id(current.as_raw_fd())?
+        {
+            return error::CrossDevice {
+                first: path,
+                second: staged_path,
+            }
+            .fail();
+        }
+
+        let swap = || {
+            syscalls::renameat2(
+                staged_dir.as_raw_fd(),
+                staged_name,
+                dir.as_raw_fd(),
+                name,
+                exchange.0,
+            )
+        };
+        match swap() {
+            Ok(_) => (),
+            Err(err) if err.root_cause().raw_os_error() == Some(libc::EXDEV) => {
+                return error::CrossDevice {
+                    first: path,
+                    second: staged_path,
+                }
+                .fail()
+            }
+            Err(err) => {
+                return Err(err).context(error::RawOsError {
+                    operation: "pathrs exchange_with_external",
+                })
+            }
+        }
+        self.invalidate_caches(path);
+
+        let adopted = syscalls::openat(dir.as_raw_fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0)
+            .context(error::RawOsError {
+            operation: "open exchanged target",
+        })?;
+        if adopted.inode_id()? != staged_id || open_staged()?.inode_id()? != current_id {
+            // Something was swapped in under one of the names, so put
+            // whatever we exchanged back where it came from.
+            let _ = swap();
+            return error::SafetyViolation {
+                description: "exchanged directories were swapped during the exchange",
+            }
+            .fail();
+        }
+        Ok(Handle::from_file_unchecked(current))
+    }
+
+    /// Compute the path of `handle` relative to the [`Root`].
+    ///
+    /// The path is first computed by comparing the `/proc/self/fd` paths of
+    /// the [`Root`] and `handle`, and is then verified by walking down each of
+    /// the path components from the [`Root`] (without following symlinks) and
+    /// checking that we end up at the same inode as `handle`. If `handle` is a
+    /// directory, we also walk back up the tree from `handle` with `..` and
+    /// check that each parent matches the components we walked down through.
+    ///
+    /// Since no component is followed, `handle` may be a handle to a symlink
+    /// (in which case the path of the symlink itself is returned).
+    ///
+    /// If the path of `handle` is longer than `PATH_MAX` (so procfs cannot
+    /// give us its path) and `handle` is a directory, the path is instead
+    /// computed by walking up the tree with `..` and searching each parent
+    /// directory for the entry with the same `(st_dev, st_ino)`. This is much
+    /// slower, and is not possible for non-directories (which result in an
+    /// [`Error::NotSupported`]).
+    ///
+    /// The returned path always has a leading `/` (which refers to the
+    /// [`Root`] itself, so the [`Root`] has the path `/`), which means it can
+    /// be compared against other root-relative paths with
+    /// [`Path::starts_with`].
+    ///
+    /// Note that the returned path is an [`UnverifiedPath`] -- while the path
+    /// was correct at some point during this method, it could've been changed
+    /// by the time you get to use it.
+    ///
+    /// # Errors
+    ///
+    /// If `handle` is not inside the [`Root`] (or was moved during the
+    /// computation), an [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`UnverifiedPath`]: struct.UnverifiedPath.html
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    /// [`Path::starts_with`]: https://doc.rust-lang.org/std/path/struct.Path.html#method.starts_with
+    pub fn relative_path_of(&self, handle: &Handle) -> Result<UnverifiedPath, Error> {
+        // SAFETY: as_unsafe_path is safe here since we are only using it to
+        //         generate a tentative path, which is then verified below.
+        let root_path = self
+            .inner
+            .as_unsafe_path()
+            .wrap("get root path to compute relative path")?;
+        let relpath = match handle.inner.as_unsafe_path() {
+            Ok(handle_path) => handle_path
+                .strip_prefix(&root_path)
+                .ok()
+                .context(error::SafetyViolation {
+                    description: "handle is not inside the root",
+                })?
+                .to_path_buf(),
+            // The path is longer than PATH_MAX, so procfs can't tell us.
+            Err(err) if err.raw_os_error() == Some(libc::ENAMETOOLONG) => self
+                .relative_path_by_inode(handle)
+                .wrap("compute relative path of long path")?,
+            Err(err) => return Err(err).wrap("get handle path to compute relative path"),
+        };
+
+        // Walk down the tree from the root, storing (dev, ino) of each
+        // directory we pass through.
+        let mut chain = Vec::new();
+        let mut current = self
+            .inner
+            .try_clone_hotfix()
+            .wrap("dup root as starting point")?;
+        for part in relpath.components() {
+            // The /proc/self/fd paths are always canonical, so this should
+            // never happen outside of an attack (or a buggy kernel).
+            let part = match part {
+                Component::Normal(part) => part,
+                _ => {
+                    return error::SafetyViolation {
+                        description: "handle path contains non-normal components",
+                    }
+                    .fail()
+                }
+            };
+            chain.push(current.inode_id()?);
+            current = syscalls::openat(
+                current.as_raw_fd(),
+                part,
+                libc::O_PATH | libc::O_NOFOLLOW,
+                0,
+            )
+            .context(error::RawOsError {
+                operation: "open next component of handle path",
+            })?;
+        }
+
+        // The walk must land on the same inode as the handle.
+        ensure!(
+            current.inode_id()? == handle.inner.inode_id()?,
+            error::SafetyViolation {
+                description: "handle path doesn't match the handle inode",
+            }
+        );
+
+        // Directories also let us verify the chain in reverse. Since ".." is
+        // resolved by the kernel based on the dentry tree, this ensures that
+        // the handle really is reachable from the root.
+        let is_dir = handle
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat handle",
+            })?
+            .is_dir();
+        if is_dir {
+            let mut current = handle
+                .inner
+                .try_clone_hotfix()
+                .wrap("dup handle as starting point")?;
+            for expected in chain.iter().rev() {
+                current = syscalls::openat(current.as_raw_fd(), "..", libc::O_PATH, 0).context(
+                    error::RawOsError {
+                        operation: "open parent of handle path component",
+                    },
+                )?;
+                ensure!(
+                    current.inode_id()? == *expected,
+                    error::SafetyViolation {
+                        description: "handle parent doesn't match expected path component",
+                    }
+                );
+            }
+        }
+
+        Ok(UnverifiedPath(Path::new("/").join(relpath)))
     }
 
-    // TODO: mkdir_all()
+    /// Compute the path of the directory `handle` relative to the [`Root`]
+    /// without procfs, by walking up the tree with `..` and looking for the
+    /// entry of each directory in its parent (by comparing `(st_dev, st_ino)`).
+    /// This is very slow, and is only used for paths longer than `PATH_MAX`.
+    /// The result still needs to be verified by the caller.
+    ///
+    /// [`Root`]: struct.Root.html
+    fn relative_path_by_inode(&self, handle: &Handle) -> Result<PathBuf, Error> {
+        let root_id = self.inner.inode_id()?;
+        let mut current = handle
+            .inner
+            .try_clone_hotfix()
+            .wrap("dup handle as starting point")?;
+        let mut current_id = current.inode_id()?;
+        ensure!(
+            current
+                .metadata()
+                .context(error::OsError {
+                    operation: "fstat handle",
+                })?
+                .is_dir(),
+            error::NotSupported {
+                feature: "relative paths of non-directories longer than PATH_MAX",
+            }
+        );
+
+        let mut parts = Vec::new();
+        while current_id != root_id {
+            let parent = syscalls::openat(
+                current.as_raw_fd(),
+                "..",
+                libc::O_PATH | libc::O_DIRECTORY,
+                0,
+            )
+            .context(error::RawOsError {
+                operation: "open parent of handle path component",
+            })?;
+            let parent_id = parent.inode_id()?;
+            // ".." of the root of the filesystem (or of our chroot) is itself.
+            ensure!(
+                parent_id != current_id,
+                error::SafetyViolation {
+                    description: "handle is not inside the root",
+                }
+            );
 
-    // TODO: remove_all()
+            let mut dirents = Dirents::new(
+                parent
+                    .reopen(OpenFlags(libc::O_RDONLY | libc::O_DIRECTORY))
+                    .wrap("reopen parent directory for reading")?,
+            );
+            let mut name = None;
+            while let Some(entry) = dirents.next() {
+                let entry = entry?;
+                // fstatat(2) of a mountpoint gives us the root of the mount,
+                // which is what ".." of the mount root leads back to.
+                match syscalls::fstatat(dirents.as_raw_fd(), entry.name()) {
+                    Ok(stat) if (stat.st_dev, stat.st_ino) == current_id => {
+                        name = Some(entry.name().to_os_string());
+                        break;
+                    }
+                    _ => continue,
+                }
+            }
+            parts.push(name.context(error::SafetyViolation {
+                description: "handle was moved while computing its path",
+            })?);
+            current = parent;
+            current_id = parent_id;
+        }
+        Ok(parts.iter().rev().collect())
+    }
+
+    /// Within the [`Root`]'s tree, create the directory at `path` and every
+    /// missing parent directory (with the permissions `perm`), like
+    /// [`std::fs::create_dir_all`]. Returns an `O_PATH` [`Handle`] to the
+    /// final directory.
+    ///
+    /// Each component is created (with `mkdirat(2)`) and opened (without
+    /// following it) relative to the handle of the previous component, so an
+    /// attacker cannot redirect the creation of later components by swapping
+    /// an earlier component. Existing symlinks in the path are resolved inside
+    /// the root (as with [`Root::resolve`]), and `..` components refer to the
+    /// parent of the directory reached so far (after following any symlinks)
+    /// and cannot go above the root, exactly as during resolution. The final
+    /// directory is checked against a fresh resolution of `path` before it is
+    /// returned. The permissions of existing directories are not changed.
+    ///
+    /// # Errors
+    ///
+    /// If one of the existing components of `path` is not a directory, an
+    /// [`Error::Conflict`] is returned. If the final directory was moved during
+    /// the operation, an [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Handle`]: struct.Handle.html
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`std::fs::create_dir_all`]: https://doc.rust-lang.org/std/fs/fn.create_dir_all.html
+    /// [`Error::Conflict`]: error/enum.Error.html#variant.Conflict
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn mkdir_all<P: AsRef<Path>>(&self, path: P, perm: &Permissions) -> Result<Handle, Error> {
+        let path = Path::new("/").join(path);
+        let mode = self.sanitize_mode(perm.mode())?;
+
+        let mut current = self
+            .inner
+            .try_clone_hotfix()
+            .wrap("dup root as starting point")?;
+        // The path of current inside the root, without any symlinks.
+        let mut current_path = PathBuf::from("/");
+        for part in path.components() {
+            let part = match part {
+                Component::Normal(part) => part,
+                Component::ParentDir => {
+                    // As with the emulated resolver, ".." is the parent of
+                    // the directory we actually reached (after following
+                    // symlinks), and can't go above the root.
+                    if current_path.pop() {
+                        current = self
+                            .resolve(&current_path)
+                            .wrap("resolve parent directory in mkdir_all")?
+                            .inner;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            current_path.push(part);
+            let next = match syscalls::openat(
+                current.as_raw_fd(),
+                part,
+                libc::O_PATH | libc::O_NOFOLLOW,
+                0,
+            ) {
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOENT) => {
+                    self.validate_name(Path::new(part))?;
+                    match syscalls::mkdirat(current.as_raw_fd(), part, mode) {
+                        Err(err) if err.root_cause().raw_os_error() != Some(libc::EEXIST) => {
+                            return Err(err).context(error::RawOsError {
+                                operation: "pathrs mkdir_all",
+                            })
+                        }
+                        _ => self.invalidate_caches(&current_path),
+                    }
+                    syscalls::openat(
+                        current.as_raw_fd(),
+                        part,
+                        libc::O_PATH | libc::O_NOFOLLOW,
+                        0,
+                    )
+                }
+                ret => ret,
+            }
+            .context(error::RawOsError {
+                operation: "open directory component without following symlinks",
+            })?;
+
+            let is_symlink = next
+                .metadata()
+                .context(error::OsError {
+                    operation: "fstat directory component",
+                })?
+                .file_type()
+                .is_symlink();
+            current = if is_symlink {
+                // Symlinks are resolved like any other resolution, so they
+                // cannot lead outside the root.
+                let handle = self
+                    .resolve(&current_path)
+                    .wrap("resolve symlink component of mkdir_all")?;
+                current_path = self
+                    .relative_path_of(&handle)
+                    .wrap("get path of symlink component of mkdir_all")?
+                    .into_unverified_path_buf();
+                handle.inner
+            } else {
+                next
+            };
+            ensure!(
+                current
+                    .metadata()
+                    .context(error::OsError {
+                        operation: "fstat directory component",
+                    })?
+                    .is_dir(),
+                error::Conflict {
+                    path: current_path,
+                    description: "existing inode is not a directory",
+                }
+            );
+        }
+
+        // Walking down can never escape the directory we are in, but one of
+        // the directories could have been moved outside the root while we were
+        // walking. Make sure the final directory is still at path.
+        let handle = self.resolve(&path).wrap("resolve created directory")?;
+        ensure!(
+            handle.inner.inode_id()? == current.inode_id()?,
+            error::SafetyViolation {
+                description: format!("directory {:?} was moved while it was created", path),
+            }
+        );
+        Ok(handle)
+    }
 
     // TODO: implement a way to duplicate (and even serialise) Roots so that you
     //       can send them between processes (presumably with SCM_RIGHTS).
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        tests::{backends, root_with_backend, TempDir},
+        InodeType, ModeBits, OpenFlags, RenameFlags, ReopenPolicy,
+    };
+
+    use std::{
+        ffi::{OsStr, OsString},
+        fs::{self, Permissions},
+        os::unix::{ffi::OsStrExt, fs::MetadataExt, fs::PermissionsExt},
+        path::{Path, PathBuf},
+    };
+
+    fn is_invalid_argument(err: &Error) -> bool {
+        err.iter_chain_hotfix()
+            .filter_map(|err| err.downcast_ref::<Error>())
+            .any(|err| matches!(err, Error::InvalidArgument { .. }))
+            || err.raw_os_error() == Some(libc::EINVAL)
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let names = [&b"caf\xe9"[..], b"\xff\xfe\xfd", b"\x80dir", b"ok\xc3"];
+        for backend in backends() {
+            let tmpdir = TempDir::new();
+            let root = root_with_backend(tmpdir.path(), backend);
+            let perm = Permissions::from_mode(0o755);
+            for name in names.iter() {
+                let name = Path::new(OsStr::from_bytes(name));
+                root.create(name, &InodeType::Directory(&perm)).unwrap();
+                root.create_file(name.join(name), &perm).unwrap();
+                root.create(name.join("link"), &InodeType::Symlink(name))
+                    .unwrap();
+
+                let handle = root.resolve(name.join(name)).unwrap();
+                let expected = tmpdir.path().join(name).join(name);
+                assert_eq!(
+                    handle.inner.metadata().unwrap().ino(),
+                    fs::metadata(&expected).unwrap().ino()
+                );
+                assert_eq!(
+                    fs::read_link(tmpdir.path().join(name).join("link")).unwrap(),
+                    name,
+                    "{:?}",
+                    backend
+                );
+            }
+
+            let mut entries: Vec<OsString> = root
+                .resolve("/")
+                .unwrap()
+                .read_dir()
+                .unwrap()
+                .map(|entry| entry.unwrap().name().to_os_string())
+                .collect();
+            entries.sort();
+            let mut expected: Vec<OsString> = names
+                .iter()
+                .map(|name| OsStr::from_bytes(name).to_os_string())
+                .collect();
+            expected.sort();
+            assert_eq!(entries, expected);
+
+            for name in names.iter() {
+                let name = Path::new(OsStr::from_bytes(name));
+                root.rename(name, Path::new("renamed"), RenameFlags(0))
+                    .unwrap();
+                root.rename(Path::new("renamed"), name, RenameFlags(0))
+                    .unwrap();
+                root.remove_all(name).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn embedded_nul() {
+        let bad = Path::new(OsStr::from_bytes(b"dir\0/../../etc"));
+        for backend in backends() {
+            let tmpdir = TempDir::new();
+            fs::create_dir(tmpdir.path().join("dir")).unwrap();
+            let root = root_with_backend(tmpdir.path(), backend);
+            let perm = Permissions::from_mode(0o644);
+
+            assert!(is_invalid_argument(&root.resolve(bad).unwrap_err()));
+            assert!(is_invalid_argument(&root.lstat_nofollow(bad).unwrap_err()));
+            assert!(is_invalid_argument(
+                &root.create_file(bad, &perm).unwrap_err()
+            ));
+            assert!(is_invalid_argument(
+                &root.create(bad, &InodeType::Directory(&perm)).unwrap_err()
+            ));
+            assert!(is_invalid_argument(
+                &root.create("link", &InodeType::Symlink(bad)).unwrap_err()
+            ));
+            assert!(is_invalid_argument(&root.remove(bad).unwrap_err()));
+            assert!(is_invalid_argument(&root.remove_all(bad).unwrap_err()));
+            assert!(is_invalid_argument(
+                &root
+                    .rename(Path::new("dir"), bad, RenameFlags(0))
+                    .unwrap_err()
+            ));
+            // Nothing was created or removed.
+            let entries: Vec<_> = fs::read_dir(tmpdir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(entries, vec![OsString::from("dir")]);
+        }
+    }
+
+    /// A relative path of 20 components of 250 bytes each, which is longer
+    /// than PATH_MAX.
+    fn long_path() -> PathBuf {
+        let path: PathBuf = (0..20)
+            .map(|i| format!("{:03}{}", i, "x".repeat(247)))
+            .collect();
+        assert!(path.as_os_str().len() > libc::PATH_MAX as usize);
+        path
+    }
+
+    #[test]
+    fn longer_than_path_max() {
+        let long = long_path();
+        let name = "n".repeat(255);
+        for backend in backends() {
+            let tmpdir = TempDir::new();
+            let root = root_with_backend(tmpdir.path(), backend);
+            let dir_perm = Permissions::from_mode(0o755);
+            let file_perm = Permissions::from_mode(0o644);
+
+            // Creation.
+            let dir = root.mkdir_all(&long, &dir_perm).unwrap();
+            root.create_file(long.join(&name), &file_perm).unwrap();
+            assert!(root
+                .create_file(long.join("m".repeat(256)), &file_perm)
+                .is_err());
+            root.create(long.join("link"), &InodeType::Symlink(Path::new(&name)))
+                .unwrap();
+            root.create(long.join("subdir"), &InodeType::Directory(&dir_perm))
+                .unwrap();
+
+            // Resolution (including through symlinks and "..").
+            let ino = |path: &Path| root.resolve(path).unwrap().inner.metadata().unwrap().ino();
+            let file_ino = ino(&long.join(&name));
+            assert_eq!(ino(&long.join("link")), file_ino, "{:?}", backend);
+            assert_eq!(
+                ino(&long.join("subdir/..").join(&name)),
+                file_ino,
+                "{:?}",
+                backend
+            );
+            assert_eq!(
+                ino(&Path::new("/").join(&long).join(&name)),
+                file_ino,
+                "{:?}",
+                backend
+            );
+
+            // Relative paths (which need the fallback for long paths).
+            assert_eq!(
+                root.relative_path_of(&dir)
+                    .unwrap()
+                    .into_unverified_path_buf(),
+                Path::new("/").join(&long)
+            );
+            let subdir = root.resolve(long.join("subdir")).unwrap();
+            assert_eq!(
+                root.relative_path_of(&subdir)
+                    .unwrap()
+                    .into_unverified_path_buf(),
+                Path::new("/").join(&long).join("subdir")
+            );
+            // Non-directories can't be found by walking up with "..".
+            let file = root.resolve(long.join(&name)).unwrap();
+            assert!(root.relative_path_of(&file).is_err());
+
+            // Walking.
+            let entries: Vec<_> = root.walk("/").unwrap().map(Result::unwrap).collect();
+            let file = entries
+                .iter()
+                .find(|entry| entry.path() == long.join(&name))
+                .expect("walk should find the deepest file");
+            assert_eq!(file.depth(), 21);
+            assert_eq!(file.metadata().ino(), file_ino);
+            assert_eq!(entries.len(), 1 + 20 + 3);
+
+            root.remove_all(long.iter().next().unwrap()).unwrap();
+            assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn reopen_policy_terminal() {
+        for backend in backends() {
+            let mut root = root_with_backend("/dev", backend);
+            root.reopen_policy = ReopenPolicy::all() - ReopenPolicy::TERMINAL;
+
+            // /dev/null is a character device, but not a terminal.
+            let null = root.resolve("null").expect("resolve /dev/null");
+            root.reopen(&null, OpenFlags(libc::O_RDWR))
+                .expect("reopen /dev/null without TERMINAL");
+
+            // Opening /dev/ptmx allocates a new pty master, which is a
+            // terminal. Skip if this system has no ptys.
+            let ptmx = match root.resolve("ptmx") {
+                Ok(ptmx) => ptmx,
+                Err(_) => continue,
+            };
+            match root.reopen(&ptmx, OpenFlags(libc::O_RDWR)) {
+                // No devpts instance to allocate from.
+                Err(err) if err.raw_os_error().is_some() => continue,
+                Err(err) => assert!(
+                    is_invalid_argument(&err),
+                    "{:?}: unexpected error reopening /dev/ptmx: {}",
+                    backend,
+                    err
+                ),
+                Ok(_) => panic!("{:?}: reopened a pty without TERMINAL", backend),
+            }
+
+            root.reopen_policy = ReopenPolicy::all();
+            root.reopen(&ptmx, OpenFlags(libc::O_RDWR))
+                .expect("reopen /dev/ptmx with TERMINAL");
+        }
+    }
+
+    #[test]
+    fn mkdir_all_dotdot() {
+        let perm = Permissions::from_mode(0o755);
+        for backend in backends() {
+            let dir = TempDir::new();
+            let root = root_with_backend(dir.path(), backend);
+            fs::create_dir_all(dir.path().join("a/b")).unwrap();
+            std::os::unix::fs::symlink("a/b", dir.path().join("link")).unwrap();
+
+            let check = |path: &str, expected: &str| {
+                let handle = root.mkdir_all(path, &perm).unwrap_or_else(|err| {
+                    panic!("{:?}: mkdir_all({:?}) failed: {}", backend, path, err)
+                });
+                assert_eq!(
+                    root.relative_path_of(&handle)
+                        .unwrap()
+                        .into_unverified_path_buf(),
+                    Path::new(expected),
+                    "{:?}: mkdir_all({:?})",
+                    backend,
+                    path
+                );
+                assert!(dir.path().join(&expected[1..]).is_dir());
+            };
+            // ".." can't go above the root.
+            check("../../x/../../y", "/y");
+            check("a/new/../c", "/a/c");
+            assert!(dir.path().join("a/new").is_dir());
+            // ".." after a symlink is the parent of the symlink target.
+            check("link/../d", "/a/d");
+            check("link/e/../../f", "/a/f");
+            assert!(dir.path().join("a/b/e").is_dir());
+            assert!(!dir.path().join("d").exists());
+        }
+    }
+
+    #[test]
+    fn set_permissions_mode_policy() {
+        let dir = TempDir::new();
+        let mut root = crate::Root::open(dir.path()).unwrap();
+        root.mode_policy.strip = ModeBits::SETUID;
+        root.mode_policy.reject = ModeBits::WORLD_WRITABLE;
+        fs::write(dir.path().join("file"), b"").unwrap();
+        let handle = root.resolve("file").unwrap();
+        let mode = || fs::metadata(dir.path().join("file")).unwrap().mode() & 0o7777;
+
+        root.set_permissions(&handle, &Permissions::from_mode(0o4750))
+            .expect("set permissions with stripped bits");
+        assert_eq!(mode(), 0o750);
+
+        let err = root
+            .set_permissions(&handle, &Permissions::from_mode(0o777))
+            .expect_err("set permissions with rejected bits");
+        assert!(is_invalid_argument(&err), "unexpected error: {}", err);
+        assert_eq!(mode(), 0o750);
+    }
+}
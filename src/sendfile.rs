@@ -0,0 +1,288 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    opstats,
+    root::path_split,
+    syscalls,
+    utils::RawFdExt,
+    Handle, OpenFlags, Root,
+};
+
+use std::{
+    fs::{File, Permissions},
+    io::Error as IOError,
+    ops::{Bound, RangeBounds},
+    os::unix::{fs::PermissionsExt, io::AsRawFd, io::RawFd},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// Maximum number of bytes moved by a single `sendfile(2)` or `splice(2)`
+/// call.
+const SEND_CHUNK_SIZE: usize = 1 << 30;
+
+/// Open `handle` for reading its contents, and convert `range` to the
+/// `[start, end)` byte offsets to send. Only regular files are permitted, since
+/// neither `sendfile(2)` nor `splice(2)` can seek in other inodes.
+fn open_range<R: RangeBounds<u64>>(handle: &Handle, range: R) -> Result<(File, u64, u64), Error> {
+    let file = handle
+        .reopen(OpenFlags(libc::O_RDONLY))
+        .wrap("reopen handle for sending")?;
+    let meta = file.metadata().context(error::OsError {
+        operation: "check inode type for sending",
+    })?;
+    ensure!(
+        meta.file_type().is_file(),
+        error::InvalidArgument {
+            name: "handle",
+            description: "only the contents of regular files can be sent",
+        }
+    );
+
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end.saturating_add(1),
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => meta.len(),
+    };
+    ensure!(
+        start <= end && end <= i64::MAX as u64,
+        error::InvalidArgument {
+            name: "range",
+            description: "must be a valid range of file offsets",
+        }
+    );
+    Ok((file, start, end))
+}
+
+/// Length of the next chunk to send, when at `offset` of `[.., end)`.
+fn chunk_len(offset: i64, end: u64) -> usize {
+    (end - offset as u64).min(SEND_CHUNK_SIZE as u64) as usize
+}
+
+/// Whether a failure to write to the (non-blocking) output should end the
+/// transfer early rather than be returned, because some bytes were already
+/// sent.
+fn is_short_write(err: &syscalls::Error, sent: u64) -> bool {
+    sent > 0 && err.root_cause().raw_os_error() == Some(libc::EAGAIN)
+}
+
+impl Handle {
+    /// Send the `range` of the contents of the file to `out` (usually a
+    /// socket) with `sendfile(2)`, without copying them through userspace.
+    /// The number of bytes sent is returned.
+    ///
+    /// The file is re-opened read-only through the [`Handle`] (as with
+    /// [`Handle::reopen`]), so its file offset is not shared with other users
+    /// of the [`Handle`]. Fewer bytes than requested are sent if the file is
+    /// shorter than `range`, or if `out` is non-blocking and becomes full
+    /// after some bytes were sent.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] is not a regular file or `range` is invalid, an
+    /// [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn sendfile_to<F: AsRawFd, R: RangeBounds<u64>>(
+        &self,
+        out: &F,
+        range: R,
+    ) -> Result<u64, Error> {
+        let (file, start, end) = open_range(self, range)?;
+        let mut offset = start as i64;
+        while (offset as u64) < end {
+            let len = chunk_len(offset, end);
+            match syscalls::sendfile(out.as_raw_fd(), file.as_raw_fd(), &mut offset, len) {
+                Ok(0) => break,
+                Ok(sent) => opstats::record_bytes(sent as u64),
+                Err(err) if is_short_write(&err, offset as u64 - start) => break,
+                Err(err) => Err(err).context(error::RawOsError {
+                    operation: "send file contents",
+                })?,
+            }
+        }
+        Ok(offset as u64 - start)
+    }
+
+    /// Identical to [`Handle::sendfile_to`], except that the contents are
+    /// moved to `out` with `splice(2)` (through an intermediate pipe).
+    ///
+    /// This works for any `out` which supports `splice(2)` (such as pipes and
+    /// sockets), and can be used where `sendfile(2)` is unavailable or blocked
+    /// by a seccomp policy.
+    ///
+    /// [`Handle::sendfile_to`]: struct.Handle.html#method.sendfile_to
+    pub fn splice_to<F: AsRawFd, R: RangeBounds<u64>>(
+        &self,
+        out: &F,
+        range: R,
+    ) -> Result<u64, Error> {
+        let (file, start, end) = open_range(self, range)?;
+        let (reader, writer) = syscalls::pipe2().context(error::RawOsError {
+            operation: "create pipe for splicing",
+        })?;
+        let mut offset = start as i64;
+        let mut sent = 0;
+        while (offset as u64) < end {
+            let len = chunk_len(offset, end);
+            let buffered =
+                syscalls::splice(file.as_raw_fd(), Some(&mut offset), writer.as_raw_fd(), len)
+                    .context(error::RawOsError {
+                        operation: "splice file contents into pipe",
+                    })?;
+            if buffered == 0 {
+                break;
+            }
+            match drain_pipe(&reader, out.as_raw_fd(), buffered, &mut sent) {
+                Err(err) if is_short_write(&err, sent) => break,
+                ret => ret.context(error::RawOsError {
+                    operation: "splice file contents from pipe",
+                })?,
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Move `len` bytes from the pipe `reader` to `out`, adding the number of bytes
+/// moved to `sent`.
+fn drain_pipe(
+    reader: &File,
+    out: RawFd,
+    len: usize,
+    sent: &mut u64,
+) -> Result<(), syscalls::Error> {
+    let mut left = len;
+    while left > 0 {
+        let moved = syscalls::splice(reader.as_raw_fd(), None, out, left)?;
+        *sent += moved as u64;
+        opstats::record_bytes(moved as u64);
+        left -= moved;
+    }
+    Ok(())
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, create a new file at `path` (with the
+    /// permissions `perm`) containing everything read from `input` (a pipe or
+    /// socket) until end-of-file. The contents are moved with `splice(2)`, so
+    /// they are not copied through userspace. The number of bytes imported is
+    /// returned.
+    ///
+    /// As with [`Root::copy_file`], the file is only linked into place once
+    /// the import has completed, so other processes never see a partially
+    /// written file at `path` (and nothing is left behind if the import
+    /// fails).
+    ///
+    /// # Errors
+    ///
+    /// If `path` already exists, an error is returned (as with
+    /// [`Root::create_file`]). If `input` produces more than `max_size` bytes,
+    /// an [`Error::OsError`] with `EFBIG` is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::copy_file`]: struct.Root.html#method.copy_file
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    /// [`Error::OsError`]: error/enum.Error.html#variant.OsError
+    pub fn import_stream<F: AsRawFd, P: AsRef<Path>>(
+        &self,
+        input: &F,
+        path: P,
+        perm: &Permissions,
+        max_size: u64,
+    ) -> Result<u64, Error> {
+        let (parent, name) =
+            path_split(path.as_ref()).wrap("split target path into (parent, name)")?;
+        self.validate_name(name)?;
+        let mode = self.sanitize_mode(perm.mode())?;
+        let dir = self
+            .grace_policy
+            .retry(|| self.resolve(parent))
+            .wrap("resolve target parent directory for import")?
+            .inner;
+        let dirfd = dir.as_raw_fd();
+
+        let (dst, temp) = self.create_copy_destination(&dir, name, mode)?;
+        let result = splice_stream(input.as_raw_fd(), &dst, max_size).and_then(|imported| {
+            match temp {
+                None => dst.link_into(dirfd, name),
+                Some(ref temp) => syscalls::linkat(dirfd, temp.as_path(), dirfd, name, 0).context(
+                    error::RawOsError {
+                        operation: "link imported file into place",
+                    },
+                ),
+            }
+            .map(|_| imported)
+        });
+        if let Some(temp) = temp {
+            // Best-effort cleanup, the import (if any) has its own name now.
+            let _ = syscalls::unlinkat(dirfd, temp, 0);
+        }
+        let imported = result.wrap("pathrs import_stream")?;
+        self.invalidate_caches(path.as_ref());
+        Ok(imported)
+    }
+}
+
+/// Move everything from `input` to the (empty) `dst` through an intermediate
+/// pipe, failing if more than `max_size` bytes are read.
+fn splice_stream(input: RawFd, dst: &File, max_size: u64) -> Result<u64, Error> {
+    let (reader, writer) = syscalls::pipe2().context(error::RawOsError {
+        operation: "create pipe for splicing",
+    })?;
+    let mut imported = 0;
+    let mut written = 0;
+    loop {
+        // Ask for one byte more than the limit, so we can tell whether the
+        // stream would exceed it.
+        let len = (max_size - imported)
+            .saturating_add(1)
+            .min(SEND_CHUNK_SIZE as u64) as usize;
+        let buffered =
+            syscalls::splice(input, None, writer.as_raw_fd(), len).context(error::RawOsError {
+                operation: "splice stream into pipe",
+            })?;
+        if buffered == 0 {
+            break;
+        }
+        imported += buffered as u64;
+        if imported > max_size {
+            return Err(IOError::from_raw_os_error(libc::EFBIG)).context(error::OsError {
+                operation: "check size of imported stream",
+            });
+        }
+        drain_pipe(&reader, dst.as_raw_fd(), buffered, &mut written).context(
+            error::RawOsError {
+                operation: "splice stream from pipe into file",
+            },
+        )?;
+    }
+    Ok(written)
+}
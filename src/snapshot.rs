@@ -0,0 +1,493 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    syscalls,
+    utils::FileExt,
+    Root,
+};
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    fs::File,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// The metadata of a single inode, as captured by [`Root::snapshot_metadata`].
+///
+/// [`Root::snapshot_metadata`]: struct.Root.html#method.snapshot_metadata
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EntryMetadata {
+    /// The full `st_mode` of the inode (including the file type bits).
+    pub mode: u32,
+    /// The owning user of the inode.
+    pub uid: u32,
+    /// The owning group of the inode.
+    pub gid: u32,
+    /// The access time of the inode, as a `(tv_sec, tv_nsec)` pair.
+    pub atime: (i64, i64),
+    /// The modification time of the inode, as a `(tv_sec, tv_nsec)` pair.
+    pub mtime: (i64, i64),
+    /// The extended attributes of the inode, as `(name, value)` pairs. This is
+    /// always empty for symlinks.
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl EntryMetadata {
+    fn is_symlink(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFLNK
+    }
+}
+
+/// A snapshot of the metadata of a directory tree, created with
+/// [`Root::snapshot_metadata`] and applied with [`Root::restore_metadata`].
+///
+/// All of the fields are public so that callers can store the snapshot in
+/// whatever format they prefer. Alternatively, [`MetadataSnapshot::to_text`]
+/// and [`MetadataSnapshot::parse`] provide a stable line-based text encoding.
+///
+/// [`Root::snapshot_metadata`]: struct.Root.html#method.snapshot_metadata
+/// [`Root::restore_metadata`]: struct.Root.html#method.restore_metadata
+/// [`MetadataSnapshot::to_text`]: struct.MetadataSnapshot.html#method.to_text
+/// [`MetadataSnapshot::parse`]: struct.MetadataSnapshot.html#method.parse
+// TODO: Implement serde::{Serialize, Deserialize} once we have optional
+//       dependencies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MetadataSnapshot {
+    /// The entries in the snapshot, as `(path, metadata)` pairs. The paths are
+    /// relative to the directory the snapshot was taken of (which has an empty
+    /// path), and parent directories always precede their children.
+    pub entries: Vec<(PathBuf, EntryMetadata)>,
+}
+
+/// The first line of the text encoding of a [`MetadataSnapshot`].
+///
+/// [`MetadataSnapshot`]: struct.MetadataSnapshot.html
+const SNAPSHOT_HEADER: &str = "pathrs-metadata-snapshot v1";
+
+/// Append `bytes` to `text`, percent-encoding every byte other than ASCII
+/// alphanumerics and a few harmless punctuation characters (so the result never
+/// contains whitespace, `=` or `%`).
+fn encode_bytes(text: &mut String, bytes: &[u8]) {
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"/._-+,:@".contains(&byte) {
+            text.push(byte as char);
+        } else {
+            let _ = write!(text, "%{:02x}", byte);
+        }
+    }
+}
+
+fn decode_bytes(field: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut iter = field.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let byte = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        bytes.push(byte.ok_or_else(|| format!("invalid percent-encoding in {:?}", field))?);
+    }
+    Ok(bytes)
+}
+
+fn parse_time(field: &str) -> Result<(i64, i64), String> {
+    let invalid = || format!("invalid timestamp {:?}", field);
+    let pos = field.find('.').ok_or_else(invalid)?;
+    let sec = field[..pos].parse().map_err(|_| invalid())?;
+    let nsec = field[pos + 1..].parse().map_err(|_| invalid())?;
+    if !(0..1_000_000_000).contains(&nsec) {
+        return Err(invalid());
+    }
+    Ok((sec, nsec))
+}
+
+fn parse_entry(line: &str) -> Result<(PathBuf, EntryMetadata), String> {
+    let mut fields = line.split(' ');
+    let mut next = |name: &str| {
+        fields
+            .next()
+            .filter(|field| !field.is_empty())
+            .ok_or_else(|| format!("missing {} field", name))
+    };
+    let path = decode_bytes(next("path")?)?;
+    let path = match path.split_first() {
+        Some((b'/', path)) => PathBuf::from(OsStr::from_bytes(path)),
+        _ => return Err("path must start with '/'".into()),
+    };
+    let mode = next("mode")?;
+    let mode = u32::from_str_radix(mode, 8).map_err(|_| format!("invalid mode {:?}", mode))?;
+    let uid = next("uid")?;
+    let uid = uid.parse().map_err(|_| format!("invalid uid {:?}", uid))?;
+    let gid = next("gid")?;
+    let gid = gid.parse().map_err(|_| format!("invalid gid {:?}", gid))?;
+    let atime = parse_time(next("atime")?)?;
+    let mtime = parse_time(next("mtime")?)?;
+    let xattrs = fields
+        .map(|xattr| {
+            let pos = xattr
+                .find('=')
+                .ok_or_else(|| format!("expected 'name=value', got {:?}", xattr))?;
+            Ok((
+                OsString::from_vec(decode_bytes(&xattr[..pos])?),
+                decode_bytes(&xattr[pos + 1..])?,
+            ))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((
+        path,
+        EntryMetadata {
+            mode,
+            uid,
+            gid,
+            atime,
+            mtime,
+            xattrs,
+        },
+    ))
+}
+
+impl MetadataSnapshot {
+    /// Encode the snapshot as text, which can be turned back into an identical
+    /// [`MetadataSnapshot`] with [`MetadataSnapshot::parse`].
+    ///
+    /// The first line is `pathrs-metadata-snapshot v1`, followed by one line per
+    /// entry (in order) with space-separated fields:
+    ///
+    /// ```text
+    /// <path> <mode> <uid> <gid> <atime> <mtime> [<xattr-name>=<xattr-value>...]
+    /// ```
+    ///
+    /// The path is prefixed with `/` (so the directory the snapshot was taken
+    /// of is `/`), the mode is in octal and the timestamps are formatted as
+    /// `<tv_sec>.<tv_nsec>`. Paths and xattrs are percent-encoded, except for
+    /// ASCII alphanumerics and `/._-+,:@`.
+    ///
+    /// [`MetadataSnapshot`]: struct.MetadataSnapshot.html
+    /// [`MetadataSnapshot::parse`]: struct.MetadataSnapshot.html#method.parse
+    pub fn to_text(&self) -> String {
+        let mut text = String::from(SNAPSHOT_HEADER);
+        text.push('\n');
+        for (path, meta) in &self.entries {
+            text.push('/');
+            encode_bytes(&mut text, path.as_os_str().as_bytes());
+            let _ = write!(
+                text,
+                " {:o} {} {} {}.{:09} {}.{:09}",
+                meta.mode,
+                meta.uid,
+                meta.gid,
+                meta.atime.0,
+                meta.atime.1,
+                meta.mtime.0,
+                meta.mtime.1
+            );
+            for (name, value) in &meta.xattrs {
+                text.push(' ');
+                encode_bytes(&mut text, name.as_bytes());
+                text.push('=');
+                encode_bytes(&mut text, value);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Parse a [`MetadataSnapshot`] from the text encoding produced by
+    /// [`MetadataSnapshot::to_text`]. Empty lines are ignored.
+    ///
+    /// # Errors
+    ///
+    /// If the header line is missing (or has an unknown version), or any entry
+    /// is malformed, an [`Error::InvalidArgument`] is returned.
+    ///
+    /// [`MetadataSnapshot`]: struct.MetadataSnapshot.html
+    /// [`MetadataSnapshot::to_text`]: struct.MetadataSnapshot.html#method.to_text
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty());
+        ensure!(
+            lines.next().map(|(_, line)| line) == Some(SNAPSHOT_HEADER),
+            error::InvalidArgument {
+                name: "snapshot",
+                description: format!("missing {:?} header", SNAPSHOT_HEADER),
+            }
+        );
+
+        let mut snapshot = Self::default();
+        for (idx, line) in lines {
+            match parse_entry(line) {
+                Ok(entry) => snapshot.entries.push(entry),
+                Err(description) => {
+                    return error::InvalidArgument {
+                        name: "snapshot",
+                        description: format!("line {}: {}", idx + 1, description),
+                    }
+                    .fail()
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Open the inode at `subpath` under `base` without following the final
+/// component (so that symlinks have their own metadata restored).
+fn open_nofollow(root: &Root, base: &Path, subpath: &Path) -> Result<File, Error> {
+    let (parent, name) = match (subpath.parent(), subpath.file_name()) {
+        (Some(parent), Some(name)) => (base.join(parent), name),
+        _ => {
+            return Ok(root
+                .resolve(base)
+                .wrap("resolve snapshot base directory")?
+                .into_file())
+        }
+    };
+    let dir = root
+        .resolve(parent)
+        .wrap("resolve parent of snapshot entry")?;
+    syscalls::openat(dir.inner.as_raw_fd(), name, libc::O_PATH, 0).context(error::RawOsError {
+        operation: "open snapshot entry",
+    })
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, capture the metadata of every inode in the
+    /// directory tree at `path` (including `path` itself).
+    ///
+    /// The tree is traversed with [`Root::walk`], so symlinks are never
+    /// followed.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    pub fn snapshot_metadata<P: AsRef<Path>>(&self, path: P) -> Result<MetadataSnapshot, Error> {
+        let mut snapshot = MetadataSnapshot::default();
+        for entry in self.walk(path)? {
+            let entry = entry?;
+            let meta = entry.metadata();
+            let xattrs = if meta.file_type().is_symlink() {
+                Vec::new()
+            } else {
+                let file = &entry.handle().inner;
+                let mut xattrs = Vec::new();
+                for name in file.list_xattrs()? {
+                    let value = file.get_xattr(&name)?;
+                    xattrs.push((name, value));
+                }
+                xattrs
+            };
+            snapshot.entries.push((
+                entry.path().to_path_buf(),
+                EntryMetadata {
+                    mode: meta.mode(),
+                    uid: meta.uid(),
+                    gid: meta.gid(),
+                    atime: (meta.atime(), meta.atime_nsec()),
+                    mtime: (meta.mtime(), meta.mtime_nsec()),
+                    xattrs,
+                },
+            ));
+        }
+        Ok(snapshot)
+    }
+
+    /// Within the [`Root`]'s tree, re-apply the metadata in `snapshot` to the
+    /// directory tree at `path`.
+    ///
+    /// Each entry in the snapshot is resolved inside the [`Root`] (without
+    /// following the final component) and then has its owner, mode, extended
+    /// attributes and timestamps set -- in that order, so that the `chown(2)`
+    /// doesn't clear any set-id bits and the timestamps are not clobbered by
+    /// the other changes. Symlinks only have their owner restored. Children are
+    /// restored before their parents, so that directory timestamps are
+    /// preserved.
+    ///
+    /// Extended attributes present on an inode but not in the snapshot are
+    /// left untouched.
+    ///
+    /// # Errors
+    ///
+    /// If any of the entries in `snapshot` no longer exist, or the type of an
+    /// inode has changed since the snapshot was taken, an error is returned.
+    /// Entries restored before the error are not rolled back.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn restore_metadata<P: AsRef<Path>>(
+        &self,
+        path: P,
+        snapshot: &MetadataSnapshot,
+    ) -> Result<(), Error> {
+        let base = path.as_ref();
+        for (subpath, meta) in snapshot.entries.iter().rev() {
+            let file = open_nofollow(self, base, subpath)?;
+            let mode = file
+                .metadata()
+                .context(error::OsError {
+                    operation: "fstat snapshot entry",
+                })?
+                .mode();
+            ensure!(
+                mode & libc::S_IFMT == meta.mode & libc::S_IFMT,
+                error::SafetyViolation {
+                    description: format!(
+                        "inode type of {:?} changed since snapshot was taken",
+                        subpath
+                    ),
+                }
+            );
+
+            file.set_owner(meta.uid, meta.gid)
+                .wrap("restore owner of snapshot entry")?;
+            if meta.is_symlink() {
+                continue;
+            }
+            file.set_mode(self.sanitize_mode(meta.mode)?)
+                .wrap("restore mode of snapshot entry")?;
+            for (name, value) in &meta.xattrs {
+                file.set_xattr(name, value)
+                    .wrap("restore xattr of snapshot entry")?;
+            }
+            file.set_timestamps(meta.atime, meta.mtime)
+                .wrap("restore times of snapshot entry")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryMetadata, MetadataSnapshot};
+
+    use crate::{tests::TempDir, Root};
+
+    use std::{
+        ffi::{OsStr, OsString},
+        fs,
+        os::unix::ffi::OsStrExt,
+        path::PathBuf,
+    };
+
+    fn entry(path: &[u8], xattrs: Vec<(&[u8], &[u8])>) -> (PathBuf, EntryMetadata) {
+        (
+            PathBuf::from(OsStr::from_bytes(path)),
+            EntryMetadata {
+                mode: libc::S_IFREG | 0o4755,
+                uid: 1000,
+                gid: u32::MAX,
+                atime: (-1, 999_999_999),
+                mtime: (1_600_000_000, 0),
+                xattrs: xattrs
+                    .into_iter()
+                    .map(|(name, value)| (OsString::from(OsStr::from_bytes(name)), value.to_vec()))
+                    .collect(),
+            },
+        )
+    }
+
+    #[test]
+    fn snapshot_text_roundtrip() {
+        let snapshot = MetadataSnapshot {
+            entries: vec![
+                entry(b"", vec![]),
+                entry(b"dir/file name", vec![(b"user.a", b"")]),
+                entry(
+                    b"dir/\xff\n%=\t",
+                    vec![
+                        (b"security.selinux", b"system_u:object_r:x\0"),
+                        (b"user.=", b"\x00\xff%= \n"),
+                    ],
+                ),
+            ],
+        };
+        let text = snapshot.to_text();
+        // Every entry is on its own line, and there is no whitespace inside
+        // the fields.
+        assert_eq!(text.lines().count(), 4);
+        assert_eq!(text.matches(' ').count(), 1 + 5 * 3 + 3);
+        assert_eq!(MetadataSnapshot::parse(&text).unwrap(), snapshot);
+        assert_eq!(
+            MetadataSnapshot::parse(&MetadataSnapshot::default().to_text()).unwrap(),
+            MetadataSnapshot::default()
+        );
+    }
+
+    #[test]
+    fn snapshot_text_invalid() {
+        let header = "pathrs-metadata-snapshot v1\n";
+        for text in &[
+            "",
+            "pathrs-metadata-snapshot v2\n",
+            "/ 100644 0 0 0.0 0.0\n",
+        ] {
+            assert!(MetadataSnapshot::parse(text).is_err(), "{:?}", text);
+        }
+        for line in &[
+            "a 100644 0 0 0.0 0.0",
+            "/ 100644 0 0 0.0",
+            "/ 100648 0 0 0.0 0.0",
+            "/ 100644 -1 0 0.0 0.0",
+            "/ 100644 0 0 0 0.0",
+            "/ 100644 0 0 0.1000000000 0.0",
+            "/ 100644 0 0 0.0 0.0 user.a",
+            "/%f 100644 0 0 0.0 0.0",
+            "/%zz 100644 0 0 0.0 0.0",
+            "/  100644 0 0 0.0 0.0",
+        ] {
+            assert!(
+                MetadataSnapshot::parse(&format!("{}{}\n", header, line)).is_err(),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_roundtrip_restore() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("dir")).unwrap();
+        fs::write(dir.path().join("dir/file"), b"data").unwrap();
+        let root = Root::open(dir.path()).unwrap();
+
+        let snapshot = root.snapshot_metadata("/").unwrap();
+        let parsed = MetadataSnapshot::parse(&snapshot.to_text()).unwrap();
+        assert_eq!(parsed, snapshot);
+        root.restore_metadata("/", &parsed).unwrap();
+        assert_eq!(root.snapshot_metadata("/").unwrap(), snapshot);
+    }
+}
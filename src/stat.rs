@@ -0,0 +1,321 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    metrics::{self, FallbackEvent},
+    root::path_split,
+    syscalls, Root,
+};
+
+use std::{
+    fs::File,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+bitflags! {
+    /// The set of [`Stat`] fields which were actually filled in by the
+    /// kernel (a subset of the `stx_mask` returned by `statx(2)`).
+    ///
+    /// [`Stat`]: struct.Stat.html
+    #[derive(Default)]
+    pub struct StatMask: u32 {
+        /// All of the basic `struct stat` fields.
+        const BASIC_STATS = libc::STATX_BASIC_STATS;
+        /// The birth time ([`Stat::btime`]).
+        ///
+        /// [`Stat::btime`]: struct.Stat.html#structfield.btime
+        const BTIME = libc::STATX_BTIME;
+        /// The mount id ([`Stat::mnt_id`]).
+        ///
+        /// [`Stat::mnt_id`]: struct.Stat.html#structfield.mnt_id
+        const MNT_ID = libc::STATX_MNT_ID;
+    }
+}
+
+bitflags! {
+    /// Attributes of an inode, as reported in `stx_attributes` by `statx(2)`.
+    /// Most of these correspond to the inode flags set with `chattr(1)`.
+    #[derive(Default)]
+    pub struct StatAttributes: u64 {
+        /// The file is compressed by the filesystem.
+        const COMPRESSED = libc::STATX_ATTR_COMPRESSED as u64;
+        /// The file cannot be modified, deleted or renamed (`chattr +i`).
+        const IMMUTABLE = libc::STATX_ATTR_IMMUTABLE as u64;
+        /// The file can only be opened in append mode (`chattr +a`).
+        const APPEND = libc::STATX_ATTR_APPEND as u64;
+        /// The file is not a candidate for backup (`chattr +d`).
+        const NODUMP = libc::STATX_ATTR_NODUMP as u64;
+        /// The file is encrypted by the filesystem.
+        const ENCRYPTED = libc::STATX_ATTR_ENCRYPTED as u64;
+        /// The directory is an automount trigger.
+        const AUTOMOUNT = libc::STATX_ATTR_AUTOMOUNT as u64;
+        /// The inode is the root of a mount.
+        const MOUNT_ROOT = libc::STATX_ATTR_MOUNT_ROOT as u64;
+        /// The file has fs-verity enabled.
+        const VERITY = libc::STATX_ATTR_VERITY as u64;
+        /// The file is in the DAX (CPU direct access) state.
+        const DAX = libc::STATX_ATTR_DAX as u64;
+    }
+}
+
+/// Metadata of an inode, as returned by [`Root::lstat_nofollow`].
+///
+/// The fields have the same meaning as the corresponding `struct stat`
+/// fields, with the timestamps given as `(tv_sec, tv_nsec)` pairs. The
+/// `Option` fields are only available from `statx(2)`, and are `None` if the
+/// kernel (or filesystem) didn't provide them -- [`Stat::mask`] describes
+/// which fields were returned.
+///
+/// [`Root::lstat_nofollow`]: struct.Root.html#method.lstat_nofollow
+/// [`Stat::mask`]: struct.Stat.html#structfield.mask
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Stat {
+    /// Device containing the inode (`st_dev`).
+    pub dev: u64,
+    /// Inode number (`st_ino`).
+    pub ino: u64,
+    /// File type and mode (`st_mode`).
+    pub mode: u32,
+    /// Number of hard links (`st_nlink`).
+    pub nlink: u64,
+    /// Owning user (`st_uid`).
+    pub uid: u32,
+    /// Owning group (`st_gid`).
+    pub gid: u32,
+    /// Device number, for device inodes (`st_rdev`).
+    pub rdev: u64,
+    /// Size in bytes (`st_size`).
+    pub size: u64,
+    /// Preferred block size for I/O (`st_blksize`).
+    pub blksize: u64,
+    /// Number of 512-byte blocks allocated (`st_blocks`).
+    pub blocks: u64,
+    /// Last access time (`st_atim`).
+    pub atime: (i64, i64),
+    /// Last modification time (`st_mtim`).
+    pub mtime: (i64, i64),
+    /// Last status change time (`st_ctim`).
+    pub ctime: (i64, i64),
+    /// Creation time (`stx_btime`).
+    pub btime: Option<(i64, i64)>,
+    /// Attributes of the inode (`stx_attributes`). Only the attributes in
+    /// `attributes_supported` are meaningful.
+    pub attributes: Option<StatAttributes>,
+    /// Attributes supported by the filesystem (`stx_attributes_mask`).
+    pub attributes_supported: StatAttributes,
+    /// Id of the mount containing the inode (`stx_mnt_id`), as also shown in
+    /// `/proc/self/mountinfo`.
+    pub mnt_id: Option<u64>,
+    /// The set of fields which were returned.
+    pub mask: StatMask,
+}
+
+/// The fields requested from `statx(2)` for a [`Stat`].
+const STATX_MASK: u32 = libc::STATX_BASIC_STATS | libc::STATX_BTIME | libc::STATX_MNT_ID;
+
+impl From<libc::statx> for Stat {
+    fn from(stx: libc::statx) -> Self {
+        let mask = StatMask::from_bits_truncate(stx.stx_mask);
+        let attributes_supported = StatAttributes::from_bits_truncate(stx.stx_attributes_mask);
+        Self {
+            dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+            ino: stx.stx_ino,
+            mode: stx.stx_mode as u32,
+            nlink: stx.stx_nlink as u64,
+            uid: stx.stx_uid,
+            gid: stx.stx_gid,
+            rdev: libc::makedev(stx.stx_rdev_major, stx.stx_rdev_minor),
+            size: stx.stx_size,
+            blksize: stx.stx_blksize as u64,
+            blocks: stx.stx_blocks,
+            atime: (stx.stx_atime.tv_sec, stx.stx_atime.tv_nsec as i64),
+            mtime: (stx.stx_mtime.tv_sec, stx.stx_mtime.tv_nsec as i64),
+            ctime: (stx.stx_ctime.tv_sec, stx.stx_ctime.tv_nsec as i64),
+            btime: if mask.contains(StatMask::BTIME) {
+                Some((stx.stx_btime.tv_sec, stx.stx_btime.tv_nsec as i64))
+            } else {
+                None
+            },
+            // Kernels which predate stx_attributes_mask leave it zeroed.
+            attributes: if attributes_supported.is_empty() {
+                None
+            } else {
+                Some(StatAttributes::from_bits_truncate(stx.stx_attributes) & attributes_supported)
+            },
+            attributes_supported,
+            mnt_id: if mask.contains(StatMask::MNT_ID) {
+                Some(stx.stx_mnt_id)
+            } else {
+                None
+            },
+            mask,
+        }
+    }
+}
+
+impl From<libc::stat> for Stat {
+    // The types of some struct stat fields differ between architectures.
+    #[allow(clippy::unnecessary_cast)]
+    fn from(st: libc::stat) -> Self {
+        Self {
+            dev: st.st_dev,
+            ino: st.st_ino,
+            mode: st.st_mode,
+            nlink: st.st_nlink as u64,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            rdev: st.st_rdev,
+            size: st.st_size as u64,
+            blksize: st.st_blksize as u64,
+            blocks: st.st_blocks as u64,
+            atime: (st.st_atime, st.st_atime_nsec),
+            mtime: (st.st_mtime, st.st_mtime_nsec),
+            ctime: (st.st_ctime, st.st_ctime_nsec),
+            btime: None,
+            attributes: None,
+            attributes_supported: StatAttributes::empty(),
+            mnt_id: None,
+            mask: StatMask::BASIC_STATS,
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, get the metadata of the inode at `path`
+    /// without following a trailing symlink (as with `lstat(2)`).
+    ///
+    /// Only the parent directory of `path` is resolved to a handle -- the
+    /// trailing component is passed directly to `statx(2)` (relative to the
+    /// parent handle, with `AT_SYMLINK_NOFOLLOW`) without opening it. This
+    /// avoids the cost of opening an `O_PATH` descriptor for every inode in
+    /// metadata-only workloads (such as `du`-style tree scans). On kernels
+    /// without `statx(2)`, `fstatat(2)` is used instead.
+    ///
+    /// # Errors
+    ///
+    /// If `path` has no trailing component (such as `/`), an
+    /// [`Error::InvalidArgument`] is returned. If the parent of `path` does not
+    /// exist or the trailing component doesn't exist, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+    pub fn lstat_nofollow<P: AsRef<Path>>(&self, path: P) -> Result<Stat, Error> {
+        let (parent, name) =
+            path_split(path.as_ref()).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .resolve(parent)
+            .wrap("resolve target parent directory for lstat")?
+            .inner;
+        stat_at(dir.as_raw_fd(), name)
+    }
+
+    /// Within the [`Root`]'s tree, check whether each of `paths` exists and
+    /// get its metadata (without following trailing symlinks, as with
+    /// [`Root::lstat_nofollow`]).
+    ///
+    /// This is intended for checking large lists of paths (such as the file
+    /// list of a package). Paths are grouped by their parent directory so that
+    /// each parent directory is only resolved once, and no handles are kept
+    /// once the scan is complete.
+    ///
+    /// # Errors
+    ///
+    /// The result of each path is returned, in the same order as `paths`. A
+    /// path which doesn't exist (including because one of its parent
+    /// components is missing or is not a directory) is `Ok(None)` rather than
+    /// an error.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::lstat_nofollow`]: struct.Root.html#method.lstat_nofollow
+    pub fn try_resolve_all<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<Option<Stat>, Error>> {
+        let mut order = (0..paths.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&idx| paths[idx].as_ref().parent());
+
+        let mut results = (0..paths.len()).map(|_| None).collect::<Vec<_>>();
+        // The most recently resolved parent, and the handle to it (or None if
+        // it does not exist). Resolution errors are not cached.
+        let mut current: Option<(&Path, Option<File>)> = None;
+        for idx in order {
+            let ret = match path_split(paths[idx].as_ref()) {
+                Err(err) => Err(err),
+                Ok((parent, name)) => {
+                    if current.as_ref().map(|(cached, _)| *cached) != Some(parent) {
+                        current = match self.resolve(parent) {
+                            Ok(handle) => Some((parent, Some(handle.inner))),
+                            Err(err) if is_missing(&err) => Some((parent, None)),
+                            Err(err) => {
+                                results[idx] = Some(Err(err).wrap("resolve parent directory"));
+                                continue;
+                            }
+                        };
+                    }
+                    match &current {
+                        Some((_, Some(dir))) => match stat_at(dir.as_raw_fd(), name) {
+                            Ok(stat) => Ok(Some(stat)),
+                            Err(err) if is_missing(&err) => Ok(None),
+                            Err(err) => Err(err),
+                        },
+                        _ => Ok(None),
+                    }
+                }
+            };
+            results[idx] = Some(ret);
+        }
+        results
+            .into_iter()
+            .map(|ret| ret.expect("every path should've been scanned"))
+            .collect()
+    }
+}
+
+/// Does the error indicate that the path doesn't exist?
+fn is_missing(err: &Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOENT) | Some(libc::ENOTDIR))
+}
+
+/// Get the id of the mount containing `fd` (`stx_mnt_id`), if the kernel
+/// provides it.
+pub(crate) fn mount_id(fd: RawFd) -> Result<Option<u64>, Error> {
+    stat_at(fd, Path::new(""))
+        .map(|stat| stat.mnt_id)
+        .wrap("get mount id")
+}
+
+/// Get the metadata of `name` inside `dirfd` without following symlinks.
+pub(crate) fn stat_at(dirfd: RawFd, name: &Path) -> Result<Stat, Error> {
+    match syscalls::statx(dirfd, name, STATX_MASK) {
+        Ok(stx) => Ok(stx.into()),
+        Err(err) if err.root_cause().raw_os_error() == Some(libc::ENOSYS) => {
+            metrics::record_fallback(FallbackEvent::StatxUnsupported);
+            syscalls::fstatat(dirfd, name)
+                .map(Stat::from)
+                .context(error::RawOsError {
+                    operation: "lstat trailing component",
+                })
+        }
+        Err(err) => Err(err).context(error::RawOsError {
+            operation: "statx trailing component",
+        }),
+    }
+}
@@ -0,0 +1,256 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Asynchronous stream versions of [`Root::walk`] and [`Handle::read_dir`].
+//!
+//! [`Root::walk`]: ../struct.Root.html#method.walk
+//! [`Handle::read_dir`]: ../struct.Handle.html#method.read_dir
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    DirEntry, Handle, Root, WalkEntry,
+};
+
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use snafu::ResultExt;
+
+/// Number of items the worker thread of an [`IterStream`] may read ahead of
+/// the consumer.
+///
+/// [`IterStream`]: struct.IterStream.html
+const READ_AHEAD: usize = 64;
+
+/// An asynchronous stream of the items produced by a blocking iterator (such
+/// as a [`Walk`] or [`Dirents`]), created with [`Root::walk_stream`] and
+/// [`Handle::read_dir_stream`].
+///
+/// The iterator is driven by a dedicated thread, which reads at most a small
+/// number of items ahead of the consumer (so the number of file descriptors
+/// held by a [`WalkStream`] stays bounded). Dropping the stream stops the
+/// thread once it next tries to hand over an item.
+///
+/// Note that [`IterStream`] does **not** implement `futures::Stream` (libpathrs
+/// doesn't depend on `futures`), so it cannot be passed to stream combinators
+/// directly. [`IterStream::poll_next_item`] works like
+/// `futures::Stream::poll_next` though, so it is easy to adapt:
+///
+/// ```ignore
+/// let mut walk = root.walk_stream("/var/lib")?;
+/// let stream = futures::stream::poll_fn(move |cx| Pin::new(&mut walk).poll_next_item(cx));
+/// ```
+///
+/// [`Walk`]: struct.Walk.html
+/// [`Dirents`]: struct.Dirents.html
+/// [`WalkStream`]: type.WalkStream.html
+/// [`Root::walk_stream`]: struct.Root.html#method.walk_stream
+/// [`Handle::read_dir_stream`]: struct.Handle.html#method.read_dir_stream
+/// [`IterStream`]: struct.IterStream.html
+/// [`IterStream::poll_next_item`]: struct.IterStream.html#method.poll_next_item
+#[derive(Debug)]
+pub struct IterStream<T> {
+    receiver: mpsc::Receiver<T>,
+    /// The waker of the last task which polled the stream without getting an
+    /// item.
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+/// An asynchronous [`Root::walk`], created with [`Root::walk_stream`].
+///
+/// [`Root::walk`]: struct.Root.html#method.walk
+/// [`Root::walk_stream`]: struct.Root.html#method.walk_stream
+pub type WalkStream = IterStream<Result<WalkEntry, Error>>;
+
+/// An asynchronous [`Handle::read_dir`], created with
+/// [`Handle::read_dir_stream`].
+///
+/// [`Handle::read_dir`]: struct.Handle.html#method.read_dir
+/// [`Handle::read_dir_stream`]: struct.Handle.html#method.read_dir_stream
+pub type DirentsStream = IterStream<Result<DirEntry, Error>>;
+
+fn wake(waker: &Mutex<Option<Waker>>) {
+    if let Some(waker) = waker.lock().unwrap().take() {
+        waker.wake();
+    }
+}
+
+impl<T: Send + 'static> IterStream<T> {
+    /// Drive `iter` from a new thread (named `name`).
+    pub(crate) fn spawn<I>(name: &str, iter: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = T> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(READ_AHEAD);
+        let waker = Arc::new(Mutex::new(None));
+        let worker_waker = Arc::clone(&waker);
+        thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                for item in iter {
+                    // The stream was dropped.
+                    if sender.send(item).is_err() {
+                        return;
+                    }
+                    wake(&worker_waker);
+                }
+                // Disconnect before waking, so the consumer sees the end of
+                // the stream.
+                drop(sender);
+                wake(&worker_waker);
+            })
+            .context(error::OsError {
+                operation: format!("spawn {} thread", name),
+            })?;
+        Ok(Self { receiver, waker })
+    }
+}
+
+impl<T> IterStream<T> {
+    /// Attempt to get the next item of the stream, registering the current
+    /// task to be woken up if no item is available yet. Returns
+    /// `Poll::Ready(None)` once the stream is exhausted. This has the same
+    /// semantics as `futures::Stream::poll_next`, but is not part of a
+    /// `Stream` implementation.
+    pub fn poll_next_item(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        match this.receiver.try_recv() {
+            Ok(item) => return Poll::Ready(Some(item)),
+            Err(mpsc::TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(mpsc::TryRecvError::Empty) => (),
+        }
+        *this.waker.lock().unwrap() = Some(cx.waker().clone());
+        // The worker may have produced an item before we registered the waker.
+        match this.receiver.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(mpsc::TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(mpsc::TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, walk the directory tree starting at `path`
+    /// as an asynchronous stream. This is the same as [`Root::walk`], except
+    /// that the walk is driven by a separate thread. See [`IterStream`] for
+    /// more details.
+    ///
+    /// # Errors
+    ///
+    /// If `path` doesn't exist or is not a directory, an error is returned.
+    /// Errors encountered during the walk are returned by the stream.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    /// [`IterStream`]: struct.IterStream.html
+    pub fn walk_stream<P: AsRef<Path>>(&self, path: P) -> Result<WalkStream, Error> {
+        let walk = self.walk(path)?;
+        IterStream::spawn("pathrs-walk", walk).wrap("start walk stream")
+    }
+}
+
+impl Handle {
+    /// Read the entries of the directory referenced by the [`Handle`] as an
+    /// asynchronous stream. This is the same as [`Handle::read_dir`], except
+    /// that the directory is read by a separate thread. See [`IterStream`]
+    /// for more details.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Handle`] is not a directory, an error is returned.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    /// [`Handle::read_dir`]: struct.Handle.html#method.read_dir
+    /// [`IterStream`]: struct.IterStream.html
+    pub fn read_dir_stream(&self) -> Result<DirentsStream, Error> {
+        let dirents = self.read_dir()?;
+        IterStream::spawn("pathrs-read-dir", dirents).wrap("start read_dir stream")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IterStream;
+
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::{self, Thread},
+    };
+
+    /// Wakes the test thread by unparking it.
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Collect every item of `stream`, parking the thread while the stream is
+    /// pending.
+    fn collect<T>(mut stream: IterStream<T>) -> Vec<T> {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut items = Vec::new();
+        loop {
+            match Pin::new(&mut stream).poll_next_item(&mut cx) {
+                Poll::Ready(Some(item)) => items.push(item),
+                Poll::Ready(None) => return items,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn iter_stream_yields_all_items() {
+        let stream = IterStream::spawn("test-stream", 0..1000).unwrap();
+        assert_eq!(collect(stream), (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_stream_empty() {
+        let stream = IterStream::spawn("test-stream", std::iter::empty::<u32>()).unwrap();
+        assert!(collect(stream).is_empty());
+    }
+
+    #[test]
+    fn iter_stream_drop_stops_worker() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let iter = (0..).inspect(move |n| {
+            let _ = tx.send(*n);
+        });
+        let mut stream = IterStream::spawn("test-stream", iter).unwrap();
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        while Pin::new(&mut stream).poll_next_item(&mut cx).is_pending() {
+            thread::park();
+        }
+        drop(stream);
+        // Once the stream is gone the worker exits, disconnecting the channel.
+        assert!(rx.iter().count() < 10_000);
+    }
+}
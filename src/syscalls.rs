@@ -21,22 +21,27 @@
 
 use crate::{
     error::Backtrace,
+    opstats, retry,
     utils::{RawFdExt, ToCString},
 };
 
 use std::{
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     fmt,
     fs::File,
-    io::Error as IOError,
+    io::{Error as IOError, Read},
+    mem,
     os::unix::{
         ffi::OsStrExt,
-        io::{FromRawFd, RawFd},
+        io::{AsRawFd, FromRawFd, RawFd},
     },
     path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Instant,
 };
 
-use libc::{c_int, dev_t, mode_t, stat, statfs};
+use libc::{c_int, c_void, dev_t, mode_t, stat, statfs};
 use snafu::ResultExt;
 
 /// Representation of a file descriptor and its associated path at a given point
@@ -49,15 +54,17 @@ use snafu::ResultExt;
 /// Note that the file descriptor value is very unlikely to reference a live
 /// file descriptor. Its value is only used for informational purposes.
 // TODO: Should probably be #[doc(hidden)].
+// The path is a Box<Path> (rather than a PathBuf) to keep syscall errors
+// (which often contain two of these) small.
 #[derive(Clone, Debug)]
-pub struct FrozenFd(c_int, Option<PathBuf>);
+pub struct FrozenFd(c_int, Option<Box<Path>>);
 
 // TODO: Should probably be a pub(crate) impl.
 impl From<RawFd> for FrozenFd {
     fn from(fd: RawFd) -> Self {
         // SAFETY: as_unsafe_path is safe here since it is only used for
         //         pretty-printing error messages and no real logic.
-        FrozenFd(fd, fd.as_unsafe_path().ok())
+        FrozenFd(fd, fd.as_unsafe_path().ok().map(PathBuf::into_boxed_path))
     }
 }
 
@@ -75,6 +82,224 @@ impl fmt::Display for FrozenFd {
     }
 }
 
+impl FrozenFd {
+    /// The file descriptor number (or `AT_FDCWD`).
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.0
+    }
+
+    /// The path the file descriptor referred to (according to
+    /// `/proc/self/fd`) when it was frozen, which for syscall errors is the
+    /// time of the failure. This is only informational, and should never be
+    /// used to operate on the file.
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.1.as_deref()
+    }
+}
+
+/// Decode a set of flags into their symbolic form (such as `O_RDONLY|O_PATH`),
+/// with any unknown bits printed in hex. Flags are matched in order, and each
+/// match removes its bits, so multi-bit flags (like `O_TMPFILE`) need to come
+/// before the flags they contain.
+fn decode_flags(value: u64, zero: &str, names: &[(u64, &str)]) -> String {
+    let mut rest = value;
+    let mut parts = Vec::new();
+    for &(bits, name) in names {
+        if bits != 0 && rest & bits == bits {
+            parts.push(name.to_string());
+            rest &= !bits;
+        }
+    }
+    if rest != 0 {
+        parts.push(format!("0x{:x}", rest));
+    }
+    if parts.is_empty() {
+        zero.to_string()
+    } else {
+        parts.join("|")
+    }
+}
+
+/// Decode `O_*` flags.
+fn open_flags(flags: c_int) -> String {
+    let access = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => "O_RDONLY",
+        libc::O_WRONLY => "O_WRONLY",
+        libc::O_RDWR => "O_RDWR",
+        _ => "O_ACCMODE",
+    };
+    let rest = decode_flags(
+        (flags & !libc::O_ACCMODE) as u32 as u64,
+        "",
+        &[
+            (libc::O_TMPFILE as u64, "O_TMPFILE"),
+            (libc::O_SYNC as u64, "O_SYNC"),
+            (libc::O_CREAT as u64, "O_CREAT"),
+            (libc::O_EXCL as u64, "O_EXCL"),
+            (libc::O_NOCTTY as u64, "O_NOCTTY"),
+            (libc::O_TRUNC as u64, "O_TRUNC"),
+            (libc::O_APPEND as u64, "O_APPEND"),
+            (libc::O_NONBLOCK as u64, "O_NONBLOCK"),
+            (libc::O_DSYNC as u64, "O_DSYNC"),
+            (libc::O_ASYNC as u64, "O_ASYNC"),
+            (libc::O_DIRECT as u64, "O_DIRECT"),
+            (libc::O_LARGEFILE as u64, "O_LARGEFILE"),
+            (libc::O_DIRECTORY as u64, "O_DIRECTORY"),
+            (libc::O_NOFOLLOW as u64, "O_NOFOLLOW"),
+            (libc::O_NOATIME as u64, "O_NOATIME"),
+            (libc::O_CLOEXEC as u64, "O_CLOEXEC"),
+            (libc::O_PATH as u64, "O_PATH"),
+        ],
+    );
+    if rest.is_empty() {
+        access.to_string()
+    } else {
+        format!("{}|{}", access, rest)
+    }
+}
+
+/// Decode `AT_*` flags.
+fn at_flags(flags: c_int) -> String {
+    decode_flags(
+        flags as u32 as u64,
+        "0",
+        &[
+            (libc::AT_SYMLINK_NOFOLLOW as u64, "AT_SYMLINK_NOFOLLOW"),
+            (libc::AT_REMOVEDIR as u64, "AT_REMOVEDIR"),
+            (libc::AT_SYMLINK_FOLLOW as u64, "AT_SYMLINK_FOLLOW"),
+            (libc::AT_NO_AUTOMOUNT as u64, "AT_NO_AUTOMOUNT"),
+            (libc::AT_EMPTY_PATH as u64, "AT_EMPTY_PATH"),
+            (libc::AT_STATX_FORCE_SYNC as u64, "AT_STATX_FORCE_SYNC"),
+            (libc::AT_STATX_DONT_SYNC as u64, "AT_STATX_DONT_SYNC"),
+        ],
+    )
+}
+
+/// Decode `RESOLVE_*` flags.
+fn resolve_flags(flags: u64) -> String {
+    decode_flags(
+        flags,
+        "0",
+        &[
+            (unstable::RESOLVE_NO_XDEV, "RESOLVE_NO_XDEV"),
+            (unstable::RESOLVE_NO_MAGICLINKS, "RESOLVE_NO_MAGICLINKS"),
+            (unstable::RESOLVE_NO_SYMLINKS, "RESOLVE_NO_SYMLINKS"),
+            (unstable::RESOLVE_BENEATH, "RESOLVE_BENEATH"),
+            (unstable::RESOLVE_IN_ROOT, "RESOLVE_IN_ROOT"),
+            (unstable::RESOLVE_CACHED, "RESOLVE_CACHED"),
+        ],
+    )
+}
+
+/// Decode `RENAME_*` flags.
+fn rename_flags(flags: u32) -> String {
+    decode_flags(
+        flags as u64,
+        "0",
+        &[
+            (libc::RENAME_NOREPLACE as u64, "RENAME_NOREPLACE"),
+            (libc::RENAME_EXCHANGE as u64, "RENAME_EXCHANGE"),
+            (libc::RENAME_WHITEOUT as u64, "RENAME_WHITEOUT"),
+        ],
+    )
+}
+
+/// Decode `open_tree(2)` flags.
+fn open_tree_flags(flags: u32) -> String {
+    decode_flags(
+        flags as u64,
+        "0",
+        &[
+            (OPEN_TREE_CLONE as u64, "OPEN_TREE_CLONE"),
+            (libc::O_CLOEXEC as u64, "OPEN_TREE_CLOEXEC"),
+            (libc::AT_SYMLINK_NOFOLLOW as u64, "AT_SYMLINK_NOFOLLOW"),
+            (libc::AT_NO_AUTOMOUNT as u64, "AT_NO_AUTOMOUNT"),
+            (libc::AT_EMPTY_PATH as u64, "AT_EMPTY_PATH"),
+            (libc::AT_RECURSIVE as u64, "AT_RECURSIVE"),
+        ],
+    )
+}
+
+/// Decode `FD_*` flags.
+fn fd_flags(flags: c_int) -> String {
+    decode_flags(
+        flags as u32 as u64,
+        "0",
+        &[(libc::FD_CLOEXEC as u64, "FD_CLOEXEC")],
+    )
+}
+
+/// Decode `FALLOC_FL_*` flags.
+fn falloc_flags(flags: c_int) -> String {
+    decode_flags(
+        flags as u32 as u64,
+        "0",
+        &[
+            (libc::FALLOC_FL_KEEP_SIZE as u64, "FALLOC_FL_KEEP_SIZE"),
+            (libc::FALLOC_FL_PUNCH_HOLE as u64, "FALLOC_FL_PUNCH_HOLE"),
+            (
+                libc::FALLOC_FL_COLLAPSE_RANGE as u64,
+                "FALLOC_FL_COLLAPSE_RANGE",
+            ),
+            (libc::FALLOC_FL_ZERO_RANGE as u64, "FALLOC_FL_ZERO_RANGE"),
+            (
+                libc::FALLOC_FL_INSERT_RANGE as u64,
+                "FALLOC_FL_INSERT_RANGE",
+            ),
+            (
+                libc::FALLOC_FL_UNSHARE_RANGE as u64,
+                "FALLOC_FL_UNSHARE_RANGE",
+            ),
+        ],
+    )
+}
+
+/// Decode `XATTR_*` flags.
+fn xattr_flags(flags: c_int) -> String {
+    decode_flags(
+        flags as u32 as u64,
+        "0",
+        &[
+            (libc::XATTR_CREATE as u64, "XATTR_CREATE"),
+            (libc::XATTR_REPLACE as u64, "XATTR_REPLACE"),
+        ],
+    )
+}
+
+/// Decode a lease type, as in `fcntl(F_SETLEASE)`.
+fn lease_type(lease: c_int) -> String {
+    match lease {
+        libc::F_RDLCK => "F_RDLCK".to_string(),
+        libc::F_WRLCK => "F_WRLCK".to_string(),
+        libc::F_UNLCK => "F_UNLCK".to_string(),
+        lease => lease.to_string(),
+    }
+}
+
+/// Structured information about a failed syscall, returned by
+/// [`SyscallError::info`]. This is intended for diagnostics (such as
+/// structured logging of failures) -- the same information is included in
+/// the `Display` output of the error.
+///
+/// [`SyscallError::info`]: enum.SyscallError.html#method.info
+#[derive(Clone, Debug)]
+pub struct SyscallInfo<'a> {
+    /// Name of the syscall (such as `"openat2"`).
+    pub name: &'static str,
+    /// File descriptor arguments, in argument order. Each includes the path
+    /// the file descriptor referred to at the time of the failure.
+    pub fds: Vec<&'a FrozenFd>,
+    /// Path arguments, in argument order.
+    pub paths: Vec<&'a Path>,
+    /// Flag arguments decoded into their symbolic form (such as
+    /// `"O_RDONLY|O_CLOEXEC"`), in argument order.
+    pub flags: Vec<String>,
+    /// The `errno` the syscall failed with, if any.
+    pub errno: Option<c_int>,
+}
+
 /// Internal error returned by libpathrs's syscall wrappers.
 ///
 /// The primary thing of note is that these errors contain detailed debugging
@@ -99,7 +324,7 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("fcntl({}, F_SETFD, 0x{:x})", fd, flags))]
+    #[snafu(display("fcntl({}, F_SETFD, {})", fd, fd_flags(*flags)))]
     FcntlSetFlags {
         fd: FrozenFd,
         flags: i32,
@@ -107,7 +332,45 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("openat({}, {:?}, 0x{:x}, 0o{:o})", dirfd, path, flags, mode))]
+    #[snafu(display("fcntl({}, F_GETFL)", fd))]
+    FcntlGetStatusFlags {
+        fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("fcntl({}, F_SETFL, {})", fd, open_flags(*flags)))]
+    FcntlSetStatusFlags {
+        fd: FrozenFd,
+        flags: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("fcntl({}, F_SETLEASE, {})", fd, lease_type(*lease)))]
+    FcntlSetLease {
+        fd: FrozenFd,
+        lease: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("fcntl({}, F_GETLEASE)", fd))]
+    FcntlGetLease {
+        fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("fcntl({}, F_SETSIG, {})", fd, signal))]
+    FcntlSetSig {
+        fd: FrozenFd,
+        signal: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("openat({}, {:?}, {}, 0o{:o})", dirfd, path, open_flags(*flags), mode))]
     Openat {
         dirfd: FrozenFd,
         path: PathBuf,
@@ -155,7 +418,7 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("unlinkat({}, {:?}, 0x{:x})", dirfd, path, flags))]
+    #[snafu(display("unlinkat({}, {:?}, {})", dirfd, path, at_flags(*flags)))]
     Unlinkat {
         dirfd: FrozenFd,
         path: PathBuf,
@@ -165,12 +428,12 @@ pub enum Error {
     },
 
     #[snafu(display(
-        "linkat({}, {:?}, {}, {:?}, 0x{:x})",
+        "linkat({}, {:?}, {}, {:?}, {})",
         olddirfd,
         oldpath,
         newdirfd,
         newpath,
-        flags
+        at_flags(*flags)
     ))]
     Linkat {
         olddirfd: FrozenFd,
@@ -202,12 +465,12 @@ pub enum Error {
     },
 
     #[snafu(display(
-        "renameat2({}, {:?}, {}, {:?}, 0x{:x})",
+        "renameat2({}, {:?}, {}, {:?}, {})",
         olddirfd,
         oldpath,
         newdirfd,
         newpath,
-        flags
+        rename_flags(*flags)
     ))]
     Renameat2 {
         olddirfd: FrozenFd,
@@ -226,7 +489,23 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("fstatat({}, {:?}, 0x{:x})", dirfd, path, flags))]
+    #[snafu(display("fchdir({})", fd))]
+    Fchdir {
+        fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("readahead({}, {}, {})", fd, offset, count))]
+    Readahead {
+        fd: FrozenFd,
+        offset: u64,
+        count: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("fstatat({}, {:?}, {})", dirfd, path, at_flags(*flags)))]
     Fstatat {
         dirfd: FrozenFd,
         path: PathBuf,
@@ -234,430 +513,3076 @@ pub enum Error {
         source: IOError,
         backtrace: Backtrace,
     },
-}
-
-impl Error {
-    pub(crate) fn root_cause(&self) -> &IOError {
-        // XXX: This should probably be a macro...
-        match self {
-            Error::FcntlDup { source, .. } => source,
-            Error::FcntlGetFlags { source, .. } => source,
-            Error::FcntlSetFlags { source, .. } => source,
-            Error::Openat { source, .. } => source,
-            Error::Openat2 { source, .. } => source,
-            Error::Readlinkat { source, .. } => source,
-            Error::Mkdirat { source, .. } => source,
-            Error::Mknodat { source, .. } => source,
-            Error::Unlinkat { source, .. } => source,
-            Error::Linkat { source, .. } => source,
-            Error::Symlinkat { source, .. } => source,
-            Error::Renameat { source, .. } => source,
-            Error::Renameat2 { source, .. } => source,
-            Error::Fstatfs { source, .. } => source,
-            Error::Fstatat { source, .. } => source,
-        }
-    }
-}
 
-// XXX: We might want to switch to nix at some point, but the interfaces
-//      provided by nix are slightly non-ergonomic. I much prefer these simpler
-//      C-like bindings. We also have the ability to check for support of each
-//      syscall.
+    #[snafu(display("statx({}, {:?}, {}, 0x{:x})", dirfd, path, at_flags(*flags), mask))]
+    Statx {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        flags: i32,
+        mask: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-/// Wrapper for `fcntl(F_DUPFD_CLOEXEC)`.
-///
-/// This is required because [Rust's `File::try_clone` doesn't handle `O_PATH`
-/// descriptors properly][bug62314]. I have [sent a PR to fix it][pr62425].
-///
-/// [bug62314]: https://github.com/rust-lang/rust/issues/62314
-/// [pr62425]: https://github.com/rust-lang/rust/pull/62425
-pub(crate) fn fcntl_dupfd_cloxec(fd: RawFd) -> Result<File, Error> {
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let newfd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
-    let err = IOError::last_os_error();
+    #[snafu(display("getdents64({}, <buf>, {})", fd, size))]
+    Getdents64 {
+        fd: FrozenFd,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    if newfd >= 0 {
-        // SAFETY: We know it's a real file descriptor.
-        Ok(unsafe { File::from_raw_fd(newfd) })
-    } else {
-        Err(err).context(FcntlDup { fd })
-    }
-}
+    #[snafu(display("mmap(NULL, {}, PROT_READ, MAP_PRIVATE, {}, {})", len, fd, offset))]
+    Mmap {
+        fd: FrozenFd,
+        len: usize,
+        offset: u64,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-/// Wrapper for `fcntl(F_GETFD)` followed by `fcntl(F_SETFD)`, clearing the
-/// `FD_CLOEXEC` bit.
-///
-/// This is required because Rust automatically sets `O_CLOEXEC` on all new
-/// files, so we need to manually unset it when we return certain fds to the C
-/// FFI (in fairness, `O_CLOEXEC` is a good default).
-pub(crate) fn fcntl_unset_cloexec(fd: RawFd) -> Result<(), Error> {
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let old = unsafe { libc::fcntl(fd, libc::F_GETFD) };
-    let err = IOError::last_os_error();
+    #[snafu(display(
+        "mmap(NULL, {}, PROT_READ|PROT_WRITE, MAP_SHARED, {}, {})",
+        len,
+        fd,
+        offset
+    ))]
+    MmapRing {
+        fd: FrozenFd,
+        len: usize,
+        offset: u64,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    if old < 0 {
-        return Err(err).context(FcntlGetFlags { fd });
-    }
+    #[snafu(display("io_uring_setup({}, <params>)", entries))]
+    IoUringSetup {
+        entries: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    let new = old & !libc::FD_CLOEXEC;
-    if new == old {
-        return Ok(());
-    }
+    #[snafu(display(
+        "io_uring_enter({}, {}, {}, IORING_ENTER_GETEVENTS, NULL, 0)",
+        fd,
+        to_submit,
+        min_complete
+    ))]
+    IoUringEnter {
+        fd: FrozenFd,
+        to_submit: u32,
+        min_complete: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe { libc::fcntl(fd, libc::F_SETFD, new) };
-    let err = IOError::last_os_error();
+    #[snafu(display(
+        "fchownat({}, {:?}, {}, {}, {})",
+        dirfd,
+        path,
+        uid,
+        gid,
+        at_flags(*flags)
+    ))]
+    Fchownat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        uid: u32,
+        gid: u32,
+        flags: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    if ret >= 0 {
-        Ok(())
-    } else {
-        Err(err).context(FcntlSetFlags { fd, flags: new })
-    }
-}
+    #[snafu(display("fchmodat({}, {:?}, 0o{:o})", dirfd, path, mode))]
+    Fchmodat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        mode: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-/// Wrapper for `openat(2)` which auto-sets `O_CLOEXEC | O_NOCTTY`.
-///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `openat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn openat_follow<P: AsRef<Path>>(
-    dirfd: RawFd,
-    path: P,
-    flags: c_int,
-    mode: mode_t,
-) -> Result<File, Error> {
-    let path = path.as_ref();
-    let flags = libc::O_CLOEXEC | libc::O_NOCTTY | flags;
+    #[snafu(display(
+        "utimensat({}, {:?}, {:?}, {})",
+        dirfd,
+        path,
+        times,
+        at_flags(*flags)
+    ))]
+    Utimensat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        times: [(i64, i64); 2],
+        flags: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let fd = unsafe { libc::openat(dirfd, path.to_c_string().as_ptr(), flags, mode) };
-    let err = IOError::last_os_error();
+    #[snafu(display("fallocate({}, {}, {}, {})", fd, falloc_flags(*mode), offset, len))]
+    Fallocate {
+        fd: FrozenFd,
+        mode: i32,
+        offset: u64,
+        len: u64,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-    if fd >= 0 {
-        // SAFETY: We know it's a real file descriptor.
-        Ok(unsafe { File::from_raw_fd(fd) })
-    } else {
-        Err(err).context(Openat {
-            dirfd,
-            path,
-            flags,
-            mode,
-        })
-    }
-}
+    #[snafu(display("ioctl({}, FICLONE, {})", fd, src_fd))]
+    Ficlone {
+        fd: FrozenFd,
+        src_fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-/// Wrapper for `openat(2)` which auto-sets `O_CLOEXEC | O_NOCTTY | O_NOFOLLOW`.
-///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `openat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn openat<P: AsRef<Path>>(
-    dirfd: RawFd,
-    path: P,
-    flags: c_int,
-    mode: mode_t,
-) -> Result<File, Error> {
-    openat_follow(dirfd, path, libc::O_NOFOLLOW | flags, mode)
-}
+    #[snafu(display("ioctl({}, FS_IOC_GETFLAGS, <buf>)", fd))]
+    GetFlags {
+        fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
 
-/// Wrapper for `readlinkat(2)`.
-///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `readlinkat(2)`. We need the dirfd argument, so we need a
-/// wrapper.
-pub(crate) fn readlinkat<P: AsRef<Path>>(dirfd: RawFd, path: P) -> Result<PathBuf, Error> {
-    let path = path.as_ref();
+    #[snafu(display("ioctl({}, FS_IOC_FSGETXATTR, <buf>)", fd))]
+    FsGetXattr {
+        fd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "ioctl({}, FS_IOC_FSSETXATTR, {{ fsx_xflags: 0x{:x}, .. }})",
+        fd,
+        xflags
+    ))]
+    FsSetXattr {
+        fd: FrozenFd,
+        xflags: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("copy_file_range({}, NULL, {}, NULL, {}, 0)", fd_in, fd_out, len))]
+    CopyFileRange {
+        fd_in: FrozenFd,
+        fd_out: FrozenFd,
+        len: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("sendfile({}, {}, [{}], {})", fd_out, fd_in, offset, count))]
+    Sendfile {
+        fd_out: FrozenFd,
+        fd_in: FrozenFd,
+        offset: i64,
+        count: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "splice({}, {:?}, {}, NULL, {}, SPLICE_F_MOVE|SPLICE_F_MORE)",
+        fd_in,
+        offset,
+        fd_out,
+        len
+    ))]
+    Splice {
+        fd_in: FrozenFd,
+        offset: Option<i64>,
+        fd_out: FrozenFd,
+        len: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("pipe2(O_CLOEXEC)"))]
+    Pipe2 {
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("open_tree({}, {:?}, {})", dirfd, path, open_tree_flags(*flags)))]
+    OpenTree {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        flags: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("listxattr({:?}, <buf>, {})", path, size))]
+    Listxattr {
+        path: PathBuf,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("getxattr({:?}, {:?}, <buf>, {})", path, name, size))]
+    Getxattr {
+        path: PathBuf,
+        name: OsString,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "setxattr({:?}, {:?}, <buf>, {}, {})",
+        path,
+        name,
+        size,
+        xattr_flags(*flags)
+    ))]
+    Setxattr {
+        path: PathBuf,
+        name: OsString,
+        size: usize,
+        flags: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("removexattr({:?}, {:?})", path, name))]
+    Removexattr {
+        path: PathBuf,
+        name: OsString,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("listxattrat({}, {:?}, 0, <buf>, {})", dirfd, path, size))]
+    Listxattrat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("getxattrat({}, {:?}, 0, {:?}, <buf>, {})", dirfd, path, name, size))]
+    Getxattrat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        name: OsString,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "setxattrat({}, {:?}, 0, {:?}, <buf>, {}, {})",
+        dirfd,
+        path,
+        name,
+        size,
+        xattr_flags(*flags)
+    ))]
+    Setxattrat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        name: OsString,
+        size: usize,
+        flags: i32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("removexattrat({}, {:?}, 0, {:?})", dirfd, path, name))]
+    Removexattrat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        name: OsString,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("sendmsg({}, <buf>, {}, SCM_RIGHTS={:?})", sockfd, size, fd))]
+    SendmsgFd {
+        sockfd: FrozenFd,
+        size: usize,
+        fd: Option<RawFd>,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("recvmsg({}, <buf>, {})", sockfd, size))]
+    RecvmsgFd {
+        sockfd: FrozenFd,
+        size: usize,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("getrlimit({})", resource))]
+    Getrlimit {
+        resource: &'static str,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("getsockopt({}, SOL_SOCKET, SO_PEERCRED)", sockfd))]
+    GetPeerCred {
+        sockfd: FrozenFd,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("chroot({}) + stat({:?})", rootfd, path))]
+    ChrootStat {
+        rootfd: FrozenFd,
+        path: PathBuf,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("pidfd_send_signal({}, {}, NULL, 0)", pidfd, sig))]
+    PidfdSendSignal {
+        pidfd: FrozenFd,
+        sig: c_int,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("inotify_init1(IN_NONBLOCK|IN_CLOEXEC)"))]
+    InotifyInit1 {
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("inotify_add_watch({}, {:?}, 0x{:x})", fd, path, mask))]
+    InotifyAddWatch {
+        fd: FrozenFd,
+        path: PathBuf,
+        mask: u32,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("faccessat({}, {:?}, 0x{:x}, AT_EACCESS)", dirfd, path, mode))]
+    Faccessat {
+        dirfd: FrozenFd,
+        path: PathBuf,
+        mode: c_int,
+        source: IOError,
+        backtrace: Backtrace,
+    },
+}
+
+impl Error {
+    /// Get structured information about the failed syscall and its arguments.
+    pub fn info(&self) -> SyscallInfo<'_> {
+        let (name, fds, paths, flags): (_, Vec<&FrozenFd>, Vec<&Path>, Vec<String>) = match self {
+            Error::FcntlDup { fd, .. } => ("fcntl", vec![fd], vec![], vec![]),
+            Error::FcntlGetFlags { fd, .. } => ("fcntl", vec![fd], vec![], vec![]),
+            Error::FcntlSetFlags { fd, flags, .. } => {
+                ("fcntl", vec![fd], vec![], vec![fd_flags(*flags)])
+            }
+            Error::FcntlGetStatusFlags { fd, .. } => ("fcntl", vec![fd], vec![], vec![]),
+            Error::FcntlSetStatusFlags { fd, flags, .. } => {
+                ("fcntl", vec![fd], vec![], vec![open_flags(*flags)])
+            }
+            Error::FcntlSetLease { fd, lease, .. } => {
+                ("fcntl", vec![fd], vec![], vec![lease_type(*lease)])
+            }
+            Error::FcntlGetLease { fd, .. } => ("fcntl", vec![fd], vec![], vec![]),
+            Error::FcntlSetSig { fd, .. } => ("fcntl", vec![fd], vec![], vec![]),
+            Error::Openat {
+                dirfd, path, flags, ..
+            } => ("openat", vec![dirfd], vec![path], vec![open_flags(*flags)]),
+            Error::Openat2 {
+                dirfd, path, how, ..
+            } => (
+                "openat2",
+                vec![dirfd],
+                vec![path],
+                vec![open_flags(how.flags as c_int), resolve_flags(how.resolve)],
+            ),
+            Error::Readlinkat { dirfd, path, .. } => {
+                ("readlinkat", vec![dirfd], vec![path], vec![])
+            }
+            Error::Mkdirat { dirfd, path, .. } => ("mkdirat", vec![dirfd], vec![path], vec![]),
+            Error::Mknodat { dirfd, path, .. } => ("mknodat", vec![dirfd], vec![path], vec![]),
+            Error::Unlinkat {
+                dirfd, path, flags, ..
+            } => ("unlinkat", vec![dirfd], vec![path], vec![at_flags(*flags)]),
+            Error::Linkat {
+                olddirfd,
+                oldpath,
+                newdirfd,
+                newpath,
+                flags,
+                ..
+            } => (
+                "linkat",
+                vec![olddirfd, newdirfd],
+                vec![oldpath, newpath],
+                vec![at_flags(*flags)],
+            ),
+            Error::Symlinkat {
+                dirfd,
+                path,
+                target,
+                ..
+            } => ("symlinkat", vec![dirfd], vec![target, path], vec![]),
+            Error::Renameat {
+                olddirfd,
+                oldpath,
+                newdirfd,
+                newpath,
+                ..
+            } => (
+                "renameat",
+                vec![olddirfd, newdirfd],
+                vec![oldpath, newpath],
+                vec![],
+            ),
+            Error::Renameat2 {
+                olddirfd,
+                oldpath,
+                newdirfd,
+                newpath,
+                flags,
+                ..
+            } => (
+                "renameat2",
+                vec![olddirfd, newdirfd],
+                vec![oldpath, newpath],
+                vec![rename_flags(*flags)],
+            ),
+            Error::Fstatfs { fd, .. } => ("fstatfs", vec![fd], vec![], vec![]),
+            Error::Fchdir { fd, .. } => ("fchdir", vec![fd], vec![], vec![]),
+            Error::Readahead { fd, .. } => ("readahead", vec![fd], vec![], vec![]),
+            Error::Fstatat {
+                dirfd, path, flags, ..
+            } => ("fstatat", vec![dirfd], vec![path], vec![at_flags(*flags)]),
+            Error::Statx {
+                dirfd, path, flags, ..
+            } => ("statx", vec![dirfd], vec![path], vec![at_flags(*flags)]),
+            Error::Getdents64 { fd, .. } => ("getdents64", vec![fd], vec![], vec![]),
+            Error::Mmap { fd, .. } => ("mmap", vec![fd], vec![], vec![]),
+            Error::MmapRing { fd, .. } => ("mmap", vec![fd], vec![], vec![]),
+            Error::IoUringSetup { .. } => ("io_uring_setup", vec![], vec![], vec![]),
+            Error::IoUringEnter { fd, .. } => ("io_uring_enter", vec![fd], vec![], vec![]),
+            Error::Fchownat {
+                dirfd, path, flags, ..
+            } => ("fchownat", vec![dirfd], vec![path], vec![at_flags(*flags)]),
+            Error::Fchmodat { dirfd, path, .. } => ("fchmodat", vec![dirfd], vec![path], vec![]),
+            Error::Utimensat {
+                dirfd, path, flags, ..
+            } => ("utimensat", vec![dirfd], vec![path], vec![at_flags(*flags)]),
+            Error::Ficlone { fd, src_fd, .. } => ("ioctl", vec![fd, src_fd], vec![], vec![]),
+            Error::GetFlags { fd, .. } => ("ioctl", vec![fd], vec![], vec![]),
+            Error::FsGetXattr { fd, .. } => ("ioctl", vec![fd], vec![], vec![]),
+            Error::FsSetXattr { fd, .. } => ("ioctl", vec![fd], vec![], vec![]),
+            Error::CopyFileRange { fd_in, fd_out, .. } => {
+                ("copy_file_range", vec![fd_in, fd_out], vec![], vec![])
+            }
+            Error::Fallocate { fd, mode, .. } => {
+                ("fallocate", vec![fd], vec![], vec![falloc_flags(*mode)])
+            }
+            Error::Sendfile { fd_out, fd_in, .. } => {
+                ("sendfile", vec![fd_out, fd_in], vec![], vec![])
+            }
+            Error::Splice { fd_in, fd_out, .. } => ("splice", vec![fd_in, fd_out], vec![], vec![]),
+            Error::Pipe2 { .. } => ("pipe2", vec![], vec![], vec![]),
+            Error::OpenTree {
+                dirfd, path, flags, ..
+            } => (
+                "open_tree",
+                vec![dirfd],
+                vec![path],
+                vec![open_tree_flags(*flags)],
+            ),
+            Error::Listxattr { path, .. } => ("listxattr", vec![], vec![path], vec![]),
+            Error::Getxattr { path, .. } => ("getxattr", vec![], vec![path], vec![]),
+            Error::Setxattr { path, flags, .. } => {
+                ("setxattr", vec![], vec![path], vec![xattr_flags(*flags)])
+            }
+            Error::Removexattr { path, .. } => ("removexattr", vec![], vec![path], vec![]),
+            Error::Listxattrat { dirfd, path, .. } => {
+                ("listxattrat", vec![dirfd], vec![path], vec![])
+            }
+            Error::Getxattrat { dirfd, path, .. } => {
+                ("getxattrat", vec![dirfd], vec![path], vec![])
+            }
+            Error::Setxattrat {
+                dirfd, path, flags, ..
+            } => (
+                "setxattrat",
+                vec![dirfd],
+                vec![path],
+                vec![xattr_flags(*flags)],
+            ),
+            Error::Removexattrat { dirfd, path, .. } => {
+                ("removexattrat", vec![dirfd], vec![path], vec![])
+            }
+            Error::SendmsgFd { sockfd, .. } => ("sendmsg", vec![sockfd], vec![], vec![]),
+            Error::RecvmsgFd { sockfd, .. } => ("recvmsg", vec![sockfd], vec![], vec![]),
+            Error::Getrlimit { .. } => ("getrlimit", vec![], vec![], vec![]),
+            Error::GetPeerCred { sockfd, .. } => ("getsockopt", vec![sockfd], vec![], vec![]),
+            Error::ChrootStat { rootfd, path, .. } => ("chroot", vec![rootfd], vec![path], vec![]),
+            Error::PidfdSendSignal { pidfd, .. } => {
+                ("pidfd_send_signal", vec![pidfd], vec![], vec![])
+            }
+            Error::InotifyInit1 { .. } => ("inotify_init1", vec![], vec![], vec![]),
+            Error::InotifyAddWatch { fd, path, .. } => {
+                ("inotify_add_watch", vec![fd], vec![path], vec![])
+            }
+            Error::Faccessat { dirfd, path, .. } => ("faccessat", vec![dirfd], vec![path], vec![]),
+        };
+        SyscallInfo {
+            name,
+            fds,
+            paths,
+            flags,
+            errno: self.root_cause().raw_os_error(),
+        }
+    }
+
+    pub(crate) fn root_cause(&self) -> &IOError {
+        // XXX: This should probably be a macro...
+        match self {
+            Error::FcntlDup { source, .. } => source,
+            Error::FcntlGetFlags { source, .. } => source,
+            Error::FcntlSetFlags { source, .. } => source,
+            Error::FcntlGetStatusFlags { source, .. } => source,
+            Error::FcntlSetStatusFlags { source, .. } => source,
+            Error::FcntlSetLease { source, .. } => source,
+            Error::FcntlGetLease { source, .. } => source,
+            Error::FcntlSetSig { source, .. } => source,
+            Error::Openat { source, .. } => source,
+            Error::Openat2 { source, .. } => source,
+            Error::Readlinkat { source, .. } => source,
+            Error::Mkdirat { source, .. } => source,
+            Error::Mknodat { source, .. } => source,
+            Error::Unlinkat { source, .. } => source,
+            Error::Linkat { source, .. } => source,
+            Error::Symlinkat { source, .. } => source,
+            Error::Renameat { source, .. } => source,
+            Error::Renameat2 { source, .. } => source,
+            Error::Fstatfs { source, .. } => source,
+            Error::Fchdir { source, .. } => source,
+            Error::Readahead { source, .. } => source,
+            Error::Fstatat { source, .. } => source,
+            Error::Statx { source, .. } => source,
+            Error::Getdents64 { source, .. } => source,
+            Error::Mmap { source, .. } => source,
+            Error::MmapRing { source, .. } => source,
+            Error::IoUringSetup { source, .. } => source,
+            Error::IoUringEnter { source, .. } => source,
+            Error::Fchownat { source, .. } => source,
+            Error::Fchmodat { source, .. } => source,
+            Error::Utimensat { source, .. } => source,
+            Error::Fallocate { source, .. } => source,
+            Error::Ficlone { source, .. } => source,
+            Error::GetFlags { source, .. } => source,
+            Error::FsGetXattr { source, .. } => source,
+            Error::FsSetXattr { source, .. } => source,
+            Error::CopyFileRange { source, .. } => source,
+            Error::Sendfile { source, .. } => source,
+            Error::Splice { source, .. } => source,
+            Error::Pipe2 { source, .. } => source,
+            Error::OpenTree { source, .. } => source,
+            Error::Listxattr { source, .. } => source,
+            Error::Getxattr { source, .. } => source,
+            Error::Setxattr { source, .. } => source,
+            Error::Removexattr { source, .. } => source,
+            Error::Listxattrat { source, .. } => source,
+            Error::Getxattrat { source, .. } => source,
+            Error::Setxattrat { source, .. } => source,
+            Error::Removexattrat { source, .. } => source,
+            Error::SendmsgFd { source, .. } => source,
+            Error::RecvmsgFd { source, .. } => source,
+            Error::Getrlimit { source, .. } => source,
+            Error::GetPeerCred { source, .. } => source,
+            Error::ChrootStat { source, .. } => source,
+            Error::PidfdSendSignal { source, .. } => source,
+            Error::InotifyInit1 { source, .. } => source,
+            Error::InotifyAddWatch { source, .. } => source,
+            Error::Faccessat { source, .. } => source,
+        }
+    }
+}
+
+// XXX: We might want to switch to nix at some point, but the interfaces
+//      provided by nix are slightly non-ergonomic. I much prefer these simpler
+//      C-like bindings. We also have the ability to check for support of each
+//      syscall.
+
+/// The return value of a syscall, which is negative on error.
+trait SyscallReturn: Copy + PartialOrd {
+    const ZERO: Self;
+
+    #[cfg_attr(not(feature = "syscall-trace"), allow(dead_code))]
+    fn as_i64(self) -> i64;
+}
+
+impl SyscallReturn for i32 {
+    const ZERO: Self = 0;
+
+    fn as_i64(self) -> i64 {
+        self.into()
+    }
+}
+
+impl SyscallReturn for i64 {
+    const ZERO: Self = 0;
+
+    fn as_i64(self) -> i64 {
+        self
+    }
+}
+
+impl SyscallReturn for isize {
+    const ZERO: Self = 0;
+
+    fn as_i64(self) -> i64 {
+        self as i64
+    }
+}
+
+/// Start timing a syscall, if it is going to be traced.
+#[cfg(feature = "syscall-trace")]
+#[inline]
+fn trace_start() -> Option<Instant> {
+    crate::trace::start()
+}
+
+/// Start timing a syscall, if it is going to be traced.
+#[cfg(not(feature = "syscall-trace"))]
+#[inline]
+fn trace_start() -> Option<Instant> {
+    None
+}
+
+/// Report a syscall started with [`trace_start`] to the current tracer. `args`
+/// is only called if the syscall is actually being traced.
+///
+/// [`trace_start`]: fn.trace_start.html
+#[inline]
+fn trace_finish<T, A>(start: Option<Instant>, name: &'static str, args: A, ret: T, err: &IOError)
+where
+    T: SyscallReturn,
+    A: FnOnce() -> String,
+{
+    #[cfg(feature = "syscall-trace")]
+    {
+        if let Some(start) = start {
+            let result = if ret < T::ZERO {
+                Err(err.raw_os_error().unwrap_or(0))
+            } else {
+                Ok(ret.as_i64())
+            };
+            crate::trace::finish(start, name, args, result);
+        }
+    }
+    #[cfg(not(feature = "syscall-trace"))]
+    {
+        let _ = (start, name, args, ret, err);
+    }
+}
+
+/// Run a syscall (which returns a negative value on error), returning its
+/// result along with the `errno` it set. If syscall tracing is enabled, the
+/// syscall is reported as `name(args)`.
+fn traced<T, A, F>(name: &'static str, args: A, syscall: F) -> (T, IOError)
+where
+    T: SyscallReturn,
+    A: FnOnce() -> String,
+    F: FnOnce() -> T,
+{
+    let start = trace_start();
+    let ret = syscall();
+    let err = IOError::last_os_error();
+    opstats::record_syscall(ret < T::ZERO);
+    trace_finish(start, name, args, ret, &err);
+    (ret, err)
+}
+
+/// Run a syscall (which returns a negative value on error), retrying it while
+/// it fails with `EINTR`. Returns the result of the last attempt, along with
+/// the `errno` it set. If syscall tracing is enabled, the syscall is reported
+/// as `name(args)`.
+///
+/// Every wrapper for a syscall which can block on a filesystem (such as NFS or
+/// FUSE) or socket should use this, so that signals delivered to the process
+/// don't result in spurious errors.
+fn retry_eintr<T, A, F>(name: &'static str, args: A, mut syscall: F) -> (T, IOError)
+where
+    T: SyscallReturn,
+    A: FnOnce() -> String,
+    F: FnMut() -> T,
+{
+    let start = trace_start();
+    loop {
+        let ret = syscall();
+        let err = IOError::last_os_error();
+        opstats::record_syscall(ret < T::ZERO);
+        if ret < T::ZERO && err.raw_os_error() == Some(libc::EINTR) {
+            retry::record_eintr_retry();
+            continue;
+        }
+        trace_finish(start, name, args, ret, &err);
+        return (ret, err);
+    }
+}
+
+/// Wrapper for `fcntl(F_DUPFD_CLOEXEC)`.
+///
+/// This is required because [Rust's `File::try_clone` doesn't handle `O_PATH`
+/// descriptors properly][bug62314]. I have [sent a PR to fix it][pr62425].
+///
+/// [bug62314]: https://github.com/rust-lang/rust/issues/62314
+/// [pr62425]: https://github.com/rust-lang/rust/pull/62425
+pub(crate) fn fcntl_dupfd_cloxec(fd: RawFd) -> Result<File, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (newfd, err) = traced(
+        "fcntl",
+        || format!("{}, F_DUPFD_CLOEXEC, 0", FrozenFd::from(fd)),
+        || unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) },
+    );
+
+    if newfd >= 0 {
+        // SAFETY: We know it's a real file descriptor.
+        Ok(unsafe { File::from_raw_fd(newfd) })
+    } else {
+        Err(err).context(FcntlDup { fd })
+    }
+}
+
+/// Wrapper for `fcntl(F_GETFD)` followed by `fcntl(F_SETFD)`, clearing the
+/// `FD_CLOEXEC` bit.
+///
+/// This is required because Rust automatically sets `O_CLOEXEC` on all new
+/// files, so we need to manually unset it when we return certain fds to the C
+/// FFI (in fairness, `O_CLOEXEC` is a good default).
+pub(crate) fn fcntl_unset_cloexec(fd: RawFd) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (old, err) = traced(
+        "fcntl",
+        || format!("{}, F_GETFD", FrozenFd::from(fd)),
+        || unsafe { libc::fcntl(fd, libc::F_GETFD) },
+    );
+
+    if old < 0 {
+        return Err(err).context(FcntlGetFlags { fd });
+    }
+
+    let new = old & !libc::FD_CLOEXEC;
+    if new == old {
+        return Ok(());
+    }
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_SETFD, {}", FrozenFd::from(fd), fd_flags(new)),
+        || unsafe { libc::fcntl(fd, libc::F_SETFD, new) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FcntlSetFlags { fd, flags: new })
+    }
+}
+
+/// Wrapper for `fcntl(F_SETFD)`.
+///
+/// This is needed because Rust doesn't provide a way to change the descriptor
+/// flags of a `File`.
+pub(crate) fn fcntl_setfd(fd: RawFd, flags: c_int) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_SETFD, {}", FrozenFd::from(fd), fd_flags(flags)),
+        || unsafe { libc::fcntl(fd, libc::F_SETFD, flags) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FcntlSetFlags { fd, flags })
+    }
+}
+
+/// Wrapper for `fcntl(F_GETFL)`.
+///
+/// This is needed because Rust doesn't provide a way to get the file status
+/// flags of a `File`.
+pub(crate) fn fcntl_getfl(fd: RawFd) -> Result<c_int, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_GETFL", FrozenFd::from(fd)),
+        || unsafe { libc::fcntl(fd, libc::F_GETFL) },
+    );
+
+    if ret >= 0 {
+        Ok(ret)
+    } else {
+        Err(err).context(FcntlGetStatusFlags { fd })
+    }
+}
+
+/// Wrapper for `fcntl(F_SETFL)`.
+///
+/// This is needed because Rust doesn't provide a way to change the file status
+/// flags (such as `O_NONBLOCK`) of a `File`.
+pub(crate) fn fcntl_setfl(fd: RawFd, flags: c_int) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_SETFL, {}", FrozenFd::from(fd), open_flags(flags)),
+        || unsafe { libc::fcntl(fd, libc::F_SETFL, flags) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FcntlSetStatusFlags { fd, flags })
+    }
+}
+
+/// Wrapper for `fcntl(F_SETLEASE)`.
+///
+/// This is needed because Rust doesn't provide a way to manage file leases.
+pub(crate) fn fcntl_setlease(fd: RawFd, lease: c_int) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_SETLEASE, {}", FrozenFd::from(fd), lease_type(lease)),
+        || unsafe { libc::fcntl(fd, libc::F_SETLEASE, lease) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FcntlSetLease { fd, lease })
+    }
+}
+
+/// Wrapper for `fcntl(F_GETLEASE)`.
+///
+/// This is needed because Rust doesn't provide a way to manage file leases.
+pub(crate) fn fcntl_getlease(fd: RawFd) -> Result<c_int, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_GETLEASE", FrozenFd::from(fd)),
+        || unsafe { libc::fcntl(fd, libc::F_GETLEASE) },
+    );
+
+    if ret >= 0 {
+        Ok(ret)
+    } else {
+        Err(err).context(FcntlGetLease { fd })
+    }
+}
+
+// This is part of Linux's ABI, but isn't exported by libc for glibc targets.
+const F_SETSIG: c_int = 10;
+
+/// Wrapper for `fcntl(F_SETSIG)`.
+///
+/// This is needed because Rust doesn't provide a way to configure which signal
+/// is sent for I/O (and lease break) notifications.
+pub(crate) fn fcntl_setsig(fd: RawFd, signal: c_int) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fcntl",
+        || format!("{}, F_SETSIG, {}", FrozenFd::from(fd), signal),
+        || unsafe { libc::fcntl(fd, F_SETSIG, signal) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FcntlSetSig { fd, signal })
+    }
+}
+
+/// Wrapper for `openat(2)` which auto-sets `O_CLOEXEC | O_NOCTTY`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `openat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn openat_follow<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    flags: c_int,
+    mode: mode_t,
+) -> Result<File, Error> {
+    let path = path.as_ref();
+    let flags = libc::O_CLOEXEC | libc::O_NOCTTY | flags;
+    let cpath = path.to_c_string().context(Openat {
+        dirfd,
+        path,
+        flags,
+        mode,
+    })?;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (fd, err) = retry_eintr(
+        "openat",
+        || {
+            format!(
+                "{}, {:?}, {}, 0o{:o}",
+                FrozenFd::from(dirfd),
+                path,
+                open_flags(flags),
+                mode
+            )
+        },
+        || unsafe { libc::openat(dirfd, cpath.as_ptr(), flags, mode) },
+    );
+
+    if fd >= 0 {
+        // SAFETY: We know it's a real file descriptor.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    } else {
+        Err(err).context(Openat {
+            dirfd,
+            path,
+            flags,
+            mode,
+        })
+    }
+}
+
+/// Wrapper for `openat(2)` which auto-sets `O_CLOEXEC | O_NOCTTY | O_NOFOLLOW`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `openat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn openat<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    flags: c_int,
+    mode: mode_t,
+) -> Result<File, Error> {
+    openat_follow(dirfd, path, libc::O_NOFOLLOW | flags, mode)
+}
+
+/// Wrapper for `readlinkat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `readlinkat(2)`. We need the dirfd argument, so we need a
+/// wrapper.
+pub(crate) fn readlinkat<P: AsRef<Path>>(dirfd: RawFd, path: P) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
 
     // If the contents of the symlink are larger than this, we raise a
     // SafetyViolation to avoid DoS vectors (because there is no way to get the
     // size of a symlink beforehand, you just have to read it).
-    let mut buffer = [0 as u8; 32 * libc::PATH_MAX as usize];
+    let mut buffer = [0u8; 32 * libc::PATH_MAX as usize];
+    let cpath = path.to_c_string().context(Readlinkat { dirfd, path })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (len, mut err) = retry_eintr(
+        "readlinkat",
+        || format!("{}, {:?}, <buf>", FrozenFd::from(dirfd), path),
+        || unsafe {
+            libc::readlinkat(
+                dirfd,
+                cpath.as_ptr(),
+                buffer.as_mut_ptr() as *mut i8,
+                buffer.len(),
+            )
+        },
+    );
+    let maybe_truncated = len >= (buffer.len() as isize);
+    if len < 0 || maybe_truncated {
+        if maybe_truncated {
+            err = IOError::from_raw_os_error(libc::ENAMETOOLONG);
+        }
+        Err(err).context(Readlinkat { dirfd, path })
+    } else {
+        let content = OsStr::from_bytes(&buffer[..(len as usize)]);
+        Ok(PathBuf::from(content))
+    }
+}
+
+/// Wrapper for `mkdirat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `mkdirat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn mkdirat<P: AsRef<Path>>(dirfd: RawFd, path: P, mode: mode_t) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path.to_c_string().context(Mkdirat { dirfd, path, mode })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "mkdirat",
+        || format!("{}, {:?}, 0o{:o}", FrozenFd::from(dirfd), path, mode),
+        || unsafe { libc::mkdirat(dirfd, cpath.as_ptr(), mode) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Mkdirat { dirfd, path, mode })
+    }
+}
+
+/// Wrapper for `mknodat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `mknodat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn mknodat<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    mode: mode_t,
+    dev: dev_t,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path.to_c_string().context(Mknodat {
+        dirfd,
+        path,
+        mode,
+        major: libc::major(dev),
+        minor: libc::minor(dev),
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "mknodat",
+        || {
+            format!(
+                "{}, {:?}, 0o{:o}, {}:{}",
+                FrozenFd::from(dirfd),
+                path,
+                mode,
+                libc::major(dev),
+                libc::minor(dev)
+            )
+        },
+        || unsafe { libc::mknodat(dirfd, cpath.as_ptr(), mode, dev) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Mknodat {
+            dirfd,
+            path,
+            mode,
+            major: libc::major(dev),
+            minor: libc::minor(dev),
+        })
+    }
+}
+
+/// Wrapper for `unlinkat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `unlinkat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn unlinkat<P: AsRef<Path>>(dirfd: RawFd, path: P, flags: c_int) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path
+        .to_c_string()
+        .context(Unlinkat { dirfd, path, flags })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "unlinkat",
+        || format!("{}, {:?}, {}", FrozenFd::from(dirfd), path, at_flags(flags)),
+        || unsafe { libc::unlinkat(dirfd, cpath.as_ptr(), flags) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Unlinkat { dirfd, path, flags })
+    }
+}
+
+/// Wrapper for `linkat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `linkat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn linkat<P: AsRef<Path>>(
+    olddirfd: RawFd,
+    oldpath: P,
+    newdirfd: RawFd,
+    newpath: P,
+    flags: c_int,
+) -> Result<(), Error> {
+    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
+    let coldpath = oldpath.to_c_string().context(Linkat {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+        flags,
+    })?;
+    let cnewpath = newpath.to_c_string().context(Linkat {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+        flags,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "linkat",
+        || {
+            format!(
+                "{}, {:?}, {}, {:?}, {}",
+                FrozenFd::from(olddirfd),
+                oldpath,
+                FrozenFd::from(newdirfd),
+                newpath,
+                at_flags(flags)
+            )
+        },
+        || unsafe {
+            libc::linkat(
+                olddirfd,
+                coldpath.as_ptr(),
+                newdirfd,
+                cnewpath.as_ptr(),
+                flags,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Linkat {
+            olddirfd,
+            oldpath,
+            newdirfd,
+            newpath,
+            flags,
+        })
+    }
+}
+
+/// Wrapper for `symlinkat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `symlinkat(2)`. We need the dirfd argument, so we need a
+/// wrapper.
+pub(crate) fn symlinkat<P: AsRef<Path>>(target: P, dirfd: RawFd, path: P) -> Result<(), Error> {
+    let (target, path) = (target.as_ref(), path.as_ref());
+    let ctarget = target.to_c_string().context(Symlinkat {
+        dirfd,
+        path,
+        target,
+    })?;
+    let cpath = path.to_c_string().context(Symlinkat {
+        dirfd,
+        path,
+        target,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "symlinkat",
+        || format!("{:?}, {}, {:?}", target, FrozenFd::from(dirfd), path),
+        || unsafe { libc::symlinkat(ctarget.as_ptr(), dirfd, cpath.as_ptr()) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Symlinkat {
+            dirfd,
+            path,
+            target,
+        })
+    }
+}
+
+/// Wrapper for `renameat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `renameat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn renameat<P: AsRef<Path>>(
+    olddirfd: RawFd,
+    oldpath: P,
+    newdirfd: RawFd,
+    newpath: P,
+) -> Result<(), Error> {
+    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
+    let coldpath = oldpath.to_c_string().context(Renameat {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+    })?;
+    let cnewpath = newpath.to_c_string().context(Renameat {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "renameat",
+        || {
+            format!(
+                "{}, {:?}, {}, {:?}",
+                FrozenFd::from(olddirfd),
+                oldpath,
+                FrozenFd::from(newdirfd),
+                newpath
+            )
+        },
+        || unsafe { libc::renameat(olddirfd, coldpath.as_ptr(), newdirfd, cnewpath.as_ptr()) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Renameat {
+            olddirfd,
+            oldpath,
+            newdirfd,
+            newpath,
+        })
+    }
+}
+
+lazy_static! {
+    pub(crate) static ref RENAME_FLAGS_SUPPORTED: bool = {
+        match renameat2(
+            libc::AT_FDCWD,
+            ".",
+            libc::AT_FDCWD,
+            ".",
+            libc::RENAME_EXCHANGE,
+        ) {
+            Ok(_) => true,
+            // We expect EBUSY, but just to be safe we only check for ENOSYS.
+            Err(err) => err.root_cause().raw_os_error() != Some(libc::ENOSYS),
+        }
+    };
+}
+
+/// Wrapper for `renameat2(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for `renameat2(2)`
+/// (especially not an interface for the dirfd).
+pub(crate) fn renameat2<P: AsRef<Path>>(
+    olddirfd: RawFd,
+    oldpath: P,
+    newdirfd: RawFd,
+    newpath: P,
+    flags: u32,
+) -> Result<(), Error> {
+    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
+    let coldpath = oldpath.to_c_string().context(Renameat2 {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+        flags,
+    })?;
+    let cnewpath = newpath.to_c_string().context(Renameat2 {
+        olddirfd,
+        oldpath,
+        newdirfd,
+        newpath,
+        flags,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "renameat2",
+        || {
+            format!(
+                "{}, {:?}, {}, {:?}, {}",
+                FrozenFd::from(olddirfd),
+                oldpath,
+                FrozenFd::from(newdirfd),
+                newpath,
+                rename_flags(flags)
+            )
+        },
+        || unsafe {
+            // (g)libc doesn't have a renameat2 wrapper in older versions.
+            libc::syscall(
+                libc::SYS_renameat2,
+                olddirfd,
+                coldpath.as_ptr(),
+                newdirfd,
+                cnewpath.as_ptr(),
+                flags,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        if flags == 0 {
+            // Fall back to renameat(2) if possible.
+            return renameat(olddirfd, oldpath, newdirfd, newpath);
+        }
+        Err(err).context(Renameat2 {
+            olddirfd,
+            oldpath,
+            newdirfd,
+            newpath,
+            flags,
+        })
+    }
+}
+
+/// Wrapper for `fstatfs(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for `fstatfs(2)`.
+pub(crate) fn fstatfs(fd: RawFd) -> Result<statfs, Error> {
+    // SAFETY: repr(C) struct without internal references is definitely valid. C
+    //         callers are expected to zero it as well.
+    let mut buf: statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "fstatfs",
+        || format!("{}, <buf>", FrozenFd::from(fd)),
+        || unsafe { libc::fstatfs(fd, &mut buf as *mut statfs) },
+    );
+
+    if ret >= 0 {
+        Ok(buf)
+    } else {
+        Err(err).context(Fstatfs { fd })
+    }
+}
+
+/// Wrapper for `readahead(2)`.
+/// Wrapper for `fchdir(2)`.
+pub(crate) fn fchdir(fd: RawFd) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "fchdir",
+        || FrozenFd::from(fd).to_string(),
+        || unsafe { libc::fchdir(fd) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Fchdir { fd })
+    }
+}
+
+pub(crate) fn readahead(fd: RawFd, offset: u64, count: usize) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "readahead",
+        || format!("{}, {}, {}", FrozenFd::from(fd), offset, count),
+        || unsafe { libc::readahead(fd, offset as _, count) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Readahead { fd, offset, count })
+    }
+}
+
+/// Wrapper for `fstatat(2)`, which auto-sets `AT_NO_AUTOMOUNT |
+/// AT_SYMLINK_NOFOLLOW | AT_EMPTY_PATH`.
+///
+/// This is needed because Rust doesn't provide any interface for `fstatat(2)`.
+pub(crate) fn fstatat<P: AsRef<Path>>(dirfd: RawFd, path: P) -> Result<stat, Error> {
+    // SAFETY: repr(C) struct without internal references is definitely valid. C
+    //         callers are expected to zero it as well.
+    let mut buf: stat = unsafe { std::mem::zeroed() };
+    let path = path.as_ref();
+    let flags = libc::AT_NO_AUTOMOUNT | libc::AT_SYMLINK_NOFOLLOW | libc::AT_EMPTY_PATH;
+    let cpath = path.to_c_string().context(Fstatat { dirfd, path, flags })?;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "fstatat",
+        || {
+            format!(
+                "{}, {:?}, <buf>, {}",
+                FrozenFd::from(dirfd),
+                path,
+                at_flags(flags)
+            )
+        },
+        || unsafe { libc::fstatat(dirfd, cpath.as_ptr(), &mut buf as *mut stat, flags) },
+    );
+
+    if ret >= 0 {
+        Ok(buf)
+    } else {
+        Err(err).context(Fstatat { dirfd, path, flags })
+    }
+}
+
+/// Wrapper for `statx(2)`, which doesn't follow symlinks or trigger
+/// automounts.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `statx(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn statx<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    mask: u32,
+) -> Result<libc::statx, Error> {
+    // SAFETY: repr(C) struct without internal references is definitely valid.
+    let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+    let path = path.as_ref();
+    let flags = libc::AT_NO_AUTOMOUNT
+        | libc::AT_SYMLINK_NOFOLLOW
+        | libc::AT_EMPTY_PATH
+        | libc::AT_STATX_SYNC_AS_STAT;
+    let cpath = path.to_c_string().context(Statx {
+        dirfd,
+        path,
+        flags,
+        mask,
+    })?;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "statx",
+        || {
+            format!(
+                "{}, {:?}, {}, 0x{:x}, <buf>",
+                FrozenFd::from(dirfd),
+                path,
+                at_flags(flags),
+                mask
+            )
+        },
+        || unsafe {
+            libc::statx(
+                dirfd,
+                cpath.as_ptr(),
+                flags,
+                mask,
+                &mut buf as *mut libc::statx,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(buf)
+    } else {
+        Err(err).context(Statx {
+            dirfd,
+            path,
+            flags,
+            mask,
+        })
+    }
+}
+
+/// Wrapper for `getdents64(2)`.
+///
+/// This is needed because Rust doesn't provide any way of reading directory
+/// entries from a file descriptor (and `readdir(3)` has hidden state we'd
+/// rather avoid). The number of bytes filled in `buf` is returned, with `0`
+/// indicating that the end of the directory has been reached.
+pub(crate) fn getdents64(fd: RawFd, buf: &mut [u8]) -> Result<usize, Error> {
+    let size = buf.len();
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "getdents64",
+        || format!("{}, <buf>, {}", FrozenFd::from(fd), size),
+        || unsafe { libc::syscall(libc::SYS_getdents64, fd, buf.as_mut_ptr(), size) },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(err).context(Getdents64 { fd, size })
+    }
+}
+
+/// Wrapper for a read-only, private `mmap(2)` of a file.
+///
+/// This is needed because Rust doesn't provide a way to memory-map files.
+pub(crate) fn mmap_readonly(fd: RawFd, len: usize, offset: u64) -> Result<*mut c_void, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall. A new PROT_READ mapping
+    //         cannot affect any existing memory.
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            fd,
+            offset as libc::off_t,
+        )
+    };
+    let err = IOError::last_os_error();
+
+    if addr != libc::MAP_FAILED {
+        Ok(addr)
+    } else {
+        Err(err).context(Mmap { fd, len, offset })
+    }
+}
+
+/// Wrapper for `munmap(2)`. Errors are ignored, since the only possible error
+/// is `-EINVAL` (which would be a bug in libpathrs).
+///
+/// # Safety
+///
+/// The caller guarantees that `addr` and `len` correspond to a mapping created
+/// with [`mmap_readonly`] which is no longer referenced.
+///
+/// [`mmap_readonly`]: fn.mmap_readonly.html
+pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) {
+    libc::munmap(addr, len);
+}
+
+/// `struct io_sqring_offsets`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IoSqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_cqring_offsets`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IoCqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_uring_params`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IoUringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: IoSqringOffsets,
+    cq_off: IoCqringOffsets,
+}
+
+/// `struct io_uring_sqe`, with only the fields used by `IORING_OP_UNLINKAT`
+/// given their proper names.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IoUringSqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    /// The union of per-opcode flags (`unlink_flags` for unlinkat).
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// `struct io_uring_cqe`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct IoUringCqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+const IORING_OFF_SQ_RING: u64 = 0;
+const IORING_OFF_CQ_RING: u64 = 0x800_0000;
+const IORING_OFF_SQES: u64 = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_UNLINKAT: u8 = 36;
+
+/// A `MAP_SHARED` mapping of one of the regions of an io_uring.
+struct RingMap {
+    addr: *mut c_void,
+    len: usize,
+}
+
+impl RingMap {
+    fn new(fd: RawFd, len: usize, offset: u64) -> Result<Self, Error> {
+        // SAFETY: Obviously safe-to-use Linux syscall. A new mapping cannot
+        //         affect any existing memory.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        let err = IOError::last_os_error();
+
+        if addr != libc::MAP_FAILED {
+            Ok(Self { addr, len })
+        } else {
+            Err(err).context(MmapRing { fd, len, offset })
+        }
+    }
+
+    /// Get a pointer to the `T` at byte offset `offset` of the mapping.
+    ///
+    /// # Safety
+    ///
+    /// The caller guarantees that `offset` is the offset of a `T` in the
+    /// mapping, as given by the kernel in `struct io_uring_params`.
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        (self.addr as *mut u8).add(offset as usize) as *mut T
+    }
+}
+
+impl Drop for RingMap {
+    fn drop(&mut self) {
+        // SAFETY: The mapping is only referenced through self.
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+/// A minimal io_uring instance, which is only used to submit batches of
+/// `IORING_OP_UNLINKAT` operations (Linux 5.11).
+///
+/// libc doesn't provide any of the io_uring structures (only the syscall
+/// numbers), so this is a bare-bones version of what liburing does.
+pub(crate) struct UnlinkRing {
+    fd: File,
+    params: IoUringParams,
+    sq: RingMap,
+    cq: RingMap,
+    sqes: RingMap,
+}
+
+impl UnlinkRing {
+    /// Wrapper for `io_uring_setup(2)`, creating a ring for batches of up to
+    /// `entries` operations.
+    ///
+    /// This fails with `ENOSYS` on kernels without io_uring, and with `EPERM`
+    /// if io_uring has been disabled (with the `kernel.io_uring_disabled`
+    /// sysctl or seccomp).
+    pub(crate) fn new(entries: u32) -> Result<Self, Error> {
+        let mut params = IoUringParams::default();
+        // SAFETY: Obviously safe-to-use Linux syscall.
+        let (fd, err) = traced(
+            "io_uring_setup",
+            || format!("{}, <params>", entries),
+            || unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_setup,
+                    entries,
+                    &mut params as *mut IoUringParams,
+                )
+            } as RawFd,
+        );
+        if fd < 0 {
+            return Err(err).context(IoUringSetup { entries });
+        }
+        // SAFETY: We know it's a real file descriptor.
+        let fd = unsafe { File::from_raw_fd(fd) };
+
+        let rawfd = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<IoUringCqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<IoUringSqe>();
+        Ok(Self {
+            sq: RingMap::new(rawfd, sq_len, IORING_OFF_SQ_RING)?,
+            cq: RingMap::new(rawfd, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: RingMap::new(rawfd, sqes_len, IORING_OFF_SQES)?,
+            fd,
+            params,
+        })
+    }
+
+    /// Wrapper for `io_uring_enter(2)`, returning the number of submitted
+    /// operations.
+    fn enter(&self, to_submit: u32, min_complete: u32) -> Result<u32, Error> {
+        let fd = self.fd.as_raw_fd();
+        // SAFETY: Obviously safe-to-use Linux syscall. The submission queue
+        //         only references memory which outlives the call.
+        let (ret, err) = retry_eintr(
+            "io_uring_enter",
+            || {
+                format!(
+                    "{}, {}, {}, IORING_ENTER_GETEVENTS, NULL, 0",
+                    FrozenFd::from(fd),
+                    to_submit,
+                    min_complete
+                )
+            },
+            || unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    fd,
+                    to_submit,
+                    min_complete,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<c_void>(),
+                    0,
+                )
+            } as c_int,
+        );
+
+        if ret >= 0 {
+            Ok(ret as u32)
+        } else {
+            Err(err).context(IoUringEnter {
+                fd,
+                to_submit,
+                min_complete,
+            })
+        }
+    }
+
+    /// The maximum number of operations submitted at once by
+    /// [`UnlinkRing::unlinkat_batch`].
+    ///
+    /// [`UnlinkRing::unlinkat_batch`]: struct.UnlinkRing.html#method.unlinkat_batch
+    pub(crate) fn entries(&self) -> usize {
+        self.params.sq_entries as usize
+    }
+
+    /// Run `unlinkat(dirfd, path, flags)` for each `(path, flags)` in `ops`
+    /// through the ring, returning the result of each operation (as though it
+    /// was done with [`unlinkat`]).
+    ///
+    /// Kernels older than Linux 5.11 don't support `IORING_OP_UNLINKAT`, in
+    /// which case every operation fails with `EINVAL`. If this returns an
+    /// error, the state of the ring is unknown and it must not be used again.
+    ///
+    /// [`unlinkat`]: fn.unlinkat.html
+    pub(crate) fn unlinkat_batch<P: AsRef<Path>>(
+        &mut self,
+        dirfd: RawFd,
+        ops: &[(P, c_int)],
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let mut results = Vec::with_capacity(ops.len());
+        for chunk in ops.chunks(self.entries()) {
+            let codes = self.submit_unlinkat(dirfd, chunk)?;
+            for ((path, flags), code) in chunk.iter().zip(codes) {
+                let (path, flags) = (path.as_ref(), *flags);
+                results.push(if code >= 0 {
+                    Ok(())
+                } else {
+                    Err(IOError::from_raw_os_error(-code)).context(Unlinkat { dirfd, path, flags })
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// Submit a chunk of at most `self.entries()` unlinkat operations and wait
+    /// for all of them to complete, returning their `res` values.
+    fn submit_unlinkat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: RawFd,
+        ops: &[(P, c_int)],
+    ) -> Result<Vec<i32>, Error> {
+        // The paths need to stay alive until the operations were submitted
+        // (the kernel copies them when preparing each operation).
+        let mut paths = Vec::with_capacity(ops.len());
+        for (path, flags) in ops {
+            let (path, flags) = (path.as_ref(), *flags);
+            paths.push(
+                path.to_c_string()
+                    .context(Unlinkat { dirfd, path, flags })?,
+            );
+        }
+        let count = ops.len() as u32;
+        let sq_off = self.params.sq_off;
+        let cq_off = self.params.cq_off;
+
+        // SAFETY: All of the offsets come from the kernel, and the entries we
+        //         write to are not owned by the kernel until the tail is
+        //         updated.
+        unsafe {
+            let sq_tail = &*self.sq.at::<AtomicU32>(sq_off.tail);
+            let sq_mask = *self.sq.at::<u32>(sq_off.ring_mask);
+            let sq_array = self.sq.at::<u32>(sq_off.array);
+            let sqes = self.sqes.at::<IoUringSqe>(0);
+
+            let mut tail = sq_tail.load(Ordering::Acquire);
+            for (idx, ((_, flags), path)) in ops.iter().zip(&paths).enumerate() {
+                let slot = tail & sq_mask;
+                *sqes.add(slot as usize) = IoUringSqe {
+                    opcode: IORING_OP_UNLINKAT,
+                    fd: dirfd,
+                    addr: path.as_ptr() as u64,
+                    op_flags: *flags as u32,
+                    user_data: idx as u64,
+                    ..Default::default()
+                };
+                *sq_array.add(slot as usize) = slot;
+                tail = tail.wrapping_add(1);
+            }
+            sq_tail.store(tail, Ordering::Release);
+        }
+
+        let mut codes = vec![0; ops.len()];
+        let (mut submitted, mut completed) = (0, 0);
+        while completed < count {
+            submitted += self.enter(count - submitted, count - completed)?;
+
+            // SAFETY: All of the offsets come from the kernel, and the entries
+            //         between head and tail are owned by us until the head is
+            //         updated.
+            unsafe {
+                let cq_head = &*self.cq.at::<AtomicU32>(cq_off.head);
+                let cq_tail = &*self.cq.at::<AtomicU32>(cq_off.tail);
+                let cq_mask = *self.cq.at::<u32>(cq_off.ring_mask);
+                let cqes = self.cq.at::<IoUringCqe>(cq_off.cqes);
+
+                let mut head = cq_head.load(Ordering::Relaxed);
+                let tail = cq_tail.load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = *cqes.add((head & cq_mask) as usize);
+                    if let Some(code) = codes.get_mut(cqe.user_data as usize) {
+                        *code = cqe.res;
+                    }
+                    head = head.wrapping_add(1);
+                    completed += 1;
+                }
+                cq_head.store(head, Ordering::Release);
+            }
+        }
+        Ok(codes)
+    }
+}
+
+/// Wrapper for `getrlimit(RLIMIT_NOFILE)`, returning the soft limit.
+///
+/// This is needed because Rust doesn't provide any way of getting resource
+/// limits.
+pub(crate) fn getrlimit_nofile() -> Result<u64, Error> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "getrlimit",
+        || "RLIMIT_NOFILE, <rlim>".to_string(),
+        || unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) },
+    );
+
+    if ret >= 0 {
+        Ok(rlim.rlim_cur)
+    } else {
+        Err(err).context(Getrlimit {
+            resource: "RLIMIT_NOFILE",
+        })
+    }
+}
+
+/// Get the thread id of the calling thread.
+pub(crate) fn gettid() -> libc::pid_t {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+/// Get the system page size.
+pub(crate) fn page_size() -> usize {
+    // SAFETY: Obviously safe-to-use libc function.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Is `fd` a terminal (as in `isatty(3)`)?
+pub(crate) fn isatty(fd: RawFd) -> bool {
+    // SAFETY: Obviously safe-to-use libc function.
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Wrapper for `fchownat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `fchownat(2)`. We need the dirfd argument, so we need a wrapper.
+pub(crate) fn fchownat<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    flags: c_int,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path.to_c_string().context(Fchownat {
+        dirfd,
+        path,
+        uid,
+        gid,
+        flags,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "fchownat",
+        || {
+            format!(
+                "{}, {:?}, {}, {}, {}",
+                FrozenFd::from(dirfd),
+                path,
+                uid,
+                gid,
+                at_flags(flags)
+            )
+        },
+        || unsafe { libc::fchownat(dirfd, cpath.as_ptr(), uid, gid, flags) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Fchownat {
+            dirfd,
+            path,
+            uid,
+            gid,
+            flags,
+        })
+    }
+}
+
+/// Wrapper for `fchmodat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `fchmodat(2)`. We need the dirfd argument, so we need a wrapper.
+/// Note that `fchmodat(2)` doesn't support any flags (`AT_SYMLINK_NOFOLLOW` is
+/// only implemented by glibc through `/proc`), so this wrapper always follows
+/// the final path component.
+pub(crate) fn fchmodat<P: AsRef<Path>>(dirfd: RawFd, path: P, mode: mode_t) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path.to_c_string().context(Fchmodat { dirfd, path, mode })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "fchmodat",
+        || format!("{}, {:?}, 0o{:o}", FrozenFd::from(dirfd), path, mode),
+        || unsafe { libc::fchmodat(dirfd, cpath.as_ptr(), mode, 0) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Fchmodat { dirfd, path, mode })
+    }
+}
+
+/// Wrapper for `utimensat(2)`.
+///
+/// This is needed because Rust doesn't provide a way to access the dirfd
+/// argument of `utimensat(2)`. We need the dirfd argument, so we need a
+/// wrapper. The `times` are `(tv_sec, tv_nsec)` pairs for the access and
+/// modification times respectively (and so accept `UTIME_NOW` and
+/// `UTIME_OMIT`).
+pub(crate) fn utimensat<P: AsRef<Path>>(
+    dirfd: RawFd,
+    path: P,
+    times: [(i64, i64); 2],
+    flags: c_int,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let timespecs = [
+        libc::timespec {
+            tv_sec: times[0].0,
+            tv_nsec: times[0].1,
+        },
+        libc::timespec {
+            tv_sec: times[1].0,
+            tv_nsec: times[1].1,
+        },
+    ];
+    let cpath = path.to_c_string().context(Utimensat {
+        dirfd,
+        path,
+        times,
+        flags,
+    })?;
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let len = unsafe {
-        libc::readlinkat(
+    let (ret, err) = retry_eintr(
+        "utimensat",
+        || {
+            format!(
+                "{}, {:?}, {:?}, {}",
+                FrozenFd::from(dirfd),
+                path,
+                times,
+                at_flags(flags)
+            )
+        },
+        || unsafe { libc::utimensat(dirfd, cpath.as_ptr(), timespecs.as_ptr(), flags) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Utimensat {
             dirfd,
-            path.to_c_string().as_ptr(),
-            buffer.as_mut_ptr() as *mut i8,
-            buffer.len(),
-        )
+            path,
+            times,
+            flags,
+        })
+    }
+}
+
+/// Wrapper for `fallocate(2)`.
+///
+/// This is needed because Rust doesn't provide a way to punch holes in files.
+pub(crate) fn fallocate(fd: RawFd, mode: c_int, offset: u64, len: u64) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "fallocate",
+        || {
+            format!(
+                "{}, {}, {}, {}",
+                FrozenFd::from(fd),
+                falloc_flags(mode),
+                offset,
+                len
+            )
+        },
+        || unsafe { libc::fallocate(fd, mode, offset as libc::off_t, len as libc::off_t) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Fallocate {
+            fd,
+            mode,
+            offset,
+            len,
+        })
+    }
+}
+
+/// Wrapper for `ioctl(fd, FICLONE, src_fd)`, which makes `fd` a reflink of
+/// `src_fd`.
+pub(crate) fn ioctl_ficlone(fd: RawFd, src_fd: RawFd) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "ioctl",
+        || {
+            format!(
+                "{}, FICLONE, {}",
+                FrozenFd::from(fd),
+                FrozenFd::from(src_fd)
+            )
+        },
+        || unsafe { libc::ioctl(fd, libc::FICLONE, src_fd) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Ficlone { fd, src_fd })
+    }
+}
+
+/// `struct fsxattr`, as used by `FS_IOC_FSGETXATTR` and `FS_IOC_FSSETXATTR`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Fsxattr {
+    /// FS_XFLAG_* flags.
+    pub(crate) fsx_xflags: u32,
+    /// Extent size hint.
+    pub(crate) fsx_extsize: u32,
+    /// Number of extents (read-only).
+    pub(crate) fsx_nextents: u32,
+    /// Project id.
+    pub(crate) fsx_projid: u32,
+    /// Copy-on-write extent size hint.
+    pub(crate) fsx_cowextsize: u32,
+    fsx_pad: [u8; 8],
+}
+
+/// `FS_XFLAG_DAX` flag for `struct fsxattr`.
+pub(crate) const FS_XFLAG_DAX: u32 = 0x8000;
+
+// These are not in the libc crate.
+const FS_IOC_FSGETXATTR: libc::Ioctl = libc::_IOR::<Fsxattr>(b'X' as u32, 31);
+const FS_IOC_FSSETXATTR: libc::Ioctl = libc::_IOW::<Fsxattr>(b'X' as u32, 32);
+
+/// The inode flag of casefolded directories (`chattr +F`). This is not in the
+/// libc crate.
+pub(crate) const FS_CASEFOLD_FL: c_int = 0x4000_0000;
+
+/// Wrapper for `ioctl(fd, FS_IOC_GETFLAGS)`, which gets the `FS_*_FL` inode
+/// flags of `fd` (which must not be an `O_PATH` descriptor).
+///
+/// This is needed because Rust doesn't provide any interface for inode flags.
+pub(crate) fn ioctl_getflags(fd: RawFd) -> Result<c_int, Error> {
+    let mut flags: c_int = 0;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "ioctl",
+        || format!("{}, FS_IOC_GETFLAGS, <buf>", FrozenFd::from(fd)),
+        || unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut flags as *mut c_int) },
+    );
+
+    if ret >= 0 {
+        Ok(flags)
+    } else {
+        Err(err).context(GetFlags { fd })
+    }
+}
+
+/// Wrapper for `ioctl(fd, FS_IOC_FSGETXATTR)`.
+///
+/// This is needed because Rust doesn't provide any interface for inode flags.
+pub(crate) fn ioctl_fsgetxattr(fd: RawFd) -> Result<Fsxattr, Error> {
+    let mut fsx = Fsxattr::default();
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "ioctl",
+        || format!("{}, FS_IOC_FSGETXATTR, <buf>", FrozenFd::from(fd)),
+        || unsafe { libc::ioctl(fd, FS_IOC_FSGETXATTR, &mut fsx as *mut Fsxattr) },
+    );
+
+    if ret >= 0 {
+        Ok(fsx)
+    } else {
+        Err(err).context(FsGetXattr { fd })
+    }
+}
+
+/// Wrapper for `ioctl(fd, FS_IOC_FSSETXATTR)`.
+///
+/// This is needed because Rust doesn't provide any interface for inode flags.
+pub(crate) fn ioctl_fssetxattr(fd: RawFd, fsx: &Fsxattr) -> Result<(), Error> {
+    let xflags = fsx.fsx_xflags;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "ioctl",
+        || {
+            format!(
+                "{}, FS_IOC_FSSETXATTR, {{ fsx_xflags: 0x{:x}, .. }}",
+                FrozenFd::from(fd),
+                xflags
+            )
+        },
+        || unsafe { libc::ioctl(fd, FS_IOC_FSSETXATTR, fsx as *const Fsxattr) },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(FsSetXattr { fd, xflags })
+    }
+}
+
+/// Wrapper for `copy_file_range(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for in-kernel
+/// copies. The file offsets of both file descriptors are used (and updated),
+/// and the number of bytes copied is returned, with `0` indicating that the
+/// end of `fd_in` has been reached.
+pub(crate) fn copy_file_range(fd_in: RawFd, fd_out: RawFd, len: usize) -> Result<usize, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "copy_file_range",
+        || {
+            format!(
+                "{}, NULL, {}, NULL, {}, 0",
+                FrozenFd::from(fd_in),
+                FrozenFd::from(fd_out),
+                len
+            )
+        },
+        || unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                fd_in,
+                std::ptr::null_mut::<libc::loff_t>(),
+                fd_out,
+                std::ptr::null_mut::<libc::loff_t>(),
+                len,
+                0,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(err).context(CopyFileRange { fd_in, fd_out, len })
+    }
+}
+
+/// `OPEN_TREE_CLONE` flag for `open_tree(2)`.
+pub(crate) const OPEN_TREE_CLONE: u32 = 1;
+
+/// Wrapper for `sendfile(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for in-kernel
+/// copies. `offset` is used (and updated) instead of the file offset of
+/// `fd_in`, and the number of bytes sent is returned, with `0` indicating that
+/// the end of `fd_in` has been reached.
+pub(crate) fn sendfile(
+    fd_out: RawFd,
+    fd_in: RawFd,
+    offset: &mut i64,
+    count: usize,
+) -> Result<usize, Error> {
+    let start = *offset;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "sendfile",
+        || {
+            format!(
+                "{}, {}, [{}], {}",
+                FrozenFd::from(fd_out),
+                FrozenFd::from(fd_in),
+                start,
+                count
+            )
+        },
+        || unsafe { libc::sendfile(fd_out, fd_in, offset as *mut libc::off_t, count) },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(err).context(Sendfile {
+            fd_out,
+            fd_in,
+            offset: start,
+            count,
+        })
+    }
+}
+
+/// Wrapper for `splice(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for in-kernel
+/// copies. One of `fd_in` and `fd_out` must be a pipe. If `offset` is given, it
+/// is used (and updated) instead of the file offset of `fd_in`. The number of
+/// bytes moved is returned, with `0` indicating that the end of `fd_in` has
+/// been reached.
+pub(crate) fn splice(
+    fd_in: RawFd,
+    offset: Option<&mut i64>,
+    fd_out: RawFd,
+    len: usize,
+) -> Result<usize, Error> {
+    let start = offset.as_ref().map(|offset| **offset);
+    let off_in = match offset {
+        Some(offset) => offset as *mut libc::loff_t,
+        None => ptr::null_mut(),
     };
-    let mut err = IOError::last_os_error();
-    let maybe_truncated = len >= (buffer.len() as isize);
-    if len < 0 || maybe_truncated {
-        if maybe_truncated {
-            err = IOError::from_raw_os_error(libc::ENAMETOOLONG);
-        }
-        Err(err).context(Readlinkat { dirfd, path })
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "splice",
+        || {
+            format!(
+                "{}, {:?}, {}, NULL, {}, SPLICE_F_MOVE|SPLICE_F_MORE",
+                FrozenFd::from(fd_in),
+                start,
+                FrozenFd::from(fd_out),
+                len
+            )
+        },
+        || unsafe {
+            libc::splice(
+                fd_in,
+                off_in,
+                fd_out,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
     } else {
-        let content = OsStr::from_bytes(&buffer[..(len as usize)]);
-        Ok(PathBuf::from(content))
+        Err(err).context(Splice {
+            fd_in,
+            offset: start,
+            fd_out,
+            len,
+        })
+    }
+}
+
+/// Wrapper for `pipe2(2)`, returning the `(reader, writer)` ends of a new
+/// pipe. `O_CLOEXEC` is always set.
+pub(crate) fn pipe2() -> Result<(File, File), Error> {
+    let mut pipefds: [c_int; 2] = [-1; 2];
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "pipe2",
+        || "<fds>, O_CLOEXEC".to_string(),
+        || unsafe { libc::pipe2(pipefds.as_mut_ptr(), libc::O_CLOEXEC) },
+    );
+
+    if ret >= 0 {
+        // SAFETY: pipe2 gave us two new file descriptors.
+        Ok(unsafe { (File::from_raw_fd(pipefds[0]), File::from_raw_fd(pipefds[1])) })
+    } else {
+        Err(err).context(Pipe2)
+    }
+}
+
+/// Wrapper for `open_tree(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for the new mount
+/// API. `O_CLOEXEC` (`OPEN_TREE_CLOEXEC`) is always added to `flags`.
+pub(crate) fn open_tree<P: AsRef<Path>>(dirfd: RawFd, path: P, flags: u32) -> Result<File, Error> {
+    let path = path.as_ref();
+    let flags = flags | libc::O_CLOEXEC as u32;
+    let cpath = path
+        .to_c_string()
+        .context(OpenTree { dirfd, path, flags })?;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (fd, err) = traced(
+        "open_tree",
+        || {
+            format!(
+                "{}, {:?}, {}",
+                FrozenFd::from(dirfd),
+                path,
+                open_tree_flags(flags)
+            )
+        },
+        || unsafe { libc::syscall(libc::SYS_open_tree, dirfd, cpath.as_ptr(), flags) } as RawFd,
+    );
+
+    if fd >= 0 {
+        // SAFETY: We know it's a real file descriptor.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    } else {
+        Err(err).context(OpenTree { dirfd, path, flags })
+    }
+}
+
+/// Wrapper for `listxattr(2)`.
+///
+/// The `*xattrat(2)` family of syscalls (see [`listxattrat`]) only exists on
+/// recent kernels, and `O_PATH` descriptors cannot be used with
+/// `flistxattr(2)` -- so on older kernels callers need to pass a
+/// `/proc/self/fd/$n` path. The number of bytes filled in `buf` is returned
+/// (or the required size if `buf` is empty).
+///
+/// [`listxattrat`]: fn.listxattrat.html
+pub(crate) fn listxattr<P: AsRef<Path>>(path: P, buf: &mut [u8]) -> Result<usize, Error> {
+    let path = path.as_ref();
+    let size = buf.len();
+    let cpath = path.to_c_string().context(Listxattr { path, size })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "listxattr",
+        || format!("{:?}, <buf>, {}", path, size),
+        || unsafe { libc::listxattr(cpath.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, size) },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(err).context(Listxattr { path, size })
+    }
+}
+
+/// Wrapper for `getxattr(2)`.
+///
+/// See [`listxattr`] for why this takes a path. The number of bytes filled in
+/// `buf` is returned (or the required size if `buf` is empty).
+///
+/// [`listxattr`]: fn.listxattr.html
+pub(crate) fn getxattr<P: AsRef<Path>, N: AsRef<OsStr>>(
+    path: P,
+    name: N,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let size = buf.len();
+    let cpath = path.to_c_string().context(Getxattr { path, name, size })?;
+    let cname = name.to_c_string().context(Getxattr { path, name, size })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "getxattr",
+        || format!("{:?}, {:?}, <buf>, {}", path, name, size),
+        || unsafe {
+            libc::getxattr(
+                cpath.as_ptr(),
+                cname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                size,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(err).context(Getxattr { path, name, size })
+    }
+}
+
+/// Wrapper for `setxattr(2)`.
+///
+/// See [`listxattr`] for why this takes a path.
+///
+/// [`listxattr`]: fn.listxattr.html
+pub(crate) fn setxattr<P: AsRef<Path>, N: AsRef<OsStr>>(
+    path: P,
+    name: N,
+    value: &[u8],
+    flags: c_int,
+) -> Result<(), Error> {
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let size = value.len();
+    let cpath = path.to_c_string().context(Setxattr {
+        path,
+        name,
+        size,
+        flags,
+    })?;
+    let cname = name.to_c_string().context(Setxattr {
+        path,
+        name,
+        size,
+        flags,
+    })?;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "setxattr",
+        || {
+            format!(
+                "{:?}, {:?}, <buf>, {}, {}",
+                path,
+                name,
+                size,
+                xattr_flags(flags)
+            )
+        },
+        || unsafe {
+            libc::setxattr(
+                cpath.as_ptr(),
+                cname.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                size,
+                flags,
+            )
+        },
+    );
+
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(err).context(Setxattr {
+            path,
+            name,
+            size,
+            flags,
+        })
     }
 }
 
-/// Wrapper for `mkdirat(2)`.
+/// Wrapper for `removexattr(2)`.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `mkdirat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn mkdirat<P: AsRef<Path>>(dirfd: RawFd, path: P, mode: mode_t) -> Result<(), Error> {
-    let path = path.as_ref();
+/// See [`listxattr`] for why this takes a path.
+///
+/// [`listxattr`]: fn.listxattr.html
+pub(crate) fn removexattr<P: AsRef<Path>, N: AsRef<OsStr>>(path: P, name: N) -> Result<(), Error> {
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let cpath = path.to_c_string().context(Removexattr { path, name })?;
+    let cname = name.to_c_string().context(Removexattr { path, name })?;
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe { libc::mkdirat(dirfd, path.to_c_string().as_ptr(), mode) };
-    let err = IOError::last_os_error();
+    let (ret, err) = retry_eintr(
+        "removexattr",
+        || format!("{:?}, {:?}", path, name),
+        || unsafe { libc::removexattr(cpath.as_ptr(), cname.as_ptr()) },
+    );
 
     if ret >= 0 {
         Ok(())
     } else {
-        Err(err).context(Mkdirat { dirfd, path, mode })
+        Err(err).context(Removexattr { path, name })
     }
 }
 
-/// Wrapper for `mknodat(2)`.
+// The *xattrat(2) syscalls are too new for libc, but were added after the
+// syscall tables were unified so they have the same number everywhere.
+#[allow(non_upper_case_globals)]
+const SYS_setxattrat: libc::c_long = 463;
+#[allow(non_upper_case_globals)]
+const SYS_getxattrat: libc::c_long = 464;
+#[allow(non_upper_case_globals)]
+const SYS_listxattrat: libc::c_long = 465;
+#[allow(non_upper_case_globals)]
+const SYS_removexattrat: libc::c_long = 466;
+
+/// `struct xattr_args`, as used by `getxattrat(2)` and `setxattrat(2)`.
+#[repr(C)]
+struct XattrArgs {
+    value: u64,
+    size: u32,
+    flags: u32,
+}
+
+/// Wrapper for `listxattrat(2)` (Linux 6.13), which is [`listxattr`] relative
+/// to `dirfd`. The magic-links in `path` are always followed, so this can be
+/// used on `O_PATH` descriptors through `PROCFS_HANDLE` without touching the
+/// global `/proc`. Older kernels return `ENOSYS`.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `mknodat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn mknodat<P: AsRef<Path>>(
+/// [`listxattr`]: fn.listxattr.html
+pub(crate) fn listxattrat<P: AsRef<Path>>(
     dirfd: RawFd,
     path: P,
-    mode: mode_t,
-    dev: dev_t,
-) -> Result<(), Error> {
+    buf: &mut [u8],
+) -> Result<usize, Error> {
     let path = path.as_ref();
+    let size = buf.len();
+    let cpath = path
+        .to_c_string()
+        .context(Listxattrat { dirfd, path, size })?;
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe { libc::mknodat(dirfd, path.to_c_string().as_ptr(), mode, dev) };
-    let err = IOError::last_os_error();
+    let (ret, err) = retry_eintr(
+        "listxattrat",
+        || format!("{}, {:?}, 0, <buf>, {}", FrozenFd::from(dirfd), path, size),
+        || unsafe {
+            libc::syscall(
+                SYS_listxattrat,
+                dirfd,
+                cpath.as_ptr(),
+                0,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                size,
+            )
+        },
+    );
 
     if ret >= 0 {
-        Ok(())
+        Ok(ret as usize)
     } else {
-        Err(err).context(Mknodat {
-            dirfd,
-            path,
-            mode,
-            // SAFETY: Obviously safe-to-use libc function.
-            major: unsafe { libc::major(dev) },
-            // SAFETY: Obviously safe-to-use libc function.
-            minor: unsafe { libc::minor(dev) },
-        })
+        Err(err).context(Listxattrat { dirfd, path, size })
     }
 }
 
-/// Wrapper for `unlinkat(2)`.
+/// Wrapper for `getxattrat(2)` (Linux 6.13). See [`listxattrat`] for details.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `unlinkat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn unlinkat<P: AsRef<Path>>(dirfd: RawFd, path: P, flags: c_int) -> Result<(), Error> {
-    let path = path.as_ref();
+/// [`listxattrat`]: fn.listxattrat.html
+pub(crate) fn getxattrat<P: AsRef<Path>, N: AsRef<OsStr>>(
+    dirfd: RawFd,
+    path: P,
+    name: N,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let size = buf.len();
+    let cpath = path.to_c_string().context(Getxattrat {
+        dirfd,
+        path,
+        name,
+        size,
+    })?;
+    let cname = name.to_c_string().context(Getxattrat {
+        dirfd,
+        path,
+        name,
+        size,
+    })?;
+    let mut args = XattrArgs {
+        value: buf.as_mut_ptr() as u64,
+        size: size as u32,
+        flags: 0,
+    };
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe { libc::unlinkat(dirfd, path.to_c_string().as_ptr(), flags) };
-    let err = IOError::last_os_error();
+    let (ret, err) = retry_eintr(
+        "getxattrat",
+        || {
+            format!(
+                "{}, {:?}, 0, {:?}, <buf>, {}",
+                FrozenFd::from(dirfd),
+                path,
+                name,
+                size
+            )
+        },
+        || unsafe {
+            libc::syscall(
+                SYS_getxattrat,
+                dirfd,
+                cpath.as_ptr(),
+                0,
+                cname.as_ptr(),
+                &mut args as *mut XattrArgs,
+                mem::size_of::<XattrArgs>(),
+            )
+        },
+    );
 
     if ret >= 0 {
-        Ok(())
+        Ok(ret as usize)
     } else {
-        Err(err).context(Unlinkat { dirfd, path, flags })
+        Err(err).context(Getxattrat {
+            dirfd,
+            path,
+            name,
+            size,
+        })
     }
 }
 
-/// Wrapper for `linkat(2)`.
+/// Wrapper for `setxattrat(2)` (Linux 6.13). See [`listxattrat`] for details.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `linkat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn linkat<P: AsRef<Path>>(
-    olddirfd: RawFd,
-    oldpath: P,
-    newdirfd: RawFd,
-    newpath: P,
+/// [`listxattrat`]: fn.listxattrat.html
+pub(crate) fn setxattrat<P: AsRef<Path>, N: AsRef<OsStr>>(
+    dirfd: RawFd,
+    path: P,
+    name: N,
+    value: &[u8],
     flags: c_int,
 ) -> Result<(), Error> {
-    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe {
-        libc::linkat(
-            olddirfd,
-            oldpath.to_c_string().as_ptr(),
-            newdirfd,
-            newpath.to_c_string().as_ptr(),
-            flags,
-        )
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let size = value.len();
+    let cpath = path.to_c_string().context(Setxattrat {
+        dirfd,
+        path,
+        name,
+        size,
+        flags,
+    })?;
+    let cname = name.to_c_string().context(Setxattrat {
+        dirfd,
+        path,
+        name,
+        size,
+        flags,
+    })?;
+    let args = XattrArgs {
+        value: value.as_ptr() as u64,
+        size: size as u32,
+        flags: flags as u32,
     };
-    let err = IOError::last_os_error();
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "setxattrat",
+        || {
+            format!(
+                "{}, {:?}, 0, {:?}, <buf>, {}, {}",
+                FrozenFd::from(dirfd),
+                path,
+                name,
+                size,
+                xattr_flags(flags)
+            )
+        },
+        || unsafe {
+            libc::syscall(
+                SYS_setxattrat,
+                dirfd,
+                cpath.as_ptr(),
+                0,
+                cname.as_ptr(),
+                &args as *const XattrArgs,
+                mem::size_of::<XattrArgs>(),
+            )
+        },
+    );
 
     if ret >= 0 {
         Ok(())
     } else {
-        Err(err).context(Linkat {
-            olddirfd,
-            oldpath,
-            newdirfd,
-            newpath,
+        Err(err).context(Setxattrat {
+            dirfd,
+            path,
+            name,
+            size,
             flags,
         })
     }
 }
 
-/// Wrapper for `symlinkat(2)`.
+/// Wrapper for `removexattrat(2)` (Linux 6.13). See [`listxattrat`] for
+/// details.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `symlinkat(2)`. We need the dirfd argument, so we need a
-/// wrapper.
-pub(crate) fn symlinkat<P: AsRef<Path>>(target: P, dirfd: RawFd, path: P) -> Result<(), Error> {
-    let (target, path) = (target.as_ref(), path.as_ref());
+/// [`listxattrat`]: fn.listxattrat.html
+pub(crate) fn removexattrat<P: AsRef<Path>, N: AsRef<OsStr>>(
+    dirfd: RawFd,
+    path: P,
+    name: N,
+) -> Result<(), Error> {
+    let (path, name) = (path.as_ref(), name.as_ref());
+    let cpath = path
+        .to_c_string()
+        .context(Removexattrat { dirfd, path, name })?;
+    let cname = name
+        .to_c_string()
+        .context(Removexattrat { dirfd, path, name })?;
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe {
-        libc::symlinkat(
-            target.to_c_string().as_ptr(),
-            dirfd,
-            path.to_c_string().as_ptr(),
-        )
-    };
-    let err = IOError::last_os_error();
+    let (ret, err) = retry_eintr(
+        "removexattrat",
+        || format!("{}, {:?}, 0, {:?}", FrozenFd::from(dirfd), path, name),
+        || unsafe { libc::syscall(SYS_removexattrat, dirfd, cpath.as_ptr(), 0, cname.as_ptr()) },
+    );
 
     if ret >= 0 {
         Ok(())
     } else {
-        Err(err).context(Symlinkat {
-            dirfd,
-            path,
-            target,
-        })
+        Err(err).context(Removexattrat { dirfd, path, name })
     }
 }
 
-/// Wrapper for `renameat(2)`.
+/// Size of the control message buffer needed for a single `SCM_RIGHTS` file
+/// descriptor.
+fn scm_rights_space() -> usize {
+    // SAFETY: CMSG_SPACE is just arithmetic.
+    unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize }
+}
+
+/// Wrapper for `sendmsg(2)` with an optional `SCM_RIGHTS` file descriptor.
 ///
-/// This is needed because Rust doesn't provide a way to access the dirfd
-/// argument of `renameat(2)`. We need the dirfd argument, so we need a wrapper.
-pub(crate) fn renameat<P: AsRef<Path>>(
-    olddirfd: RawFd,
-    oldpath: P,
-    newdirfd: RawFd,
-    newpath: P,
-) -> Result<(), Error> {
-    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe {
-        libc::renameat(
-            olddirfd,
-            oldpath.to_c_string().as_ptr(),
-            newdirfd,
-            newpath.to_c_string().as_ptr(),
-        )
+/// This is needed because Rust doesn't provide any way of passing file
+/// descriptors over unix sockets. `MSG_NOSIGNAL` is always set. The number of
+/// bytes sent is returned (if it is shorter than `data`, the file descriptor
+/// has still been sent along with the first byte).
+pub(crate) fn sendmsg_fd(sockfd: RawFd, data: &[u8], fd: Option<RawFd>) -> Result<usize, Error> {
+    let size = data.len();
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: size,
     };
-    let err = IOError::last_os_error();
+    // Use a u64 buffer to ensure the control message is correctly aligned.
+    let mut cmsg_buf = [0u64; 8];
+
+    // SAFETY: msghdr is a plain C struct, so all-zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = scm_rights_space() as _;
+        // SAFETY: The control buffer is large enough and aligned for a single
+        //         cmsghdr containing one c_int.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, fd);
+        }
+    }
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "sendmsg",
+        || {
+            format!(
+                "{}, <msg: {} bytes, SCM_RIGHTS={:?}>, MSG_NOSIGNAL",
+                FrozenFd::from(sockfd),
+                size,
+                fd
+            )
+        },
+        || unsafe { libc::sendmsg(sockfd, &msg, libc::MSG_NOSIGNAL) },
+    );
 
     if ret >= 0 {
-        Ok(())
+        Ok(ret as usize)
     } else {
-        Err(err).context(Renameat {
-            olddirfd,
-            oldpath,
-            newdirfd,
-            newpath,
-        })
+        Err(err).context(SendmsgFd { sockfd, size, fd })
     }
 }
 
-lazy_static! {
-    pub(crate) static ref RENAME_FLAGS_SUPPORTED: bool = {
-        match renameat2(
-            libc::AT_FDCWD,
-            ".",
-            libc::AT_FDCWD,
-            ".",
-            libc::RENAME_EXCHANGE,
-        ) {
-            Ok(_) => true,
-            // We expect EBUSY, but just to be safe we only check for ENOSYS.
-            Err(err) => err.root_cause().raw_os_error() != Some(libc::ENOSYS),
-        }
+/// Wrapper for `recvmsg(2)` which accepts a single `SCM_RIGHTS` file
+/// descriptor.
+///
+/// This is needed because Rust doesn't provide any way of receiving file
+/// descriptors over unix sockets. The received file descriptor (if any) is
+/// always `O_CLOEXEC`. Any extra file descriptors sent by the peer are closed
+/// by the kernel, since we only provide space for one.
+pub(crate) fn recvmsg_fd(sockfd: RawFd, buf: &mut [u8]) -> Result<(usize, Option<File>), Error> {
+    let size = buf.len();
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: size,
     };
+    // Use a u64 buffer to ensure the control message is correctly aligned.
+    let mut cmsg_buf = [0u64; 8];
+
+    // SAFETY: msghdr is a plain C struct, so all-zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = scm_rights_space() as _;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "recvmsg",
+        || {
+            format!(
+                "{}, <msg: {} bytes>, MSG_CMSG_CLOEXEC",
+                FrozenFd::from(sockfd),
+                size
+            )
+        },
+        || unsafe { libc::recvmsg(sockfd, &mut msg, libc::MSG_CMSG_CLOEXEC) },
+    );
+
+    if ret < 0 {
+        return Err(err).context(RecvmsgFd { sockfd, size });
+    }
+
+    let mut file = None;
+    // SAFETY: The kernel has filled the control buffer with valid cmsghdrs (up
+    //         to msg_controllen), and any SCM_RIGHTS fds are now ours.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int);
+                file = Some(File::from_raw_fd(fd));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((ret as usize, file))
 }
 
-/// Wrapper for `renameat2(2)`.
+/// Wrapper for `getsockopt(SO_PEERCRED)`.
 ///
-/// This is needed because Rust doesn't provide any interface for `renameat2(2)`
-/// (especially not an interface for the dirfd).
-pub(crate) fn renameat2<P: AsRef<Path>>(
-    olddirfd: RawFd,
-    oldpath: P,
-    newdirfd: RawFd,
-    newpath: P,
-    flags: u32,
-) -> Result<(), Error> {
-    let (oldpath, newpath) = (oldpath.as_ref(), newpath.as_ref());
-    // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe {
-        // (g)libc doesn't have a renameat2 wrapper in older versions.
-        libc::syscall(
-            libc::SYS_renameat2,
-            olddirfd,
-            oldpath.to_c_string().as_ptr(),
-            newdirfd,
-            newpath.to_c_string().as_ptr(),
-            flags,
-        )
+/// This is needed because Rust's `UnixStream::peer_cred` is not yet stable.
+pub(crate) fn getsockopt_peercred(sockfd: RawFd) -> Result<libc::ucred, Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
     };
-    let err = IOError::last_os_error();
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "getsockopt",
+        || format!("{}, SOL_SOCKET, SO_PEERCRED", FrozenFd::from(sockfd)),
+        || unsafe {
+            libc::getsockopt(
+                sockfd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut c_void,
+                &mut len,
+            )
+        },
+    );
 
     if ret >= 0 {
-        Ok(())
+        Ok(cred)
     } else {
-        if flags == 0 {
-            // Fall back to renameat(2) if possible.
-            return renameat(olddirfd, oldpath, newdirfd, newpath);
-        }
-        Err(err).context(Renameat2 {
-            olddirfd,
-            oldpath,
-            newdirfd,
-            newpath,
-            flags,
-        })
+        Err(err).context(GetPeerCred { sockfd })
     }
 }
 
-/// Wrapper for `fstatfs(2)`.
+/// Run `stat(2)` on `path` inside a throwaway `chroot(2)` of `rootfd` (in a
+/// forked child, which first unshares a user namespace unless the process is
+/// privileged), returning the `(st_dev, st_ino)` of the result or the `errno`
+/// of the `stat(2)` failure.
 ///
-/// This is needed because Rust doesn't provide any interface for `fstatfs(2)`.
-pub(crate) fn fstatfs(fd: RawFd) -> Result<statfs, Error> {
-    // SAFETY: repr(C) struct without internal references is definitely valid. C
-    //         callers are expected to zero it as well.
-    let mut buf: statfs = unsafe { std::mem::zeroed() };
+/// This is used as an independent "oracle" for how the kernel resolves a path
+/// within a root. Only async-signal-safe syscalls are used in the child, so
+/// this is safe to use in multi-threaded programs.
+pub(crate) fn chroot_stat<P: AsRef<Path>>(
+    rootfd: RawFd,
+    path: P,
+) -> Result<Result<(u64, u64), c_int>, Error> {
+    let path = path.as_ref();
+    // All allocations must be done before forking.
+    let cpath = path.to_c_string().context(ChrootStat { rootfd, path })?;
+    let mut pipefds: [c_int; 2] = [-1; 2];
+
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe { libc::fstatfs(fd, &mut buf as *mut statfs) };
+    let ret = unsafe { libc::pipe2(pipefds.as_mut_ptr(), libc::O_CLOEXEC) };
+    let err = IOError::last_os_error();
+    if ret < 0 {
+        return Err(err).context(ChrootStat { rootfd, path });
+    }
+    // SAFETY: pipe2 gave us two new file descriptors.
+    let (reader, writer) =
+        unsafe { (File::from_raw_fd(pipefds[0]), File::from_raw_fd(pipefds[1])) };
+
+    // SAFETY: The child only uses async-signal-safe syscalls and then calls
+    //         _exit(2), so forking a multi-threaded process is fine.
+    let pid = unsafe { libc::fork() };
     let err = IOError::last_os_error();
+    if pid < 0 {
+        return Err(err).context(ChrootStat { rootfd, path });
+    }
+    if pid == 0 {
+        // SAFETY: See above. The response is [stage, errno, st_dev, st_ino],
+        //         where stage is 0 on success, 1 if setting up the chroot
+        //         failed and 2 if stat(2) failed.
+        unsafe {
+            let mut st: stat = mem::zeroed();
+            let mut res: [u64; 4] = [0; 4];
+            if libc::unshare(libc::CLONE_NEWUSER) < 0 && libc::geteuid() != 0
+                || libc::fchdir(rootfd) < 0
+                || libc::chroot(b".\0".as_ptr() as *const libc::c_char) < 0
+            {
+                res = [1, *libc::__errno_location() as u64, 0, 0];
+            } else if libc::stat(cpath.as_ptr(), &mut st) < 0 {
+                res = [2, *libc::__errno_location() as u64, 0, 0];
+            } else {
+                res[2] = st.st_dev;
+                res[3] = st.st_ino;
+            }
+            libc::write(
+                writer.as_raw_fd(),
+                res.as_ptr() as *const c_void,
+                mem::size_of_val(&res),
+            );
+            libc::_exit(0);
+        }
+    }
+    drop(writer);
+
+    let mut res: [u64; 4] = [0; 4];
+    let mut buf = [0u8; mem::size_of::<[u64; 4]>()];
+    let read = (&reader).read_exact(&mut buf);
+    loop {
+        // SAFETY: Obviously safe-to-use Linux syscall.
+        let ret = unsafe { libc::waitpid(pid, ptr::null_mut(), 0) };
+        if ret >= 0 || IOError::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            break;
+        }
+    }
+    read.context(ChrootStat { rootfd, path })?;
+    for (idx, chunk) in buf.chunks_exact(mem::size_of::<u64>()).enumerate() {
+        let mut word = [0u8; mem::size_of::<u64>()];
+        word.copy_from_slice(chunk);
+        res[idx] = u64::from_ne_bytes(word);
+    }
+
+    match res {
+        [0, _, dev, ino] => Ok(Ok((dev, ino))),
+        [2, errno, _, _] => Ok(Err(errno as c_int)),
+        [_, errno, _, _] => {
+            Err(IOError::from_raw_os_error(errno as c_int)).context(ChrootStat { rootfd, path })
+        }
+    }
+}
+
+/// Wrapper for `pidfd_send_signal(2)`.
+///
+/// This is needed because Rust doesn't provide any interface for pidfds. A
+/// `sig` of `0` only checks whether the process referenced by `pidfd` is still
+/// alive.
+pub(crate) fn pidfd_send_signal(pidfd: RawFd, sig: c_int) -> Result<(), Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = traced(
+        "pidfd_send_signal",
+        || format!("{}, {}, NULL, 0", FrozenFd::from(pidfd), sig),
+        || unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                pidfd,
+                sig,
+                ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        },
+    );
 
     if ret >= 0 {
-        Ok(buf)
+        Ok(())
     } else {
-        Err(err).context(Fstatfs { fd })
+        Err(err).context(PidfdSendSignal { pidfd, sig })
     }
 }
 
-/// Wrapper for `fstatat(2)`, which auto-sets `AT_NO_AUTOMOUNT |
-/// AT_SYMLINK_NOFOLLOW | AT_EMPTY_PATH`.
+/// Wrapper for `inotify_init1(2)`. The inotify descriptor is always
+/// non-blocking, so that pending events can be drained without waiting.
+pub(crate) fn inotify_init1() -> Result<File, Error> {
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (fd, err) = traced(
+        "inotify_init1",
+        || "IN_NONBLOCK|IN_CLOEXEC".to_string(),
+        || unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) },
+    );
+
+    if fd >= 0 {
+        // SAFETY: We know it's a real file descriptor.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    } else {
+        Err(err).context(InotifyInit1)
+    }
+}
+
+/// Wrapper for `inotify_add_watch(2)`, returning the watch descriptor.
 ///
-/// This is needed because Rust doesn't provide any interface for `fstatat(2)`.
-pub(crate) fn fstatat<P: AsRef<Path>>(dirfd: RawFd, path: P) -> Result<stat, Error> {
-    // SAFETY: repr(C) struct without internal references is definitely valid. C
-    //         callers are expected to zero it as well.
-    let mut buf: stat = unsafe { std::mem::zeroed() };
+/// There is no fd-based variant, so callers watching an `O_PATH` descriptor
+/// need to pass a `/proc/self/fd/$n` path.
+pub(crate) fn inotify_add_watch<P: AsRef<Path>>(
+    fd: RawFd,
+    path: P,
+    mask: u32,
+) -> Result<c_int, Error> {
     let path = path.as_ref();
-    let flags = libc::AT_NO_AUTOMOUNT | libc::AT_SYMLINK_NOFOLLOW | libc::AT_EMPTY_PATH;
+    let cpath = path
+        .to_c_string()
+        .context(InotifyAddWatch { fd, path, mask })?;
 
     // SAFETY: Obviously safe-to-use Linux syscall.
-    let ret = unsafe {
-        libc::fstatat(
-            dirfd,
-            path.to_c_string().as_ptr(),
-            &mut buf as *mut stat,
-            flags,
-        )
-    };
-    let err = IOError::last_os_error();
+    let (wd, err) = traced(
+        "inotify_add_watch",
+        || format!("{}, {:?}, 0x{:x}", FrozenFd::from(fd), path, mask),
+        || unsafe { libc::inotify_add_watch(fd, cpath.as_ptr(), mask) },
+    );
+
+    if wd >= 0 {
+        Ok(wd)
+    } else {
+        Err(err).context(InotifyAddWatch { fd, path, mask })
+    }
+}
+
+/// Wrapper for `faccessat(2)`, which checks `mode` using the effective ids
+/// (`AT_EACCESS`) as the kernel does for the actual operation.
+pub(crate) fn faccessat<P: AsRef<Path>>(dirfd: RawFd, path: P, mode: c_int) -> Result<(), Error> {
+    let path = path.as_ref();
+    let cpath = path
+        .to_c_string()
+        .context(Faccessat { dirfd, path, mode })?;
+
+    // SAFETY: Obviously safe-to-use Linux syscall.
+    let (ret, err) = retry_eintr(
+        "faccessat",
+        || {
+            format!(
+                "{}, {:?}, 0x{:x}, AT_EACCESS",
+                FrozenFd::from(dirfd),
+                path,
+                mode
+            )
+        },
+        || unsafe { libc::faccessat(dirfd, cpath.as_ptr(), mode, libc::AT_EACCESS) },
+    );
 
     if ret >= 0 {
-        Ok(buf)
+        Ok(())
     } else {
-        Err(err).context(Fstatat { dirfd, path, flags })
+        Err(err).context(Faccessat { dirfd, path, mode })
     }
 }
 
@@ -686,12 +3611,12 @@ pub(crate) mod unstable {
     impl fmt::Display for OpenHow {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             // self.flags
-            write!(f, "{{ flags: 0x{:x}, ", self.flags)?;
+            write!(f, "{{ flags: {}, ", open_flags(self.flags as i32))?;
             if self.flags & (libc::O_CREAT | libc::O_TMPFILE) as u64 != 0 {
                 write!(f, "mode: 0o{:o}, ", self.mode)?;
             }
             // self.resolve
-            write!(f, "resolve: 0x{:x} }}", self.resolve)
+            write!(f, "resolve: {} }}", resolve_flags(self.resolve))
         }
     }
 
@@ -717,6 +3642,11 @@ pub(crate) mod unstable {
     #[allow(unused)]
     pub const RESOLVE_IN_ROOT: u64 = 0x10;
 
+    /// Only complete the lookup if it can be done using cached data (without
+    /// blocking), otherwise fail with `-EAGAIN`.
+    #[allow(unused)]
+    pub const RESOLVE_CACHED: u64 = 0x20;
+
     #[allow(non_upper_case_globals)]
     const SYS_openat2: i64 = 437;
 
@@ -727,18 +3657,35 @@ pub(crate) mod unstable {
         // RESOLVE_IN_ROOT handles that correctly in a race-free way.
         let mut how = how.clone();
         how.flags |= libc::O_CLOEXEC as u64;
+        let cpath = path.to_c_string().context(Openat2 {
+            dirfd,
+            path,
+            how: how.clone(),
+            size: OPEN_HOW_SIZE,
+        })?;
 
         // SAFETY: Obviously safe-to-use Linux syscall.
-        let fd = unsafe {
-            libc::syscall(
-                SYS_openat2,
-                dirfd,
-                path.to_c_string().as_ptr(),
-                &how as *const OpenHow,
-                OPEN_HOW_SIZE,
-            )
-        } as RawFd;
-        let err = IOError::last_os_error();
+        let (fd, err) = retry_eintr(
+            "openat2",
+            || {
+                format!(
+                    "{}, {:?}, {}, {}",
+                    FrozenFd::from(dirfd),
+                    path,
+                    how,
+                    OPEN_HOW_SIZE
+                )
+            },
+            || unsafe {
+                libc::syscall(
+                    SYS_openat2,
+                    dirfd,
+                    cpath.as_ptr(),
+                    &how as *const OpenHow,
+                    OPEN_HOW_SIZE,
+                )
+            } as RawFd,
+        );
 
         if fd >= 0 {
             // SAFETY: We know it's a real file descriptor.
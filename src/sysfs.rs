@@ -0,0 +1,223 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Verified access to sysfs.
+//!
+//! Device and cgroup tooling needs to read and write files in `/sys`, which
+//! has the same overmount concerns as `/proc` -- a malicious container can
+//! mount something on top of `/sys` (or any of its subdirectories) to
+//! redirect writes. A [`SysfsHandle`] checks that its root really is the root
+//! of a sysfs mount, and every file opened through it is checked to be on the
+//! same sysfs mount (so overmounts of subdirectories are detected as well).
+//! Paths are resolved with the same scoping rules as a [`Root`], so the
+//! relative symlinks in sysfs (such as those in `/sys/class`) are safe to
+//! follow.
+//!
+//! [`SysfsHandle`]: struct.SysfsHandle.html
+//! [`Root`]: ../struct.Root.html
+
+use crate::{
+    error::{self, Error},
+    stat, syscalls, Handle, OpenFlags, Root,
+};
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// `f_type` of sysfs.
+const SYSFS_MAGIC: libc::__fsword_t = 0x6265_6572;
+
+/// The inode number of the root directory of a sysfs mount (the root of
+/// every kernfs instance has this inode number).
+const SYSFS_ROOT_INO: u64 = 1;
+
+/// A verified handle to the root of a sysfs mount.
+#[derive(Debug)]
+pub struct SysfsHandle {
+    root: Root,
+    /// Id of the sysfs mount, if the kernel provides it (`stx_mnt_id`).
+    mnt_id: Option<u64>,
+}
+
+/// Check that `file` is on sysfs.
+fn check_sysfs(file: &File) -> Result<(), Error> {
+    let fs_type = syscalls::fstatfs(file.as_raw_fd())
+        .context(error::RawOsError {
+            operation: "check filesystem of sysfs file",
+        })?
+        .f_type;
+    ensure!(
+        fs_type == SYSFS_MAGIC,
+        error::SafetyViolation {
+            description: format!("sysfs file is on a filesystem with f_type 0x{:X}", fs_type),
+        }
+    );
+    Ok(())
+}
+
+impl SysfsHandle {
+    /// Open and verify the host's `/sys`.
+    ///
+    /// # Errors
+    ///
+    /// If `/sys` is not the root of a sysfs mount, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+    pub fn new() -> Result<Self, Error> {
+        let dir = syscalls::openat(libc::AT_FDCWD, "/sys", libc::O_PATH | libc::O_DIRECTORY, 0)
+            .context(error::RawOsError {
+                operation: "open /sys",
+            })?;
+        Self::from_file(dir)
+    }
+
+    /// Verify that `dir` is the root of a sysfs mount (such as a container's
+    /// `/sys`, opened through its [`Root`]) and wrap it in a [`SysfsHandle`].
+    ///
+    /// # Errors
+    ///
+    /// If `dir` is not the root of a sysfs mount, an
+    /// [`Error::SafetyViolation`] is returned.
+    ///
+    /// [`Root`]: ../struct.Root.html
+    /// [`SysfsHandle`]: struct.SysfsHandle.html
+    /// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+    pub fn from_file(dir: File) -> Result<Self, Error> {
+        check_sysfs(&dir)?;
+        let ino = dir
+            .metadata()
+            .context(error::OsError {
+                operation: "fstat sysfs root",
+            })?
+            .ino();
+        ensure!(
+            ino == SYSFS_ROOT_INO,
+            error::SafetyViolation {
+                description: format!(
+                    "sysfs handle is not the root of a sysfs mount (ino is {}, not {})",
+                    ino, SYSFS_ROOT_INO
+                ),
+            }
+        );
+        let mnt_id = stat::mount_id(dir.as_raw_fd())?;
+        Ok(Self {
+            root: Root::from_file_unchecked(dir),
+            mnt_id,
+        })
+    }
+
+    /// Check that `file` is on the same sysfs mount as the root.
+    fn verify(&self, file: &File) -> Result<(), Error> {
+        check_sysfs(file)?;
+        if let (Some(expected), Some(actual)) = (self.mnt_id, stat::mount_id(file.as_raw_fd())?) {
+            ensure!(
+                expected == actual,
+                error::SafetyViolation {
+                    description: format!(
+                        "sysfs file is on mount {} rather than the sysfs mount {}",
+                        actual, expected
+                    ),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Resolve `subpath` within the sysfs mount (as with [`Root::resolve`]),
+    /// returning an `O_PATH` [`Handle`].
+    ///
+    /// # Errors
+    ///
+    /// If the resolved inode is not on the same sysfs mount as the root of the
+    /// [`SysfsHandle`] (because something has been mounted on top of part of
+    /// sysfs), an [`Error::SafetyViolation`] is returned. Otherwise, the errors
+    /// are identical to [`Root::resolve`].
+    ///
+    /// [`Root::resolve`]: ../struct.Root.html#method.resolve
+    /// [`Handle`]: ../struct.Handle.html
+    /// [`SysfsHandle`]: struct.SysfsHandle.html
+    /// [`Error::SafetyViolation`]: ../error/enum.Error.html#variant.SafetyViolation
+    pub fn resolve<P: AsRef<Path>>(&self, subpath: P) -> Result<Handle, Error> {
+        let handle = self.root.resolve(subpath)?;
+        self.verify(&handle.inner)?;
+        Ok(handle)
+    }
+
+    /// Open `subpath` within the sysfs mount with `flags` (as with
+    /// [`Root::open_file`]).
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`SysfsHandle::resolve`].
+    ///
+    /// [`Root::open_file`]: ../struct.Root.html#method.open_file
+    /// [`SysfsHandle::resolve`]: struct.SysfsHandle.html#method.resolve
+    pub fn open<P: AsRef<Path>, F: Into<OpenFlags>>(
+        &self,
+        subpath: P,
+        flags: F,
+    ) -> Result<File, Error> {
+        let file = self.root.open_file(subpath, flags)?;
+        self.verify(&file)?;
+        Ok(file)
+    }
+
+    /// Read the contents of the sysfs attribute `subpath`, without the
+    /// trailing newline.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`SysfsHandle::resolve`].
+    ///
+    /// [`SysfsHandle::resolve`]: struct.SysfsHandle.html#method.resolve
+    pub fn read<P: AsRef<Path>>(&self, subpath: P) -> Result<String, Error> {
+        let mut value = String::new();
+        self.open(subpath, OpenFlags(libc::O_RDONLY))?
+            .read_to_string(&mut value)
+            .context(error::OsError {
+                operation: "read sysfs attribute",
+            })?;
+        value.truncate(value.trim_end_matches('\n').len());
+        Ok(value)
+    }
+
+    /// Write `value` to the sysfs attribute `subpath` with a single
+    /// `write(2)` (sysfs attributes are parsed one write at a time).
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`SysfsHandle::resolve`].
+    ///
+    /// [`SysfsHandle::resolve`]: struct.SysfsHandle.html#method.resolve
+    pub fn write<P: AsRef<Path>, V: AsRef<[u8]>>(&self, subpath: P, value: V) -> Result<(), Error> {
+        self.open(subpath, OpenFlags(libc::O_WRONLY))?
+            .write_all(value.as_ref())
+            .context(error::OsError {
+                operation: "write sysfs attribute",
+            })
+    }
+}
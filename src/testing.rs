@@ -0,0 +1,603 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Randomised equivalence testing of the [`Resolver`] backends.
+//!
+//! This module (only available with the `testing` feature) generates random
+//! directory trees containing symlinks, along with random paths to resolve in
+//! them, and checks that the in-kernel and emulated backends agree on the
+//! outcome of each resolution (and the inode they resolve to). All of the
+//! generators are deterministic for a given seed, so failures can be
+//! reproduced, and they are public so that you can extend the corpus (or
+//! drive them from a property-testing framework).
+//!
+//! This module also provides the checks used by the fuzz targets in `fuzz/`
+//! (see [`fuzz_path_split`], [`fuzz_lexical`] and [`FuzzSandbox`]).
+//!
+//! [`fuzz_path_split`]: fn.fuzz_path_split.html
+//! [`fuzz_lexical`]: fn.fuzz_lexical.html
+//! [`FuzzSandbox`]: struct.FuzzSandbox.html
+//! [`Resolver`]: ../struct.Resolver.html
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    path, root,
+    utils::{FileExt, RawFdExt},
+    InodeType, ResolveOutcome, Resolver, ResolverBackend, Root,
+};
+
+use std::{
+    ffi::OsStr,
+    fs::{self, Permissions},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+/// A small deterministic pseudo-random number generator (`xorshift64*`), used
+/// by all of the generators in this module.
+///
+/// This is **not** suitable for anything other than generating test cases.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a new generator from `seed`.
+    pub fn new(seed: u64) -> Self {
+        // Scramble the seed with a round of splitmix64, so that similar seeds
+        // give unrelated sequences. The state must never be zero.
+        let mut state = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        state ^= state >> 31;
+        Self(if state == 0 { 1 } else { state })
+    }
+
+    /// Get the next pseudo-random `u64`.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Get a pseudo-random number in `0..bound` (`bound` must be non-zero).
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    /// Return `true` with a probability of `percent`%.
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    /// Pick a pseudo-random element of `items` (which must be non-empty).
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Parameters for [`TreeSpec::generate`] and [`generate_path`].
+///
+/// [`TreeSpec::generate`]: struct.TreeSpec.html#method.generate
+/// [`generate_path`]: fn.generate_path.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// Number of entries to generate.
+    pub entries: usize,
+    /// Maximum depth of the generated entries.
+    pub max_depth: usize,
+    /// Percentage of entries which are symlinks.
+    pub symlink_percent: usize,
+    /// Maximum number of components in generated paths (and symlink
+    /// targets).
+    pub max_path_components: usize,
+    /// The names used for path components. A small set of names results in
+    /// more paths which actually exist.
+    pub names: Vec<String>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            entries: 32,
+            max_depth: 4,
+            symlink_percent: 40,
+            max_path_components: 6,
+            names: ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// A single entry in a [`TreeSpec`].
+///
+/// [`TreeSpec`]: struct.TreeSpec.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeEntry {
+    /// A directory at the given path.
+    Directory(PathBuf),
+    /// An empty regular file at the given path.
+    File(PathBuf),
+    /// A symlink at the given path, with the given target.
+    Symlink { path: PathBuf, target: PathBuf },
+}
+
+impl TreeEntry {
+    /// The path of the entry.
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Directory(path) | Self::File(path) => path,
+            Self::Symlink { path, .. } => path,
+        }
+    }
+}
+
+/// A description of a directory tree, which can be created inside a [`Root`]
+/// with [`TreeSpec::create_in`].
+///
+/// [`Root`]: ../struct.Root.html
+/// [`TreeSpec::create_in`]: struct.TreeSpec.html#method.create_in
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeSpec {
+    /// The entries of the tree, ordered so that parents come before their
+    /// children.
+    pub entries: Vec<TreeEntry>,
+}
+
+impl TreeSpec {
+    /// Generate a random tree, according to `config`.
+    pub fn generate(rng: &mut Rng, config: &TreeConfig) -> Self {
+        let mut dirs = vec![PathBuf::from("/")];
+        let mut entries = Vec::with_capacity(config.entries);
+        for _ in 0..config.entries {
+            let parent = rng.pick(&dirs).clone();
+            let path = parent.join(rng.pick(&config.names));
+            let depth = path.components().count() - 1;
+            if entries.iter().any(|entry: &TreeEntry| entry.path() == path) {
+                continue;
+            }
+            let entry = if rng.chance(config.symlink_percent) {
+                TreeEntry::Symlink {
+                    path,
+                    target: generate_path(rng, config),
+                }
+            } else if depth < config.max_depth && rng.chance(70) {
+                dirs.push(path.clone());
+                TreeEntry::Directory(path)
+            } else {
+                TreeEntry::File(path)
+            };
+            entries.push(entry);
+        }
+        Self { entries }
+    }
+
+    /// Create every entry of the tree inside `root`. The tree should be
+    /// created in an empty directory.
+    pub fn create_in(&self, root: &Root) -> Result<(), Error> {
+        let dir_perm = Permissions::from_mode(0o755);
+        let file_perm = Permissions::from_mode(0o644);
+        for entry in &self.entries {
+            let inode_type = match entry {
+                TreeEntry::Directory(_) => InodeType::Directory(&dir_perm),
+                TreeEntry::File(_) => InodeType::File(&file_perm),
+                TreeEntry::Symlink { target, .. } => InodeType::Symlink(target),
+            };
+            root.create(entry.path(), &inode_type)
+                .wrap_with(|| format!("create test tree entry {:?}", entry.path()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate a random path (which may be absolute or relative, and may contain
+/// `.` and `..` components), using the component names in `config`.
+pub fn generate_path(rng: &mut Rng, config: &TreeConfig) -> PathBuf {
+    let mut path = PathBuf::from(if rng.chance(50) { "/" } else { "" });
+    let components = 1 + rng.below(config.max_path_components);
+    for _ in 0..components {
+        match rng.below(10) {
+            0 => path.push("."),
+            1 | 2 => path.push(".."),
+            3 => path.push("nonexistent"),
+            _ => path.push(rng.pick(&config.names)),
+        }
+    }
+    path
+}
+
+/// A path which the [`Resolver`] backends resolved differently, as found by
+/// [`compare_resolvers`].
+///
+/// [`Resolver`]: ../struct.Resolver.html
+/// [`compare_resolvers`]: fn.compare_resolvers.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The path which was resolved.
+    pub path: PathBuf,
+    /// The outcome of resolution with [`ResolverBackend::Kernel`].
+    ///
+    /// [`ResolverBackend::Kernel`]: ../enum.ResolverBackend.html#variant.Kernel
+    pub kernel: ResolveOutcome,
+    /// The outcome of resolution with [`ResolverBackend::Emulated`].
+    ///
+    /// [`ResolverBackend::Emulated`]: ../enum.ResolverBackend.html#variant.Emulated
+    pub emulated: ResolveOutcome,
+}
+
+fn resolve_outcome(root: &Root, path: &Path) -> Result<ResolveOutcome, Error> {
+    Ok(match root.resolve(path) {
+        Ok(handle) => {
+            let (dev, ino) = handle.inner.inode_id()?;
+            ResolveOutcome::Resolved { dev, ino }
+        }
+        Err(err) => ResolveOutcome::Failed {
+            errno: err.raw_os_error().unwrap_or(0),
+        },
+    })
+}
+
+/// Resolve `path` inside `root` with both the in-kernel and emulated
+/// [`Resolver`] backends (using the resolver flags of `root`), returning a
+/// [`Divergence`] if they disagree.
+///
+/// # Errors
+///
+/// If the in-kernel backend is not supported, an [`Error::NotSupported`] is
+/// returned. Failing to resolve `path` is not an error.
+///
+/// [`Resolver`]: ../struct.Resolver.html
+/// [`Divergence`]: struct.Divergence.html
+/// [`Error::NotSupported`]: ../error/enum.Error.html#variant.NotSupported
+pub fn compare_resolvers<P: AsRef<Path>>(
+    root: &Root,
+    path: P,
+) -> Result<Option<Divergence>, Error> {
+    let path = path.as_ref();
+    ensure!(
+        ResolverBackend::Kernel.supported(),
+        error::NotSupported { feature: "openat2" }
+    );
+
+    let mut root = root.try_clone()?;
+    root.resolver = Resolver {
+        backend: ResolverBackend::Kernel,
+        ..root.resolver
+    };
+    let kernel = resolve_outcome(&root, path)?;
+    root.resolver.backend = ResolverBackend::Emulated;
+    let emulated = resolve_outcome(&root, path)?;
+
+    Ok(if kernel == emulated {
+        None
+    } else {
+        Some(Divergence {
+            path: path.to_path_buf(),
+            kernel,
+            emulated,
+        })
+    })
+}
+
+/// Generate a random tree (with `seed`) inside `root` (which should be an
+/// empty directory) and then compare the [`Resolver`] backends on `paths`
+/// random paths, returning every [`Divergence`] found.
+///
+/// # Errors
+///
+/// The errors are identical to [`TreeSpec::create_in`] and
+/// [`compare_resolvers`].
+///
+/// [`Resolver`]: ../struct.Resolver.html
+/// [`Divergence`]: struct.Divergence.html
+/// [`TreeSpec::create_in`]: struct.TreeSpec.html#method.create_in
+/// [`compare_resolvers`]: fn.compare_resolvers.html
+pub fn check_resolver_equivalence(
+    root: &Root,
+    seed: u64,
+    config: &TreeConfig,
+    paths: usize,
+) -> Result<Vec<Divergence>, Error> {
+    let mut rng = Rng::new(seed);
+    TreeSpec::generate(&mut rng, config).create_in(root)?;
+
+    let mut divergences = Vec::new();
+    for _ in 0..paths {
+        let path = generate_path(&mut rng, config);
+        if let Some(divergence) = compare_resolvers(root, &path)? {
+            divergences.push(divergence);
+        }
+    }
+    Ok(divergences)
+}
+
+/// Check the invariants of the path splitting used by [`Root`] methods which
+/// operate on the trailing component of a path, on an arbitrary
+/// (fuzzer-provided) path. Panics if an invariant is violated.
+///
+/// [`Root`]: ../struct.Root.html
+pub fn fuzz_path_split(data: &[u8]) {
+    let path = Path::new(OsStr::from_bytes(data));
+    if let Ok((parent, name)) = root::path_split(path) {
+        let name = name.as_os_str().as_bytes();
+        assert!(!name.is_empty(), "trailing component is empty");
+        assert!(!name.contains(&b'/'), "trailing component contains '/'");
+        assert!(
+            name != b"." && name != b"..",
+            "trailing component is a dot entry"
+        );
+        // Paths are relative to the root, so single-component relative paths
+        // have "/" as their parent.
+        let root = Path::new("/");
+        assert_eq!(
+            root.join(parent).join(OsStr::from_bytes(name)),
+            root.join(path),
+            "split is lossy"
+        );
+    }
+}
+
+/// Check the invariants of the [`path`] helpers on an arbitrary
+/// (fuzzer-provided) path. Panics if an invariant is violated.
+///
+/// [`path`]: ../path/index.html
+pub fn fuzz_lexical(data: &[u8]) {
+    let unsafe_path = Path::new(OsStr::from_bytes(data));
+
+    let normalized = path::normalize_lexical(unsafe_path);
+    assert_eq!(
+        path::normalize_lexical(&normalized),
+        normalized,
+        "normalize_lexical is not idempotent"
+    );
+    let components = normalized.components().collect::<Vec<_>>();
+    assert!(
+        normalized == Path::new(".") || !components.contains(&Component::CurDir),
+        "normalized path contains '.'"
+    );
+    if normalized.is_absolute() {
+        assert!(
+            !components.contains(&Component::ParentDir),
+            "normalized absolute path contains '..'"
+        );
+    }
+
+    let root = Path::new("/sentinel/root");
+    let joined = path::securejoin(root, unsafe_path);
+    assert!(joined.starts_with(root), "securejoin escaped the root");
+    assert!(
+        !joined.components().any(|c| c == Component::ParentDir),
+        "securejoin result contains '..'"
+    );
+}
+
+/// A directory tree for fuzzing the emulated [`Resolver`] backend, created in
+/// a `root` subdirectory next to a `sentinel` file which resolutions must never
+/// reach.
+///
+/// The tree is generated (as with [`TreeSpec::generate`]) from a seed, and
+/// additionally contains symlinks which try to reach the sentinel (with both
+/// relative and absolute host paths).
+///
+/// [`Resolver`]: ../struct.Resolver.html
+/// [`TreeSpec::generate`]: struct.TreeSpec.html#method.generate
+#[derive(Debug)]
+pub struct FuzzSandbox {
+    root_path: PathBuf,
+    root: Root,
+    sentinel: (u64, u64),
+}
+
+impl FuzzSandbox {
+    /// Create a new sandbox inside `base` (which is created if necessary, and
+    /// any existing sandbox in it is removed).
+    pub fn new<P: AsRef<Path>>(base: P, seed: u64) -> Result<Self, Error> {
+        let base = base.as_ref();
+        let root_path = base.join("root");
+        let sentinel_path = base.join("sentinel");
+        let _ = fs::remove_dir_all(&root_path);
+        fs::create_dir_all(&root_path).context(error::OsError {
+            operation: "create fuzz sandbox",
+        })?;
+        fs::write(&sentinel_path, b"sentinel").context(error::OsError {
+            operation: "create fuzz sentinel",
+        })?;
+        let root_path = fs::canonicalize(&root_path).context(error::OsError {
+            operation: "canonicalise fuzz sandbox",
+        })?;
+        let sentinel_path = fs::canonicalize(&sentinel_path).context(error::OsError {
+            operation: "canonicalise fuzz sentinel",
+        })?;
+        let meta = fs::metadata(&sentinel_path).context(error::OsError {
+            operation: "stat fuzz sentinel",
+        })?;
+
+        let mut root = Root::open(&root_path)?;
+        root.resolver = Resolver {
+            backend: ResolverBackend::Emulated,
+            ..root.resolver
+        };
+        let mut spec = TreeSpec::generate(&mut Rng::new(seed), &TreeConfig::default());
+        let escapes = [
+            PathBuf::from("../sentinel"),
+            PathBuf::from("../../../../../../sentinel"),
+            sentinel_path,
+        ];
+        for (idx, target) in escapes.iter().enumerate() {
+            spec.entries.push(TreeEntry::Symlink {
+                path: PathBuf::from(format!("/escape{}", idx)),
+                target: target.clone(),
+            });
+        }
+        spec.create_in(&root)?;
+
+        Ok(Self {
+            root_path,
+            root,
+            sentinel: (meta.dev(), meta.ino()),
+        })
+    }
+
+    /// The [`Root`] of the sandbox (which uses the emulated backend).
+    ///
+    /// [`Root`]: ../struct.Root.html
+    #[inline]
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+
+    /// Resolve an arbitrary (fuzzer-provided) path inside the sandbox, and
+    /// panic if it resolved to something outside the sandbox.
+    pub fn check_resolve(&self, data: &[u8]) {
+        if data.contains(&b'\0') {
+            return;
+        }
+        let handle = match self.root.resolve(OsStr::from_bytes(data)) {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let id = handle
+            .inner
+            .inode_id()
+            .expect("fstat of resolved handle failed");
+        assert_ne!(id, self.sentinel, "resolution reached the sentinel");
+        let path = handle
+            .inner
+            .as_unsafe_path()
+            .expect("readlink of resolved handle failed");
+        assert!(
+            path.starts_with(&self.root_path),
+            "resolution escaped the root: {:?}",
+            path
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::TempDir;
+
+    /// Generate an arbitrary byte string, biased towards the bytes which are
+    /// significant to path handling.
+    fn generate_bytes(rng: &mut Rng) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"//..ab\xff\0";
+        (0..rng.below(24))
+            .map(|_| {
+                if rng.chance(10) {
+                    rng.next_u64() as u8
+                } else {
+                    *rng.pick(ALPHABET)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generators_are_deterministic() {
+        let config = TreeConfig::default();
+        let (mut rng1, mut rng2) = (Rng::new(1234), Rng::new(1234));
+        assert_eq!(
+            TreeSpec::generate(&mut rng1, &config),
+            TreeSpec::generate(&mut rng2, &config)
+        );
+        for _ in 0..64 {
+            assert_eq!(
+                generate_path(&mut rng1, &config),
+                generate_path(&mut rng2, &config)
+            );
+        }
+    }
+
+    #[test]
+    fn generated_trees_are_well_formed() {
+        let config = TreeConfig::default();
+        for seed in 0..64 {
+            let spec = TreeSpec::generate(&mut Rng::new(seed), &config);
+            for (idx, entry) in spec.entries.iter().enumerate() {
+                let path = entry.path();
+                assert!(path.components().count() - 1 <= config.max_depth + 1);
+                // Parents come before their children, and are directories.
+                let parent = path.parent().unwrap();
+                assert!(
+                    parent == Path::new("/")
+                        || spec.entries[..idx].contains(&TreeEntry::Directory(parent.into())),
+                    "seed {}: {:?} created before its parent",
+                    seed,
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn resolver_equivalence() {
+        if !ResolverBackend::Kernel.supported() {
+            return;
+        }
+        let config = TreeConfig::default();
+        for seed in 0..32 {
+            let dir = TempDir::new();
+            let root = Root::open(dir.path()).expect("open test root");
+            let divergences =
+                check_resolver_equivalence(&root, seed, &config, 128).expect("compare resolvers");
+            assert!(
+                divergences.is_empty(),
+                "seed {}: resolvers diverged: {:#?}",
+                seed,
+                divergences
+            );
+        }
+    }
+
+    #[test]
+    fn lexical_invariants() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..4096 {
+            let data = generate_bytes(&mut rng);
+            fuzz_path_split(&data);
+            fuzz_lexical(&data);
+        }
+    }
+
+    #[test]
+    fn sandbox_contains_resolution() {
+        let config = TreeConfig::default();
+        for seed in 0..8 {
+            let dir = TempDir::new();
+            let sandbox = FuzzSandbox::new(dir.path(), seed).expect("create fuzz sandbox");
+            for idx in 0..3 {
+                sandbox.check_resolve(format!("/escape{}", idx).as_bytes());
+                sandbox.check_resolve(format!("a/../escape{}/..", idx).as_bytes());
+            }
+            let mut rng = Rng::new(seed);
+            for _ in 0..128 {
+                let path = generate_path(&mut rng, &config);
+                sandbox.check_resolve(path.as_os_str().as_bytes());
+                sandbox.check_resolve(&generate_bytes(&mut rng));
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Helpers shared by the unit tests.
+
+use crate::{ResolverBackend, Root};
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The system allocator, counting the allocations made by each thread (see
+/// [`count_allocations`]).
+///
+/// [`count_allocations`]: fn.count_allocations.html
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+// SAFETY: All of the actual work is done by System.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter may already be gone while a thread is exiting.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Run `func`, returning its result and the number of heap allocations (and
+/// reallocations) it made on the current thread.
+pub(crate) fn count_allocations<T, F: FnOnce() -> T>(func: F) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let ret = func();
+    (ret, ALLOCATIONS.with(Cell::get) - before)
+}
+
+/// A scratch directory which is removed (with everything inside it) when
+/// dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "pathrs-test.{}.{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir(&path).expect("create test directory");
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The resolver backends supported by the running kernel.
+pub(crate) fn backends() -> Vec<ResolverBackend> {
+    [ResolverBackend::Kernel, ResolverBackend::Emulated]
+        .iter()
+        .copied()
+        .filter(|backend| backend.supported())
+        .collect()
+}
+
+/// Open `path` as a [`Root`] which uses `backend`.
+///
+/// [`Root`]: ../struct.Root.html
+pub(crate) fn root_with_backend<P: AsRef<Path>>(path: P, backend: ResolverBackend) -> Root {
+    let mut root = Root::open(path).expect("open test root");
+    root.resolver.backend = backend;
+    root
+}
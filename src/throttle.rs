@@ -0,0 +1,169 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use std::{
+    cmp,
+    io::{self, Read},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Throttling applied to recursive operations (such as [`Root::walk`] and the
+/// operations built on top of it), so that background maintenance of a large
+/// tree doesn't starve foreground I/O on the same host.
+///
+/// A [`Root`]'s `throttle` applies to every recursive operation through that
+/// [`Root`], and is shared by all threads of a parallel operation. The default
+/// is to not throttle at all.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::walk`]: struct.Root.html#method.walk
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Throttle {
+    /// Maximum number of entries visited per second.
+    pub max_entries_per_sec: Option<u64>,
+    /// Maximum number of bytes of file contents read (or written) per second.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Sleep for `batch_sleep` after every `batch_size` entries (a batch size
+    /// of `0` disables batching).
+    pub batch_size: u64,
+    /// See `batch_size`.
+    pub batch_sleep: Duration,
+}
+
+impl Throttle {
+    /// Does this [`Throttle`] not restrict anything?
+    ///
+    /// [`Throttle`]: struct.Throttle.html
+    pub fn is_unlimited(&self) -> bool {
+        self.max_entries_per_sec.is_none()
+            && self.max_bytes_per_sec.is_none()
+            && (self.batch_size == 0 || self.batch_sleep == Duration::from_secs(0))
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    start: Instant,
+    /// Total time spent in batch sleeps, which doesn't count towards the rate
+    /// limits (otherwise the rate limits would allow for a burst after every
+    /// batch).
+    paused: Duration,
+    entries: u64,
+    bytes: u64,
+}
+
+/// The shared state of a [`Throttle`] during an operation.
+///
+/// [`Throttle`]: struct.Throttle.html
+#[derive(Debug)]
+pub(crate) struct Throttler {
+    throttle: Throttle,
+    state: Mutex<ThrottleState>,
+}
+
+/// How long after the start of the operation `done` units of work are allowed
+/// to have been completed at `rate` units per second.
+fn rate_deadline(done: u64, rate: Option<u64>) -> Duration {
+    match rate {
+        Some(rate) => Duration::from_secs_f64(done as f64 / cmp::max(rate, 1) as f64),
+        None => Duration::from_secs(0),
+    }
+}
+
+impl Throttler {
+    /// Create a [`Throttler`] for `throttle`, or `None` if it doesn't restrict
+    /// anything.
+    ///
+    /// [`Throttler`]: struct.Throttler.html
+    pub(crate) fn new(throttle: Throttle) -> Option<Arc<Self>> {
+        if throttle.is_unlimited() {
+            return None;
+        }
+        Some(Arc::new(Self {
+            throttle,
+            state: Mutex::new(ThrottleState {
+                start: Instant::now(),
+                paused: Duration::from_secs(0),
+                entries: 0,
+                bytes: 0,
+            }),
+        }))
+    }
+
+    /// Account for `entries` visited entries and `bytes` transferred bytes,
+    /// sleeping if the operation is going faster than the limits allow.
+    pub(crate) fn account(&self, entries: u64, bytes: u64) {
+        let throttle = &self.throttle;
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            let batches = |entries| match throttle.batch_size {
+                0 => 0,
+                size => entries / size,
+            };
+            let batch_done = batches(state.entries) != batches(state.entries + entries);
+            state.entries += entries;
+            state.bytes += bytes;
+
+            let deadline = state.paused
+                + cmp::max(
+                    rate_deadline(state.entries, throttle.max_entries_per_sec),
+                    rate_deadline(state.bytes, throttle.max_bytes_per_sec),
+                );
+            let mut delay = deadline
+                .checked_sub(state.start.elapsed())
+                .unwrap_or_default();
+            if batch_done {
+                state.paused += throttle.batch_sleep;
+                delay += throttle.batch_sleep;
+            }
+            delay
+        };
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// A [`Read`] which accounts for the bytes read with a [`Throttler`].
+///
+/// [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
+/// [`Throttler`]: struct.Throttler.html
+pub(crate) struct ThrottledReader<'a, R> {
+    inner: R,
+    throttler: Option<&'a Throttler>,
+}
+
+impl<'a, R: Read> ThrottledReader<'a, R> {
+    pub(crate) fn new(inner: R, throttler: Option<&'a Throttler>) -> Self {
+        Self { inner, throttler }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(throttler) = self.throttler {
+            throttler.account(0, n as u64);
+        }
+        Ok(n)
+    }
+}
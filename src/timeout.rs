@@ -0,0 +1,288 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error, ErrorExt},
+    Handle, OpenFlags, Root, Stat,
+};
+
+use std::{
+    fs::File,
+    panic,
+    path::Path,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use snafu::ResultExt;
+
+/// Maximum number of worker threads which may still be running after their
+/// caller timed out. Once this many are stuck, new operations fail straight
+/// away rather than spawning yet another thread which will likely get stuck
+/// as well.
+const MAX_ABANDONED_WORKERS: usize = 64;
+
+/// Number of worker threads which are still running after their caller timed
+/// out.
+static ABANDONED_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+// States of a worker thread, shared with its caller.
+const WORKER_RUNNING: u8 = 0;
+const WORKER_DONE: u8 = 1;
+const WORKER_ABANDONED: u8 = 2;
+
+/// Run `func` in a worker thread, waiting at most `timeout` for it to finish.
+/// If `func` panics, the panic is propagated to the caller. If too many
+/// earlier workers are still stuck (see [`MAX_ABANDONED_WORKERS`]), this fails
+/// with `Error::Timeout` without running `func`.
+///
+/// [`MAX_ABANDONED_WORKERS`]: constant.MAX_ABANDONED_WORKERS.html
+fn run_with_timeout<T, F>(operation: &str, timeout: Duration, func: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    if ABANDONED_WORKERS.load(Ordering::SeqCst) >= MAX_ABANDONED_WORKERS {
+        return error::Timeout { operation, timeout }.fail();
+    }
+
+    let (tx, rx) = mpsc::sync_channel(1);
+    let state = Arc::new(AtomicU8::new(WORKER_RUNNING));
+    let worker_state = Arc::clone(&state);
+    let worker = thread::Builder::new()
+        .name("pathrs-timeout".into())
+        .spawn(move || {
+            let ret = func();
+            if worker_state.swap(WORKER_DONE, Ordering::SeqCst) == WORKER_ABANDONED {
+                ABANDONED_WORKERS.fetch_sub(1, Ordering::SeqCst);
+            }
+            // If the receiver has given up on us, the result (and any file
+            // descriptors it contains) is simply dropped.
+            let _ = tx.send(ret);
+        })
+        .context(error::OsError {
+            operation: "spawn worker thread",
+        })?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(ret) => ret,
+        Err(RecvTimeoutError::Timeout) => {
+            // Count the worker before marking it as abandoned, so that it
+            // can never be uncounted before it was counted.
+            ABANDONED_WORKERS.fetch_add(1, Ordering::SeqCst);
+            if state
+                .compare_exchange(
+                    WORKER_RUNNING,
+                    WORKER_ABANDONED,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_err()
+            {
+                // The worker finished (or panicked) in the meantime.
+                ABANDONED_WORKERS.fetch_sub(1, Ordering::SeqCst);
+            }
+            error::Timeout { operation, timeout }.fail()
+        }
+        // The worker only drops the sender without sending anything if func
+        // panicked.
+        Err(RecvTimeoutError::Disconnected) => match worker.join() {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(()) => unreachable!("worker thread for {} exited without a result", operation),
+        },
+    }
+}
+
+impl Root {
+    /// Run `func` with a copy of this [`Root`] in a worker thread, giving up
+    /// if it has not finished after `timeout`.
+    ///
+    /// This is intended for services which operate on filesystems that may
+    /// hang indefinitely (such as a dead NFS server or a wedged FUSE daemon),
+    /// and would rather shed load than have their own threads get stuck. Note
+    /// that a hung syscall cannot be cancelled -- if the timeout is exceeded,
+    /// the worker thread is left running in the background (and will exit
+    /// whenever the syscall returns). Any result it produces after the timeout
+    /// is discarded.
+    ///
+    /// To stop repeated timeouts from leaking threads without bound, at most
+    /// 64 timed-out worker threads may be left running (across all operations
+    /// with a timeout). Once that many are stuck, operations with a timeout
+    /// fail immediately (without running `func`) until some of the stuck
+    /// threads have exited.
+    ///
+    /// # Errors
+    ///
+    /// If `func` does not finish within `timeout`, or too many earlier
+    /// timed-out operations are still running, an [`Error::Timeout`] is
+    /// returned. Otherwise, the result of `func` is returned.
+    ///
+    /// # Panics
+    ///
+    /// If `func` panics (before the timeout), the panic is propagated to the
+    /// caller.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Error::Timeout`]: error/enum.Error.html#variant.Timeout
+    pub fn with_timeout<T, F>(&self, timeout: Duration, func: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Root) -> Result<T, Error> + Send + 'static,
+    {
+        let root = self.try_clone().wrap("clone root for worker thread")?;
+        run_with_timeout("root operation", timeout, move || func(&root))
+    }
+
+    /// [`Root::resolve`] with a timeout. See [`Root::with_timeout`] for more
+    /// details.
+    ///
+    /// [`Root::resolve`]: struct.Root.html#method.resolve
+    /// [`Root::with_timeout`]: struct.Root.html#method.with_timeout
+    pub fn resolve_timeout<P: AsRef<Path>>(
+        &self,
+        path: P,
+        timeout: Duration,
+    ) -> Result<Handle, Error> {
+        let path = path.as_ref().to_path_buf();
+        self.with_timeout(timeout, move |root| root.resolve(path))
+            .wrap("resolve path with timeout")
+    }
+
+    /// [`Root::lstat_nofollow`] with a timeout. See [`Root::with_timeout`] for
+    /// more details.
+    ///
+    /// [`Root::lstat_nofollow`]: struct.Root.html#method.lstat_nofollow
+    /// [`Root::with_timeout`]: struct.Root.html#method.with_timeout
+    pub fn lstat_nofollow_timeout<P: AsRef<Path>>(
+        &self,
+        path: P,
+        timeout: Duration,
+    ) -> Result<Stat, Error> {
+        let path = path.as_ref().to_path_buf();
+        self.with_timeout(timeout, move |root| root.lstat_nofollow(path))
+            .wrap("lstat path with timeout")
+    }
+}
+
+impl Handle {
+    /// [`Handle::reopen`] with a timeout. See [`Root::with_timeout`] for more
+    /// details.
+    ///
+    /// [`Handle::reopen`]: struct.Handle.html#method.reopen
+    /// [`Root::with_timeout`]: struct.Root.html#method.with_timeout
+    pub fn reopen_timeout<F: Into<OpenFlags>>(
+        &self,
+        flags: F,
+        timeout: Duration,
+    ) -> Result<File, Error> {
+        let handle = self.try_clone().wrap("clone handle for worker thread")?;
+        let flags = flags.into();
+        run_with_timeout("reopen handle", timeout, move || handle.reopen(flags))
+            .wrap("reopen handle with timeout")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_with_timeout, ABANDONED_WORKERS, MAX_ABANDONED_WORKERS};
+    use crate::error::Error;
+
+    use std::{
+        panic,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// Serialises the tests which depend on ABANDONED_WORKERS.
+    static ABANDONED_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn run_with_timeout_result() {
+        let _guard = ABANDONED_LOCK.lock().unwrap();
+        let ret = run_with_timeout("test", Duration::from_secs(10), || Ok(42));
+        assert_eq!(ret.unwrap(), 42);
+    }
+
+    #[test]
+    fn run_with_timeout_panic() {
+        let _guard = ABANDONED_LOCK.lock().unwrap();
+        let ret = panic::catch_unwind(|| {
+            run_with_timeout::<(), _>("test", Duration::from_secs(10), || panic!("worker panic"))
+        });
+        let payload = ret.expect_err("panic should be propagated");
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"worker panic"));
+    }
+
+    #[test]
+    fn run_with_timeout_abandoned_cap() {
+        let _guard = ABANDONED_LOCK.lock().unwrap();
+        let hang = Arc::new(Mutex::new(()));
+        let hung = hang.lock().unwrap();
+
+        for _ in 0..MAX_ABANDONED_WORKERS {
+            let hang = Arc::clone(&hang);
+            let err = run_with_timeout("hang", Duration::from_millis(1), move || {
+                drop(hang.lock());
+                Ok(())
+            })
+            .unwrap_err();
+            assert!(
+                matches!(err, Error::Timeout { .. }),
+                "unexpected error: {}",
+                err
+            );
+        }
+        assert_eq!(
+            ABANDONED_WORKERS.load(Ordering::SeqCst),
+            MAX_ABANDONED_WORKERS
+        );
+
+        // Once the cap is reached, nothing new is run.
+        let ran = Arc::new(AtomicBool::new(false));
+        let worker_ran = Arc::clone(&ran);
+        let err = run_with_timeout("test", Duration::from_secs(10), move || {
+            worker_ran.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(
+            matches!(err, Error::Timeout { .. }),
+            "unexpected error: {}",
+            err
+        );
+        assert!(!ran.load(Ordering::SeqCst));
+
+        // The stuck workers are uncounted as they exit.
+        drop(hung);
+        while ABANDONED_WORKERS.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        run_with_timeout("test", Duration::from_secs(10), || Ok(())).unwrap();
+    }
+}
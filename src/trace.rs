@@ -0,0 +1,174 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! Tracing of the syscalls performed by libpathrs, in the style of
+//! `strace(1)`.
+//!
+//! This is only available with the `syscall-trace` feature (without it, the
+//! syscall wrappers don't have any tracing overhead). Tracing is enabled for
+//! operations on a particular [`Root`] by running them inside
+//! [`Root::with_syscall_trace`], and each syscall is reported to a
+//! [`SyscallTracer`]. libpathrs doesn't depend on any logging framework, so to
+//! send events to `log` or `tracing` you need to implement [`SyscallTracer`]
+//! yourself -- [`StderrTracer`] is provided for quick debugging sessions.
+//!
+//! [`Root`]: ../struct.Root.html
+//! [`Root::with_syscall_trace`]: ../struct.Root.html#method.with_syscall_trace
+//! [`SyscallTracer`]: trait.SyscallTracer.html
+//! [`StderrTracer`]: struct.StderrTracer.html
+
+use crate::Root;
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    io::Error as IOError,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use libc::c_int;
+
+/// A single syscall performed by libpathrs.
+#[derive(Clone, Debug)]
+pub struct SyscallEvent<'a> {
+    /// Name of the syscall (such as `"openat2"`).
+    pub name: &'static str,
+    /// The arguments of the syscall, formatted as they would be by
+    /// `strace(1)` (with flags decoded into their symbolic form, and file
+    /// descriptors followed by the path they referred to).
+    pub args: &'a str,
+    /// The return value of the syscall, or the `errno` it failed with.
+    pub result: Result<i64, c_int>,
+    /// How long the syscall took (including any `EINTR` retries).
+    pub duration: Duration,
+}
+
+impl fmt::Display for SyscallEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({}) = ", self.name, self.args)?;
+        match self.result {
+            Ok(ret) => write!(f, "{}", ret)?,
+            Err(errno) => write!(f, "-1 ({})", IOError::from_raw_os_error(errno))?,
+        };
+        write!(f, " <{:.6}>", self.duration.as_secs_f64())
+    }
+}
+
+/// A receiver of [`SyscallEvent`]s, see [`Root::with_syscall_trace`].
+///
+/// Syscalls performed by the tracer itself (including through libpathrs) are
+/// not traced.
+///
+/// [`SyscallEvent`]: struct.SyscallEvent.html
+/// [`Root::with_syscall_trace`]: ../struct.Root.html#method.with_syscall_trace
+pub trait SyscallTracer: fmt::Debug + Send + Sync {
+    /// Record a syscall which was just performed.
+    fn trace(&self, event: &SyscallEvent<'_>);
+}
+
+/// A [`SyscallTracer`] which writes each event to stderr, in the format used
+/// by `strace(1)`.
+///
+/// [`SyscallTracer`]: trait.SyscallTracer.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrTracer;
+
+impl SyscallTracer for StderrTracer {
+    fn trace(&self, event: &SyscallEvent<'_>) {
+        eprintln!("pathrs: {}", event);
+    }
+}
+
+thread_local! {
+    /// The tracer for the current thread.
+    static TRACER: RefCell<Option<Arc<dyn SyscallTracer>>> = const { RefCell::new(None) };
+    /// Whether we are currently inside the tracer (or formatting the
+    /// arguments for it), in which case syscalls are not traced.
+    static IN_TRACER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the previous tracer of the thread when dropped.
+struct TracerGuard(Option<Arc<dyn SyscallTracer>>);
+
+impl Drop for TracerGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        TRACER.with(|tracer| *tracer.borrow_mut() = previous);
+    }
+}
+
+/// Start timing a syscall, if it should be traced.
+pub(crate) fn start() -> Option<Instant> {
+    if IN_TRACER.with(Cell::get) || TRACER.with(|tracer| tracer.borrow().is_none()) {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
+/// Report a syscall (started with [`start`]) to the current tracer.
+///
+/// [`start`]: fn.start.html
+pub(crate) fn finish<A: FnOnce() -> String>(
+    start: Instant,
+    name: &'static str,
+    args: A,
+    result: Result<i64, c_int>,
+) {
+    let duration = start.elapsed();
+    let tracer = match TRACER.with(|tracer| tracer.borrow().clone()) {
+        Some(tracer) => tracer,
+        None => return,
+    };
+    IN_TRACER.with(|in_tracer| in_tracer.set(true));
+    let args = args();
+    tracer.trace(&SyscallEvent {
+        name,
+        args: &args,
+        result,
+        duration,
+    });
+    IN_TRACER.with(|in_tracer| in_tracer.set(false));
+}
+
+impl Root {
+    /// Run `f` with every syscall performed by libpathrs on the current thread
+    /// reported to `tracer`. This is intended for debugging failures on
+    /// unusual filesystems or kernels, where the exact sequence of syscalls
+    /// (and their results) is needed to figure out what went wrong.
+    ///
+    /// Tracing is scoped to the current thread, so operations on other
+    /// [`Root`]s within `f` are also traced, while operations which run on
+    /// other threads (such as the worker thread of [`Root::with_timeout`])
+    /// are not. Calls can be nested, with the innermost `tracer` taking
+    /// precedence.
+    ///
+    /// [`Root`]: ../struct.Root.html
+    /// [`Root::with_timeout`]: ../struct.Root.html#method.with_timeout
+    pub fn with_syscall_trace<T, F>(&self, tracer: Arc<dyn SyscallTracer>, f: F) -> T
+    where
+        F: FnOnce(&Root) -> T,
+    {
+        let previous = TRACER.with(|current| current.borrow_mut().replace(tracer));
+        let _guard = TracerGuard(previous);
+        f(self)
+    }
+}
@@ -0,0 +1,474 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    audit::check_same,
+    error::{self, Error, ErrorExt},
+    root::path_split,
+    syscalls, ActedOn, Handle, InodeType, OpenFlags, RenameFlags, Root,
+};
+
+use std::{
+    ffi::OsString,
+    fs::Permissions,
+    io::Write,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use snafu::ResultExt;
+
+/// Number of temporary names to try before giving up in
+/// [`Transaction::write_atomic`].
+const TEMP_NAME_RETRIES: usize = 16;
+
+/// Counter used to generate unique temporary names within this process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How to undo a single operation recorded in a [`Transaction`].
+#[derive(Debug)]
+enum UndoOp {
+    /// The inode `id` was created at `path` by the transaction. It is only
+    /// removed if it is still the inode at `path`.
+    Remove { path: PathBuf, id: ActedOn },
+    /// An inode was moved from `to` to `from`, and needs to be moved back.
+    Rename { from: PathBuf, to: PathBuf },
+    /// The inode at `path` was replaced, and the original inode was moved to
+    /// `backup`. The backup is removed on commit.
+    Restore { path: PathBuf, backup: PathBuf },
+}
+
+/// A sequence of mutating operations inside a [`Root`], created with
+/// [`Root::transaction`].
+///
+/// Each operation is applied immediately, and enough information is recorded
+/// to undo it. Once all operations have succeeded, call
+/// [`Transaction::commit`] -- otherwise call [`Transaction::rollback`] to undo
+/// every operation (in reverse order). If a [`Transaction`] is dropped without
+/// being committed it is rolled back, so bailing out of a sequence of
+/// operations with `?` is enough to undo the operations which did succeed.
+///
+/// Note that this is not a transaction in the database sense -- other
+/// processes can see (and interfere with) the intermediate states, and a
+/// crash will leave the tree half-modified (possibly with stray temporary
+/// files from [`Transaction::write_atomic`]). Rollback is best-effort: if the
+/// tree was modified concurrently, it may not be possible to restore it.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::transaction`]: struct.Root.html#method.transaction
+/// [`Transaction`]: struct.Transaction.html
+/// [`Transaction::commit`]: struct.Transaction.html#method.commit
+/// [`Transaction::rollback`]: struct.Transaction.html#method.rollback
+/// [`Transaction::write_atomic`]: struct.Transaction.html#method.write_atomic
+#[derive(Debug)]
+pub struct Transaction<'r> {
+    root: &'r Root,
+    undo: Vec<UndoOp>,
+    finished: bool,
+}
+
+impl<'r> Transaction<'r> {
+    /// The [`Root`] which this transaction operates on.
+    ///
+    /// [`Root`]: struct.Root.html
+    #[inline]
+    pub fn root(&self) -> &'r Root {
+        self.root
+    }
+
+    /// Identical to [`Root::create`], except that the new inode is removed on
+    /// rollback.
+    ///
+    /// [`Root::create`]: struct.Root.html#method.create
+    pub fn create<P: AsRef<Path>>(&mut self, path: P, inode_type: &InodeType) -> Result<(), Error> {
+        let path = path.as_ref();
+        let id = self.root.create_audited(path, inode_type)?;
+        self.undo.push(UndoOp::Remove {
+            path: path.to_path_buf(),
+            id,
+        });
+        Ok(())
+    }
+
+    /// Identical to [`Root::create_file`], except that the new file is removed
+    /// on rollback.
+    ///
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    pub fn create_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        perm: &Permissions,
+    ) -> Result<Handle, Error> {
+        let path = path.as_ref();
+        let handle = self.root.create_file(path, perm)?;
+        let id = handle.acted_on().wrap("get identity of created file")?;
+        self.undo.push(UndoOp::Remove {
+            path: path.to_path_buf(),
+            id,
+        });
+        Ok(handle)
+    }
+
+    /// Atomically replace the contents of the file at `path` with `contents`,
+    /// creating it (with the permissions `perm`) if it doesn't exist.
+    ///
+    /// The contents are written to a temporary file in the same directory
+    /// (created with [`Root::create_file`]), synced, and then moved into place
+    /// with [`renameat2(2)`] using `RENAME_NOREPLACE`. If `path` already
+    /// exists it is instead swapped with the temporary file using
+    /// `RENAME_EXCHANGE`, so the original file is kept (under the temporary
+    /// name) until the transaction is committed. On rollback, the original
+    /// file is swapped back into place (or the new file is removed if `path`
+    /// didn't exist).
+    ///
+    /// # Errors
+    ///
+    /// `RENAME_NOREPLACE` (and `RENAME_EXCHANGE`, if `path` already exists)
+    /// must be supported by the running kernel (see
+    /// [`RenameFlags::supported`]), otherwise [`Error::NotSupported`] is
+    /// returned. If any step fails, the temporary file is removed and the
+    /// original file is left untouched.
+    ///
+    /// [`Root::create_file`]: struct.Root.html#method.create_file
+    /// [`RenameFlags::supported`]: struct.RenameFlags.html#method.supported
+    /// [`Error::NotSupported`]: error/enum.Error.html#variant.NotSupported
+    /// [`renameat2(2)`]: http://man7.org/linux/man-pages/man2/renameat2.2.html
+    pub fn write_atomic<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        contents: &[u8],
+        perm: &Permissions,
+    ) -> Result<(), Error> {
+        let noreplace = RenameFlags(libc::RENAME_NOREPLACE);
+        ensure!(
+            noreplace.supported(),
+            error::NotSupported {
+                feature: "renameat2(RENAME_NOREPLACE)",
+            }
+        );
+
+        let path = path.as_ref();
+        let (temp, handle) = self.create_temp(path, perm)?;
+        let id = match write_contents(&handle, contents).and_then(|_| handle.acted_on()) {
+            Ok(id) => id,
+            Err(err) => {
+                let _ = self.root.remove(&temp);
+                return Err(err);
+            }
+        };
+
+        let op = match self.root.rename(temp.as_path(), path, noreplace) {
+            Ok(_) => UndoOp::Remove {
+                path: path.to_path_buf(),
+                id,
+            },
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                let exchange = RenameFlags(libc::RENAME_EXCHANGE);
+                if !exchange.supported() {
+                    let _ = self.root.remove(&temp);
+                    return error::NotSupported {
+                        feature: "renameat2(RENAME_EXCHANGE)",
+                    }
+                    .fail();
+                }
+                if let Err(err) = self.root.rename(temp.as_path(), path, exchange) {
+                    let _ = self.root.remove(&temp);
+                    return Err(err).wrap("swap new file contents into place");
+                }
+                UndoOp::Restore {
+                    path: path.to_path_buf(),
+                    backup: temp,
+                }
+            }
+            Err(err) => {
+                let _ = self.root.remove(&temp);
+                return Err(err).wrap("move new file into place");
+            }
+        };
+        self.undo.push(op);
+        Ok(())
+    }
+
+    /// Identical to [`Root::rename`] with `RENAME_NOREPLACE`, except that the
+    /// inode is moved back to `source` on rollback.
+    ///
+    /// Only non-replacing renames are supported, since a replacing rename
+    /// destroys the original `destination` (which could then not be restored
+    /// on rollback).
+    ///
+    /// [`Root::rename`]: struct.Root.html#method.rename
+    pub fn rename<P: AsRef<Path>>(&mut self, source: P, destination: P) -> Result<(), Error> {
+        let (source, destination) = (source.as_ref(), destination.as_ref());
+        self.root
+            .rename(source, destination, RenameFlags(libc::RENAME_NOREPLACE))?;
+        self.undo.push(UndoOp::Rename {
+            from: destination.to_path_buf(),
+            to: source.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    /// Commit the transaction. The operations have already been applied, so
+    /// this only removes the original files kept around by
+    /// [`Transaction::write_atomic`].
+    ///
+    /// # Errors
+    ///
+    /// If a backup file cannot be removed, the remaining backups are still
+    /// removed and the first error is returned. The transaction counts as
+    /// committed regardless.
+    ///
+    /// [`Transaction::write_atomic`]: struct.Transaction.html#method.write_atomic
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.finished = true;
+        let mut result = Ok(());
+        for op in self.undo.drain(..) {
+            if let UndoOp::Restore { backup, .. } = op {
+                let ret = self.root.remove(&backup).wrap("remove transaction backup");
+                result = result.and(ret);
+            }
+        }
+        result
+    }
+
+    /// Roll back the transaction, undoing every operation in reverse order.
+    ///
+    /// # Errors
+    ///
+    /// Rollback is best-effort -- if an operation cannot be undone, the
+    /// remaining operations are still undone and the first error is returned.
+    pub fn rollback(mut self) -> Result<(), Error> {
+        self.undo_all()
+    }
+
+    fn undo_all(&mut self) -> Result<(), Error> {
+        self.finished = true;
+        let mut result = Ok(());
+        while let Some(op) = self.undo.pop() {
+            let ret = match op {
+                UndoOp::Remove { path, id } => self.remove_created(&path, &id),
+                UndoOp::Rename { from, to } => {
+                    let noreplace = RenameFlags(libc::RENAME_NOREPLACE);
+                    self.root.rename(from, to, noreplace)
+                }
+                UndoOp::Restore { path, backup } => {
+                    let exchange = RenameFlags(libc::RENAME_EXCHANGE);
+                    self.root
+                        .rename(backup.as_path(), path.as_path(), exchange)
+                        .and_then(|_| self.root.remove(&backup))
+                }
+            };
+            result = result.and(ret.wrap("undo transaction operation"));
+        }
+        result
+    }
+
+    /// Remove the inode at `path`, if it is still the inode `id` which the
+    /// transaction created there (rather than something which replaced it
+    /// after the operation).
+    ///
+    /// The inode is checked and unlinked relative to the same handle to its
+    /// parent directory, which is checked to still be the parent of `path`
+    /// inside the root after the inode was checked. There is no way to unlink
+    /// an inode by identity, so the inode can still be swapped for another one
+    /// between the check and the unlinkat(2).
+    fn remove_created(&self, path: &Path, id: &ActedOn) -> Result<(), Error> {
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let dir = self
+            .root
+            .resolve(parent)
+            .wrap("resolve parent directory of inode to remove")?;
+        let dir_id = dir.acted_on()?;
+        let dirfd = dir.inner.as_raw_fd();
+
+        let file = syscalls::openat(dirfd, name, libc::O_PATH, 0).context(error::RawOsError {
+            operation: "open inode to remove",
+        })?;
+        let file = Handle::from_file_unchecked(file);
+        check_same(
+            id,
+            &file.acted_on()?,
+            "inode to remove is not the inode created by the transaction",
+        )?;
+        let is_dir = file
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "get type of inode to remove",
+            })?
+            .is_dir();
+
+        let current_dir = self
+            .root
+            .resolve(parent)
+            .wrap("re-resolve parent directory of inode to remove")?;
+        check_same(
+            &dir_id,
+            &current_dir.acted_on()?,
+            "parent directory of inode to remove was moved",
+        )?;
+
+        let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+        syscalls::unlinkat(dirfd, name, flags).context(error::RawOsError {
+            operation: "remove inode created by transaction",
+        })?;
+        self.root.invalidate_caches(path);
+        Ok(())
+    }
+
+    /// Create an empty temporary file next to `path`.
+    fn create_temp(&self, path: &Path, perm: &Permissions) -> Result<(PathBuf, Handle), Error> {
+        let (parent, name) = path_split(path).wrap("split target path into (parent, name)")?;
+        let mut last_error = None;
+        for _ in 0..TEMP_NAME_RETRIES {
+            let mut temp_name = OsString::from(".");
+            temp_name.push(name);
+            temp_name.push(format!(
+                ".pathrs-txn-{}-{}",
+                process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let temp = parent.join(temp_name);
+            match self.root.create_file(&temp, perm) {
+                Ok(handle) => return Ok((temp, handle)),
+                Err(err) if err.raw_os_error() == Some(libc::EEXIST) => last_error = Some(err),
+                Err(err) => return Err(err).wrap("create temporary file"),
+            }
+        }
+        // If we ever are here, then last_error must be Some.
+        Err(last_error.expect("create_temp loop failed so last_error must exist"))
+            .wrap("create temporary file")
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.undo_all();
+        }
+    }
+}
+
+/// Write `contents` to the (empty) file referenced by `handle` and sync it.
+fn write_contents(handle: &Handle, contents: &[u8]) -> Result<(), Error> {
+    let mut file = handle
+        .reopen(OpenFlags(libc::O_WRONLY))
+        .wrap("reopen temporary file for writing")?;
+    file.write_all(contents).context(error::OsError {
+        operation: "write temporary file contents",
+    })?;
+    file.sync_all().context(error::OsError {
+        operation: "sync temporary file contents",
+    })
+}
+
+impl Root {
+    /// Start a new [`Transaction`] inside the [`Root`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Transaction`]: struct.Transaction.html
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            root: self,
+            undo: Vec::new(),
+            finished: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, tests::TempDir, InodeType, Root};
+
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+    };
+
+    #[test]
+    fn write_atomic_rollback() {
+        let tmpdir = TempDir::new();
+        fs::write(tmpdir.path().join("existing"), "old").unwrap();
+        let root = Root::open(tmpdir.path()).unwrap();
+        let perm = Permissions::from_mode(0o644);
+
+        let mut txn = root.transaction();
+        txn.write_atomic("existing", b"new", &perm).unwrap();
+        txn.write_atomic("created", b"new", &perm).unwrap();
+        assert_eq!(fs::read(tmpdir.path().join("existing")).unwrap(), b"new");
+        assert_eq!(fs::read(tmpdir.path().join("created")).unwrap(), b"new");
+        txn.rollback().unwrap();
+
+        assert_eq!(fs::read(tmpdir.path().join("existing")).unwrap(), b"old");
+        assert!(!tmpdir.path().join("created").exists());
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_atomic_commit() {
+        let tmpdir = TempDir::new();
+        fs::write(tmpdir.path().join("existing"), "old").unwrap();
+        let root = Root::open(tmpdir.path()).unwrap();
+
+        let mut txn = root.transaction();
+        txn.write_atomic("existing", b"new", &Permissions::from_mode(0o644))
+            .unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(fs::read(tmpdir.path().join("existing")).unwrap(), b"new");
+        assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rollback_keeps_replaced_inode() {
+        let tmpdir = TempDir::new();
+        let root = Root::open(tmpdir.path()).unwrap();
+
+        let mut txn = root.transaction();
+        txn.create("dir", &InodeType::Directory(&Permissions::from_mode(0o755)))
+            .unwrap();
+        txn.create_file("file", &Permissions::from_mode(0o644))
+            .unwrap();
+        // Swap out the created inodes behind the transaction's back. The
+        // replacements are created before the originals are gone, so they
+        // cannot reuse their inode numbers.
+        fs::create_dir(tmpdir.path().join("dir.new")).unwrap();
+        fs::rename(tmpdir.path().join("dir.new"), tmpdir.path().join("dir")).unwrap();
+        fs::write(tmpdir.path().join("file.new"), "replacement").unwrap();
+        fs::rename(tmpdir.path().join("file.new"), tmpdir.path().join("file")).unwrap();
+
+        let err = txn.rollback().unwrap_err();
+        assert!(
+            err.iter_chain_hotfix()
+                .filter_map(|err| err.downcast_ref::<Error>())
+                .any(|err| matches!(err, Error::SafetyViolation { .. })),
+            "unexpected rollback error: {}",
+            err
+        );
+        assert!(tmpdir.path().join("dir").is_dir());
+        assert_eq!(
+            fs::read(tmpdir.path().join("file")).unwrap(),
+            b"replacement"
+        );
+    }
+}
@@ -24,8 +24,11 @@ use crate::{
 };
 
 use std::{
-    ffi::{CString, OsStr},
+    ffi::{CString, OsStr, OsString},
+    fmt,
     fs::File,
+    io::{Error as IOError, Write},
+    os::raw::c_char,
     os::unix::{
         ffi::OsStrExt,
         fs::MetadataExt,
@@ -34,7 +37,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use snafu::ResultExt;
+use snafu::{IntoError, ResultExt};
 
 // This is part of Linux's ABI.
 const PROC_ROOT_INO: u64 = 1;
@@ -87,7 +90,7 @@ lazy_static! {
     // [1]: https://nvd.nist.gov/vuln/detail/CVE-2019-16884
     // [2]: https://nvd.nist.gov/vuln/detail/CVE-2019-19921
     // [3]: https://youtu.be/tGseJW_uBB8
-    static ref PROCFS_HANDLE: File = {
+    pub(crate) static ref PROCFS_HANDLE: File = {
         // Get a /proc handle for the lifetime of the process.
         let proc = syscalls::openat(
             libc::AT_FDCWD,
@@ -115,26 +118,84 @@ lazy_static! {
     };
 }
 
+/// Reject `value` (the `name` argument) with an [`Error::InvalidArgument`] if
+/// it contains a nul byte, since it could not be passed to the kernel.
+///
+/// [`Error::InvalidArgument`]: error/enum.Error.html#variant.InvalidArgument
+pub(crate) fn check_no_nul<S: AsRef<OsStr>>(name: &str, value: S) -> Result<(), Error> {
+    let value = value.as_ref();
+    ensure!(
+        !value.as_bytes().contains(&b'\0'),
+        error::InvalidArgument {
+            name,
+            description: format!("{:?} contains a nul byte", value),
+        }
+    );
+    Ok(())
+}
+
+/// Size of the inline buffer of [`SmallCString`], which is large enough for
+/// almost every path component (and most paths) passed to syscalls.
+///
+/// [`SmallCString`]: enum.SmallCString.html
+const SMALL_CSTRING_SIZE: usize = 256;
+
+/// A nul-terminated C string which is stored inline if it is short enough, so
+/// that converting the arguments of syscalls doesn't usually need a heap
+/// allocation.
+// The size difference is the point -- boxing the buffer would defeat it.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum SmallCString {
+    /// The string and its nul terminator (followed by more nuls).
+    Inline([u8; SMALL_CSTRING_SIZE]),
+    Heap(CString),
+}
+
+impl SmallCString {
+    fn new(bytes: &[u8]) -> Self {
+        if bytes.len() < SMALL_CSTRING_SIZE {
+            let mut buf = [0u8; SMALL_CSTRING_SIZE];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            Self::Inline(buf)
+        } else {
+            Self::Heap(CString::new(bytes).expect("nul bytes should've been rejected"))
+        }
+    }
+
+    /// Get a pointer to the C string, which is valid for as long as the
+    /// [`SmallCString`] is not moved or dropped.
+    ///
+    /// [`SmallCString`]: enum.SmallCString.html
+    pub(crate) fn as_ptr(&self) -> *const c_char {
+        match self {
+            Self::Inline(buf) => buf.as_ptr() as *const c_char,
+            Self::Heap(cstr) => cstr.as_ptr(),
+        }
+    }
+}
+
 // Private trait necessary to work around the "orphan trait" restriction.
 pub(crate) trait ToCString {
-    /// Convert to a CStr.
-    fn to_c_string(&self) -> CString;
+    /// Convert to a C string. Strings containing a nul byte cannot be
+    /// represented (the C string would be silently truncated), so they are
+    /// rejected with `EINVAL` -- which is what the kernel would return had the
+    /// string been passed as-is.
+    fn to_c_string(&self) -> Result<SmallCString, IOError>;
 }
 
 impl ToCString for OsStr {
-    fn to_c_string(&self) -> CString {
-        let filtered: Vec<_> = self
-            .as_bytes()
-            .iter()
-            .copied()
-            .take_while(|&c| c != b'\0')
-            .collect();
-        CString::new(filtered).expect("nul bytes should've been excluded")
+    fn to_c_string(&self) -> Result<SmallCString, IOError> {
+        let bytes = self.as_bytes();
+        if bytes.contains(&b'\0') {
+            Err(IOError::from_raw_os_error(libc::EINVAL))
+        } else {
+            Ok(SmallCString::new(bytes))
+        }
     }
 }
 
 impl ToCString for Path {
-    fn to_c_string(&self) -> CString {
+    fn to_c_string(&self) -> Result<SmallCString, IOError> {
         self.as_os_str().to_c_string()
     }
 }
@@ -160,13 +221,54 @@ pub(crate) trait RawFdExt {
     /// [bug62314]: https://github.com/rust-lang/rust/issues/62314
     /// [pr62425]: https://github.com/rust-lang/rust/pull/62425
     fn try_clone_hotfix(&self) -> Result<File, Error>;
+
+    /// Give the inode this RawFd references a new name (`name` inside
+    /// `dirfd`). This is done through `linkat(/proc/self/fd)`, so it also
+    /// works for unlinked `O_TMPFILE` files.
+    fn link_into(&self, dirfd: RawFd, name: &Path) -> Result<(), Error>;
 }
 
-fn proc_subpath(fd: RawFd) -> Result<String, Error> {
+/// Is the given `st_mode` a FIFO or device inode (whose `open(2)` may block)?
+pub(crate) fn is_special_file(mode: libc::mode_t) -> bool {
+    matches!(
+        mode & libc::S_IFMT,
+        libc::S_IFIFO | libc::S_IFCHR | libc::S_IFBLK
+    )
+}
+
+/// Enough for `self/fd/` followed by any fd number.
+const PROC_SUBPATH_SIZE: usize = 24;
+
+/// The path of a file descriptor inside `/proc` (such as `self/fd/42`), which
+/// is stored inline so that going through procfs doesn't need an allocation.
+struct ProcSubpath {
+    buf: [u8; PROC_SUBPATH_SIZE],
+    len: usize,
+}
+
+impl ProcSubpath {
+    fn new(args: fmt::Arguments) -> Self {
+        let mut buf = [0u8; PROC_SUBPATH_SIZE];
+        let mut cursor = &mut buf[..];
+        cursor
+            .write_fmt(args)
+            .expect("procfs path of fd should fit in buffer");
+        let len = PROC_SUBPATH_SIZE - cursor.len();
+        Self { buf, len }
+    }
+}
+
+impl AsRef<Path> for ProcSubpath {
+    fn as_ref(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.buf[..self.len]))
+    }
+}
+
+fn proc_subpath(fd: RawFd) -> Result<ProcSubpath, Error> {
     if fd == libc::AT_FDCWD {
-        Ok("self/cwd".to_string())
+        Ok(ProcSubpath::new(format_args!("self/cwd")))
     } else if fd.is_positive() {
-        Ok(format!("self/fd/{}", fd))
+        Ok(ProcSubpath::new(format_args!("self/fd/{}", fd)))
     } else {
         error::InvalidArgument {
             name: "fd",
@@ -178,13 +280,45 @@ fn proc_subpath(fd: RawFd) -> Result<String, Error> {
 
 impl RawFdExt for RawFd {
     fn reopen(&self, flags: OpenFlags) -> Result<File, Error> {
+        // Opening a FIFO blocks until there is a peer (and opening a device
+        // can block indefinitely depending on the driver), which would let an
+        // attacker who can place such inodes wedge us. So we open them with
+        // O_NONBLOCK and then clear it (unless the caller asked for it).
+        let nonblock = if flags.0 & (libc::O_PATH | libc::O_DIRECTORY | libc::O_NONBLOCK) == 0 {
+            let stat = syscalls::fstatat(*self, "").context(error::RawOsError {
+                operation: "check inode type before reopen",
+            })?;
+            is_special_file(stat.st_mode)
+        } else {
+            false
+        };
+        let open_flags = if nonblock {
+            flags.0 | libc::O_NONBLOCK
+        } else {
+            flags.0
+        };
+
         // TODO: We should look into using O_EMPTYPATH if it's available to
         //       avoid the /proc dependency -- though then again, as_unsafe_path
         //       necessarily requires /proc.
-        syscalls::openat_follow(PROCFS_HANDLE.as_raw_fd(), proc_subpath(*self)?, flags.0, 0)
-            .context(error::RawOsError {
-                operation: "reopen fd through procfs",
-            })
+        let file = syscalls::openat_follow(
+            PROCFS_HANDLE.as_raw_fd(),
+            proc_subpath(*self)?,
+            open_flags,
+            0,
+        )
+        .context(error::RawOsError {
+            operation: "reopen fd through procfs",
+        })?;
+        if nonblock {
+            let fd = file.as_raw_fd();
+            syscalls::fcntl_getfl(fd)
+                .and_then(|fl| syscalls::fcntl_setfl(fd, fl & !libc::O_NONBLOCK))
+                .context(error::RawOsError {
+                    operation: "clear O_NONBLOCK on reopened fd",
+                })?;
+        }
+        Ok(file)
     }
 
     fn as_unsafe_path(&self) -> Result<PathBuf, Error> {
@@ -200,6 +334,19 @@ impl RawFdExt for RawFd {
             operation: "clone fd",
         })
     }
+
+    fn link_into(&self, dirfd: RawFd, name: &Path) -> Result<(), Error> {
+        syscalls::linkat(
+            PROCFS_HANDLE.as_raw_fd(),
+            proc_subpath(*self)?.as_ref(),
+            dirfd,
+            name,
+            libc::AT_SYMLINK_FOLLOW,
+        )
+        .context(error::RawOsError {
+            operation: "link fd through procfs",
+        })
+    }
 }
 
 // XXX: We can't use <T: AsRawFd> here, because Rust tells us that RawFd might
@@ -220,12 +367,238 @@ impl RawFdExt for File {
     fn try_clone_hotfix(&self) -> Result<File, Error> {
         self.as_raw_fd().try_clone_hotfix()
     }
+
+    fn link_into(&self, dirfd: RawFd, name: &Path) -> Result<(), Error> {
+        self.as_raw_fd().link_into(dirfd, name)
+    }
 }
 
 pub(crate) trait FileExt {
     /// Check if the File is on a "dangerous" filesystem that might contain
     /// magic-links.
     fn is_dangerous(&self) -> Result<bool, Error>;
+
+    /// Get the `(st_dev, st_ino)` pair which identifies the file's inode.
+    fn inode_id(&self) -> Result<(u64, u64), Error>;
+
+    /// List the names of all extended attributes of the file.
+    fn list_xattrs(&self) -> Result<Vec<OsString>, Error>;
+
+    /// Get the value of the extended attribute `name` of the file.
+    fn get_xattr(&self, name: &OsStr) -> Result<Vec<u8>, Error>;
+
+    /// Set the extended attribute `name` of the file to `value`.
+    fn set_xattr(&self, name: &OsStr, value: &[u8]) -> Result<(), Error>;
+
+    /// Remove the extended attribute `name` of the file.
+    fn remove_xattr(&self, name: &OsStr) -> Result<(), Error>;
+
+    /// Change the owner of the file (without following symlinks).
+    fn set_owner(&self, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), Error>;
+
+    /// Change the mode of the file. Symlinks do not have a mode, so this will
+    /// fail if the file is an `O_PATH` handle to a symlink.
+    fn set_mode(&self, mode: libc::mode_t) -> Result<(), Error>;
+
+    /// Change the access and modification times of the file, as `(tv_sec,
+    /// tv_nsec)` pairs.
+    fn set_timestamps(&self, atime: (i64, i64), mtime: (i64, i64)) -> Result<(), Error>;
+}
+
+/// Get a `/proc/self/fd/$n` path for the given fd, for use with syscalls which
+/// only take a path (and follow it).
+///
+/// Unlike most other procfs operations this goes through the global `/proc`
+/// rather than `PROCFS_HANDLE`, because there is no other way of passing a
+/// path. Callers should prefer fd-based syscalls wherever possible.
+pub(crate) fn procfd_path(fd: RawFd) -> Result<PathBuf, Error> {
+    Ok(Path::new("/proc").join(proc_subpath(fd)?.as_ref()))
+}
+
+/// Identical to [`procfd_path`], except that the magic-link in the global
+/// `/proc` is checked to be on the same procfs as `PROCFS_HANDLE` (so that an
+/// existing mount over `/proc`, `/proc/self/fd` or the magic-link itself is
+/// detected). This cannot detect mounts made after the check, so it should
+/// only be used if there is no way of going through `PROCFS_HANDLE`.
+///
+/// [`procfd_path`]: fn.procfd_path.html
+pub(crate) fn checked_procfd_path(fd: RawFd) -> Result<PathBuf, Error> {
+    let path = procfd_path(fd)?;
+    let procfs_dev = PROCFS_HANDLE
+        .metadata()
+        .context(error::OsError {
+            operation: "fstat procfs handle",
+        })?
+        .dev();
+    let magiclink = syscalls::fstatat(libc::AT_FDCWD, &path).context(error::RawOsError {
+        operation: "check global procfs magic-link",
+    })?;
+    ensure!(
+        magiclink.st_dev == procfs_dev && magiclink.st_mode & libc::S_IFMT == libc::S_IFLNK,
+        error::SafetyViolation {
+            description: format!("{:?} is not a magic-link on the verified procfs", path),
+        }
+    );
+    Ok(path)
+}
+
+lazy_static! {
+    /// Whether the `*xattrat(2)` family of syscalls (Linux 6.13) is available,
+    /// which lets us operate on the xattrs of `O_PATH` descriptors through
+    /// `PROCFS_HANDLE`.
+    static ref XATTRAT_SUPPORTED: bool = match syscalls::listxattrat(
+        PROCFS_HANDLE.as_raw_fd(),
+        ".",
+        &mut [],
+    ) {
+        Err(err) => err.root_cause().raw_os_error() != Some(libc::ENOSYS),
+        Ok(_) => true,
+    };
+}
+
+/// The path used to operate on the xattrs of a file descriptor, since there
+/// are no fd-based xattr syscalls which work with `O_PATH` descriptors.
+#[derive(Debug)]
+pub(crate) enum XattrPath {
+    /// A magic-link relative to `PROCFS_HANDLE`, for the `*xattrat(2)`
+    /// syscalls.
+    Procfs(PathBuf),
+    /// A magic-link in the global `/proc` (checked with
+    /// [`checked_procfd_path`]), on kernels without `*xattrat(2)`.
+    ///
+    /// [`checked_procfd_path`]: fn.checked_procfd_path.html
+    Global(PathBuf),
+}
+
+impl XattrPath {
+    pub(crate) fn of(fd: RawFd) -> Result<Self, Error> {
+        if *XATTRAT_SUPPORTED {
+            Ok(Self::Procfs(proc_subpath(fd)?.as_ref().to_path_buf()))
+        } else {
+            Ok(Self::Global(checked_procfd_path(fd)?))
+        }
+    }
+
+    pub(crate) fn listxattr(&self, buf: &mut [u8]) -> Result<usize, syscalls::Error> {
+        match self {
+            Self::Procfs(path) => syscalls::listxattrat(PROCFS_HANDLE.as_raw_fd(), path, buf),
+            Self::Global(path) => syscalls::listxattr(path, buf),
+        }
+    }
+
+    pub(crate) fn getxattr(&self, name: &OsStr, buf: &mut [u8]) -> Result<usize, syscalls::Error> {
+        match self {
+            Self::Procfs(path) => syscalls::getxattrat(PROCFS_HANDLE.as_raw_fd(), path, name, buf),
+            Self::Global(path) => syscalls::getxattr(path, name, buf),
+        }
+    }
+
+    pub(crate) fn setxattr(&self, name: &OsStr, value: &[u8]) -> Result<(), syscalls::Error> {
+        match self {
+            Self::Procfs(path) => {
+                syscalls::setxattrat(PROCFS_HANDLE.as_raw_fd(), path, name, value, 0)
+            }
+            Self::Global(path) => syscalls::setxattr(path, name, value, 0),
+        }
+    }
+
+    pub(crate) fn removexattr(&self, name: &OsStr) -> Result<(), syscalls::Error> {
+        match self {
+            Self::Procfs(path) => syscalls::removexattrat(PROCFS_HANDLE.as_raw_fd(), path, name),
+            Self::Global(path) => syscalls::removexattr(path, name),
+        }
+    }
+}
+
+/// Smallest fd budget used by recursive operations by default.
+const MIN_FD_BUDGET: usize = 4;
+
+/// Largest fd budget used by recursive operations by default. Going beyond
+/// this doesn't make traversals noticeably faster.
+const MAX_FD_BUDGET: usize = 1024;
+
+/// Get the soft `RLIMIT_NOFILE` of the process, or `u64::MAX` if it cannot be
+/// determined.
+pub(crate) fn nofile_limit() -> u64 {
+    syscalls::getrlimit_nofile().unwrap_or(u64::MAX)
+}
+
+/// The default number of directory file descriptors a recursive operation may
+/// hold open at once -- a quarter of the soft `RLIMIT_NOFILE` (so that the
+/// caller still has plenty of room for its own file descriptors).
+pub(crate) fn default_fd_budget() -> usize {
+    let budget = nofile_limit() / 4;
+    if budget > MAX_FD_BUDGET as u64 {
+        MAX_FD_BUDGET
+    } else if budget < MIN_FD_BUDGET as u64 {
+        MIN_FD_BUDGET
+    } else {
+        budget as usize
+    }
+}
+
+/// Is the error the result of running out of file descriptors?
+pub(crate) fn is_fd_exhaustion(err: &Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// Convert an error from running out of file descriptors into an
+/// `Error::TooManyOpenFiles` (other errors are returned unchanged).
+pub(crate) fn check_fd_exhaustion(err: Error, operation: &str) -> Error {
+    match err.raw_os_error() {
+        Some(errno @ libc::EMFILE) | Some(errno @ libc::ENFILE) => error::TooManyOpenFiles {
+            operation,
+            limit: nofile_limit(),
+        }
+        .into_error(IOError::from_raw_os_error(errno)),
+        _ => err,
+    }
+}
+
+/// Maximum number of times we will retry an xattr syscall which fails with
+/// `-ERANGE` (because the xattr was racily changed between us getting the size
+/// and reading it).
+const XATTR_MAX_RETRIES: usize = 16;
+
+/// Helper for the "get the size, then read" dance needed for xattr syscalls.
+fn read_xattr_buffer<F>(read: F) -> Result<Vec<u8>, syscalls::Error>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, syscalls::Error>,
+{
+    read_xattr_buffer_capped(usize::MAX, read)
+        .map(|buf| buf.expect("uncapped xattr buffer must always fit"))
+}
+
+/// Identical to `read_xattr_buffer`, except that `None` is returned if the
+/// buffer would be larger than `max_size` bytes (without reading it).
+pub(crate) fn read_xattr_buffer_capped<F>(
+    max_size: usize,
+    mut read: F,
+) -> Result<Option<Vec<u8>>, syscalls::Error>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, syscalls::Error>,
+{
+    let mut last_error = None;
+    for _ in 0..XATTR_MAX_RETRIES {
+        let size = read(&mut [])?;
+        if size > max_size {
+            return Ok(None);
+        }
+        let mut buf = vec![0; size];
+        match read(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(Some(buf));
+            }
+            Err(err) => {
+                if err.root_cause().raw_os_error() != Some(libc::ERANGE) {
+                    return Err(err);
+                }
+                last_error = Some(err);
+            }
+        }
+    }
+    Err(last_error.expect("xattr retry loop failed so last_error must exist"))
 }
 
 lazy_static! {
@@ -252,4 +625,200 @@ impl FileExt for File {
         })?;
         Ok(DANGEROUS_FILESYSTEMS.contains(&stat.f_type))
     }
+
+    fn inode_id(&self) -> Result<(u64, u64), Error> {
+        let meta = self.metadata().context(error::OsError {
+            operation: "fstat to get inode identity",
+        })?;
+        Ok((meta.dev(), meta.ino()))
+    }
+
+    fn list_xattrs(&self) -> Result<Vec<OsString>, Error> {
+        let path = XattrPath::of(self.as_raw_fd())?;
+        let names = read_xattr_buffer(|buf| path.listxattr(buf)).context(error::RawOsError {
+            operation: "list xattrs of fd",
+        })?;
+        // The list of names is a sequence of NUL-terminated strings.
+        Ok(names
+            .split(|&c| c == b'\0')
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_os_string())
+            .collect())
+    }
+
+    fn get_xattr(&self, name: &OsStr) -> Result<Vec<u8>, Error> {
+        check_no_nul("name", name)?;
+        let path = XattrPath::of(self.as_raw_fd())?;
+        read_xattr_buffer(|buf| path.getxattr(name, buf)).context(error::RawOsError {
+            operation: "get xattr of fd",
+        })
+    }
+
+    fn set_xattr(&self, name: &OsStr, value: &[u8]) -> Result<(), Error> {
+        check_no_nul("name", name)?;
+        XattrPath::of(self.as_raw_fd())?
+            .setxattr(name, value)
+            .context(error::RawOsError {
+                operation: "set xattr of fd",
+            })
+    }
+
+    fn remove_xattr(&self, name: &OsStr) -> Result<(), Error> {
+        check_no_nul("name", name)?;
+        XattrPath::of(self.as_raw_fd())?
+            .removexattr(name)
+            .context(error::RawOsError {
+                operation: "remove xattr of fd",
+            })
+    }
+
+    fn set_owner(&self, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), Error> {
+        syscalls::fchownat(
+            self.as_raw_fd(),
+            "",
+            uid,
+            gid,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+        .context(error::RawOsError {
+            operation: "chown fd",
+        })
+    }
+
+    fn set_mode(&self, mode: libc::mode_t) -> Result<(), Error> {
+        // fchmod(2) doesn't work on O_PATH descriptors, so we have to go
+        // through the magic-link instead.
+        syscalls::fchmodat(
+            PROCFS_HANDLE.as_raw_fd(),
+            proc_subpath(self.as_raw_fd())?,
+            mode,
+        )
+        .context(error::RawOsError {
+            operation: "chmod fd through procfs",
+        })
+    }
+
+    fn set_timestamps(&self, atime: (i64, i64), mtime: (i64, i64)) -> Result<(), Error> {
+        // futimens(2) doesn't work on O_PATH descriptors, so we have to go
+        // through the magic-link instead.
+        syscalls::utimensat(
+            PROCFS_HANDLE.as_raw_fd(),
+            proc_subpath(self.as_raw_fd())?,
+            [atime, mtime],
+            0,
+        )
+        .context(error::RawOsError {
+            operation: "set times of fd through procfs",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checked_procfd_path, procfd_path, read_xattr_buffer, SmallCString, ToCString, XattrPath,
+        SMALL_CSTRING_SIZE, XATTRAT_SUPPORTED,
+    };
+    use crate::{
+        syscalls,
+        tests::{count_allocations, TempDir},
+    };
+
+    use std::{
+        ffi::OsStr,
+        fs,
+        os::unix::{ffi::OsStrExt, io::AsRawFd},
+    };
+
+    fn c_bytes(cstr: &SmallCString) -> Vec<u8> {
+        match cstr {
+            SmallCString::Inline(buf) => {
+                let len = buf.iter().position(|&c| c == b'\0').unwrap();
+                buf[..len].to_vec()
+            }
+            SmallCString::Heap(cstr) => cstr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn to_c_string_non_utf8() {
+        for bytes in [&b"caf\xe9"[..], b"\xff\xfe", b"a/\x80/b", b""].iter() {
+            let cstr = OsStr::from_bytes(bytes).to_c_string().unwrap();
+            assert_eq!(c_bytes(&cstr), *bytes);
+        }
+    }
+
+    #[test]
+    fn to_c_string_lengths() {
+        for len in [
+            SMALL_CSTRING_SIZE - 1,
+            SMALL_CSTRING_SIZE,
+            4 * SMALL_CSTRING_SIZE,
+        ]
+        .iter()
+        {
+            let bytes = vec![b'\xaa'; *len];
+            let cstr = OsStr::from_bytes(&bytes).to_c_string().unwrap();
+            assert_eq!(c_bytes(&cstr), bytes, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn to_c_string_rejects_nul() {
+        let long = [vec![b'a'; 2 * SMALL_CSTRING_SIZE], vec![b'\0']].concat();
+        for bytes in [&b"\0"[..], b"a\0b", b"trailing\0", &long].iter() {
+            let err = OsStr::from_bytes(bytes).to_c_string().err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn xattr_paths() {
+        let dir = TempDir::new();
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        let fd = syscalls::openat(libc::AT_FDCWD, &file, libc::O_PATH, 0).unwrap();
+
+        let global = checked_procfd_path(fd.as_raw_fd()).unwrap();
+        assert_eq!(global, procfd_path(fd.as_raw_fd()).unwrap());
+        let name = OsStr::new("user.pathrs-test");
+        let mut paths = vec![XattrPath::Global(global)];
+        if *XATTRAT_SUPPORTED {
+            paths.push(XattrPath::of(fd.as_raw_fd()).unwrap());
+        }
+        for path in paths {
+            match path.setxattr(name, b"value") {
+                // Not all filesystems support user xattrs.
+                Err(err) if err.root_cause().raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+                ret => ret.unwrap(),
+            }
+            let names = read_xattr_buffer(|buf| path.listxattr(buf)).unwrap();
+            assert!(names.split(|&c| c == b'\0').any(|n| n == name.as_bytes()));
+            let value = read_xattr_buffer(|buf| path.getxattr(name, buf)).unwrap();
+            assert_eq!(value, b"value", "{:?}", path);
+            path.removexattr(name).unwrap();
+            let err = path.getxattr(name, &mut []).unwrap_err();
+            assert_eq!(err.root_cause().raw_os_error(), Some(libc::ENODATA));
+        }
+    }
+
+    #[test]
+    fn syscalls_reject_nul() {
+        let path = OsStr::from_bytes(b"/tmp\0/etc/passwd");
+        let err = syscalls::openat(libc::AT_FDCWD, path, libc::O_PATH, 0).unwrap_err();
+        assert_eq!(err.root_cause().raw_os_error(), Some(libc::EINVAL));
+        let err = syscalls::fstatat(libc::AT_FDCWD, path).unwrap_err();
+        assert_eq!(err.root_cause().raw_os_error(), Some(libc::EINVAL));
+        let err = syscalls::mkdirat(libc::AT_FDCWD, path, 0o755).unwrap_err();
+        assert_eq!(err.root_cause().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn to_c_string_allocations() {
+        let (_, count) = count_allocations(|| OsStr::new("some/short/path").to_c_string());
+        assert_eq!(count, 0);
+        let long = "x".repeat(SMALL_CSTRING_SIZE);
+        let (_, count) = count_allocations(|| OsStr::new(&long).to_c_string());
+        assert_eq!(count, 1);
+    }
 }
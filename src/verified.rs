@@ -0,0 +1,166 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    digest::{Sha256, SHA256_SIZE},
+    error::{self, Error, ErrorExt},
+    OpenFlags, Root,
+};
+
+use std::{
+    fs::File,
+    io::{Error as IOError, ErrorKind, Read},
+    path::Path,
+};
+
+use snafu::ResultExt;
+
+/// A reader for a file opened with [`Root::open_verified`], which computes the
+/// SHA-256 digest of the contents as they are read and checks it against the
+/// expected digest when the end of the file is reached.
+///
+/// If the digest doesn't match, the read which reached the end of the file
+/// fails with an [`ErrorKind::InvalidData`] error. This means that **none** of
+/// the data read from a [`VerifiedReader`] can be trusted until it has
+/// returned end-of-file -- if you need to act on the contents before reading
+/// all of them, use [`Root::read_verified`] instead.
+///
+/// [`Root::open_verified`]: struct.Root.html#method.open_verified
+/// [`Root::read_verified`]: struct.Root.html#method.read_verified
+/// [`VerifiedReader`]: struct.VerifiedReader.html
+/// [`ErrorKind::InvalidData`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.InvalidData
+#[derive(Debug)]
+pub struct VerifiedReader {
+    file: File,
+    hasher: Option<Sha256>,
+    expected: [u8; SHA256_SIZE],
+    verified: bool,
+}
+
+impl VerifiedReader {
+    /// Whether the end of the file has been reached and the digest of the
+    /// contents matched.
+    #[inline]
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+}
+
+impl Read for VerifiedReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        let hasher = match self.hasher.as_mut() {
+            Some(hasher) => hasher,
+            // We have already verified (or rejected) the contents.
+            None if self.verified => return Ok(0),
+            None => {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    "file contents do not match expected digest",
+                ))
+            }
+        };
+        let n = self.file.read(buf)?;
+        if n > 0 || buf.is_empty() {
+            hasher.update(&buf[..n]);
+            return Ok(n);
+        }
+
+        let actual = self
+            .hasher
+            .take()
+            .expect("hasher must exist until EOF")
+            .finalize();
+        self.verified = actual == self.expected;
+        if self.verified {
+            Ok(0)
+        } else {
+            Err(IOError::new(
+                ErrorKind::InvalidData,
+                "file contents do not match expected digest",
+            ))
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, open the file at `path` for reading with a
+    /// [`VerifiedReader`], which checks that the contents of the file have the
+    /// SHA-256 digest `expected_sha256` once the whole file has been read.
+    ///
+    /// The file is opened with [`Root::open_file`] (so the [`Root`]'s
+    /// `reopen_policy` applies). Since the digest covers exactly the bytes
+    /// which were read from the opened file, there is no window for the file
+    /// to be swapped between the check and the use.
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Root::open_file`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open_file`]: struct.Root.html#method.open_file
+    /// [`VerifiedReader`]: struct.VerifiedReader.html
+    pub fn open_verified<P: AsRef<Path>>(
+        &self,
+        path: P,
+        expected_sha256: &[u8; SHA256_SIZE],
+    ) -> Result<VerifiedReader, Error> {
+        let file = self.open_file(path, OpenFlags(libc::O_RDONLY))?;
+        Ok(VerifiedReader {
+            file,
+            hasher: Some(Sha256::new()),
+            expected: *expected_sha256,
+            verified: false,
+        })
+    }
+
+    /// Within the [`Root`]'s tree, read the entire contents of the file at
+    /// `path`, and only return them if they have the SHA-256 digest
+    /// `expected_sha256`. See [`Root::open_verified`] for more details.
+    ///
+    /// # Errors
+    ///
+    /// If the digest of the contents doesn't match, an
+    /// [`Error::SafetyViolation`] is returned. Otherwise, the errors are
+    /// identical to [`Root::open_file`].
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::open_verified`]: struct.Root.html#method.open_verified
+    /// [`Root::open_file`]: struct.Root.html#method.open_file
+    /// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+    pub fn read_verified<P: AsRef<Path>>(
+        &self,
+        path: P,
+        expected_sha256: &[u8; SHA256_SIZE],
+    ) -> Result<Vec<u8>, Error> {
+        let mut reader = self
+            .open_verified(path, expected_sha256)
+            .wrap("open file for verified read")?;
+        let mut contents = Vec::new();
+        match reader.read_to_end(&mut contents) {
+            Err(err) if err.kind() == ErrorKind::InvalidData => error::SafetyViolation {
+                description: "file contents do not match expected digest",
+            }
+            .fail(),
+            ret => ret.map(|_| contents).context(error::OsError {
+                operation: "read file contents",
+            }),
+        }
+    }
+}
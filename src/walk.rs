@@ -0,0 +1,624 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    dirent::SuspendedDirents,
+    error::{self, Error, ErrorExt},
+    syscalls,
+    throttle::Throttler,
+    utils::{self, FileExt, RawFdExt},
+    DeviceType, Dirents, EntryType, Handle, OpenFlags, Root, Throttle,
+};
+
+use std::{
+    cmp,
+    ffi::{OsStr, OsString},
+    fs::{File, Metadata},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use snafu::ResultExt;
+
+/// An entry yielded by [`Walk`].
+///
+/// [`Walk`]: struct.Walk.html
+#[derive(Debug)]
+pub struct WalkEntry {
+    path: PathBuf,
+    depth: usize,
+    handle: Handle,
+    metadata: Metadata,
+}
+
+impl WalkEntry {
+    /// The path of the entry, relative to the starting directory of the walk.
+    /// The starting directory itself has an empty path.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How many directories deep the entry is from the starting directory of
+    /// the walk (which has a depth of `0`).
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The [`Handle`] to the entry. Symlinks are not followed, so this may be
+    /// a handle to a symlink.
+    ///
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The metadata of the entry, as it was when the entry was opened.
+    #[inline]
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The type of the entry. Unlike [`DirEntry::entry_type`] this is never
+    /// [`EntryType::Unknown`], because the walk always stats each entry.
+    ///
+    /// [`DirEntry::entry_type`]: struct.DirEntry.html#method.entry_type
+    /// [`EntryType::Unknown`]: enum.EntryType.html#variant.Unknown
+    #[inline]
+    pub fn entry_type(&self) -> EntryType {
+        EntryType::of(self.metadata.file_type())
+    }
+
+    /// If the entry is a device inode, its type and `(major, minor)` device
+    /// number.
+    pub fn device(&self) -> Option<(DeviceType, u32, u32)> {
+        let rdev = self.metadata.rdev();
+        DeviceType::of(self.metadata.file_type())
+            .map(|device_type| (device_type, libc::major(rdev), libc::minor(rdev)))
+    }
+
+    /// Unwrap the [`WalkEntry`] to get the underlying [`Handle`].
+    ///
+    /// [`WalkEntry`]: struct.WalkEntry.html
+    /// [`Handle`]: struct.Handle.html
+    #[inline]
+    pub fn into_handle(self) -> Handle {
+        self.handle
+    }
+}
+
+/// A directory which has been yielded but not yet descended into.
+#[derive(Debug)]
+struct PendingDir {
+    dir: File,
+    path: PathBuf,
+    depth: usize,
+}
+
+/// A directory which is currently being read.
+struct OpenDir {
+    state: DirState,
+    /// Name of the directory inside its parent (empty for the starting
+    /// directory of the walk).
+    name: OsString,
+    /// The `(st_dev, st_ino)` of the directory, used to verify the directory
+    /// when it is re-opened.
+    inode_id: (u64, u64),
+    path: PathBuf,
+    depth: usize,
+}
+
+/// Whether an [`OpenDir`] currently holds a file descriptor.
+pub(crate) enum DirState {
+    Open(Dirents),
+    /// The directory was closed to stay within the fd budget of the walk, and
+    /// will be re-opened once the walk returns to it.
+    Suspended(SuspendedDirents),
+}
+
+impl OpenDir {
+    #[inline]
+    fn is_open(&self) -> bool {
+        matches!(self.state, DirState::Open(_))
+    }
+}
+
+/// A pre-order, depth-first walk of a directory tree inside a [`Root`],
+/// created with [`Root::walk`].
+///
+/// Each entry is opened with `O_PATH | O_NOFOLLOW` relative to its parent
+/// directory (using the name returned by `getdents64(2)`), so symlinks are
+/// never followed. Before a directory is read, its ancestors are checked (by
+/// following `..` back up to the starting directory) to make sure it has not
+/// been moved out of the tree, in which case the walk fails with
+/// [`Error::SafetyViolation`]. Entries which are removed while the walk is in
+/// progress are silently skipped.
+///
+/// Note that a directory which is moved out of the tree *while* it is being
+/// read is only detected once the walk descends into one of its
+/// subdirectories, so some of its (non-directory) entries may be yielded even
+/// though they are no longer inside the tree.
+///
+/// The order of entries within a directory is the order returned by the
+/// kernel, and should not be relied upon.
+///
+/// # File Descriptors
+///
+/// The walk holds at most a fixed number of directories open for reading (see
+/// [`Walk::set_fd_budget`]), in addition to the [`WalkEntry`]s you hold and a
+/// few file descriptors used internally. When descending past the budget, the
+/// shallowest open directories are closed and then re-opened (relative to
+/// their closest open ancestor) once the walk returns to them -- if a
+/// re-opened directory is not the same directory, the walk fails with
+/// [`Error::SafetyViolation`]. If the process runs out of file descriptors
+/// regardless, [`Error::TooManyOpenFiles`] is returned.
+///
+/// [`Root`]: struct.Root.html
+/// [`Root::walk`]: struct.Root.html#method.walk
+/// [`Walk::set_fd_budget`]: struct.Walk.html#method.set_fd_budget
+/// [`WalkEntry`]: struct.WalkEntry.html
+/// [`Error::SafetyViolation`]: error/enum.Error.html#variant.SafetyViolation
+/// [`Error::TooManyOpenFiles`]: error/enum.Error.html#variant.TooManyOpenFiles
+pub struct Walk {
+    /// `O_PATH` handle to the starting directory, which suspended directories
+    /// are re-opened relative to if none of their ancestors are open.
+    anchor: File,
+    first: Option<WalkEntry>,
+    pending: Option<PendingDir>,
+    stack: Vec<OpenDir>,
+    /// Maximum number of directories in `stack` which may be open at once.
+    fd_budget: usize,
+    /// Number of directories in `stack` which are currently open.
+    open_dirs: usize,
+    throttler: Option<Arc<Throttler>>,
+}
+
+impl Walk {
+    pub(crate) fn new(handle: Handle) -> Result<Self, Error> {
+        let metadata = handle.inner.metadata().context(error::OsError {
+            operation: "fstat walk root",
+        })?;
+        ensure!(
+            metadata.is_dir(),
+            error::InvalidArgument {
+                name: "path",
+                description: "walk must start at a directory",
+            }
+        );
+        let anchor = handle.inner.try_clone_hotfix().wrap("dup walk root")?;
+        Ok(Self {
+            anchor,
+            first: Some(WalkEntry {
+                path: PathBuf::new(),
+                depth: 0,
+                handle,
+                metadata,
+            }),
+            pending: None,
+            stack: Vec::new(),
+            fd_budget: utils::default_fd_budget(),
+            open_dirs: 0,
+            throttler: None,
+        })
+    }
+
+    /// Set the maximum number of directories the walk will hold open for
+    /// reading at once (a budget of `0` is treated as `1`). The default is a
+    /// quarter of the soft `RLIMIT_NOFILE` of the process (clamped to a
+    /// reasonable range).
+    ///
+    /// A lower budget reduces the number of file descriptors used by deep
+    /// walks, at the cost of re-opening directories more often.
+    pub fn set_fd_budget(&mut self, budget: usize) {
+        self.fd_budget = cmp::max(budget, 1);
+    }
+
+    /// Throttle the walk according to `throttle`, replacing any previously
+    /// configured [`Throttle`]. Walks created with [`Root::walk`] use the
+    /// [`Root`]'s `throttle` by default.
+    ///
+    /// [`Throttle`]: struct.Throttle.html
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttler = Throttler::new(throttle);
+    }
+
+    /// The [`Throttler`] of the walk, which operations built on top of the
+    /// walk use to account for the file contents they read.
+    ///
+    /// [`Throttler`]: struct.Throttler.html
+    pub(crate) fn throttler(&self) -> Option<Arc<Throttler>> {
+        self.throttler.clone()
+    }
+
+    /// Do not descend into the directory most recently yielded by the walk. If
+    /// the most recent entry was not a directory, this is a no-op.
+    pub fn skip_current_dir(&mut self) {
+        self.pending = None;
+    }
+
+    /// Yield an entry, marking it to be descended into if it is a directory.
+    fn yield_entry(&mut self, entry: WalkEntry) -> Result<WalkEntry, Error> {
+        if let Some(throttler) = &self.throttler {
+            throttler.account(1, 0);
+        }
+        if entry.metadata.is_dir() {
+            self.pending = Some(PendingDir {
+                dir: entry
+                    .handle
+                    .inner
+                    .try_clone_hotfix()
+                    .wrap("dup directory for walk")?,
+                path: entry.path.clone(),
+                depth: entry.depth,
+            });
+        }
+        Ok(entry)
+    }
+
+    /// Close the shallowest open directory in the stack (except for the last
+    /// `keep` entries). Returns whether a directory was closed.
+    fn suspend_one(&mut self, keep: usize) -> Result<bool, Error> {
+        let end = self.stack.len().saturating_sub(keep);
+        let dir = match self.stack[..end].iter_mut().find(|dir| dir.is_open()) {
+            Some(dir) => dir,
+            None => return Ok(false),
+        };
+        if let DirState::Open(dirents) = &mut dir.state {
+            dir.state = DirState::Suspended(dirents.suspend()?);
+        }
+        self.open_dirs -= 1;
+        Ok(true)
+    }
+
+    /// Close directories until there is room in the fd budget for another open
+    /// directory.
+    fn make_room(&mut self) -> Result<(), Error> {
+        while self.open_dirs >= self.fd_budget && self.suspend_one(0)? {}
+        Ok(())
+    }
+
+    /// Run `open`, closing directories (other than the last `keep` entries in
+    /// the stack) and retrying if we have run out of file descriptors.
+    fn open_retry<F>(&mut self, keep: usize, operation: &str, mut open: F) -> Result<File, Error>
+    where
+        F: FnMut() -> Result<File, Error>,
+    {
+        loop {
+            match open() {
+                Err(err) if utils::is_fd_exhaustion(&err) => {
+                    if !self.suspend_one(keep)? {
+                        return Err(utils::check_fd_exhaustion(err, operation));
+                    }
+                }
+                ret => return ret.wrap(operation),
+            }
+        }
+    }
+
+    /// Open a directory (given as an `O_PATH` handle) for reading.
+    fn open_for_reading(&mut self, dir: &File) -> Result<File, Error> {
+        self.open_retry(0, "reopen directory for reading", || {
+            dir.reopen(OpenFlags(libc::O_RDONLY | libc::O_DIRECTORY))
+        })
+    }
+
+    /// Check that `dir` (which is about to be read as the directory at
+    /// `self.stack[depth]`) is still inside the starting directory, by walking
+    /// back up with `..` and comparing each parent against the directories in
+    /// the stack. Since `..` is resolved by the kernel based on the dentry
+    /// tree, this detects directories which were moved elsewhere (even if the
+    /// directory we opened them from was not).
+    fn verify_ancestors(&self, dir: &File, depth: usize) -> Result<(), Error> {
+        let mut current: Option<File> = None;
+        for expected in self.stack[..depth].iter().rev() {
+            let fd = current.as_ref().map_or(dir.as_raw_fd(), AsRawFd::as_raw_fd);
+            let parent = syscalls::openat(fd, "..", libc::O_PATH | libc::O_DIRECTORY, 0)
+                .context(error::RawOsError {
+                    operation: "open parent directory during walk",
+                })
+                .map_err(|err| utils::check_fd_exhaustion(err, "open parent directory"))?;
+            ensure!(
+                parent.inode_id()? == expected.inode_id,
+                error::SafetyViolation {
+                    description: "directory was moved during walk",
+                }
+            );
+            current = Some(parent);
+        }
+        Ok(())
+    }
+
+    /// Open the most recently yielded directory (if any) for reading.
+    fn descend(&mut self) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.make_room()?;
+            // The directory was yielded from the top of the stack, so the
+            // stack holds all of its ancestors.
+            self.verify_ancestors(&pending.dir, self.stack.len())?;
+            let inode_id = pending.dir.inode_id()?;
+            let dir = self.open_for_reading(&pending.dir)?;
+            self.stack.push(OpenDir {
+                state: DirState::Open(Dirents::new(dir)),
+                name: pending
+                    .path
+                    .file_name()
+                    .map(OsStr::to_os_string)
+                    .unwrap_or_default(),
+                inode_id,
+                path: pending.path,
+                depth: pending.depth,
+            });
+            self.open_dirs += 1;
+        }
+        Ok(())
+    }
+
+    /// Re-open the directory at the top of the stack if it was suspended.
+    fn resume_top(&mut self) -> Result<(), Error> {
+        let idx = match self.stack.len() {
+            0 => return Ok(()),
+            len => len - 1,
+        };
+        if self.stack[idx].is_open() {
+            return Ok(());
+        }
+        self.make_room()?;
+
+        // Walk down from the closest open ancestor (or the starting directory)
+        // using the names we saw in getdents64(2). The directory must still be
+        // the same inode, otherwise the rest of the walk would be done in some
+        // other part of the tree.
+        let (base_fd, first) = match self.stack[..idx].iter().rposition(OpenDir::is_open) {
+            Some(base) => match &self.stack[base].state {
+                DirState::Open(dirents) => (dirents.as_raw_fd(), base + 1),
+                DirState::Suspended(_) => unreachable!("rposition found an open directory"),
+            },
+            None => (self.anchor.as_raw_fd(), 1),
+        };
+        let mut current: Option<File> = None;
+        for dir in &self.stack[first..=idx] {
+            let dirfd = current.as_ref().map_or(base_fd, AsRawFd::as_raw_fd);
+            let file = syscalls::openat(dirfd, &dir.name, libc::O_PATH | libc::O_DIRECTORY, 0)
+                .context(error::RawOsError {
+                    operation: "re-open suspended directory during walk",
+                })
+                .map_err(|err| utils::check_fd_exhaustion(err, "re-open suspended directory"))?;
+            current = Some(file);
+        }
+        let current = match current {
+            Some(file) => file,
+            None => self.anchor.try_clone_hotfix().wrap("dup walk root")?,
+        };
+        ensure!(
+            current.inode_id()? == self.stack[idx].inode_id,
+            error::SafetyViolation {
+                description: "directory was moved during walk",
+            }
+        );
+        self.verify_ancestors(&current, idx)?;
+
+        let dir = self.open_for_reading(&current)?;
+        if let DirState::Suspended(suspended) = &mut self.stack[idx].state {
+            self.stack[idx].state = DirState::Open(suspended.resume(dir)?);
+        }
+        self.open_dirs += 1;
+        Ok(())
+    }
+
+    /// Remove the directory at the top of the stack.
+    fn pop(&mut self) {
+        if let Some(dir) = self.stack.pop() {
+            if dir.is_open() {
+                self.open_dirs -= 1;
+            }
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(first) = self.first.take() {
+            return Some(self.yield_entry(first));
+        }
+        if let Err(err) = self.descend() {
+            return Some(Err(err));
+        }
+
+        loop {
+            if let Err(err) = self.resume_top() {
+                // We cannot continue reading this directory.
+                self.pop();
+                return Some(Err(err));
+            }
+            let current = self.stack.last_mut()?;
+            let dirents = match &mut current.state {
+                DirState::Open(dirents) => dirents,
+                DirState::Suspended(_) => unreachable!("top of walk stack must be open"),
+            };
+            let dirent = match dirents.next() {
+                None => {
+                    self.pop();
+                    continue;
+                }
+                Some(Err(err)) => {
+                    // We cannot continue reading this directory.
+                    self.pop();
+                    return Some(Err(err));
+                }
+                Some(Ok(dirent)) => dirent,
+            };
+            let dirfd = dirents.as_raw_fd();
+
+            // The name comes from getdents64(2) so it is a single component
+            // (and never "." or ".."), so this cannot escape the directory.
+            let file = match self.open_retry(1, "open directory entry during walk", || {
+                syscalls::openat(dirfd, &dirent.name, libc::O_PATH, 0).context(error::RawOsError {
+                    operation: "open directory entry",
+                })
+            }) {
+                Ok(file) => file,
+                // The entry was removed after we read the directory.
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            let metadata = match file.metadata() {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    return Some(Err(err).context(error::OsError {
+                        operation: "fstat directory entry during walk",
+                    }))
+                }
+            };
+
+            let current = self.stack.last().expect("walk stack should be non-empty");
+            let entry = WalkEntry {
+                path: current.path.join(&dirent.name),
+                depth: current.depth + 1,
+                handle: Handle::from_file_unchecked(file),
+                metadata,
+            };
+            return Some(self.yield_entry(entry));
+        }
+    }
+}
+
+impl Root {
+    /// Within the [`Root`]'s tree, walk the directory tree starting at `path`.
+    /// See [`Walk`] for more details.
+    ///
+    /// # Errors
+    ///
+    /// If `path` doesn't exist or is not a directory, an error is returned.
+    /// Errors encountered during the walk are returned by the iterator.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Walk`]: struct.Walk.html
+    pub fn walk<P: AsRef<Path>>(&self, path: P) -> Result<Walk, Error> {
+        let handle = self.resolve(path).wrap("resolve walk root")?;
+        let mut walk = Walk::new(handle)?;
+        walk.set_throttle(self.throttle);
+        Ok(walk)
+    }
+
+    /// Within the [`Root`]'s tree, check whether the directory at `path` is
+    /// empty (contains no entries other than `.` and `..`).
+    ///
+    /// Only the first batch of directory entries is read, so this is cheap
+    /// even for huge directories.
+    ///
+    /// # Errors
+    ///
+    /// If `path` doesn't exist or is not a directory, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    pub fn dir_is_empty<P: AsRef<Path>>(&self, path: P) -> Result<bool, Error> {
+        match self.resolve(path)?.read_dir()?.next() {
+            None => Ok(true),
+            Some(entry) => entry.map(|_| false),
+        }
+    }
+
+    /// Within the [`Root`]'s tree, count the number of entries in the
+    /// directory at `path` (not including `.` and `..`).
+    ///
+    /// If `recursive` is set, the entries of all subdirectories are counted as
+    /// well (by doing a [`Root::walk`], so symlinks to directories are not
+    /// followed).
+    ///
+    /// # Errors
+    ///
+    /// If `path` doesn't exist or is not a directory, an error is returned.
+    ///
+    /// [`Root`]: struct.Root.html
+    /// [`Root::walk`]: struct.Root.html#method.walk
+    pub fn count_entries<P: AsRef<Path>>(&self, path: P, recursive: bool) -> Result<u64, Error> {
+        let mut count = 0;
+        if recursive {
+            for entry in self.walk(path)? {
+                if entry?.depth() > 0 {
+                    count += 1;
+                }
+            }
+        } else {
+            for entry in self.resolve(path)?.read_dir()? {
+                entry?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, tests::TempDir, Root};
+
+    use std::fs;
+
+    #[test]
+    fn walk_directory_moved_out() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path().join("root/a/b/c")).unwrap();
+        fs::create_dir(dir.path().join("outside")).unwrap();
+        let root = Root::open(dir.path().join("root")).unwrap();
+
+        let mut walk = root.walk("/").unwrap();
+        assert_eq!(walk.next().unwrap().unwrap().depth(), 0);
+        let entry = walk.next().unwrap().unwrap();
+        assert_eq!(entry.path().to_str(), Some("a"));
+
+        // Move the directory we are about to descend into out of the root.
+        fs::rename(dir.path().join("root/a"), dir.path().join("outside/a")).unwrap();
+        let err = walk
+            .next()
+            .expect("walk should report the moved directory")
+            .expect_err("walk should not descend into the moved directory");
+        assert!(
+            err.iter_chain_hotfix()
+                .filter_map(|err| err.downcast_ref::<Error>())
+                .any(|err| matches!(err, Error::SafetyViolation { .. })),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn walk_directory_moved_within() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        fs::create_dir(dir.path().join("d")).unwrap();
+        let root = Root::open(dir.path()).unwrap();
+
+        let mut walk = root.walk("/a").unwrap();
+        walk.next().unwrap().unwrap();
+        assert_eq!(walk.next().unwrap().unwrap().path().to_str(), Some("b"));
+        // Moving the directory elsewhere inside the root still moves it out
+        // of the tree being walked.
+        fs::rename(dir.path().join("a/b"), dir.path().join("d/b")).unwrap();
+        assert!(walk.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+//! A small worker pool for parallelising the per-entry work of tree walks.
+
+use crate::{
+    error::{self, Error},
+    utils,
+};
+
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+};
+
+use snafu::ResultExt;
+
+/// Number of jobs which may be queued per worker thread.
+const QUEUE_PER_THREAD: usize = 64;
+
+/// The first error hit by any of the workers (or the producer).
+#[derive(Default)]
+struct FirstError {
+    failed: AtomicBool,
+    error: Mutex<Option<Error>>,
+}
+
+impl FirstError {
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, err: Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+    }
+
+    fn into_result(self) -> Result<(), Error> {
+        match self.error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run `work` for every job produced by `jobs`, using up to `threads` worker
+/// threads (named `name`). With a single thread, `work` is run by the calling
+/// thread. Jobs are always produced (and hence walks are always driven) by the
+/// calling thread.
+///
+/// Jobs usually hold file descriptors (such as a [`WalkEntry`]), so the number
+/// of queued jobs is bounded both by the number of threads and by the fd budget
+/// of the process. `work` should only open a bounded number of file
+/// descriptors which are closed before it returns.
+///
+/// Processing stops at the first error (either from `jobs` or `work`), which
+/// is returned once all of the workers have exited. Jobs which were already
+/// queued are dropped without being processed.
+///
+/// [`WalkEntry`]: struct.WalkEntry.html
+pub(crate) fn for_each<T, I, W>(name: &str, threads: usize, jobs: I, work: W) -> Result<(), Error>
+where
+    T: Send,
+    I: IntoIterator<Item = Result<T, Error>>,
+    W: Fn(T) -> Result<(), Error> + Sync,
+{
+    let budget = utils::default_fd_budget();
+    let threads = cmp::min(threads, budget);
+    let first_error = FirstError::default();
+
+    if threads <= 1 {
+        for job in jobs {
+            work(job?)?;
+        }
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::sync_channel::<T>(cmp::min(threads * QUEUE_PER_THREAD, budget));
+    let rx = Mutex::new(rx);
+    thread::scope(|scope| {
+        // Move the sender into the scope, so that it is dropped (and the
+        // workers exit) before the scope waits for the workers.
+        let tx = tx;
+        for _ in 0..threads {
+            let (rx, first_error, work) = (&rx, &first_error, &work);
+            let spawned = thread::Builder::new()
+                .name(name.into())
+                .spawn_scoped(scope, move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) if !first_error.failed() => {
+                            if let Err(err) = work(job) {
+                                first_error.set(err);
+                            }
+                        }
+                        Ok(_) => (),
+                        Err(_) => break,
+                    }
+                })
+                .context(error::OsError {
+                    operation: format!("spawn {} worker thread", name),
+                });
+            if let Err(err) = spawned {
+                first_error.set(err);
+                return;
+            }
+        }
+
+        for job in jobs {
+            if first_error.failed() {
+                break;
+            }
+            match job {
+                // The workers only exit once the sender is dropped, so this
+                // can't fail.
+                Ok(job) => {
+                    let _ = tx.send(job);
+                }
+                Err(err) => first_error.set(err),
+            }
+        }
+    });
+    first_error.into_result()
+}
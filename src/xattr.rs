@@ -0,0 +1,169 @@
+/*
+ * libpathrs: safe path resolution on Linux
+ * Copyright (C) 2019-2021 Aleksa Sarai <cyphar@cyphar.com>
+ * Copyright (C) 2019-2021 SUSE LLC
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU Lesser General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option) any
+ * later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+ * PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![forbid(unsafe_code)]
+
+use crate::{
+    error::{self, Error},
+    utils::{self, XattrPath},
+    Handle,
+};
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    io::Error as IOError,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    vec,
+};
+
+use snafu::{IntoError, ResultExt};
+
+/// The extended attributes of a file, keyed by name (as returned by
+/// [`Handle::xattr_map`]).
+///
+/// [`Handle::xattr_map`]: struct.Handle.html#method.xattr_map
+pub type XattrMap = BTreeMap<OsString, Vec<u8>>;
+
+/// An iterator over the `(name, value)` pairs of the extended attributes of a
+/// file, returned by [`Handle::xattrs`].
+///
+/// Each value is only read once the iterator reaches it, so a full dump never
+/// needs more memory than the size cap passed to [`Handle::xattrs`]. Extended
+/// attributes which are removed while iterating are skipped. Once an error has
+/// been returned, the iterator is finished.
+///
+/// [`Handle::xattrs`]: struct.Handle.html#method.xattrs
+#[derive(Debug)]
+pub struct Xattrs<'a> {
+    /// The handle must outlive the iterator, since `path` references it.
+    _handle: &'a Handle,
+    path: XattrPath,
+    names: vec::IntoIter<OsString>,
+    /// Number of bytes which may still be read before hitting the size cap.
+    remaining: usize,
+}
+
+/// The error returned when the extended attributes exceed the size cap.
+fn too_big() -> Error {
+    error::OsError {
+        operation: "check size of xattrs against limit",
+    }
+    .into_error(IOError::from_raw_os_error(libc::E2BIG))
+}
+
+impl Xattrs<'_> {
+    fn read_value(&mut self, name: &OsStr) -> Result<Option<Vec<u8>>, Error> {
+        let path = &self.path;
+        let value = match utils::read_xattr_buffer_capped(self.remaining, |buf| {
+            path.getxattr(name, buf)
+        }) {
+            Err(err) if err.root_cause().raw_os_error() == Some(libc::ENODATA) => return Ok(None),
+            ret => ret.context(error::RawOsError {
+                operation: "get xattr of handle",
+            })?,
+        };
+        let value = value.ok_or_else(too_big)?;
+        self.remaining -= value.len();
+        Ok(Some(value))
+    }
+}
+
+impl Iterator for Xattrs<'_> {
+    type Item = Result<(OsString, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let name = self.names.next()?;
+            match self.read_value(&name) {
+                Ok(Some(value)) => return Some(Ok((name, value))),
+                Ok(None) => continue,
+                Err(err) => {
+                    self.names = Vec::new().into_iter();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl Handle {
+    /// Enumerate the extended attributes of the file, returning an iterator
+    /// over their names and values (see [`Xattrs`]). Symlinks have no
+    /// extended attributes as far as this is concerned (they cannot be read
+    /// without following the symlink).
+    ///
+    /// `max_size` limits the total size of the names and values (in bytes),
+    /// so that a file with a huge number of large extended attributes cannot
+    /// exhaust the caller's memory.
+    ///
+    /// # Errors
+    ///
+    /// If the extended attributes exceed `max_size`, an [`Error::OsError`]
+    /// with `E2BIG` is returned (by this method when listing the names, or by
+    /// the iterator when reading a value).
+    ///
+    /// [`Xattrs`]: struct.Xattrs.html
+    /// [`Error::OsError`]: error/enum.Error.html#variant.OsError
+    pub fn xattrs(&self, max_size: usize) -> Result<Xattrs<'_>, Error> {
+        let path = XattrPath::of(self.inner.as_raw_fd())?;
+        let is_symlink = self
+            .inner
+            .metadata()
+            .context(error::OsError {
+                operation: "check inode type for xattrs",
+            })?
+            .file_type()
+            .is_symlink();
+        let names = if is_symlink {
+            Vec::new()
+        } else {
+            utils::read_xattr_buffer_capped(max_size, |buf| path.listxattr(buf))
+                .context(error::RawOsError {
+                    operation: "list xattrs of handle",
+                })?
+                .ok_or_else(too_big)?
+        };
+        // The list of names is a sequence of NUL-terminated strings.
+        let remaining = max_size - names.len();
+        let names = names
+            .split(|&c| c == b'\0')
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_os_string())
+            .collect::<Vec<_>>();
+        Ok(Xattrs {
+            _handle: self,
+            path,
+            names: names.into_iter(),
+            remaining,
+        })
+    }
+
+    /// Read all of the extended attributes of the file into an [`XattrMap`],
+    /// as with [`Handle::xattrs`].
+    ///
+    /// # Errors
+    ///
+    /// The errors are identical to [`Handle::xattrs`].
+    ///
+    /// [`XattrMap`]: type.XattrMap.html
+    /// [`Handle::xattrs`]: struct.Handle.html#method.xattrs
+    pub fn xattr_map(&self, max_size: usize) -> Result<XattrMap, Error> {
+        self.xattrs(max_size)?.collect()
+    }
+}